@@ -1,4 +1,4 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{any::Any, marker::PhantomData, sync::Arc};
 
 use luminal_cudarc::{
     driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig},
@@ -7,8 +7,9 @@ use luminal_cudarc::{
 
 use itertools::Itertools;
 use luminal::{
+    compiler_internals::{petgraph::visit::EdgeRef, *},
     op::*,
-    prelude::{petgraph::visit::EdgeRef, *},
+    prelude::*,
 };
 use rustc_hash::FxHashMap;
 
@@ -458,11 +459,11 @@ impl<T: CudaFloat> CudaGather<T> {
         let type_name = T::type_name();
         let code = format!("
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void gather({type_name} *out, const {type_name} *weights, const float *inp, int n_embeddings, int embedding_dim) {{
+extern \"C\" __global__ void gather({type_name} *out, const {type_name} *weights, const int *inp, int n_embeddings, int embedding_dim) {{
     int x = blockIdx.x * blockDim.x + threadIdx.x;
     int y = blockIdx.y * blockDim.y + threadIdx.y;
     if (x < n_embeddings && y < embedding_dim) {{
-        out[x * embedding_dim + y] = weights[(int)inp[x] * embedding_dim + y];
+        out[x * embedding_dim + y] = weights[inp[x] * embedding_dim + y];
     }}
 }}");
         dev.load_ptx(
@@ -494,14 +495,8 @@ where
     CudaData<T>: Data,
 {
     fn process(&mut self, inputs: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        // Inp 1 should be Vec<f32> and inp 2 should be a CudaSlice<T>
-        let indexes = inputs[0]
-            .0
-            .borrowed()
-            .data
-            .as_any()
-            .downcast_ref::<Vec<f32>>()
-            .unwrap();
+        // Inp 1 should be i32 or f32 indexes and inp 2 should be a CudaSlice<T>
+        let indexes = get_indexes_from_tensor(&inputs[0].0);
         let weights = inputs[1]
             .0
             .borrowed()
@@ -510,9 +505,9 @@ where
             .downcast_ref::<CudaData<T>>()
             .unwrap();
 
-        let mut indexes_buffer = unsafe { self.device.alloc::<f32>(indexes.len()).unwrap() };
+        let mut indexes_buffer = unsafe { self.device.alloc::<i32>(indexes.len()).unwrap() };
         self.device
-            .htod_copy_into(indexes.clone(), &mut indexes_buffer)
+            .htod_copy_into(indexes.to_vec(), &mut indexes_buffer)
             .unwrap();
         let mut out = self
             .device
@@ -546,6 +541,14 @@ where
             data: Box::new(CudaData(out)),
         }]
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        // This op can accept integer inputs
+        if key == "int_inputs" {
+            return Some(Box::new(()));
+        }
+        None
+    }
 }
 
 #[derive(LuminalPrint, Default)]
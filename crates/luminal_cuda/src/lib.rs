@@ -7,11 +7,11 @@ mod prim;
 mod tests;
 
 use itertools::Itertools;
-use luminal_cudarc::driver::{CudaSlice, DeviceRepr};
+use luminal_cudarc::driver::{CudaSlice, DevicePtr, DeviceRepr};
 
 use std::{collections::hash_map::DefaultHasher, fmt::Write, hash::Hasher};
 
-use luminal::prelude::*;
+use luminal::{compiler_internals::*, prelude::*};
 
 use self::symbolic::{BigExpression, Term};
 
@@ -25,6 +25,16 @@ pub type CudaCompiler<T> = (
     prim::CopyCompiler<T>,
 );
 
+/// Make the CUDA compiler stacks available to `Graph::auto_compile`
+pub fn register_auto_compile() {
+    register_pipeline(Pipeline::CudaF16, |graph, remap| {
+        graph.compile(<(GenericCompiler, CudaCompiler<f16>)>::default(), remap)
+    });
+    register_pipeline(Pipeline::CudaF32, |graph, remap| {
+        graph.compile(<(GenericCompiler, CudaCompiler<f32>)>::default(), remap)
+    });
+}
+
 pub trait CudaFloat:
     std::fmt::Debug
     + Copy
@@ -69,6 +79,13 @@ impl Data for CudaData<f32> {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn allocation(&self) -> Option<(usize, usize)> {
+        Some((
+            *self.0.device_ptr() as usize,
+            self.0.len() * std::mem::size_of::<f32>(),
+        ))
+    }
 }
 
 impl CudaFloat for f16 {
@@ -93,6 +110,13 @@ impl Data for CudaData<f16> {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn allocation(&self) -> Option<(usize, usize)> {
+        Some((
+            *self.0.device_ptr() as usize,
+            self.0.len() * std::mem::size_of::<f16>(),
+        ))
+    }
 }
 
 fn expr_to_cuda_string(expr: BigExpression) -> String {
@@ -117,6 +141,10 @@ fn expr_to_cuda_string(expr: BigExpression) -> String {
                 symbols.pop().unwrap(),
                 symbols.pop().unwrap()
             ),
+            Term::CeilDiv => {
+                let (a, b) = (symbols.pop().unwrap(), symbols.pop().unwrap());
+                format!("(({a}+{b}-1)/{b})")
+            }
             _ => format!(
                 "({}{term:?}{})",
                 symbols.pop().unwrap(),
@@ -10,6 +10,7 @@ use crate::{
     CudaData, CudaFloat,
 };
 use luminal::{
+    compiler_internals::*,
     graph::NodeIndex,
     op::{InputTensor, Operator},
     prelude::*,
@@ -44,7 +45,14 @@ where
             .as_any()
             .downcast_ref::<CudaData<T>>()
             .unwrap();
-        let mut out = self.1.alloc_zeros::<T>((m * n) as usize).unwrap();
+        // CUDA can't allocate empty buffers
+        let mut out = self.1.alloc_zeros::<T>(((m * n) as usize).max(1)).unwrap();
+        if m * n == 0 || k == 0 {
+            // Empty output, or an empty inner dimension giving zeros. cuBLAS rejects k = 0
+            return vec![Tensor {
+                data: Box::new(CudaData(out)),
+            }];
+        }
         let (a_row_major, b_row_major) = (
             inp[0].1.indexes[1] > inp[0].1.indexes[0],
             inp[1].1.indexes[1] > inp[1].1.indexes[0],
@@ -103,7 +111,8 @@ where
     }
 }
 
-/// Multiplies a BxMxK matrix with a BxKxN matrix, resulting in a BxMxN matrix
+/// Multiplies a BxMxK matrix with a BxKxN matrix, resulting in a BxMxN matrix. B's batch dimension can be fake, sharing
+/// one KxN matrix across the batch.
 #[derive(LuminalPrint, LuminalEqFalse, Clone)]
 pub struct CudaBatchMatmul2D<T>(Arc<CudaBlas>, Arc<CudaDevice>, PhantomData<T>);
 
@@ -113,13 +122,26 @@ where
 {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let (a_shape, b_shape) = (inp[0].1.shape(), inp[1].1.shape());
-        let a_strides = inp[0].1.strides();
+        let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
         let (batch_size, m, k, n) = (
             a_shape[0].to_usize().unwrap() as i32,
             a_shape[1].to_usize().unwrap() as i32,
             a_shape[2].to_usize().unwrap() as i32,
-            b_shape[1].to_usize().unwrap() as i32,
+            b_shape[2].to_usize().unwrap() as i32,
         );
+        // A broadcast B is shared by every batch
+        let b_batch_stride = if inp[1].1.fake[inp[1].1.indexes[0]] {
+            0
+        } else {
+            b_strides[0].to_usize().unwrap() as i64
+        };
+        let ldb = if inp[1].1.indexes[2] > inp[1].1.indexes[1] {
+            b_strides[1]
+        } else {
+            b_strides[2]
+        }
+        .to_usize()
+        .unwrap() as i32;
         let a = inp[0]
             .0
             .borrowed()
@@ -136,11 +158,16 @@ where
             .unwrap();
         let mut out = self
             .1
-            .alloc_zeros::<T>((m * n * batch_size) as usize)
+            .alloc_zeros::<T>(((m * n * batch_size) as usize).max(1))
             .unwrap();
+        if m * n * batch_size == 0 || k == 0 {
+            return vec![Tensor {
+                data: Box::new(CudaData(out)),
+            }];
+        }
         let (a_row_major, b_row_major) = (
             inp[0].1.indexes[2] > inp[0].1.indexes[1],
-            inp[1].1.indexes[1] > inp[1].1.indexes[0],
+            inp[1].1.indexes[2] > inp[1].1.indexes[1],
         );
         let (transa, transb) = match (a_row_major, b_row_major) {
             (true, true) => (CUBLAS_OP_N, CUBLAS_OP_N),
@@ -159,8 +186,8 @@ where
                     k,
                     &1.0_f32 as *const f32,
                     *b.0.device_ptr() as *const f32,
-                    if b_row_major { n } else { k },
-                    0,
+                    ldb,
+                    b_batch_stride,
                     *a.0.device_ptr() as *const f32,
                     if a_row_major { k } else { m },
                     a_strides[0].to_usize().unwrap() as i64,
@@ -183,8 +210,8 @@ where
                     k,
                     &f16::from_f32(1.0) as *const f16,
                     *b.0.device_ptr() as *const f16,
-                    if b_row_major { n } else { k },
-                    0,
+                    ldb,
+                    b_batch_stride,
                     *a.0.device_ptr() as *const f16,
                     if a_row_major { k } else { m },
                     a_strides[0].to_usize().unwrap() as i64,
@@ -243,6 +270,11 @@ where
                 // The intermediate mul can't be deleted
                 continue;
             }
+            if graph.has_hint(mul, &CompilerHint::NoFuse)
+                || graph.has_hint(sum_reduce, &CompilerHint::NoFuse)
+            {
+                continue;
+            }
             // Insert MatMul2D op
             let mut srcs = graph.get_sources(mul);
             // Undo expansions and permute
@@ -279,19 +311,20 @@ where
             // Remove the old ops
             graph.graph.remove_node(mul);
             graph.graph.remove_node(sum_reduce);
+            graph.record_rewrite("CudaMatmul2D");
         }
 
         // Look for the batch matmul pattern
         let (mut sum_reduce, mut mul) = (NodeIndex::default(), NodeIndex::default());
-        // Mul ([A, C(fake), B] | [A(fake), C, B]) -> SumReduce(2) -> [A, C]
-        // Actually starts at [A,B] | [B, C]
+        // Mul ([D, A, C(fake), B] | [D(maybe fake), A(fake), C, B]) -> SumReduce(3) -> [D, A, C]
+        // Actually starts at [D, A, B] | [D(maybe fake), B, C]
         let mut searcher = SelectEdge::new(
             SelectOp::new()
                 .ty::<CudaMul<T>>()
                 .shapes([['D', 'A', 'C', 'B'], ['D', 'A', 'C', 'B']])
                 .fakes([
                     [Some(false), Some(false), Some(true), Some(false)],
-                    [Some(true), Some(true), Some(false), Some(false)],
+                    [None, Some(true), Some(false), Some(false)],
                 ])
                 .ptr(&mut mul),
             SelectOp::new()
@@ -311,13 +344,17 @@ where
                 // The intermediate mul can't be deleted
                 continue;
             }
+            if graph.has_hint(mul, &CompilerHint::NoFuse)
+                || graph.has_hint(sum_reduce, &CompilerHint::NoFuse)
+            {
+                continue;
+            }
             // Insert BatchMatMul2D op
             let mut srcs = graph.get_sources(mul);
             // Undo expansions and permute
             srcs[0].2.remove_dim(2);
             srcs[1].2.remove_dim(1);
-            srcs[1].2.remove_dim(0);
-            srcs[1].2.permute(&[1, 0]);
+            srcs[1].2.permute(&[0, 2, 1]);
             let new_op = graph
                 .add_op(CudaBatchMatmul2D::<T>(
                     Arc::new(CudaBlas::new(dev.clone()).unwrap()),
@@ -348,6 +385,7 @@ where
             // Remove the old ops
             graph.graph.remove_node(mul);
             graph.graph.remove_node(sum_reduce);
+            graph.record_rewrite("CudaBatchMatmul2D");
         }
     }
 }
@@ -6,8 +6,9 @@ use luminal_cudarc::{
 };
 
 use luminal::{
+    compiler_internals::{petgraph::visit::EdgeRef, *},
     op::*,
-    prelude::{petgraph::visit::EdgeRef, *},
+    prelude::*,
     shape::symbolic::BigExpression,
 };
 use rustc_hash::FxHashMap;
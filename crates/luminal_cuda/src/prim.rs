@@ -18,8 +18,9 @@ use luminal_cudarc::{
 };
 
 use luminal::{
+    compiler_internals::{petgraph::visit::EdgeRef, *},
     op::{Function as LFunction, *},
-    prelude::{petgraph::visit::EdgeRef, *},
+    prelude::*,
 };
 
 /// Copy a tensor to the GPU
@@ -54,6 +55,12 @@ where
             .copied()
             .map(CudaFloat::from_f32)
             .collect::<Vec<_>>();
+        if vec.is_empty() {
+            // CUDA can't allocate empty buffers
+            return vec![Tensor {
+                data: Box::new(CudaData(self.0.alloc_zeros::<T>(1).unwrap())),
+            }];
+        }
         let mut a = unsafe { self.0.alloc::<T>(vec.len()).unwrap() };
         self.0.htod_copy_into(vec, &mut a).unwrap();
         vec![Tensor {
@@ -1497,12 +1504,15 @@ where
             }
         }
 
-        // Copy prints from device
+        // Copy prints and taps from device
         for (output_node, edge) in graph
             .graph
             .node_indices()
             // Filter non-functions
-            .filter(|n| graph.graph.node_weight(*n).unwrap().as_any().is::<Print>())
+            .filter(|n| {
+                graph.graph.node_weight(*n).unwrap().as_any().is::<Print>()
+                    || graph.graph.node_weight(*n).unwrap().as_any().is::<Tap>()
+            })
             .map(|n| {
                 (
                     n,
@@ -1602,7 +1612,7 @@ where
                     dev.clone(),
                     &graph.dyn_map,
                 ));
-            } else if let Some(SumReduce(dim)) = op_ref.as_any().downcast_ref() {
+            } else if let Some(SumReduce(dim, _)) = op_ref.as_any().downcast_ref() {
                 *op_ref = Box::new(CudaSumReduce::<T>::new(
                     *dim,
                     shapes[0],
@@ -1617,6 +1627,9 @@ where
                     &graph.dyn_map,
                 ));
             }
+            if graph.graph.node_weight(id).unwrap().as_any().type_id() != op {
+                graph.record_rewrite("CudaPrimitive");
+            }
         }
     }
 }
@@ -377,6 +377,43 @@ fn test_batch_matmul() {
     }
 }
 
+#[test]
+fn test_batch_matmul_batched_b() {
+    let mut cx = Graph::new();
+    let mut rng = StdRng::seed_from_u64(0);
+    let a = cx.tensor::<(Dyn<'B'>, Dyn<'M'>, Dyn<'K'>)>();
+    let b = cx.tensor::<(Dyn<'B'>, Dyn<'K'>, Dyn<'N'>)>();
+    let b_t = cx.tensor::<(Dyn<'B'>, Dyn<'N'>, Dyn<'K'>)>();
+    let mut c = a.matmul(b).retrieve();
+    let mut c_t = a.matmul(b_t.permute::<_, LAxes3<0, 2, 1>>()).retrieve();
+
+    cx.compile(
+        <(GenericCompiler, CudaCompiler<f32>)>::default(),
+        (&mut c, &mut c_t),
+    );
+    for (batch, m, k, n) in [(1, 1, 8, 5), (3, 12, 7, 33), (5, 40, 64, 17)] {
+        let a_data = random_vec_rng(batch * m * k, &mut rng);
+        let b_data = random_vec_rng(batch * k * n, &mut rng);
+        let b_t_data = random_vec_rng(batch * n * k, &mut rng);
+        a.set_dyn(a_data.clone(), &[batch, m, k]);
+        b.set_dyn(b_data.clone(), &[batch, k, n]);
+        b_t.set_dyn(b_t_data.clone(), &[batch, n, k]);
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (batch, m, k));
+        let d_b = d_dev.tensor_from_vec(b_data, (batch, k, n));
+        let d_b_t = d_dev.tensor_from_vec(b_t_data, (batch, n, k));
+        let d_c = d_a.clone().matmul(d_b);
+        let d_c_t = d_a.matmul(d_b_t.permute::<_, DAxes3<0, 2, 1>>());
+
+        assert_close_precision(&c.data(), &d_c.as_vec(), 2);
+        assert_close_precision(&c_t.data(), &d_c_t.as_vec(), 2);
+        c.drop();
+        c_t.drop();
+    }
+}
+
 #[test]
 fn test_batch_matmul_transpose() {
     const B: usize = 1;
@@ -747,7 +784,7 @@ fn test_pad_contig() {
         .set_dyn(a_data, &[m, k])
         .retrieve();
     let mut b: GraphTensor<(Dyn<'M'>, Dyn<'K'>)> = a
-        .pad(&[(0, 0.into()), (0, Expression::from(16) - 'K')])
+        .pad(&[(0, 0.into()), (0, (Expression::from(16) - 'K').max(0))])
         .contiguous()
         .retrieve();
     let mut c: GraphTensor<(Dyn<'M'>, Dyn<'K'>)> =
@@ -782,3 +819,44 @@ fn test_movement() {
 
     assert_exact(&c.data(), &d_c.as_vec());
 }
+
+#[test]
+fn test_matmul_degenerate_dims() {
+    // (batch, m, k, n), with an empty inner dimension giving zeros and other empty dimensions giving empty outputs
+    for (batch, m, k, n) in [
+        (1, 2, 0, 3),
+        (1, 0, 2, 3),
+        (1, 2, 3, 0),
+        (2, 2, 0, 3),
+        (0, 2, 2, 3),
+        (1, 1, 0, 3),
+    ] {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<(Dyn<'M'>, Dyn<'K'>)>();
+        let b = cx.tensor::<(Dyn<'K'>, Dyn<'N'>)>();
+        let batched_a = cx.tensor::<(Dyn<'B'>, Dyn<'M'>, Dyn<'K'>)>();
+        let mut c = a.matmul(b).retrieve();
+        let mut batched_c = batched_a.matmul(b).retrieve();
+        cx.compile(CudaCompiler::<f32>::default(), (&mut c, &mut batched_c));
+        a.set_dyn(vec![1.; m * k], &[m, k]);
+        b.set_dyn(vec![1.; k * n], &[k, n]);
+        batched_a.set_dyn(vec![1.; batch * m * k], &[batch, m, k]);
+        cx.execute();
+
+        // Each output sums k ones
+        assert_exact(&c.data(), &vec![k as f32; m * n]);
+        assert_exact(&batched_c.data(), &vec![k as f32; batch * m * n]);
+    }
+}
+
+#[test]
+fn test_fuzz_corpus() {
+    if let Err(failure) = luminal::tests::fuzz::fuzz_backend(
+        &luminal::tests::fuzz::FuzzConfig::ci(),
+        |cx, outputs| {
+            cx.compile(CudaCompiler::<f32>::default(), outputs);
+        },
+    ) {
+        panic!("{failure}");
+    }
+}
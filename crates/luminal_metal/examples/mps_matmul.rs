@@ -0,0 +1,45 @@
+use std::time::{Duration, Instant};
+
+use luminal::{prelude::*, tests::random_vec};
+use luminal_metal::MetalCompiler;
+
+const RUNS: u32 = 50;
+
+/// Time an MxK by KxN matmul, with the custom kernels or with MetalPerformanceShaders
+fn time_matmul<const M: usize, const K: usize, const N: usize>(mps: bool) -> Duration {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<M, K>>().set(random_vec(M * K)).keep();
+    let b = cx.tensor::<R2<K, N>>().set(random_vec(K * N)).keep();
+    let mut c = a.matmul(b);
+    if mps {
+        c = c.hint(CompilerHint::PreferKernel("mps".to_string()));
+    }
+    c.retrieve();
+    cx.compile(<(GenericCompiler, MetalCompiler<f16>)>::default(), &mut c);
+
+    cx.execute();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        cx.execute();
+    }
+    start.elapsed() / RUNS
+}
+
+fn compare<const M: usize, const K: usize, const N: usize>() {
+    let (custom, mps) = (time_matmul::<M, K, N>(false), time_matmul::<M, K, N>(true));
+    println!(
+        "{M:>5} x {K:>5} x {N:>5}   custom {custom:>12?}   mps {mps:>12?}   {:.2}x",
+        custom.as_secs_f64() / mps.as_secs_f64()
+    );
+}
+
+fn main() {
+    println!("M x K x N, f16, mean of {RUNS} runs");
+    compare::<1, 4096, 4096>();
+    compare::<16, 4096, 4096>();
+    compare::<128, 4096, 4096>();
+    compare::<512, 512, 512>();
+    compare::<1024, 1024, 1024>();
+    compare::<2048, 2048, 2048>();
+    compare::<512, 4096, 11008>();
+}
@@ -0,0 +1,478 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device, MTLSize,
+};
+
+use luminal::{
+    compiler_internals::{
+        petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction},
+        *,
+    },
+    op::{InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::BigExpression,
+};
+
+use crate::{
+    binary::MetalSub,
+    compile_function, finish_command_buffer, get_buffers_from_tensors, new_buffer,
+    prim::MetalAdd,
+    unary::{MetalMeanReduce, MetalRMSNorm, MetalStdNorm},
+    MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
+};
+
+/// The kernel behind the fused residual add and norm ops. Each row gets a threadgroup, which adds the two inputs,
+/// reduces the norm statistics and writes the normed row, all in one pass over the hidden states.
+#[derive(Clone)]
+struct AddNorm<T> {
+    pipeline: ComputePipelineState,
+    device: Device,
+    queue: CommandQueue,
+    epsilon: f32,
+    /// Whether the sum is also written out, as a second output
+    keep_sum: bool,
+    /// Whether the normed row is scaled by a weight, the third input
+    weighted: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: MetalFloat> AddNorm<T> {
+    /// Subtract the mean before scaling for a layer norm, or scale by the root mean square for an RMSNorm
+    fn new(
+        center: bool,
+        epsilon: f32,
+        keep_sum: bool,
+        device: Device,
+        queue: CommandQueue,
+    ) -> Self {
+        let type_name = T::type_name();
+        let weighted = !center;
+        let weight_arg = if weighted {
+            format!("device const {type_name} * weight [[buffer(2)]],")
+        } else {
+            String::new()
+        };
+        let (sum_arg, store_sum, load_sum) = if keep_sum {
+            (
+                format!("device {type_name} * sum [[buffer(4)]],"),
+                format!("sum[row * row_size + i] = ({type_name})s;"),
+                "(float)sum[row * row_size + i]".to_string(),
+            )
+        } else {
+            (
+                String::new(),
+                String::new(),
+                format!("(float)({type_name})((float)a[i] + (float)b[i])"),
+            )
+        };
+        let normalize = if center {
+            // Rounded like a separate mean reduce and subtract would be
+            format!(
+                "const float mean = (float)({type_name})(total / row_size);
+    float var = 0;
+    for (int i = tid; i < row_size; i += n_threads) {{
+        float c = (float)({type_name})({load_sum} - mean);
+        var += c * c;
+    }}
+    const float scale = rsqrt(threadgroup_sum(var, buf, simd_index, simd_lane, n_threads) / row_size + eps);
+    for (int i = tid; i < row_size; i += n_threads) {{
+        y[i] = ({type_name})((float)({type_name})({load_sum} - mean) * scale);
+    }}"
+            )
+        } else {
+            format!(
+                "const float scale = rsqrt(total / row_size + eps);
+    for (int i = tid; i < row_size; i += n_threads) {{
+        y[i] = ({type_name})({load_sum} * scale * (float)weight[i]);
+    }}"
+            )
+        };
+        let stat = if center { "s" } else { "s * s" };
+        let kernel_code = format!(
+            "#include <metal_stdlib>
+#define SIMD_WIDTH 32
+
+using namespace metal;
+
+float threadgroup_sum(float v, threadgroup float * buf, uint simd_index, uint simd_lane, uint n_threads) {{
+    v = simd_sum(v);
+    if (n_threads > SIMD_WIDTH) {{
+        // Wait for an earlier reduction to finish reading the scratch space
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        if (simd_index == 0) {{
+            buf[simd_lane] = 0.0f;
+        }}
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        if (simd_lane == 0) {{
+            buf[simd_index] = v;
+        }}
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        v = simd_sum(buf[simd_lane]);
+    }}
+    return v;
+}}
+
+kernel void kernel_add_norm(
+        device const {type_name} * src0 [[buffer(0)]],
+        device const {type_name} * src1 [[buffer(1)]],
+        {weight_arg}
+        device       {type_name} * dst [[buffer(3)]],
+        {sum_arg}
+        constant   int64_t & row_size [[buffer(5)]],
+        constant     float & eps [[buffer(6)]],
+        threadgroup float  * buf [[threadgroup(0)]],
+        uint row [[threadgroup_position_in_grid]],
+        uint tid [[thread_position_in_threadgroup]],
+        uint simd_index [[simdgroup_index_in_threadgroup]],
+        uint simd_lane [[thread_index_in_simdgroup]],
+        uint n_threads [[threads_per_threadgroup]]) {{
+    device const {type_name} * a = src0 + row * row_size;
+    device const {type_name} * b = src1 + row * row_size;
+    device {type_name} * y = dst + row * row_size;
+
+    // Residual sum, rounded like a separate add would be
+    float total = 0;
+    for (int i = tid; i < row_size; i += n_threads) {{
+        float s = (float)({type_name})((float)a[i] + (float)b[i]);
+        {store_sum}
+        total += {stat};
+    }}
+    total = threadgroup_sum(total, buf, simd_index, simd_lane, n_threads);
+
+    {normalize}
+}}"
+        );
+
+        Self {
+            pipeline: compile_function("kernel_add_norm", &kernel_code, &device),
+            device,
+            queue,
+            epsilon,
+            keep_sum,
+            weighted,
+            _phantom: Default::default(),
+        }
+    }
+
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![input_shapes[0].n_elements() * size_of::<T>(); 1 + self.keep_sum as usize]
+    }
+
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        output_buffers: &[&Buffer],
+    ) {
+        let row_size = inputs[0].1.shape().last().unwrap().to_usize().unwrap();
+        let batch_size = inputs[0]
+            .1
+            .shape()
+            .into_iter()
+            .take(inputs[0].1.len() - 1)
+            .map(|i| i.to_usize().unwrap())
+            .product::<usize>();
+        if batch_size * row_size == 0 {
+            return;
+        }
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        if self.weighted {
+            encoder.set_buffer(2, Some(inputs[2].0), 0);
+        }
+        encoder.set_buffer(3, Some(output_buffers[0]), 0);
+        if self.keep_sum {
+            encoder.set_buffer(4, Some(output_buffers[1]), 0);
+        }
+        encoder.set_i64(5, row_size as i64);
+        encoder.set_f32(6, self.epsilon);
+        let mut nth = 32; // SIMD width
+        while nth < row_size && nth < 1024 {
+            nth *= 2;
+        }
+        encoder.set_threadgroup_memory_length(0, 32 * size_of::<f32>() as u64);
+        encoder.dispatch_thread_groups(
+            MTLSize::new(batch_size as u64, 1, 1),
+            MTLSize::new(nth as u64, 1, 1),
+        );
+        encoder.end_encoding();
+    }
+
+    fn process(&self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let inputs = get_buffers_from_tensors(&tensors)?;
+            let size = (tensors[0].1.n_elements().to_usize().unwrap() * size_of::<T>()).max(1);
+            let outputs = (0..1 + self.keep_sum as usize)
+                .map(|_| new_buffer(&self.device, size as u64))
+                .collect::<Vec<_>>();
+
+            self.metal_forward(&inputs, command_buffer, &outputs.iter().collect::<Vec<_>>());
+
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(outputs
+                .into_iter()
+                .map(|b| Tensor::new(MetalBuffer(b)))
+                .collect())
+        })
+    }
+}
+
+macro_rules! add_norm_op {
+    ($name:ident) => {
+        impl<T: MetalFloat> MetalKernel for $name<T> {
+            fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+                self.0.output_buffer_sizes(input_shapes)
+            }
+
+            fn metal_forward(
+                &self,
+                inputs: &[(&Buffer, ShapeTracker)],
+                command_buffer: &CommandBufferRef,
+                _: &[&Buffer],
+                output_buffers: &[&Buffer],
+            ) {
+                self.0.metal_forward(inputs, command_buffer, output_buffers)
+            }
+        }
+
+        impl<T: MetalFloat> Operator for $name<T> {
+            fn try_process(
+                &mut self,
+                tensors: Vec<(InputTensor, ShapeTracker)>,
+            ) -> Result<Vec<Tensor>, OpError> {
+                self.0.process(tensors)
+            }
+
+            fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+                if key == "metal" {
+                    #[allow(clippy::arc_with_non_send_sync)]
+                    return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                        self.clone(),
+                    )))));
+                }
+                None
+            }
+        }
+    };
+}
+
+/// A residual add followed by an RMSNorm of the sum, in a single kernel. Takes the residual, the sublayer output and
+/// the norm weight, and outputs the normed sum, then the sum itself if it's used downstream.
+#[derive(LuminalPrint, LuminalEqFalse, Clone)]
+pub struct MetalAddRMSNorm<T>(AddNorm<T>);
+add_norm_op!(MetalAddRMSNorm);
+
+/// A residual add followed by a layer norm of the sum over the last dim, in a single kernel. Takes the residual and
+/// the sublayer output, and outputs the normed sum, then the sum itself if it's used downstream.
+#[derive(LuminalPrint, LuminalEqFalse, Clone)]
+pub struct MetalAddLayerNorm<T>(AddNorm<T>);
+add_norm_op!(MetalAddLayerNorm);
+
+/// The two inputs of an add, if both are contiguous rows of the sum
+fn add_inputs<T: MetalFloat>(
+    graph: &Graph,
+    add: NodeIndex,
+) -> Option<[(NodeIndex, u8, ShapeTracker); 2]> {
+    if !graph.graph[add].as_any().is::<MetalAdd<T>>() || graph.no_delete.contains(&add) {
+        return None;
+    }
+    let inputs: [_; 2] = graph.get_sources(add).try_into().ok()?;
+    inputs
+        .iter()
+        .all(|(_, _, sh)| sh.is_contiguous() && !sh.is_sliced() && !sh.is_padded())
+        .then_some(inputs)
+}
+
+/// Fuse a residual add into the RMSNorm or layer norm that follows it, so the hidden states are read once instead of
+/// written by the add and read back by the norm. If the sum is used anywhere else, like the next residual of a
+/// pre-norm block, it becomes the fused op's second output. This is meant to be ran **after** the RMSNormCompiler and
+/// StdNormCompiler.
+#[derive(Default, Debug)]
+pub struct AddNormCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for AddNormCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        for norm in graph.graph.node_indices().collect::<Vec<_>>() {
+            if !graph.graph.contains_node(norm) {
+                continue;
+            }
+            let op = graph.graph[norm].as_any();
+            let (epsilon, center) = if let Some(rms) = op.downcast_ref::<MetalRMSNorm<T>>() {
+                (rms.epsilon, false)
+            } else if let Some(std) = op.downcast_ref::<MetalStdNorm<T>>() {
+                (std.epsilon, true)
+            } else {
+                continue;
+            };
+            let sources = graph.get_sources(norm);
+            let (x, _, x_sh) = sources[0];
+            if !x_sh.is_contiguous() || x_sh.is_sliced() || x_sh.is_padded() {
+                continue;
+            }
+            // The add and the ops between it and the norm, which the fused op replaces
+            let (add, replaced) = if center {
+                // A layer norm is a std norm of sub(x, mean_reduce(x)) over the last dim
+                let centered = graph.get_sources(x);
+                let [(add, _, _), (mean, _, _)] = centered[..] else {
+                    continue;
+                };
+                if !graph.graph[x].as_any().is::<MetalSub<T>>()
+                    || !graph.graph[mean]
+                        .as_any()
+                        .downcast_ref::<MetalMeanReduce<T>>()
+                        .is_some_and(|m| m.3 == x_sh.len() - 1)
+                    || graph.get_sources(mean).first().map(|(s, _, _)| *s) != Some(add)
+                    || check_no_delete(graph, &[x, mean])
+                    || graph.get_dests(x).len() != 1
+                    || graph.get_dests(mean).len() != 1
+                {
+                    continue;
+                }
+                (add, vec![x, mean])
+            } else {
+                (x, vec![])
+            };
+            let Some([a, b]) = add_inputs::<T>(graph, add) else {
+                continue;
+            };
+
+            // Consumers of the sum that aren't replaced read it from the fused op's second output
+            let sum_consumers = graph
+                .graph
+                .edges_directed(add, Direction::Outgoing)
+                .filter(|e| e.target() != norm && !replaced.contains(&e.target()))
+                .map(|e| (e.target(), *e.weight()))
+                .collect::<Vec<_>>();
+            let keep_sum = sum_consumers.iter().any(|(_, d)| !d.is_schedule());
+            let add_norm = AddNorm::<T>::new(center, epsilon, keep_sum, dev.clone(), queue.clone());
+            let fused = if center {
+                graph
+                    .add_op(MetalAddLayerNorm(add_norm))
+                    .input(a.0, a.1, a.2)
+                    .input(b.0, b.1, b.2)
+                    .finish()
+            } else {
+                let (weight, weight_output, weight_sh) = sources[1];
+                graph
+                    .add_op(MetalAddRMSNorm(add_norm))
+                    .input(a.0, a.1, a.2)
+                    .input(b.0, b.1, b.2)
+                    .input(weight, weight_output, weight_sh)
+                    .finish()
+            };
+            for (target, dependency) in sum_consumers {
+                let dependency = match dependency {
+                    Dependency::Data {
+                        input_order, shape, ..
+                    } => Dependency::Data {
+                        input_order,
+                        output_order: 1,
+                        shape,
+                    },
+                    Dependency::Schedule => Dependency::Schedule,
+                };
+                graph.graph.add_edge(fused, target, dependency);
+            }
+
+            // Create edges to dests
+            move_outgoing_edge(norm, fused, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                norm,
+                fused,
+            );
+
+            // Remove the old ops
+            graph.graph.remove_node(norm);
+            for node in replaced {
+                graph.graph.remove_node(node);
+            }
+            graph.graph.remove_node(add);
+            graph.record_rewrite(if center { "AddLayerNorm" } else { "AddRMSNorm" });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        nn::norm::RMSNorm,
+        prelude::{Module, *},
+        tests::{assert_close, random_vec},
+    };
+
+    use super::{MetalAddLayerNorm, MetalAddRMSNorm};
+    use crate::{unary::MetalRMSNorm, MetalCompiler};
+
+    fn count<O: 'static>(cx: &Graph) -> usize {
+        cx.graph
+            .node_weights()
+            .filter(|op| op.as_any().is::<O>())
+            .count()
+    }
+
+    #[test]
+    fn test_add_rms_norm() {
+        // Pre-norm blocks, where each sum is both normed and the next residual
+        const LAYERS: usize = 3;
+        let mut cx = Graph::new();
+        let mut x = cx.tensor::<R2<4, 96>>().set(random_vec(4 * 96));
+        let mut normed = vec![];
+        for _ in 0..LAYERS {
+            let norm = RMSNorm::<96>::initialize(&mut cx);
+            norm.weight.set(random_vec(96));
+            let sublayer = cx.tensor::<R2<4, 96>>().set(random_vec(4 * 96));
+            x += sublayer;
+            normed.push(norm.forward(x).retrieve());
+        }
+        let mut out = x.retrieve();
+        cx.execute();
+        let unfused = normed.iter().map(|n| n.data()).collect::<Vec<_>>();
+        let unfused_out = out.data();
+
+        cx.compile(MetalCompiler::<f32>::default(), (&mut normed, &mut out));
+        // One kernel per layer, except the last sum, which is retrieved so can't be fused away
+        assert_eq!(count::<MetalAddRMSNorm<f32>>(&cx), LAYERS - 1);
+        assert_eq!(count::<MetalRMSNorm<f32>>(&cx), 1);
+        cx.execute();
+
+        for (n, unfused) in normed.iter().zip(&unfused) {
+            assert_close(&n.data(), unfused);
+        }
+        assert_close(&out.data(), &unfused_out);
+    }
+
+    #[test]
+    fn test_add_layer_norm() {
+        // Post-norm blocks, where only the normed sum is used
+        const LAYERS: usize = 2;
+        let mut cx = Graph::new();
+        let mut x = cx.tensor::<R3<2, 3, 64>>().set(random_vec(2 * 3 * 64));
+        for _ in 0..LAYERS {
+            let sublayer = cx.tensor::<R3<2, 3, 64>>().set(random_vec(2 * 3 * 64));
+            x = (x + sublayer).layer_norm::<2, _>(1e-5);
+        }
+        let mut out = x.retrieve();
+        cx.execute();
+        let unfused = out.data();
+
+        cx.compile(MetalCompiler::<f32>::default(), &mut out);
+        assert_eq!(count::<MetalAddLayerNorm<f32>>(&cx), LAYERS);
+        cx.execute();
+
+        assert_close(&out.data(), &unfused);
+    }
+}
@@ -0,0 +1,264 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use luminal::{
+    op::{InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::BigExpression,
+};
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device, MTLSize,
+};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    compile_function, fast_math_enabled, finish_command_buffer, get_buffer_from_tensor,
+    get_idx_valid_exps, input_dyn_dims, new_buffer, render_dyn_dim_inputs, with_fast_math,
+    MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
+};
+
+/// Indexes of the largest (or smallest) elements along a dimension.
+///
+/// Each output gets a threadgroup, whose threads scan strided parts of the dimension and then tree reduce (value, index)
+/// pairs in threadgroup memory. Ties resolve to the lowest index and NaNs win, matching the CPU op. Note indexes are
+/// stored as T, so in fp16 indexes above 2048 aren't exactly representable.
+#[derive(LuminalPrint, Clone)]
+pub struct MetalArgMax<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub dim: usize,
+    pub min: bool,
+    dyn_symbols: Vec<char>,
+    _phantom: PhantomData<T>,
+    dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
+}
+
+impl<T> PartialEq for MetalArgMax<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.dim == other.dim && self.min == other.min
+    }
+}
+
+impl<T: MetalFloat> MetalArgMax<T> {
+    pub fn new(
+        shape: ShapeTracker,
+        dim: usize,
+        min: bool,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 4);
+        let type_name = T::type_name();
+        let cmp = if min { "<" } else { ">" };
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+#define NONE 0xFFFFFFFF
+
+// Whether (a, ai) should replace (b, bi)
+bool better(float a, uint ai, float b, uint bi) {{
+    if (ai == NONE) return false;
+    if (bi == NONE) return true;
+    bool a_nan = isnan(a), b_nan = isnan(b);
+    if (a_nan || b_nan) return a_nan && (!b_nan || ai < bi);
+    if (a == b) return ai < bi;
+    return a {cmp} b;
+}}
+
+kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *out [[buffer(1)]], device uint& back_size [[buffer(2)]], device uint& dim_size [[buffer(3)]], threadgroup float *vals [[threadgroup(0)]], threadgroup uint *idxs [[threadgroup(1)]], uint row [[threadgroup_position_in_grid]], uint tid [[thread_position_in_threadgroup]], uint tg_size [[threads_per_threadgroup]]{rendered}) {{
+    uint a_ = row / back_size;
+    uint b_ = row % back_size;
+    float best = 0.0;
+    uint best_i = NONE;
+    for (uint c_ = tid; c_ < dim_size; c_ += tg_size) {{
+        uint idx = a_ * dim_size * back_size + c_ * back_size + b_;
+        float v = (({valid_exp}) != 0) ? (float)inp[{idx_exp}] : 0.0;
+        if (better(v, c_, best, best_i)) {{
+            best = v;
+            best_i = c_;
+        }}
+    }}
+    vals[tid] = best;
+    idxs[tid] = best_i;
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = tg_size / 2; s > 0; s >>= 1) {{
+        if (tid < s && better(vals[tid + s], idxs[tid + s], vals[tid], idxs[tid])) {{
+            vals[tid] = vals[tid + s];
+            idxs[tid] = idxs[tid + s];
+        }}
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }}
+    if (tid == 0) {{
+        out[row] = ({type_name})(idxs[0] == NONE ? 0 : idxs[0]);
+    }}
+}}
+");
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            dim,
+            min,
+            dyn_symbols,
+            _phantom: Default::default(),
+            dyn_map,
+            fast_math: fast_math_enabled(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalArgMax<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        let mut sh = input_shapes[0];
+        sh.remove_dim(self.dim);
+        vec![sh.n_elements() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let mut sh = inputs[0].1;
+        sh.remove_dim(self.dim);
+        let n_outputs = sh.n_elements().to_usize().unwrap();
+        if n_outputs == 0 {
+            return;
+        }
+        let shape = inputs[0].1.shape();
+        let dim_size = shape[self.dim].to_usize().unwrap();
+        let back_size: usize = shape
+            .iter()
+            .skip(self.dim + 1)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        // The tree reduction needs a power of two threads
+        let threadgroup_size = dim_size.next_power_of_two().clamp(1, 1024);
+
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(output_buffers[0]), 0);
+        encoder.set_u32(2, back_size as u32);
+        encoder.set_u32(3, dim_size as u32);
+        encoder.set_threadgroup_memory_length(0, (threadgroup_size * size_of::<f32>()) as u64);
+        encoder.set_threadgroup_memory_length(1, (threadgroup_size * size_of::<u32>()) as u64);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            4,
+        );
+
+        // Execute one threadgroup per output
+        encoder.dispatch_thread_groups(
+            MTLSize::new(n_outputs as u64, 1, 1),
+            MTLSize::new(threadgroup_size as u64, 1, 1),
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalArgMax<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let mut sh = tensors[0].1;
+            sh.remove_dim(self.dim);
+            let out_size = sh.n_elements().to_usize().unwrap();
+            let out = new_buffer(&self.device, (out_size * size_of::<T>()).max(1) as u64);
+            let inp = get_buffer_from_tensor(&tensors[0].0)?;
+
+            let command_buffer = self.queue.new_command_buffer();
+            self.metal_forward(&[(inp, tensors[0].1)], command_buffer, &[], &[&out]);
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        // This op can accept non contiguous inputs
+        if key == "non_contiguous" {
+            return Some(Box::new(()));
+        }
+        if key == "recompile_shapes" {
+            if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        self.dim,
+                        self.min,
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        prelude::*,
+        tests::{assert_exact, random_vec},
+    };
+
+    use crate::MetalCompiler;
+
+    #[test]
+    fn test_argmax() {
+        let mut cx = Graph::new();
+        // Quantize to get lots of ties, and make the rows longer than a threadgroup
+        let data = random_vec(3 * 2000)
+            .into_iter()
+            .map(|i| (i * 8.).round())
+            .collect::<Vec<_>>();
+        let a = cx.tensor::<R2<3, 2000>>().set(data.clone());
+        let mut outs = vec![
+            a.argmax::<1>().retrieve().no_shape(),
+            a.argmin::<1>().retrieve().no_shape(),
+            a.argmax::<0>().retrieve().no_shape(),
+            a.permute::<R2<2000, 3>, _>()
+                .argmin::<0>()
+                .retrieve()
+                .no_shape(),
+        ];
+        cx.execute();
+        let cpu = outs.iter().map(|o| o.data()).collect::<Vec<_>>();
+        cx.compile(MetalCompiler::<f32>::default(), &mut outs);
+        cx.execute();
+
+        for (out, cpu) in outs.iter().zip(&cpu) {
+            assert_exact(&out.data(), cpu);
+        }
+        // The CPU op is checked against a plain scan
+        let first_max = |row: &[f32]| {
+            let max = row.iter().copied().fold(f32::MIN, f32::max);
+            row.iter().position(|v| *v == max).unwrap() as f32
+        };
+        assert_exact(
+            &cpu[0],
+            &data.chunks(2000).map(first_max).collect::<Vec<_>>(),
+        );
+    }
+}
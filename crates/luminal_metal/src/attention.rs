@@ -0,0 +1,636 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use luminal::{
+    compiler_internals::{
+        petgraph::{visit::EdgeRef, Direction},
+        *,
+    },
+    op::{ConstantValue, InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::BigExpression,
+};
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device, MTLSize,
+};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    compile_function, finish_command_buffer, get_buffers_from_tensors, get_idx_valid_exps,
+    input_dyn_dims,
+    matmul::Matmul,
+    new_buffer,
+    prim::{MetalAdd, MetalConstant, MetalMul},
+    render_dyn_dim_inputs,
+    unary::MetalSoftmax,
+    MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
+};
+
+const SIMD_WIDTH: usize = 32;
+/// Largest query and value head dims the kernel keeps in threadgroup memory and registers
+const MAX_HEAD_DIM: usize = 256;
+
+/// Attention, softmax(QK^T * scale + mask)V, without materializing the scores.
+///
+/// Each query row gets a simdgroup, which walks the keys in blocks of 32 with one key per lane, keeping a running max,
+/// softmax denominator and output the way flash attention does. Accumulation is in fp32. Inputs are read through
+/// their shape trackers, so permuted and expanded views of Q, K^T, V and the mask don't need to be made contiguous.
+///
+/// Causal attention hides keys after each query's position (aligned to the last key, for queries continuing a cache)
+/// without reading a mask.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct MetalFlashAttention<T> {
+    pipeline: ComputePipelineState,
+    pub causal: bool,
+    value_dim: usize,
+    queue: CommandQueue,
+    device: Device,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: MetalFloat> MetalFlashAttention<T> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        q_shape: ShapeTracker,
+        k_shape: ShapeTracker,
+        v_shape: ShapeTracker,
+        mask_shape: Option<ShapeTracker>,
+        scale: f32,
+        causal: bool,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let type_name = T::type_name();
+        let head_dim = q_shape.shape().last().unwrap().to_usize().unwrap();
+        let value_dim = v_shape.shape().last().unwrap().to_usize().unwrap();
+        let lanes = value_dim.div_ceil(SIMD_WIDTH);
+        let (q_idx, q_valid) = get_idx_valid_exps(q_shape);
+        let (k_idx, k_valid) = get_idx_valid_exps(k_shape);
+        let (v_idx, v_valid) = get_idx_valid_exps(v_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(
+            &[q_shape, k_shape, v_shape]
+                .into_iter()
+                .chain(mask_shape)
+                .collect::<Vec<_>>(),
+            7,
+        );
+        let (mask_input, add_mask) = mask_shape
+            .map(|shape| {
+                let (idx, valid) = get_idx_valid_exps(shape);
+                (
+                    format!(", device {type_name} *mask [[buffer(6)]]"),
+                    format!(
+                        "
+            {{
+                int idx = (batch * q_len + row) * kv_len + key;
+                score += ({valid}) != 0 ? (float)mask[{idx}] : 0.0;
+            }}"
+                    ),
+                )
+            })
+            .unwrap_or_default();
+        let visible = if causal {
+            "min(kv_len, row + kv_len - q_len + 1)"
+        } else {
+            "kv_len"
+        };
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel(device {type_name} *q [[buffer(0)]], device {type_name} *k [[buffer(1)]], device {type_name} *v [[buffer(2)]], device {type_name} *out [[buffer(3)]], device int& q_len [[buffer(4)]], device int& kv_len [[buffer(5)]]{mask_input}, uint2 group [[threadgroup_position_in_grid]], uint lane [[thread_index_in_simdgroup]]{rendered}) {{
+    int row = group.x;
+    int batch = group.y;
+    threadgroup float q_row[{head_dim}];
+    for (int d = lane; d < {head_dim}; d += {SIMD_WIDTH}) {{
+        int idx = (batch * q_len + row) * {head_dim} + d;
+        q_row[d] = ({q_valid}) != 0 ? (float)q[{q_idx}] : 0.0;
+    }}
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    // Max score, softmax denominator and output over the keys seen so far. Each lane holds every 32nd output value.
+    float row_max = -INFINITY;
+    float row_sum = 0.0;
+    float acc[{lanes}];
+    for (int i = 0; i < {lanes}; i++) {{
+        acc[i] = 0.0;
+    }}
+    int visible = {visible};
+    for (int start = 0; start < visible; start += {SIMD_WIDTH}) {{
+        // Each lane scores one key of the block
+        int key = start + (int)lane;
+        float score = -INFINITY;
+        if (key < visible) {{
+            float dot = 0.0;
+            for (int d = 0; d < {head_dim}; d++) {{
+                int idx = (batch * {head_dim} + d) * kv_len + key;
+                dot += q_row[d] * (({k_valid}) != 0 ? (float)k[{k_idx}] : 0.0);
+            }}
+            score = dot * {scale:?};{add_mask}
+        }}
+        float new_max = max(row_max, simd_max(score));
+        // While every key so far is masked out the max stays -inf, where rescaling would give NaNs
+        float p = new_max == -INFINITY ? 0.0 : exp(score - new_max);
+        float correction = new_max == -INFINITY ? 1.0 : exp(row_max - new_max);
+        row_sum = row_sum * correction + simd_sum(p);
+        row_max = new_max;
+        for (int i = 0; i < {lanes}; i++) {{
+            acc[i] *= correction;
+        }}
+
+        // Accumulate the block's values, weighted by each key's probability
+        int block = min({SIMD_WIDTH}, visible - start);
+        for (int j = 0; j < block; j++) {{
+            float p_j = simd_shuffle(p, (ushort)j);
+            for (int i = 0; i < {lanes}; i++) {{
+                int d = (int)lane + i * {SIMD_WIDTH};
+                if (d < {value_dim}) {{
+                    int idx = (batch * kv_len + start + j) * {value_dim} + d;
+                    acc[i] += p_j * (({v_valid}) != 0 ? (float)v[{v_idx}] : 0.0);
+                }}
+            }}
+        }}
+    }}
+
+    // Rows with every key masked out have nothing to average, and come out as zeros
+    float inv_sum = row_sum == 0.0 ? 0.0 : 1.0 / row_sum;
+    for (int i = 0; i < {lanes}; i++) {{
+        int d = (int)lane + i * {SIMD_WIDTH};
+        if (d < {value_dim}) {{
+            out[(batch * q_len + row) * {value_dim} + d] = ({type_name})(acc[i] * inv_sum);
+        }}
+    }}
+}}
+");
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            causal,
+            value_dim,
+            queue,
+            device,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalFlashAttention<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        let q_shape = input_shapes[0].shape();
+        let rows = q_shape
+            .iter()
+            .take(q_shape.len() - 1)
+            .cloned()
+            .product::<BigExpression>();
+        vec![rows * self.value_dim * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let (q_shape, k_shape) = (inputs[0].1.shape(), inputs[1].1.shape());
+        let q_len = q_shape[q_shape.len() - 2].to_usize().unwrap();
+        let kv_len = k_shape[k_shape.len() - 1].to_usize().unwrap();
+        let batch_size = q_shape
+            .iter()
+            .take(q_shape.len() - 2)
+            .map(|i| i.to_usize().unwrap())
+            .product::<usize>();
+        if q_len * batch_size == 0 {
+            return;
+        }
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        encoder.set_buffer(2, Some(inputs[2].0), 0);
+        encoder.set_buffer(3, Some(output_buffers[0]), 0);
+        encoder.set_i32(4, q_len as i32);
+        encoder.set_i32(5, kv_len as i32);
+        if let Some((mask, _)) = inputs.get(3) {
+            encoder.set_buffer(6, Some(mask), 0);
+        }
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            7,
+        );
+
+        // Execute a simdgroup per query row
+        encoder.dispatch_thread_groups(
+            MTLSize::new(q_len as u64, batch_size as u64, 1),
+            MTLSize::new(SIMD_WIDTH as u64, 1, 1),
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalFlashAttention<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let inp_shapes = tensors.iter().map(|(_, s)| *s).collect::<Vec<_>>();
+            let out = new_buffer(
+                &self.device,
+                (self.output_buffer_sizes(&inp_shapes)[0]
+                    .exec(unsafe { self.dyn_map.as_ref().unwrap() })
+                    .unwrap()
+                    .max(1)) as u64,
+            );
+            let inputs = get_buffers_from_tensors(&tensors)?;
+
+            let command_buffer = self.queue.new_command_buffer();
+            self.metal_forward(&inputs, command_buffer, &[], &[&out]);
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
+/// Replace attention, matmul -> scale -> (mask add) -> softmax -> matmul, with a flash attention kernel. This is meant
+/// to be ran after the softmax and matmul compilers.
+#[derive(Default, Debug)]
+pub struct FlashAttentionCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for FlashAttentionCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        let (mut qk, mut scale, mut mask_add, mut softmax, mut av) = (
+            NodeIndex::default(),
+            NodeIndex::default(),
+            NodeIndex::default(),
+            NodeIndex::default(),
+            NodeIndex::default(),
+        );
+        for masked in [true, false] {
+            let mut s = SelectOp::new()
+                .ty::<Matmul<T>>()
+                .ptr(&mut qk)
+                .edge(SelectOp::new().ty::<MetalMul<T>>().ptr(&mut scale));
+            if masked {
+                s = s.edge(SelectOp::new().ty::<MetalAdd<T>>().ptr(&mut mask_add));
+            }
+            let mut searcher = s
+                .edge(SelectOp::new().ty::<MetalSoftmax<T>>().ptr(&mut softmax))
+                .edge(SelectOp::new().ty::<Matmul<T>>().ptr(&mut av))
+                .search(graph);
+            while searcher.next_match() {
+                let intermediates = if masked {
+                    vec![qk, scale, mask_add, softmax]
+                } else {
+                    vec![qk, scale, softmax]
+                };
+                if check_no_delete(graph, &intermediates)
+                    || intermediates.iter().any(|n| n_consumers(graph, *n) != 1)
+                {
+                    // The scores are needed elsewhere
+                    continue;
+                }
+                let Some(attention) =
+                    match_attention::<T>(graph, qk, scale, masked.then_some(mask_add), softmax, av)
+                else {
+                    continue;
+                };
+
+                let mut op = graph
+                    .add_op(MetalFlashAttention::<T>::new(
+                        attention.q.2,
+                        attention.k.2,
+                        attention.v.2,
+                        attention.mask.map(|m| m.2),
+                        attention.scale,
+                        attention.causal,
+                        dev.clone(),
+                        queue.clone(),
+                        &graph.dyn_map,
+                    ))
+                    .input(attention.q.0, attention.q.1, attention.q.2)
+                    .input(attention.k.0, attention.k.1, attention.k.2)
+                    .input(attention.v.0, attention.v.1, attention.v.2);
+                if let Some((mask, output, shape)) = attention.mask {
+                    op = op.input(mask, output, shape);
+                }
+                let op = op.finish();
+
+                // Create edges to dests
+                move_outgoing_edge(av, op, &mut graph.graph);
+                move_references(
+                    &mut remap,
+                    &mut graph.no_delete,
+                    &mut graph.to_retrieve,
+                    av,
+                    op,
+                );
+
+                // Remove the old ops, and the scale and causal mask nothing else uses
+                graph.graph.remove_node(av);
+                for node in intermediates {
+                    graph.graph.remove_node(node);
+                }
+                remove_unused(graph, attention.scale_constant);
+                if let Some(causal_mask) = attention.causal_mask {
+                    remove_unused(graph, causal_mask);
+                }
+                graph.record_rewrite("FlashAttention");
+            }
+        }
+    }
+}
+
+/// The inputs of a matched attention pattern
+struct Attention {
+    q: (NodeIndex, u8, ShapeTracker),
+    k: (NodeIndex, u8, ShapeTracker),
+    v: (NodeIndex, u8, ShapeTracker),
+    /// The additive mask the kernel reads, if there is one it can't apply by position
+    mask: Option<(NodeIndex, u8, ShapeTracker)>,
+    scale: f32,
+    scale_constant: NodeIndex,
+    causal: bool,
+    /// The causal mask the kernel applies by position
+    causal_mask: Option<NodeIndex>,
+}
+
+/// Check a matched pattern is attention the kernel can compute, and get its inputs
+fn match_attention<T: MetalFloat>(
+    graph: &Graph,
+    qk: NodeIndex,
+    scale: NodeIndex,
+    mask_add: Option<NodeIndex>,
+    softmax: NodeIndex,
+    av: NodeIndex,
+) -> Option<Attention> {
+    let [q, k] = graph.get_sources(qk)[..] else {
+        return None;
+    };
+    let [scores, v] = graph.get_sources(av)[..] else {
+        return None;
+    };
+    // Head dims are baked into the kernel
+    let head_dim = q.2.shape().last()?.to_usize()?;
+    let value_dim = v.2.shape().last()?.to_usize()?;
+    if scores.0 != softmax
+        || head_dim > MAX_HEAD_DIM
+        || value_dim > MAX_HEAD_DIM
+        || q.2.len() < 2
+        || k.2.len() != q.2.len()
+        || v.2.len() != q.2.len()
+    {
+        return None;
+    }
+
+    // The scores are scaled by a constant
+    let (scale_constant, scale) = graph
+        .get_sources(scale)
+        .into_iter()
+        .filter(|(n, _, _)| *n != qk)
+        .find_map(|(n, _, sh)| {
+            let constant = graph
+                .graph
+                .node_weight(n)?
+                .as_any()
+                .downcast_ref::<MetalConstant<T>>()?;
+            match constant.0 {
+                ConstantValue::Float(f) if sh.n_physical_elements().to_usize() == Some(1) => {
+                    Some((n, f))
+                }
+                _ => None,
+            }
+        })?;
+
+    let (mut mask, mut causal, mut causal_mask) = (None, false, None);
+    if let Some(mask_add) = mask_add {
+        let m = graph
+            .get_sources(mask_add)
+            .into_iter()
+            .find(|(n, _, _)| *n != scale)?;
+        if m.2.len() != q.2.len() {
+            return None;
+        }
+        if graph.has_hint(m.0, &CompilerHint::CausalMask) && is_causal_view(m.2) {
+            causal = true;
+            causal_mask = Some(m.0);
+        } else {
+            mask = Some(m);
+        }
+    }
+    Some(Attention {
+        q,
+        k,
+        v,
+        mask,
+        scale,
+        scale_constant,
+        causal,
+        causal_mask,
+    })
+}
+
+/// Whether a causal mask is viewed with its query and key dims last and in order, broadcast over every other dim
+fn is_causal_view(shape: ShapeTracker) -> bool {
+    let n = shape.len();
+    !shape.is_sliced()
+        && !shape.is_padded()
+        && shape.indexes[n - 2] < shape.indexes[n - 1]
+        && !shape.fake[shape.indexes[n - 2]]
+        && !shape.fake[shape.indexes[n - 1]]
+        && shape.indexes.iter().take(n - 2).all(|i| shape.fake[*i])
+}
+
+fn n_consumers(graph: &Graph, node: NodeIndex) -> usize {
+    graph
+        .graph
+        .edges_directed(node, Direction::Outgoing)
+        .filter(|e| !e.weight().is_schedule())
+        .count()
+}
+
+/// Remove a node nothing consumes, along with the sources only it used
+fn remove_unused(graph: &mut Graph, node: NodeIndex) {
+    if graph.no_delete.contains(&node) || n_consumers(graph, node) > 0 {
+        return;
+    }
+    let sources = graph.get_sources(node);
+    graph.graph.remove_node(node);
+    for (source, _, _) in sources {
+        remove_unused(graph, source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfdx::{
+        shapes::Rank3,
+        tensor::TensorFromVec,
+        tensor_ops::{PermuteTo, TryMatMul},
+    };
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, assert_close_precision, random_vec},
+    };
+
+    use crate::MetalCompiler;
+
+    /// Attention over (heads, seq, head dim) tensors, optionally causal
+    fn attention<const S: usize, const D: usize>(
+        q: GraphTensor<R3<2, S, D>>,
+        k: GraphTensor<R3<2, S, D>>,
+        v: GraphTensor<R3<2, S, D>>,
+        causal: bool,
+    ) -> GraphTensor<R3<2, S, D>> {
+        let mut weights = q
+            .matmul(k.permute::<_, Axes3<0, 2, 1>>())
+            .mul((1.0 / (D as f64).sqrt()) as f32);
+        if causal {
+            weights += q
+                .graph()
+                .causal_mask::<LConst<S>>()
+                .to_additive()
+                .expand::<_, LAxis<0>>();
+        }
+        weights.softmax::<2>().matmul(v)
+    }
+
+    #[test]
+    fn test_flash_attention_causal() {
+        // Longer than a block of keys, with a head dim that doesn't fill the last lanes
+        const S: usize = 70;
+        const D: usize = 40;
+        let mut cx = Graph::new();
+        let q = cx.tensor::<R3<2, S, D>>().set(random_vec(2 * S * D));
+        let k = cx.tensor::<R3<2, S, D>>().set(random_vec(2 * S * D));
+        let v = cx.tensor::<R3<2, S, D>>().set(random_vec(2 * S * D));
+        let mut out = attention(q, k, v, true).retrieve();
+        cx.execute();
+        let unopt = out.data();
+        out.drop();
+
+        let report = cx.compile(<(GenericCompiler, MetalCompiler<f16>)>::default(), &mut out);
+        assert_eq!(report.total().per_pattern_counts["FlashAttention"], 1);
+        cx.execute();
+
+        assert_close_precision(&out.data(), &unopt, 2);
+    }
+
+    #[test]
+    fn test_flash_attention_padding_mask() {
+        // A mask that isn't causal is read by the kernel
+        const S: usize = 5;
+        const D: usize = 8;
+        let mut cx = Graph::new();
+        let q = cx.tensor::<R3<2, S, D>>().set(random_vec(2 * S * D));
+        let k = cx.tensor::<R3<2, S, D>>().set(random_vec(2 * S * D));
+        let v = cx.tensor::<R3<2, S, D>>().set(random_vec(2 * S * D));
+        let padding = cx.tensor::<R1<S>>().set(vec![1., 1., 1., 0., 0.]);
+        let mask = Mask::from_float(padding.expand::<(LConst<S>, LConst<S>), LAxis<0>>());
+        let weights = q
+            .matmul(k.permute::<_, Axes3<0, 2, 1>>())
+            .mul((1.0 / (D as f64).sqrt()) as f32)
+            + mask.to_additive().expand::<_, LAxis<0>>();
+        let mut out = weights.softmax::<2>().matmul(v).retrieve();
+        cx.execute();
+        let unopt = out.data();
+        out.drop();
+
+        let report = cx.compile(<(GenericCompiler, MetalCompiler<f16>)>::default(), &mut out);
+        assert_eq!(report.total().per_pattern_counts["FlashAttention"], 1);
+        cx.execute();
+
+        assert_close(&out.data(), &unopt);
+    }
+
+    #[test]
+    fn test_attention_dyn_sequence_length() {
+        // One compiled graph serves every sequence length
+        const D: usize = 16;
+        let mut cx = Graph::new();
+        let q = cx.tensor::<(LConst<2>, Dyn<'S'>, LConst<D>)>();
+        let k = cx.tensor::<(LConst<2>, Dyn<'S'>, LConst<D>)>();
+        let v = cx.tensor::<(LConst<2>, Dyn<'S'>, LConst<D>)>();
+        let weights = q
+            .matmul(k.permute::<_, Axes3<0, 2, 1>>())
+            .mul((1.0 / (D as f64).sqrt()) as f32);
+        let mut out = weights.softmax::<2>().matmul(v).retrieve();
+        cx.compile(<(GenericCompiler, MetalCompiler<f16>)>::default(), &mut out);
+
+        for s in [1, 6, 40] {
+            let (q_data, k_data, v_data) = (
+                random_vec(2 * s * D),
+                random_vec(2 * s * D),
+                random_vec(2 * s * D),
+            );
+            q.set_dyn(q_data.clone(), &[2, s, D]);
+            k.set_dyn(k_data.clone(), &[2, s, D]);
+            v.set_dyn(v_data.clone(), &[2, s, D]);
+            cx.execute();
+
+            let d_dev = dfdx::tensor::Cpu::default();
+            let d_q = d_dev.tensor_from_vec(q_data, (2, s, D));
+            let d_k = d_dev.tensor_from_vec(k_data, (2, s, D));
+            let d_v = d_dev.tensor_from_vec(v_data, (2, s, D));
+            let d_scores = d_q.matmul(d_k.permute::<_, dfdx::shapes::Axes3<0, 2, 1>>())
+                * (1.0 / (D as f32).sqrt());
+            let d_out = d_scores.softmax::<dfdx::shapes::Axis<2>>().matmul(d_v);
+
+            assert_close_precision(&out.data(), &d_out.as_vec(), 2);
+            out.drop();
+        }
+    }
+
+    #[test]
+    fn test_flash_attention_long_context() {
+        // The 2048 x 2048 scores per head are never materialized
+        const S: usize = 2048;
+        const D: usize = 64;
+        let mut cx = Graph::new();
+        let (q_data, k_data, v_data) = (
+            random_vec(2 * S * D),
+            random_vec(2 * S * D),
+            random_vec(2 * S * D),
+        );
+        let q = cx.tensor::<R3<2, S, D>>().set(q_data.clone());
+        let k = cx.tensor::<R3<2, S, D>>().set(k_data.clone());
+        let v = cx.tensor::<R3<2, S, D>>().set(v_data.clone());
+        let mut out = attention(q, k, v, false).retrieve();
+        cx.compile(<(GenericCompiler, MetalCompiler<f16>)>::default(), &mut out);
+        cx.execute();
+
+        let d_dev = dfdx::tensor::Cpu::default();
+        let shape = (
+            dfdx::shapes::Const::<2>,
+            dfdx::shapes::Const::<S>,
+            dfdx::shapes::Const::<D>,
+        );
+        let d_q = d_dev.tensor_from_vec(q_data, shape);
+        let d_k = d_dev.tensor_from_vec(k_data, shape);
+        let d_v = d_dev.tensor_from_vec(v_data, shape);
+        let d_scores = d_q.matmul(d_k.permute::<Rank3<2, D, S>, _>()) * (1.0 / (D as f32).sqrt());
+        let d_out = d_scores.softmax::<dfdx::shapes::Axis<2>>().matmul(d_v);
+
+        assert_close_precision(&out.data(), &d_out.as_vec(), 2);
+    }
+}
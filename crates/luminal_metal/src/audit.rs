@@ -0,0 +1,148 @@
+use std::{collections::BTreeMap, fmt::Display, mem::size_of};
+
+use luminal::{
+    compiler_internals::*,
+    op::{InputTensor, Operator},
+    prelude::*,
+};
+use metal_rs::Device;
+use petgraph::algo::toposort;
+
+use crate::{sync_for_cpu, MetalBuffer, MetalFloat};
+
+/// How far an op's output moved when accumulating in fp32 instead of the graph's precision
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PrecisionDivergence {
+    /// Largest absolute difference between any two corresponding outputs
+    pub max_abs_diff: f32,
+    /// Largest difference relative to the fp32 accumulated output
+    pub max_rel_diff: f32,
+    /// Number of executions this op was audited over
+    pub executions: usize,
+}
+
+/// Per-op precision divergences, keyed by node name (`{op:?} #{node index}`)
+#[derive(Debug, Clone, Default)]
+pub struct PrecisionAuditReport(pub BTreeMap<String, PrecisionDivergence>);
+
+impl PrecisionAuditReport {
+    /// Ops whose absolute divergence exceeded the threshold, most divergent first
+    pub fn flagged(&self, threshold: f32) -> Vec<(&str, PrecisionDivergence)> {
+        let mut flagged = self
+            .0
+            .iter()
+            .filter(|(_, d)| d.max_abs_diff > threshold)
+            .map(|(n, d)| (n.as_str(), *d))
+            .collect::<Vec<_>>();
+        flagged.sort_by(|(_, a), (_, b)| b.max_abs_diff.total_cmp(&a.max_abs_diff));
+        flagged
+    }
+
+    fn record(&mut self, name: String, reduced: &[f32], full: &[f32]) {
+        let entry = self.0.entry(name).or_default();
+        for (r, f) in reduced.iter().zip(full) {
+            let abs = if r.is_nan() || f.is_nan() {
+                // Only count NaNs appearing in one precision but not the other
+                if r.is_nan() == f.is_nan() {
+                    0.
+                } else {
+                    f32::INFINITY
+                }
+            } else {
+                (r - f).abs()
+            };
+            entry.max_abs_diff = entry.max_abs_diff.max(abs);
+            entry.max_rel_diff = entry.max_rel_diff.max(abs / f.abs().max(f32::EPSILON));
+        }
+        entry.executions += 1;
+    }
+}
+
+impl Display for PrecisionAuditReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, d) in self.flagged(-1.) {
+            writeln!(
+                f,
+                "{name}: max abs diff {:.3e}, max rel diff {:.3e} ({} executions)",
+                d.max_abs_diff, d.max_rel_diff, d.executions
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Execute the graph, additionally running every op that has an fp32 accumulation variant (matmuls, sum reductions
+/// and softmaxes) a second time with it, and record how far the outputs diverge into the report. Divergences are
+/// aggregated across calls, so the same report can be reused over many executions.
+///
+/// **This is slow.** Audited ops run twice, both outputs are downloaded for comparison, and intermediate tensors are
+/// kept around until the end of execution. Only use it to find which ops need fp32 accumulation, and compile the
+/// graph with [`MetalAuditCompiler`](crate::MetalAuditCompiler) so each op's output can be inspected.
+///
+/// Outputs of the graph are computed with the regular (reduced precision) ops, same as `Graph::execute`.
+pub fn execute_precision_audit<T: MetalFloat>(
+    graph: &mut Graph,
+    report: &mut PrecisionAuditReport,
+) {
+    for node in toposort(&graph.graph, None).unwrap() {
+        if graph.tensors.contains_key(&(node, 0)) {
+            continue;
+        }
+        let srcs = graph.get_sources(node);
+        let shapes = srcs
+            .iter()
+            .map(|(_, _, st)| {
+                let mut st = *st;
+                st.resolve_global_dyn_dims(&graph.dyn_map);
+                st
+            })
+            .collect::<Vec<_>>();
+        let inputs = || {
+            srcs.iter()
+                .zip(&shapes)
+                .map(|((id, ind, _), st)| {
+                    (
+                        InputTensor::Borrowed(graph.tensors.get(&(*id, *ind)).unwrap()),
+                        *st,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let op = graph.graph.node_weight_mut(node).unwrap();
+        let outputs = op.process(inputs());
+        if let Some(variant) = op.custom("fp32_accumulation", Box::new(shapes.clone())) {
+            let mut variant = variant.downcast::<Box<dyn Operator>>().unwrap();
+            let name = format!("{op:?} #{}", node.index());
+            for (reduced, full) in outputs.iter().zip(variant.process(inputs())) {
+                report.record(
+                    name.clone(),
+                    &read_buffer::<T>(reduced),
+                    &read_buffer::<T>(&full),
+                );
+            }
+        }
+        for (i, tensor) in outputs.into_iter().enumerate() {
+            graph.tensors.insert((node, i as u8), tensor);
+        }
+    }
+    graph.reset();
+}
+
+fn read_buffer<T: MetalFloat>(tensor: &Tensor) -> Vec<f32> {
+    let buffer = tensor
+        .data
+        .as_any()
+        .downcast_ref::<MetalBuffer>()
+        .expect("Audited ops must output metal buffers");
+    sync_for_cpu(&Device::system_default().unwrap(), buffer);
+    unsafe {
+        std::slice::from_raw_parts(
+            buffer.contents() as *const T,
+            buffer.length() as usize / size_of::<T>(),
+        )
+    }
+    .iter()
+    .map(|v| v.to_f32())
+    .collect()
+}
@@ -0,0 +1,140 @@
+use std::{
+    cell::Cell,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use luminal::prelude::CompileCache;
+use metal_rs::MTLSize;
+use rustc_hash::FxHashMap;
+
+thread_local! {
+    static MATMUL_AUTOTUNING: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Benchmark the tile configurations of each matmul shape the first time it runs, and run it with the fastest one
+/// from then on. With tuning off, every matmul uses [`GemmConfig::DEFAULT`].
+///
+/// This must be set before compiling the graph, since matmuls pick it up when they're built.
+pub fn set_matmul_autotuning(enabled: bool) {
+    MATMUL_AUTOTUNING.with(|t| t.set(enabled));
+}
+
+/// Whether matmuls compiled on this thread tune their tile configuration
+pub fn matmul_autotuning_enabled() -> bool {
+    MATMUL_AUTOTUNING.with(|t| t.get())
+}
+
+/// The tiling of the matmul kernel: each threadgroup computes a `bm`x`bn` block of the output, stepping through K
+/// `bk` at a time, with `wm`x`wn` simdgroups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GemmConfig {
+    pub bm: u64,
+    pub bn: u64,
+    pub bk: u64,
+    pub wm: u64,
+    pub wn: u64,
+}
+
+impl GemmConfig {
+    pub const DEFAULT: Self = Self::new(32, 32, 16, 2, 2);
+    /// The configurations the matmul kernel is instantiated with
+    pub const CANDIDATES: [Self; 4] = [
+        Self::DEFAULT,
+        Self::new(64, 64, 16, 2, 2),
+        Self::new(64, 32, 32, 2, 2),
+        Self::new(64, 32, 16, 2, 2),
+    ];
+
+    const fn new(bm: u64, bn: u64, bk: u64, wm: u64, wn: u64) -> Self {
+        Self { bm, bn, bk, wm, wn }
+    }
+
+    /// The configurations worth trying for an inner dimension of `k`. Blocks of K the kernel doesn't divide evenly
+    /// are only handled by the default.
+    pub(crate) fn candidates(k: usize) -> impl Iterator<Item = Self> {
+        Self::CANDIDATES
+            .into_iter()
+            .filter(move |c| *c == Self::DEFAULT || k as u64 % c.bk == 0)
+    }
+
+    /// The kernel instantiated with this configuration, for transposes like `"nt"`
+    pub(crate) fn function_name(&self, transpose: &str, type_name: &str) -> String {
+        let Self { bm, bn, bk, wm, wn } = self;
+        format!("gemm_{transpose}_{type_name}_{type_name}_bm{bm}_bn{bn}_bk{bk}_wm{wm}_wn{wn}_MN_naligned_K_taligned")
+    }
+
+    /// Threadgroups covering a batch of MxN outputs
+    pub(crate) fn grid(&self, m: usize, n: usize, batch_size: usize) -> MTLSize {
+        MTLSize::new(
+            (n as u64).div_ceil(self.bn),
+            (m as u64).div_ceil(self.bm),
+            batch_size as u64,
+        )
+    }
+
+    /// Threads in each threadgroup, a simdgroup of 32 for every warp
+    pub(crate) fn threadgroup(&self) -> MTLSize {
+        MTLSize::new(32, self.wn, self.wm)
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        [self.bm, self.bn, self.bk, self.wm, self.wn]
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect()
+    }
+
+    /// Read back a configuration, if it's still one the kernel is instantiated with
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 40 {
+            return None;
+        }
+        let fields = bytes
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        let config = Self::new(fields[0], fields[1], fields[2], fields[3], fields[4]);
+        Self::CANDIDATES.contains(&config).then_some(config)
+    }
+}
+
+/// A matmul shape on a device, which tuned configurations are kept for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct TuningKey {
+    pub device: String,
+    pub m: usize,
+    pub k: usize,
+    pub n: usize,
+    pub type_name: &'static str,
+    pub transpose: String,
+}
+
+/// Configurations picked by tuning, shared across all graphs
+static TUNED: OnceLock<Mutex<FxHashMap<TuningKey, GemmConfig>>> = OnceLock::new();
+
+/// The fastest configuration for a matmul shape. The first time a shape is seen on a device, each candidate is timed
+/// with `benchmark` and the fastest is kept in memory, and in the [`CompileCache`] if `LUMINAL_CACHE_DIR` is set.
+pub(crate) fn tuned_config(
+    key: TuningKey,
+    candidates: impl Iterator<Item = GemmConfig>,
+    mut benchmark: impl FnMut(GemmConfig) -> Duration,
+) -> GemmConfig {
+    let tuned = TUNED.get_or_init(Default::default);
+    if let Some(config) = tuned.lock().unwrap().get(&key) {
+        return *config;
+    }
+    // The lock isn't held while benchmarking, so matmuls on other threads aren't blocked
+    let config = CompileCache::from_env(key.device.as_str()).get_or_insert_with_codec(
+        "metal_matmul_tuning",
+        format!("{key:?}").as_bytes(),
+        || {
+            candidates
+                .min_by_key(|c| benchmark(*c))
+                .unwrap_or(GemmConfig::DEFAULT)
+        },
+        |c| c.to_bytes(),
+        GemmConfig::from_bytes,
+    );
+    *tuned.lock().unwrap().entry(key).or_insert(config)
+}
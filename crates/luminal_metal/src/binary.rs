@@ -3,23 +3,25 @@ use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
 use itertools::Itertools;
 use metal_rs::{
     objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
-    ComputePipelineState, Device, MTLResourceOptions, MTLSize,
+    ComputePipelineState, Device, MTLSize,
 };
 use rustc_hash::FxHashMap;
 
 use crate::{
-    compile_function, get_buffer_from_tensor, get_idx_valid_exps, input_dyn_dims,
-    render_dyn_dim_inputs, select_const, DispatchNElements, MetalBuffer, MetalFloat, MetalKernel,
+    compile_function, fast_math_enabled, finish_command_buffer, get_buffer_from_tensor,
+    get_idx_valid_exps, input_dyn_dims, new_buffer, new_buffer_with_data, render_dyn_dim_inputs,
+    select_const, with_fast_math, DispatchNElements, MetalBuffer, MetalFloat, MetalKernel,
     MetalKernelWrapper, SetInt,
 };
 
 use super::prim::*;
 use luminal::{
-    op::{InputTensor, Operator},
-    prelude::{
+    compiler_internals::{
         petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction},
         *,
     },
+    op::{get_indexes_from_tensor, InputTensor, OpError, Operator},
+    prelude::*,
     shape::symbolic::BigExpression,
 };
 
@@ -33,6 +35,7 @@ pub struct MetalSub<T> {
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
+    fast_math: bool,
 }
 
 impl<T: MetalFloat> MetalSub<T> {
@@ -66,6 +69,7 @@ kernel void mkernel(device {type_name} *inp_a [[buffer(0)]], device {type_name}
             dyn_symbols,
             dyn_map,
             _phantom: Default::default(),
+            fast_math: fast_math_enabled(),
         }
     }
 }
@@ -105,29 +109,28 @@ impl<T> MetalKernel for MetalSub<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalSub<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
                 &[
-                    (get_buffer_from_tensor(&tensors[0].0), tensors[0].1),
-                    (get_buffer_from_tensor(&tensors[1].0), tensors[1].1),
+                    (get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1),
+                    (get_buffer_from_tensor(&tensors[1].0)?, tensors[1].1),
                 ],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -146,13 +149,15 @@ impl<T: MetalFloat> Operator for MetalSub<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = Self::new(
-                    input_shapes[0],
-                    input_shapes[1],
-                    self.device.clone(),
-                    self.queue.clone(),
-                    self.dyn_map,
-                )
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        input_shapes[1],
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
             }
         }
         None
@@ -238,6 +243,7 @@ pub struct MetalEqual<T> {
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
+    fast_math: bool,
 }
 
 impl<T: MetalFloat> MetalEqual<T> {
@@ -271,6 +277,7 @@ kernel void mkernel(device {type_name} *inp_a [[buffer(0)]], device {type_name}
             dyn_symbols,
             dyn_map,
             _phantom: Default::default(),
+            fast_math: fast_math_enabled(),
         }
     }
 }
@@ -311,29 +318,28 @@ impl<T> MetalKernel for MetalEqual<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalEqual<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
                 &[
-                    (get_buffer_from_tensor(&tensors[0].0), tensors[0].1),
-                    (get_buffer_from_tensor(&tensors[1].0), tensors[1].1),
+                    (get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1),
+                    (get_buffer_from_tensor(&tensors[1].0)?, tensors[1].1),
                 ],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -352,13 +358,15 @@ impl<T: MetalFloat> Operator for MetalEqual<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = Self::new(
-                    input_shapes[0],
-                    input_shapes[1],
-                    self.device.clone(),
-                    self.queue.clone(),
-                    self.dyn_map,
-                )
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        input_shapes[1],
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
             }
         }
         None
@@ -466,6 +474,11 @@ impl<T: MetalFloat> Compiler for MetalEqualCompiler<T> {
     }
 }
 
+/// Copy the embedding rows of a list of indexes into the output, replacing the one-hot matmul [`GraphTensor::gather`]
+/// builds.
+///
+/// Indexes are read as unsigned, so negative indexes are out of range too. Out of range indexes produce rows of zeros,
+/// the same as the one-hot matmul, which never matches them.
 #[derive(LuminalEqFalse, LuminalPrint, Clone)]
 pub struct MetalGather<T> {
     pipeline: ComputePipelineState,
@@ -482,44 +495,34 @@ impl<T: MetalFloat> MetalGather<T> {
             "
 #include <metal_stdlib>
 using namespace metal;
-kernel void metal_gather(device float *inp [[buffer(0)]], device {type_name} *weights [[buffer(1)]], device {type_name} *out [[buffer(2)]], device int& n_embeddings [[buffer(3)]], device int& embedding_dim [[buffer(4)]], uint2 i_ [[thread_position_in_grid]]) {{
+kernel void metal_gather(device uint *inp [[buffer(0)]], device {type_name} *weights [[buffer(1)]], device {type_name} *out [[buffer(2)]], device uint& n_embeddings [[buffer(3)]], device uint& embedding_dim [[buffer(4)]], device uint& n_rows [[buffer(5)]], uint2 i_ [[thread_position_in_grid]]) {{
     if (i_.x < n_embeddings && i_.y < embedding_dim) {{
-        out[i_.x * embedding_dim + i_.y] = weights[(int)inp[i_.x] * embedding_dim + i_.y];
+        uint row = inp[i_.x];
+        out[i_.x * embedding_dim + i_.y] = row < n_rows ? weights[row * embedding_dim + i_.y] : ({type_name})0.0;
     }}
 }}"), &device), device, embed_dim, queue, _phantom: Default::default()}
     }
 }
 
 impl<T: MetalFloat> Operator for MetalGather<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             // Setup buffers
-            let indexes = tensors[0]
-                .0
-                .borrowed()
-                .data
-                .as_any()
-                .downcast_ref::<Vec<f32>>()
-                .unwrap();
-            let index_buffer = self.device.new_buffer_with_data(
-                unsafe { std::mem::transmute(indexes.as_ptr()) },
-                (indexes.len() * std::mem::size_of::<f32>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
-            let b_inp = tensors[1]
-                .0
-                .borrowed()
-                .data
-                .as_any()
-                .downcast_ref::<MetalBuffer>()
-                .unwrap();
+            let indexes = get_indexes_from_tensor(&tensors[0].0);
+            let index_buffer = new_buffer_with_data(&self.device, &indexes);
+            // The weights come in expanded over the indexes, so the rows are the second dim
+            let n_rows = tensors[1].1.shape()[1].to_usize().unwrap();
+            let b_inp = get_buffer_from_tensor(&tensors[1].0)?;
 
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
 
-            let out = self.device.new_buffer(
+            let out = new_buffer(
+                &self.device,
                 (indexes.len() * self.embed_dim * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
             );
 
             let encoder = command_buffer
@@ -532,6 +535,7 @@ impl<T: MetalFloat> Operator for MetalGather<T> {
             encoder.set_buffer(2, Some(&out), 0);
             encoder.set_u32(3, indexes.len() as u32);
             encoder.set_u32(4, self.embed_dim as u32);
+            encoder.set_u32(5, n_rows as u32);
 
             // Execute
             encoder.dispatch_threads(
@@ -548,12 +552,19 @@ impl<T: MetalFloat> Operator for MetalGather<T> {
             );
             encoder.end_encoding();
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        // This op can accept integer inputs
+        if key == "int_inputs" {
+            return Some(Box::new(()));
+        }
+        None
+    }
 }
 
 #[derive(LuminalPrint, Default)]
@@ -623,8 +634,12 @@ impl<T: MetalFloat> Compiler for MetalGatherCompiler<T> {
 
 #[cfg(test)]
 mod tests {
-    use luminal::{prelude::*, tests::assert_close};
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, assert_exact},
+    };
 
+    use super::MetalGather;
     use crate::MetalCompiler;
     #[test]
     fn test_subtraction() {
@@ -649,4 +664,32 @@ mod tests {
         assert_close(&unopt_c, &c.data());
         assert_close(&unopt_d, &d.data());
     }
+
+    #[test]
+    fn test_gather() {
+        let mut cx = Graph::new();
+        let table = cx
+            .tensor::<R2<5, 3>>()
+            .set((0..15).map(|i| i as f32).collect::<Vec<_>>());
+        let ids = cx.tensor::<(Dyn<'s'>,)>().set_dyn(vec![1., 4.], &[2]);
+        let mut out = table.gather(ids).retrieve();
+        cx.compile(MetalCompiler::<f32>::default(), &mut out);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<MetalGather<f32>>()));
+        cx.execute();
+        assert_exact(&out.data(), &[3., 4., 5., 12., 13., 14.]);
+
+        // A longer sequence, with out of range indexes producing zeros like the one-hot matmul
+        ids.set_dyn(vec![0., 5., 2., -1., 4.], &[5]);
+        out.drop();
+        cx.execute();
+        assert_exact(
+            &out.data(),
+            &[
+                0., 1., 2., 0., 0., 0., 6., 7., 8., 0., 0., 0., 12., 13., 14.,
+            ],
+        );
+    }
 }
@@ -1,7 +1,7 @@
-use std::{any::Any, cell::UnsafeCell, ops::Deref, sync::Arc};
+use std::{any::Any, cell::UnsafeCell, sync::Arc};
 
 use itertools::Itertools;
-use metal_rs::{Buffer, CommandBuffer, CommandQueue, Device};
+use metal_rs::{Buffer, CommandBuffer, CommandQueue, Device, MTLCommandBufferStatus};
 use petgraph::{
     stable_graph::NodeIndex,
     visit::EdgeRef,
@@ -10,13 +10,14 @@ use petgraph::{
 use rustc_hash::{FxHashMap, FxHashSet};
 
 use luminal::{
-    op::{InputTensor, Operator},
+    compiler_internals::*,
+    op::{InputTensor, OpError, Operator},
     prelude::*,
 };
 
-use crate::{MetalBuffer, MetalKernel, MetalKernelWrapper};
+use crate::{finish_command_buffer, wait_for_device, MetalBuffer, MetalKernel, MetalKernelWrapper};
 
-use super::get_buffer_from_tensor;
+use super::get_buffers_from_tensors;
 
 #[derive(Default, LuminalPrint)]
 pub struct CommandBufferCompiler;
@@ -183,6 +184,7 @@ impl Compiler for CommandBufferCompiler {
                 *graph.graph.node_weight_mut(*node).unwrap() = Box::new(CommandBufferWrapper {
                     wrapper,
                     buffer: buffer.clone(),
+                    queue: queue.clone(),
                     dyn_map: &graph.dyn_map,
                 });
                 // Create schedule dependencies from exec to consumers
@@ -210,17 +212,29 @@ struct ExecuteMetalKernels {
 impl Operator for ExecuteMetalKernels {
     fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let buffer = unsafe { &mut *self.buffer.get() };
-        buffer.commit();
-        buffer.wait_until_completed();
+        finish_command_buffer(&self.queue, buffer);
         *buffer = self.queue.new_command_buffer().to_owned();
         vec![]
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "cancelled" {
+            // Drop kernels encoded for the cancelled execution, so the next one doesn't run them
+            let buffer = unsafe { &mut *self.buffer.get() };
+            if buffer.status() == MTLCommandBufferStatus::NotEnqueued {
+                *buffer = self.queue.new_command_buffer().to_owned();
+            }
+            wait_for_device();
+        }
+        None
+    }
 }
 
 #[derive(Clone, LuminalEqFalse)]
 struct CommandBufferWrapper {
     wrapper: Box<MetalKernelWrapper>,
     buffer: Arc<UnsafeCell<CommandBuffer>>,
+    queue: CommandQueue,
     dyn_map: *const FxHashMap<char, usize>,
 }
 
@@ -270,17 +284,28 @@ impl MetalKernel for CommandBufferWrapper {
 }
 
 impl Operator for CommandBufferWrapper {
-    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        self.without_storage_buffers(
-            &inp.iter()
-                .map(|(t, sh)| (get_buffer_from_tensor(t).deref(), *sh))
-                .collect::<Vec<_>>(),
-            unsafe { &*self.buffer.get() },
-            unsafe { self.dyn_map.as_ref().unwrap() },
-        )
-        .into_iter()
-        .map(|b| Tensor::new(MetalBuffer(b)))
-        .collect()
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        let inputs = get_buffers_from_tensors(&inp)?;
+        let dyn_map = unsafe { self.dyn_map.as_ref().unwrap() };
+        let outputs = if profiling() {
+            // Run the kernel in a command buffer of its own, so the GPU time reported for it is its own
+            let command_buffer = self.queue.new_command_buffer();
+            let outputs = self
+                .wrapper
+                .0
+                .without_storage_buffers(&inputs, command_buffer, dyn_map);
+            finish_command_buffer(&self.queue, command_buffer);
+            outputs
+        } else {
+            self.without_storage_buffers(&inputs, unsafe { &*self.buffer.get() }, dyn_map)
+        };
+        Ok(outputs
+            .into_iter()
+            .map(|b| Tensor::new(MetalBuffer(b)))
+            .collect())
     }
 
     #[allow(clippy::arc_with_non_send_sync)]
@@ -318,3 +343,82 @@ fn test_common_buffer() {
 
     assert_close(&d.data(), &d_unopt);
 }
+
+#[cfg(test)]
+#[test]
+fn test_cancel_common_buffer() {
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use crate::MetalCompiler;
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<32>>().set(random_vec(32)).keep();
+    let token = CancellationToken::new();
+    let mut x = a;
+    for i in 0..20 {
+        if i == 10 {
+            let canceller = token.clone();
+            x.tap("Cancel", move |_, _| canceller.cancel());
+        }
+        x = (x.sin() + a).exp2() * 0.5;
+    }
+    let mut out = x.retrieve();
+    cx.execute();
+    let expected = out.data();
+    out.drop();
+
+    cx.compile(MetalCompiler::<f32>::default(), &mut out);
+    assert!(cx.execute_with_cancel(&token).is_err());
+    // Nothing computed before the cancellation is left over
+    assert!(cx.get_tensor_ref(out.id, 0).is_none());
+    cx.execute();
+    assert_close(&out.data(), &expected);
+}
+
+#[cfg(test)]
+#[test]
+fn test_profile_kernels() {
+    use std::time::Duration;
+
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use crate::MetalCompiler;
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<16, 32>>().set(random_vec(16 * 32)).keep();
+    let b = cx.tensor::<R2<32, 8>>().set(random_vec(32 * 8)).keep();
+    let mut c = (a.matmul(b).exp() + 1.)
+        .sum_reduce::<_, Axis<1>>()
+        .retrieve();
+    cx.execute();
+    let expected = c.data();
+    c.drop();
+
+    cx.compile(MetalCompiler::<f32>::default(), &mut c);
+    let mut report = ProfileReport::default();
+    for _ in 0..2 {
+        report.merge(cx.execute_profiled());
+        assert_close(&c.data(), &expected);
+        c.drop();
+    }
+
+    // Every kernel ran in its own command buffer and got a GPU time, summed over both runs
+    let kernels = report
+        .ops
+        .iter()
+        .filter(|op| op.op.starts_with("MetalKernel"))
+        .collect::<Vec<_>>();
+    assert!(!kernels.is_empty());
+    for kernel in kernels {
+        assert_eq!(kernel.executions, 2);
+        assert!(kernel.device_time.is_some_and(|t| t > Duration::ZERO));
+        assert!(kernel.bytes > 0);
+    }
+    // Normal executions still batch the kernels
+    cx.execute();
+    assert_close(&c.data(), &expected);
+}
@@ -0,0 +1,405 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use itertools::Itertools;
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device,
+};
+use rustc_hash::FxHashMap;
+
+use luminal::{
+    compiler_internals::{
+        petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction},
+        *,
+    },
+    op::{InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::BigExpression,
+};
+
+use crate::{
+    compile_function, fast_math_enabled, finish_command_buffer, get_buffers_from_tensors,
+    get_idx_valid_exps, input_dyn_dims, new_buffer, prim::MetalAdd, render_dyn_dim_inputs,
+    with_fast_math, DispatchNElements, MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper,
+    SetInt,
+};
+
+/// Concatenate any number of inputs along an axis, copying each input straight into its region of the output.
+///
+/// Each input gets its own kernel reading it through its view, so inputs don't need to be contiguous. The output is
+/// contiguous, and every element is written once.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct MetalConcat<T> {
+    pub axis: usize,
+    /// A kernel and the dyn dims it takes for each input
+    pipelines: Vec<(ComputePipelineState, Vec<char>)>,
+    queue: CommandQueue,
+    device: Device,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+    fast_math: bool,
+}
+
+impl<T: MetalFloat> MetalConcat<T> {
+    pub fn new(
+        axis: usize,
+        input_shapes: &[ShapeTracker],
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let type_name = T::type_name();
+        let pipelines = input_shapes
+            .iter()
+            .map(|shape| {
+                let (idx_exp, valid_exp) = get_idx_valid_exps(*shape);
+                let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[*shape], 7);
+                let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *out [[buffer(1)]], device uint& n_elements [[buffer(2)]], device uint& len [[buffer(3)]], device uint& out_len [[buffer(4)]], device uint& offset [[buffer(5)]], device uint& inner [[buffer(6)]], uint idx [[thread_position_in_grid]]{rendered}) {{
+    if (idx < n_elements) {{
+        uint chunk = len * inner;
+        out[(idx / chunk) * out_len * inner + offset * inner + idx % chunk] =
+            ({valid_exp}) == 0 ? ({type_name})0 : inp[{idx_exp}];
+    }}
+}}
+");
+                (compile_function("mkernel", &code, &device), dyn_symbols)
+            })
+            .collect();
+        Self {
+            axis,
+            pipelines,
+            queue,
+            device,
+            dyn_map,
+            _phantom: Default::default(),
+            fast_math: fast_math_enabled(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalConcat<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![
+            input_shapes
+                .iter()
+                .map(|s| s.n_elements())
+                .fold(BigExpression::from(0), |a, b| a + b)
+                * size_of::<T>(),
+        ]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let lens = inputs
+            .iter()
+            .map(|(_, s)| s.shape()[self.axis].to_usize().unwrap())
+            .collect_vec();
+        let inner = inputs[0].1.shape()[self.axis + 1..]
+            .iter()
+            .map(|d| d.to_usize().unwrap())
+            .product::<usize>();
+        let out_len = lens.iter().sum::<usize>();
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        let mut offset = 0;
+        for (((buffer, shape), (pipeline, dyn_symbols)), len) in
+            inputs.iter().zip(&self.pipelines).zip(lens)
+        {
+            let n_elements = shape.n_elements().to_usize().unwrap();
+            if n_elements > 0 {
+                encoder.set_compute_pipeline_state(pipeline);
+                encoder.set_buffer(0, Some(buffer), 0);
+                encoder.set_buffer(1, Some(output_buffers[0]), 0);
+                encoder.set_u32(2, n_elements as u32);
+                encoder.set_u32(3, len as u32);
+                encoder.set_u32(4, out_len as u32);
+                encoder.set_u32(5, offset as u32);
+                encoder.set_u32(6, inner as u32);
+                input_dyn_dims(
+                    dyn_symbols,
+                    unsafe { self.dyn_map.as_ref().unwrap() },
+                    encoder,
+                    7,
+                );
+                encoder.dispatch_1d(n_elements);
+            }
+            offset += len;
+        }
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalConcat<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let n_elements = tensors
+                .iter()
+                .map(|(_, s)| s.n_elements().to_usize().unwrap())
+                .sum::<usize>();
+            let out = new_buffer(&self.device, (n_elements * size_of::<T>()) as u64);
+            let inputs = get_buffers_from_tensors(&tensors)?;
+
+            self.metal_forward(&inputs, command_buffer, &[], &[&out]);
+
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        // This op can accept non contiguous inputs
+        if key == "non_contiguous" {
+            return Some(Box::new(()));
+        }
+        if key == "recompile_shapes" {
+            if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        self.axis,
+                        input_shapes,
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
+            }
+        }
+        None
+    }
+}
+
+/// If an add's inputs are two views padded to sit side by side along one axis, the way
+/// [`GraphTensor::concat_along`] builds them, the axis and the inputs without their padding, in order
+fn concat_inputs(
+    sources: &[(NodeIndex, u8, ShapeTracker)],
+) -> Option<(usize, Vec<(NodeIndex, u8, ShapeTracker)>)> {
+    let [a, b] = sources else {
+        return None;
+    };
+    let padded_axis = |shape: &ShapeTracker| {
+        let padded = (0..shape.len())
+            .filter(|i| shape.padding[shape.indexes[*i]] != (0.into(), 0.into()))
+            .collect_vec();
+        (padded.len() == 1).then(|| padded[0])
+    };
+    let axis = padded_axis(&a.2)?;
+    if padded_axis(&b.2)? != axis {
+        return None;
+    }
+    let padding = |shape: &ShapeTracker| shape.padding[shape.indexes[axis]];
+    // The first input is padded after it, and the second before it
+    let (first, second) = if padding(&a.2).0 == 0.into() {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let ((first_start, _), (second_start, second_end)) = (padding(&first.2), padding(&second.2));
+    if first_start != 0.into() || second_end != 0.into() {
+        return None;
+    }
+    let unpad = |(node, output, mut shape): (NodeIndex, u8, ShapeTracker)| {
+        shape.padding[shape.indexes[axis]] = (0.into(), 0.into());
+        (node, output, shape)
+    };
+    let (first, second) = (unpad(*first), unpad(*second));
+    // The second input has to start where the first ends, otherwise they overlap
+    let first_len: Expression = first.2.shape()[axis].clone().into();
+    if first_len != second_start
+        && (first_len.to_usize().is_none() || first_len.to_usize() != second_start.to_usize())
+    {
+        return None;
+    }
+    Some((axis, vec![first, second]))
+}
+
+/// Replace the pad and add [`GraphTensor::concat_along`] builds with a [`MetalConcat`], and merge concats feeding
+/// straight into another concat along the same axis, so a chain of concats copies each input once
+#[derive(LuminalPrint, Default)]
+pub struct MetalConcatCompiler<T: MetalFloat>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for MetalConcatCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        let mut replace = |graph: &mut Graph,
+                           old: &[NodeIndex],
+                           axis: usize,
+                           inputs: Vec<(NodeIndex, u8, ShapeTracker)>| {
+            let shapes = inputs.iter().map(|(_, _, s)| *s).collect_vec();
+            let mut new_op = graph.add_op(MetalConcat::<T>::new(
+                axis,
+                &shapes,
+                dev.clone(),
+                queue.clone(),
+                &graph.dyn_map,
+            ));
+            for (node, output, shape) in inputs {
+                new_op = new_op.input(node, output, shape);
+            }
+            let concat = new_op.finish();
+            move_outgoing_edge(old[0], concat, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                old[0],
+                concat,
+            );
+            for node in old {
+                graph.graph.remove_node(*node);
+            }
+            graph.record_rewrite("Concat");
+        };
+
+        for add in graph.graph.node_indices().collect_vec() {
+            if !graph.graph[add].as_any().is::<MetalAdd<T>>() {
+                continue;
+            }
+            if let Some((axis, inputs)) = concat_inputs(&graph.get_sources(add)) {
+                replace(graph, &[add], axis, inputs);
+            }
+        }
+
+        // Merge chains, until no concat takes another one only it consumes
+        while let Some((outer, inner, axis)) = graph.graph.node_indices().find_map(|outer| {
+            let axis = graph.graph[outer]
+                .as_any()
+                .downcast_ref::<MetalConcat<T>>()?
+                .axis;
+            let inner = graph
+                .get_sources(outer)
+                .into_iter()
+                .map(|(src, _, _)| src)
+                .find(|src| {
+                    graph.graph[*src]
+                        .as_any()
+                        .downcast_ref::<MetalConcat<T>>()
+                        .map(|c| c.axis == axis)
+                        .unwrap_or_default()
+                        && !graph.no_delete.contains(src)
+                        && graph
+                            .graph
+                            .edges_directed(*src, Direction::Outgoing)
+                            .filter(|e| !e.weight().is_schedule())
+                            .count()
+                            == 1
+                })?;
+            Some((outer, inner, axis))
+        }) {
+            let inputs = graph
+                .get_sources(outer)
+                .into_iter()
+                .flat_map(|source| {
+                    if source.0 == inner {
+                        graph.get_sources(inner)
+                    } else {
+                        vec![source]
+                    }
+                })
+                .collect_vec();
+            replace(graph, &[outer, inner], axis, inputs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        prelude::*,
+        tests::{assert_exact, random_vec},
+    };
+
+    use super::MetalConcat;
+    use crate::MetalCompiler;
+
+    fn concats(cx: &Graph) -> Vec<usize> {
+        cx.graph
+            .node_weights()
+            .filter_map(|op| op.as_any().downcast_ref::<MetalConcat<f32>>())
+            .map(|c| c.pipelines.len())
+            .collect()
+    }
+
+    #[test]
+    fn test_concat() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let b = cx.tensor::<R2<3, 2>>().set(random_vec(6));
+        // Non-contiguous
+        let c = cx.tensor::<R2<3, 5>>().set(random_vec(15)).permute();
+        let mut rows = a
+            .permute::<R2<4, 3>, _>()
+            .concat_along::<R2<9, 3>, LAxis<0>, _>(c)
+            .retrieve();
+        let mut three = a
+            .concat_along::<R2<3, 6>, LAxis<1>, _>(b)
+            .concat_along::<R2<3, 10>, LAxis<1>, _>(a)
+            .retrieve();
+        cx.execute();
+        let (unopt_rows, unopt_three) = (rows.data(), three.data());
+
+        cx.compile(MetalCompiler::<f32>::default(), (&mut rows, &mut three));
+        // The chain of two concats is merged into one with three inputs
+        let mut found = concats(&cx);
+        found.sort();
+        assert_eq!(found, vec![2, 3]);
+        cx.execute();
+
+        assert_exact(&rows.data(), &unopt_rows);
+        assert_exact(&three.data(), &unopt_three);
+    }
+
+    #[test]
+    fn test_kv_cache_append() {
+        let mut cx = Graph::new();
+        let cache = cx
+            .tensor::<(LConst<2>, Dyn<'p'>, LConst<4>)>()
+            .set_dyn(vec![], &[2, 0, 4]);
+        let new = cx.tensor::<R3<2, 1, 4>>().set(random_vec(8));
+        let mut out = cache
+            .concat_along::<(LConst<2>, Dyn<'t'>, LConst<4>), LAxis<1>, _>(new)
+            .retrieve();
+        cx.compile(MetalCompiler::<f32>::default(), &mut out);
+        assert_eq!(concats(&cx), vec![2]);
+
+        // Append to an empty cache, then to the result, like each decoding step
+        let mut cache_data = vec![];
+        for step in 0..3 {
+            let new_data = random_vec(8);
+            new.set(new_data.clone());
+            cx.set_dyn_dim('t', step + 1);
+            cx.execute();
+            let expected = (0..2)
+                .flat_map(|h| {
+                    cache_data[h * step * 4..(h + 1) * step * 4]
+                        .iter()
+                        .chain(&new_data[h * 4..(h + 1) * 4])
+                        .copied()
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            assert_exact(&out.data(), &expected);
+            cache.set_dyn(expected.clone(), &[2, step + 1, 4]);
+            cache_data = expected;
+            out.drop();
+        }
+    }
+}
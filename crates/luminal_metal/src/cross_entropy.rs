@@ -0,0 +1,158 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use luminal::{
+    op::{InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::BigExpression,
+};
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device, MTLSize,
+};
+
+use crate::{
+    compile_function, finish_command_buffer, get_buffers_from_tensors, new_buffer, MetalBuffer,
+    MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
+};
+
+const THREADGROUP_SIZE: usize = 256;
+
+/// Cross entropy of `hidden x weight` against target indexes, without materializing the logits.
+///
+/// Each row gets a threadgroup. Threads stride over the vocab, computing one logit at a time and keeping a running max
+/// and sum of exponentials, then the threadgroup merges them. Accumulation is in fp32. Note targets are stored as T,
+/// so in fp16 indexes above 2048 aren't exactly representable.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct MetalChunkedCrossEntropy<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: MetalFloat> MetalChunkedCrossEntropy<T> {
+    pub fn new(device: Device, queue: CommandQueue) -> Self {
+        let type_name = T::type_name();
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+
+kernel void mkernel(device {type_name} *hidden [[buffer(0)]], device {type_name} *weight [[buffer(1)]], device {type_name} *targets [[buffer(2)]], device {type_name} *out [[buffer(3)]], device uint& dim [[buffer(4)]], device uint& vocab [[buffer(5)]], threadgroup float* maxes [[threadgroup(0)]], threadgroup float* sums [[threadgroup(1)]], uint row [[threadgroup_position_in_grid]], uint tid [[thread_position_in_threadgroup]], uint tg_size [[threads_per_threadgroup]]) {{
+    uint target = (uint)(float)targets[row];
+    float max_ = -INFINITY;
+    float sum_ = 0.0;
+    for (uint v = tid; v < vocab; v += tg_size) {{
+        float logit = 0.0;
+        for (uint d = 0; d < dim; d++) {{
+            logit += (float)hidden[row * dim + d] * (float)weight[d * vocab + v];
+        }}
+        if (v == target) {{
+            // Only one thread sees the target, and the slot past the reduction is read after the barriers
+            maxes[tg_size] = logit;
+        }}
+        if (logit > max_) {{
+            sum_ *= exp(max_ - logit);
+            max_ = logit;
+        }}
+        sum_ += exp(logit - max_);
+    }}
+    maxes[tid] = max_;
+    sums[tid] = sum_;
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    // Merge the running max and sum of each thread
+    for (uint s = tg_size / 2; s > 0; s >>= 1) {{
+        if (tid < s) {{
+            float m = max(maxes[tid], maxes[tid + s]);
+            float a = m == -INFINITY ? 0.0 : sums[tid] * exp(maxes[tid] - m);
+            float b = m == -INFINITY ? 0.0 : sums[tid + s] * exp(maxes[tid + s] - m);
+            maxes[tid] = m;
+            sums[tid] = a + b;
+        }}
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }}
+    if (tid == 0) {{
+        out[row] = ({type_name})(maxes[0] + log(sums[0]) - maxes[tg_size]);
+    }}
+}}
+");
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalChunkedCrossEntropy<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![input_shapes[2].n_elements() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let (hidden_shape, weight_shape) = (inputs[0].1.shape(), inputs[1].1.shape());
+        let n_rows = hidden_shape[0].to_usize().unwrap();
+        if n_rows == 0 {
+            return;
+        }
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        encoder.set_buffer(2, Some(inputs[2].0), 0);
+        encoder.set_buffer(3, Some(output_buffers[0]), 0);
+        encoder.set_u32(4, hidden_shape[1].to_usize().unwrap() as u32);
+        encoder.set_u32(5, weight_shape[1].to_usize().unwrap() as u32);
+        // One extra slot holds the target logit
+        encoder.set_threadgroup_memory_length(
+            0,
+            ((THREADGROUP_SIZE + 1) * size_of::<f32>()).next_multiple_of(16) as u64,
+        );
+        encoder.set_threadgroup_memory_length(1, (THREADGROUP_SIZE * size_of::<f32>()) as u64);
+
+        // Execute one threadgroup per row
+        encoder.dispatch_thread_groups(
+            MTLSize::new(n_rows as u64, 1, 1),
+            MTLSize::new(THREADGROUP_SIZE as u64, 1, 1),
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalChunkedCrossEntropy<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let n_rows = tensors[2].1.n_elements().to_usize().unwrap();
+            let out = new_buffer(&self.device, (n_rows * size_of::<T>()) as u64);
+            let inputs = get_buffers_from_tensors(&tensors)?;
+
+            let command_buffer = self.queue.new_command_buffer();
+            self.metal_forward(&inputs, command_buffer, &[], &[&out]);
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
@@ -0,0 +1,228 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use luminal::{
+    op::{InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::BigExpression,
+};
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device,
+};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    compile_function, fast_math_enabled, finish_command_buffer, get_buffer_from_tensor,
+    get_idx_valid_exps, input_dyn_dims, new_buffer, render_dyn_dim_inputs, with_fast_math,
+    DispatchNElements, MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
+};
+
+/// Cumulative sum along a dimension, writing a contiguous output.
+///
+/// Each thread scans one line along the dimension sequentially, reading the input through its view and accumulating
+/// in fp32.
+#[derive(LuminalPrint, Clone)]
+pub struct MetalCumSum<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub dim: usize,
+    dyn_symbols: Vec<char>,
+    _phantom: PhantomData<T>,
+    dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
+}
+
+impl<T> PartialEq for MetalCumSum<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.dim == other.dim
+    }
+}
+
+impl<T: MetalFloat> MetalCumSum<T> {
+    pub fn new(
+        shape: ShapeTracker,
+        dim: usize,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 5);
+        let type_name = T::type_name();
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *out [[buffer(1)]], device uint& n_lines [[buffer(2)]], device uint& back_size [[buffer(3)]], device uint& dim_size [[buffer(4)]], uint i_ [[thread_position_in_grid]]{rendered}) {{
+    if (i_ < n_lines) {{
+        uint a_ = i_ / back_size;
+        uint b_ = i_ % back_size;
+        float sum = 0.0;
+        for (uint c_ = 0; c_ < dim_size; c_++) {{
+            uint idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            if (({valid_exp}) != 0) {{
+                sum += (float)inp[{idx_exp}];
+            }}
+            out[idx] = ({type_name})sum;
+        }}
+    }}
+}}
+");
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            dim,
+            dyn_symbols,
+            _phantom: Default::default(),
+            dyn_map,
+            fast_math: fast_math_enabled(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalCumSum<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![input_shapes[0].n_elements() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let shape = inputs[0].1.shape();
+        let dim_size = shape[self.dim].to_usize().unwrap();
+        let back_size: usize = shape
+            .iter()
+            .skip(self.dim + 1)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let n_lines = inputs[0]
+            .1
+            .n_elements()
+            .to_usize()
+            .unwrap()
+            .checked_div(dim_size)
+            .unwrap_or_default();
+        if n_lines == 0 {
+            return;
+        }
+
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(output_buffers[0]), 0);
+        encoder.set_u32(2, n_lines as u32);
+        encoder.set_u32(3, back_size as u32);
+        encoder.set_u32(4, dim_size as u32);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            5,
+        );
+
+        // Execute one thread per line
+        encoder.dispatch_1d(n_lines);
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalCumSum<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+            let out = new_buffer(&self.device, (inp_size * size_of::<T>()) as u64);
+            let inp = get_buffer_from_tensor(&tensors[0].0)?;
+
+            let command_buffer = self.queue.new_command_buffer();
+            self.metal_forward(&[(inp, tensors[0].1)], command_buffer, &[], &[&out]);
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        // This op can accept non contiguous inputs
+        if key == "non_contiguous" {
+            return Some(Box::new(()));
+        }
+        if key == "recompile_shapes" {
+            if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        self.dim,
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use crate::MetalCompiler;
+
+    #[test]
+    fn test_cumsum() {
+        let mut cx = Graph::new();
+        let data = random_vec(6 * 7 * 33);
+        let a = cx.tensor::<R3<6, 7, 33>>().set(data.clone());
+        let mut sums = (0..3)
+            .map(|axis| a.cumsum(axis).retrieve())
+            .collect::<Vec<_>>();
+        // A permuted input is read through its index expression
+        let mut permuted = a.permute::<_, LAxes3<2, 0, 1>>().cumsum(0).retrieve();
+        cx.compile(MetalCompiler::<f32>::default(), (&mut sums, &mut permuted));
+        cx.execute();
+
+        let shape = [6, 7, 33];
+        for (axis, sum) in sums.iter().enumerate() {
+            let back = shape[axis + 1..].iter().product::<usize>();
+            let mut expected = data.clone();
+            for i in 0..expected.len() {
+                if (i / back) % shape[axis] != 0 {
+                    expected[i] += expected[i - back];
+                }
+            }
+            assert_close(&sum.data(), &expected);
+        }
+        let mut expected = vec![0.; data.len()];
+        for k in 0..33 {
+            for ij in 0..42 {
+                expected[k * 42 + ij] = data[ij * 33 + k]
+                    + if k > 0 {
+                        expected[(k - 1) * 42 + ij]
+                    } else {
+                        0.
+                    };
+            }
+        }
+        assert_close(&permuted.data(), &expected);
+    }
+}
@@ -1,27 +1,32 @@
 use rustc_hash::{FxHashMap, FxHashSet};
-use std::{any::Any, marker::PhantomData, ops::Deref, sync::Arc};
+use std::{any::Any, marker::PhantomData, sync::Arc};
 
 use itertools::Itertools;
 use metal_rs::{
     objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
-    ComputePipelineState, Device, MTLResourceOptions,
+    ComputePipelineState, Device,
 };
 
 use luminal::{
-    op::{InputTensor, Operator},
-    prelude::{
+    compiler_internals::{
         petgraph::{visit::EdgeRef, Direction},
         *,
     },
+    op::{ConstantValue, InputTensor, OpError, Operator},
+    prelude::*,
 };
 
-use crate::{get_buffer_from_tensor, MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper};
+use crate::{
+    fast_math_enabled, finish_command_buffer, get_buffers_from_tensors, new_buffer, sync_for_cpu,
+    with_fast_math, MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper,
+};
 
 use self::symbolic::BigExpression;
 
 use super::{
-    compile_function, get_idx_valid_exps, input_dyn_dims, prim::MetalConstant,
-    render_dyn_dim_inputs, DispatchNElements, SetInt,
+    compile_function, get_idx_valid_exps, input_dyn_dims, is_vectorizable,
+    prim::{MetalConstant, MetalCopyToDevice, MetalMaxReduce, MetalSumReduce},
+    render_dyn_dim_inputs, render_vectorized_elementwise, DispatchNElements, SetInt, VECTOR_WIDTH,
 };
 
 #[derive(Default, Debug)]
@@ -189,24 +194,27 @@ impl<T: MetalFloat> Compiler for ElementwiseFusionCompiler<T> {
                 // B is already fused, just combine with b
                 new_op = b;
                 // Render a into b as input to_input
-                fused_op.equation = multi_replace(&fused_op.equation, &replacements)
-                    .replace(&format!("input{to_input}"), &format!("({a_equation})"));
+                replacements.push((format!("input{to_input}"), format!("({a_equation})")));
+                fused_op.equation = multi_replace(&fused_op.equation, &replacements);
             } else {
                 let mut b_equation = graph
                     .node_custom::<String, _>(b, "elementwise", ())
                     .unwrap();
-                b_equation = multi_replace(&b_equation, &replacements)
-                    .replace(&format!("input{to_input}"), &format!("({a_equation})"));
+                replacements.push((format!("input{to_input}"), format!("({a_equation})")));
+                b_equation = multi_replace(&b_equation, &replacements);
                 // B is not a fused op, let's create a new one
                 new_op = graph
                     .add_op(FusedElementwiseOp::<T> {
                         kernel: None,
+                        vectorized: false,
                         dyn_map: &graph.dyn_map,
                         dyn_chars: vec![],
                         equation: b_equation,
+                        baked: vec![],
                         queue: queue.clone(),
                         device: device.clone(),
                         _phantom: Default::default(),
+                        fast_math: fast_math_enabled(),
                     })
                     .finish();
                 move_incoming_edge(b, new_op, &mut graph.graph);
@@ -240,15 +248,15 @@ impl<T: MetalFloat> Compiler for ElementwiseFusionCompiler<T> {
             }
             fused_ops.remove(&a);
             fused_ops.insert(new_op);
+            graph.record_rewrite("ElementwiseFusion");
             selector.reset();
         }
         // Compile all the kernels we placed
-        let type_name = T::type_name();
         for fused_op in fused_ops {
-            let edges = graph
-                .graph
-                .edges_directed(fused_op, Direction::Incoming)
-                .filter_map(|e| e.weight().as_data())
+            let input_shapes = graph
+                .get_sources(fused_op)
+                .into_iter()
+                .map(|(_, _, sh)| sh)
                 .collect_vec();
             if let Some(op) = graph
                 .graph
@@ -257,53 +265,347 @@ impl<T: MetalFloat> Compiler for ElementwiseFusionCompiler<T> {
                 .as_any_mut()
                 .downcast_mut::<FusedElementwiseOp<T>>()
             {
-                let (dyn_chars, rendered) = render_dyn_dim_inputs(
-                    &edges.iter().map(|i| i.2).collect_vec(),
-                    edges.len() + 2,
+                op.compile(&input_shapes);
+            }
+        }
+    }
+}
+
+/// Most elementwise ops folded into a single reduce's epilogue
+pub const MAX_EPILOGUE_OPS: usize = 8;
+
+/// Fold elementwise ops following a reduce into the reduce kernel, applying them to each output as it's stored.
+///
+/// A reduce's only consumer is folded in when it reads the reduced values directly, without broadcasting them, and
+/// its other inputs are scalar constants, like the scale, epsilon, sqrt and reciprocal after the sum in a norm.
+#[derive(Default, Debug)]
+pub struct ReduceEpilogueCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for ReduceEpilogueCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        for reduce in graph.graph.node_indices().collect_vec() {
+            let Some(op) = graph.graph.node_weight(reduce) else {
+                continue;
+            };
+            if !op.as_any().is::<MetalSumReduce<T>>() && !op.as_any().is::<MetalMaxReduce<T>>() {
+                continue;
+            }
+            for _ in 0..MAX_EPILOGUE_OPS {
+                if graph.no_delete.contains(&reduce) {
+                    break;
+                }
+                let outgoing = graph
+                    .graph
+                    .edges_directed(reduce, Direction::Outgoing)
+                    .filter_map(|e| e.weight().as_data().map(|d| (e.target(), d)))
+                    .collect_vec();
+                let [(consumer, (to_input, _, shape))] = outgoing[..] else {
+                    break;
+                };
+                // Each output of the consumer must read exactly one reduced value
+                if !shape.is_contiguous()
+                    || shape.is_sliced()
+                    || shape.is_padded()
+                    || shape.fake.iter().any(|f| *f)
+                {
+                    break;
+                }
+                let Some(equation) = consumer_equation::<T>(graph, consumer) else {
+                    break;
+                };
+                // Every other input needs to be a scalar constant
+                let mut replacements = vec![(format!("input{to_input}"), "input0".to_string())];
+                let mut constants = vec![];
+                let mut all_constant = true;
+                for (src, (inp, _, sh)) in graph
+                    .graph
+                    .edges_directed(consumer, Direction::Incoming)
+                    .filter_map(|e| e.weight().as_data().map(|d| (e.source(), d)))
+                    .filter(|(_, (inp, _, _))| *inp != to_input)
+                    .collect_vec()
+                {
+                    let eq = (sh.n_physical_elements().to_usize() == Some(1))
+                        .then(|| scalar_equation::<T>(graph, src, MAX_EPILOGUE_OPS))
+                        .flatten();
+                    let Some(eq) = eq else {
+                        all_constant = false;
+                        break;
+                    };
+                    replacements.push((format!("input{inp}"), format!("({eq})")));
+                    constants.push(src);
+                }
+                if !all_constant {
+                    break;
+                }
+                let equation = multi_replace(&equation, &replacements);
+                let input_shapes = graph
+                    .get_sources(reduce)
+                    .into_iter()
+                    .map(|(_, _, sh)| sh)
+                    .collect_vec();
+                let Some(new_op) = graph
+                    .graph
+                    .node_weight_mut(reduce)
+                    .unwrap()
+                    .custom("reduce_epilogue", Box::new((input_shapes, equation)))
+                    .and_then(|o| o.downcast::<Box<dyn Operator>>().ok())
+                else {
+                    break;
+                };
+                *graph.graph.node_weight_mut(reduce).unwrap() = *new_op;
+
+                // The reduce takes the place of the consumer
+                move_outgoing_edge(consumer, reduce, &mut graph.graph);
+                move_references(
+                    &mut remap,
+                    &mut graph.no_delete,
+                    &mut graph.to_retrieve,
+                    consumer,
+                    reduce,
                 );
-                for (inp_ind, _, sh) in &edges {
-                    let (ind, val) = get_idx_valid_exps(*sh);
-                    if (sh.is_contiguous() && !sh.is_sliced() && !sh.is_padded())
-                        || (!sh.is_sliced() && !sh.is_padded())
+                graph.graph.remove_node(consumer);
+                for constant in constants {
+                    remove_unused_scalar(graph, constant);
+                }
+                graph.record_rewrite("ReduceEpilogue");
+            }
+        }
+    }
+}
+
+/// The elementwise equation of an op, unless it reads baked weights
+fn consumer_equation<T: MetalFloat>(graph: &mut Graph, node: NodeIndex) -> Option<String> {
+    if let Some(fused) = graph
+        .graph
+        .node_weight(node)?
+        .as_any()
+        .downcast_ref::<FusedElementwiseOp<T>>()
+    {
+        return fused.baked.is_empty().then(|| fused.equation.clone());
+    }
+    graph.node_custom::<String, _>(node, "elementwise", ())
+}
+
+/// An equation for a scalar computed only from constants, like the reciprocal of a reduced dimension's size
+fn scalar_equation<T: MetalFloat>(
+    graph: &mut Graph,
+    node: NodeIndex,
+    depth: usize,
+) -> Option<String> {
+    if let Some(constant) = graph
+        .graph
+        .node_weight(node)?
+        .as_any()
+        .downcast_ref::<MetalConstant<T>>()
+    {
+        return match &constant.0 {
+            ConstantValue::Float(f) => Some(f.to_string()),
+            // Only expressions that don't depend on dyn dims can be written into the kernel
+            ConstantValue::Expression(e) => e
+                .exec(&FxHashMap::default())
+                .map(|v| (v as f32).to_string()),
+        };
+    }
+    if depth == 0 || graph.no_delete.contains(&node) {
+        return None;
+    }
+    let equation = consumer_equation::<T>(graph, node)?;
+    let mut replacements = vec![];
+    for (src, (inp, _, sh)) in graph
+        .graph
+        .edges_directed(node, Direction::Incoming)
+        .filter_map(|e| e.weight().as_data().map(|d| (e.source(), d)))
+        .collect_vec()
+    {
+        if sh.n_physical_elements().to_usize() != Some(1) {
+            return None;
+        }
+        let eq = scalar_equation::<T>(graph, src, depth - 1)?;
+        replacements.push((format!("input{inp}"), format!("({eq})")));
+    }
+    Some(multi_replace(&equation, &replacements))
+}
+
+/// Remove a scalar folded into an epilogue, and the constants it was computed from, once nothing else uses them
+fn remove_unused_scalar(graph: &mut Graph, node: NodeIndex) {
+    if graph.graph.node_weight(node).is_none()
+        || graph
+            .graph
+            .edges_directed(node, Direction::Outgoing)
+            .next()
+            .is_some()
+        || graph.no_delete.contains(&node)
+        || graph.to_retrieve.contains(&node)
+    {
+        return;
+    }
+    let srcs = graph
+        .get_sources(node)
+        .into_iter()
+        .map(|(src, _, _)| src)
+        .collect_vec();
+    graph.graph.remove_node(node);
+    for src in srcs {
+        remove_unused_scalar(graph, src);
+    }
+}
+
+/// Largest weight (in elements) that gets baked into a kernel
+pub const MAX_BAKED_WEIGHT_SIZE: usize = 1024;
+
+/// Bake small weights into fused elementwise kernels as constant arrays, removing their buffer inputs.
+///
+/// Only inputs that are marked to be kept and already have data at compile time are baked, so weights must be loaded
+/// (by running the graph once) before compiling. Changing a baked tensor after compiling has no effect.
+#[derive(Default, Debug)]
+pub struct WeightBakingCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for WeightBakingCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        for node in graph.graph.node_indices().collect_vec() {
+            if !graph
+                .graph
+                .node_weight(node)
+                .unwrap()
+                .as_any()
+                .is::<FusedElementwiseOp<T>>()
+            {
+                continue;
+            }
+            let mut baked_any = false;
+            loop {
+                let inputs = graph
+                    .graph
+                    .edges_directed(node, Direction::Incoming)
+                    .filter_map(|e| e.weight().as_data().map(|i| (e.id(), e.source(), i)))
+                    .sorted_by_key(|(_, _, (inp, _, _))| *inp)
+                    .collect_vec();
+                // Always leave at least one buffer input
+                if inputs.len() < 2 {
+                    break;
+                }
+                let Some((edge, src, inp_ind, data, shape)) =
+                    inputs.iter().find_map(|(edge, src, (inp, out, sh))| {
+                        loaded_weight::<T>(graph, *src, *out, *sh)
+                            .map(|d| (*edge, *src, *inp, d, *sh))
+                    })
+                else {
+                    break;
+                };
+
+                // Remove the input and shift the later ones down
+                graph.graph.remove_edge(edge);
+                for edge in graph
+                    .graph
+                    .edges_directed(node, Direction::Incoming)
+                    .map(|e| e.id())
+                    .collect_vec()
+                {
+                    if let Some(Dependency::Data { input_order, .. }) =
+                        graph.graph.edge_weight_mut(edge)
                     {
-                        op.equation = op.equation.replace(
-                            &format!("input{inp_ind}"),
-                            &format!("(float)input{inp_ind}[{ind}]"),
-                        );
-                    } else {
-                        op.equation = op.equation.replace(
-                            &format!("input{inp_ind}"),
-                            &format!("(({val} != 0) ? (float)input{inp_ind}[{ind}] : 0.0)"),
-                        );
+                        if *input_order > inp_ind {
+                            *input_order -= 1;
+                        }
                     }
                 }
-                let kernel = format!(
-                    "
-#include <metal_stdlib>
-using namespace metal;
-kernel void mkernel({} device {type_name} *out [[buffer({})]], device uint& n_elements [[buffer({})]], uint idx [[thread_position_in_grid]]{rendered}) {{
-    if (idx < n_elements) {{
-        out[idx] = ({type_name})({});
-    }}
-}}",
-                    edges
-                        .iter()
-                        .map(|(inp_ind, _, _)| format!(
-                            "device {type_name}* input{inp_ind} [[buffer({inp_ind})]],"
-                        ))
-                        .collect_vec()
-                        .join(" "),
-                    edges.len(),
-                    edges.len() + 1,
-                    op.equation
-                );
-                op.kernel = Some(compile_function("mkernel", &kernel, &device));
-                op.dyn_chars = dyn_chars;
+                let op = graph
+                    .graph
+                    .node_weight_mut(node)
+                    .unwrap()
+                    .as_any_mut()
+                    .downcast_mut::<FusedElementwiseOp<T>>()
+                    .unwrap();
+                let mut replacements = vec![(
+                    format!("input{inp_ind}"),
+                    format!("baked{}", op.baked.len()),
+                )];
+                for (_, _, (inp, _, _)) in &inputs {
+                    if *inp > inp_ind {
+                        replacements.push((format!("input{inp}"), format!("input{}", inp - 1)));
+                    }
+                }
+                op.equation = multi_replace(&op.equation, &replacements);
+                op.baked.push((data, shape));
+                baked_any = true;
+
+                // Remove the copy if nothing else uses it
+                if graph.graph.edges_directed(src, Direction::Outgoing).count() == 0
+                    && !graph.no_delete.contains(&src)
+                    && !graph.to_retrieve.contains(&src)
+                {
+                    graph.graph.remove_node(src);
+                }
+            }
+            if baked_any {
+                let input_shapes = graph
+                    .get_sources(node)
+                    .into_iter()
+                    .map(|(_, _, sh)| sh)
+                    .collect_vec();
+                graph
+                    .graph
+                    .node_weight_mut(node)
+                    .unwrap()
+                    .as_any_mut()
+                    .downcast_mut::<FusedElementwiseOp<T>>()
+                    .unwrap()
+                    .compile(&input_shapes);
             }
         }
     }
 }
 
+/// Get the data of a small, kept tensor that's already loaded, either on device or on the host behind a copy
+fn loaded_weight<T: MetalFloat>(
+    graph: &Graph,
+    src: NodeIndex,
+    output: u8,
+    shape: ShapeTracker,
+) -> Option<Vec<f32>> {
+    let n_elements = shape.n_physical_elements().to_usize()?;
+    if n_elements > MAX_BAKED_WEIGHT_SIZE || !render_dyn_dim_inputs(&[shape], 0).0.is_empty() {
+        return None;
+    }
+    let data = if let Some(tensor) = graph.tensors.get(&(src, output)) {
+        if !graph.no_delete.contains(&src) {
+            return None;
+        }
+        let buffer = tensor.data.as_any().downcast_ref::<MetalBuffer>()?;
+        sync_for_cpu(&Device::system_default()?, buffer);
+        let len = buffer.length() as usize / std::mem::size_of::<T>();
+        unsafe { std::slice::from_raw_parts(buffer.contents() as *const T, len) }
+            .iter()
+            .map(|v| v.to_f32())
+            .collect_vec()
+    } else if graph
+        .graph
+        .node_weight(src)?
+        .as_any()
+        .is::<MetalCopyToDevice<T>>()
+    {
+        let (host, host_output, _) = graph.get_sources(src).pop()?;
+        if !graph.no_delete.contains(&host) {
+            return None;
+        }
+        graph
+            .tensors
+            .get(&(host, host_output))?
+            .data
+            .as_any()
+            .downcast_ref::<Vec<f32>>()?
+            .clone()
+    } else {
+        return None;
+    };
+    if data.len() < n_elements || data.iter().any(|v| !v.is_finite()) {
+        return None;
+    }
+    Some(data)
+}
+
+/// Replace names in an equation all at once, so replaced text is never replaced again. Only whole names match, so
+/// `input1` doesn't replace the start of `input10`.
 fn multi_replace(input: &str, replacements: &[(String, String)]) -> String {
     // Use Unicode Private Use Areas as unlikely placeholders
     // Starting at U+E000
@@ -319,9 +621,9 @@ fn multi_replace(input: &str, replacements: &[(String, String)]) -> String {
         placeholders.push((from.clone(), placeholder));
     }
 
-    // First pass: Replace all target strings with placeholders
+    // First pass: Replace all target names with placeholders
     for (from, placeholder) in &placeholders {
-        output = output.replace(from, &placeholder.to_string());
+        output = replace_name(&output, from, &placeholder.to_string());
     }
 
     // Second pass: Replace placeholders with final strings
@@ -332,19 +634,123 @@ fn multi_replace(input: &str, replacements: &[(String, String)]) -> String {
     output
 }
 
+/// Replace each whole occurrence of a name, skipping ones that are the start of a longer name
+fn replace_name(input: &str, from: &str, to: &str) -> String {
+    let mut output = String::new();
+    let mut rest = input;
+    while let Some(start) = rest.find(from) {
+        let end = start + from.len();
+        output.push_str(&rest[..start]);
+        if rest[end..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+            output.push_str(from);
+        } else {
+            output.push_str(to);
+        }
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
 #[derive(LuminalPrint, LuminalEqFalse, Clone)]
 pub struct FusedElementwiseOp<T> {
     kernel: Option<ComputePipelineState>,
+    /// Whether the inputs are contiguous, so the kernel handles 4 elements per thread
+    vectorized: bool,
     dyn_map: *const FxHashMap<char, usize>,
     dyn_chars: Vec<char>,
     equation: String,
+    /// Weights baked into the kernel as constant arrays, referenced in the equation as baked0, baked1, etc.
+    baked: Vec<(Vec<f32>, ShapeTracker)>,
     queue: CommandQueue,
     device: Device,
     _phantom: PhantomData<T>,
+    /// Whether the op was created under fast math, so later passes compile its kernel the same way
+    fast_math: bool,
+}
+
+impl<T: MetalFloat> FusedElementwiseOp<T> {
+    /// Render the equation into a kernel for these input shapes and compile it
+    fn compile(&mut self, input_shapes: &[ShapeTracker]) {
+        let type_name = T::type_name();
+        self.vectorized = self.baked.is_empty() && is_vectorizable::<T>(input_shapes);
+        if self.vectorized {
+            let kernel =
+                render_vectorized_elementwise(type_name, input_shapes.len(), &self.equation);
+            self.kernel = Some(with_fast_math(self.fast_math, || {
+                compile_function("mkernel", &kernel, &self.device)
+            }));
+            self.dyn_chars = vec![];
+            return;
+        }
+        let (dyn_chars, rendered) = render_dyn_dim_inputs(input_shapes, input_shapes.len() + 2);
+        let replacements = input_shapes
+            .iter()
+            .enumerate()
+            .map(|(inp_ind, sh)| (format!("input{inp_ind}"), *sh))
+            .chain(
+                self.baked
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, sh))| (format!("baked{i}"), *sh)),
+            )
+            .map(|(name, sh)| {
+                let read = render_input_read(&name, sh);
+                (name, read)
+            })
+            .collect_vec();
+        let equation = multi_replace(&self.equation, &replacements);
+        let constants = self
+            .baked
+            .iter()
+            .enumerate()
+            .map(|(i, (data, _))| {
+                format!(
+                    "constant float baked{i}[{}] = {{{}}};",
+                    data.len(),
+                    data.iter().map(|v| format!("{v:?}f")).join(", ")
+                )
+            })
+            .join("\n");
+        let kernel = format!(
+            "
+#include <metal_stdlib>
+using namespace metal;
+{constants}
+kernel void mkernel({} device {type_name} *out [[buffer({})]], device uint& n_elements [[buffer({})]], uint idx [[thread_position_in_grid]]{rendered}) {{
+    if (idx < n_elements) {{
+        out[idx] = ({type_name})({equation});
+    }}
+}}",
+            (0..input_shapes.len())
+                .map(|inp_ind| format!(
+                    "device {type_name}* input{inp_ind} [[buffer({inp_ind})]],"
+                ))
+                .collect_vec()
+                .join(" "),
+            input_shapes.len(),
+            input_shapes.len() + 1,
+        );
+        self.kernel = Some(with_fast_math(self.fast_math, || {
+            compile_function("mkernel", &kernel, &self.device)
+        }));
+        self.dyn_chars = dyn_chars;
+    }
+}
+
+/// Render an indexed read of an input, only guarding it with the valid expression if some index can be invalid
+fn render_input_read(name: &str, shape: ShapeTracker) -> String {
+    let (ind, val) = get_idx_valid_exps(shape);
+    if shape.is_always_valid() {
+        format!("(float){name}[{ind}]")
+    } else {
+        format!("(({val} != 0) ? (float){name}[{ind}] : 0.0)")
+    }
 }
+
 impl<T> MetalKernel for FusedElementwiseOp<T> {
     fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
-        if input_shapes.len() == 1 {
+        if input_shapes.len() == 1 && self.baked.is_empty() {
             // Assume since it's a unary op, we're outputting 1-1 elements from input
             vec![input_shapes[0].n_physical_elements() * std::mem::size_of::<T>()]
         } else {
@@ -383,36 +789,39 @@ impl<T> MetalKernel for FusedElementwiseOp<T> {
 
         // Execute
         println!("Out: {:?}", out_size);
-        encoder.dispatch_1d(out_size);
+        if self.vectorized {
+            encoder.dispatch_1d(out_size.div_ceil(VECTOR_WIDTH));
+        } else {
+            encoder.dispatch_1d(out_size);
+        }
         encoder.end_encoding();
     }
 }
 
 impl<T: MetalFloat> Operator for FusedElementwiseOp<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
-            let out = self.device.new_buffer(
+            let out = new_buffer(
+                &self.device,
                 self.output_buffer_sizes(&tensors.iter().map(|(_, s)| *s).collect_vec())[0]
                     .exec(unsafe { self.dyn_map.as_ref().unwrap() })
                     .unwrap() as u64,
-                MTLResourceOptions::StorageModeShared,
             );
 
             self.metal_forward(
-                &tensors
-                    .iter()
-                    .map(|(t, s)| (get_buffer_from_tensor(t).deref(), *s))
-                    .collect_vec(),
+                &get_buffers_from_tensors(&tensors)?,
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -440,7 +849,7 @@ mod tests {
         tests::{assert_close, random_vec},
     };
 
-    use crate::MetalCompiler;
+    use crate::{FrozenMetalCompiler, MetalAuditCompiler, MetalCompiler};
     #[test]
     fn test_fusion() {
         let mut cx = Graph::new();
@@ -457,4 +866,151 @@ mod tests {
 
         assert_close(&c.data(), &unopt_c);
     }
+
+    #[test]
+    fn test_multi_replace_whole_names() {
+        let replacements = (0..11)
+            .map(|i| (format!("input{i}"), format!("v{}", i + 1)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            super::multi_replace("input1 + input10 * input0", &replacements),
+            "v2 + v11 * v1"
+        );
+        assert_eq!(
+            super::multi_replace(
+                "input1 + input10",
+                &[("input1".to_string(), "(input0)".to_string())]
+            ),
+            "(input0) + input10"
+        );
+    }
+
+    #[test]
+    fn test_fusion_many_inputs() {
+        let mut cx = Graph::new();
+        let inputs = (0..11)
+            .map(|i| {
+                cx.named_tensor::<R1<10>>(&format!("x{i}"))
+                    .set(random_vec(10))
+                    .keep()
+            })
+            .collect::<Vec<_>>();
+        // Scale each input differently so mixing up any two of them changes the result
+        let mut c = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x.sin() * (i + 1) as f32)
+            .reduce(|a, b| a + b)
+            .unwrap()
+            .retrieve();
+
+        cx.execute();
+        let unopt_c = c.data();
+        c.drop();
+
+        cx.compile(<(GenericCompiler, MetalCompiler<f32>)>::default(), &mut c);
+        cx.execute();
+
+        assert_close(&c.data(), &unopt_c);
+    }
+
+    #[test]
+    fn test_weight_baking() {
+        let build = || {
+            let mut cx = Graph::new();
+            let a = cx.named_tensor::<R1<10>>("a").set(random_vec(10)).keep();
+            let w = cx.named_tensor::<R1<10>>("w").set(random_vec(10)).keep();
+            let c = ((a.exp2() + 1.) * w).retrieve();
+            cx.execute();
+            let unopt_c = c.data();
+            c.drop();
+            (cx, c, unopt_c)
+        };
+        // Total buffer bindings across fused kernels
+        let bindings = |cx: &Graph| {
+            cx.graph
+                .node_indices()
+                .filter(|n| {
+                    format!("{:?}", cx.graph.node_weight(*n).unwrap()).contains("FusedElementwise")
+                })
+                .map(|n| cx.get_sources(n).len())
+                .sum::<usize>()
+        };
+
+        let (mut cx, mut c, unopt_c) = build();
+        cx.compile(<(GenericCompiler, MetalCompiler<f32>)>::default(), &mut c);
+        let unbaked_bindings = bindings(&cx);
+        cx.execute();
+        assert_close(&c.data(), &unopt_c);
+
+        let (mut cx, mut c, unopt_c) = build();
+        cx.compile(
+            <(GenericCompiler, FrozenMetalCompiler<f32>)>::default(),
+            &mut c,
+        );
+        assert!(bindings(&cx) < unbaked_bindings);
+        cx.execute();
+        assert_close(&c.data(), &unopt_c);
+    }
+
+    #[test]
+    fn test_reduce_epilogue() {
+        type WithoutEpilogues = (
+            crate::prim::PrimitiveCompiler<f32>,
+            crate::SpecialOpsCompiler<f32>,
+            crate::other::CopyCompiler<f32>,
+            crate::other::ContiguousElimination<f32>,
+            super::ElementwiseFusionCompiler<f32>,
+        );
+        // Kernels launched, leaving out loads, copies and constants
+        let kernels = |cx: &Graph| {
+            cx.graph
+                .node_weights()
+                .map(|op| format!("{op:?}"))
+                .filter(|n| !n.contains("Load") && !n.contains("Copy") && !n.contains("Constant"))
+                .count()
+        };
+        for layer_norm in [false, true] {
+            let build = || {
+                let mut cx = Graph::new();
+                let a = cx.named_tensor::<R2<4, 64>>("a").set(random_vec(4 * 64));
+                let b = if layer_norm {
+                    a.layer_norm::<1, _>(1e-5)
+                } else {
+                    a.std_norm::<1, _>(1e-5)
+                }
+                .retrieve();
+                cx.execute();
+                let unopt_b = b.data();
+                b.drop();
+                (cx, b, unopt_b)
+            };
+
+            let (mut cx, mut b, _) = build();
+            cx.compile(<(GenericCompiler, WithoutEpilogues)>::default(), &mut b);
+            let unfused_kernels = kernels(&cx);
+
+            let (mut cx, mut b, unopt_b) = build();
+            cx.compile(
+                <(GenericCompiler, MetalAuditCompiler<f32>)>::default(),
+                &mut b,
+            );
+            // The scale, epsilon, sqrt and reciprocal after each sum are stored by the reduce
+            assert!(kernels(&cx) + 2 <= unfused_kernels);
+            assert!(cx
+                .graph
+                .node_weights()
+                .filter_map(|op| op
+                    .as_any()
+                    .downcast_ref::<crate::prim::MetalSumReduce<f32>>())
+                .all(|op| op.epilogue.is_some()));
+            cx.execute();
+            assert_close(&b.data(), &unopt_b);
+
+            let (mut cx, mut b, unopt_b) = build();
+            cx.compile(<(GenericCompiler, MetalCompiler<f32>)>::default(), &mut b);
+            cx.execute();
+            assert_close(&b.data(), &unopt_b);
+        }
+    }
 }
@@ -0,0 +1,137 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use luminal::{
+    op::{EmbeddingBagMode, InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::BigExpression,
+};
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device, MTLSize,
+};
+
+use crate::{
+    compile_function, finish_command_buffer, get_buffers_from_tensors, new_buffer, MetalBuffer,
+    MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
+};
+
+const THREADGROUP_SIZE: usize = 256;
+
+/// Gather and combine the table rows of each bag of indices.
+///
+/// Each bag gets a threadgroup, and threads stride over the embedding dim, walking the bag's indices in order.
+/// Accumulation is in fp32. Empty bags write zeros. Indices and offsets are stored as T, so in fp16 values above 2048
+/// aren't exactly representable.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct MetalEmbeddingBag<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: MetalFloat> MetalEmbeddingBag<T> {
+    pub fn new(mode: EmbeddingBagMode, device: Device, queue: CommandQueue) -> Self {
+        let type_name = T::type_name();
+        let (init, combine, finish) = match mode {
+            EmbeddingBagMode::Sum => ("0.0", "acc += v;", "acc"),
+            EmbeddingBagMode::Mean => ("0.0", "acc += v;", "acc / (float)(end - start)"),
+            EmbeddingBagMode::Max => ("-INFINITY", "acc = max(acc, v);", "acc"),
+        };
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+
+kernel void mkernel(device {type_name} *table [[buffer(0)]], device {type_name} *indices [[buffer(1)]], device {type_name} *offsets [[buffer(2)]], device {type_name} *out [[buffer(3)]], device uint& dim [[buffer(4)]], device uint& n_indices [[buffer(5)]], device uint& n_bags [[buffer(6)]], uint bag [[threadgroup_position_in_grid]], uint tid [[thread_position_in_threadgroup]], uint tg_size [[threads_per_threadgroup]]) {{
+    uint start = (uint)(float)offsets[bag];
+    uint end = bag + 1 < n_bags ? (uint)(float)offsets[bag + 1] : n_indices;
+    for (uint d = tid; d < dim; d += tg_size) {{
+        if (start >= end) {{
+            out[bag * dim + d] = 0.0;
+            continue;
+        }}
+        float acc = {init};
+        for (uint i = start; i < end; i++) {{
+            float v = (float)table[(uint)(float)indices[i] * dim + d];
+            {combine}
+        }}
+        out[bag * dim + d] = ({type_name})({finish});
+    }}
+}}
+");
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalEmbeddingBag<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![input_shapes[2].n_elements() * input_shapes[0].shape()[1].clone() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let n_bags = inputs[2].1.n_elements().to_usize().unwrap();
+        let dim = inputs[0].1.shape()[1].to_usize().unwrap();
+        if n_bags == 0 || dim == 0 {
+            return;
+        }
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        encoder.set_buffer(2, Some(inputs[2].0), 0);
+        encoder.set_buffer(3, Some(output_buffers[0]), 0);
+        encoder.set_u32(4, dim as u32);
+        encoder.set_u32(5, inputs[1].1.n_elements().to_usize().unwrap() as u32);
+        encoder.set_u32(6, n_bags as u32);
+
+        // Execute one threadgroup per bag
+        encoder.dispatch_thread_groups(
+            MTLSize::new(n_bags as u64, 1, 1),
+            MTLSize::new(THREADGROUP_SIZE.min(dim) as u64, 1, 1),
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalEmbeddingBag<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let n_out = tensors[2].1.n_elements().to_usize().unwrap()
+                * tensors[0].1.shape()[1].to_usize().unwrap();
+            let out = new_buffer(&self.device, (n_out * size_of::<T>()) as u64);
+            let inputs = get_buffers_from_tensors(&tensors)?;
+
+            let command_buffer = self.queue.new_command_buffer();
+            self.metal_forward(&inputs, command_buffer, &[], &[&out]);
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
@@ -0,0 +1,270 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use luminal::{
+    compiler_internals::{petgraph::stable_graph::NodeIndex, *},
+    op::{InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::BigExpression,
+};
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device,
+};
+
+use crate::{
+    compile_function, finish_command_buffer, get_buffer_from_tensor, new_buffer,
+    prim::MetalContiguous, DispatchNElements, MetalBuffer, MetalFloat, MetalKernel,
+    MetalKernelWrapper, SetInt,
+};
+
+/// The patch matrix of a convolution, (channels * kernel x * kernel y, out x * out y), read from a dense
+/// (channels, x, y) input.
+///
+/// Each thread writes one element, reading zero where the patch hangs over the padding.
+#[derive(LuminalPrint, Clone)]
+pub struct MetalIm2Col<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub params: Im2Col,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> PartialEq for MetalIm2Col<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.params == other.params
+    }
+}
+
+impl<T: MetalFloat> MetalIm2Col<T> {
+    pub fn new(params: Im2Col, device: Device, queue: CommandQueue) -> Self {
+        let type_name = T::type_name();
+        let [dim_x, dim_y] = params.dims;
+        let [kernel_x, kernel_y] = params.kernel;
+        let [stride_x, stride_y] = params.stride;
+        let [pad_x, pad_y] = params.padding;
+        let [out_x, out_y] = params.out_dims();
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *out [[buffer(1)]], device uint& n_elements [[buffer(2)]], uint i_ [[thread_position_in_grid]]) {{
+    if (i_ < n_elements) {{
+        uint col = i_ % {out_xy};
+        uint row = i_ / {out_xy};
+        uint ky = row % {kernel_y};
+        uint kx = (row / {kernel_y}) % {kernel_x};
+        uint c = row / {kernel_xy};
+        int x = (int)((col / {out_y}) * {stride_x} + kx) - {pad_x};
+        int y = (int)((col % {out_y}) * {stride_y} + ky) - {pad_y};
+        bool inside = x >= 0 && x < {dim_x} && y >= 0 && y < {dim_y};
+        out[i_] = inside ? inp[(c * {dim_x} + x) * {dim_y} + y] : ({type_name})0.0;
+    }}
+}}
+", out_xy = out_x * out_y, kernel_xy = kernel_x * kernel_y);
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            params,
+            _phantom: Default::default(),
+        }
+    }
+
+    fn n_elements(&self) -> usize {
+        let [out_x, out_y] = self.params.out_dims();
+        self.params.channels * self.params.kernel[0] * self.params.kernel[1] * out_x * out_y
+    }
+}
+
+impl<T: MetalFloat> MetalKernel for MetalIm2Col<T> {
+    fn output_buffer_sizes(&self, _: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![BigExpression::from(self.n_elements() * size_of::<T>())]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let n_elements = self.n_elements();
+        if n_elements == 0 {
+            return;
+        }
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(output_buffers[0]), 0);
+        encoder.set_u32(2, n_elements as u32);
+
+        // Execute one thread per element
+        encoder.dispatch_1d(n_elements);
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalIm2Col<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let out = new_buffer(
+                &self.device,
+                (self.n_elements() * size_of::<T>()).max(1) as u64,
+            );
+            let inp = get_buffer_from_tensor(&tensors[0].0)?;
+
+            let command_buffer = self.queue.new_command_buffer();
+            self.metal_forward(&[(inp, tensors[0].1)], command_buffer, &[], &[&out]);
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
+/// Build convolution patch matrices hinted with [`CompilerHint::Im2Col`] in one kernel, instead of the chain of
+/// contiguous ops that pads, pools and transposes the input into them
+#[derive(Debug, Default)]
+pub struct Im2ColCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for Im2ColCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        let mut hinted = graph
+            .compiler_hints
+            .iter()
+            .filter_map(|(node, hints)| {
+                hints.iter().find_map(|h| match h {
+                    CompilerHint::Im2Col(params) => Some((*node, *params)),
+                    _ => None,
+                })
+            })
+            .collect::<Vec<_>>();
+        hinted.sort_by_key(|(node, _)| *node);
+
+        for (output, params) in hinted {
+            let Some((chain, (input, input_output))) = expansion_chain::<T>(graph, output, params)
+            else {
+                continue;
+            };
+            let im2col = graph
+                .add_op(MetalIm2Col::<T>::new(params, dev.clone(), queue.clone()))
+                .input(
+                    input,
+                    input_output,
+                    ShapeTracker::new(&[
+                        params.channels.into(),
+                        params.dims[0].into(),
+                        params.dims[1].into(),
+                    ]),
+                )
+                .finish();
+            move_outgoing_edge(output, im2col, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                output,
+                im2col,
+            );
+            for node in chain {
+                graph.graph.remove_node(node);
+                // Removed indexes get reused, so their hints can't stay behind
+                graph.compiler_hints.remove(&node);
+            }
+        }
+    }
+}
+
+/// The contiguous ops an expansion is made of, from its output back to the first, and the input they read. `None` if
+/// other compilers changed them, so the expansion no longer is a plain chain.
+fn expansion_chain<T: MetalFloat>(
+    graph: &Graph,
+    output: NodeIndex,
+    params: Im2Col,
+) -> Option<(Vec<NodeIndex>, (NodeIndex, u8))> {
+    let mut chain = vec![];
+    let mut node = output;
+    for i in 0..params.ops {
+        let op = graph.graph.node_weight(node)?;
+        let consumers = graph
+            .graph
+            .edges_directed(node, petgraph::Direction::Outgoing)
+            .filter(|e| !e.weight().is_schedule())
+            .count();
+        if !op.as_any().is::<MetalContiguous<T>>()
+            || (i > 0 && (consumers != 1 || graph.no_delete.contains(&node)))
+        {
+            return None;
+        }
+        let [(source, output_index, shape)] = graph.get_sources(node)[..] else {
+            return None;
+        };
+        chain.push(node);
+        if i + 1 == params.ops {
+            // The first op reads the dense input
+            let n_input = params.channels * params.dims[0] * params.dims[1];
+            if shape.n_physical_elements().to_usize() != Some(n_input) {
+                return None;
+            }
+            return Some((chain, (source, output_index)));
+        }
+        node = source;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        nn::convolution::Conv2D,
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use super::MetalIm2Col;
+    use crate::MetalCompiler;
+
+    #[test]
+    fn test_im2col_conv2d() {
+        let mut cx = Graph::new();
+        let same: Conv2D<3, 4, 3, 3, 1, 1, 0, 0, 27> =
+            Conv2D::new(&mut cx, true).with_padding(1, 1);
+        let strided: Conv2D<4, 2, 3, 2, 2, 3, 0, 0, 24> =
+            Conv2D::new(&mut cx, false).with_padding(1, 0);
+        let input = cx.tensor::<R3<3, 7, 6>>().set(random_vec(3 * 7 * 6));
+        let mut hidden = same.forward::<7, 6, 7, 6, 42>(input).retrieve();
+        let mut out = strided.forward::<7, 6, 4, 2, 8>(hidden).retrieve();
+        cx.execute();
+        let (cpu_hidden, cpu_out) = (hidden.data(), out.data());
+
+        cx.compile(MetalCompiler::<f32>::default(), (&mut hidden, &mut out));
+        cx.execute();
+
+        assert_close(&hidden.data(), &cpu_hidden);
+        assert_close(&out.data(), &cpu_out);
+        // Both expansions were built by a single kernel
+        let kernels = cx
+            .graph
+            .node_weights()
+            .filter(|op| op.as_any().is::<MetalIm2Col<f32>>())
+            .count();
+        assert_eq!(kernels, 2);
+    }
+}
@@ -22,7 +22,8 @@ template <
   const int BM, /* Threadgroup rows (in threads) */
   const int BN, /* Threadgroup cols (in threads) */
   const int TM, /* Thread rows (in elements) */
-  const int TN > /* Thread cols (in elements) */
+  const int TN, /* Thread cols (in elements) */
+  typename AccT = T> /* Accumulation type */
 struct GEMVKernel {
 
   static_assert(BN == SIMD_SIZE, "gemv block must have a width of SIMD_SIZE");
@@ -66,9 +67,9 @@ struct GEMVKernel {
     threadgroup T* in_vec_block = tgp_memory + simd_lid * TN * 2;
 
     // Thread local accumulation results
-    thread T result[TM] = {0};
-    thread T inter[TN];
-    thread T v_coeff[TN];
+    thread AccT result[TM] = {0};
+    thread AccT inter[TN];
+    thread AccT v_coeff[TN];
 
     // Block position
     int out_row = (tid.x * BM + simd_gid) * TM;
@@ -154,7 +155,7 @@ struct GEMVKernel {
 
       #pragma clang loop unroll(full)
       for(int tm = 0; tm < TM; tm++) {
-        out_vec[out_row + tm] = result[tm];
+        out_vec[out_row + tm] = static_cast<T>(result[tm]);
       }
 
     }
@@ -172,7 +173,8 @@ template <
   const int BM, /* Threadgroup rows (in threads) */
   const int BN, /* Threadgroup cols (in threads) */
   const int TM, /* Thread rows (in elements) */
-  const int TN > /* Thread cols (in elements) */
+  const int TN, /* Thread cols (in elements) */
+  typename AccT = T> /* Accumulation type */
 struct GEMVTKernel {
 
   // - The matrix of size (M = in_vec_size, N = out_vec_size) is divided up
@@ -202,7 +204,7 @@ struct GEMVTKernel {
       device T* out_vec,
       const constant int& in_vec_size [[buffer(3)]],
       const constant int& out_vec_size [[buffer(4)]],
-      threadgroup T* tgp_memory [[threadgroup(0)]],
+      threadgroup AccT* tgp_memory [[threadgroup(0)]],
       uint3 tid [[threadgroup_position_in_grid]],
       uint3 lid [[thread_position_in_threadgroup]],
       uint simd_gid [[simdgroup_index_in_threadgroup]],
@@ -213,12 +215,12 @@ struct GEMVTKernel {
     (void)simd_lid;
 
     // Thread local accumulation results
-    T result[TN] = {0};
-    T inter[TN];
-    T v_coeff[TM];
+    AccT result[TN] = {0};
+    AccT inter[TN];
+    AccT v_coeff[TM];
 
     // Threadgroup accumulation results
-    threadgroup T* tgp_results = tgp_memory + lid.x * BM * TN;
+    threadgroup AccT* tgp_results = tgp_memory + lid.x * BM * TN;
 
     int out_col = (tid.x * BN + lid.x) * TN;
     int in_row = lid.y * TM;
@@ -292,7 +294,7 @@ struct GEMVTKernel {
 
       #pragma clang loop unroll(full)
       for(int j = 0; j < TN; j++) {
-        out_vec[out_col + j] = result[j];
+        out_vec[out_col + j] = static_cast<T>(result[j]);
       }
     }
 
@@ -310,7 +312,8 @@ template <
     const int BM, /* Threadgroup rows (in threads) */
     const int BN, /* Threadgroup cols (in threads) */
     const int TM, /* Thread rows (in elements) */
-    const int TN> /* Thread cols (in elements) */
+    const int TN, /* Thread cols (in elements) */
+    typename AccT = T> /* Accumulation type */
 [[kernel, max_total_threads_per_threadgroup(BM * BN)]] void gemv(
     const device T* mat [[buffer(0)]],
     const device T* in_vec [[buffer(1)]],
@@ -324,7 +327,7 @@ template <
     uint simd_gid [[simdgroup_index_in_threadgroup]],
     uint simd_lid [[thread_index_in_simdgroup]]) {
 
-  using gemv_kernel = GEMVKernel<T, BM, BN, TM, TN>;
+  using gemv_kernel = GEMVKernel<T, BM, BN, TM, TN, AccT>;
   threadgroup T tgp_memory[gemv_kernel::tgp_mem_size];
 
   // Update batch offsets
@@ -437,6 +440,25 @@ instantiate_gemv_blocks(float32, float);
 instantiate_gemv_blocks(float16, half);
 instantiate_gemv_blocks(bfloat16, bfloat16_t);
 
+// Reduced precision inputs accumulated in fp32
+#define instantiate_gemv_acc32(name, itype, bm, bn, tm, tn) \
+  template [[host_name("gemv_" #name "_acc32_bm" #bm "_bn" #bn "_tm" #tm "_tn" #tn)]] \
+  [[kernel]] void gemv<itype, bm, bn, tm, tn, float>( \
+    const device itype* mat [[buffer(0)]], \
+    const device itype* vec [[buffer(1)]], \
+    device itype* out [[buffer(2)]], \
+    const constant int& in_vec_size [[buffer(3)]], \
+    const constant int& out_vec_size [[buffer(4)]], \
+    const constant int& vector_batch_stride [[buffer(5)]], \
+    const constant int& matrix_batch_stride [[buffer(6)]], \
+    uint3 tid [[threadgroup_position_in_grid]], \
+    uint3 lid [[thread_position_in_threadgroup]], \
+    uint simd_gid [[simdgroup_index_in_threadgroup]], \
+    uint simd_lid [[thread_index_in_simdgroup]]);
+
+instantiate_gemv_acc32(float16, half, 8, 32, 4, 4);
+instantiate_gemv_acc32(bfloat16, bfloat16_t, 8, 32, 4, 4);
+
 ///////////////////////////////////////////////////////////////////////////////
 /// Vector matrix multiplication
 ///////////////////////////////////////////////////////////////////////////////
@@ -446,7 +468,8 @@ template <
     const int BM, /* Threadgroup rows (in threads) */
     const int BN, /* Threadgroup cols (in threads) */
     const int TM, /* Thread rows (in elements) */
-    const int TN> /* Thread cols (in elements) */
+    const int TN, /* Thread cols (in elements) */
+    typename AccT = T> /* Accumulation type */
 [[kernel, max_total_threads_per_threadgroup(BM * BN)]] void gemv_t(
     const device T* mat [[buffer(0)]],
     const device T* in_vec [[buffer(1)]],
@@ -460,8 +483,8 @@ template <
     uint simd_gid [[simdgroup_index_in_threadgroup]],
     uint simd_lid [[thread_index_in_simdgroup]]) {
 
-  using gemv_kernel = GEMVTKernel<T, BM, BN, TM, TN>;
-  threadgroup T tgp_memory[gemv_kernel::tgp_mem_size];
+  using gemv_kernel = GEMVTKernel<T, BM, BN, TM, TN, AccT>;
+  threadgroup AccT tgp_memory[gemv_kernel::tgp_mem_size];
 
   // Update batch offsets
   in_vec += tid.z * vector_batch_stride;
@@ -573,3 +596,22 @@ template <
 instantiate_gemv_t_blocks(float32, float);
 instantiate_gemv_t_blocks(float16, half);
 instantiate_gemv_t_blocks(bfloat16, bfloat16_t);
+
+// Reduced precision inputs accumulated in fp32
+#define instantiate_gemv_t_acc32(name, itype, bm, bn, tm, tn) \
+  template [[host_name("gemv_t_" #name "_acc32_bm" #bm "_bn" #bn "_tm" #tm "_tn" #tn)]] \
+  [[kernel]] void gemv_t<itype, bm, bn, tm, tn, float>( \
+    const device itype* mat [[buffer(0)]], \
+    const device itype* vec [[buffer(1)]], \
+    device itype* out [[buffer(2)]], \
+    const constant int& in_vec_size [[buffer(3)]], \
+    const constant int& out_vec_size [[buffer(4)]], \
+    const constant int& vector_batch_stride [[buffer(5)]], \
+    const constant int& matrix_batch_stride [[buffer(6)]], \
+    uint3 tid [[threadgroup_position_in_grid]], \
+    uint3 lid [[thread_position_in_threadgroup]], \
+    uint simd_gid [[simdgroup_index_in_threadgroup]], \
+    uint simd_lid [[thread_index_in_simdgroup]]);
+
+instantiate_gemv_t_acc32(float16, half, 8, 32, 4, 4);
+instantiate_gemv_t_acc32(bfloat16, bfloat16_t, 8, 32, 4, 4);
@@ -18,20 +18,20 @@ inline T softmax_exp(T x) {
   return fast::exp(x);
 }
 
-template <typename T, int N_READS = SOFTMAX_N_READS>
+template <typename T, typename AccT = T, int N_READS = SOFTMAX_N_READS>
 [[kernel]] void softmax_single_row(
     const device T* in,
     device T* out,
     constant int& axis_size,
-    threadgroup T* local_max [[threadgroup(0)]],
-    threadgroup T* local_normalizer [[threadgroup(1)]],
+    threadgroup AccT* local_max [[threadgroup(0)]],
+    threadgroup AccT* local_normalizer [[threadgroup(1)]],
     uint gid [[threadgroup_position_in_grid]],
     uint _lid [[thread_position_in_threadgroup]],
     uint simd_lane_id [[thread_index_in_simdgroup]],
     uint simd_group_id [[simdgroup_index_in_threadgroup]]) {
   int lid = _lid;
 
-  T ld[N_READS];
+  AccT ld[N_READS];
 
   in += gid * axis_size + lid * N_READS;
   if (lid * N_READS + N_READS <= axis_size) {
@@ -41,17 +41,17 @@ template <typename T, int N_READS = SOFTMAX_N_READS>
   } else {
       for (int i = 0; i < N_READS; i++) {
         ld[i] =
-            ((lid * N_READS + i) < axis_size) ? in[i] : T(Limits<T>::finite_min);
+            ((lid * N_READS + i) < axis_size) ? AccT(in[i]) : AccT(Limits<AccT>::finite_min);
       }
   }
   if (simd_group_id == 0) {
-    local_max[simd_lane_id] = Limits<T>::finite_min;
+    local_max[simd_lane_id] = Limits<AccT>::finite_min;
     local_normalizer[simd_lane_id] = 0;
   }
   threadgroup_barrier(mem_flags::mem_threadgroup);
 
   // Get the max
-  T maxval = Limits<T>::finite_min;
+  AccT maxval = Limits<AccT>::finite_min;
   for (int i = 0; i < N_READS; i++) {
     maxval = (maxval < ld[i]) ? ld[i] : maxval;
   }
@@ -70,9 +70,9 @@ template <typename T, int N_READS = SOFTMAX_N_READS>
   maxval = local_max[0];
 
   // Compute exp(x_i - maxval) and store the partial sums in local_normalizer
-  T normalizer = 0;
+  AccT normalizer = 0;
   for (int i = 0; i < N_READS; i++) {
-    T exp_x = softmax_exp(ld[i] - maxval);
+    AccT exp_x = softmax_exp(ld[i] - maxval);
     ld[i] = exp_x;
     normalizer += exp_x;
   }
@@ -88,30 +88,31 @@ template <typename T, int N_READS = SOFTMAX_N_READS>
     }
   }
   threadgroup_barrier(mem_flags::mem_threadgroup);
-  normalizer = 1 / local_normalizer[0];
+  // A row of only -inf, like a fully masked one, sums to 0. Write zeros for it rather than NaNs
+  normalizer = local_normalizer[0] == 0 ? AccT(0) : 1 / local_normalizer[0];
 
   // Normalize and write to the output
   out += gid * axis_size + lid * N_READS;
   if (lid * N_READS + N_READS <= axis_size) {
     for (int i=0; i<N_READS; i++) {
-        out[i] = ld[i] * normalizer;
+        out[i] = static_cast<T>(ld[i] * normalizer);
     }
   } else {
       for (int i = 0; i < N_READS; i++) {
         if ((lid * N_READS + i) < axis_size) {
-          out[i] = ld[i] * normalizer;
+          out[i] = static_cast<T>(ld[i] * normalizer);
         }
       }
   }
 }
 
-template <typename T, int N_READS = SOFTMAX_N_READS>
+template <typename T, typename AccT = T, int N_READS = SOFTMAX_N_READS>
 [[kernel]] void softmax_looped(
     const device T* in,
     device T* out,
     constant int& axis_size,
-    threadgroup T* local_max [[threadgroup(0)]],
-    threadgroup T* local_normalizer [[threadgroup(1)]],
+    threadgroup AccT* local_max [[threadgroup(0)]],
+    threadgroup AccT* local_normalizer [[threadgroup(1)]],
     uint gid [[threadgroup_position_in_grid]],
     uint lid [[thread_position_in_threadgroup]],
     uint lsize [[threads_per_threadgroup]],
@@ -120,13 +121,13 @@ template <typename T, int N_READS = SOFTMAX_N_READS>
   in += gid * axis_size;
 
   // Get the max and the normalizer in one go
-  T prevmax;
-  T maxval = Limits<T>::finite_min;
-  T normalizer = 0;
+  AccT prevmax;
+  AccT maxval = Limits<AccT>::finite_min;
+  AccT normalizer = 0;
   for (int r = 0; r < static_cast<int>(ceildiv(axis_size, N_READS * lsize));
        r++) {
     int offset = r * lsize * N_READS + lid * N_READS;
-    T vals[N_READS];
+    AccT vals[N_READS];
     if (offset + N_READS <= axis_size) {
       for (int i = 0; i < N_READS; i++) {
         vals[i] = in[offset + i];
@@ -134,7 +135,7 @@ template <typename T, int N_READS = SOFTMAX_N_READS>
     } else {
       for (int i = 0; i < N_READS; i++) {
         vals[i] =
-            (offset + i < axis_size) ? in[offset + i] : T(Limits<T>::finite_min);
+            (offset + i < axis_size) ? AccT(in[offset + i]) : AccT(Limits<AccT>::finite_min);
       }
     }
     prevmax = maxval;
@@ -171,7 +172,8 @@ template <typename T, int N_READS = SOFTMAX_N_READS>
   }
   threadgroup_barrier(mem_flags::mem_threadgroup);
   normalizer = simd_sum(local_normalizer[simd_lane_id]);
-  normalizer = 1 / normalizer;
+  // A row of only -inf, like a fully masked one, sums to 0. Write zeros for it rather than NaNs
+  normalizer = normalizer == 0 ? AccT(0) : 1 / normalizer;
 
   // Finally given the normalizer and max value we can directly write the
   // softmax output
@@ -181,18 +183,114 @@ template <typename T, int N_READS = SOFTMAX_N_READS>
     int offset = r * lsize * N_READS + lid * N_READS;
     if (offset + N_READS <= axis_size) {
       for (int i=0; i<N_READS; i++) {
-        out[offset + i] = softmax_exp(in[offset + i] - maxval) * normalizer;
+        out[offset + i] = static_cast<T>(softmax_exp(AccT(in[offset + i]) - maxval) * normalizer);
       }
     } else {
       for (int i = 0; i < N_READS; i++) {
         if (offset + i < axis_size) {
-          out[offset + i] = softmax_exp(in[offset + i] - maxval) * normalizer;
+          out[offset + i] = static_cast<T>(softmax_exp(AccT(in[offset + i]) - maxval) * normalizer);
         }
       }
     }
   }
 }
 
+// Rows too long for one threadgroup to get through quickly are split into chunks of chunk_size elements, each
+// handled by its own threadgroup. The first stage writes the max and the sum of exp(x - max) of each chunk, and the
+// second merges the chunks of the row and normalizes its chunk with them.
+template <typename T, typename AccT = T>
+[[kernel]] void softmax_split_partials(
+    const device T* in,
+    device float* partials,
+    constant int& axis_size,
+    constant int& chunk_size,
+    threadgroup AccT* local_max [[threadgroup(0)]],
+    threadgroup AccT* local_normalizer [[threadgroup(1)]],
+    uint3 gid [[threadgroup_position_in_grid]],
+    uint3 n_groups [[threadgroups_per_grid]],
+    uint3 lid [[thread_position_in_threadgroup]],
+    uint3 lsize [[threads_per_threadgroup]],
+    uint simd_lane_id [[thread_index_in_simdgroup]],
+    uint simd_group_id [[simdgroup_index_in_threadgroup]]) {
+  int start = gid.x * chunk_size;
+  int end = min(start + chunk_size, axis_size);
+  in += gid.y * axis_size;
+  if (simd_group_id == 0) {
+    local_max[simd_lane_id] = Limits<AccT>::finite_min;
+    local_normalizer[simd_lane_id] = 0;
+  }
+
+  // Running max and normalizer of the values this thread reads
+  AccT prevmax;
+  AccT maxval = Limits<AccT>::finite_min;
+  AccT normalizer = 0;
+  for (int i = start + static_cast<int>(lid.x); i < end; i += lsize.x) {
+    AccT val = in[i];
+    prevmax = maxval;
+    maxval = (maxval < val) ? val : maxval;
+    normalizer = normalizer * softmax_exp(prevmax - maxval) + softmax_exp(val - maxval);
+  }
+
+  // Combine them across the threadgroup, like the looped kernel
+  prevmax = maxval;
+  maxval = simd_max(maxval);
+  normalizer *= softmax_exp(prevmax - maxval);
+  normalizer = simd_sum(normalizer);
+  threadgroup_barrier(mem_flags::mem_threadgroup);
+  prevmax = maxval;
+  if (simd_lane_id == 0) {
+    local_max[simd_group_id] = maxval;
+  }
+  threadgroup_barrier(mem_flags::mem_threadgroup);
+  maxval = simd_max(local_max[simd_lane_id]);
+  normalizer *= softmax_exp(prevmax - maxval);
+  if (simd_lane_id == 0) {
+    local_normalizer[simd_group_id] = normalizer;
+  }
+  threadgroup_barrier(mem_flags::mem_threadgroup);
+  normalizer = simd_sum(local_normalizer[simd_lane_id]);
+
+  if (lid.x == 0) {
+    partials += (gid.y * n_groups.x + gid.x) * 2;
+    partials[0] = static_cast<float>(maxval);
+    partials[1] = static_cast<float>(normalizer);
+  }
+}
+
+template <typename T, typename AccT = T>
+[[kernel]] void softmax_split_normalize(
+    const device T* in,
+    device T* out,
+    const device float* partials,
+    constant int& axis_size,
+    constant int& chunk_size,
+    uint3 gid [[threadgroup_position_in_grid]],
+    uint3 n_groups [[threadgroups_per_grid]],
+    uint3 lid [[thread_position_in_threadgroup]],
+    uint3 lsize [[threads_per_threadgroup]]) {
+  // Merge the chunks of the row: the sum of exp(x - max) over the row is the sum of each chunk's sum scaled by
+  // exp(chunk max - max)
+  partials += gid.y * n_groups.x * 2;
+  float maxval = Limits<float>::finite_min;
+  for (uint c = 0; c < n_groups.x; c++) {
+    maxval = (maxval < partials[c * 2]) ? partials[c * 2] : maxval;
+  }
+  float normalizer = 0;
+  for (uint c = 0; c < n_groups.x; c++) {
+    normalizer += partials[c * 2 + 1] * softmax_exp(partials[c * 2] - maxval);
+  }
+  // A row of only -inf, like a fully masked one, sums to 0. Write zeros for it rather than NaNs
+  AccT scale = normalizer == 0 ? AccT(0) : AccT(1 / normalizer);
+
+  int start = gid.x * chunk_size;
+  int end = min(start + chunk_size, axis_size);
+  in += gid.y * axis_size;
+  out += gid.y * axis_size;
+  for (int i = start + static_cast<int>(lid.x); i < end; i += lsize.x) {
+    out[i] = static_cast<T>(softmax_exp(AccT(in[i]) - AccT(maxval)) * scale);
+  }
+}
+
 #define instantiate_softmax_single_row(name, itype)           \
   template [[host_name("softmax_" #name)]] [[kernel]] void    \
   softmax_single_row<itype>(                                  \
@@ -201,7 +299,7 @@ template <typename T, int N_READS = SOFTMAX_N_READS>
       constant int& axis_size,                                \
       threadgroup itype* local_max [[threadgroup(0)]],        \
       threadgroup itype* local_normalizer [[threadgroup(1)]], \
-      uint gid [[thread_position_in_grid]],                   \
+      uint gid [[threadgroup_position_in_grid]],              \
       uint _lid [[thread_position_in_threadgroup]],           \
       uint simd_lane_id [[thread_index_in_simdgroup]],        \
       uint simd_group_id [[simdgroup_index_in_threadgroup]]);
@@ -226,3 +324,65 @@ template <typename T, int N_READS = SOFTMAX_N_READS>
 
 instantiate_softmax(float32, float) instantiate_softmax(float16, half)
     instantiate_softmax(bfloat16, bfloat16_t)
+
+#define instantiate_softmax_split(name, itype, acc_name, acc_type)                  \
+  template [[host_name("softmax_split_partials_" #name acc_name)]] [[kernel]] void \
+  softmax_split_partials<itype, acc_type>(                                         \
+      const device itype* in,                                                      \
+      device float* partials,                                                      \
+      constant int& axis_size,                                                     \
+      constant int& chunk_size,                                                    \
+      threadgroup acc_type* local_max [[threadgroup(0)]],                          \
+      threadgroup acc_type* local_normalizer [[threadgroup(1)]],                   \
+      uint3 gid [[threadgroup_position_in_grid]],                                  \
+      uint3 n_groups [[threadgroups_per_grid]],                                    \
+      uint3 lid [[thread_position_in_threadgroup]],                                \
+      uint3 lsize [[threads_per_threadgroup]],                                     \
+      uint simd_lane_id [[thread_index_in_simdgroup]],                             \
+      uint simd_group_id [[simdgroup_index_in_threadgroup]]);                      \
+  template [[host_name("softmax_split_normalize_" #name acc_name)]] [[kernel]] void \
+  softmax_split_normalize<itype, acc_type>(                                        \
+      const device itype* in,                                                      \
+      device itype* out,                                                           \
+      const device float* partials,                                                \
+      constant int& axis_size,                                                     \
+      constant int& chunk_size,                                                    \
+      uint3 gid [[threadgroup_position_in_grid]],                                  \
+      uint3 n_groups [[threadgroups_per_grid]],                                    \
+      uint3 lid [[thread_position_in_threadgroup]],                                \
+      uint3 lsize [[threads_per_threadgroup]]);
+
+instantiate_softmax_split(float32, float, "", float)
+instantiate_softmax_split(float16, half, "", half)
+instantiate_softmax_split(bfloat16, bfloat16_t, "", bfloat16_t)
+instantiate_softmax_split(float16, half, "_acc32", float)
+instantiate_softmax_split(bfloat16, bfloat16_t, "_acc32", float)
+
+// Reduced precision inputs accumulated in fp32
+#define instantiate_softmax_acc32(name, itype)                      \
+  template [[host_name("softmax_" #name "_acc32")]] [[kernel]] void \
+  softmax_single_row<itype, float>(                                 \
+      const device itype* in,                                       \
+      device itype* out,                                            \
+      constant int& axis_size,                                      \
+      threadgroup float* local_max [[threadgroup(0)]],              \
+      threadgroup float* local_normalizer [[threadgroup(1)]],       \
+      uint gid [[threadgroup_position_in_grid]],                    \
+      uint _lid [[thread_position_in_threadgroup]],                 \
+      uint simd_lane_id [[thread_index_in_simdgroup]],              \
+      uint simd_group_id [[simdgroup_index_in_threadgroup]]);       \
+  template [[host_name("softmax_looped_" #name "_acc32")]] [[kernel]] void \
+  softmax_looped<itype, float>(                                     \
+      const device itype* in,                                       \
+      device itype* out,                                            \
+      constant int& axis_size,                                      \
+      threadgroup float* local_max [[threadgroup(0)]],              \
+      threadgroup float* local_normalizer [[threadgroup(1)]],       \
+      uint gid [[threadgroup_position_in_grid]],                    \
+      uint lid [[thread_position_in_threadgroup]],                  \
+      uint lsize [[threads_per_threadgroup]],                       \
+      uint simd_lane_id [[thread_index_in_simdgroup]],              \
+      uint simd_group_id [[simdgroup_index_in_threadgroup]]);
+
+instantiate_softmax_acc32(float16, half)
+instantiate_softmax_acc32(bfloat16, bfloat16_t)
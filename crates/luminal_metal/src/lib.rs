@@ -1,5 +1,7 @@
 use std::{
     any::{Any, TypeId},
+    borrow::Cow,
+    cell::Cell,
     fmt::{Debug, Write},
     ops::Deref,
     sync::Arc,
@@ -8,37 +10,82 @@ use std::{
 #[cfg(test)]
 mod tests;
 
+mod add_norm;
+mod argmax;
+mod attention;
+mod audit;
+mod autotune;
 mod binary;
 mod command_buffer;
+mod concat;
+mod cross_entropy;
+mod cumsum;
 mod elementwise_fusion;
+mod embedding_bag;
+mod im2col;
 mod matmul;
+mod mps;
 mod other;
+mod pending;
+mod pipeline_cache;
 mod prim;
 mod quantized;
+mod scatter_add;
+mod sort;
 mod storage_buffer;
+mod storage_mode;
+mod topk;
 mod unary;
+mod upload_cache;
 
+pub use audit::*;
+pub use autotune::{matmul_autotuning_enabled, set_matmul_autotuning, GemmConfig};
 use itertools::Itertools;
 use metal_rs::*;
+use pending::finish_command_buffer;
+pub use pending::wait_for_device;
+pub use pipeline_cache::cached_pipelines;
+use pipeline_cache::KernelLibrary;
 pub use quantized::*;
 use rustc_hash::FxHashMap;
+use storage_mode::{did_write, new_buffer, new_buffer_with_data, sync_for_cpu};
+pub use storage_mode::{set_storage_mode, StorageMode};
+pub use upload_cache::resident_uploads;
 
 use luminal::{
-    op::InputTensor,
-    prelude::{
+    compiler_internals::{
         symbolic::{BigExpression, Term},
         *,
     },
+    op::InputTensor,
+    prelude::*,
 };
 
 /// Compile graphs to run on Metal-supported macOS devices in supported data formats
-pub type MetalCompiler<T> = (
+pub type MetalCompiler<T> = (KernelCompilers<T>, BufferCompilers);
+
+/// Same as the MetalCompiler, but also bakes small weights into kernels as constants.
+///
+/// The weights must already be loaded (the graph has been ran once with them kept) when compiling, and must not change afterwards.
+pub type FrozenMetalCompiler<T> = (
+    KernelCompilers<T>,
+    elementwise_fusion::WeightBakingCompiler<T>,
+    BufferCompilers,
+);
+
+/// Same kernels as the MetalCompiler, but without sharing command and storage buffers, so the output of every op
+/// can be inspected by [`execute_precision_audit`].
+pub type MetalAuditCompiler<T> = KernelCompilers<T>;
+
+/// Compilers to swap in and fuse metal kernels
+type KernelCompilers<T> = (
     prim::PrimitiveCompiler<T>,
     SpecialOpsCompiler<T>,
     other::CopyCompiler<T>,
     other::ContiguousElimination<T>,
+    prim::SumReduceMergeCompiler<T>,
+    elementwise_fusion::ReduceEpilogueCompiler<T>,
     elementwise_fusion::ElementwiseFusionCompiler<T>,
-    BufferCompilers,
 );
 
 /// Compilers to share command and storage buffers
@@ -53,13 +100,18 @@ type SpecialOpsCompiler<T> = (
     binary::MetalEqualCompiler<T>,
     other::ARangeCompiler<T>,
     binary::MetalGatherCompiler<T>,
+    concat::MetalConcatCompiler<T>,
     unary::MetalExpCompiler<T>,
     unary::MetalCosCompiler<T>,
     unary::MeanReduceCompiler<T>,
+    unary::RMSNormCompiler<T>,
     unary::StdNormCompiler<T>,
+    add_norm::AddNormCompiler<T>,
     unary::SoftmaxCompiler<T>,
     unary::RopeCompiler<T>,
+    im2col::Im2ColCompiler<T>,
     matmul::MetalMatMulCompiler<T>,
+    attention::FlashAttentionCompiler<T>,
 );
 
 #[derive(Debug, Clone)]
@@ -80,6 +132,17 @@ impl Data for MetalBuffer {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn allocation(&self) -> Option<(usize, usize)> {
+        Some((self.0.gpu_address() as usize, self.0.length() as usize))
+    }
+}
+
+/// The device's recommended limit on the memory a process uses, a good starting point for `Graph::set_memory_limit`
+pub fn recommended_memory_limit() -> usize {
+    Device::system_default()
+        .unwrap()
+        .recommended_max_working_set_size() as usize
 }
 
 pub trait MetalFloat: Copy + 'static {
@@ -87,6 +150,18 @@ pub trait MetalFloat: Copy + 'static {
     fn from_f32(a: f32) -> Self;
     fn is_f32() -> bool;
     fn type_name() -> &'static str;
+    /// Name of the type in the kernels instantiated in the gemm, gemv and softmax libraries
+    fn library_type_name() -> &'static str;
+    /// The MPSDataType of the type, for MetalPerformanceShaders kernels
+    fn mps_data_type() -> u32;
+    /// Whether the type has a 4-wide vector type in Metal, for vectorized elementwise kernels
+    fn has_vector_type() -> bool {
+        true
+    }
+    /// The lowest finite value of the type, which mask constants too large for it are clamped to
+    fn finite_min() -> f32 {
+        f32::MIN
+    }
 }
 
 // Quantization types
@@ -110,6 +185,10 @@ impl MetalQuantizationType for f16 {
     type MatmulCompiler = matmul::MetalMatMulCompiler<Self>;
 }
 
+impl MetalQuantizationType for bf16 {
+    type MatmulCompiler = matmul::MetalMatMulCompiler<Self>;
+}
+
 // Main metal dtypes
 
 impl MetalFloat for f32 {
@@ -125,6 +204,12 @@ impl MetalFloat for f32 {
     fn type_name() -> &'static str {
         "float"
     }
+    fn library_type_name() -> &'static str {
+        "float32"
+    }
+    fn mps_data_type() -> u32 {
+        mps::MPS_FLOAT32
+    }
 }
 
 impl MetalFloat for f16 {
@@ -140,6 +225,45 @@ impl MetalFloat for f16 {
     fn type_name() -> &'static str {
         "half"
     }
+    fn library_type_name() -> &'static str {
+        "float16"
+    }
+    fn mps_data_type() -> u32 {
+        mps::MPS_FLOAT16
+    }
+    fn finite_min() -> f32 {
+        f16::MIN.to_f32()
+    }
+}
+
+/// bf16 keeps f32's exponent range, so checkpoints stored in bf16 don't overflow like they can in f16. Kernels use the
+/// `bfloat16_t` type from `kernels/bf16.h`, which is Metal's native `bfloat` on Metal 3.1 and emulated with 16 bit
+/// integers on older devices, so every device supports it.
+impl MetalFloat for bf16 {
+    fn from_f32(a: f32) -> Self {
+        bf16::from_f32(a)
+    }
+    fn to_f32(self) -> f32 {
+        self.to_f32()
+    }
+    fn is_f32() -> bool {
+        false
+    }
+    fn type_name() -> &'static str {
+        "bfloat16_t"
+    }
+    fn library_type_name() -> &'static str {
+        "bfloat16"
+    }
+    fn mps_data_type() -> u32 {
+        mps::MPS_BFLOAT16
+    }
+    fn has_vector_type() -> bool {
+        false
+    }
+    fn finite_min() -> f32 {
+        bf16::MIN.to_f32()
+    }
 }
 
 pub trait MetalKernel: Debug {
@@ -175,28 +299,18 @@ pub trait MetalKernel: Debug {
         dyn_map: &FxHashMap<char, usize>,
     ) -> Vec<Buffer> {
         let dev = Device::system_default().unwrap();
-        // Allocate storage buffers
+        // Allocate storage buffers (Metal can't allocate empty buffers)
         let inp_shapes = inputs.iter().map(|(_, s)| *s).collect::<Vec<_>>();
         let intermediate_buffers = self
             .intermediate_buffer_sizes(&inp_shapes)
             .into_iter()
-            .map(|n| {
-                dev.new_buffer(
-                    n.exec(dyn_map).unwrap() as u64,
-                    MTLResourceOptions::StorageModeShared,
-                )
-            })
+            .map(|n| new_buffer(&dev, n.exec(dyn_map).unwrap().max(1) as u64))
             .collect::<Vec<_>>();
         let intermediate_buffers_ref = intermediate_buffers.iter().collect::<Vec<_>>();
         let output_buffers = self
             .output_buffer_sizes(&inp_shapes)
             .into_iter()
-            .map(|n| {
-                dev.new_buffer(
-                    n.exec(dyn_map).unwrap() as u64,
-                    MTLResourceOptions::StorageModeShared,
-                )
-            })
+            .map(|n| new_buffer(&dev, n.exec(dyn_map).unwrap().max(1) as u64))
             .collect::<Vec<_>>();
         let output_buffers_ref = output_buffers.iter().collect::<Vec<_>>();
         self.metal_forward(
@@ -232,40 +346,92 @@ impl MetalKernel for () {
     }
 }
 
-fn compile_lib(device: &Device, source: &str) -> Library {
-    let options = CompileOptions::new();
-    options.set_fast_math_enabled(true);
-    // options.set_install_name(
-    //     &rand::thread_rng()
-    //         .sample_iter(&rand::distributions::Alphanumeric)
-    //         .take(7)
-    //         .map(char::from)
-    //         .collect::<String>(),
-    // );
-    device
-        .new_library_with_source(
-            &source.replace(
-                "KERNEL_PATH",
-                &format!("{}/src/kernels", env!("CARGO_MANIFEST_DIR")),
-            ),
-            &options,
-        )
-        .unwrap()
+thread_local! {
+    /// Whether the kernels being built use fast math, set while a [`FastMath`] compiler runs
+    static FAST_MATH: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Make the Metal compiler stacks available to `Graph::auto_compile`
+pub fn register_auto_compile() {
+    register_pipeline(Pipeline::MetalF16, |graph, remap| {
+        graph.compile(<(GenericCompiler, MetalCompiler<f16>)>::default(), remap)
+    });
+    register_pipeline(Pipeline::MetalF32, |graph, remap| {
+        graph.compile(<(GenericCompiler, MetalCompiler<f32>)>::default(), remap)
+    });
+}
+
+/// Wrap this around a Metal compiler to set whether the kernels it builds use fast math
+/// (`MTLCompileOptions::fastMathEnabled`), like `FastMath { compiler: MetalCompiler::<f16>::default(), enabled: true }`.
+///
+/// Fast math flushes denormals to zero and uses lower precision transcendental and division implementations,
+/// so results can diverge from PyTorch's MPS backend, which compiles its kernels without it. Kernels are built without
+/// fast math unless they're compiled through this. Ops keep the setting they were built with, so kernels they rebuild
+/// later, like in passes run outside this wrapper or when tuning matmuls at execution, match it.
+#[derive(Debug, Default)]
+pub struct FastMath<C: Compiler> {
+    pub compiler: C,
+    pub enabled: bool,
+}
+
+impl<C: Compiler> Compiler for FastMath<C> {
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, remap: T) {
+        with_fast_math(self.enabled, || self.compiler.compile(graph, remap));
+    }
+}
+
+/// Whether the kernels being built use fast math
+pub(crate) fn fast_math_enabled() -> bool {
+    FAST_MATH.with(|f| f.get())
+}
+
+/// Build kernels with or without fast math inside `f`
+pub(crate) fn with_fast_math<R>(enabled: bool, f: impl FnOnce() -> R) -> R {
+    let previous = FAST_MATH.with(|fast_math| fast_math.replace(enabled));
+    let result = f();
+    FAST_MATH.with(|fast_math| fast_math.set(previous));
+    result
+}
+
+/// Compile kernel source into a library, or get the library it was already compiled into
+fn compile_lib(device: &Device, source: &str) -> KernelLibrary {
+    // Generated bf16 kernels need the bfloat16_t type and its math functions
+    let source = if source.contains("bfloat16_t") && !source.contains("bf16.h") {
+        Cow::Owned(format!("#include \"KERNEL_PATH/bf16.h\"\n{source}"))
+    } else {
+        Cow::Borrowed(source)
+    };
+    let fast_math = fast_math_enabled();
+    pipeline_cache::get_or_compile_library(device, &source, fast_math, || {
+        let options = CompileOptions::new();
+        options.set_fast_math_enabled(fast_math);
+        device
+            .new_library_with_source(
+                &source.replace(
+                    "KERNEL_PATH",
+                    &format!("{}/src/kernels", env!("CARGO_MANIFEST_DIR")),
+                ),
+                &options,
+            )
+            .unwrap()
+    })
 }
 
 fn select_function_from_lib(
-    lib: &Library,
+    lib: &KernelLibrary,
     function: &str,
     device: &Device,
 ) -> ComputePipelineState {
-    let pipeline_state_descriptor = ComputePipelineDescriptor::new();
-    pipeline_state_descriptor
-        .set_compute_function(Some(&lib.get_function(function, None).unwrap()));
-    device
-        .new_compute_pipeline_state_with_function(
-            pipeline_state_descriptor.compute_function().unwrap(),
-        )
-        .unwrap()
+    pipeline_cache::get_or_create_pipeline(lib, function, || {
+        let pipeline_state_descriptor = ComputePipelineDescriptor::new();
+        pipeline_state_descriptor
+            .set_compute_function(Some(&lib.get_function(function, None).unwrap()));
+        device
+            .new_compute_pipeline_state_with_function(
+                pipeline_state_descriptor.compute_function().unwrap(),
+            )
+            .unwrap()
+    })
 }
 
 fn compile_function(name: &str, code: &str, device: &Device) -> ComputePipelineState {
@@ -282,7 +448,13 @@ trait DispatchNElements {
 }
 
 impl DispatchNElements for ComputeCommandEncoderRef {
+    /// Launch `n` threads in threadgroups of 1024. The grid allows 2^32 - 1 threadgroups per dimension, so the limit
+    /// that matters is the kernels' 32 bit `thread_position_in_grid` index, which would silently wrap past `u32::MAX`.
     fn dispatch_1d(&self, n: usize) {
+        assert!(
+            n <= u32::MAX as usize,
+            "Can't launch {n} threads, kernels index threads with 32 bit integers"
+        );
         self.dispatch_thread_groups(
             MTLSize {
                 width: n.div_ceil(1024) as u64,
@@ -413,6 +585,10 @@ fn expr_to_metal_string(expr: BigExpression) -> String {
                 symbols.pop().unwrap(),
                 symbols.pop().unwrap()
             ),
+            Term::CeilDiv => {
+                let (a, b) = (symbols.pop().unwrap(), symbols.pop().unwrap());
+                format!("(({a}+{b}-1)/{b})")
+            }
             _ => format!(
                 "({}{term:?}{})",
                 symbols.pop().unwrap(),
@@ -431,13 +607,134 @@ fn get_idx_valid_exps(shape: ShapeTracker) -> (String, String) {
     )
 }
 
-fn get_buffer_from_tensor<'a>(tensor: &'a InputTensor) -> &'a MetalBuffer {
-    tensor
-        .borrowed()
-        .data
-        .as_any()
+/// Elements each thread handles in vectorized elementwise kernels
+const VECTOR_WIDTH: usize = 4;
+
+/// Whether an elementwise kernel over these inputs can read and write them as contiguous vectors
+fn is_vectorizable<T: MetalFloat>(shapes: &[ShapeTracker]) -> bool {
+    T::has_vector_type()
+        && shapes
+            .iter()
+            .all(|s| s.is_contiguous() && !s.is_sliced() && !s.is_padded())
+}
+
+/// Replace each `input<n>` in an elementwise expression
+fn replace_inputs(expression: &str, replace: impl Fn(usize) -> String) -> String {
+    let mut output = String::new();
+    let mut rest = expression;
+    while let Some(start) = rest.find("input") {
+        output.push_str(&rest[..start]);
+        rest = &rest[start + "input".len()..];
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        match rest[..digits].parse() {
+            Ok(n) => output.push_str(&replace(n)),
+            Err(_) => output.push_str("input"),
+        }
+        rest = &rest[digits..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Whether an elementwise expression works on float4 values as it is. That's arithmetic and single argument math
+/// functions, but not comparisons, selects or casts, which need to run per lane.
+fn is_vector_expression(expression: &str) -> bool {
+    const VECTOR_FUNCTIONS: [&str; 7] = ["exp", "exp2", "log", "log2", "sin", "cos", "sqrt"];
+    !expression.contains(['?', '<', '>', '=', ',', '['])
+        && expression
+            .split(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.')
+            .filter(|t| !t.is_empty() && !t.starts_with(|c: char| c.is_ascii_digit()))
+            .all(|t| VECTOR_FUNCTIONS.contains(&t) || t.starts_with("input"))
+}
+
+/// Render a kernel applying an elementwise expression, which reads its inputs as `input0`, `input1`, etc. as floats,
+/// to contiguous inputs. Each thread handles 4 elements with vector loads and stores, and the last thread loops over
+/// the tail when the element count isn't divisible by 4, so dispatch it over `n_elements.div_ceil(VECTOR_WIDTH)`
+/// threads. Buffers are the inputs, then the output, then the element count.
+fn render_vectorized_elementwise(type_name: &str, n_inputs: usize, expression: &str) -> String {
+    let inputs = (0..n_inputs)
+        .map(|i| format!("device {type_name} *input{i} [[buffer({i})]], "))
+        .join("");
+    let loads = (0..n_inputs)
+        .map(|i| format!("{type_name}4 v{i} = ((device {type_name}4*)input{i})[vec_idx];"))
+        .join("\n        ");
+    let vector_body = if is_vector_expression(expression) {
+        let vector_exp = replace_inputs(expression, |i| format!("float4(v{i})"));
+        format!("{type_name}4 result = {type_name}4({vector_exp});")
+    } else {
+        let lane_exp = replace_inputs(expression, |i| format!("(float)v{i}[lane]"));
+        format!(
+            "{type_name}4 result;
+        for (uint lane = 0; lane < {VECTOR_WIDTH}; lane++) {{
+            uint idx = base + lane;
+            result[lane] = ({type_name})({lane_exp});
+        }}"
+        )
+    };
+    let scalar_exp = replace_inputs(expression, |i| format!("(float)input{i}[idx]"));
+    format!(
+        "
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel({inputs}device {type_name} *out [[buffer({n_inputs})]], device uint& n_elements [[buffer({})]], uint vec_idx [[thread_position_in_grid]]) {{
+    uint base = vec_idx * {VECTOR_WIDTH};
+    if (base + {VECTOR_WIDTH} <= n_elements) {{
+        {loads}
+        {vector_body}
+        ((device {type_name}4*)out)[vec_idx] = result;
+    }} else {{
+        for (uint idx = base; idx < n_elements; idx++) {{
+            out[idx] = ({type_name})({scalar_exp});
+        }}
+    }}
+}}
+",
+        n_inputs + 1
+    )
+}
+
+/// Why a Metal op couldn't run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetalError {
+    /// An input wasn't on the device, usually because an op the Metal compiler didn't handle feeds a Metal op
+    NotMetalBuffer {
+        /// The type of the input's data
+        found: &'static str,
+    },
+}
+
+impl std::fmt::Display for MetalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotMetalBuffer { found } => write!(
+                f,
+                "Expected an input in a Metal buffer, found {found}. Was the Metal compiler run after every other compiler?"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MetalError {}
+
+fn get_buffer_from_tensor<'a>(tensor: &'a InputTensor) -> Result<&'a MetalBuffer, MetalError> {
+    let data = &tensor.borrowed().data;
+    data.as_any()
         .downcast_ref::<MetalBuffer>()
-        .expect("Tensor does not contain a metal buffer")
+        .ok_or(MetalError::NotMetalBuffer {
+            found: data.type_name(),
+        })
+}
+
+/// The buffers of all the inputs, with their shapes
+fn get_buffers_from_tensors(
+    tensors: &[(InputTensor, ShapeTracker)],
+) -> Result<Vec<(&Buffer, ShapeTracker)>, MetalError> {
+    tensors
+        .iter()
+        .map(|(t, sh)| Ok((get_buffer_from_tensor(t)?.deref(), *sh)))
+        .collect()
 }
 
 #[macro_export]
@@ -1,15 +1,22 @@
-use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+use std::{any::Any, cell::RefCell, marker::PhantomData, mem::size_of, sync::Arc, time::Duration};
 
 use luminal::{
-    op::{InputTensor, Operator},
+    compiler_internals::*,
+    op::{InputTensor, OpError, Operator},
     prelude::*,
     shape::symbolic::BigExpression,
 };
 
 use metal_rs::{objc::rc::autoreleasepool, *};
+use rustc_hash::FxHashMap;
 
 use crate::{
-    compile_lib, get_buffer_from_tensor,
+    autotune::{matmul_autotuning_enabled, tuned_config, GemmConfig, TuningKey},
+    compile_lib, finish_command_buffer, get_buffer_from_tensor,
+    mps::{encode_matrix_multiplication, MpsMatrix},
+    new_buffer,
+    pending::gpu_time,
+    pipeline_cache::KernelLibrary,
     prim::{MetalContiguous, MetalMul, MetalSumReduce},
     select_function_from_lib, MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
 };
@@ -17,8 +24,21 @@ use crate::{
 /// Multiplies a BxMxK matrix with a KxN matrix, resulting in a BxMxN matrix
 #[derive(LuminalEqFalse, LuminalPrint, Clone)]
 pub struct Matmul<T> {
+    /// The matmul kernel with the default tile configuration
     matmul_pipeline: ComputePipelineState,
+    /// Built with the fast math setting of the compile, so pipelines picked from it when tuning at execution match it
+    matmul_library: KernelLibrary,
+    /// Whether A and B are transposed, like `"nt"`, which picks the matmul kernel
+    transpose: String,
+    /// Tune the tile configuration of the matmul kernel for each shape, from [`crate::set_matmul_autotuning`]
+    autotune: bool,
+    /// The configurations tuned for the (M, K, N) shapes this op ran with, and their pipelines
+    tuned: RefCell<FxHashMap<(usize, usize, usize), (GemmConfig, ComputePipelineState)>>,
     matvec_pipeline: ComputePipelineState,
+    matvec_library: KernelLibrary,
+    matvec_function: String,
+    /// Use the matmul kernel even for matrix-vector products, from a `PreferKernel("gemm")` hint
+    prefer_gemm: bool,
     queue: CommandQueue,
     device: Device,
     _phantom: PhantomData<T>,
@@ -26,7 +46,85 @@ pub struct Matmul<T> {
 
 const BM: u64 = 8;
 const BN: u64 = 32;
-impl<T> MetalKernel for Matmul<T> {
+/// Timed runs of each tile configuration when tuning, after a warmup run
+const TUNING_RUNS: usize = 3;
+
+impl<T: MetalFloat> Matmul<T> {
+    /// The tile configuration and pipeline to run the matmul kernel with, tuning them the first time a shape runs
+    fn gemm_pipeline(&self, shape: GemmShape) -> (GemmConfig, ComputePipelineState) {
+        if !self.autotune {
+            return (GemmConfig::DEFAULT, self.matmul_pipeline.clone());
+        }
+        let GemmShape { m, k, n, .. } = shape;
+        if let Some(tuned) = self.tuned.borrow().get(&(m, k, n)) {
+            return tuned.clone();
+        }
+        let key = TuningKey {
+            device: self.device.name().to_string(),
+            m,
+            k,
+            n,
+            type_name: T::library_type_name(),
+            transpose: self.transpose.clone(),
+        };
+        let config = tuned_config(key, GemmConfig::candidates(k), |config| {
+            self.benchmark_gemm(config, shape)
+        });
+        let tuned = (config, self.config_pipeline(config));
+        self.tuned.borrow_mut().insert((m, k, n), tuned.clone());
+        tuned
+    }
+
+    fn config_pipeline(&self, config: GemmConfig) -> ComputePipelineState {
+        select_function_from_lib(
+            &self.matmul_library,
+            &config.function_name(&self.transpose, T::library_type_name()),
+            &self.device,
+        )
+    }
+
+    /// Time the matmul kernel with a tile configuration, on scratch buffers the size of the shape
+    fn benchmark_gemm(&self, config: GemmConfig, shape: GemmShape) -> Duration {
+        let pipeline = self.config_pipeline(config);
+        let GemmShape {
+            m,
+            k,
+            n,
+            batch_size,
+            b_batch_stride,
+            b_batch_size,
+        } = shape;
+        autoreleasepool(|| {
+            let buffer = |elements: usize| {
+                new_buffer(&self.device, (elements.max(1) * size_of::<T>()) as u64)
+            };
+            let b_batches = batch_size.div_ceil(b_batch_size);
+            let (a, b, c) = (
+                buffer(batch_size * m * k),
+                buffer(b_batch_stride * (b_batches - 1) + k * n),
+                buffer(batch_size * m * n),
+            );
+            // A queue of its own, so the graph's pending work isn't timed with it
+            let queue = self.device.new_command_queue();
+            (0..=TUNING_RUNS)
+                .map(|_| {
+                    let command_buffer = queue.new_command_buffer();
+                    let encoder = command_buffer
+                        .compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+                    encode_gemm(encoder, &pipeline, config, [&a, &b, &c], shape);
+                    encoder.end_encoding();
+                    command_buffer.commit();
+                    command_buffer.wait_until_completed();
+                    gpu_time(command_buffer)
+                })
+                .skip(1)
+                .min()
+                .unwrap()
+        })
+    }
+}
+
+impl<T: MetalFloat> MetalKernel for Matmul<T> {
     fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
         let m = input_shapes[0].shape()[input_shapes[0].len() - 2].clone();
         let n = input_shapes[1].shape()[input_shapes[1].len() - 1].clone();
@@ -45,6 +143,13 @@ impl<T> MetalKernel for Matmul<T> {
         _: &[&Buffer],
         output_buffers: &[&Buffer],
     ) {
+        // The kernels index dense rows and columns, so the compiler makes sliced and padded inputs contiguous first
+        assert!(
+            inputs
+                .iter()
+                .all(|(_, shape)| !shape.is_sliced() && !shape.is_padded()),
+            "Matmul inputs can't be sliced or padded"
+        );
         let (a_shape, b_shape) = (
             inputs[0]
                 .1
@@ -61,31 +166,31 @@ impl<T> MetalKernel for Matmul<T> {
         );
         let a_dims = a_shape.len();
         let m = a_shape[a_dims - 2];
-        let batch_size = a_shape.iter().take(a_dims - 2).product::<usize>().max(1);
-        let b_batch_size = b_shape
-            .iter()
-            .enumerate()
-            .take(b_shape.len() - 2)
-            .filter(|(i, _)| !inputs[1].1.fake[inputs[1].1.indexes[*i]])
-            .map(|(_, i)| *i)
-            .product::<usize>()
-            .max(1);
+        let batch_size = a_shape.iter().take(a_dims - 2).product::<usize>();
         let b_dims = b_shape.len();
         let k = b_shape[b_dims - 2];
         let n = b_shape[b_dims - 1];
 
+        if encode_empty_matmul::<T>(batch_size, m, n, k, command_buffer, output_buffers[0]) {
+            return;
+        }
+
+        let (b_batch_stride, b_batch_size) = b_batch_layout(&inputs[1].1, &b_shape, k, n);
         let encoder =
             command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
-        if m == 1 && batch_size == 1 {
+        // M can be a dynamic dim, so the kernel is picked here. When decoding M is 1, and the matvec kernel caches the
+        // vector in threadgroup memory and splits K between the threads of each group. It steps through the batches of
+        // B with a single stride, so B batches repeated within that stride go to the matmul kernel.
+        if m == 1 && b_batch_size == 1 && !self.prefer_gemm {
             // Matvec
             encoder.set_compute_pipeline_state(&self.matvec_pipeline);
             encoder.set_buffer(0, Some(inputs[1].0), 0);
             encoder.set_buffer(1, Some(inputs[0].0), 0);
             encoder.set_buffer(2, Some(output_buffers[0]), 0);
-            encoder.set_i32(3, if m == 1 { k } else { m } as i32);
-            encoder.set_i32(4, if m == 1 { n } else { m } as i32);
-            encoder.set_i32(5, 0);
-            encoder.set_i32(6, 0);
+            encoder.set_i32(3, k as i32);
+            encoder.set_i32(4, n as i32);
+            encoder.set_i32(5, k as i32); // Vector batch stride
+            encoder.set_i32(6, b_batch_stride as i32); // Matrix batch stride
             encoder.set_threadgroup_memory_length(
                 0,
                 if inputs[1].1.indexes[inputs[1].1.len() - 1]
@@ -98,56 +203,37 @@ impl<T> MetalKernel for Matmul<T> {
             );
             let b = if inputs[1].1.is_contiguous() { BN } else { BM };
             encoder.dispatch_thread_groups(
-                MTLSize::new((n as u64 + b * 4 - 1).div_ceil(b * 4), 1, 1),
+                MTLSize::new((n as u64 + b * 4 - 1).div_ceil(b * 4), 1, batch_size as u64),
                 MTLSize::new(BN, BM, 1),
             );
         } else {
             // Matmul
-            encoder.set_compute_pipeline_state(&self.matmul_pipeline);
-
-            // Set inputs
-            encoder.set_buffer(0, Some(inputs[0].0), 0);
-            encoder.set_buffer(1, Some(inputs[1].0), 0);
-            encoder.set_buffer(2, Some(output_buffers[0]), 0);
-            encoder.set_i32(3, m as i32);
-            encoder.set_i32(4, n as i32);
-            encoder.set_i32(5, k as i32);
-            encoder.set_i32(6, (m * k) as i32); // A batch stride
-            if inputs[1].1.len() > 2 // 3D or larger
-                && inputs[1].1.fake[inputs[1].1.indexes[inputs[1].1.len() - 3]] // 3rd to last dimension is fake
-                && inputs[1]
-                    .1
-                    .indexes
-                    .iter()
-                    .take(inputs[1].1.len().saturating_sub(4))
-                    .any(|i| !inputs[1].1.fake[*i])
-            // At least one non-fake dimension before 3rd to last
-            {
-                encoder.set_i32(7, (k * n) as i32); // B batch stride
-                                                    // B batch size 2
-                encoder.set_i32(8, b_shape[inputs[1].1.len() - 3] as i32);
-            } else {
-                encoder.set_i32(7, if b_batch_size == 1 { 0 } else { n * k } as i32); // B batch stride
-                encoder.set_i32(8, 1); // B batch size
-            }
-            encoder.set_i32(9, (m * n) as i32); // C batch stride
-
-            // Execute
-            encoder.dispatch_thread_groups(
-                MTLSize::new(
-                    (n + 31).div_ceil(32) as u64,
-                    (m + 31).div_ceil(32) as u64,
-                    batch_size as u64,
-                ),
-                MTLSize::new(32, 2, 2),
+            let shape = GemmShape {
+                m,
+                k,
+                n,
+                batch_size,
+                b_batch_stride,
+                b_batch_size,
+            };
+            let (config, pipeline) = self.gemm_pipeline(shape);
+            encode_gemm(
+                encoder,
+                &pipeline,
+                config,
+                [inputs[0].0, inputs[1].0, output_buffers[0]],
+                shape,
             );
         }
         encoder.end_encoding();
     }
 }
 
-impl<T: 'static + Clone> Operator for Matmul<T> {
-    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+impl<T: MetalFloat> Operator for Matmul<T> {
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
@@ -161,25 +247,261 @@ impl<T: 'static + Clone> Operator for Matmul<T> {
                 .product::<usize>();
             let m = a_shape[a_shape.len() - 2].to_usize().unwrap();
 
-            let out = self.device.new_buffer(
-                (batch_size * m * n * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
+            // Metal can't allocate empty buffers
+            let out = new_buffer(
+                &self.device,
+                ((batch_size * m * n).max(1) * std::mem::size_of::<T>()) as u64,
             );
 
             self.metal_forward(
                 &[
-                    (get_buffer_from_tensor(&inp[0].0), inp[0].1),
-                    (get_buffer_from_tensor(&inp[1].0), inp[1].1),
+                    (get_buffer_from_tensor(&inp[0].0)?, inp[0].1),
+                    (get_buffer_from_tensor(&inp[1].0)?, inp[1].1),
                 ],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        // The matmul kernel always accumulates in fp32, but the matvec kernel accumulates in T
+        if key == "fp32_accumulation" && !T::is_f32() {
+            let mut variant = self.clone();
+            variant.matvec_pipeline = select_function_from_lib(
+                &self.matvec_library,
+                &self.matvec_function.replacen("_bm", "_acc32_bm", 1),
+                &self.device,
+            );
+            return Some(Box::new(Box::new(variant) as Box<dyn Operator>));
+        }
+        None
+    }
+}
+
+/// The sizes of a batched matmul, and how B's batches are laid out (see [`b_batch_layout`])
+#[derive(Debug, Clone, Copy)]
+struct GemmShape {
+    m: usize,
+    k: usize,
+    n: usize,
+    batch_size: usize,
+    b_batch_stride: usize,
+    b_batch_size: usize,
+}
+
+/// Encode the matmul kernel instantiated with `config`, multiplying buffers A and B into C
+fn encode_gemm(
+    encoder: &ComputeCommandEncoderRef,
+    pipeline: &ComputePipelineState,
+    config: GemmConfig,
+    [a, b, c]: [&Buffer; 3],
+    shape: GemmShape,
+) {
+    let GemmShape {
+        m,
+        k,
+        n,
+        batch_size,
+        b_batch_stride,
+        b_batch_size,
+    } = shape;
+    encoder.set_compute_pipeline_state(pipeline);
+
+    // Set inputs
+    encoder.set_buffer(0, Some(a), 0);
+    encoder.set_buffer(1, Some(b), 0);
+    encoder.set_buffer(2, Some(c), 0);
+    encoder.set_i32(3, m as i32);
+    encoder.set_i32(4, n as i32);
+    encoder.set_i32(5, k as i32);
+    encoder.set_i32(6, (m * k) as i32); // A batch stride
+    encoder.set_i32(7, b_batch_stride as i32);
+    encoder.set_i32(8, b_batch_size as i32);
+    encoder.set_i32(9, (m * n) as i32); // C batch stride
+
+    // Execute
+    encoder.dispatch_thread_groups(config.grid(m, n, batch_size), config.threadgroup());
+}
+
+/// Handle matmuls with nothing to multiply, returning whether there were. An empty inner dimension sums nothing, so
+/// the output is all zeros.
+fn encode_empty_matmul<T>(
+    batch_size: usize,
+    m: usize,
+    n: usize,
+    k: usize,
+    command_buffer: &CommandBufferRef,
+    output: &Buffer,
+) -> bool {
+    if batch_size * m * n == 0 {
+        // Empty output, nothing to compute
+        return true;
+    }
+    if k == 0 {
+        let encoder = command_buffer.new_blit_command_encoder();
+        encoder.fill_buffer(
+            output,
+            NSRange::new(0, (batch_size * m * n * size_of::<T>()) as u64),
+            0,
+        );
+        encoder.end_encoding();
+        return true;
+    }
+    false
+}
+
+/// Elements between consecutive B matrices, and how many batches in a row share each B. B batch `i` starts at
+/// `stride * (i / batch_size)`.
+fn b_batch_layout(b: &ShapeTracker, b_shape: &[usize], k: usize, n: usize) -> (usize, usize) {
+    let b_dims = b.len();
+    if b_dims > 2 // 3D or larger
+        && b.fake[b.indexes[b_dims - 3]] // 3rd to last dimension is fake
+        && b.indexes.iter().take(b_dims.saturating_sub(4)).any(|i| !b.fake[*i])
+    // At least one non-fake dimension before 3rd to last
+    {
+        (k * n, b_shape[b_dims - 3])
+    } else if b_dims > 2 && !b.fake[b.indexes[b_dims - 3]] {
+        (b.strides()[b_dims - 3].to_usize().unwrap(), 1)
+    } else {
+        // Every batch shares one B
+        (0, 1)
+    }
+}
+
+/// Multiplies a BxMxK matrix with a KxN matrix like [`Matmul`], with MetalPerformanceShaders' matrix multiplication
+/// instead of the custom kernels. Picked with a `PreferKernel("mps")` hint on the matmul, or for every matmul with the
+/// `mps-matmul` feature.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct MpsMatmul<T> {
+    queue: CommandQueue,
+    device: Device,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: MetalFloat> MetalKernel for MpsMatmul<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        let m = input_shapes[0].shape()[input_shapes[0].len() - 2].clone();
+        let n = input_shapes[1].shape()[input_shapes[1].len() - 1].clone();
+        let batch_size = input_shapes[0]
+            .shape()
+            .into_iter()
+            .take(input_shapes[0].len() - 2)
+            .product::<BigExpression>()
+            .max(BigExpression::from(1));
+        vec![batch_size * m * n * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        assert!(
+            inputs
+                .iter()
+                .all(|(_, shape)| !shape.is_sliced() && !shape.is_padded()),
+            "Matmul inputs can't be sliced or padded"
+        );
+        let shape = |i: usize| {
+            inputs[i]
+                .1
+                .shape()
+                .into_iter()
+                .map(|d| d.to_usize().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let (a_shape, b_shape) = (shape(0), shape(1));
+        let (a, b) = (&inputs[0].1, &inputs[1].1);
+        let m = a_shape[a_shape.len() - 2];
+        let batch_size = a_shape.iter().take(a_shape.len() - 2).product::<usize>();
+        let k = b_shape[b_shape.len() - 2];
+        let n = b_shape[b_shape.len() - 1];
+        if encode_empty_matmul::<T>(batch_size, m, n, k, command_buffer, output_buffers[0]) {
+            return;
+        }
+
+        // The compiler makes batch dims contiguous, so only the last two dims can be swapped
+        let transpose_a = !a.is_contiguous();
+        let transpose_b = b.indexes[b.len() - 1] < b.indexes[b.len() - 2];
+        let (b_batch_stride, b_batch_size) = b_batch_layout(b, &b_shape, k, n);
+        let size = size_of::<T>();
+        let matrix = |buffer, offset, rows, columns| MpsMatrix {
+            buffer,
+            offset: offset * size,
+            rows,
+            columns,
+            row_bytes: columns * size,
+        };
+        // Batches sharing a B can run as one taller matrix, if A's rows follow each other
+        let (batches, rows) = if b_batch_stride == 0 && !transpose_a {
+            (1, batch_size * m)
+        } else {
+            (batch_size, m)
+        };
+        autoreleasepool(|| {
+            for batch in 0..batches {
+                let left = if transpose_a {
+                    matrix(inputs[0].0, batch * m * k, k, m)
+                } else {
+                    matrix(inputs[0].0, batch * m * k, rows, k)
+                };
+                let b_offset = b_batch_stride * (batch / b_batch_size);
+                let right = if transpose_b {
+                    matrix(inputs[1].0, b_offset, n, k)
+                } else {
+                    matrix(inputs[1].0, b_offset, k, n)
+                };
+                encode_matrix_multiplication(
+                    &self.device,
+                    command_buffer,
+                    T::mps_data_type(),
+                    (&left, transpose_a),
+                    (&right, transpose_b),
+                    &matrix(output_buffers[0], batch * m * n, rows, n),
+                    k,
+                );
+            }
+        });
+    }
+}
 
-            vec![Tensor::new(MetalBuffer(out))]
+impl<T: MetalFloat> Operator for MpsMatmul<T> {
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let out = new_buffer(
+                &self.device,
+                self.output_buffer_sizes(&[inp[0].1, inp[1].1])[0]
+                    .to_usize()
+                    .unwrap()
+                    .max(1) as u64,
+            );
+            self.metal_forward(
+                &[
+                    (get_buffer_from_tensor(&inp[0].0)?, inp[0].1),
+                    (get_buffer_from_tensor(&inp[1].0)?, inp[1].1),
+                ],
+                command_buffer,
+                &[],
+                &[&out],
+            );
+            finish_command_buffer(&self.queue, command_buffer);
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -217,7 +539,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                 SelectOp::new()
                     .check(|o, _| {
                         if let Some(o) = o.as_any().downcast_ref::<MetalSumReduce<T>>() {
-                            o.dim == 2
+                            o.dims == [2]
                         } else {
                             false
                         }
@@ -238,7 +560,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                     .ty::<MetalSumReduce<T>>()
                     .check(|o, _| {
                         if let Some(o) = o.as_any().downcast_ref::<MetalSumReduce<T>>() {
-                            o.dim == 3
+                            o.dims == [3]
                         } else {
                             false
                         }
@@ -265,7 +587,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                     .ty::<MetalSumReduce<T>>()
                     .check(|o, _| {
                         if let Some(o) = o.as_any().downcast_ref::<MetalSumReduce<T>>() {
-                            o.dim == 4
+                            o.dims == [4]
                         } else {
                             false
                         }
@@ -296,7 +618,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                     .ty::<MetalSumReduce<T>>()
                     .check(|o, _| {
                         if let Some(o) = o.as_any().downcast_ref::<MetalSumReduce<T>>() {
-                            o.dim == 5
+                            o.dims == [5]
                         } else {
                             false
                         }
@@ -315,6 +637,11 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                 // The intermediate mul can't be deleted
                 continue;
             }
+            if graph.has_hint(mul, &CompilerHint::NoFuse)
+                || graph.has_hint(sum_reduce, &CompilerHint::NoFuse)
+            {
+                continue;
+            }
             // Insert Matmul op
             let srcs = graph.get_sources(mul);
             let (mut src1, mut src1_shape) = (srcs[0].0, srcs[0].2);
@@ -368,26 +695,64 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                     .finish();
                 src2_shape = src2_shape.contiguous();
             }
-            let type_name = if T::is_f32() { "float32" } else { "float16" };
-            let matmul_op = graph
-                .add_op(Matmul::<T> {
+            let preferred_kernel = graph
+                .preferred_kernel(sum_reduce)
+                .or(graph.preferred_kernel(mul));
+            let prefer_gemm = preferred_kernel == Some("gemm");
+            let use_mps = cfg!(feature = "mps-matmul") || preferred_kernel == Some("mps");
+            let new_op = if use_mps {
+                graph.add_op(MpsMatmul::<T> {
+                    queue: queue.clone(),
+                    device: dev.clone(),
+                    _phantom: Default::default(),
+                })
+            } else {
+                let type_name = T::library_type_name();
+                let transpose = format!(
+                    "{}{}",
+                    if src1_shape.is_contiguous() { "n" } else { "t" },
+                    if src2_shape.indexes[src2_shape.len() - 1]
+                        > src2_shape.indexes[src2_shape.len() - 2]
+                    {
+                        "n"
+                    } else {
+                        "t"
+                    }
+                );
+                let matvec_function = format!(
+                    "gemv_{}{type_name}_bm{BM}_bn{BN}_tm4_tn4",
+                    if src2_shape.indexes[src2_shape.len() - 1]
+                        > src2_shape.indexes[src2_shape.len() - 2]
+                    {
+                        "t_"
+                    } else {
+                        ""
+                    }
+                );
+                graph.add_op(Matmul::<T> {
                     matmul_pipeline: select_function_from_lib(
                         &matmul_library,
-                        &format!( "gemm_{}{}_{type_name}_{type_name}_bm32_bn32_bk16_wm2_wn2_MN_naligned_K_taligned", if src1_shape.is_contiguous() {"n"} else {"t"}, if src2_shape.indexes[src2_shape.len() - 1] > src2_shape.indexes[src2_shape.len() - 2] {"n"} else {"t"}),
-                        &dev
+                        &GemmConfig::DEFAULT.function_name(&transpose, type_name),
+                        &dev,
                     ),
+                    matmul_library: matmul_library.clone(),
+                    transpose,
+                    autotune: matmul_autotuning_enabled(),
+                    tuned: Default::default(),
                     matvec_pipeline: select_function_from_lib(
                         &matvec_library,
-                        &format!(
-                            "gemv_{}{type_name}_bm{BM}_bn{BN}_tm4_tn4",
-                            if src2_shape.indexes[src2_shape.len() - 1] > src2_shape.indexes[src2_shape.len() - 2] { "t_" } else { "" }
-                        ),
-                        &dev
+                        &matvec_function,
+                        &dev,
                     ),
+                    matvec_library: matvec_library.clone(),
+                    matvec_function,
+                    prefer_gemm,
                     queue: queue.clone(),
                     device: dev.clone(),
-                    _phantom: Default::default()
+                    _phantom: Default::default(),
                 })
+            };
+            let matmul_op = new_op
                 .input(src1, 0, src1_shape)
                 .input(src2, 0, src2_shape)
                 .finish();
@@ -405,6 +770,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
             // Remove the old ops
             graph.graph.remove_node(mul);
             graph.graph.remove_node(sum_reduce);
+            graph.record_rewrite(if use_mps { "MpsMatmul" } else { "Matmul" });
         }
     }
 }
@@ -420,7 +786,13 @@ mod tests {
         tests::{assert_close_precision, random_vec},
     };
 
-    use crate::MetalCompiler;
+    use metal_rs::Device;
+
+    use super::{Matmul, MpsMatmul};
+    use crate::{
+        autotune::{tuned_config, TuningKey},
+        set_matmul_autotuning, GemmConfig, MetalCompiler,
+    };
     #[test]
     fn test_matrix_vector() {
         const M: usize = 53;
@@ -477,4 +849,220 @@ mod tests {
 
         assert_close_precision(&c.data(), &d_c.to_dtype::<f32>().as_vec(), 2);
     }
+
+    #[test]
+    fn test_batched_vectors() {
+        const K: usize = 96;
+        const N: usize = 160;
+        let mut cx = Graph::new();
+        let (a_vec, b_mat, c_mat) = (random_vec(3 * K), random_vec(K * N), random_vec(3 * K * N));
+        let a = cx.tensor::<R3<3, 1, K>>().set(a_vec.clone());
+        let b = cx.tensor::<R2<K, N>>().set(b_mat.clone());
+        let c = cx.tensor::<R3<3, K, N>>().set(c_mat.clone());
+        // Every vector multiplies the same matrix
+        let mut shared = a.matmul(b).retrieve();
+        // Every vector multiplies its own matrix
+        let mut batched = a.matmul(c).retrieve();
+
+        cx.compile(
+            <(GenericCompiler, MetalCompiler<f32>)>::default(),
+            (&mut shared, &mut batched),
+        );
+        cx.execute();
+
+        let d_dev = dfdx::tensor::Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_vec, (3, 1, K));
+        let d_shared = d_a.clone().matmul(d_dev.tensor_from_vec(b_mat, (K, N)));
+        let d_batched = d_a.matmul(d_dev.tensor_from_vec(c_mat, (3, K, N)));
+
+        assert_close_precision(&shared.data(), &d_shared.as_vec(), 2);
+        assert_close_precision(&batched.data(), &d_batched.as_vec(), 2);
+    }
+
+    #[test]
+    fn test_dyn_sequence_length() {
+        // Decoding a token and prefilling a prompt run the same compiled graph
+        const D: usize = 32;
+        const N: usize = 48;
+        let mut cx = Graph::new();
+        let w_data = random_vec(D * N);
+        let x = cx.tensor::<(Dyn<'S'>, Const<D>)>();
+        let w = cx.tensor::<R2<D, N>>().set(w_data.clone());
+        let q = cx.tensor::<(Const<2>, Dyn<'S'>, Const<D>)>();
+        let k = cx.tensor::<(Const<2>, Dyn<'T'>, Const<D>)>();
+        let mut proj = x.matmul(w).retrieve();
+        let mut scores = q.matmul(k.permute::<_, Axes3<0, 2, 1>>()).retrieve();
+
+        cx.compile(
+            <(GenericCompiler, MetalCompiler<f32>)>::default(),
+            (&mut proj, &mut scores),
+        );
+        for (s, t) in [(1, 1), (1, 9), (7, 16)] {
+            let (x_data, q_data, k_data) = (
+                random_vec(s * D),
+                random_vec(2 * s * D),
+                random_vec(2 * t * D),
+            );
+            x.set_dyn(x_data.clone(), &[s, D]);
+            q.set_dyn(q_data.clone(), &[2, s, D]);
+            k.set_dyn(k_data.clone(), &[2, t, D]);
+            cx.execute();
+
+            let d_dev = dfdx::tensor::Cpu::default();
+            let d_proj = d_dev
+                .tensor_from_vec(x_data, (s, D))
+                .matmul(d_dev.tensor_from_vec(w_data.clone(), (D, N)));
+            let d_scores = d_dev.tensor_from_vec(q_data, (2, s, D)).matmul(
+                d_dev
+                    .tensor_from_vec(k_data, (2, t, D))
+                    .permute::<_, dfdx::shapes::Axes3<0, 2, 1>>(),
+            );
+
+            assert_close_precision(&proj.data(), &d_proj.as_vec(), 2);
+            assert_close_precision(&scores.data(), &d_scores.as_vec(), 2);
+            proj.drop();
+            scores.drop();
+        }
+    }
+
+    #[test]
+    fn test_prefer_gemm_hint() {
+        const M: usize = 53;
+        const N: usize = 256;
+        let mut cx = Graph::new();
+        let (a_vec, b_mat) = (random_vec(M), random_vec(M * N));
+        let mut a = cx.named_tensor::<R2<1, M>>("Vec").set(a_vec.clone());
+        let mut b = cx.named_tensor::<R2<M, N>>("Mat").set(b_mat.clone());
+        let mut c = a
+            .matmul(b)
+            .hint(CompilerHint::PreferKernel("gemm".to_string()))
+            .retrieve();
+
+        cx.compile(
+            <(GenericCompiler, MetalCompiler<f32>)>::default(),
+            (&mut a, &mut b, &mut c),
+        );
+        assert!(cx
+            .graph
+            .node_weights()
+            .filter_map(|op| op.as_any().downcast_ref::<Matmul<f32>>())
+            .all(|op| op.prefer_gemm));
+        cx.execute();
+
+        let d_dev = dfdx::tensor::Cpu::default();
+        let d_a =
+            d_dev.tensor_from_vec(a_vec, (dfdx::shapes::Const::<1>, dfdx::shapes::Const::<M>));
+        let d_b =
+            d_dev.tensor_from_vec(b_mat, (dfdx::shapes::Const::<M>, dfdx::shapes::Const::<N>));
+        let d_c = d_a.matmul(d_b);
+
+        assert_close_precision(&c.data(), &d_c.as_vec(), 2);
+    }
+
+    #[test]
+    fn test_mps_hint() {
+        let mps = || CompilerHint::PreferKernel("mps".to_string());
+        let mut cx = Graph::new();
+        let (a_vec, at_vec) = (random_vec(2 * 5 * 7), random_vec(2 * 7 * 5));
+        let (b_mat, c_mat) = (random_vec(7 * 6), random_vec(2 * 6 * 7));
+        let a = cx.tensor::<R3<2, 5, 7>>().set(a_vec.clone());
+        let at = cx.tensor::<R3<2, 7, 5>>().set(at_vec.clone());
+        let b = cx.tensor::<R2<7, 6>>().set(b_mat.clone());
+        let c = cx.tensor::<R3<2, 6, 7>>().set(c_mat.clone());
+        // Shared B, folded into one multiplication
+        let mut shared = a.matmul(b).hint(mps()).retrieve();
+        // Transposed A
+        let mut transposed_a = at
+            .permute::<_, luminal::prelude::Axes3<0, 2, 1>>()
+            .matmul(b)
+            .hint(mps())
+            .retrieve();
+        // Batched, transposed B
+        let mut batched = a
+            .matmul(c.permute::<_, luminal::prelude::Axes3<0, 2, 1>>())
+            .hint(mps())
+            .retrieve();
+
+        cx.compile(
+            <(GenericCompiler, MetalCompiler<f32>)>::default(),
+            (&mut shared, &mut transposed_a, &mut batched),
+        );
+        assert_eq!(
+            cx.graph
+                .node_weights()
+                .filter(|op| op.as_any().is::<MpsMatmul<f32>>())
+                .count(),
+            3
+        );
+        assert!(!cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<Matmul<f32>>()));
+        cx.execute();
+
+        let d_dev = dfdx::tensor::Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_vec, (2, 5, 7));
+        let d_b = d_dev.tensor_from_vec(b_mat, (7, 6));
+        let d_c = d_dev.tensor_from_vec(c_mat, (2, 6, 7));
+        let d_at = d_dev.tensor_from_vec(at_vec, (2, 7, 5));
+        let d_shared = d_a.clone().matmul(d_b.clone());
+        let d_transposed_a = d_at
+            .permute::<_, dfdx::shapes::Axes3<0, 2, 1>>()
+            .matmul(d_b);
+        let d_batched = d_a.matmul(d_c.permute::<_, dfdx::shapes::Axes3<0, 2, 1>>());
+
+        assert_close_precision(&shared.data(), &d_shared.as_vec(), 3);
+        assert_close_precision(&transposed_a.data(), &d_transposed_a.as_vec(), 3);
+        assert_close_precision(&batched.data(), &d_batched.as_vec(), 3);
+    }
+
+    #[test]
+    fn test_matmul_autotuning() {
+        const M: usize = 64;
+        const K: usize = 96;
+        const N: usize = 80;
+        let (a_vec, b_vec) = (random_vec(M * K), random_vec(K * N));
+        let mut outputs = vec![];
+        let mut transpose = String::new();
+        for autotune in [true, false] {
+            set_matmul_autotuning(autotune);
+            let mut cx = Graph::new();
+            let a = cx.tensor::<R2<M, K>>().set(a_vec.clone());
+            let b = cx.tensor::<R2<K, N>>().set(b_vec.clone());
+            let mut c = a.matmul(b).retrieve();
+            cx.compile(<(GenericCompiler, MetalCompiler<f32>)>::default(), &mut c);
+            let matmuls = cx
+                .graph
+                .node_weights()
+                .filter_map(|op| op.as_any().downcast_ref::<Matmul<f32>>())
+                .collect::<Vec<_>>();
+            assert_eq!(matmuls.len(), 1);
+            assert_eq!(matmuls[0].autotune, autotune);
+            transpose = matmuls[0].transpose.clone();
+            cx.execute();
+            outputs.push(c.data());
+        }
+        set_matmul_autotuning(true);
+
+        // The shape was tuned the first time it ran, so it isn't benchmarked again
+        let key = TuningKey {
+            device: Device::system_default().unwrap().name().to_string(),
+            m: M,
+            k: K,
+            n: N,
+            type_name: "float32",
+            transpose,
+        };
+        let config = tuned_config(key, GemmConfig::candidates(K), |_| {
+            panic!("Benchmarked a tuned shape")
+        });
+        assert!(GemmConfig::CANDIDATES.contains(&config));
+
+        let d_dev = dfdx::tensor::Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_vec, (M, K));
+        let d_b = d_dev.tensor_from_vec(b_vec, (K, N));
+        let d_c = d_a.matmul(d_b).as_vec();
+        assert_close_precision(&outputs[0], &d_c, 3);
+        assert_close_precision(&outputs[1], &d_c, 3);
+    }
 }
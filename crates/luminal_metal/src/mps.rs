@@ -0,0 +1,102 @@
+//! Bindings to the MetalPerformanceShaders matrix multiplication, which metal-rs doesn't wrap
+
+use metal_rs::{
+    objc::{
+        class, msg_send,
+        runtime::{Object, BOOL, NO, YES},
+        sel, sel_impl,
+    },
+    BufferRef, CommandBufferRef, DeviceRef, NSUInteger,
+};
+
+const MPS_DATA_TYPE_FLOAT_BIT: u32 = 0x10000000;
+const MPS_DATA_TYPE_ALTERNATE_ENCODING_BIT: u32 = 0x1000000;
+/// MPSDataTypeFloat32
+pub(crate) const MPS_FLOAT32: u32 = MPS_DATA_TYPE_FLOAT_BIT | 32;
+/// MPSDataTypeFloat16
+pub(crate) const MPS_FLOAT16: u32 = MPS_DATA_TYPE_FLOAT_BIT | 16;
+/// MPSDataTypeBFloat16
+pub(crate) const MPS_BFLOAT16: u32 =
+    MPS_DATA_TYPE_FLOAT_BIT | MPS_DATA_TYPE_ALTERNATE_ENCODING_BIT | 16;
+
+/// A row-major matrix in a buffer, starting `offset` bytes in
+pub(crate) struct MpsMatrix<'a> {
+    pub buffer: &'a BufferRef,
+    pub offset: usize,
+    pub rows: usize,
+    pub columns: usize,
+    /// Bytes between the starts of consecutive rows
+    pub row_bytes: usize,
+}
+
+impl MpsMatrix<'_> {
+    /// Create the MPSMatrix. The caller owns it, and has to release it.
+    unsafe fn create(&self, data_type: u32) -> *mut Object {
+        let descriptor: *mut Object = msg_send![
+            class!(MPSMatrixDescriptor),
+            matrixDescriptorWithRows: self.rows as NSUInteger
+            columns: self.columns as NSUInteger
+            rowBytes: self.row_bytes as NSUInteger
+            dataType: data_type
+        ];
+        let matrix: *mut Object = msg_send![class!(MPSMatrix), alloc];
+        msg_send![
+            matrix,
+            initWithBuffer: self.buffer
+            offset: self.offset as NSUInteger
+            descriptor: descriptor
+        ]
+    }
+}
+
+fn objc_bool(b: bool) -> BOOL {
+    if b {
+        YES
+    } else {
+        NO
+    }
+}
+
+/// Encode `result = left * right`, transposing the inputs first if asked. `interior_columns` is the dimension the
+/// product sums over, and the matrices hold `data_type` elements. Descriptors are autoreleased, so this needs an
+/// autorelease pool.
+pub(crate) fn encode_matrix_multiplication(
+    device: &DeviceRef,
+    command_buffer: &CommandBufferRef,
+    data_type: u32,
+    (left, transpose_left): (&MpsMatrix, bool),
+    (right, transpose_right): (&MpsMatrix, bool),
+    result: &MpsMatrix,
+    interior_columns: usize,
+) {
+    unsafe {
+        let kernel: *mut Object = msg_send![class!(MPSMatrixMultiplication), alloc];
+        let kernel: *mut Object = msg_send![
+            kernel,
+            initWithDevice: device
+            transposeLeft: objc_bool(transpose_left)
+            transposeRight: objc_bool(transpose_right)
+            resultRows: result.rows as NSUInteger
+            resultColumns: result.columns as NSUInteger
+            interiorColumns: interior_columns as NSUInteger
+            alpha: 1.0f64
+            beta: 0.0f64
+        ];
+        let (left, right, result) = (
+            left.create(data_type),
+            right.create(data_type),
+            result.create(data_type),
+        );
+        let () = msg_send![
+            kernel,
+            encodeToCommandBuffer: command_buffer
+            leftMatrix: left
+            rightMatrix: right
+            resultMatrix: result
+        ];
+        // The command buffer keeps what it needs alive
+        for object in [left, right, result, kernel] {
+            let () = msg_send![object, release];
+        }
+    }
+}
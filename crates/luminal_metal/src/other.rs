@@ -1,21 +1,22 @@
 use std::{any::Any, marker::PhantomData, sync::Arc};
 
 use luminal::{
-    op::{InputTensor, Operator},
-    prelude::{
+    compiler_internals::{
         petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction},
         *,
     },
+    op::{InputTensor, Operator},
+    prelude::*,
     shape::symbolic::BigExpression,
 };
 use metal_rs::{
     objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
-    ComputePipelineState, Device, MTLResourceOptions,
+    ComputePipelineState, Device,
 };
 use rustc_hash::FxHashMap;
 
 use crate::{
-    compile_function,
+    compile_function, finish_command_buffer, new_buffer,
     prim::{MetalAdd, MetalContiguous, MetalCopyFromDevice, MetalCopyToDevice, MetalSumReduce},
     select_const, DispatchNElements, MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper,
     SetInt,
@@ -171,15 +172,11 @@ impl<T: MetalFloat> Operator for MetalARange<T> {
                 .size
                 .exec(unsafe { self.dyn_map.as_ref().unwrap() })
                 .unwrap();
-            let out = self.device.new_buffer(
-                (size * std::mem::size_of::<f16>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (size * std::mem::size_of::<f16>()) as u64);
 
             self.metal_forward(&[], command_buffer, &[], &[&out]);
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
             vec![Tensor::new(MetalBuffer(out))]
         })
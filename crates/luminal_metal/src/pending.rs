@@ -0,0 +1,107 @@
+use std::{cell::RefCell, time::Duration};
+
+use luminal::compiler_internals::{deferred_sync, profiling, record_device_time};
+use metal_rs::{
+    objc::{msg_send, sel, sel_impl},
+    CommandBuffer, CommandBufferRef, CommandQueue, CommandQueueRef, MTLCommandBufferStatus,
+};
+
+thread_local! {
+    /// Command buffers committed without waiting, and the queue they're on
+    static PENDING: RefCell<Option<(CommandQueue, Vec<CommandBuffer>)>> = const { RefCell::new(None) };
+}
+
+/// Commit a command buffer, and wait for it unless the graph is executing asynchronously.
+///
+/// Command buffers on the same queue run in order, so later ones see the writes of deferred ones. Queues don't order
+/// against each other, so work on another queue is waited for before committing. While the graph is profiled, the
+/// GPU time of the command buffer is recorded for the op that's running.
+pub(crate) fn finish_command_buffer(queue: &CommandQueueRef, command_buffer: &CommandBufferRef) {
+    let other_queue = PENDING.with(|p| {
+        p.borrow()
+            .as_ref()
+            .is_some_and(|(q, _)| !std::ptr::eq(&**q, queue))
+    });
+    if other_queue {
+        wait_for_device();
+    }
+    command_buffer.commit();
+    if !deferred_sync() {
+        command_buffer.wait_until_completed();
+        if profiling() {
+            record_device_time(gpu_time(command_buffer));
+        }
+        // Everything committed before on this queue is done too
+        wait_for_device();
+        return;
+    }
+    PENDING.with(|p| {
+        let mut pending = p.borrow_mut();
+        let (_, buffers) = pending.get_or_insert_with(|| (queue.to_owned(), vec![]));
+        buffers.retain(|b| b.status() != MTLCommandBufferStatus::Completed);
+        buffers.push(command_buffer.to_owned());
+    });
+}
+
+/// Time the GPU spent running a completed command buffer
+pub(crate) fn gpu_time(command_buffer: &CommandBufferRef) -> Duration {
+    let (start, end): (f64, f64) = unsafe {
+        (
+            msg_send![command_buffer, GPUStartTime],
+            msg_send![command_buffer, GPUEndTime],
+        )
+    };
+    Duration::from_secs_f64((end - start).max(0.))
+}
+
+/// Wait for command buffers committed while the graph executed asynchronously. Reading buffers on the host, like
+/// copying outputs back, does this first.
+pub fn wait_for_device() {
+    if let Some((_, buffers)) = PENDING.with(|p| p.borrow_mut().take()) {
+        for buffer in buffers {
+            buffer.wait_until_completed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use crate::MetalCompiler;
+
+    #[test]
+    fn test_execute_async() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<4, 8>>().set(random_vec(32)).keep();
+        let b = cx.tensor::<R2<8, 3>>().set(random_vec(24)).keep();
+        let hidden = a.matmul(b).exp();
+        let mut c = (hidden.cumsum_last_dim() * hidden)
+            .sum_reduce::<_, Axis<1>>()
+            .retrieve();
+        let mut d = a.sum_reduce::<_, Axis<0>>().sqrt().retrieve();
+        cx.execute();
+        let (c_ref, d_ref) = (c.data(), d.data());
+        c.drop();
+        d.drop();
+
+        cx.compile(MetalCompiler::<f32>::default(), (&mut c, &mut d));
+        for _ in 0..2 {
+            // Reading a retrieved tensor waits for the execution to finish
+            drop(cx.execute_async());
+            assert_close(&d.data(), &d_ref);
+            assert_close(&c.data(), &c_ref);
+            c.drop();
+            d.drop();
+
+            cx.execute_async().wait();
+            assert_close(&d.data(), &d_ref);
+            assert_close(&c.data(), &c_ref);
+            c.drop();
+            d.drop();
+        }
+    }
+}
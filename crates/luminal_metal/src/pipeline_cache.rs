@@ -0,0 +1,95 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    sync::{Mutex, OnceLock},
+};
+
+use metal_rs::{ComputePipelineState, Device, Library};
+use rustc_hash::FxHashMap;
+
+/// (Device registry id, kernel source hash, fast math)
+type LibraryKey = (u64, u64, bool);
+
+/// Libraries compiled from kernel source, shared across all compilers and graphs
+static LIBRARIES: OnceLock<Mutex<FxHashMap<LibraryKey, Library>>> = OnceLock::new();
+/// Pipeline states for functions of the cached libraries
+static PIPELINES: OnceLock<Mutex<FxHashMap<(LibraryKey, String), ComputePipelineState>>> =
+    OnceLock::new();
+
+/// A compiled kernel library, along with the key its pipelines are cached under
+#[derive(Clone)]
+pub(crate) struct KernelLibrary {
+    library: Library,
+    key: LibraryKey,
+}
+
+impl Deref for KernelLibrary {
+    type Target = Library;
+
+    fn deref(&self) -> &Self::Target {
+        &self.library
+    }
+}
+
+/// Get the library already compiled from this source on the device, or compile it and cache it.
+///
+/// The lock isn't held while compiling, so compilers on other threads aren't blocked. If two threads compile the
+/// same source at once, both get the library that was cached first.
+pub(crate) fn get_or_compile_library(
+    device: &Device,
+    source: &str,
+    fast_math: bool,
+    compile: impl FnOnce() -> Library,
+) -> KernelLibrary {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    let key = (device.registry_id(), hasher.finish(), fast_math);
+    let libraries = LIBRARIES.get_or_init(Default::default);
+    if let Some(library) = libraries.lock().unwrap().get(&key) {
+        return KernelLibrary {
+            library: library.clone(),
+            key,
+        };
+    }
+    let library = compile();
+    let library = libraries
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert(library)
+        .clone();
+    KernelLibrary { library, key }
+}
+
+/// Get the pipeline state already created for a function of the library, or create it and cache it
+pub(crate) fn get_or_create_pipeline(
+    library: &KernelLibrary,
+    function: &str,
+    create: impl FnOnce() -> ComputePipelineState,
+) -> ComputePipelineState {
+    let key = (library.key, function.to_string());
+    let pipelines = PIPELINES.get_or_init(Default::default);
+    if let Some(pipeline) = pipelines.lock().unwrap().get(&key) {
+        return pipeline.clone();
+    }
+    let pipeline = create();
+    pipelines
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert(pipeline)
+        .clone()
+}
+
+/// Number of pipeline states cached for the device
+pub fn cached_pipelines(device: &Device) -> usize {
+    let id = device.registry_id();
+    PIPELINES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .keys()
+        .filter(|((d, _, _), _)| *d == id)
+        .count()
+}
@@ -4,20 +4,35 @@ use super::*;
 use metal_rs::*;
 use objc::rc::autoreleasepool;
 use petgraph::visit::EdgeRef;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use luminal::{
+    compiler_internals::*,
     op::{Function as LFunction, *},
     prelude::*,
 };
 
 /// Copy a tensor to the GPU
+///
+/// If the tensor's content key is known, the upload is shared with any other copy of the same content on the device.
+/// The op holds a lease on the shared upload, so it stays resident until every op using it is dropped.
 #[derive(LuminalEqTrue, LuminalPrint, Clone)]
-pub struct MetalCopyToDevice<T>(Device, PhantomData<T>);
+pub struct MetalCopyToDevice<T>(
+    Device,
+    Option<u64>,
+    PhantomData<T>,
+    Option<upload_cache::UploadLease>,
+);
 
 impl<T> MetalCopyToDevice<T> {
     pub fn new(dev: Device) -> Self {
-        Self(dev, Default::default())
+        Self(dev, None, Default::default(), None)
+    }
+
+    /// Share uploads with other tensors with the same content key
+    pub fn with_content_key(mut self, key: Option<u64>) -> Self {
+        self.1 = key;
+        self
     }
 }
 
@@ -27,29 +42,133 @@ impl<T: MetalFloat> Operator for MetalCopyToDevice<T> {
             // Already on device
             return vec![inp.pop().unwrap().0.cloned()];
         }
-        let mut data = inp[0]
-            .0
-            .borrowed()
-            .data
-            .as_any()
-            .downcast_ref::<Vec<f32>>()
-            .unwrap()
-            .iter()
-            .copied()
-            .map(MetalFloat::from_f32)
-            .collect::<Vec<T>>();
-        if data.is_empty() {
-            data.push(T::from_f32(0.0));
-        }
-        let buffer = self.0.new_buffer_with_bytes_no_copy(
-            data.as_ptr() as *mut _,
-            (data.len() * std::mem::size_of::<T>()) as u64,
-            MTLResourceOptions::StorageModeShared,
-            None,
-        );
-        data.leak();
+        if let Some(ints) = inp[0].0.borrowed().data.as_any().downcast_ref::<Vec<i32>>() {
+            // Integer inputs are uploaded as int buffers as they are
+            let mut data = ints.clone();
+            if data.is_empty() {
+                data.push(0);
+            }
+            let buffer = new_buffer_with_data(&self.0, &data);
+            return vec![Tensor::new(MetalBuffer(buffer))];
+        }
+        let data = inp[0].0.borrowed().data.as_any();
+        if let Some(blocks) = data
+            .downcast_ref::<Q8_0Blocks>()
+            .map(|b| &b.0)
+            .or_else(|| data.downcast_ref::<Q4_0Blocks>().map(|b| &b.0))
+        {
+            // Quantized weights are uploaded as raw blocks, to be read by the quantized kernels
+            let buffer = new_buffer_with_data(&self.0, blocks);
+            return vec![Tensor::new(MetalBuffer(buffer))];
+        }
+        let upload = || {
+            let mut data = inp[0]
+                .0
+                .borrowed()
+                .data
+                .as_any()
+                .downcast_ref::<Vec<f32>>()
+                .unwrap()
+                .iter()
+                .copied()
+                .map(MetalFloat::from_f32)
+                .collect::<Vec<T>>();
+            if data.is_empty() {
+                data.push(T::from_f32(0.0));
+            }
+            if StorageMode::of_device(&self.0) == StorageMode::Managed {
+                // Discrete GPUs keep their own copy of managed buffers, so there's no use wrapping the data
+                return new_buffer_with_data(&self.0, &data);
+            }
+            let buffer = self.0.new_buffer_with_bytes_no_copy(
+                data.as_ptr() as *mut _,
+                (data.len() * std::mem::size_of::<T>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+                None,
+            );
+            data.leak();
+            buffer
+        };
+        let buffer = if let Some(key) = self.1 {
+            let (buffer, lease) = upload_cache::get_or_upload::<T>(&self.0, key, upload);
+            self.3 = Some(lease);
+            buffer
+        } else {
+            upload()
+        };
         vec![Tensor::new(MetalBuffer(buffer))]
     }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        // This op can accept integer inputs
+        if key == "int_inputs" {
+            return Some(Box::new(()));
+        }
+        if key == "write_region" {
+            let write = input.downcast::<RegionWrite>().ok()?;
+            let buffer = write.tensor.data.as_any().downcast_ref::<MetalBuffer>()?;
+            return self
+                .write_region(buffer, &write)
+                .map(|b| Box::new(Tensor::new(MetalBuffer(b))) as Box<dyn Any>);
+        }
+        None
+    }
+}
+
+impl<T: MetalFloat> MetalCopyToDevice<T> {
+    /// Write host data into runs of an uploaded buffer through a staging buffer. Uploads shared with other tensors
+    /// are copied first, so the write only changes this one.
+    fn write_region(&self, buffer: &Buffer, write: &RegionWrite) -> Option<Buffer> {
+        let elem = size_of::<T>() as u64;
+        let run_bytes = write.run_len as u64 * elem;
+        let end = write.runs.last().map(|r| *r as u64 * elem + run_bytes);
+        if end.unwrap_or_default() > buffer.length() {
+            // Not a buffer of T, like quantized weights
+            return None;
+        }
+        autoreleasepool(|| {
+            let queue = self.0.new_command_queue();
+            let command_buffer = queue.new_command_buffer();
+            let target = if self
+                .1
+                .is_some_and(|key| upload_cache::is_shared::<T>(&self.0, key, buffer))
+            {
+                let copy = new_buffer(&self.0, buffer.length());
+                let encoder = command_buffer.new_blit_command_encoder();
+                encoder.copy_from_buffer(buffer, 0, &copy, 0, buffer.length());
+                encoder.end_encoding();
+                copy
+            } else {
+                buffer.clone()
+            };
+
+            let data = write
+                .data
+                .iter()
+                .copied()
+                .map(MetalFloat::from_f32)
+                .collect::<Vec<T>>();
+            let staging = self.0.new_buffer_with_data(
+                data.as_ptr() as *const _,
+                data.len() as u64 * elem,
+                MTLResourceOptions::StorageModeShared,
+            );
+            let encoder = command_buffer.new_blit_command_encoder();
+            for (i, offset) in write.runs.iter().enumerate() {
+                encoder.copy_from_buffer(
+                    &staging,
+                    i as u64 * run_bytes,
+                    &target,
+                    *offset as u64 * elem,
+                    run_bytes,
+                );
+            }
+            encoder.end_encoding();
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+            Some(target)
+        })
+    }
 }
 
 /// Copy a tensor from the GPU
@@ -63,26 +182,30 @@ impl<T> MetalCopyFromDevice<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalCopyFromDevice<T> {
-    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        mut inp: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         if inp[0].0.borrowed().data.as_any().is::<Vec<f32>>() {
             // Already off device
-            return vec![inp.pop().unwrap().0.cloned()];
+            return Ok(vec![inp.pop().unwrap().0.cloned()]);
         }
-        let buffer = get_buffer_from_tensor(&inp[0].0);
+        let buffer = get_buffer_from_tensor(&inp[0].0)?;
+        sync_for_cpu(&self.0, buffer);
         let mut data = vec![0.0; buffer.length() as usize / std::mem::size_of::<T>()];
         let ptr = buffer.contents() as *mut T;
         for (i, d) in data.iter_mut().enumerate() {
             *d = unsafe { *ptr.add(i) }.to_f32();
         }
 
-        vec![Tensor {
+        Ok(vec![Tensor {
             data: Box::new(data),
-        }]
+        }])
     }
 
     fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
-        // This op can accept non contiguous inputs
-        if key == "non_contiguous" {
+        // This op can accept non contiguous inputs, and waits for the device before reading its input
+        if key == "non_contiguous" || key == "synchronizes" {
             return Some(Box::new(()));
         }
         None
@@ -118,10 +241,9 @@ impl<T: MetalFloat> Operator for MetalConstant<T> {
             ConstantValue::Float(f) => *f,
         });
         vec![Tensor {
-            data: Box::new(MetalBuffer(self.1.new_buffer_with_data(
-                &val as *const T as *const _,
-                std::mem::size_of::<T>() as u64,
-                MTLResourceOptions::StorageModeShared,
+            data: Box::new(MetalBuffer(new_buffer_with_data(
+                &self.1,
+                std::slice::from_ref(&val),
             ))),
         }]
     }
@@ -139,11 +261,14 @@ impl<T: MetalFloat> Operator for MetalConstant<T> {
 #[derive(LuminalEqTrue, LuminalPrint, Clone)]
 pub struct MetalContiguous<T> {
     pipeline: ComputePipelineState,
+    /// Whether the input is already contiguous, so the kernel copies 4 elements per thread
+    vectorized: bool,
     queue: CommandQueue,
     device: Device,
     dyn_symbols: Vec<char>,
     _phantom: PhantomData<T>,
     dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
 }
 
 impl<T: MetalFloat> MetalContiguous<T> {
@@ -156,7 +281,11 @@ impl<T: MetalFloat> MetalContiguous<T> {
         let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 3);
         let type_name = T::type_name();
-        let code = format!("
+        let vectorized = is_vectorizable::<T>(&[shape]);
+        let code = if vectorized {
+            render_vectorized_elementwise(type_name, 1, "input0")
+        } else {
+            format!("
 #include <metal_stdlib>
 using namespace metal;
 kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *out [[buffer(1)]], device int& n_elements [[buffer(2)]], uint idx [[thread_position_in_grid]]{rendered}) {{
@@ -164,14 +293,17 @@ kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *o
         out[idx] = inp[{idx_exp}];
     }}
 }}
-");
+")
+        };
         Self {
             pipeline: compile_function("mkernel", &code, &device),
+            vectorized,
             queue,
             device,
             dyn_symbols,
             _phantom: Default::default(),
             dyn_map,
+            fast_math: fast_math_enabled(),
         }
     }
 }
@@ -204,35 +336,38 @@ impl<T> MetalKernel for MetalContiguous<T> {
         );
 
         // Execute
-        encoder.dispatch_1d(inp_size);
+        if self.vectorized {
+            encoder.dispatch_1d(inp_size.div_ceil(VECTOR_WIDTH));
+        } else {
+            encoder.dispatch_1d(inp_size);
+        }
         encoder.end_encoding();
     }
 }
 
 impl<T: MetalFloat> Operator for MetalContiguous<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             // Setup command buffer and output buffer
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * size_of::<T>()) as u64);
 
             // Schedule op on the command buffer
             self.metal_forward(
-                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                &[(get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1)],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
             // Run the command buffer
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -248,12 +383,14 @@ impl<T: MetalFloat> Operator for MetalContiguous<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = Self::new(
-                    input_shapes[0],
-                    self.device.clone(),
-                    self.queue.clone(),
-                    self.dyn_map,
-                )
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
             }
         }
         if key == "elementwise" {
@@ -319,26 +456,25 @@ impl<T> MetalKernel for MetalLog2<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalLog2<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
-                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                &[(get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1)],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -414,26 +550,25 @@ impl<T> MetalKernel for MetalExp2<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalExp2<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
 
             let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
             self.metal_forward(
-                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                &[(get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1)],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -509,26 +644,25 @@ impl<T> MetalKernel for MetalSin<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalSin<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
-                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                &[(get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1)],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -604,26 +738,25 @@ impl<T> MetalKernel for MetalSqrt<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalSqrt<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
-                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                &[(get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1)],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -699,26 +832,25 @@ impl<T> MetalKernel for MetalRecip<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalRecip<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
-                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                &[(get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1)],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -742,11 +874,14 @@ impl<T: MetalFloat> Operator for MetalRecip<T> {
 #[derive(LuminalPrint, LuminalEqTrue, Clone)]
 pub struct MetalAdd<T> {
     pipeline: ComputePipelineState,
+    /// Whether both inputs are contiguous, so the kernel handles 4 elements per thread
+    vectorized: bool,
     queue: CommandQueue,
     device: Device,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
 }
 
 impl<T: MetalFloat> MetalAdd<T> {
@@ -761,24 +896,31 @@ impl<T: MetalFloat> MetalAdd<T> {
         let (b_idx_exp, b_valid_exp) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape], 4);
         let type_name = T::type_name();
-        let code = format!("
+        let vectorized = is_vectorizable::<T>(&[a_shape, b_shape]);
+        let code = if vectorized {
+            render_vectorized_elementwise(type_name, 2, "input0 + input1")
+        } else {
+            format!("
 #include <metal_stdlib>
 using namespace metal;
 kernel void mkernel(device {type_name} *inp_a [[buffer(0)]], device {type_name} *inp_b [[buffer(1)]], device {type_name} *out [[buffer(2)]], device int& n_elements [[buffer(3)]], uint idx [[thread_position_in_grid]]{rendered}) {{
     if (idx < n_elements) {{
         out[idx] =
-            (({a_valid_exp}) == 0 ? 0.0h : inp_a[{a_idx_exp}])
-            + (({b_valid_exp}) == 0 ? 0.0h : inp_b[{b_idx_exp}]);
+            (({a_valid_exp}) == 0 ? ({type_name})0 : inp_a[{a_idx_exp}])
+            + (({b_valid_exp}) == 0 ? ({type_name})0 : inp_b[{b_idx_exp}]);
     }}
 }}
-");
+")
+        };
         Self {
             pipeline: compile_function("mkernel", &code, &device),
+            vectorized,
             queue,
             device,
             dyn_symbols,
             _phantom: Default::default(),
             dyn_map,
+            fast_math: fast_math_enabled(),
         }
     }
 }
@@ -811,35 +953,38 @@ impl<T> MetalKernel for MetalAdd<T> {
             4,
         );
         // Execute
-        encoder.dispatch_1d(inp_size);
+        if self.vectorized {
+            encoder.dispatch_1d(inp_size.div_ceil(VECTOR_WIDTH));
+        } else {
+            encoder.dispatch_1d(inp_size);
+        }
         encoder.end_encoding();
     }
 }
 
 impl<T: MetalFloat> Operator for MetalAdd<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
                 &[
-                    (get_buffer_from_tensor(&tensors[0].0), tensors[0].1),
-                    (get_buffer_from_tensor(&tensors[1].0), tensors[1].1),
+                    (get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1),
+                    (get_buffer_from_tensor(&tensors[1].0)?, tensors[1].1),
                 ],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -855,13 +1000,15 @@ impl<T: MetalFloat> Operator for MetalAdd<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = Self::new(
-                    input_shapes[0],
-                    input_shapes[1],
-                    self.device.clone(),
-                    self.queue.clone(),
-                    self.dyn_map,
-                )
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        input_shapes[1],
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
             }
         }
         if key == "elementwise" {
@@ -874,11 +1021,14 @@ impl<T: MetalFloat> Operator for MetalAdd<T> {
 #[derive(LuminalPrint, LuminalEqTrue, Clone)]
 pub struct MetalMul<T> {
     pipeline: ComputePipelineState,
+    /// Whether both inputs are contiguous, so the kernel handles 4 elements per thread
+    vectorized: bool,
     queue: CommandQueue,
     device: Device,
     dyn_symbols: Vec<char>,
     _phantom: PhantomData<T>,
     dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
 }
 
 impl<T: MetalFloat> MetalMul<T> {
@@ -893,24 +1043,31 @@ impl<T: MetalFloat> MetalMul<T> {
         let (b_idx_exp, b_valid_exp) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape], 4);
         let type_name = T::type_name();
-        let code = format!("
+        let vectorized = is_vectorizable::<T>(&[a_shape, b_shape]);
+        let code = if vectorized {
+            render_vectorized_elementwise(type_name, 2, "input0 * input1")
+        } else {
+            format!("
 #include <metal_stdlib>
 using namespace metal;
 kernel void mkernel(device {type_name} *inp_a [[buffer(0)]], device {type_name} *inp_b [[buffer(1)]], device {type_name} *out [[buffer(2)]], device int& n_elements [[buffer(3)]], uint idx [[thread_position_in_grid]]{rendered}) {{
     if (idx < n_elements) {{
         out[idx] =
-            (({a_valid_exp}) == 0 ? 0.0h : inp_a[{a_idx_exp}])
-            * (({b_valid_exp}) == 0 ? 0.0h : inp_b[{b_idx_exp}]);
+            (({a_valid_exp}) == 0 ? ({type_name})0 : inp_a[{a_idx_exp}])
+            * (({b_valid_exp}) == 0 ? ({type_name})0 : inp_b[{b_idx_exp}]);
     }}
 }}
-");
+")
+        };
         Self {
             pipeline: compile_function("mkernel", &code, &device),
+            vectorized,
             queue,
             device,
             dyn_symbols,
             _phantom: Default::default(),
             dyn_map,
+            fast_math: fast_math_enabled(),
         }
     }
 }
@@ -944,35 +1101,38 @@ impl<T> MetalKernel for MetalMul<T> {
         );
 
         // Execute
-        encoder.dispatch_1d(inp_size);
+        if self.vectorized {
+            encoder.dispatch_1d(inp_size.div_ceil(VECTOR_WIDTH));
+        } else {
+            encoder.dispatch_1d(inp_size);
+        }
         encoder.end_encoding();
     }
 }
 
 impl<T: MetalFloat> Operator for MetalMul<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
                 &[
-                    (get_buffer_from_tensor(&tensors[0].0), tensors[0].1),
-                    (get_buffer_from_tensor(&tensors[1].0), tensors[1].1),
+                    (get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1),
+                    (get_buffer_from_tensor(&tensors[1].0)?, tensors[1].1),
                 ],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -988,13 +1148,15 @@ impl<T: MetalFloat> Operator for MetalMul<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = Self::new(
-                    input_shapes[0],
-                    input_shapes[1],
-                    self.device.clone(),
-                    self.queue.clone(),
-                    self.dyn_map,
-                )
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        input_shapes[1],
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
             }
         }
         if key == "elementwise" {
@@ -1012,6 +1174,7 @@ pub struct MetalLessThan<T> {
     dyn_symbols: Vec<char>,
     _phantom: PhantomData<T>,
     dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
 }
 
 impl<T: MetalFloat> MetalLessThan<T> {
@@ -1031,8 +1194,8 @@ impl<T: MetalFloat> MetalLessThan<T> {
 using namespace metal;
 kernel void mkernel(device {type_name} *inp_a [[buffer(0)]], device {type_name} *inp_b [[buffer(1)]], device {type_name} *out [[buffer(2)]], device int& n_elements [[buffer(3)]], uint idx [[thread_position_in_grid]]{rendered}) {{
     if (idx < n_elements) {{
-        {type_name} a_t = 0.0h;
-        {type_name} b_t = 0.0h;
+        {type_name} a_t = 0;
+        {type_name} b_t = 0;
         if (({a_valid_exp}) != 0) {{
             a_t = inp_a[{a_idx_exp}];
         }}
@@ -1046,7 +1209,7 @@ kernel void mkernel(device {type_name} *inp_a [[buffer(0)]], device {type_name}
         }}
     }}
 }}
-", if T::is_f32() {"1.0"} else {"1.0h"},if T::is_f32() {"0.0"} else {"0.0h"},
+", if type_name == "half" {"1.0h"} else {"1.0"}, if type_name == "half" {"0.0h"} else {"0.0"},
         );
         Self {
             pipeline: compile_function("mkernel", &code, &device),
@@ -1055,6 +1218,7 @@ kernel void mkernel(device {type_name} *inp_a [[buffer(0)]], device {type_name}
             dyn_symbols,
             _phantom: Default::default(),
             dyn_map,
+            fast_math: fast_math_enabled(),
         }
     }
 }
@@ -1095,29 +1259,28 @@ impl<T> MetalKernel for MetalLessThan<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalLessThan<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
                 &[
-                    (get_buffer_from_tensor(&tensors[0].0), tensors[0].1),
-                    (get_buffer_from_tensor(&tensors[1].0), tensors[1].1),
+                    (get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1),
+                    (get_buffer_from_tensor(&tensors[1].0)?, tensors[1].1),
                 ],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -1133,13 +1296,15 @@ impl<T: MetalFloat> Operator for MetalLessThan<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = Self::new(
-                    input_shapes[0],
-                    input_shapes[1],
-                    self.device.clone(),
-                    self.queue.clone(),
-                    self.dyn_map,
-                )
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        input_shapes[1],
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
             }
         }
         if key == "elementwise" {
@@ -1157,6 +1322,7 @@ pub struct MetalMod<T> {
     dyn_symbols: Vec<char>,
     _phantom: PhantomData<T>,
     dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
 }
 
 impl<T: MetalFloat> MetalMod<T> {
@@ -1188,6 +1354,7 @@ kernel void mkernel(device {type_name} *inp_a [[buffer(0)]], device {type_name}
             dyn_symbols,
             _phantom: Default::default(),
             dyn_map,
+            fast_math: fast_math_enabled(),
         }
     }
 }
@@ -1226,29 +1393,28 @@ impl<T> MetalKernel for MetalMod<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalMod<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
             let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
                 &[
-                    (get_buffer_from_tensor(&tensors[0].0), tensors[0].1),
-                    (get_buffer_from_tensor(&tensors[1].0), tensors[1].1),
+                    (get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1),
+                    (get_buffer_from_tensor(&tensors[1].0)?, tensors[1].1),
                 ],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -1264,13 +1430,15 @@ impl<T: MetalFloat> Operator for MetalMod<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = Self::new(
-                    input_shapes[0],
-                    input_shapes[1],
-                    self.device.clone(),
-                    self.queue.clone(),
-                    self.dyn_map,
-                )
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        input_shapes[1],
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
             }
         }
         if key == "elementwise" {
@@ -1280,23 +1448,127 @@ impl<T: MetalFloat> Operator for MetalMod<T> {
     }
 }
 
+/// Threads per threadgroup when reducing with a tree
+const TREE_REDUCE_THREADS: usize = 256;
+
+/// Reductions with few outputs over a long dimension, like full reductions to a scalar, run a threadgroup per output
+/// and combine partial values in a tree, instead of looping over the whole dimension on one thread per output.
+/// Very long dimensions always reduce with a tree, since pairwise combining loses far less precision than a single
+/// running sum.
+fn use_tree_reduce(n_outputs: usize, dim_size: usize) -> bool {
+    (n_outputs <= 1024 && dim_size >= TREE_REDUCE_THREADS) || dim_size >= 4096
+}
+
+/// Render a reduce kernel that runs one threadgroup per output, combining values with `combine(a, b)` and storing
+/// `epilogue` of the result
+#[allow(clippy::too_many_arguments)]
+fn render_tree_reduce(
+    type_name: &str,
+    accumulator: &str,
+    init: &str,
+    combine: &str,
+    idx_exp: &str,
+    valid_exp: &str,
+    rendered: &str,
+    epilogue: &str,
+) -> String {
+    let store = epilogue.replace("input0", "partials[0]");
+    format!("
+#include <metal_stdlib>
+using namespace metal;
+inline {accumulator} combine({accumulator} a, {accumulator} b) {{ return {combine}; }}
+kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *out [[buffer(1)]], device int& n_elements [[buffer(2)]], device int& front_size [[buffer(3)]], device int& back_size [[buffer(4)]], device int& dim_size [[buffer(5)]], uint i_ [[threadgroup_position_in_grid]], uint t_ [[thread_index_in_threadgroup]]{rendered}) {{
+    threadgroup {accumulator} partials[{TREE_REDUCE_THREADS}];
+    int a_ = i_ / back_size;
+    int b_ = i_ % back_size;
+    {accumulator} reduce_value = {init};
+    for (int c_ = t_; c_ < dim_size; c_ += {TREE_REDUCE_THREADS}) {{
+        uint idx = a_ * dim_size * back_size + c_ * back_size + b_;
+        if (({valid_exp}) != 0) {{
+            reduce_value = combine(reduce_value, ({accumulator})inp[{idx_exp}]);
+        }}
+    }}
+    partials[t_] = reduce_value;
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = {TREE_REDUCE_THREADS} / 2; s > 0; s >>= 1) {{
+        if (t_ < s) {{
+            partials[t_] = combine(partials[t_], partials[t_ + s]);
+        }}
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }}
+    if (t_ == 0) {{
+        out[i_] = ({type_name})({store});
+    }}
+}}
+")
+}
+
+/// Dispatch a reduce kernel with `n_outputs` outputs, using the tree kernel if it suits the shape
+fn dispatch_reduce(
+    encoder: &ComputeCommandEncoderRef,
+    pipeline: &ComputePipelineState,
+    tree_pipeline: &ComputePipelineState,
+    n_outputs: usize,
+    dim_size: usize,
+) {
+    if use_tree_reduce(n_outputs, dim_size) {
+        encoder.set_compute_pipeline_state(tree_pipeline);
+        encoder.dispatch_thread_groups(
+            MTLSize {
+                width: n_outputs as u64,
+                height: 1,
+                depth: 1,
+            },
+            MTLSize {
+                width: TREE_REDUCE_THREADS as u64,
+                height: 1,
+                depth: 1,
+            },
+        );
+    } else {
+        encoder.set_compute_pipeline_state(pipeline);
+        encoder.dispatch_1d(n_outputs);
+    }
+}
+
 #[derive(LuminalPrint, Clone)]
 pub struct MetalSumReduce<T> {
     pipeline: ComputePipelineState,
+    tree_pipeline: ComputePipelineState,
     queue: CommandQueue,
     device: Device,
-    pub dim: usize,
+    /// The axes of the input summed over, in increasing order
+    pub dims: Vec<usize>,
     dyn_symbols: Vec<char>,
+    accumulator: String,
+    /// Elementwise equation applied to each reduced value before it's stored, in terms of `input0`
+    pub epilogue: Option<String>,
     _phantom: PhantomData<T>,
     dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
 }
 
 impl<T> PartialEq for MetalSumReduce<T> {
     fn eq(&self, other: &Self) -> bool {
-        self.dim == other.dim
+        self.dims == other.dims && self.epilogue == other.epilogue
     }
 }
 
+/// View the input of a reduce over `dims` so the reduced values of each output are a single run of the logical
+/// index, returning the view and the axis the run starts at. Several reduced axes are permuted to the end.
+fn reduce_view(shape: ShapeTracker, dims: &[usize]) -> (ShapeTracker, usize) {
+    if let [dim] = dims {
+        return (shape, *dim);
+    }
+    let mut view = shape;
+    let axes = (0..shape.len())
+        .filter(|d| !dims.contains(d))
+        .chain(dims.iter().copied())
+        .collect::<Vec<_>>();
+    view.permute(&axes);
+    (view, shape.len() - dims.len())
+}
+
 impl<T: MetalFloat> MetalSumReduce<T> {
     pub fn new(
         shape: ShapeTracker,
@@ -1305,9 +1577,47 @@ impl<T: MetalFloat> MetalSumReduce<T> {
         queue: CommandQueue,
         dyn_map: *const FxHashMap<char, usize>,
     ) -> Self {
-        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        Self::with_accumulator(
+            shape,
+            vec![dim],
+            T::type_name(),
+            None,
+            device,
+            queue,
+            dyn_map,
+        )
+    }
+
+    /// Sum reduce over several axes in one pass
+    pub fn multi_axis(
+        shape: ShapeTracker,
+        mut dims: Vec<usize>,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        dims.sort_unstable();
+        dims.dedup();
+        Self::with_accumulator(shape, dims, T::type_name(), None, device, queue, dyn_map)
+    }
+
+    /// Sum reduce, accumulating in the given metal type and applying an epilogue to each output
+    fn with_accumulator(
+        shape: ShapeTracker,
+        dims: Vec<usize>,
+        accumulator: &str,
+        epilogue: Option<String>,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(reduce_view(shape, &dims).0);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 6);
         let type_name = T::type_name();
+        let store = epilogue
+            .as_deref()
+            .unwrap_or("input0")
+            .replace("input0", "reduce_value");
         let code = format!("
 #include <metal_stdlib>
 using namespace metal;
@@ -1315,34 +1625,57 @@ kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *o
     if (i_ < n_elements) {{
         int a_ = i_ / back_size;
         int b_ = i_ % back_size;
-        {type_name} reduce_value = 0.0;
+        {accumulator} reduce_value = 0.0;
         for (int c_ = 0; c_ < dim_size; c_++) {{
             uint idx = a_ * dim_size * back_size + c_ * back_size + b_;
             if (({valid_exp}) != 0) {{
-                reduce_value += inp[{idx_exp}];
+                reduce_value += ({accumulator})inp[{idx_exp}];
             }}
         }}
-        out[i_] = reduce_value;
+        out[i_] = ({type_name})({store});
     }}
 }}
 ");
+        // Partial sums always accumulate in fp32, so long fp16 sums don't round away small values
+        let tree_code = render_tree_reduce(
+            &type_name,
+            "float",
+            "0.0",
+            "a + b",
+            &idx_exp,
+            &valid_exp,
+            &rendered,
+            epilogue.as_deref().unwrap_or("input0"),
+        );
         Self {
             pipeline: compile_function("mkernel", &code, &device),
+            tree_pipeline: compile_function("mkernel", &tree_code, &device),
             queue,
             device,
-            dim,
+            dims,
             dyn_symbols,
+            accumulator: accumulator.to_string(),
+            epilogue,
             _phantom: Default::default(),
             dyn_map,
+            fast_math: fast_math_enabled(),
+        }
+    }
+}
+
+impl<T> MetalSumReduce<T> {
+    /// The input shape with the reduced axes removed
+    fn output_shape(&self, mut shape: ShapeTracker) -> ShapeTracker {
+        for dim in self.dims.iter().rev() {
+            shape.remove_dim(*dim);
         }
+        shape
     }
 }
 
 impl<T> MetalKernel for MetalSumReduce<T> {
     fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
-        let mut sh = input_shapes[0];
-        sh.remove_dim(self.dim);
-        vec![sh.n_elements() * size_of::<T>()]
+        vec![self.output_shape(input_shapes[0]).n_elements() * size_of::<T>()]
     }
     fn metal_forward(
         &self,
@@ -1351,28 +1684,24 @@ impl<T> MetalKernel for MetalSumReduce<T> {
         _: &[&Buffer],
         output_buffers: &[&Buffer],
     ) {
-        let mut sh = inputs[0].1;
-        sh.remove_dim(self.dim);
-        let inp_size = sh.n_elements().to_usize().unwrap();
-        let front_size: usize = inputs[0]
-            .1
+        let inp_size = self
+            .output_shape(inputs[0].1)
+            .n_elements()
+            .to_usize()
+            .unwrap();
+        let (view, start) = reduce_view(inputs[0].1, &self.dims);
+        let sizes = view
             .shape()
             .iter()
-            .take(self.dim)
             .map(|i| i.to_usize().unwrap())
-            .product();
-        let back_size: usize = inputs[0]
-            .1
-            .shape()
-            .iter()
-            .skip(self.dim + 1)
-            .map(|i| i.to_usize().unwrap())
-            .product();
-        let dim_size = inputs[0].1.shape()[self.dim].to_usize().unwrap();
+            .collect::<Vec<_>>();
+        let end = start + self.dims.len();
+        let front_size: usize = sizes[..start].iter().product();
+        let dim_size: usize = sizes[start..end].iter().product();
+        let back_size: usize = sizes[end..].iter().product();
 
         let encoder =
             command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
-        encoder.set_compute_pipeline_state(&self.pipeline);
 
         // Set inputs
         encoder.set_buffer(0, Some(inputs[0].0), 0);
@@ -1389,35 +1718,42 @@ impl<T> MetalKernel for MetalSumReduce<T> {
         );
 
         // Execute
-        encoder.dispatch_1d(inp_size);
+        dispatch_reduce(
+            encoder,
+            &self.pipeline,
+            &self.tree_pipeline,
+            inp_size,
+            dim_size,
+        );
         encoder.end_encoding();
     }
 }
 
 impl<T: MetalFloat> Operator for MetalSumReduce<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
-            let mut sh = tensors[0].1;
-            sh.remove_dim(self.dim);
-            let inp_size = sh.n_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let inp_size = self
+                .output_shape(tensors[0].1)
+                .n_elements()
+                .to_usize()
+                .unwrap();
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(
-                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                &[(get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1)],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -1433,13 +1769,49 @@ impl<T: MetalFloat> Operator for MetalSumReduce<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = Self::new(
-                    input_shapes[0],
-                    self.dim,
-                    self.device.clone(),
-                    self.queue.clone(),
-                    self.dyn_map,
-                )
+                *self = with_fast_math(self.fast_math, || {
+                    Self::with_accumulator(
+                        input_shapes[0],
+                        self.dims.clone(),
+                        &self.accumulator,
+                        self.epilogue.clone(),
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
+            }
+        }
+        if key == "fp32_accumulation" && !T::is_f32() {
+            if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
+                return Some(Box::new(Box::new(with_fast_math(self.fast_math, || {
+                    Self::with_accumulator(
+                        input_shapes[0],
+                        self.dims.clone(),
+                        "float",
+                        self.epilogue.clone(),
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })) as Box<dyn Operator>));
+            }
+        }
+        if key == "reduce_epilogue" {
+            if let Some((input_shapes, equation)) =
+                input.downcast_ref::<(Vec<ShapeTracker>, String)>()
+            {
+                return Some(Box::new(Box::new(with_fast_math(self.fast_math, || {
+                    Self::with_accumulator(
+                        input_shapes[0],
+                        self.dims.clone(),
+                        &self.accumulator,
+                        Some(compose_epilogue(&self.epilogue, equation)),
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })) as Box<dyn Operator>));
             }
         }
         None
@@ -1449,17 +1821,21 @@ impl<T: MetalFloat> Operator for MetalSumReduce<T> {
 #[derive(LuminalPrint, Clone)]
 pub struct MetalMaxReduce<T> {
     pipeline: ComputePipelineState,
+    tree_pipeline: ComputePipelineState,
     queue: CommandQueue,
     device: Device,
-    dim: usize,
+    pub dim: usize,
     dyn_symbols: Vec<char>,
+    /// Elementwise equation applied to each reduced value before it's stored, in terms of `input0`
+    pub epilogue: Option<String>,
     _phantom: PhantomData<T>,
     dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
 }
 
 impl<T> PartialEq for MetalMaxReduce<T> {
     fn eq(&self, other: &Self) -> bool {
-        self.dim == other.dim
+        self.dim == other.dim && self.epilogue == other.epilogue
     }
 }
 
@@ -1470,9 +1846,31 @@ impl<T: MetalFloat> MetalMaxReduce<T> {
         device: Device,
         queue: CommandQueue,
         dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        Self::with_epilogue(shape, dim, None, device, queue, dyn_map)
+    }
+
+    /// Max reduce, applying an epilogue to each output
+    fn with_epilogue(
+        shape: ShapeTracker,
+        dim: usize,
+        epilogue: Option<String>,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
     ) -> Self {
         let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
         let type_name = T::type_name();
+        // Half infinity is MAXHALF, while float and bfloat share float's exponent range
+        let inf = if type_name == "half" {
+            "MAXHALF"
+        } else {
+            "(float)0x7f800000"
+        };
+        let store = epilogue
+            .as_deref()
+            .unwrap_or("input0")
+            .replace("input0", "reduce_value");
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 6);
         let code = format!("
 #include <metal_stdlib>
@@ -1481,7 +1879,7 @@ kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *o
     if (i_ < n_elements) {{
         int a_ = i_ / back_size;
         int b_ = i_ % back_size;
-        {type_name} reduce_value = -{};
+        {type_name} reduce_value = -{inf};
         for (int c_ = 0; c_ < dim_size; c_++) {{
             uint idx = a_ * dim_size * back_size + c_ * back_size + b_;
             if (({valid_exp}) != 0) {{
@@ -1489,19 +1887,32 @@ kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *o
                 reduce_value = max(reduce_value, inp[a_idx]);
             }}
         }}
-        out[i_] = reduce_value;
+        out[i_] = ({type_name})({store});
     }}
 }}
-", if T::is_f32() {"(float)0x7f800000"} else {"MAXHALF"},
+",
+        );
+        let tree_code = render_tree_reduce(
+            &type_name,
+            &type_name,
+            &format!("-{inf}"),
+            "max(a, b)",
+            &idx_exp,
+            &valid_exp,
+            &rendered,
+            epilogue.as_deref().unwrap_or("input0"),
         );
         Self {
             pipeline: compile_function("mkernel", &code, &device),
+            tree_pipeline: compile_function("mkernel", &tree_code, &device),
             queue,
             device,
             dim,
             dyn_symbols,
+            epilogue,
             _phantom: Default::default(),
             dyn_map,
+            fast_math: fast_math_enabled(),
         }
     }
 }
@@ -1539,7 +1950,6 @@ impl<T> MetalKernel for MetalMaxReduce<T> {
 
         let encoder =
             command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
-        encoder.set_compute_pipeline_state(&self.pipeline);
 
         // Set inputs
         encoder.set_buffer(0, Some(inputs[0].0), 0);
@@ -1556,38 +1966,37 @@ impl<T> MetalKernel for MetalMaxReduce<T> {
         );
 
         // Execute
-        encoder.dispatch_1d(inp_size);
+        dispatch_reduce(
+            encoder,
+            &self.pipeline,
+            &self.tree_pipeline,
+            inp_size,
+            dim_size,
+        );
         encoder.end_encoding();
     }
 }
 
 impl<T: MetalFloat> Operator for MetalMaxReduce<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
-            let a = tensors[0]
-                .0
-                .borrowed()
-                .data
-                .as_any()
-                .downcast_ref::<MetalBuffer>()
-                .unwrap();
+            let a = get_buffer_from_tensor(&tensors[0].0)?;
 
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
             let mut sh = tensors[0].1;
             sh.remove_dim(self.dim);
             let inp_size = sh.n_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             self.metal_forward(&[(a, tensors[0].1)], command_buffer, &[], &[&out]);
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -1603,19 +2012,88 @@ impl<T: MetalFloat> Operator for MetalMaxReduce<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = Self::new(
-                    input_shapes[0],
-                    self.dim,
-                    self.device.clone(),
-                    self.queue.clone(),
-                    self.dyn_map,
-                )
+                *self = with_fast_math(self.fast_math, || {
+                    Self::with_epilogue(
+                        input_shapes[0],
+                        self.dim,
+                        self.epilogue.clone(),
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
+            }
+        }
+        if key == "reduce_epilogue" {
+            if let Some((input_shapes, equation)) =
+                input.downcast_ref::<(Vec<ShapeTracker>, String)>()
+            {
+                return Some(Box::new(Box::new(with_fast_math(self.fast_math, || {
+                    Self::with_epilogue(
+                        input_shapes[0],
+                        self.dim,
+                        Some(compose_epilogue(&self.epilogue, equation)),
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })) as Box<dyn Operator>));
             }
         }
         None
     }
 }
 
+/// Apply `equation` to the output of an existing epilogue
+fn compose_epilogue(epilogue: &Option<String>, equation: &str) -> String {
+    match epilogue {
+        Some(epilogue) => equation.replace("input0", &format!("({epilogue})")),
+        None => equation.to_string(),
+    }
+}
+
+/// Whether a node is a constant below the lowest finite `T`, like the -1e9 of additive attention masks, that's added to
+/// a softmax's input through at most a few elementwise ops. Converted to `T` it would be -inf, giving NaNs for rows the
+/// mask fully covers, so it's clamped to the lowest finite `T` instead.
+fn is_softmax_mask_constant<T: MetalFloat>(graph: &Graph, node: NodeIndex) -> bool {
+    let Some(Constant(ConstantValue::Float(value), _)) = graph
+        .graph
+        .node_weight(node)
+        .unwrap()
+        .as_any()
+        .downcast_ref()
+    else {
+        return false;
+    };
+    if *value >= T::finite_min() {
+        return false;
+    }
+    let op = |n| graph.graph.node_weight(n).unwrap().as_any();
+    let consumers = |n| {
+        graph
+            .graph
+            .edges_directed(n, petgraph::Direction::Outgoing)
+            .filter(|e| !e.weight().is_schedule())
+            .map(|e| e.target())
+    };
+    // Like `mask * -1e9` added to the scores, maybe after making the mask contiguous
+    let mut frontier = vec![node];
+    for _ in 0..3 {
+        frontier = frontier
+            .into_iter()
+            .flat_map(consumers)
+            .filter(|n| op(*n).is::<Add>() || op(*n).is::<Mul>() || op(*n).is::<Contiguous>())
+            .collect();
+        if frontier
+            .iter()
+            .any(|n| op(*n).is::<Add>() && consumers(*n).any(|c| op(c).is::<MaxReduce>()))
+        {
+            return true;
+        }
+    }
+    false
+}
+
 #[derive(Default, LuminalPrint)]
 pub struct PrimitiveCompiler<T>(PhantomData<T>);
 
@@ -1640,7 +2118,10 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
         {
             // Create copy node
             let copy_node = graph
-                .add_op(MetalCopyToDevice::<T>::new(dev.clone()))
+                .add_op(
+                    MetalCopyToDevice::<T>::new(dev.clone())
+                        .with_content_key(graph.content_keys.get(&function_node).copied()),
+                )
                 .input(function_node, 0, ShapeTracker::new(&[]))
                 .finish();
 
@@ -1739,7 +2220,7 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
             }
         }
 
-        // Copy prints and diffs from device
+        // Copy prints, diffs and taps from device
         for (output_node, edge) in graph
             .graph
             .node_indices()
@@ -1747,6 +2228,7 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
             .filter(|n| {
                 graph.graph.node_weight(*n).unwrap().as_any().is::<Print>()
                     || graph.graph.node_weight(*n).unwrap().as_any().is::<Diff>()
+                    || graph.graph.node_weight(*n).unwrap().as_any().is::<Tap>()
             })
             .map(|n| {
                 (
@@ -1783,6 +2265,11 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
         }
 
         // Swap primitive ops
+        let mask_constants = graph
+            .graph
+            .node_indices()
+            .filter(|n| is_softmax_mask_constant::<T>(graph, *n))
+            .collect::<FxHashSet<_>>();
         for id in graph.graph.node_indices().collect::<Vec<_>>() {
             let src_shapes = graph
                 .graph
@@ -1799,7 +2286,11 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                 *op_ref = Box::new(MetalExp2::<T>::new(dev.clone(), queue.clone()));
             } else if let Some(c) = op_ref.as_any().downcast_ref::<Constant>() {
                 *op_ref = Box::new(MetalConstant::<T>(
-                    c.0.clone(),
+                    if mask_constants.contains(&id) {
+                        ConstantValue::Float(T::finite_min())
+                    } else {
+                        c.0.clone()
+                    },
                     dev.clone(),
                     c.1,
                     Default::default(),
@@ -1842,7 +2333,7 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                     queue.clone(),
                     &graph.dyn_map,
                 ));
-            } else if let Some(SumReduce(dim)) = op_ref.as_any().downcast_ref() {
+            } else if let Some(SumReduce(dim, _)) = op_ref.as_any().downcast_ref() {
                 *op_ref = Box::new(MetalSumReduce::<T>::new(
                     src_shapes[0],
                     *dim,
@@ -1858,6 +2349,67 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                     queue.clone(),
                     &graph.dyn_map,
                 ));
+            } else if let Some(TopK {
+                k, dim, indices, ..
+            }) = op_ref.as_any().downcast_ref()
+            {
+                *op_ref = Box::new(topk::MetalTopK::<T>::new(
+                    src_shapes[0],
+                    *k,
+                    *dim,
+                    *indices,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ));
+            } else if let Some(ArgMax { dim, min }) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(argmax::MetalArgMax::<T>::new(
+                    src_shapes[0],
+                    *dim,
+                    *min,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ));
+            } else if let Some(CumSum(dim)) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(cumsum::MetalCumSum::<T>::new(
+                    src_shapes[0],
+                    *dim,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ));
+            } else if let Some(Sort {
+                descending,
+                indices,
+            }) = op_ref.as_any().downcast_ref()
+            {
+                *op_ref = Box::new(sort::MetalSort::<T>::new(
+                    src_shapes[0],
+                    *descending,
+                    *indices,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ));
+            } else if is::<ChunkedCrossEntropy>(op) {
+                *op_ref = Box::new(cross_entropy::MetalChunkedCrossEntropy::<T>::new(
+                    dev.clone(),
+                    queue.clone(),
+                ));
+            } else if let Some(EmbeddingBag { mode }) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(embedding_bag::MetalEmbeddingBag::<T>::new(
+                    *mode,
+                    dev.clone(),
+                    queue.clone(),
+                ));
+            } else if let Some(ScatterAdd { size, .. }) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(scatter_add::MetalScatterAdd::<T>::new(
+                    *size,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ));
             } else if is::<Contiguous>(op) {
                 *op_ref = Box::new(MetalContiguous::<T>::new(
                     src_shapes[0],
@@ -1866,6 +2418,84 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                     &graph.dyn_map,
                 ));
             }
+            if graph.graph.node_weight(id).unwrap().as_any().type_id() != op {
+                graph.record_rewrite("MetalPrimitive");
+            }
         }
     }
 }
+
+/// Merge chains of sum reduces, like the ones `sum_reduce` builds when summing over several axes, into one
+/// [`MetalSumReduce`] that reduces every axis in a single pass, without writing the partial sums in between
+#[derive(Default, LuminalPrint)]
+pub struct SumReduceMergeCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for SumReduceMergeCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        while let Some((first, second)) = find_reduce_chain::<T>(graph) {
+            let (src, src_output, src_shape) = graph.get_sources(first)[0];
+            let op = |node| {
+                graph.graph[node]
+                    .as_any()
+                    .downcast_ref::<MetalSumReduce<T>>()
+                    .unwrap()
+            };
+            let (first_op, second_op) = (op(first), op(second));
+            // The second reduce's axes count the axes left after the first
+            let kept = (0..src_shape.len())
+                .filter(|d| !first_op.dims.contains(d))
+                .collect::<Vec<_>>();
+            let mut dims = first_op.dims.clone();
+            dims.extend(second_op.dims.iter().map(|d| kept[*d]));
+            dims.sort_unstable();
+            let merged = MetalSumReduce::<T>::with_accumulator(
+                src_shape,
+                dims,
+                &second_op.accumulator,
+                second_op.epilogue.clone(),
+                second_op.device.clone(),
+                second_op.queue.clone(),
+                second_op.dyn_map,
+            );
+            let merged = graph
+                .add_op(merged)
+                .input(src, src_output, src_shape)
+                .finish();
+            move_outgoing_edge(second, merged, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                second,
+                merged,
+            );
+            graph.graph.remove_node(second);
+            graph.graph.remove_node(first);
+            graph.record_rewrite("SumReduceMerge");
+        }
+    }
+}
+
+/// A sum reduce feeding only into another sum reduce, with nothing applied to the values in between
+fn find_reduce_chain<T: MetalFloat>(graph: &Graph) -> Option<(NodeIndex, NodeIndex)> {
+    graph.graph.node_indices().find_map(|second| {
+        let second_op = graph.graph[second]
+            .as_any()
+            .downcast_ref::<MetalSumReduce<T>>()?;
+        let (first, _, shape) = *graph.get_sources(second).first()?;
+        let first_op = graph.graph[first]
+            .as_any()
+            .downcast_ref::<MetalSumReduce<T>>()?;
+        let plain_view = shape.is_contiguous() && !shape.is_sliced() && !shape.is_padded();
+        (plain_view
+            && first_op.epilogue.is_none()
+            && first_op.accumulator == second_op.accumulator
+            && !graph.no_delete.contains(&first)
+            && graph
+                .graph
+                .edges_directed(first, petgraph::Direction::Outgoing)
+                .count()
+                == 1)
+            .then_some((first, second))
+    })
+}
@@ -2,72 +2,153 @@ use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
 
 use metal_rs::{
     objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
-    ComputePipelineState, Device, MTLResourceOptions, MTLSize,
+    ComputePipelineState, Device, MTLSize,
 };
 use petgraph::visit::EdgeRef;
 
 use luminal::{
-    op::{InputTensor, Operator},
+    compiler_internals::*,
+    op::{get_indexes_from_tensor, InputTensor, OpError, Operator},
     prelude::*,
     shape::symbolic::BigExpression,
 };
 
 use crate::{
-    binary::MetalGather, get_buffer_from_tensor, MetalBuffer, MetalFloat, MetalKernel,
-    MetalKernelWrapper,
+    binary::MetalGather, finish_command_buffer, get_buffer_from_tensor, new_buffer,
+    new_buffer_with_data, MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper,
 };
 
 use super::{compile_function, SetInt};
 
+/// Block quantization formats the quantized ops can read, laid out like GGML's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QuantizedFormat {
+    /// See [`Q8_0Blocks`]
+    Q8_0,
+    /// See [`Q4_0Blocks`]
+    Q4_0,
+}
+
+impl QuantizedFormat {
+    /// Metal definition of the block type, and a function to dequantize element `i` of a block
+    fn block_definition(&self) -> &'static str {
+        match self {
+            QuantizedFormat::Q8_0 => {
+                "
+#define QK 32
+typedef struct {
+    half    d;      // delta
+    int8_t  qs[QK]; // quants
+} block_q;
+
+inline float dequantize(device const block_q& block, uint i) {
+    return (float)block.qs[i] * (float)block.d;
+}"
+            }
+            QuantizedFormat::Q4_0 => {
+                "
+#define QK 32
+typedef struct {
+    half    d;          // delta
+    uchar   qs[QK / 2]; // nibbles, with element i in the low half of byte i and element i + 16 in the high half
+} block_q;
+
+inline float dequantize(device const block_q& block, uint i) {
+    uchar q = block.qs[i % (QK / 2)];
+    return ((float)(i < QK / 2 ? q & 0x0F : q >> 4) - 8.f) * (float)block.d;
+}"
+            }
+        }
+    }
+}
+
 /// Multiplies a BxMxK matrix with a KxN matrix, resulting in a BxMxN matrix. This expects the first input to be a quantized 2D matrix
 #[derive(LuminalEqFalse, LuminalPrint, Clone)]
 pub struct QuantizedMatmul<T> {
     matmul_pipeline: ComputePipelineState,
     matvec_pipeline: ComputePipelineState,
+    format: QuantizedFormat,
     queue: CommandQueue,
     device: Device,
     _phantom: PhantomData<T>,
 }
 
 impl<T: MetalFloat> QuantizedMatmul<T> {
-    fn new(device: Device, queue: CommandQueue) -> Self {
+    fn new(device: Device, queue: CommandQueue, format: QuantizedFormat) -> Self {
         let type_name = T::type_name();
+        let block_definition = format.block_definition();
+        let matvec = match format {
+            QuantizedFormat::Q8_0 => Self::q8_0_matvec(),
+            QuantizedFormat::Q4_0 => Self::q4_0_matvec(),
+        };
         Self {
             matmul_pipeline: compile_function("matmul", &format!("
+#include <metal_stdlib>
 using namespace metal;
-#define QK8_0 32
-#define NB_Q8_0 8
-typedef struct {{
-    half    d;         // delta
-    int8_t  qs[QK8_0]; // quants
-}} block_q8_0;
+#define TILE 16
+{block_definition}
 
 kernel void matmul(
-    device block_q8_0* x [[buffer(0)]], // Quantized 2D matrix (KxN)
-    device {type_name}* y [[buffer(1)]], // Float src matrix (MxK)
-    device {type_name}* dst [[buffer(2)]], // Float dest matrix (MxN)
+    device block_q* x [[buffer(0)]], // Quantized 2D matrix (NxK), with rows padded to whole blocks
+    device {type_name}* y [[buffer(1)]], // Float src matrices (BxMxK)
+    device {type_name}* dst [[buffer(2)]], // Float dest matrices (BxMxN)
     constant uint& M [[buffer(3)]],
-    constant uint& K [[buffer(4)]], // Must be >= 32
-    constant uint& N [[buffer(5)]], // Must be >= 4
-    constant uint& mat_batch_stride [[buffer(6)]], // x batch stride
-    constant uint& vec_batch_stride [[buffer(7)]], // y batch stride
+    constant uint& K [[buffer(4)]],
+    constant uint& N [[buffer(5)]],
     uint3 threadgroup_position_in_grid [[threadgroup_position_in_grid]],
-    uint  thread_index_in_simdgroup[[thread_index_in_simdgroup]],
-    uint  simdgroup_index_in_threadgroup [[simdgroup_index_in_threadgroup]], // 2 simdgroups in a threadgroup
-    threadgroup uchar* shared_memory [[threadgroup(0)]]
+    uint2 thread_position_in_threadgroup [[thread_position_in_threadgroup]]
 ) {{
-    threadgroup half  * sa = (threadgroup half  *)(shared_memory);
-    threadgroup float * sb = (threadgroup float *)(shared_memory + 4096);
+    // Each threadgroup computes a TILE x TILE block of the output. For each quant block along K, the threadgroup
+    // loads a TILE x 32 block of the src matrix and dequantizes a TILE x 32 block of the weights into shared memory.
+    threadgroup float y_tile[TILE][QK];
+    threadgroup float x_tile[TILE][QK];
+    const uint tx = thread_position_in_threadgroup.x;
+    const uint ty = thread_position_in_threadgroup.y;
+    const uint row = threadgroup_position_in_grid.y * TILE + ty;
+    const uint col = threadgroup_position_in_grid.x * TILE + tx;
+    const uint weight_row = threadgroup_position_in_grid.x * TILE + ty;
+    const uint blocks_per_row = (K + QK - 1) / QK;
+    y += threadgroup_position_in_grid.z * M * K;
+    dst += threadgroup_position_in_grid.z * M * N;
+
+    float sum = 0.f;
+    for (uint ib = 0; ib < blocks_per_row; ++ib) {{
+        // Each thread loads 2 src values and dequantizes 2 weights. The src is zeroed past K, where the weights
+        // are padding
+        for (uint i = tx; i < QK; i += TILE) {{
+            const uint k = ib * QK + i;
+            y_tile[ty][i] = row < M && k < K ? (float)y[row * K + k] : 0.f;
+            x_tile[ty][i] = weight_row < N ? dequantize(x[weight_row * blocks_per_row + ib], i) : 0.f;
+        }}
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        for (uint i = 0; i < QK; ++i) {{
+            sum += y_tile[ty][i] * x_tile[tx][i];
+        }}
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }}
+    if (row < M && col < N) {{
+        dst[row * N + col] = ({type_name})sum;
+    }}
 }}
 "), &device),
-            matvec_pipeline: compile_function("matvec", &format!("
+            matvec_pipeline: compile_function("matvec", &matvec.replace("{type_name}", type_name), &device),
+            format,
+            queue,
+            device,
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Matvec for Q8_0 weights, with each simdgroup computing 4 rows and each thread 8 quants of a block at a time
+    fn q8_0_matvec() -> &'static str {
+        "
 using namespace metal;
 #define QK8_0 32
 #define NB_Q8_0 8
-typedef struct {{
+typedef struct {
     half    d;         // delta
     int8_t  qs[QK8_0]; // quants
-}} block_q8_0;
+} block_q8_0;
 
 kernel void matvec(
     device block_q8_0* x [[buffer(0)]], // Quantized 2D matrix
@@ -80,7 +161,7 @@ kernel void matvec(
     uint3 threadgroup_position_in_grid [[threadgroup_position_in_grid]],
     uint  thread_index_in_simdgroup[[thread_index_in_simdgroup]],
     uint  simdgroup_index_in_threadgroup [[simdgroup_index_in_threadgroup]] // 2 simdgroups in a threadgroup
-) {{
+) {
     const int num_rows = 4;
     const int num_simdgroups_per_threadgroup = 2;
     const int quant_width = 32;
@@ -98,7 +179,7 @@ kernel void matvec(
     // thread-local cache of vector values to work on. This thread must only work on 8 at a time
     {type_name} yl[8];
     // thread-local cache of 4 row sums
-    float sumf[num_rows] = {{0.f}};
+    float sumf[num_rows] = {0.f};
 
     const int ix = thread_index_in_simdgroup / 4;
     const int il = thread_index_in_simdgroup % 4;
@@ -107,41 +188,102 @@ kernel void matvec(
 
     // each thread in a SIMD group deals with 8 quants at a time
     // we start at 0-7 (ix) depending on the simdgroup index, and jump 8 indexes each time
-    for (int ib = ix; ib < num_quants_per_row; ib += 8) {{ // ib: current column position
+    for (int ib = ix; ib < num_quants_per_row; ib += 8) { // ib: current column position
         // Load vector values into the cache
-        for (int i = 0; i < 8; ++i) {{
+        for (int i = 0; i < 8; ++i) {
             yl[i] = y[i];
-        }}
+        }
 
         // Loop through 4 matrix rows
-        for (int row = 0; row < 4; ++row) {{
+        for (int row = 0; row < 4; ++row) {
             // Get pointer to matrix data
             device const int8_t* qs = x[ib + row * num_quants_per_row].qs + il * 8;
             float sumq = 0.f; // Partial sum
             // Loop through 8 columns
-            for (int iq = 0; iq < 8; ++iq) {{
+            for (int iq = 0; iq < 8; ++iq) {
                 sumq += qs[iq] * yl[iq]; // Multiply int with vector value (auto converts to float?)
-            }}
+            }
             sumf[row] += sumq * x[ib + row * num_quants_per_row].d; // multiply by delta (scaling factor)
-        }}
+        }
         y += 256; // Jump by 256
-    }}
+    }
 
     // each simdgroup is responsible for saving 4 final vector values (n rows)
-    for (int row = 0; row < num_rows; ++row) {{
+    for (int row = 0; row < num_rows; ++row) {
         const float tot = simd_sum(sumf[row]);
-        if (thread_index_in_simdgroup == 0 && first_row + row < dest_vec_size) {{
+        if (thread_index_in_simdgroup == 0 && first_row + row < dest_vec_size) {
             dst[first_row + row] = ({type_name})tot;
-        }}
-    }}
-}}
-"), &device),
-            queue,
-            device,
-            _phantom: Default::default(),
         }
     }
 }
+"
+    }
+
+    /// Matvec for Q4_0 weights, with each simdgroup computing 4 rows. Each thread takes 4 bytes of a block at a time,
+    /// unpacking both nibbles of each byte
+    fn q4_0_matvec() -> &'static str {
+        "
+using namespace metal;
+#define QK4_0 32
+typedef struct {
+    half    d;             // delta
+    uchar   qs[QK4_0 / 2]; // nibbles
+} block_q4_0;
+
+kernel void matvec(
+    device block_q4_0* x [[buffer(0)]], // Quantized 2D matrix, with rows padded to whole blocks
+    device {type_name}* y [[buffer(1)]], // Float src vector
+    device {type_name}* dst [[buffer(2)]], // Float dest vector
+    constant uint & src_vec_size [[buffer(3)]], // Matrix n cols (src vector size)
+    constant uint & dest_vec_size [[buffer(4)]], // Matrix n rows (dest vector size)
+    constant uint & vec_batch_stride [[buffer(6)]], // Vector batch stride
+    uint3 threadgroup_position_in_grid [[threadgroup_position_in_grid]],
+    uint  thread_index_in_simdgroup[[thread_index_in_simdgroup]],
+    uint  simdgroup_index_in_threadgroup [[simdgroup_index_in_threadgroup]] // 2 simdgroups in a threadgroup
+) {
+    const uint num_rows = 4;
+    const uint num_quants_per_row = (src_vec_size + QK4_0 - 1) / QK4_0;
+    const uint first_row = (threadgroup_position_in_grid.x * 2 + simdgroup_index_in_threadgroup) * num_rows;
+    y += threadgroup_position_in_grid.z * vec_batch_stride;
+    dst += threadgroup_position_in_grid.z * dest_vec_size;
+
+    // 4 threads work on each block, and 8 blocks are worked on at once
+    const uint ix = thread_index_in_simdgroup / 4;
+    const uint il = thread_index_in_simdgroup % 4;
+
+    float sumf[num_rows] = {0.f};
+    for (uint ib = ix; ib < num_quants_per_row; ib += 8) {
+        // Vector values for the low nibbles of this thread's 4 bytes, then for the high nibbles. Zero past the end,
+        // where the matrix is padding
+        float yl[8];
+        for (uint i = 0; i < 4; ++i) {
+            const uint k = ib * QK4_0 + il * 4 + i;
+            yl[i] = k < src_vec_size ? (float)y[k] : 0.f;
+            yl[i + 4] = k + QK4_0 / 2 < src_vec_size ? (float)y[k + QK4_0 / 2] : 0.f;
+        }
+
+        for (uint row = 0; row < num_rows && first_row + row < dest_vec_size; ++row) {
+            device const block_q4_0& block = x[(first_row + row) * num_quants_per_row + ib];
+            float sumq = 0.f;
+            for (uint i = 0; i < 4; ++i) {
+                const uchar q = block.qs[il * 4 + i];
+                sumq += ((float)(q & 0x0F) - 8.f) * yl[i] + ((float)(q >> 4) - 8.f) * yl[i + 4];
+            }
+            sumf[row] += sumq * (float)block.d;
+        }
+    }
+
+    // each simdgroup is responsible for saving 4 final vector values (n rows)
+    for (uint row = 0; row < num_rows; ++row) {
+        const float tot = simd_sum(sumf[row]);
+        if (thread_index_in_simdgroup == 0 && first_row + row < dest_vec_size) {
+            dst[first_row + row] = ({type_name})tot;
+        }
+    }
+}
+"
+    }
+}
 
 impl<T> MetalKernel for QuantizedMatmul<T> {
     fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
@@ -187,10 +329,16 @@ impl<T> MetalKernel for QuantizedMatmul<T> {
         let k = b_shape[b_dims - 2];
         let n = b_shape[b_dims - 1];
 
+        // Q4_0 rows are padded to whole blocks, but the Q8_0 matvec reads whole blocks of the vector
+        assert!(
+            self.format == QuantizedFormat::Q4_0 || k.is_multiple_of(32),
+            "Q8_0 matmul needs K to be a multiple of 32, got {k}"
+        );
+
         let encoder =
             command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
-        if batch_size == 1 {
-            // Matvec
+        if m == 1 {
+            // Matvec, with each batch as a separate vector
             encoder.set_compute_pipeline_state(&self.matvec_pipeline);
             encoder.set_buffer(0, Some(inputs[1].0), 0); // Matrix
             encoder.set_buffer(1, Some(inputs[0].0), 0); // Vector
@@ -200,18 +348,36 @@ impl<T> MetalKernel for QuantizedMatmul<T> {
             encoder.set_u32(5, 0); // Matrix batch stride
             encoder.set_u32(6, k as u32); // Vector batch stride
             encoder.dispatch_thread_groups(
-                MTLSize::new(n.div_ceil(8) as u64, 1, m as u64),
+                MTLSize::new(n.div_ceil(8) as u64, 1, batch_size as u64),
                 MTLSize::new(8, 8, 1),
             );
         } else {
-            todo!()
+            // Matmul, dequantizing the weights inside the K loop
+            encoder.set_compute_pipeline_state(&self.matmul_pipeline);
+            encoder.set_buffer(0, Some(inputs[1].0), 0); // Matrix
+            encoder.set_buffer(1, Some(inputs[0].0), 0); // Src matrices
+            encoder.set_buffer(2, Some(output_buffers[0]), 0); // Dest matrices
+            encoder.set_u32(3, m as u32);
+            encoder.set_u32(4, k as u32);
+            encoder.set_u32(5, n as u32);
+            encoder.dispatch_thread_groups(
+                MTLSize::new(
+                    n.div_ceil(16) as u64,
+                    m.div_ceil(16) as u64,
+                    batch_size as u64,
+                ),
+                MTLSize::new(16, 16, 1),
+            );
         }
         encoder.end_encoding();
     }
 }
 
 impl<T: 'static + Clone> Operator for QuantizedMatmul<T> {
-    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
@@ -224,28 +390,27 @@ impl<T: 'static + Clone> Operator for QuantizedMatmul<T> {
                     a_shape[1].to_usize().unwrap(),
                 )
             } else {
-                (0, a_shape[0].to_usize().unwrap())
+                (1, a_shape[0].to_usize().unwrap())
             };
 
-            let out = self.device.new_buffer(
+            let out = new_buffer(
+                &self.device,
                 (batch_size * m * n * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
             );
 
             self.metal_forward(
                 &[
-                    (get_buffer_from_tensor(&inp[0].0), inp[0].1),
-                    (get_buffer_from_tensor(&inp[1].0), inp[1].1),
+                    (get_buffer_from_tensor(&inp[0].0)?, inp[0].1),
+                    (get_buffer_from_tensor(&inp[1].0)?, inp[1].1),
                 ],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -269,50 +434,40 @@ pub struct QuantizedGather<T> {
 }
 
 impl<T: MetalFloat> QuantizedGather<T> {
-    fn new(device: Device, queue: CommandQueue, embed_dim: usize) -> Self {
+    fn new(device: Device, queue: CommandQueue, embed_dim: usize, format: QuantizedFormat) -> Self {
         let type_name = T::type_name();
+        let block_definition = format.block_definition();
         Self {pipeline: compile_function("metal_gather", &format!(
             "
 #include <metal_stdlib>
 using namespace metal;
-#define QK8_0 32
-typedef struct {{
-    half    d;         // delta
-    int8_t  qs[QK8_0]; // quants
-}} block_q8_0;
+{block_definition}
 
-kernel void metal_gather(device float *inp [[buffer(0)]], device block_q8_0 *weights [[buffer(1)]], device {type_name} *out [[buffer(2)]], device int& n_embeddings [[buffer(3)]], device int& embedding_dim [[buffer(4)]], uint2 idx [[thread_position_in_grid]]) {{
+kernel void metal_gather(device int *inp [[buffer(0)]], device block_q *weights [[buffer(1)]], device {type_name} *out [[buffer(2)]], device int& n_embeddings [[buffer(3)]], device int& embedding_dim [[buffer(4)]], uint2 idx [[thread_position_in_grid]]) {{
     if (idx.x < n_embeddings && idx.y < embedding_dim) {{
-        int block_idx = ((int)inp[idx.x] * embedding_dim + idx.y) / QK8_0;
-        out[idx.x * embedding_dim + idx.y] = weights[block_idx].qs[idx.y % QK8_0] * weights[block_idx].d;
+        int block_idx = inp[idx.x] * ((embedding_dim + QK - 1) / QK) + idx.y / QK;
+        out[idx.x * embedding_dim + idx.y] = ({type_name})dequantize(weights[block_idx], idx.y % QK);
     }}
 }}"), &device), device, embed_dim, queue, _phantom: Default::default()}
     }
 }
 
 impl<T: MetalFloat> Operator for QuantizedGather<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             // Setup buffers
-            let indexes = tensors[0]
-                .0
-                .borrowed()
-                .data
-                .as_any()
-                .downcast_ref::<Vec<f32>>()
-                .unwrap();
-            let index_buffer = self.device.new_buffer_with_data(
-                unsafe { std::mem::transmute(indexes.as_ptr()) },
-                (indexes.len() * std::mem::size_of::<f32>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let indexes = get_indexes_from_tensor(&tensors[0].0);
+            let index_buffer = new_buffer_with_data(&self.device, &indexes);
 
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
 
-            let out = self.device.new_buffer(
+            let out = new_buffer(
+                &self.device,
                 (indexes.len() * self.embed_dim * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
             );
 
             let encoder = command_buffer
@@ -321,7 +476,7 @@ impl<T: MetalFloat> Operator for QuantizedGather<T> {
 
             // Set inputs
             encoder.set_buffer(0, Some(&index_buffer), 0);
-            encoder.set_buffer(1, Some(get_buffer_from_tensor(&tensors[1].0)), 0);
+            encoder.set_buffer(1, Some(get_buffer_from_tensor(&tensors[1].0)?), 0);
             encoder.set_buffer(2, Some(&out), 0);
             encoder.set_u32(3, indexes.len() as u32);
             encoder.set_u32(4, self.embed_dim as u32);
@@ -341,20 +496,43 @@ impl<T: MetalFloat> Operator for QuantizedGather<T> {
             );
             encoder.end_encoding();
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        // This op can accept integer inputs
+        if key == "int_inputs" {
+            return Some(Box::new(()));
+        }
+        None
+    }
 }
 
+/// Metal compilation that swaps in quantized matmuls and gathers for the ones reading quantized weights
 #[derive(Default)]
-pub struct MetalQuantizedCompiler<T>(Vec<NodeIndex>, PhantomData<T>);
+pub struct MetalQuantizedCompiler<T> {
+    q8_0: Vec<NodeIndex>,
+    q4_0: Vec<NodeIndex>,
+    _phantom: PhantomData<T>,
+}
 
 impl<T> MetalQuantizedCompiler<T> {
+    /// Quantize `weights`, which are loaded as Q8_0 blocks
     pub fn new<To: ToIds>(weights: To) -> Self {
-        Self(weights.to_ids(), Default::default())
+        Self {
+            q8_0: weights.to_ids(),
+            q4_0: vec![],
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Also quantize `weights`, which are loaded as [`Q4_0Blocks`]
+    pub fn with_q4_0<To: ToIds>(mut self, weights: To) -> Self {
+        self.q4_0.extend(weights.to_ids());
+        self
     }
 }
 
@@ -362,9 +540,9 @@ impl<T: MetalFloat + Default> Compiler for MetalQuantizedCompiler<T> {
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
         let device = Device::system_default().unwrap();
         let queue = device.new_command_queue();
-        let mut weight_ids = self.0.clone();
+        let (mut q8_0, mut q4_0) = (self.q8_0.clone(), self.q4_0.clone());
         let mut local_remap = remap.to_ids_mut();
-        for w in &mut weight_ids {
+        for w in q8_0.iter_mut().chain(&mut q4_0) {
             local_remap.push(w);
         }
         // Normal metal compilation
@@ -379,28 +557,35 @@ impl<T: MetalFloat + Default> Compiler for MetalQuantizedCompiler<T> {
             &mut local_remap,
         );
         // Modify ops directly downstream of weights
-        for weight in downstream(&weight_ids, graph) {
-            for (target, (inp_ind, _, _)) in graph
-                .graph
-                .edges_directed(weight, petgraph::Direction::Outgoing)
-                .filter_map(|e| e.weight().as_data().map(|i| (e.target(), i)))
-                .collect::<Vec<_>>()
-            {
-                assert_eq!(
-                    inp_ind, 1,
-                    "Quantized weight {target:?} is the wrong input!",
-                );
-                let op_node = graph.graph.node_weight_mut(target).unwrap();
-                if let Some(gather) = op_node.as_any().downcast_ref::<MetalGather<T>>() {
-                    *op_node = Box::new(QuantizedGather::<T>::new(
-                        device.clone(),
-                        queue.clone(),
-                        gather.embed_dim,
-                    ));
-                } else if op_node.as_any().is::<super::matmul::Matmul<T>>() {
-                    *op_node = Box::new(QuantizedMatmul::<T>::new(device.clone(), queue.clone()));
-                } else {
-                    panic!("Quantized weight {target:?} is an input to a node that isn't a matmul or gather!");
+        for (weight_ids, format) in [(q8_0, QuantizedFormat::Q8_0), (q4_0, QuantizedFormat::Q4_0)] {
+            for weight in downstream(&weight_ids, graph) {
+                for (target, (inp_ind, _, _)) in graph
+                    .graph
+                    .edges_directed(weight, petgraph::Direction::Outgoing)
+                    .filter_map(|e| e.weight().as_data().map(|i| (e.target(), i)))
+                    .collect::<Vec<_>>()
+                {
+                    assert_eq!(
+                        inp_ind, 1,
+                        "Quantized weight {target:?} is the wrong input!",
+                    );
+                    let op_node = graph.graph.node_weight_mut(target).unwrap();
+                    if let Some(gather) = op_node.as_any().downcast_ref::<MetalGather<T>>() {
+                        *op_node = Box::new(QuantizedGather::<T>::new(
+                            device.clone(),
+                            queue.clone(),
+                            gather.embed_dim,
+                            format,
+                        ));
+                    } else if op_node.as_any().is::<super::matmul::Matmul<T>>() {
+                        *op_node = Box::new(QuantizedMatmul::<T>::new(
+                            device.clone(),
+                            queue.clone(),
+                            format,
+                        ));
+                    } else {
+                        panic!("Quantized weight {target:?} is an input to a node that isn't a matmul or gather!");
+                    }
                 }
             }
         }
@@ -417,12 +602,12 @@ mod tests {
     };
     use luminal::{
         prelude::*,
-        tests::{assert_close, random_vec_rng},
+        tests::{assert_close, assert_close_precision, assert_exact, random_vec_rng},
     };
     use metal_rs::{Device, MTLResourceOptions};
-    use rand::{thread_rng, Rng};
+    use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
 
-    use crate::{MetalBuffer, MetalQuantizedCompiler};
+    use crate::{MetalBuffer, MetalCompiler, MetalQuantizedCompiler};
 
     #[repr(C, packed)]
     struct BlockQ8_0 {
@@ -533,4 +718,154 @@ mod tests {
         let d_c = d_b.matmul(d_a.permute());
         assert_close(&out.data(), &d_c.as_vec());
     }
+
+    #[test]
+    fn test_quantized_matmul_accuracy() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let weight_data = random_vec_rng(96 * 256, &mut rng);
+        let mat_data = random_vec_rng(24 * 256, &mut rng);
+        let vec_data = random_vec_rng(3 * 256, &mut rng);
+        let run = |quantized: bool| {
+            let mut cx = Graph::new();
+            let weights = cx.tensor::<R2<96, 256>>();
+            let mat = cx.tensor::<R2<24, 256>>().set(mat_data.clone());
+            let vecs = cx.tensor::<R3<3, 1, 256>>().set(vec_data.clone());
+            let mut mat_out = mat.matmul(weights.permute()).retrieve();
+            let mut vec_out = vecs.matmul(weights.permute()).retrieve();
+            if quantized {
+                // Blocks are uploaded as they are, like weights from GgufLoader::keep_quantized
+                cx.tensors.insert(
+                    (weights.id, 0),
+                    Tensor::new(Q8_0Blocks::quantize(&weight_data)),
+                );
+                cx.compile(
+                    MetalQuantizedCompiler::<f16>::new(vec![weights.id]),
+                    (&mut mat_out, &mut vec_out),
+                );
+            } else {
+                weights.set(weight_data.clone());
+                cx.compile(
+                    MetalCompiler::<f16>::default(),
+                    (&mut mat_out, &mut vec_out),
+                );
+            }
+            cx.execute();
+            (mat_out.data(), vec_out.data())
+        };
+        let (mat_q8, vec_q8) = run(true);
+        let (mat_f16, vec_f16) = run(false);
+
+        for (quantized, reference) in [(mat_q8, mat_f16), (vec_q8, vec_f16)] {
+            let error = quantized
+                .iter()
+                .zip(&reference)
+                .map(|(a, b)| (a - b).powi(2))
+                .sum::<f32>()
+                .sqrt();
+            let norm = reference.iter().map(|v| v * v).sum::<f32>().sqrt();
+            assert!(
+                error / norm < 0.03,
+                "Relative error of {} against fp16",
+                error / norm
+            );
+        }
+    }
+
+    #[test]
+    fn test_quantized_q4_0() {
+        let mut rng = StdRng::seed_from_u64(1);
+        // K isn't a multiple of the block size, so rows are padded
+        let weight_data = random_vec_rng(72 * 200, &mut rng);
+        let mat_data = random_vec_rng(24 * 200, &mut rng);
+        let vec_data = random_vec_rng(3 * 200, &mut rng);
+        let blocks = Q4_0Blocks::quantize(&weight_data, 200);
+        let dequantized = blocks.dequantize(200);
+        let run = |quantized: bool| {
+            let mut cx = Graph::new();
+            let weights = cx.tensor::<R2<72, 200>>();
+            let mat = cx.tensor::<R2<24, 200>>().set(mat_data.clone());
+            let vecs = cx.tensor::<R3<3, 1, 200>>().set(vec_data.clone());
+            let mut mat_out = mat.matmul(weights.permute()).retrieve();
+            let mut vec_out = vecs.matmul(weights.permute()).retrieve();
+            if quantized {
+                cx.tensors
+                    .insert((weights.id, 0), Tensor::new(blocks.clone()));
+                cx.compile(
+                    MetalQuantizedCompiler::<f32>::default().with_q4_0(vec![weights.id]),
+                    (&mut mat_out, &mut vec_out),
+                );
+            } else {
+                weights.set(dequantized.clone());
+                cx.compile(
+                    MetalCompiler::<f32>::default(),
+                    (&mut mat_out, &mut vec_out),
+                );
+            }
+            cx.execute();
+            (mat_out.data(), vec_out.data())
+        };
+        let (mat_q4, vec_q4) = run(true);
+        let (mat_f32, vec_f32) = run(false);
+
+        // Same as an fp32 matmul with the dequantized weights
+        assert_close_precision(&mat_q4, &mat_f32, 2);
+        assert_close_precision(&vec_q4, &vec_f32, 2);
+    }
+
+    /// A Q8_0 and a Q4_0 weight
+    struct Weights(GraphTensor<R2<72, 128>>, GraphTensor<R2<40, 72>>);
+
+    impl SerializeModule for Weights {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("q8_0", self.0);
+            s.tensor("q4_0", self.1);
+        }
+    }
+
+    #[test]
+    fn test_saved_quantized_weights() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let q8_0 = Q8_0Blocks::quantize(&random_vec_rng(72 * 128, &mut rng));
+        let q4_0 = Q4_0Blocks::quantize(&random_vec_rng(40 * 72, &mut rng), 72);
+        let input = random_vec_rng(5 * 128, &mut rng);
+        let path = std::env::temp_dir().join(format!(
+            "luminal_quantized_weights_{}.safetensors",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let run = |from_file: bool| {
+            let mut cx = Graph::new();
+            let weights = Weights(cx.named_tensor("Q8_0"), cx.named_tensor("Q4_0"));
+            let inp = cx.tensor::<R2<5, 128>>().set(input.clone());
+            let hidden = inp.matmul(weights.0.permute());
+            let mut out = hidden.matmul(weights.1.permute()).retrieve();
+            let (q8_nodes, q4_nodes) = if from_file {
+                let report = SafeTensorLoader::new(&[path]).load(&weights, &mut cx);
+                (
+                    report.quantized_nodes(QuantizationScheme::Q8_0),
+                    report.quantized_nodes(QuantizationScheme::Q4_0),
+                )
+            } else {
+                cx.tensors
+                    .insert((weights.0.id, 0), Tensor::new(q8_0.clone()));
+                cx.tensors
+                    .insert((weights.1.id, 0), Tensor::new(q4_0.clone()));
+                cx.keep_tensors(vec![weights.0.id, weights.1.id]);
+                SafeTensorSaver::new(path).save(&weights, &mut cx).unwrap();
+                (vec![weights.0.id], vec![weights.1.id])
+            };
+            cx.compile(
+                MetalQuantizedCompiler::<f32>::new(q8_nodes).with_q4_0(q4_nodes),
+                &mut out,
+            );
+            cx.execute();
+            out.data()
+        };
+        let in_memory = run(false);
+        let loaded = run(true);
+        std::fs::remove_file(path).unwrap();
+
+        // Loading the saved blocks gives the same outputs as quantizing in memory
+        assert_exact(&loaded, &in_memory);
+    }
 }
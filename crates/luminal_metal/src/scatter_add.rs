@@ -0,0 +1,147 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use luminal::{
+    op::{InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::{BigExpression, Expression},
+};
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device, MTLSize,
+};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    compile_function, finish_command_buffer, get_buffers_from_tensors, new_buffer, MetalBuffer,
+    MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
+};
+
+const THREADGROUP_SIZE: usize = 256;
+
+/// Sum values into bins by their indexes.
+///
+/// Each bin gets a thread, which walks every index in order and adds up the values landing in it. This needs no
+/// atomics and sums in the same order as the CPU op, at the cost of rescanning the indexes per bin, which suits short
+/// index lists like token histories. Accumulation is in fp32. Indexes are stored as T, so in fp16 values above 2048
+/// aren't exactly representable.
+#[derive(LuminalPrint, Clone)]
+pub struct MetalScatterAdd<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub size: Expression,
+    _phantom: PhantomData<T>,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+
+impl<T> PartialEq for MetalScatterAdd<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.size == other.size
+    }
+}
+
+impl<T: MetalFloat> MetalScatterAdd<T> {
+    pub fn new(
+        size: Expression,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let type_name = T::type_name();
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+
+kernel void mkernel(device {type_name} *indexes [[buffer(0)]], device {type_name} *values [[buffer(1)]], device {type_name} *out [[buffer(2)]], device uint& n_indexes [[buffer(3)]], device uint& n_bins [[buffer(4)]], uint bin [[thread_position_in_grid]]) {{
+    if (bin >= n_bins) return;
+    float acc = 0.0;
+    for (uint i = 0; i < n_indexes; i++) {{
+        float index = (float)indexes[i];
+        if (index >= 0.0 && (uint)index == bin) {{
+            acc += (float)values[i];
+        }}
+    }}
+    out[bin] = ({type_name})acc;
+}}
+");
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            size,
+            _phantom: Default::default(),
+            dyn_map,
+        }
+    }
+}
+
+impl<T> MetalScatterAdd<T> {
+    fn n_bins(&self) -> usize {
+        self.size
+            .exec(unsafe { self.dyn_map.as_ref().unwrap() })
+            .unwrap()
+    }
+}
+
+impl<T> MetalKernel for MetalScatterAdd<T> {
+    fn output_buffer_sizes(&self, _: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![BigExpression::from(self.size) * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let n_bins = self.n_bins();
+        if n_bins == 0 {
+            return;
+        }
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        encoder.set_buffer(2, Some(output_buffers[0]), 0);
+        encoder.set_u32(3, inputs[0].1.n_elements().to_usize().unwrap() as u32);
+        encoder.set_u32(4, n_bins as u32);
+
+        // Execute one thread per bin
+        encoder.dispatch_threads(
+            MTLSize::new(n_bins as u64, 1, 1),
+            MTLSize::new(THREADGROUP_SIZE.min(n_bins) as u64, 1, 1),
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalScatterAdd<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let out = new_buffer(&self.device, (self.n_bins() * size_of::<T>()).max(1) as u64);
+            let inputs = get_buffers_from_tensors(&tensors)?;
+
+            let command_buffer = self.queue.new_command_buffer();
+            self.metal_forward(&inputs, command_buffer, &[], &[&out]);
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
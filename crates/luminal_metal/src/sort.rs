@@ -0,0 +1,280 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use luminal::{
+    compiler_internals::*,
+    op::{sort_permutation, InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::BigExpression,
+};
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device, MTLSize,
+};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    compile_function, did_write, fast_math_enabled, finish_command_buffer, get_buffer_from_tensor,
+    get_idx_valid_exps, input_dyn_dims, new_buffer, render_dyn_dim_inputs, sync_for_cpu,
+    with_fast_math, MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
+};
+
+/// Largest row that gets sorted on the GPU. Longer rows are copied back and sorted on the CPU.
+pub const MAX_BITONIC_SORT_SIZE: usize = 4096;
+
+/// Sort along the last dimension, outputting either the sorted values or the sorting indexes.
+///
+/// Each row is bitonic sorted in threadgroup memory. Elements are compared by (value, original index),
+/// so the result is stable and matches the CPU sort exactly. Note indexes are stored as T, so in fp16 indexes
+/// above 2048 aren't exactly representable.
+#[derive(LuminalPrint, Clone)]
+pub struct MetalSort<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub descending: bool,
+    pub indices: bool,
+    dyn_symbols: Vec<char>,
+    static_row_size: Option<usize>,
+    _phantom: PhantomData<T>,
+    dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
+}
+
+impl<T> PartialEq for MetalSort<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.descending == other.descending && self.indices == other.indices
+    }
+}
+
+impl<T: MetalFloat> MetalSort<T> {
+    pub fn new(
+        shape: ShapeTracker,
+        descending: bool,
+        indices: bool,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 4);
+        let type_name = T::type_name();
+        let (value_order, nan_order) = if descending {
+            ("ka > kb", "a_nan")
+        } else {
+            ("ka < kb", "b_nan")
+        };
+        let output = if indices {
+            format!("({type_name})idxs[i_]")
+        } else {
+            format!("({type_name})keys[i_]")
+        };
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+
+// Strict total order on (value, index). Padding sorts last, NaNs sort as the largest value, ties keep index order.
+inline bool comes_before(float ka, ushort ia, float kb, ushort ib, uint row_size) {{
+    if (ia >= row_size || ib >= row_size) return ia < ib;
+    bool a_nan = isnan(ka);
+    bool b_nan = isnan(kb);
+    if (a_nan != b_nan) return {nan_order};
+    if (!a_nan && ka != kb) return {value_order};
+    return ia < ib;
+}}
+
+kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *out [[buffer(1)]], device uint& row_size [[buffer(2)]], device uint& padded_size [[buffer(3)]], threadgroup float* keys [[threadgroup(0)]], threadgroup ushort* idxs [[threadgroup(1)]], uint row [[threadgroup_position_in_grid]], uint tid [[thread_position_in_threadgroup]], uint tg_size [[threads_per_threadgroup]]{rendered}) {{
+    // Load the row into threadgroup memory
+    for (uint i_ = tid; i_ < padded_size; i_ += tg_size) {{
+        uint idx = row * row_size + i_;
+        keys[i_] = (i_ < row_size && ({valid_exp}) != 0) ? (float)inp[{idx_exp}] : 0.0;
+        idxs[i_] = (ushort)i_;
+    }}
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    // Bitonic sorting network
+    for (uint k_ = 2; k_ <= padded_size; k_ <<= 1) {{
+        for (uint j_ = k_ >> 1; j_ > 0; j_ >>= 1) {{
+            for (uint p_ = tid; p_ < padded_size / 2; p_ += tg_size) {{
+                uint a_ = 2 * j_ * (p_ / j_) + (p_ % j_);
+                uint b_ = a_ + j_;
+                bool swap = (a_ & k_) == 0
+                    ? comes_before(keys[b_], idxs[b_], keys[a_], idxs[a_], row_size)
+                    : comes_before(keys[a_], idxs[a_], keys[b_], idxs[b_], row_size);
+                if (swap) {{
+                    float tk = keys[a_];
+                    keys[a_] = keys[b_];
+                    keys[b_] = tk;
+                    ushort ti = idxs[a_];
+                    idxs[a_] = idxs[b_];
+                    idxs[b_] = ti;
+                }}
+            }}
+            threadgroup_barrier(mem_flags::mem_threadgroup);
+        }}
+    }}
+
+    for (uint i_ = tid; i_ < row_size; i_ += tg_size) {{
+        out[row * row_size + i_] = {output};
+    }}
+}}
+");
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            descending,
+            indices,
+            dyn_symbols,
+            static_row_size: shape.shape().last().and_then(|i| i.to_usize()),
+            _phantom: Default::default(),
+            dyn_map,
+            fast_math: fast_math_enabled(),
+        }
+    }
+
+    /// Sort on the CPU, for rows too long to fit in threadgroup memory
+    fn cpu_sort(&self, input: &Buffer, shape: ShapeTracker, output: &Buffer) {
+        let row_size = shape.shape().last().unwrap().to_usize().unwrap();
+        let n_elements = shape.n_elements().to_usize().unwrap();
+        sync_for_cpu(&self.device, input);
+        let inp = unsafe {
+            std::slice::from_raw_parts(
+                input.contents() as *const T,
+                input.length() as usize / size_of::<T>(),
+            )
+        };
+        let out =
+            unsafe { std::slice::from_raw_parts_mut(output.contents() as *mut T, n_elements) };
+        let (ind, val) = (shape.index_expression(), shape.valid_expression());
+        let mut row = vec![0.0; row_size];
+        for row_start in (0..n_elements).step_by(row_size.max(1)) {
+            for (i, r) in row.iter_mut().enumerate() {
+                *r = if val.exec_single_var(row_start + i) != 0 {
+                    inp[ind.exec_single_var(row_start + i)].to_f32()
+                } else {
+                    0.0
+                };
+            }
+            for (o, i) in out[row_start..row_start + row_size]
+                .iter_mut()
+                .zip(sort_permutation(&row, self.descending))
+            {
+                *o = T::from_f32(if self.indices { i as f32 } else { row[i] });
+            }
+        }
+        did_write(output);
+    }
+}
+
+impl<T> MetalKernel for MetalSort<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![input_shapes[0].n_elements() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let row_size = inputs[0].1.shape().last().unwrap().to_usize().unwrap();
+        let n_rows = inputs[0]
+            .1
+            .n_elements()
+            .to_usize()
+            .unwrap()
+            .checked_div(row_size)
+            .unwrap_or_default();
+        if n_rows == 0 {
+            return;
+        }
+        let padded_size = row_size.next_power_of_two();
+        let threadgroup_size = (padded_size / 2).clamp(1, 1024);
+
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(output_buffers[0]), 0);
+        encoder.set_u32(2, row_size as u32);
+        encoder.set_u32(3, padded_size as u32);
+        encoder.set_threadgroup_memory_length(0, (padded_size * size_of::<f32>()) as u64);
+        encoder.set_threadgroup_memory_length(
+            1,
+            (padded_size * size_of::<u16>()).next_multiple_of(16) as u64,
+        );
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            4,
+        );
+
+        // Execute one threadgroup per row
+        encoder.dispatch_thread_groups(
+            MTLSize::new(n_rows as u64, 1, 1),
+            MTLSize::new(threadgroup_size as u64, 1, 1),
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalSort<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+            let out = new_buffer(&self.device, (inp_size * size_of::<T>()) as u64);
+            let inp = get_buffer_from_tensor(&tensors[0].0)?;
+
+            let row_size = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
+            if row_size > MAX_BITONIC_SORT_SIZE {
+                self.cpu_sort(inp, tensors[0].1, &out);
+            } else {
+                let command_buffer = self.queue.new_command_buffer();
+                self.metal_forward(&[(inp, tensors[0].1)], command_buffer, &[], &[&out]);
+                finish_command_buffer(&self.queue, command_buffer);
+            }
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        // Only join command buffers if we know the row will always be sorted on the GPU
+        if key == "metal"
+            && self
+                .static_row_size
+                .map(|n| n <= MAX_BITONIC_SORT_SIZE)
+                .unwrap_or_default()
+        {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        // This op can accept non contiguous inputs
+        if key == "non_contiguous" {
+            return Some(Box::new(()));
+        }
+        if key == "recompile_shapes" {
+            if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        self.descending,
+                        self.indices,
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
+            }
+        }
+        None
+    }
+}
@@ -1,26 +1,26 @@
 use std::{
     cell::UnsafeCell,
     collections::{BTreeMap, BTreeSet},
-    ops::Deref,
     sync::Arc,
 };
 
 use itertools::Itertools;
-use metal_rs::{Buffer, Device, MTLResourceOptions};
+use metal_rs::{Buffer, Device};
 use rustc_hash::{FxHashMap, FxHashSet};
 
 use luminal::{
-    op::{InputTensor, Operator},
-    prelude::{
+    compiler_internals::{
         petgraph::{algo::toposort, stable_graph::NodeIndex, visit::EdgeRef, Direction},
         symbolic::BigExpression,
         *,
     },
+    op::{InputTensor, OpError, Operator},
+    prelude::*,
 };
 
-use crate::{MetalBuffer, MetalKernelWrapper};
+use crate::{new_buffer, MetalBuffer, MetalKernelWrapper};
 
-use super::get_buffer_from_tensor;
+use super::get_buffers_from_tensors;
 
 #[derive(Default, LuminalPrint)]
 pub struct StorageBufferCompiler;
@@ -139,7 +139,25 @@ impl Compiler for StorageBufferCompiler {
                 .map(|(_, _, i)| i)
                 .collect::<Vec<_>>();
             // Assign output buffers
-            for required_buffer in wrapper.0.output_buffer_sizes(&input_shapes) {
+            let in_place = in_place_source(graph, *node, &available_buffers);
+            for (output, required_buffer) in wrapper
+                .0
+                .output_buffer_sizes(&input_shapes)
+                .into_iter()
+                .enumerate()
+            {
+                // Write elementwise ops into their dead input's buffer
+                if let Some((source_node, source_output)) = in_place.filter(|(s, o)| {
+                    output == 0
+                        && !used.contains(s)
+                        && buffer_map.contains_key(s)
+                        && available_buffers[s].0[*o as usize] == required_buffer
+                }) {
+                    let buffer = buffer_map[&source_node].0[source_output as usize];
+                    buffer_map.get_mut(node).unwrap().0.push(buffer);
+                    used.insert(source_node);
+                    continue;
+                }
                 // Find an applicable buffer
                 if let Some((buffer_index, source_node, _)) = first_pass[&node]
                     .1
@@ -290,6 +308,39 @@ impl Compiler for StorageBufferCompiler {
     }
 }
 
+/// The source (and its output) whose buffer an elementwise op can write into, if the op is its only consumer and reads
+/// it element for element
+fn in_place_source(
+    graph: &mut Graph,
+    node: NodeIndex,
+    available_buffers: &FxHashMap<NodeIndex, (Vec<BigExpression>, Vec<BigExpression>)>,
+) -> Option<(NodeIndex, u8)> {
+    let [(source, output, shape)] = graph.get_sources(node)[..] else {
+        return None;
+    };
+    let single_consumer = graph
+        .graph
+        .edges_directed(source, Direction::Outgoing)
+        .filter(|e| !e.weight().is_schedule())
+        .count()
+        == 1;
+    if !single_consumer
+        || graph.no_delete.contains(&source)
+        || !available_buffers.contains_key(&source)
+        || !shape.is_contiguous()
+        || shape.is_sliced()
+        || shape.is_padded()
+    {
+        return None;
+    }
+    graph
+        .graph
+        .node_weight_mut(node)
+        .unwrap()
+        .custom("elementwise", Box::<()>::default())
+        .map(|_| (source, output))
+}
+
 fn btreeset_intersection<T: Ord>(mut a: BTreeSet<T>, b: &BTreeSet<T>) -> BTreeSet<T> {
     a.retain(|i| b.contains(i));
     a
@@ -313,15 +364,13 @@ impl Operator for AllocateMetalBuffers {
                 .buffer_sizes
                 .iter()
                 .map(|e| {
-                    self.dev.new_buffer(
-                        e.exec(dyn_map).unwrap() as u64,
-                        MTLResourceOptions::StorageModeShared,
-                    )
+                    // Metal can't allocate empty buffers
+                    new_buffer(&self.dev, e.exec(dyn_map).unwrap().max(1) as u64)
                 })
                 .collect();
         } else {
             for (size, buffer) in self.buffer_sizes.iter().zip(buffers) {
-                let size = size.exec(dyn_map).unwrap() as u64;
+                let size = size.exec(dyn_map).unwrap().max(1) as u64;
                 if buffer.length() != size {
                     // TODO: For some reason this causes bad outputs. Maybe we are relying on buffer length somewhere? We shouldn't be.
                     // Also, it seems we are getting the benifits of this without actually doing it. Maybe metal is doing it in the background?
@@ -331,9 +380,7 @@ impl Operator for AllocateMetalBuffers {
                     //     length *= 2;
                     // }
                     let length = size;
-                    *buffer = self
-                        .dev
-                        .new_buffer(length, MTLResourceOptions::StorageModeShared);
+                    *buffer = new_buffer(&self.dev, length);
                 }
             }
         }
@@ -356,7 +403,10 @@ impl std::fmt::Debug for StorageBufferWrapper {
 }
 
 impl Operator for StorageBufferWrapper {
-    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         let buffers = unsafe { self.buffers.get().as_ref().unwrap() };
         let intermediate_buffers = self
             .intermediate_buffers
@@ -369,16 +419,14 @@ impl Operator for StorageBufferWrapper {
             .map(|i| &buffers[*i])
             .collect::<Vec<_>>();
         self.wrapper.0.without_command_buffer(
-            &inp.iter()
-                .map(|(t, sh)| (get_buffer_from_tensor(t).deref(), *sh))
-                .collect::<Vec<_>>(),
+            &get_buffers_from_tensors(&inp)?,
             &intermediate_buffers,
             &output_buffers,
         );
-        output_buffers
+        Ok(output_buffers
             .iter()
             .map(|buf| Tensor::new(MetalBuffer((*buf).clone())))
-            .collect()
+            .collect())
     }
 }
 
@@ -402,3 +450,73 @@ fn test_shared_buffers() {
 
     assert_close_precision(&e.data(), &e_unopt, 2);
 }
+
+#[test]
+fn test_in_place_unary_chain() {
+    use luminal::prelude::*;
+    use luminal::tests::{assert_close_precision, random_vec};
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<64>>().set(random_vec(64));
+    let mut b = a;
+    for _ in 0..5 {
+        b = b.exp2().log2();
+    }
+    let mut b = b.retrieve();
+
+    cx.execute();
+    let b_unopt = b.data();
+    b.drop();
+
+    // Compile without elementwise fusion so every op in the chain stays its own kernel
+    cx.compile(
+        <(
+            GenericCompiler,
+            crate::prim::PrimitiveCompiler<f16>,
+            crate::other::CopyCompiler<f16>,
+            crate::BufferCompilers,
+        )>::default(),
+        &mut b,
+    );
+    // Each op writes into the buffer of the op before it
+    let buffers = cx
+        .graph
+        .node_weights()
+        .find_map(|op| op.as_any().downcast_ref::<AllocateMetalBuffers>())
+        .unwrap()
+        .buffer_sizes
+        .len();
+    assert_eq!(buffers, 1);
+    cx.execute();
+
+    assert_close_precision(&b.data(), &b_unopt, 2);
+}
+
+#[test]
+fn test_no_in_place_on_retrieved_input() {
+    use luminal::prelude::*;
+    use luminal::tests::{assert_close_precision, random_vec};
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<64>>().set(random_vec(64));
+    let mut b = a.exp2().retrieve();
+    let mut c = b.sin().retrieve();
+
+    cx.execute();
+    let (b_unopt, c_unopt) = (b.data(), c.data());
+    b.drop();
+    c.drop();
+
+    cx.compile(
+        <(
+            GenericCompiler,
+            crate::prim::PrimitiveCompiler<f16>,
+            crate::other::CopyCompiler<f16>,
+            crate::BufferCompilers,
+        )>::default(),
+        (&mut b, &mut c),
+    );
+    cx.execute();
+
+    // The sin can't overwrite the exp2 output, which is still read back
+    assert_close_precision(&b.data(), &b_unopt, 2);
+    assert_close_precision(&c.data(), &c_unopt, 2);
+}
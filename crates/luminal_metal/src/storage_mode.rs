@@ -0,0 +1,207 @@
+use std::cell::Cell;
+
+use metal_rs::{objc::rc::autoreleasepool, *};
+
+use crate::wait_for_device;
+
+thread_local! {
+    static STORAGE_MODE: Cell<Option<StorageMode>> = const { Cell::new(None) };
+}
+
+/// Create buffers on this thread in `mode` instead of the device's default, or go back to the default with `None`.
+///
+/// Both modes work on every Mac: managed buffers on unified memory behave like shared ones with extra
+/// synchronization, and shared buffers on a discrete GPU are read over the bus. Forcing a mode is mostly useful to
+/// benchmark it, or to test the managed path on Apple Silicon. Buffers keep the mode they were created with, so this
+/// can change between executions.
+pub fn set_storage_mode(mode: Option<StorageMode>) {
+    STORAGE_MODE.with(|m| m.set(mode));
+}
+
+/// How buffers the CPU reads or writes are stored.
+///
+/// Unified memory devices like Apple Silicon share memory between the CPU and GPU, so buffers there are shared and
+/// need no synchronization. Discrete GPUs would read shared buffers over the bus, so buffers there are managed: the
+/// GPU works on its own copy, and the CPU has to flag its writes with [`did_write`] and pull in the GPU's writes with
+/// [`sync_for_cpu`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+    Shared,
+    Managed,
+}
+
+impl StorageMode {
+    /// The mode for a device with or without unified memory
+    pub fn select(unified_memory: bool) -> Self {
+        if unified_memory {
+            StorageMode::Shared
+        } else {
+            StorageMode::Managed
+        }
+    }
+
+    /// The mode buffers on a device are created with: the one set with [`set_storage_mode`], or the device's default
+    pub fn of_device(device: &DeviceRef) -> Self {
+        STORAGE_MODE
+            .with(|m| m.get())
+            .unwrap_or_else(|| Self::select(device.has_unified_memory()))
+    }
+
+    pub fn resource_options(self) -> MTLResourceOptions {
+        match self {
+            StorageMode::Shared => MTLResourceOptions::StorageModeShared,
+            StorageMode::Managed => MTLResourceOptions::StorageModeManaged,
+        }
+    }
+}
+
+/// A buffer of `length` bytes, in the device's storage mode
+pub(crate) fn new_buffer(device: &DeviceRef, length: u64) -> Buffer {
+    device.new_buffer(length, StorageMode::of_device(device).resource_options())
+}
+
+/// A buffer holding a copy of `data`, in the device's storage mode. Managed buffers created with data start out
+/// synchronized.
+pub(crate) fn new_buffer_with_data<T>(device: &DeviceRef, data: &[T]) -> Buffer {
+    device.new_buffer_with_data(
+        data.as_ptr() as *const _,
+        std::mem::size_of_val(data) as u64,
+        StorageMode::of_device(device).resource_options(),
+    )
+}
+
+fn is_managed(buffer: &BufferRef) -> bool {
+    buffer.storage_mode() == MTLStorageMode::Managed
+}
+
+/// Flag that the CPU wrote a buffer's contents, so the GPU sees the writes
+pub(crate) fn did_write(buffer: &BufferRef) {
+    if is_managed(buffer) {
+        buffer.did_modify_range(NSRange::new(0, buffer.length()));
+    }
+}
+
+/// Make the GPU's writes to a buffer visible to the CPU, before reading its contents. This waits for work deferred by
+/// an asynchronous execution, and for the synchronization to finish.
+pub(crate) fn sync_for_cpu(device: &DeviceRef, buffer: &BufferRef) {
+    wait_for_device();
+    if !is_managed(buffer) {
+        return;
+    }
+    autoreleasepool(|| {
+        let queue = device.new_command_queue();
+        let command_buffer = queue.new_command_buffer();
+        let encoder = command_buffer.new_blit_command_encoder();
+        encoder.synchronize_resource(buffer);
+        encoder.end_encoding();
+        command_buffer.commit();
+        command_buffer.wait_until_completed();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use metal_rs::{
+        objc::rc::autoreleasepool, Device, MTLResourceOptions, MTLStorageMode, NSRange,
+    };
+
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use super::{
+        did_write, new_buffer, new_buffer_with_data, set_storage_mode, sync_for_cpu, StorageMode,
+    };
+    use crate::MetalCompiler;
+
+    #[test]
+    fn test_mode_selection() {
+        assert_eq!(StorageMode::select(true), StorageMode::Shared);
+        assert_eq!(StorageMode::select(false), StorageMode::Managed);
+        assert_eq!(
+            StorageMode::select(true).resource_options(),
+            MTLResourceOptions::StorageModeShared
+        );
+        assert_eq!(
+            StorageMode::select(false).resource_options(),
+            MTLResourceOptions::StorageModeManaged
+        );
+    }
+
+    #[test]
+    fn test_cpu_read_after_gpu_write() {
+        autoreleasepool(|| {
+            let dev = Device::system_default().unwrap();
+            let buffer = new_buffer(&dev, 64);
+            let queue = dev.new_command_queue();
+            let command_buffer = queue.new_command_buffer();
+            let encoder = command_buffer.new_blit_command_encoder();
+            encoder.fill_buffer(&buffer, NSRange::new(0, 64), 7);
+            encoder.end_encoding();
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            sync_for_cpu(&dev, &buffer);
+            let data = unsafe { std::slice::from_raw_parts(buffer.contents() as *const u8, 64) };
+            assert!(data.iter().all(|b| *b == 7));
+        });
+    }
+
+    #[test]
+    fn test_gpu_read_after_cpu_write() {
+        autoreleasepool(|| {
+            let dev = Device::system_default().unwrap();
+            let src = new_buffer_with_data(&dev, &[1u32, 2, 3, 4]);
+            // Overwrite part of it from the CPU after creation
+            unsafe { *(src.contents() as *mut u32).add(2) = 30 };
+            did_write(&src);
+
+            let dst = new_buffer(&dev, 16);
+            let queue = dev.new_command_queue();
+            let command_buffer = queue.new_command_buffer();
+            let encoder = command_buffer.new_blit_command_encoder();
+            encoder.copy_from_buffer(&src, 0, &dst, 0, 16);
+            encoder.end_encoding();
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            sync_for_cpu(&dev, &dst);
+            let data = unsafe { std::slice::from_raw_parts(dst.contents() as *const u32, 4) };
+            assert_eq!(data, &[1, 2, 30, 4]);
+        });
+    }
+
+    #[test]
+    fn test_forced_storage_mode() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let b = cx.tensor::<R2<4, 2>>().set(random_vec(8));
+        let mut c = (a.matmul(b).exp() + 1.).retrieve();
+        cx.execute();
+        let expected = c.data();
+        c.drop();
+        cx.compile(MetalCompiler::<f32>::default(), &mut c);
+
+        let dev = Device::system_default().unwrap();
+        for mode in [StorageMode::Managed, StorageMode::Shared] {
+            set_storage_mode(Some(mode));
+            assert_eq!(StorageMode::of_device(&dev), mode);
+            assert_eq!(
+                new_buffer(&dev, 16).storage_mode(),
+                match mode {
+                    StorageMode::Shared => MTLStorageMode::Shared,
+                    StorageMode::Managed => MTLStorageMode::Managed,
+                }
+            );
+            cx.execute();
+            assert_close(&c.data(), &expected);
+            c.drop();
+        }
+        set_storage_mode(None);
+        assert_eq!(
+            StorageMode::of_device(&dev),
+            StorageMode::select(dev.has_unified_memory())
+        );
+    }
+}
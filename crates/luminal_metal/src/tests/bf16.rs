@@ -0,0 +1,118 @@
+use rand::{rngs::StdRng, SeedableRng};
+
+use luminal::{
+    prelude::*,
+    tests::{assert_exact, random_vec_rng},
+};
+
+use crate::MetalCompiler;
+
+/// bf16 keeps about 3 significant digits
+fn assert_close_bf16(a: &[f32], b: &[f32]) {
+    assert_eq!(a.len(), b.len(), "Number of elements doesn't match");
+    for (i, (a, b)) in a.iter().zip(b).enumerate() {
+        assert!(
+            (a - b).abs() <= 2e-2 * (1. + b.abs()),
+            "{a} is not close to {b}, index {i}"
+        );
+    }
+}
+
+#[test]
+fn test_elementwise() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let (a_data, b_data) = (random_vec_rng(783, &mut rng), random_vec_rng(783, &mut rng));
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<783>>().set(a_data.clone());
+    let b = cx.tensor::<R1<783>>().set(b_data.clone());
+    let mut c = ((a + b) * a).exp2().retrieve();
+    let mut d = a.less_than(b).retrieve();
+    cx.compile(MetalCompiler::<bf16>::default(), (&mut c, &mut d));
+    cx.execute();
+
+    let round = |v: f32| bf16::from_f32(v).to_f32();
+    assert_close_bf16(
+        &c.data(),
+        &a_data
+            .iter()
+            .zip(&b_data)
+            .map(|(a, b)| ((round(*a) + round(*b)) * round(*a)).exp2())
+            .collect::<Vec<_>>(),
+    );
+    assert_exact(
+        &d.data(),
+        &a_data
+            .iter()
+            .zip(&b_data)
+            .map(|(a, b)| (round(*a) < round(*b)) as i32 as f32)
+            .collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_max_reduce() {
+    let mut cx = Graph::new();
+    let a = cx
+        .tensor::<R2<2, 3>>()
+        .set(vec![-1e6, -2e6, -3e6, 1e5, -1., 7e4]);
+    let mut b = a.max_reduce::<_, LAxis<1>>().retrieve();
+    cx.compile(MetalCompiler::<bf16>::default(), &mut b);
+    cx.execute();
+
+    // -1e6 is below f16's range, so this would give -inf if the reduce started from half infinity
+    assert_close_bf16(&b.data(), &[-1e6, 1e5]);
+}
+
+#[test]
+fn test_matmul() {
+    let mut rng = StdRng::seed_from_u64(1);
+    // Scaled so the outputs are past f16's max of 65504
+    let a_data = random_vec_rng(64 * 128, &mut rng)
+        .into_iter()
+        .map(|v| v * 100.)
+        .collect::<Vec<_>>();
+    let b_data = random_vec_rng(128 * 32, &mut rng)
+        .into_iter()
+        .map(|v| v.abs() * 100.)
+        .collect::<Vec<_>>();
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<64, 128>>().set(a_data.clone());
+    let b = cx.tensor::<R2<128, 32>>().set(b_data.clone());
+    let mut c = a.abs().matmul(b).retrieve();
+    cx.compile(MetalCompiler::<bf16>::default(), &mut c);
+    cx.execute();
+
+    let round = |v: f32| bf16::from_f32(v).to_f32();
+    let mut expected = vec![0.; 64 * 32];
+    for m in 0..64 {
+        for n in 0..32 {
+            expected[m * 32 + n] = (0..128)
+                .map(|k| round(a_data[m * 128 + k].abs()) * round(b_data[k * 32 + n]))
+                .sum();
+        }
+    }
+    assert!(expected.iter().any(|v| *v > 65504.));
+    assert_close_bf16(&c.data(), &expected);
+}
+
+#[test]
+fn test_softmax() {
+    let mut rng = StdRng::seed_from_u64(2);
+    let data = random_vec_rng(4 * 100, &mut rng);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 100>>().set(data.clone());
+    let mut b = a.softmax::<1>().retrieve();
+    cx.compile(MetalCompiler::<bf16>::default(), &mut b);
+    cx.execute();
+
+    let expected = data
+        .chunks(100)
+        .flat_map(|row| {
+            let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let exps = row.iter().map(|v| (v - max).exp()).collect::<Vec<_>>();
+            let sum = exps.iter().sum::<f32>();
+            exps.into_iter().map(move |e| e / sum)
+        })
+        .collect::<Vec<_>>();
+    assert_close_bf16(&b.data(), &expected);
+}
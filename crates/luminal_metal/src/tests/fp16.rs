@@ -62,6 +62,151 @@ fn test_softmax() {
     assert_close(&b.data(), &d_b.to_dtype::<f32>().as_vec());
 }
 
+#[test]
+fn test_softmax_long_rows() {
+    // Rows longer than a threadgroup can hold go through the looped kernel
+    const N: usize = 5000;
+    let mut cx = Graph::new();
+    let data = random_vec(3 * N);
+    let a = cx.tensor::<R2<3, N>>().set(data.clone());
+    let mut b = a.softmax::<1>().retrieve();
+    cx.compile(MetalCompiler::<f16>::default(), &mut b);
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev
+        .tensor_from_vec(data, (dfdx::shapes::Const::<3>, dfdx::shapes::Const::<N>))
+        .to_dtype::<f16>();
+    let d_b = d_a.softmax::<dfdx::shapes::Axis<1>>();
+
+    // Scale up so the comparison isn't dominated by how small each probability is
+    let scale = |v: Vec<f32>| v.into_iter().map(|i| i * N as f32).collect::<Vec<_>>();
+    assert_close_precision(&scale(b.data()), &scale(d_b.to_dtype::<f32>().as_vec()), 2);
+}
+
+#[test]
+fn test_softmax_first_dim() {
+    // Only last dim softmaxes are swapped for the kernel
+    let mut cx = Graph::new();
+    let data = random_vec(12);
+    let a = cx.tensor::<R2<3, 4>>().set(data.clone());
+    let mut b = a.softmax::<0>().retrieve();
+    cx.compile(MetalCompiler::<f16>::default(), &mut b);
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev
+        .tensor_from_vec(data, (dfdx::shapes::Const::<3>, dfdx::shapes::Const::<4>))
+        .to_dtype::<f16>();
+    let d_b = d_a.softmax::<dfdx::shapes::Axis<0>>();
+
+    assert_close(&b.data(), &d_b.to_dtype::<f32>().as_vec());
+}
+
+#[test]
+fn test_softmax_large_negative_mask() {
+    // -1e9 is out of the half range, so it's clamped to the lowest half rather than becoming -inf. The last row and
+    // column are fully masked.
+    let mut cx = Graph::new();
+    let data = random_vec(12);
+    let mask_data = vec![0., 0., 1., 1., 0., 1., 0., 1., 1., 1., 1., 1.];
+    let a = cx.tensor::<R2<3, 4>>().set(data.clone());
+    let mask = cx.tensor::<R2<3, 4>>().set(mask_data.clone());
+    let scores = a + mask * -1e9;
+    let mut rows = scores.softmax::<1>().retrieve();
+    let mut cols = scores.softmax::<0>().retrieve();
+    cx.compile(MetalCompiler::<f16>::default(), (&mut rows, &mut cols));
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let shape = (dfdx::shapes::Const::<3>, dfdx::shapes::Const::<4>);
+    let d_scores =
+        d_dev.tensor_from_vec(data, shape) + d_dev.tensor_from_vec(mask_data, shape) * -1e9;
+    let d_rows = d_scores.clone().softmax::<dfdx::shapes::Axis<1>>();
+    let d_cols = d_scores.softmax::<dfdx::shapes::Axis<0>>();
+
+    assert!(rows
+        .data()
+        .iter()
+        .chain(&cols.data())
+        .all(|v| v.is_finite()));
+    assert_close(&rows.data(), &d_rows.as_vec());
+    assert_close(&cols.data(), &d_cols.as_vec());
+}
+
+/// Softmax rows of N scores, with the second row fully masked by -inf
+fn check_fully_masked_softmax<const N: usize>() {
+    let mut cx = Graph::new();
+    let data = random_vec(2 * N);
+    let mut mask_data = vec![1.; 2 * N];
+    mask_data[2] = 0.;
+    mask_data[N..].fill(0.);
+    let a = cx.tensor::<R2<2, N>>().set(data.clone());
+    let mask = Mask::from_float(cx.tensor::<R2<2, N>>().set(mask_data.clone()));
+    let mut b = (a + mask.to_additive()).softmax::<1>().retrieve();
+    cx.compile(MetalCompiler::<f16>::default(), &mut b);
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let mut first_row = data[..N].to_vec();
+    first_row[2] = f32::NEG_INFINITY;
+    let d_first_row = d_dev
+        .tensor_from_vec(first_row, (dfdx::shapes::Const::<N>,))
+        .softmax::<dfdx::shapes::Axis<0>>();
+
+    let out = b.data();
+    // Scale up so the comparison isn't dominated by how small each probability is
+    let scale = |v: &[f32]| v.iter().map(|i| i * N as f32).collect::<Vec<_>>();
+    assert_close_precision(&scale(&out[..N]), &scale(&d_first_row.as_vec()), 2);
+    assert_exact(&out[N..], &[0.; N]);
+}
+
+#[test]
+fn test_softmax_fully_masked_row() {
+    check_fully_masked_softmax::<4>();
+    // Through the looped kernel
+    check_fully_masked_softmax::<5000>();
+    // Through the split kernels
+    check_fully_masked_softmax::<20000>();
+}
+
+/// Softmax rows of N elements, normalized by the split kernels when they're long enough, compared to normalizing
+/// each row in a single pass
+fn check_split_softmax<const N: usize>() {
+    let mut cx = Graph::new();
+    let data = random_vec(3 * N);
+    let a = cx.tensor::<R2<3, N>>().set(data.clone());
+    let b = cx.tensor::<R2<3, N>>().set(data);
+    let mut split = a.softmax::<1>().retrieve();
+    let mut single_pass = b
+        .softmax::<1>()
+        .hint(CompilerHint::PreferKernel("single_pass".to_string()))
+        .retrieve();
+    cx.compile(
+        MetalCompiler::<f16>::default(),
+        (&mut split, &mut single_pass),
+    );
+    cx.execute();
+
+    // Scale up so the comparison isn't dominated by how small each probability is
+    let scale = |v: Vec<f32>| v.into_iter().map(|i| i * N as f32).collect::<Vec<_>>();
+    assert_close_precision(&scale(split.data()), &scale(single_pass.data()), 2);
+    // Every row sums to 1
+    for row in split.data().chunks(N) {
+        assert!((row.iter().sum::<f32>() - 1.).abs() < 1e-2);
+    }
+}
+
+#[test]
+fn test_softmax_split_rows() {
+    // Below and at the split limit both kernels normalize in a single pass
+    check_split_softmax::<4000>();
+    check_split_softmax::<16384>();
+    // A chunk of a single element
+    check_split_softmax::<16385>();
+    check_split_softmax::<40000>();
+}
+
 #[test]
 fn test_rotate() {
     let mut cx = Graph::new();
@@ -661,6 +806,44 @@ fn test_rms_norm() {
     assert_close(&b.data(), &out.to_dtype::<f32>().as_vec());
 }
 
+/// Graph for an RMSNorm over rows of N
+fn rms_norm_graph<const N: usize>(
+    cx: &mut Graph,
+    inp_data: &[f32],
+    weight_data: &[f32],
+) -> GraphTensor<R3<2, 3, N>> {
+    let a = cx.tensor::<R3<2, 3, N>>().set(inp_data.to_vec());
+    let model = RMSNorm::<N>::initialize(cx);
+    model.weight.set(weight_data.to_vec());
+    model.forward(a).retrieve()
+}
+
+/// Check the fused RMSNorm kernel against the CPU
+fn check_rms_norm<const N: usize>() {
+    let (inp_data, weight_data) = (random_vec(2 * 3 * N), random_vec(N));
+    let mut cpu = Graph::new();
+    let mut cpu_out = rms_norm_graph::<N>(&mut cpu, &inp_data, &weight_data);
+    cpu.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut cpu_out);
+    cpu.execute();
+
+    let mut cx = Graph::new();
+    let mut out = rms_norm_graph::<N>(&mut cx, &inp_data, &weight_data);
+    let report = cx.compile(MetalCompiler::<f16>::default(), &mut out);
+    assert_eq!(report.total().per_pattern_counts["RMSNorm"], 1);
+    cx.execute();
+
+    assert_close_precision(&out.data(), &cpu_out.data(), 2);
+}
+
+#[test]
+fn test_rms_norm_fused() {
+    // Rows of a single value, rows the std norm kernel can't take, and rows longer than a threadgroup
+    check_rms_norm::<1>();
+    check_rms_norm::<7>();
+    check_rms_norm::<100>();
+    check_rms_norm::<2048>();
+}
+
 #[test]
 fn test_layer_norm() {
     let mut cx = Graph::new();
@@ -900,7 +1083,7 @@ fn test_pad_contig() {
         .set_dyn(a_data, &[m, k])
         .retrieve();
     let mut b: GraphTensor<(Dyn<'M'>, Dyn<'K'>)> = a
-        .pad(&[(0, 0.into()), (0, Expression::from(16) - 'K')])
+        .pad(&[(0, 0.into()), (0, (Expression::from(16) - 'K').max(0))])
         .contiguous()
         .retrieve();
     let mut c: GraphTensor<(Dyn<'M'>, Dyn<'K'>)> =
@@ -937,3 +1120,187 @@ fn test_movement() {
 
     assert_exact(&c.data(), &d_c.as_vec());
 }
+
+#[test]
+fn test_fast_math_denormals() {
+    use crate::{compile_function, with_fast_math, DispatchNElements, SetInt};
+    use metal_rs::{ComputePassDescriptor, Device, MTLResourceOptions};
+
+    // The smallest normal half times 0.25 is a denormal
+    let input = [f16::MIN_POSITIVE, f16::from_f32(1.0)];
+    let code = "
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel(device half *inp [[buffer(0)]], device half *out [[buffer(1)]], device float& scale [[buffer(2)]], device int& n_elements [[buffer(3)]], uint idx [[thread_position_in_grid]]) {
+    if (idx < n_elements) {
+        out[idx] = inp[idx] * (half)scale;
+    }
+}";
+    let run = |fast_math| {
+        autoreleasepool(|| {
+            let dev = Device::system_default().unwrap();
+            let pipeline = with_fast_math(fast_math, || compile_function("mkernel", code, &dev));
+            let inp = dev.new_buffer_with_data(
+                input.as_ptr() as *const _,
+                std::mem::size_of_val(&input) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+            let out = dev.new_buffer(
+                std::mem::size_of_val(&input) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+            let queue = dev.new_command_queue();
+            let command_buffer = queue.new_command_buffer();
+            let encoder = command_buffer
+                .compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+            encoder.set_compute_pipeline_state(&pipeline);
+            encoder.set_buffer(0, Some(&inp), 0);
+            encoder.set_buffer(1, Some(&out), 0);
+            encoder.set_f32(2, 0.25);
+            encoder.set_u32(3, input.len() as u32);
+            encoder.dispatch_1d(input.len());
+            encoder.end_encoding();
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+            unsafe { std::slice::from_raw_parts(out.contents() as *const f16, input.len()) }
+                .to_vec()
+        })
+    };
+
+    // Without fast math the denormal survives, like on PyTorch MPS
+    let precise = run(false);
+    assert!(precise[0].is_subnormal());
+    assert_eq!(precise[0], f16::MIN_POSITIVE * f16::from_f32(0.25));
+    // With fast math the denormal result is flushed to zero
+    let fast = run(true);
+    assert_eq!(fast[0], f16::ZERO);
+    // Normal values are unaffected
+    assert_eq!(precise[1], fast[1]);
+}
+
+#[test]
+fn test_fast_math_recompiled_kernels() {
+    use crate::FastMath;
+
+    // The mul is rebuilt for its new input shape once the contiguous copy before it is removed
+    let run = |enabled| {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<2, 1>>()
+            .set(vec![f16::MIN_POSITIVE.to_f32(), 1.]);
+        let mut b = (a.permute::<R2<1, 2>, _>().contiguous() * 0.25).retrieve();
+        cx.compile(
+            FastMath {
+                compiler: MetalCompiler::<f16>::default(),
+                enabled,
+            },
+            &mut b,
+        );
+        cx.execute();
+        b.data()
+    };
+    assert_ne!(run(false)[0], 0.);
+    assert_eq!(run(true)[0], 0.);
+}
+
+#[test]
+fn test_precision_audit_long_k_matmul() {
+    use crate::{execute_precision_audit, MetalAuditCompiler, PrecisionAuditReport};
+
+    // Accumulating thousands of 0.1s in fp16 rounds every addition once the running sum gets large
+    const K: usize = 8192;
+    const N: usize = 16;
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<1, K>>().set(vec![1.; K]);
+    let b = cx.tensor::<R2<K, N>>().set(vec![0.1; K * N]);
+    let c = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+    let mut matmul = a.matmul(b).retrieve();
+    let mut sum = c.sum_reduce::<_, luminal::prelude::Axis<0>>().retrieve();
+    cx.compile(
+        <(GenericCompiler, MetalAuditCompiler<f16>)>::default(),
+        (&mut matmul, &mut sum),
+    );
+
+    let mut report = PrecisionAuditReport::default();
+    execute_precision_audit::<f16>(&mut cx, &mut report);
+    execute_precision_audit::<f16>(&mut cx, &mut report);
+
+    // Only the long matmul is flagged
+    let flagged = report.flagged(1.0);
+    assert_eq!(flagged.len(), 1);
+    assert!(flagged[0].0.starts_with("Matmul"));
+    assert_eq!(flagged[0].1.executions, 2);
+    // A short sum is exact in either precision
+    let (_, short_sum) = report
+        .0
+        .iter()
+        .find(|(name, _)| name.starts_with("MetalSumReduce"))
+        .unwrap();
+    assert_eq!(short_sum.max_abs_diff, 0.);
+    // Outputs still come from the reduced precision kernels
+    assert_exact(&sum.data(), &[10.]);
+    assert_eq!(matmul.data().len(), N);
+}
+
+#[test]
+fn test_matmul_execution_report() {
+    use crate::MetalAuditCompiler;
+
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<16, 32>>().set(random_vec(16 * 32));
+    let b = cx.tensor::<R2<32, 8>>().set(random_vec(32 * 8));
+    let mut c = a.matmul(b).retrieve();
+    cx.compile(
+        <(GenericCompiler, MetalAuditCompiler<f16>)>::default(),
+        &mut c,
+    );
+
+    let report = cx.execute_with_report();
+    let allocation = |name: &str| {
+        report
+            .allocations
+            .iter()
+            .find(|a| format!("{:?}", cx.graph.node_weight(a.node).unwrap()).starts_with(name))
+            .unwrap()
+    };
+    // The matmul writes fp16 on the device, and its output is copied back to the host as f32
+    assert_eq!(allocation("Matmul").bytes, 16 * 8 * 2);
+    assert_eq!(
+        report
+            .allocations
+            .iter()
+            .find(|a| a.node == c.id)
+            .unwrap()
+            .bytes,
+        16 * 8 * 4
+    );
+}
+
+#[test]
+fn test_set_resident_embedding_rows() {
+    let mut cx = Graph::new();
+    let table = cx
+        .tensor::<R2<5, 3>>()
+        .set((0..15).map(|i| i as f32).collect::<Vec<_>>());
+    let ids = cx.tensor::<R1<3>>().set(vec![1., 2., 4.]);
+    let mut out = table.gather(ids).retrieve();
+    cx.compile(<(GenericCompiler, MetalCompiler<f16>)>::default(), &mut out);
+    let weights = downstream(table, &cx);
+    cx.keep_tensors(&weights);
+    cx.execute();
+    assert_exact(&out.data(), &[3., 4., 5., 6., 7., 8., 12., 13., 14.]);
+
+    // Overwrite rows 1 and 2 in one run, then the middle column in a run per row
+    cx.set_tensor_region(
+        weights[0],
+        &[1, 0],
+        &[-1., -2., -3., -4., -5., -6.],
+        &[2, 3],
+    )
+    .unwrap();
+    cx.set_tensor_region(weights[0], &[0, 1], &[20., 21., 22., 23., 24.], &[5, 1])
+        .unwrap();
+    out.drop();
+    cx.execute();
+    assert_exact(&out.data(), &[-1., 21., -3., -4., 22., -6., 12., 24., 14.]);
+}
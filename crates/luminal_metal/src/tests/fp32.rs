@@ -5,7 +5,7 @@ use rand::{rngs::StdRng, SeedableRng};
 use luminal::{
     nn::{activation::ReLU, linear::Linear},
     prelude::{Module, *},
-    tests::{assert_close, assert_close_precision, random_vec, random_vec_rng},
+    tests::{assert_close, assert_close_precision, assert_exact, random_vec, random_vec_rng},
 };
 
 use crate::{binary_test, unary_test, MetalCompiler};
@@ -156,6 +156,58 @@ fn test_sum_reduce() {
     assert_close(&d.data(), &d_d.as_vec());
 }
 
+#[test]
+fn test_multi_axis_sum_reduce() {
+    let mut cx = Graph::new();
+    let data = random_vec(120);
+    let a = cx.tensor::<R4<2, 3, 4, 5>>().set(data.clone());
+    let mut b = a
+        .sum_reduce::<_, luminal::prelude::Axes2<0, 2>>()
+        .retrieve();
+    let mut c = a
+        .sum_reduce::<_, luminal::prelude::Axes2<1, 2>>()
+        .retrieve();
+    let mut d = a
+        .permute::<_, luminal::prelude::Axes4<3, 1, 0, 2>>()
+        .sum_reduce::<_, luminal::prelude::Axes3<0, 1, 3>>()
+        .retrieve();
+
+    cx.compile(MetalCompiler::<f32>::default(), (&mut b, &mut c, &mut d));
+    // Each output is summed by a single kernel
+    let reduces = cx
+        .graph
+        .node_weights()
+        .filter_map(|op| {
+            op.as_any()
+                .downcast_ref::<crate::prim::MetalSumReduce<f32>>()
+        })
+        .map(|op| op.dims.clone())
+        .sorted()
+        .collect_vec();
+    assert_eq!(reduces, vec![vec![0, 1, 3], vec![0, 2], vec![1, 2]]);
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(
+        data,
+        (
+            dfdx::shapes::Const::<2>,
+            dfdx::shapes::Const::<3>,
+            dfdx::shapes::Const::<4>,
+            dfdx::shapes::Const::<5>,
+        ),
+    );
+    let d_b = d_a.clone().sum::<_, dfdx::shapes::Axes2<0, 2>>();
+    let d_c = d_a.clone().sum::<_, dfdx::shapes::Axes2<1, 2>>();
+    let d_d = d_a
+        .permute::<_, dfdx::shapes::Axes4<3, 1, 0, 2>>()
+        .sum::<_, dfdx::shapes::Axes3<0, 1, 3>>();
+
+    assert_close(&b.data(), &d_b.as_vec());
+    assert_close(&c.data(), &d_c.as_vec());
+    assert_close(&d.data(), &d_d.as_vec());
+}
+
 #[test]
 fn test_max_reduce() {
     let mut cx = Graph::new();
@@ -210,6 +262,35 @@ fn test_mean_reduce() {
     assert_close(&b.data(), &d_b.as_vec());
 }
 
+#[test]
+fn test_full_reductions() {
+    let data = random_vec(40960);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R3<4, 10, 1024>>().set(data.clone());
+    let mut sum = a.sum_all().retrieve();
+    let mut max = a.max_all().retrieve();
+    let mut mean = a.mean_all().retrieve();
+
+    cx.compile(
+        MetalCompiler::<f32>::default(),
+        (&mut sum, &mut max, &mut mean),
+    );
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(
+        data,
+        (
+            dfdx::shapes::Const::<4>,
+            dfdx::shapes::Const::<10>,
+            dfdx::shapes::Const::<1024>,
+        ),
+    );
+    assert_close_precision(&[sum.item()], &[d_a.clone().sum::<Rank0, _>().array()], 2);
+    assert_close(&[max.item()], &[d_a.clone().max::<Rank0, _>().array()]);
+    assert_close(&[mean.item()], &[d_a.mean::<Rank0, _>().array()]);
+}
+
 #[test]
 fn test_matmul_simple() {
     let mut cx = Graph::new();
@@ -284,6 +365,101 @@ fn test_batch_matmul() {
     assert_close(&c.data(), &d_c.as_vec());
 }
 
+#[test]
+fn test_batch_matmul_batched_b() {
+    let mut cx = Graph::new();
+    let mut rng = StdRng::seed_from_u64(0);
+    let a = cx.tensor::<(Dyn<'B'>, Dyn<'M'>, Dyn<'K'>)>();
+    let b = cx.tensor::<(Dyn<'B'>, Dyn<'K'>, Dyn<'N'>)>();
+    let b_t = cx.tensor::<(Dyn<'B'>, Dyn<'N'>, Dyn<'K'>)>();
+    let mut c = a.matmul(b).retrieve();
+    let mut c_t = a
+        .matmul(b_t.permute::<_, luminal::prelude::Axes3<0, 2, 1>>())
+        .retrieve();
+
+    cx.compile(
+        <(GenericCompiler, MetalCompiler<f32>)>::default(),
+        (&mut c, &mut c_t),
+    );
+    for (batch, m, k, n) in [(1, 1, 8, 5), (3, 12, 7, 33), (5, 40, 64, 17)] {
+        let a_data = random_vec_rng(batch * m * k, &mut rng);
+        let b_data = random_vec_rng(batch * k * n, &mut rng);
+        let b_t_data = random_vec_rng(batch * n * k, &mut rng);
+        a.set_dyn(a_data.clone(), &[batch, m, k]);
+        b.set_dyn(b_data.clone(), &[batch, k, n]);
+        b_t.set_dyn(b_t_data.clone(), &[batch, n, k]);
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (batch, m, k));
+        let d_b = d_dev.tensor_from_vec(b_data, (batch, k, n));
+        let d_b_t = d_dev.tensor_from_vec(b_t_data, (batch, n, k));
+        let d_c = d_a.clone().matmul(d_b);
+        let d_c_t = d_a.matmul(d_b_t.permute::<_, dfdx::shapes::Axes3<0, 2, 1>>());
+
+        assert_close_precision(&c.data(), &d_c.as_vec(), 2);
+        assert_close_precision(&c_t.data(), &d_c_t.as_vec(), 2);
+        c.drop();
+        c_t.drop();
+    }
+}
+
+#[test]
+fn test_matmul_sliced_and_padded_inputs() {
+    let mut cx = Graph::new();
+    let mut rng = StdRng::seed_from_u64(0);
+    // Attention scores against the filled part of a KV cache
+    let q_data = random_vec_rng(2 * 3 * 16, &mut rng);
+    let cache_data = random_vec_rng(2 * 10 * 16, &mut rng);
+    let q = cx.tensor::<R3<2, 3, 16>>().set(q_data.clone());
+    let cache = cx.tensor::<R3<2, 10, 16>>().set(cache_data.clone());
+    let keys = cache
+        .slice((.., ..Expression::from(6), ..))
+        .realize::<R3<2, 6, 16>>();
+    let mut scores = q
+        .matmul(keys.permute::<_, luminal::prelude::Axes3<0, 2, 1>>())
+        .retrieve();
+    // A window of the inner dimension
+    let a_data = random_vec_rng(4 * 12, &mut rng);
+    let b_data = random_vec_rng(8 * 5, &mut rng);
+    let a = cx.tensor::<R2<4, 12>>().set(a_data.clone());
+    let b = cx.tensor::<R2<8, 5>>().set(b_data.clone());
+    let mut windowed = a
+        .slice((.., Expression::from(2)..Expression::from(10)))
+        .realize::<R2<4, 8>>()
+        .matmul(b)
+        .retrieve();
+    // A zero padded inner dimension
+    let short_data = random_vec_rng(4 * 6, &mut rng);
+    let short = cx.tensor::<R2<4, 6>>().set(short_data.clone());
+    let mut padded = short
+        .pad::<R2<4, 8>, _, _>(&[(0, 0), (0, 2)])
+        .matmul(b)
+        .retrieve();
+
+    cx.compile(
+        <(GenericCompiler, MetalCompiler<f32>)>::default(),
+        (&mut scores, &mut windowed, &mut padded),
+    );
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_q = d_dev.tensor_from_vec(q_data, (2, 3, 16));
+    let d_keys = d_dev
+        .tensor_from_vec(cache_data, (2, 10, 16))
+        .slice((.., ..6, ..));
+    let d_scores = d_q.matmul(d_keys.permute::<_, dfdx::shapes::Axes3<0, 2, 1>>());
+    let d_a = d_dev.tensor_from_vec(a_data, (4, 12));
+    let d_b = d_dev.tensor_from_vec(b_data, (8, 5));
+    let d_windowed = d_a.slice((.., 2..10)).matmul(d_b.clone());
+    let d_short = d_dev.tensor_from_vec(short_data, (4, 6));
+    let d_padded = d_short.matmul(d_b.slice((..6, ..)));
+
+    assert_close(&scores.data(), &d_scores.as_vec());
+    assert_close(&windowed.data(), &d_windowed.as_vec());
+    assert_close(&padded.data(), &d_padded.as_vec());
+}
+
 #[test]
 fn test_matmul_transpose() {
     const M: usize = 1024; // Any
@@ -500,3 +676,447 @@ fn test_transformer_encoder_block() {
 
     assert_close(&b.data(), &d_b.as_vec());
 }
+
+#[test]
+fn test_sort() {
+    let mut rng = StdRng::seed_from_u64(0);
+    // Quantize to get lots of ties, and use a row length that isn't a power of two
+    let data = random_vec_rng(4 * 300, &mut rng)
+        .into_iter()
+        .map(|i| (i * 16.).round())
+        .collect::<Vec<_>>();
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 300>>().set(data.clone());
+    let mut values = a.sort(true).retrieve();
+    let mut indexes = a.argsort(true).retrieve();
+    let mut asc_indexes = a.argsort(false).retrieve();
+    cx.execute();
+    let (cpu_values, cpu_indexes, cpu_asc_indexes) =
+        (values.data(), indexes.data(), asc_indexes.data());
+
+    cx.compile(
+        MetalCompiler::<f32>::default(),
+        (&mut values, &mut indexes, &mut asc_indexes),
+    );
+    cx.execute();
+
+    assert_eq!(values.data(), cpu_values);
+    assert_eq!(indexes.data(), cpu_indexes);
+    assert_eq!(asc_indexes.data(), cpu_asc_indexes);
+}
+
+#[test]
+fn test_sort_cpu_fallback() {
+    let mut rng = StdRng::seed_from_u64(1);
+    let data = random_vec_rng(2 * 5000, &mut rng)
+        .into_iter()
+        .map(|i| (i * 16.).round())
+        .collect::<Vec<_>>();
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 5000>>().set(data.clone());
+    let mut indexes = a.argsort(false).retrieve();
+    cx.execute();
+    let cpu_indexes = indexes.data();
+
+    cx.compile(MetalCompiler::<f32>::default(), &mut indexes);
+    cx.execute();
+
+    assert_eq!(indexes.data(), cpu_indexes);
+}
+
+#[test]
+fn test_shared_weight_uploads() {
+    let path = std::env::temp_dir().join("luminal_test_shared_weight_uploads.safetensors");
+    let path = path.to_str().unwrap();
+    // Write out a checkpoint
+    let mut cx = Graph::new();
+    let model: (Linear<4, 5>, ReLU, Linear<5, 3>) = InitModule::initialize(&mut cx);
+    model.0.weight.set(random_vec(4 * 5)).keep();
+    model.2.weight.set(random_vec(5 * 3)).keep();
+    cx.execute();
+    SafeTensorSaver::new(path).save(&model, &mut cx).unwrap();
+
+    let dev = metal_rs::Device::system_default().unwrap();
+    let resident_before = crate::resident_uploads(&dev);
+    let input = random_vec(4);
+    let build = || {
+        let mut cx = Graph::new();
+        let model: (Linear<4, 5>, ReLU, Linear<5, 3>) = InitModule::initialize(&mut cx);
+        SafeTensorLoader::new(&[path]).load(&model, &mut cx);
+        let inp = cx.tensor::<R1<4>>().set(input.clone());
+        let mut out = model.forward(inp).retrieve();
+        cx.compile(MetalCompiler::<f32>::default(), &mut out);
+        let weights = downstream(state_set(&model), &cx);
+        cx.keep_tensors(&weights);
+        cx.execute();
+        (cx, out)
+    };
+    let (cx1, out1) = build();
+    let (cx2, out2) = build();
+
+    // Both graphs share one buffer per unique weight
+    assert_eq!(crate::resident_uploads(&dev) - resident_before, 2);
+    assert_exact(&out1.data(), &out2.data());
+
+    // The buffers stay resident while either graph holds them, and are evicted once both are gone
+    drop(cx1);
+    assert_eq!(crate::resident_uploads(&dev) - resident_before, 2);
+    drop(cx2);
+    assert_eq!(crate::resident_uploads(&dev), resident_before);
+}
+
+#[test]
+fn test_pipeline_cache() {
+    let dev = metal_rs::Device::system_default().unwrap();
+    let code = "
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel(device float *out [[buffer(0)]], uint idx [[thread_position_in_grid]]) {
+    out[idx] = 0.25 * idx;
+}";
+    let cached_before = crate::cached_pipelines(&dev);
+    let first = crate::compile_function("mkernel", code, &dev);
+    let second = crate::compile_function("mkernel", code, &dev);
+    assert!(std::ptr::eq(&*first, &*second));
+    assert!(crate::cached_pipelines(&dev) > cached_before);
+
+    // Fast math changes the compiled code, so it's cached separately
+    let fast = crate::with_fast_math(true, || crate::compile_function("mkernel", code, &dev));
+    assert!(!std::ptr::eq(&*first, &*fast));
+}
+
+#[test]
+fn test_matmul_degenerate_dims() {
+    // (batch, m, k, n), with an empty inner dimension giving zeros and other empty dimensions giving empty outputs
+    for (batch, m, k, n) in [
+        (1, 2, 0, 3),
+        (1, 0, 2, 3),
+        (1, 2, 3, 0),
+        (2, 2, 0, 3),
+        (0, 2, 2, 3),
+        (1, 1, 0, 3),
+    ] {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<(Dyn<'M'>, Dyn<'K'>)>();
+        let b = cx.tensor::<(Dyn<'K'>, Dyn<'N'>)>();
+        let batched_a = cx.tensor::<(Dyn<'B'>, Dyn<'M'>, Dyn<'K'>)>();
+        let mut c = a.matmul(b).retrieve();
+        let mut batched_c = batched_a.matmul(b).retrieve();
+        cx.compile(MetalCompiler::<f32>::default(), (&mut c, &mut batched_c));
+        a.set_dyn(vec![1.; m * k], &[m, k]);
+        b.set_dyn(vec![1.; k * n], &[k, n]);
+        batched_a.set_dyn(vec![1.; batch * m * k], &[batch, m, k]);
+        cx.execute();
+
+        // Each output sums k ones
+        assert_exact(&c.data(), &vec![k as f32; m * n]);
+        assert_exact(&batched_c.data(), &vec![k as f32; batch * m * n]);
+    }
+}
+
+#[test]
+fn test_vectorized_elementwise() {
+    // 4099 elements leaves a tail of 3 after the vectorized part
+    let a_data = random_vec(4099);
+    let b_data = random_vec(4099);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4099>>().set(a_data.clone());
+    let b = cx.tensor::<R1<4099>>().set(b_data.clone());
+    let mut add = (a + b).retrieve();
+    let mut mul = (a * b).retrieve();
+    let mut fused = (a.exp2() * b.sin()).retrieve();
+    let mut lanewise = (a % b).retrieve();
+
+    cx.compile(
+        MetalCompiler::<f32>::default(),
+        (&mut add, &mut mul, &mut fused, &mut lanewise),
+    );
+    cx.execute();
+
+    let zipped = |f: fn(f32, f32) -> f32| {
+        a_data
+            .iter()
+            .zip(&b_data)
+            .map(|(a, b)| f(*a, *b))
+            .collect_vec()
+    };
+    assert_close(&add.data(), &zipped(|a, b| a + b));
+    assert_close(&mul.data(), &zipped(|a, b| a * b));
+    assert_close(&fused.data(), &zipped(|a, b| a.exp2() * b.sin()));
+    assert_close(&lanewise.data(), &zipped(|a, b| a % b));
+}
+
+#[test]
+#[ignore]
+fn bench_elementwise_bandwidth() {
+    const N: usize = 64 * 1024 * 1024;
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<N>>().set(vec![1.; N]).keep();
+    let b = cx.tensor::<R1<N>>().set(vec![2.; N]).keep();
+    let mut add = (a + b).retrieve();
+    let mut unary = a.sin().retrieve();
+    cx.compile(MetalCompiler::<f32>::default(), (&mut add, &mut unary));
+    cx.execute();
+
+    let runs = 10;
+    let start = std::time::Instant::now();
+    for _ in 0..runs {
+        add.drop();
+        unary.drop();
+        cx.execute();
+    }
+    // Add reads two buffers and writes one, sin reads one and writes one
+    let bytes = (5 * N * std::mem::size_of::<f32>() * runs) as f64;
+    println!(
+        "Bandwidth: {:.1} GB/s",
+        bytes / start.elapsed().as_secs_f64() / 1e9
+    );
+}
+
+#[test]
+#[ignore]
+fn bench_decode_matvec() {
+    const K: usize = 4096;
+    const N: usize = 4096;
+    let weight_data = random_vec(K * N);
+    let time = |kernel: Option<&str>| {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<R2<1, K>>().set(random_vec(K)).keep();
+        let weight = cx.tensor::<R2<K, N>>().set(weight_data.clone()).keep();
+        let out = x.matmul(weight);
+        let mut out = match kernel {
+            Some(kernel) => out.hint(CompilerHint::PreferKernel(kernel.to_string())),
+            None => out,
+        }
+        .retrieve();
+        cx.compile(MetalCompiler::<f32>::default(), &mut out);
+        cx.execute();
+
+        let runs = 50;
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            out.drop();
+            cx.execute();
+        }
+        start.elapsed().as_secs_f64() / runs as f64
+    };
+    let (matvec, matmul) = (time(None), time(Some("gemm")));
+    println!(
+        "1x{K} by {K}x{N}: matvec {:.3}ms, matmul {:.3}ms ({:.1}x)",
+        matvec * 1e3,
+        matmul * 1e3,
+        matmul / matvec
+    );
+}
+
+#[test]
+#[ignore]
+fn bench_softmax_vocab() {
+    const VOCAB: usize = 128 * 1024;
+    let data = random_vec(VOCAB);
+    let time = |kernel: Option<&str>| {
+        let mut cx = Graph::new();
+        let logits = cx.tensor::<R2<1, VOCAB>>().set(data.clone()).keep();
+        let probs = logits.softmax::<1>();
+        let mut probs = match kernel {
+            Some(kernel) => probs.hint(CompilerHint::PreferKernel(kernel.to_string())),
+            None => probs,
+        }
+        .retrieve();
+        cx.compile(MetalCompiler::<f32>::default(), &mut probs);
+        cx.execute();
+
+        let runs = 50;
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            probs.drop();
+            cx.execute();
+        }
+        start.elapsed().as_secs_f64() / runs as f64
+    };
+    let (split, single_pass) = (time(None), time(Some("single_pass")));
+    println!(
+        "Softmax over {VOCAB}: split {:.3}ms, single pass {:.3}ms ({:.1}x)",
+        split * 1e3,
+        single_pass * 1e3,
+        single_pass / split
+    );
+}
+
+#[test]
+#[ignore]
+fn bench_greedy_decode() {
+    const VOCAB: usize = 32_000;
+    const DIM: usize = 1024;
+    const TOKENS: usize = 64;
+    let mut rng = StdRng::seed_from_u64(0);
+    let embed_data = random_vec_rng(VOCAB * DIM, &mut rng);
+    let proj_data = random_vec_rng(DIM * VOCAB, &mut rng);
+
+    // Unfused: download logits, pick on the host, upload the id
+    let mut cx = Graph::new();
+    let embedding = cx.tensor::<R2<VOCAB, DIM>>().set(embed_data.clone()).keep();
+    let proj = cx.tensor::<R2<DIM, VOCAB>>().set(proj_data.clone()).keep();
+    let token = cx.tensor::<R1<1>>();
+    let mut logits = embedding.gather(token).matmul(proj).retrieve();
+    cx.compile(MetalCompiler::<f32>::default(), &mut logits);
+    let mut unfused = vec![0];
+    let start = std::time::Instant::now();
+    for _ in 0..TOKENS {
+        token.set(vec![*unfused.last().unwrap() as f32]);
+        cx.execute();
+        let data = logits.data();
+        logits.drop();
+        unfused.push(
+            (0..VOCAB)
+                .max_by(|a, b| data[*a].partial_cmp(&data[*b]).unwrap())
+                .unwrap(),
+        );
+    }
+    let unfused_time = start.elapsed().as_secs_f64() / TOKENS as f64;
+
+    // Fused: argmax and the embedding lookup stay on device
+    let mut cx = Graph::new();
+    let embedding = cx.tensor::<R2<VOCAB, DIM>>().set(embed_data.clone()).keep();
+    let proj = cx.tensor::<R2<DIM, VOCAB>>().set(proj_data).keep();
+    let mut decoder = GreedyDecoder::<DIM, 8>::new(&mut cx);
+    decoder.input.set(embed_data[..DIM].to_vec());
+    let logits = decoder.input.matmul(proj);
+    decoder.finish(logits, embedding);
+    cx.compile(MetalCompiler::<f32>::default(), &mut decoder);
+    let mut fused = vec![0];
+    let start = std::time::Instant::now();
+    for _ in 0..TOKENS {
+        fused.extend(decoder.step(&mut cx).unwrap_or_default());
+    }
+    let fused_time = start.elapsed().as_secs_f64() / TOKENS as f64;
+
+    assert_eq!(fused, unfused);
+    println!(
+        "Per token: unfused {:.3}ms, fused {:.3}ms",
+        unfused_time * 1e3,
+        fused_time * 1e3
+    );
+}
+
+#[test]
+fn test_fuzz_corpus() {
+    if let Err(failure) = luminal::tests::fuzz::fuzz_backend(
+        &luminal::tests::fuzz::FuzzConfig::ci(),
+        |cx, outputs| {
+            cx.compile(MetalCompiler::<f32>::default(), outputs);
+        },
+    ) {
+        panic!("{failure}");
+    }
+}
+
+#[test]
+fn test_cross_entropy_fused() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut cx = Graph::new();
+    let hidden = cx
+        .tensor::<R2<5, 16>>()
+        .set(random_vec_rng(5 * 16, &mut rng));
+    let weight = cx
+        .tensor::<R2<16, 3000>>()
+        .set(random_vec_rng(16 * 3000, &mut rng));
+    let targets = cx.tensor::<R1<5>>().set(vec![0., 17., 1023., 2999., 256.]);
+    let mut loss = luminal::nn::loss::cross_entropy_fused(hidden, weight, targets).retrieve();
+    cx.execute();
+    let cpu_loss = loss.data();
+
+    cx.compile(MetalCompiler::<f32>::default(), &mut loss);
+    cx.execute();
+
+    assert_close(&loss.data(), &cpu_loss);
+}
+
+#[test]
+fn test_embedding_bag() {
+    use luminal::nn::embedding::{embedding_bag, EmbeddingBagMode};
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut cx = Graph::new();
+    let table = cx
+        .tensor::<R2<50, 300>>()
+        .set(random_vec_rng(50 * 300, &mut rng));
+    // Ragged bags of 3, 0, 1 and 5 indices
+    let indices = cx
+        .tensor::<R1<9>>()
+        .set(vec![4., 49., 4., 0., 17., 2., 33., 33., 8.]);
+    let offsets = cx.tensor::<R1<4>>().set(vec![0., 3., 3., 4.]);
+    let mut outs = [
+        EmbeddingBagMode::Sum,
+        EmbeddingBagMode::Mean,
+        EmbeddingBagMode::Max,
+    ]
+    .into_iter()
+    .map(|mode| embedding_bag(table, indices, offsets, mode).retrieve())
+    .collect::<Vec<_>>();
+    cx.execute();
+    let cpu_outs = outs.iter().map(|o| o.data()).collect::<Vec<_>>();
+
+    cx.compile(MetalCompiler::<f32>::default(), &mut outs);
+    cx.execute();
+
+    for (out, cpu_out) in outs.iter().zip(&cpu_outs) {
+        assert_close(&out.data(), cpu_out);
+        assert_exact(&out.data()[300..600], &[0.; 300]);
+    }
+}
+
+#[test]
+fn test_scatter_add() {
+    let mut cx = Graph::new();
+    let history = cx
+        .tensor::<R1<8>>()
+        .set(vec![3., 0., 3., 999., 1000., 7., 3., -1.]);
+    let logits = cx.tensor::<R1<1000>>().set(random_vec(1000));
+    let mut counts = history.bincount::<LConst<1000>>().retrieve();
+    let mut processed = logits
+        .process_logits(
+            history,
+            &[
+                LogitsProcessor::FrequencyPenalty(0.5),
+                LogitsProcessor::BanTokens(vec![2, 500]),
+            ],
+        )
+        .retrieve();
+    cx.execute();
+    let (cpu_counts, cpu_processed) = (counts.data(), processed.data());
+
+    cx.compile(
+        MetalCompiler::<f32>::default(),
+        (&mut counts, &mut processed),
+    );
+    cx.execute();
+
+    assert_exact(&counts.data(), &cpu_counts);
+    assert_close(&processed.data(), &cpu_processed);
+}
+
+#[test]
+fn test_cpu_input_error() {
+    use crate::prim::MetalLog2;
+    use metal_rs::Device;
+
+    // A Metal op fed straight from a CPU tensor, without copying it to the device
+    let mut cx = Graph::new();
+    let dev = Device::system_default().unwrap();
+    let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let log = cx
+        .add_op(MetalLog2::<f32>::new(dev.clone(), dev.new_command_queue()))
+        .input(a.id, 0, a.shape)
+        .finish();
+    cx.no_delete.insert(log);
+
+    let Err(ExecutionError::OpFailed {
+        node, op, error, ..
+    }) = cx.try_execute()
+    else {
+        panic!("Running a Metal op on a CPU tensor should fail");
+    };
+    assert_eq!(node, log);
+    assert!(op.starts_with("MetalLog2"), "{op}");
+    assert!(error.contains("alloc::vec::Vec<f32>"), "{error}");
+}
@@ -1,3 +1,4 @@
+mod bf16;
 mod fp16;
 mod fp32;
 
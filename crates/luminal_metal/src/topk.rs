@@ -0,0 +1,295 @@
+use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
+
+use luminal::{
+    op::{InputTensor, OpError, Operator},
+    prelude::*,
+    shape::symbolic::{BigExpression, Expression},
+};
+use metal_rs::{
+    objc::rc::autoreleasepool, Buffer, CommandBufferRef, CommandQueue, ComputePassDescriptor,
+    ComputePipelineState, Device, MTLSize,
+};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    compile_function, fast_math_enabled, finish_command_buffer, get_buffer_from_tensor,
+    get_idx_valid_exps, input_dyn_dims, new_buffer, render_dyn_dim_inputs, with_fast_math,
+    MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
+};
+
+/// The `k` largest elements along a dimension in descending order, or their indexes.
+///
+/// Each output row gets a threadgroup, which picks the elements one at a time: every round finds the best element
+/// ordered after the last one picked, by scanning strided parts of the row and tree reducing (value, index) pairs in
+/// threadgroup memory. Rows are rescanned once per element, which suits the small k used for sampling. The order
+/// matches the CPU op, with ties in index order and NaNs first. Note indexes are stored as T, so in fp16 indexes above
+/// 2048 aren't exactly representable.
+#[derive(LuminalPrint, Clone)]
+pub struct MetalTopK<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub k: Expression,
+    pub dim: usize,
+    pub indices: bool,
+    dyn_symbols: Vec<char>,
+    _phantom: PhantomData<T>,
+    dyn_map: *const FxHashMap<char, usize>,
+    fast_math: bool,
+}
+
+impl<T> PartialEq for MetalTopK<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.k == other.k && self.dim == other.dim && self.indices == other.indices
+    }
+}
+
+impl<T: MetalFloat> MetalTopK<T> {
+    pub fn new(
+        shape: ShapeTracker,
+        k: Expression,
+        dim: usize,
+        indices: bool,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 5);
+        let type_name = T::type_name();
+        let output = if indices { "(float)prev_i" } else { "prev" };
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+#define NONE 0xFFFFFFFF
+
+// Whether (a, ai) comes before (b, bi) in the output
+bool before(float a, uint ai, float b, uint bi) {{
+    bool a_nan = isnan(a), b_nan = isnan(b);
+    if (a_nan || b_nan) return a_nan && (!b_nan || ai < bi);
+    if (a == b) return ai < bi;
+    return a > b;
+}}
+
+kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *out [[buffer(1)]], device uint& back_size [[buffer(2)]], device uint& dim_size [[buffer(3)]], device uint& k [[buffer(4)]], threadgroup float *vals [[threadgroup(0)]], threadgroup uint *idxs [[threadgroup(1)]], uint row [[threadgroup_position_in_grid]], uint tid [[thread_position_in_threadgroup]], uint tg_size [[threads_per_threadgroup]]{rendered}) {{
+    uint a_ = row / back_size;
+    uint b_ = row % back_size;
+    float prev = 0.0;
+    uint prev_i = NONE;
+    for (uint n = 0; n < k; n++) {{
+        // The best of this thread's elements after the last one picked
+        float best = 0.0;
+        uint best_i = NONE;
+        for (uint c_ = tid; c_ < dim_size; c_ += tg_size) {{
+            uint idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            float v = (({valid_exp}) != 0) ? (float)inp[{idx_exp}] : 0.0;
+            if ((prev_i == NONE || before(prev, prev_i, v, c_)) && (best_i == NONE || before(v, c_, best, best_i))) {{
+                best = v;
+                best_i = c_;
+            }}
+        }}
+        vals[tid] = best;
+        idxs[tid] = best_i;
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        for (uint s = tg_size / 2; s > 0; s >>= 1) {{
+            if (tid < s && idxs[tid + s] != NONE && (idxs[tid] == NONE || before(vals[tid + s], idxs[tid + s], vals[tid], idxs[tid]))) {{
+                vals[tid] = vals[tid + s];
+                idxs[tid] = idxs[tid + s];
+            }}
+            threadgroup_barrier(mem_flags::mem_threadgroup);
+        }}
+        prev = vals[0];
+        prev_i = idxs[0];
+        if (tid == 0) {{
+            out[a_ * k * back_size + n * back_size + b_] = ({type_name})({output});
+        }}
+        // Everyone has read the pick before the next round overwrites it
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }}
+}}
+");
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            k,
+            dim,
+            indices,
+            dyn_symbols,
+            _phantom: Default::default(),
+            dyn_map,
+            fast_math: fast_math_enabled(),
+        }
+    }
+}
+
+impl<T> MetalTopK<T> {
+    /// The input shape with the dimension shrunk to k
+    fn output_shape(&self, input: ShapeTracker) -> ShapeTracker {
+        let mut shape = input.contiguous();
+        shape.remove_dim(self.dim);
+        shape.add_dim(self.dim, self.k);
+        shape
+    }
+}
+
+impl<T> MetalKernel for MetalTopK<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![self.output_shape(input_shapes[0]).n_elements() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let shape = inputs[0].1.shape();
+        let dim_size = shape[self.dim].to_usize().unwrap();
+        let back_size: usize = shape
+            .iter()
+            .skip(self.dim + 1)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let n_rows = inputs[0]
+            .1
+            .n_elements()
+            .to_usize()
+            .unwrap()
+            .checked_div(dim_size)
+            .unwrap_or_default();
+        let k = self
+            .k
+            .exec(unsafe { self.dyn_map.as_ref().unwrap() })
+            .unwrap();
+        assert!(
+            k <= dim_size,
+            "Top-k of {k} is larger than the dimension of {dim_size}"
+        );
+        if n_rows == 0 || k == 0 {
+            return;
+        }
+        // The tree reduction needs a power of two threads
+        let threadgroup_size = dim_size.next_power_of_two().clamp(1, 1024);
+
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(output_buffers[0]), 0);
+        encoder.set_u32(2, back_size as u32);
+        encoder.set_u32(3, dim_size as u32);
+        encoder.set_u32(4, k as u32);
+        encoder.set_threadgroup_memory_length(0, (threadgroup_size * size_of::<f32>()) as u64);
+        encoder.set_threadgroup_memory_length(1, (threadgroup_size * size_of::<u32>()) as u64);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            5,
+        );
+
+        // Execute one threadgroup per row
+        encoder.dispatch_thread_groups(
+            MTLSize::new(n_rows as u64, 1, 1),
+            MTLSize::new(threadgroup_size as u64, 1, 1),
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalTopK<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let mut out_shape = self.output_shape(tensors[0].1);
+            out_shape.resolve_global_dyn_dims(unsafe { self.dyn_map.as_ref().unwrap() });
+            let out_size = out_shape.n_elements().to_usize().unwrap();
+            let out = new_buffer(&self.device, (out_size * size_of::<T>()).max(1) as u64);
+            let inp = get_buffer_from_tensor(&tensors[0].0)?;
+
+            let command_buffer = self.queue.new_command_buffer();
+            self.metal_forward(&[(inp, tensors[0].1)], command_buffer, &[], &[&out]);
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        // This op can accept non contiguous inputs
+        if key == "non_contiguous" {
+            return Some(Box::new(()));
+        }
+        if key == "recompile_shapes" {
+            if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        input_shapes[0],
+                        self.k,
+                        self.dim,
+                        self.indices,
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                })
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        prelude::*,
+        tests::{assert_exact, random_vec},
+    };
+
+    use crate::MetalCompiler;
+
+    #[test]
+    fn test_topk() {
+        let mut cx = Graph::new();
+        // Quantize to get lots of duplicates, and make the rows longer than a threadgroup
+        let data = random_vec(3 * 2000)
+            .into_iter()
+            .map(|i| (i * 8.).round())
+            .collect::<Vec<_>>();
+        let a = cx.tensor::<R2<3, 2000>>().set(data.clone());
+        let (values, indices) = a.topk::<(Const<3>, Dyn<'k'>)>('k', 1);
+        // k as long as the dimension, on a permuted input
+        let (col_values, col_indices) = a.permute::<R2<2000, 3>, _>().topk::<R2<3, 3>>(3, 0);
+        let mut outs = [values, indices, col_values, col_indices]
+            .map(|t| t.retrieve().no_shape())
+            .to_vec();
+        cx.set_dyn_dim('k', 40);
+        cx.execute();
+        let cpu = outs.iter().map(|o| o.data()).collect::<Vec<_>>();
+        cx.compile(MetalCompiler::<f32>::default(), &mut outs);
+        cx.execute();
+
+        for (out, cpu) in outs.iter().zip(&cpu) {
+            assert_exact(&out.data(), cpu);
+        }
+        // The CPU op is checked against sorting each row
+        let mut expected = vec![];
+        for row in data.chunks(2000) {
+            let mut perm = (0..2000).collect::<Vec<_>>();
+            perm.sort_by(|a, b| row[*b].partial_cmp(&row[*a]).unwrap());
+            expected.extend(perm[..40].iter().map(|i| *i as f32));
+        }
+        assert_exact(&cpu[1], &expected);
+    }
+}
@@ -5,7 +5,8 @@ use std::{any::Any, marker::PhantomData, mem::size_of, sync::Arc};
 use petgraph::{stable_graph::NodeIndex, visit::EdgeRef};
 
 use luminal::{
-    op::{ConstantValue, InputTensor, Operator},
+    compiler_internals::*,
+    op::{ConstantValue, InputTensor, OpError, Operator},
     prelude::*,
     select_ty,
     shape::symbolic::BigExpression,
@@ -14,9 +15,11 @@ use luminal::{
 use metal_rs::{objc::rc::autoreleasepool, *};
 
 use crate::{
-    compile_function, compile_lib, get_buffer_from_tensor, get_idx_valid_exps, input_dyn_dims,
-    prim::*, render_dyn_dim_inputs, select_const, select_function_from_lib, DispatchNElements,
-    MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
+    compile_function, compile_lib, fast_math_enabled, finish_command_buffer,
+    get_buffer_from_tensor, get_buffers_from_tensors, get_idx_valid_exps, input_dyn_dims,
+    new_buffer, pipeline_cache::KernelLibrary, prim::*, render_dyn_dim_inputs, select_const,
+    select_function_from_lib, with_fast_math, DispatchNElements, MetalBuffer, MetalFloat,
+    MetalKernel, MetalKernelWrapper, SetInt,
 };
 
 use super::binary::MetalSub;
@@ -31,6 +34,7 @@ pub struct MetalMeanReduce<T>(
     Vec<char>,
     *const FxHashMap<char, usize>,
     PhantomData<T>,
+    bool,
 );
 
 impl<T> PartialEq for MetalMeanReduce<T> {
@@ -77,6 +81,7 @@ kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *o
             dyn_symbols,
             dyn_map,
             Default::default(),
+            fast_math_enabled(),
         )
     }
 }
@@ -134,31 +139,30 @@ impl<T> MetalKernel for MetalMeanReduce<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalMeanReduce<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             // Setup buffers
             let mut sh = tensors[0].1;
             sh.remove_dim(self.3);
             let inp_size = sh.n_elements().to_usize().unwrap();
-            let out = self.2.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.2, (inp_size * std::mem::size_of::<T>()) as u64);
 
             // Setup command queue / command buffer / encoder
             let command_buffer = self.1.new_command_buffer();
 
             self.metal_forward(
-                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                &[(get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1)],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.1, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -175,13 +179,15 @@ impl<T: MetalFloat> Operator for MetalMeanReduce<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = MetalMeanReduce::<T>::new(
-                    self.2.clone(),
-                    self.1.clone(),
-                    self.3,
-                    input_shapes[0],
-                    self.5,
-                );
+                *self = with_fast_math(self.7, || {
+                    MetalMeanReduce::<T>::new(
+                        self.2.clone(),
+                        self.1.clone(),
+                        self.3,
+                        input_shapes[0],
+                        self.5,
+                    )
+                });
             }
         }
         None
@@ -225,14 +231,17 @@ impl<T: MetalFloat> Compiler for MeanReduceCompiler<T> {
                 // An intermediate node can't be deleted
                 continue;
             }
-            let dim = graph
+            let [dim] = graph
                 .graph
                 .node_weight(sum_reduce)
                 .unwrap()
                 .as_any()
                 .downcast_ref::<MetalSumReduce<T>>()
                 .unwrap()
-                .dim;
+                .dims[..]
+            else {
+                continue;
+            };
             // Insert MeanReduce op
             let src = graph.get_sources(sum_reduce)[0];
             let mean_reduce = graph
@@ -271,7 +280,7 @@ pub struct MetalStdNorm<T> {
     pipeline: ComputePipelineState,
     device: Device,
     queue: CommandQueue,
-    epsilon: f32, // Epsilon
+    pub(crate) epsilon: f32,
     _phantom: PhantomData<T>,
 }
 
@@ -397,27 +406,23 @@ impl<T> MetalKernel for MetalStdNorm<T> {
 }
 
 impl<T: 'static + Clone> Operator for MetalStdNorm<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             let command_buffer = self.queue.new_command_buffer();
-            let a = tensors[0]
-                .0
-                .borrowed()
-                .data
-                .as_any()
-                .downcast_ref::<MetalBuffer>()
-                .unwrap();
-            let out = self.device.new_buffer(
+            let a = get_buffer_from_tensor(&tensors[0].0)?;
+            let out = new_buffer(
+                &self.device,
                 (tensors[0].1.n_elements().to_usize().unwrap() * size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
             );
 
             self.metal_forward(&[(a, tensors[0].1)], command_buffer, &[], &[&out]);
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -438,6 +443,10 @@ pub struct StdNormCompiler<T>(PhantomData<T>);
 
 impl<T: MetalFloat> Compiler for StdNormCompiler<T> {
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        // The kernel works on 4-wide vectors
+        if !T::has_vector_type() {
+            return;
+        }
         let dev = Device::system_default().unwrap();
         let queue = dev.new_command_queue();
         // Look for the RMSNorm pattern
@@ -568,6 +577,328 @@ impl<T: MetalFloat> Compiler for StdNormCompiler<T> {
     }
 }
 
+/// RMSNorm in a single kernel: x * weight / sqrt(mean(x^2) + epsilon) over the last dim
+#[derive(LuminalPrint, Clone)]
+pub struct MetalRMSNorm<T> {
+    pipeline: ComputePipelineState,
+    device: Device,
+    queue: CommandQueue,
+    pub(crate) epsilon: f32,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> PartialEq for MetalRMSNorm<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.epsilon == other.epsilon
+    }
+}
+
+impl<T: MetalFloat> MetalRMSNorm<T> {
+    fn new(epsilon: f32, device: Device, queue: CommandQueue) -> Self {
+        let type_name = T::type_name();
+        let kernel_code = format!(
+            "#include <metal_stdlib>
+#define SIMD_WIDTH 32
+
+using namespace metal;
+kernel void kernel_rms_norm(
+        device const {type_name} * src0 [[buffer(0)]],
+        device const {type_name} * weight [[buffer(1)]],
+        device       {type_name} * dst [[buffer(2)]],
+        constant   int64_t & row_size [[buffer(3)]],
+        constant     float & eps [[buffer(4)]],
+        threadgroup float  * buf [[threadgroup(0)]],
+        uint threadgroup_position_in_grid[[threadgroup_position_in_grid]],
+        uint thread_position_in_threadgroup[[thread_position_in_threadgroup]],
+        uint simdgroup_index_in_threadgroup[[simdgroup_index_in_threadgroup]],
+        uint thread_index_in_simdgroup[[thread_index_in_simdgroup]],
+        uint threads_per_threadgroup[[threads_per_threadgroup]]) {{
+    device const {type_name} * x = src0 + threadgroup_position_in_grid * row_size;
+
+    // Mean of squares, reduced across the threadgroup
+    float all_sum = 0;
+    for (int i = thread_position_in_threadgroup; i < row_size; i += threads_per_threadgroup) {{
+        all_sum += (float)x[i] * (float)x[i];
+    }}
+    all_sum = simd_sum(all_sum);
+
+    if (threads_per_threadgroup > SIMD_WIDTH) {{
+        if (simdgroup_index_in_threadgroup == 0) {{
+            buf[thread_index_in_simdgroup] = 0.0f;
+        }}
+
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+
+        if (thread_index_in_simdgroup == 0) {{
+            buf[simdgroup_index_in_threadgroup] = all_sum;
+        }}
+
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+
+        all_sum = buf[thread_index_in_simdgroup];
+        all_sum = simd_sum(all_sum);
+    }}
+
+    const float scale = rsqrt(all_sum / row_size + eps);
+
+    // Normalize and scale
+    device {type_name} * y = dst + threadgroup_position_in_grid * row_size;
+    for (int i = thread_position_in_threadgroup; i < row_size; i += threads_per_threadgroup) {{
+        y[i] = ({type_name})((float)x[i] * scale * (float)weight[i]);
+    }}
+}}"
+        );
+
+        Self {
+            pipeline: compile_function("kernel_rms_norm", &kernel_code, &device),
+            device,
+            queue,
+            epsilon,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalRMSNorm<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![input_shapes[0].n_elements() * size_of::<T>()]
+    }
+
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let row_size = inputs[0].1.shape().last().unwrap().to_usize().unwrap();
+        let batch_size = inputs[0]
+            .1
+            .shape()
+            .into_iter()
+            .take(inputs[0].1.len() - 1)
+            .map(|i| i.to_usize().unwrap())
+            .product::<usize>();
+        if batch_size * row_size == 0 {
+            return;
+        }
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        encoder.set_buffer(2, Some(output_buffers[0]), 0);
+        encoder.set_i64(3, row_size as i64);
+        encoder.set_f32(4, self.epsilon);
+        let mut nth = 32; // SIMD width
+        while nth < row_size && nth < 1024 {
+            nth *= 2;
+        }
+        encoder.set_threadgroup_memory_length(0, 32 * size_of::<f32>() as u64);
+        encoder.dispatch_thread_groups(
+            MTLSize {
+                width: batch_size as u64,
+                height: 1,
+                depth: 1,
+            },
+            MTLSize {
+                width: nth as u64,
+                height: 1,
+                depth: 1,
+            },
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: 'static + Clone> Operator for MetalRMSNorm<T> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let inputs = get_buffers_from_tensors(&tensors)?;
+            let out = new_buffer(
+                &self.device,
+                (tensors[0].1.n_elements().to_usize().unwrap() * size_of::<T>()).max(1) as u64,
+            );
+
+            self.metal_forward(&inputs, command_buffer, &[], &[&out]);
+
+            finish_command_buffer(&self.queue, command_buffer);
+
+            Ok(vec![Tensor::new(MetalBuffer(out))])
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
+/// Replace RMSNorm, a std norm followed by a multiply with a weight over the last dim, with a single kernel. This is
+/// meant to be ran **after** the MeanReduceCompiler and **before** the StdNormCompiler.
+#[derive(Default, Debug)]
+pub struct RMSNormCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for RMSNormCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        // mul(mul(recip(sqrt(add(mean_reduce(mul(x, x)), epsilon))), x), weight)
+        let (mut square, mut mean, mut add, mut sqrt, mut recip, mut norm, mut scale, mut epsilon) = (
+            NodeIndex::default(),
+            NodeIndex::default(),
+            NodeIndex::default(),
+            NodeIndex::default(),
+            NodeIndex::default(),
+            NodeIndex::default(),
+            NodeIndex::default(),
+            NodeIndex::default(),
+        );
+
+        let s = SelectOp::new()
+            .ty::<MetalMul<T>>()
+            .ptr(&mut square)
+            .edge(SelectOp::new().ty::<MetalMeanReduce<T>>().ptr(&mut mean))
+            .edge(
+                SelectOp::new()
+                    .check(|op, _| {
+                        op.as_any()
+                            .downcast_ref::<MetalConstant<T>>()
+                            .is_some_and(|c| matches!(c.0, ConstantValue::Float(_)))
+                    })
+                    .ptr(&mut epsilon)
+                    .edge(SelectOp::new().ty::<MetalAdd<T>>().ptr(&mut add)),
+            )
+            .edge(SelectOp::new().ty::<MetalSqrt<T>>().ptr(&mut sqrt))
+            .edge(SelectOp::new().ty::<MetalRecip<T>>().ptr(&mut recip))
+            .edge(SelectOp::new().ty::<MetalMul<T>>().ptr(&mut norm))
+            .edge(SelectOp::new().ty::<MetalMul<T>>().ptr(&mut scale));
+
+        let mut searcher = s.search(graph);
+        while searcher.next_match() {
+            if check_no_delete(graph, &[add, sqrt, recip, norm, epsilon, square, mean]) {
+                // An intermediate node can't be deleted
+                continue;
+            }
+            let ConstantValue::Float(epsilon_num) = graph
+                .graph
+                .node_weight(epsilon)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<MetalConstant<T>>()
+                .unwrap()
+                .0
+            else {
+                continue;
+            };
+            let (mut x, _, mut sh) = graph.get_sources(square)[0];
+            if graph
+                .graph
+                .node_weight(mean)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<MetalMeanReduce<T>>()
+                .is_some_and(|m| m.3 != sh.len() - 1)
+            {
+                // The norm isn't over the last dim
+                continue;
+            }
+            if !graph.get_sources(square).iter().all(|(i, _, _)| *i == x)
+                || !graph.get_sources(norm).iter().any(|(i, _, _)| *i == x)
+            {
+                continue;
+            }
+            // The normed x can't be used anywhere but the weight multiply
+            if graph
+                .graph
+                .edges_directed(norm, petgraph::Direction::Outgoing)
+                .filter(|e| !e.weight().is_schedule())
+                .count()
+                != 1
+            {
+                continue;
+            }
+            // The weight is a vector over the last dim, broadcast over the rest
+            let Some((weight, _, weight_sh)) = graph
+                .get_sources(scale)
+                .into_iter()
+                .find(|(i, _, _)| *i != norm)
+            else {
+                continue;
+            };
+            if !is_row_broadcast(weight_sh, sh.len()) {
+                continue;
+            }
+
+            // Input must be contiguous
+            if !sh.is_contiguous() || sh.is_sliced() || sh.is_padded() {
+                x = graph
+                    .add_op(MetalContiguous::<T>::new(
+                        sh,
+                        dev.clone(),
+                        queue.clone(),
+                        &graph.dyn_map,
+                    ))
+                    .input(x, 0, sh)
+                    .finish();
+                sh = sh.contiguous();
+            }
+
+            // Insert RMSNorm op
+            let rms_norm = graph
+                .add_op(MetalRMSNorm::<T>::new(
+                    epsilon_num,
+                    dev.clone(),
+                    queue.clone(),
+                ))
+                .input(x, 0, sh)
+                .input(weight, 0, weight_sh)
+                .finish();
+
+            // Create edges to dests
+            move_outgoing_edge(scale, rms_norm, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                scale,
+                rms_norm,
+            );
+
+            // Remove the old ops
+            graph.graph.remove_node(scale);
+            graph.safe_remove_node(norm, 0);
+            graph.safe_remove_node(recip, 0);
+            graph.safe_remove_node(sqrt, 0);
+            graph.safe_remove_node(add, 0);
+            graph.safe_remove_node(epsilon, 0);
+            graph.safe_remove_node(mean, 0);
+            graph.safe_remove_node(square, 0);
+            graph.record_rewrite("RMSNorm");
+        }
+    }
+}
+
+/// Whether a view reads a contiguous vector along the last of `rank` dims, the same for every other index
+fn is_row_broadcast(shape: ShapeTracker, rank: usize) -> bool {
+    shape.len() == rank
+        && !shape.is_sliced()
+        && !shape.is_padded()
+        && shape.indexes[..rank - 1].iter().all(|i| shape.fake[*i])
+        && !shape.fake[shape.indexes[rank - 1]]
+}
+
 #[derive(LuminalEqTrue, LuminalPrint, Clone)]
 pub struct MetalExp<T: MetalFloat> {
     pipeline: ComputePipelineState,
@@ -625,31 +956,24 @@ impl<T: MetalFloat> MetalKernel for MetalExp<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalExp<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             // Setup buffers
-            let a_inp = tensors[0]
-                .0
-                .borrowed()
-                .data
-                .as_any()
-                .downcast_ref::<MetalBuffer>()
-                .unwrap();
+            let a_inp = get_buffer_from_tensor(&tensors[0].0)?;
             let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
 
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
 
             self.metal_forward(&[(a_inp, tensors[0].1)], command_buffer, &[], &[&out]);
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -784,29 +1108,22 @@ impl<T: MetalFloat> MetalKernel for MetalCos<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalCos<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
-            let a = tensors[0]
-                .0
-                .borrowed()
-                .data
-                .as_any()
-                .downcast_ref::<MetalBuffer>()
-                .unwrap();
+            let a = get_buffer_from_tensor(&tensors[0].0)?;
             let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-            let out = self.device.new_buffer(
-                (inp_size * std::mem::size_of::<T>()) as u64,
-                MTLResourceOptions::StorageModeShared,
-            );
+            let out = new_buffer(&self.device, (inp_size * std::mem::size_of::<T>()) as u64);
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
 
             self.metal_forward(&[(a, tensors[0].1)], command_buffer, &[], &[&out]);
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -898,6 +1215,11 @@ impl<T: MetalFloat> Compiler for MetalCosCompiler<T> {
 pub struct MetalSoftmax<T> {
     single_row_pipeline: ComputePipelineState,
     looped_pipeline: ComputePipelineState,
+    split_partials_pipeline: ComputePipelineState,
+    split_normalize_pipeline: ComputePipelineState,
+    library: KernelLibrary,
+    /// Normalize every row with a single threadgroup, even the long ones, from a `PreferKernel("single_pass")` hint
+    single_pass: bool,
     queue: CommandQueue,
     device: Device,
     _phantom: PhantomData<T>,
@@ -905,8 +1227,25 @@ pub struct MetalSoftmax<T> {
 
 const SOFTMAX_N_READS: usize = 4;
 const SOFTMAX_LOOPED_LIMIT: usize = 4096;
+/// Rows longer than this are split into chunks normalized by separate threadgroups
+const SOFTMAX_SPLIT_LIMIT: usize = 16384;
+const SOFTMAX_SPLIT_CHUNK: usize = 4096;
 const SIMD_SIZE: usize = 32;
 impl<T> MetalKernel for MetalSoftmax<T> {
+    fn intermediate_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        // The max and normalizer of each chunk, as floats, for split rows
+        let shape = input_shapes[0].shape();
+        let (rows, axis_size) = (
+            shape
+                .iter()
+                .take(shape.len() - 1)
+                .cloned()
+                .product::<BigExpression>()
+                .max(BigExpression::from(1)),
+            shape.last().unwrap().clone(),
+        );
+        vec![rows * axis_size.ceil_div(SOFTMAX_SPLIT_CHUNK) * 2 * size_of::<f32>()]
+    }
     fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
         vec![input_shapes[0].n_elements() * size_of::<T>()]
     }
@@ -914,7 +1253,7 @@ impl<T> MetalKernel for MetalSoftmax<T> {
         &self,
         inputs: &[(&Buffer, ShapeTracker)],
         command_buffer: &CommandBufferRef,
-        _: &[&Buffer],
+        intermediate_buffers: &[&Buffer],
         output_buffers: &[&Buffer],
     ) {
         let batch_size = inputs[0]
@@ -926,17 +1265,30 @@ impl<T> MetalKernel for MetalSoftmax<T> {
             .product::<usize>()
             .max(1);
         let axis_size = inputs[0].1.shape().last().unwrap().to_usize().unwrap();
+        if axis_size > SOFTMAX_SPLIT_LIMIT && !self.single_pass {
+            self.encode_split(
+                inputs[0].0,
+                command_buffer,
+                intermediate_buffers[0],
+                output_buffers[0],
+                batch_size,
+                axis_size,
+            );
+            return;
+        }
 
         let encoder =
             command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
         encoder.set_buffer(0, Some(inputs[0].0), 0);
         encoder.set_buffer(1, Some(output_buffers[0]), 0);
         encoder.set_i32(2, axis_size as i32);
+        // Per-simdgroup maxes and normalizers
         encoder.set_threadgroup_memory_length(0, (SIMD_SIZE * std::mem::size_of::<u32>()) as u64);
+        encoder.set_threadgroup_memory_length(1, (SIMD_SIZE * std::mem::size_of::<u32>()) as u64);
         if axis_size <= SOFTMAX_LOOPED_LIMIT {
             encoder.set_compute_pipeline_state(&self.single_row_pipeline);
-            let threadgroup_needed = (axis_size + SOFTMAX_N_READS - 1) / SOFTMAX_N_READS;
-            let simds_needed = (threadgroup_needed + SIMD_SIZE - 1) / SIMD_SIZE;
+            let threadgroup_needed = axis_size.div_ceil(SOFTMAX_N_READS);
+            let simds_needed = threadgroup_needed.div_ceil(SIMD_SIZE);
             let threadgroup_size = SIMD_SIZE * simds_needed;
             let n_threads = batch_size * threadgroup_size;
             encoder.dispatch_threads(
@@ -944,36 +1296,102 @@ impl<T> MetalKernel for MetalSoftmax<T> {
                 MTLSize::new(threadgroup_size as u64, 1, 1),
             );
         } else {
+            // Rows too long to hold in one threadgroup's registers are walked in chunks, still one threadgroup per row
             encoder.set_compute_pipeline_state(&self.looped_pipeline);
-            encoder.dispatch_1d(batch_size * axis_size);
+            let threadgroup_size = self
+                .looped_pipeline
+                .max_total_threads_per_threadgroup()
+                .min(1024);
+            encoder.dispatch_thread_groups(
+                MTLSize::new(batch_size as u64, 1, 1),
+                MTLSize::new(threadgroup_size, 1, 1),
+            );
         }
         encoder.end_encoding();
     }
 }
 
+impl<T> MetalSoftmax<T> {
+    /// Normalize long rows in two stages: the max and normalizer of each chunk of each row, then each chunk with the
+    /// merged ones of its row
+    fn encode_split(
+        &self,
+        input: &Buffer,
+        command_buffer: &CommandBufferRef,
+        partials: &Buffer,
+        output: &Buffer,
+        batch_size: usize,
+        axis_size: usize,
+    ) {
+        let groups = MTLSize::new(
+            axis_size.div_ceil(SOFTMAX_SPLIT_CHUNK) as u64,
+            batch_size as u64,
+            1,
+        );
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.split_partials_pipeline);
+        encoder.set_buffer(0, Some(input), 0);
+        encoder.set_buffer(1, Some(partials), 0);
+        encoder.set_i32(2, axis_size as i32);
+        encoder.set_i32(3, SOFTMAX_SPLIT_CHUNK as i32);
+        encoder.set_threadgroup_memory_length(0, (SIMD_SIZE * std::mem::size_of::<u32>()) as u64);
+        encoder.set_threadgroup_memory_length(1, (SIMD_SIZE * std::mem::size_of::<u32>()) as u64);
+        let threadgroup_size = self
+            .split_partials_pipeline
+            .max_total_threads_per_threadgroup()
+            .min(1024);
+        encoder.dispatch_thread_groups(groups, MTLSize::new(threadgroup_size, 1, 1));
+        encoder.end_encoding();
+
+        // The second stage reads every partial of its row, so it's encoded after the first finishes
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.split_normalize_pipeline);
+        encoder.set_buffer(0, Some(input), 0);
+        encoder.set_buffer(1, Some(output), 0);
+        encoder.set_buffer(2, Some(partials), 0);
+        encoder.set_i32(3, axis_size as i32);
+        encoder.set_i32(4, SOFTMAX_SPLIT_CHUNK as i32);
+        let threadgroup_size = self
+            .split_normalize_pipeline
+            .max_total_threads_per_threadgroup()
+            .min(1024);
+        encoder.dispatch_thread_groups(groups, MTLSize::new(threadgroup_size, 1, 1));
+        encoder.end_encoding();
+    }
+}
+
 impl<T: MetalFloat> Operator for MetalSoftmax<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             // Setup buffers
             let inp_size = tensors[0].1.n_elements().to_usize().unwrap() * size_of::<T>();
-            let out = self
-                .device
-                .new_buffer(inp_size as u64, MTLResourceOptions::StorageModeShared);
+            let out = new_buffer(&self.device, inp_size as u64);
+            let partials = new_buffer(
+                &self.device,
+                self.intermediate_buffer_sizes(&[tensors[0].1])[0]
+                    .to_usize()
+                    .unwrap()
+                    .max(1) as u64,
+            );
 
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
 
             self.metal_forward(
-                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                &[(get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1)],
                 command_buffer,
-                &[],
+                &[&partials],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -984,6 +1402,29 @@ impl<T: MetalFloat> Operator for MetalSoftmax<T> {
                 self.clone(),
             )))));
         }
+        if key == "fp32_accumulation" && !T::is_f32() {
+            let name = T::library_type_name();
+            let lib = &self.library;
+            let mut variant = self.clone();
+            variant.single_row_pipeline =
+                select_function_from_lib(lib, &format!("softmax_{name}_acc32"), &self.device);
+            variant.looped_pipeline = select_function_from_lib(
+                lib,
+                &format!("softmax_looped_{name}_acc32"),
+                &self.device,
+            );
+            variant.split_partials_pipeline = select_function_from_lib(
+                lib,
+                &format!("softmax_split_partials_{name}_acc32"),
+                &self.device,
+            );
+            variant.split_normalize_pipeline = select_function_from_lib(
+                lib,
+                &format!("softmax_split_normalize_{name}_acc32"),
+                &self.device,
+            );
+            return Some(Box::new(Box::new(variant) as Box<dyn Operator>));
+        }
         None
     }
 }
@@ -1021,15 +1462,19 @@ impl<T: MetalFloat> Compiler for SoftmaxCompiler<T> {
             .edge(SelectOp::new().ty::<MetalMul<T>>().ptr(&mut mul));
 
         let lib = compile_lib(&dev, include_str!("kernels/softmax.metal"));
-        let type_name = if T::is_f32() { "float32" } else { "float16" };
+        let type_name = T::library_type_name();
         let mut searcher = s.search(graph);
         while searcher.next_match() {
             if check_no_delete(graph, &[max_reduce, sub, exp, sum_reduce, recip]) {
                 // An intermediate node can't be deleted
                 continue;
             }
-            // Insert Softmax op
             let src = graph.get_sources(max_reduce)[0];
+            if !is_softmax(graph, src, [max_reduce, sub, exp, sum_reduce, recip, mul]) {
+                continue;
+            }
+            let single_pass = graph.preferred_kernel(mul) == Some("single_pass");
+            // Insert Softmax op
             let mean_reduce = graph
                 .add_op(MetalSoftmax::<T> {
                     device: dev.clone(),
@@ -1045,6 +1490,18 @@ impl<T: MetalFloat> Compiler for SoftmaxCompiler<T> {
                         &format!("softmax_looped_{type_name}"),
                         &dev,
                     ),
+                    split_partials_pipeline: select_function_from_lib(
+                        &lib,
+                        &format!("softmax_split_partials_{type_name}"),
+                        &dev,
+                    ),
+                    split_normalize_pipeline: select_function_from_lib(
+                        &lib,
+                        &format!("softmax_split_normalize_{type_name}"),
+                        &dev,
+                    ),
+                    library: lib.clone(),
+                    single_pass,
                 })
                 .input(src.0, 0, src.2)
                 .finish();
@@ -1070,6 +1527,35 @@ impl<T: MetalFloat> Compiler for SoftmaxCompiler<T> {
     }
 }
 
+/// Check a matched softmax pattern computes exp(x - max(x)) / sum(exp(x - max(x))) over the last dim of a contiguous
+/// x, since the kernel normalizes whole rows of its input
+fn is_softmax<T: MetalFloat>(
+    graph: &Graph,
+    (x, x_output, x_shape): (NodeIndex, u8, ShapeTracker),
+    [max_reduce, sub, exp, sum_reduce, recip, mul]: [NodeIndex; 6],
+) -> bool {
+    if !x_shape.is_contiguous() || x_shape.is_sliced() || x_shape.is_padded() {
+        return false;
+    }
+    let last_dim = x_shape.len() - 1;
+    let op = |node| graph.graph.node_weight(node).unwrap().as_any();
+    let reduces_last_dim = op(max_reduce)
+        .downcast_ref::<MetalMaxReduce<T>>()
+        .is_some_and(|o| o.dim == last_dim && o.epilogue.is_none())
+        && op(sum_reduce)
+            .downcast_ref::<MetalSumReduce<T>>()
+            .is_some_and(|o| o.dims == [last_dim] && o.epilogue.is_none());
+    let sub_srcs = graph.get_sources(sub);
+    let mul_srcs = graph.get_sources(mul);
+    reduces_last_dim
+        && sub_srcs.len() == 2
+        && (sub_srcs[0].0, sub_srcs[0].1) == (x, x_output)
+        && sub_srcs[1].0 == max_reduce
+        && mul_srcs.len() == 2
+        && mul_srcs.iter().any(|(n, _, _)| *n == exp)
+        && mul_srcs.iter().any(|(n, _, _)| *n == recip)
+}
+
 /// Special kernel for rotating. Probably shouldn't exist, seeing as it's only for rotary embeddings
 #[derive(LuminalPrint, LuminalEqTrue, Clone)]
 pub struct MetalRope<T> {
@@ -1081,6 +1567,7 @@ pub struct MetalRope<T> {
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
+    fast_math: bool,
 }
 
 impl<T: MetalFloat> MetalRope<T> {
@@ -1140,6 +1627,7 @@ kernel void mkernel(
             seq_offset,
             dyn_map,
             _phantom: Default::default(),
+            fast_math: fast_math_enabled(),
         }
     }
 }
@@ -1186,28 +1674,28 @@ impl<T> MetalKernel for MetalRope<T> {
 }
 
 impl<T: MetalFloat> Operator for MetalRope<T> {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
         autoreleasepool(|| {
             // Setup buffers
             let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-            let out = self
-                .device
-                .new_buffer(inp_size as u64, MTLResourceOptions::StorageModeShared);
+            let out = new_buffer(&self.device, inp_size as u64);
 
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
 
             self.metal_forward(
-                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                &[(get_buffer_from_tensor(&tensors[0].0)?, tensors[0].1)],
                 command_buffer,
                 &[],
                 &[&out],
             );
 
-            command_buffer.commit();
-            command_buffer.wait_until_completed();
+            finish_command_buffer(&self.queue, command_buffer);
 
-            vec![Tensor::new(MetalBuffer(out))]
+            Ok(vec![Tensor::new(MetalBuffer(out))])
         })
     }
 
@@ -1224,14 +1712,16 @@ impl<T: MetalFloat> Operator for MetalRope<T> {
         }
         if key == "recompile_shapes" {
             if let Some(input_shapes) = input.downcast_ref::<Vec<ShapeTracker>>() {
-                *self = Self::new(
-                    self.axis_size,
-                    self.seq_offset.clone(),
-                    input_shapes[0],
-                    self.device.clone(),
-                    self.queue.clone(),
-                    self.dyn_map,
-                );
+                *self = with_fast_math(self.fast_math, || {
+                    Self::new(
+                        self.axis_size,
+                        self.seq_offset.clone(),
+                        input_shapes[0],
+                        self.device.clone(),
+                        self.queue.clone(),
+                        self.dyn_map,
+                    )
+                });
             }
         }
         None
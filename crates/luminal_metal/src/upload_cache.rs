@@ -0,0 +1,81 @@
+use std::{
+    any::TypeId,
+    sync::{Mutex, MutexGuard, OnceLock},
+};
+
+use metal_rs::{Buffer, Device};
+use rustc_hash::FxHashMap;
+
+/// (Device registry id, content key, element type)
+type UploadKey = (u64, u64, TypeId);
+
+/// A buffer uploaded from a content-keyed tensor, with the number of leases held on it
+struct Upload {
+    buffer: Buffer,
+    leases: usize,
+}
+
+/// Buffers uploaded from content-keyed tensors, shared across all graphs
+static UPLOADS: OnceLock<Mutex<FxHashMap<UploadKey, Upload>>> = OnceLock::new();
+
+fn uploads() -> MutexGuard<'static, FxHashMap<UploadKey, Upload>> {
+    UPLOADS.get_or_init(Default::default).lock().unwrap()
+}
+
+/// A hold on a shared upload, kept by the op that copies the tensor to the device.
+///
+/// Each lease counts towards the upload's owners, so it stays resident (and is reused) for as long as any lease on
+/// it is alive, and is evicted when the last one is dropped.
+#[derive(Debug)]
+pub(crate) struct UploadLease(UploadKey);
+
+impl Clone for UploadLease {
+    fn clone(&self) -> Self {
+        if let Some(upload) = uploads().get_mut(&self.0) {
+            upload.leases += 1;
+        }
+        Self(self.0)
+    }
+}
+
+impl Drop for UploadLease {
+    fn drop(&mut self) {
+        let mut uploads = uploads();
+        if let Some(upload) = uploads.get_mut(&self.0) {
+            upload.leases -= 1;
+            if upload.leases == 0 {
+                uploads.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// Get the buffer already holding this content on the device, or upload it and cache the buffer, along with a lease
+/// that keeps it cached.
+pub(crate) fn get_or_upload<T: 'static>(
+    device: &Device,
+    content_key: u64,
+    upload: impl FnOnce() -> Buffer,
+) -> (Buffer, UploadLease) {
+    let key = (device.registry_id(), content_key, TypeId::of::<T>());
+    let mut uploads = uploads();
+    let entry = uploads.entry(key).or_insert_with(|| Upload {
+        buffer: upload(),
+        leases: 0,
+    });
+    entry.leases += 1;
+    (entry.buffer.clone(), UploadLease(key))
+}
+
+/// Whether a buffer is the upload shared by every tensor with this content, so writing to it would change them all
+pub(crate) fn is_shared<T: 'static>(device: &Device, content_key: u64, buffer: &Buffer) -> bool {
+    uploads()
+        .get(&(device.registry_id(), content_key, TypeId::of::<T>()))
+        .is_some_and(|u| u.buffer.gpu_address() == buffer.gpu_address())
+}
+
+/// Number of content-keyed buffers currently resident on the device
+pub fn resident_uploads(device: &Device) -> usize {
+    let id = device.registry_id();
+    uploads().keys().filter(|(d, _, _)| *d == id).count()
+}
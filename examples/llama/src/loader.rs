@@ -1,4 +1,6 @@
-use luminal::{op::Function, prelude::*};
+use std::rc::Rc;
+
+use luminal::{compiler_internals::petgraph, op::Function, prelude::*};
 
 /// Load the model in the same way dfdx-llama does
 pub struct DfdxDeferredLoader {
@@ -34,7 +36,7 @@ impl Loader for DfdxDeferredLoader {
                 .downcast_mut::<Function>()
             {
                 let path = self.path.clone();
-                inp_func.1 = Box::new(move |_| {
+                inp_func.1 = Rc::new(move |_| {
                     // Get memmapped tensor
                     let bytes = std::fs::read(format!("{path}/{s}")).unwrap();
                     let data: Vec<f32> = if bytes.len() == n_elements * 2 {
@@ -1,4 +1,4 @@
-use std::fs::File;
+use std::{fs::File, rc::Rc};
 
 use luminal::{op::Function, prelude::*};
 
@@ -57,7 +57,7 @@ impl Loader for MetalQ8Loader {
                     }
                     _ => panic!("Unsupported dtype: {data_type:?}"),
                 };
-                loading_node.1 = Box::new(move |_| {
+                loading_node.1 = Rc::new(move |_| {
                     let mmap_buffer =
                         unsafe { Mmap::map(&File::open(&file_path).unwrap()).unwrap() };
                     let buffer = Device::system_default().unwrap().new_buffer_with_data(
@@ -128,7 +128,7 @@ impl Loader for Q8Loader {
                     }
                     _ => panic!("Unsupported dtype: {data_type:?}"),
                 };
-                loading_node.1 = Box::new(move |_| {
+                loading_node.1 = Rc::new(move |_| {
                     // Load all bytes
                     let mut bytes = vec![0; n_bytes];
                     let mut file = File::open(&file_path).unwrap();
@@ -0,0 +1,57 @@
+use std::time::{Duration, Instant};
+
+use luminal::prelude::*;
+
+const RUNS: usize = 100;
+
+/// Build a small MLP over a batch of rows, optionally specialized to that batch size, and time its executions
+fn run(batch: usize, specialize: bool) -> (Vec<f32>, Duration) {
+    let mut cx = Graph::new();
+    let x = cx
+        .tensor::<(Dyn<'b'>, Const<256>)>()
+        .set_dyn(vec![0.5; batch * 256], &[batch, 256]);
+    let w1 = cx
+        .tensor::<R2<256, 512>>()
+        .set(vec![0.01; 256 * 512])
+        .keep();
+    let w2 = cx
+        .tensor::<R2<512, 256>>()
+        .set(vec![0.01; 512 * 256])
+        .keep();
+    let mut out = x.matmul(w1).relu().matmul(w2).softmax::<1>().retrieve();
+    let compiler = <(GenericCompiler, CPUCompiler)>::default();
+    let mut cx = if specialize {
+        cx.specialize(&[('b', batch)].into_iter().collect(), compiler, &mut out)
+            .unwrap()
+    } else {
+        cx.compile(compiler, &mut out);
+        cx
+    };
+    // Point the output at the graph that runs
+    let out = GraphTensor::<(Dyn<'b'>, Const<256>)>::from_id(out.id, out.shape, &mut cx);
+
+    cx.execute();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        cx.execute();
+    }
+    (out.data(), start.elapsed() / RUNS as u32)
+}
+
+fn main() {
+    // A fixed-size workload, where the batch dim never changes
+    let batch = 64;
+    let (dynamic_out, dynamic_time) = run(batch, false);
+    let (specialized_out, specialized_time) = run(batch, true);
+    assert!(dynamic_out
+        .iter()
+        .zip(&specialized_out)
+        .all(|(a, b)| (a - b).abs() < 1e-5));
+
+    println!("Dynamic:     {dynamic_time:?} per run");
+    println!("Specialized: {specialized_time:?} per run");
+    println!(
+        "Speedup:     {:.2}x",
+        dynamic_time.as_secs_f64() / specialized_time.as_secs_f64()
+    );
+}
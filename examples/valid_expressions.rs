@@ -0,0 +1,49 @@
+use std::time::{Duration, Instant};
+
+use luminal::prelude::*;
+
+const N: usize = 512;
+const LAYERS: usize = 8;
+const RUNS: usize = 10;
+
+/// Time a long elementwise graph whose ops read transposed and broadcasted inputs. Neither is padded or sliced, so
+/// every valid expression is 1, but neither input is contiguous either.
+fn run() -> (Vec<f32>, Duration) {
+    let mut cx = Graph::new();
+    let x = cx
+        .tensor::<R2<N, N>>()
+        .set(
+            (0..N * N)
+                .map(|i| (i % 97) as f32 / 97.)
+                .collect::<Vec<_>>(),
+        )
+        .keep();
+    let bias = cx
+        .tensor::<R1<N>>()
+        .set((0..N).map(|i| (i % 13) as f32 / 13.).collect::<Vec<_>>())
+        .keep();
+    let transposed = x.permute::<R2<N, N>, Axes2<1, 0>>();
+    let broadcasted = bias.expand::<R2<N, N>, Axis<0>>();
+    let mut out = x;
+    for _ in 0..LAYERS {
+        out = (out.sin() + transposed) * broadcasted;
+    }
+    let mut out = out.retrieve();
+    cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut out);
+
+    cx.execute();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        out.drop();
+        cx.execute();
+    }
+    (out.data(), start.elapsed() / RUNS as u32)
+}
+
+fn main() {
+    let (out, time) = run();
+    println!(
+        "{LAYERS} layers over {N}x{N}: {time:?} per run (checksum {:.4})",
+        out.iter().sum::<f32>()
+    );
+}
@@ -0,0 +1,104 @@
+"""Writes the same checkpoint as multi_dtype.safetensors, multi_dtype.gguf and multi_dtype.npz, each using a mix of dtypes.
+
+All values are exactly representable in every dtype used (fp16, bf16 and GGML's Q8_0), so conversions between the
+formats are lossless. Run from this directory with `python3 multi_dtype.py`. Only uses the standard library.
+"""
+import json
+import struct
+import zipfile
+
+weight = [(i - 5) * 0.25 for i in range(12)]  # [3, 4]
+bias = [0.5, -1.0, 2.0, 0.125]  # [4]
+# [2, 32], one Q8_0 block per row. The first value of each block is its absolute max, so the block scale is 0.25
+embed = [31.75 if i % 32 == 0 else ((i * 37) % 255 - 127) * 0.25 for i in range(64)]
+norm = [1.0, 1.5, 2.0, 2.5]  # [4]
+# Already quantized int8 weight with per-channel scales, only in the safetensors file
+qweight = [-128, -3, 0, 7, 1, 2, 3, 127]  # [2, 4]
+qweight_scales = [0.5, 0.25]
+
+
+def f16_bits(v):
+    return struct.unpack("<H", struct.pack("<e", v))[0]
+
+
+def bf16(values):
+    return b"".join(struct.pack("<f", v)[2:] for v in values)
+
+
+# safetensors
+tensors = [
+    ("weight", "F32", [3, 4], struct.pack("<12f", *weight)),
+    ("bias", "F16", [4], struct.pack("<4e", *bias)),
+    ("embed", "BF16", [2, 32], bf16(embed)),
+    ("layer0.norm", "F32", [4], struct.pack("<4f", *norm)),
+    ("qweight", "I8", [2, 4], struct.pack("<8b", *qweight)),
+    ("qweight.scales", "F32", [2], struct.pack("<2f", *qweight_scales)),
+]
+header, data = {}, b""
+for name, dtype, shape, raw in tensors:
+    header[name] = {"dtype": dtype, "shape": shape, "data_offsets": [len(data), len(data) + len(raw)]}
+    data += raw
+header = json.dumps(header, separators=(",", ":")).encode()
+header += b" " * (-len(header) % 8)
+with open("multi_dtype.safetensors", "wb") as f:
+    f.write(struct.pack("<Q", len(header)) + header + data)
+
+
+# gguf (v3). Dimensions are stored innermost first
+def q8_0(values):
+    out = b""
+    for b in range(0, len(values), 32):
+        block = values[b : b + 32]
+        scale = max(abs(v) for v in block) / 127
+        out += struct.pack("<e", scale) + struct.pack("<32b", *[round(v / scale) for v in block])
+    return out
+
+
+def gguf_string(s):
+    return struct.pack("<Q", len(s)) + s.encode()
+
+
+ALIGNMENT = 32
+tensors = [
+    ("weight", 0, [3, 4], struct.pack("<12f", *weight)),
+    ("bias", 1, [4], struct.pack("<4e", *bias)),
+    ("embed", 8, [2, 32], q8_0(embed)),
+    ("layer0.norm", 0, [4], struct.pack("<4f", *norm)),
+]
+infos, data = b"", b""
+for name, ggml_type, shape, raw in tensors:
+    data += b"\0" * (-len(data) % ALIGNMENT)
+    infos += gguf_string(name) + struct.pack("<I", len(shape))
+    infos += struct.pack(f"<{len(shape)}Q", *reversed(shape))
+    infos += struct.pack("<IQ", ggml_type, len(data))
+    data += raw
+metadata = gguf_string("general.alignment") + struct.pack("<II", 4, ALIGNMENT)
+header = b"GGUF" + struct.pack("<IQQ", 3, len(tensors), 1) + metadata + infos
+header += b"\0" * (-len(header) % ALIGNMENT)
+with open("multi_dtype.gguf", "wb") as f:
+    f.write(header + data)
+
+
+# npz, written the way np.savez does (stored, forcing zip64 headers)
+def npy(descr, shape, raw):
+    header = f"{{'descr': '{descr}', 'fortran_order': False, 'shape': {tuple(shape)}, }}"
+    header += " " * (-(len(header) + 11) % 64) + "\n"
+    return b"\x93NUMPY\x01\x00" + struct.pack("<H", len(header)) + header.encode() + raw
+
+
+arrays = [
+    ("weight", npy("<f4", [3, 4], struct.pack("<12f", *weight))),
+    ("bias", npy("<f2", [4], struct.pack("<4e", *bias))),
+    ("embed", npy("<f8", [2, 32], struct.pack("<64d", *embed))),
+    ("layer0.norm", npy("<f4", [4], struct.pack("<4f", *norm))),
+]
+with zipfile.ZipFile("multi_dtype.npz", "w", zipfile.ZIP_STORED) as z:
+    for name, raw in arrays:
+        with z.open(name + ".npy", "w", force_zip64=True) as f:
+            f.write(raw)
+
+print("weight", weight)
+print("bias", bias)
+print("embed", embed)
+print("layer0.norm", norm)
+print("qweight", [q * s for i, s in enumerate(qweight_scales) for q in qweight[i * 4 : i * 4 + 4]])
@@ -0,0 +1,37 @@
+"""Writes quantized_int8.safetensors and prints the dequantized reference values.
+
+Run from this directory with `python3 quantized_int8.py`. Only uses the standard library.
+"""
+import json
+import struct
+
+# Per-channel: int8 weights, one f32 scale per output channel (row), symmetric (no zero points)
+per_channel = [[-128, -3, 0, 7], [1, 2, 3, 127], [-50, 25, -10, 5]]
+per_channel_scales = [0.5, 0.01, 2.0]
+# Per-tensor: uint8 weights, a single f16 scale and uint8 zero point
+per_tensor = [[0, 128, 255], [100, 130, 7]]
+per_tensor_scale = 0.25
+per_tensor_zero = 128
+# Int8 weight with no scale tensor alongside it
+missing = [1, 2, 3, 4]
+
+tensors = [
+    ("per_channel", "I8", [3, 4], struct.pack("<12b", *sum(per_channel, []))),
+    ("per_channel.scales", "F32", [3], struct.pack("<3f", *per_channel_scales)),
+    ("per_tensor", "U8", [2, 3], struct.pack("<6B", *sum(per_tensor, []))),
+    ("per_tensor.scales", "F16", [1], struct.pack("<e", per_tensor_scale)),
+    ("per_tensor.qzeros", "U8", [1], struct.pack("<B", per_tensor_zero)),
+    ("missing", "I8", [4], struct.pack("<4b", *missing)),
+]
+
+header, data = {}, b""
+for name, dtype, shape, raw in tensors:
+    header[name] = {"dtype": dtype, "shape": shape, "data_offsets": [len(data), len(data) + len(raw)]}
+    data += raw
+header = json.dumps(header, separators=(",", ":")).encode()
+header += b" " * (-len(header) % 8)
+with open("quantized_int8.safetensors", "wb") as f:
+    f.write(struct.pack("<Q", len(header)) + header + data)
+
+print("per_channel", [q * s for row, s in zip(per_channel, per_channel_scales) for q in row])
+print("per_tensor", [(q - per_tensor_zero) * per_tensor_scale for row in per_tensor for q in row])
@@ -0,0 +1,30 @@
+"""Writes quirky_names.safetensors, a checkpoint with the naming quirks of real-world files.
+
+Run from this directory with `python3 quirky_names.py`. Only uses the standard library.
+"""
+import json
+import struct
+
+embed = [0.5, -1.0, 2.0, 3.25, -4.5, 6.0]
+tensors = [
+    # "::" separators and a different case than the model uses
+    ("Embed::Weight", [2, 3], embed),
+    # Mixed separators
+    ("layers.0/attn::w_q", [6], [1.0, -1.0, 2.0, -2.0, 3.0, -3.0]),
+    # The embedding duplicated under a second name, in upper case
+    ("LM_HEAD.weight", [2, 3], embed),
+    # A metadata-only entry without elements
+    ("version", [0], []),
+    # A tensor no model weight uses
+    ("extra::unused", [1], [9.0]),
+]
+
+header, data = {"__metadata__": {"format": "pt"}}, b""
+for name, shape, values in tensors:
+    raw = struct.pack(f"<{len(values)}f", *values)
+    header[name] = {"dtype": "F32", "shape": shape, "data_offsets": [len(data), len(data) + len(raw)]}
+    data += raw
+header = json.dumps(header, separators=(",", ":")).encode()
+header += b" " * (-len(header) % 8)
+with open("quirky_names.safetensors", "wb") as f:
+    f.write(struct.pack("<Q", len(header)) + header + data)
@@ -0,0 +1,43 @@
+//! Convert a safetensors, GGUF or npz checkpoint to safetensors.
+//!
+//! Usage: convert_checkpoint <input> <output.safetensors> [--dtype f32|f16|bf16] [--quantize-int8]
+
+use std::{path::Path, process::exit};
+
+use luminal::serialization::{convert_checkpoint, ConvertOptions, Dtype};
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let mut paths = vec![];
+    let mut options = ConvertOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dtype" => {
+                options.dtype = Some(match args.next().map(|s| s.as_str()) {
+                    Some("f32") => Dtype::F32,
+                    Some("f16") => Dtype::F16,
+                    Some("bf16") => Dtype::BF16,
+                    d => usage(&format!("Unknown dtype {d:?}")),
+                })
+            }
+            "--quantize-int8" => options.quantize_int8 = true,
+            _ => paths.push(arg),
+        }
+    }
+    let [input, output] = paths[..] else {
+        usage("Expected an input and an output path");
+    };
+    if let Err(e) = convert_checkpoint(Path::new(input), Path::new(output), &options) {
+        eprintln!("Failed to convert {input}: {e}");
+        exit(1);
+    }
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("{error}");
+    eprintln!(
+        "Usage: convert_checkpoint <input> <output.safetensors> [--dtype f32|f16|bf16] [--quantize-int8]"
+    );
+    exit(1);
+}
@@ -1,8 +1,8 @@
-use crate::{
-    op::*,
-    prelude::{petgraph::visit::EdgeRef, *},
-};
+use std::any::Any;
+
+use crate::{compiler_internals::*, op::*, prelude::*};
 use itertools::Itertools;
+use petgraph::visit::EdgeRef;
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Sub;
@@ -19,7 +19,7 @@ impl Operator for Sub {
             tensors[1].1.index_expression(),
             tensors[1].1.valid_expression(),
         );
-        let mut data = vec![0.; tensors[0].1.n_elements().to_usize().unwrap()];
+        let mut data = vec![0.; tensors[0].1.n_elements_or_zero().to_usize().unwrap()];
         for i in 0..data.len() {
             let lhs = if a_val.exec_single_var(i) != 0 {
                 a_data[a_ind.exec_single_var(i)]
@@ -37,6 +37,10 @@ impl Operator for Sub {
             data: Box::new(data),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(LuminalPrint, Default)]
@@ -108,6 +112,7 @@ impl Compiler for SubtractionCompiler {
             }
             graph.graph.remove_node(mul);
             graph.graph.remove_node(add);
+            graph.record_rewrite("Sub");
         }
     }
 }
@@ -121,7 +126,7 @@ impl Operator for Equal {
             get_vec_from_tensor(&tensors[0].0),
             get_vec_from_tensor(&tensors[1].0),
         );
-        let mut data = vec![0.; tensors[0].1.n_elements().to_usize().unwrap()];
+        let mut data = vec![0.; tensors[0].1.n_elements_or_zero().to_usize().unwrap()];
         let (a_ind, a_val, b_ind, b_val) = (
             tensors[0].1.index_expression(),
             tensors[0].1.valid_expression(),
@@ -139,12 +144,16 @@ impl Operator for Equal {
             } else {
                 0.0
             };
-            data[i] = if a < b { 1. } else { 0. };
+            data[i] = if a == b { 1. } else { 0. };
         }
         vec![Tensor {
             data: Box::new(data),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(LuminalPrint, Default)]
@@ -247,6 +256,7 @@ impl Compiler for EqualCompiler {
             graph.safe_remove_node(one, 0);
             graph.safe_remove_node(less_than2, 0);
             graph.safe_remove_node(less_than1, 0);
+            graph.record_rewrite("Equal");
             searcher.clear_cached_results();
         }
     }
@@ -259,14 +269,8 @@ pub struct Gather {
 
 impl Operator for Gather {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        // Inp 1 should be Vec<f32> and inp 2 should be a CudaSlice<T>
-        let indexes = tensors[0]
-            .0
-            .borrowed()
-            .data
-            .as_any()
-            .downcast_ref::<Vec<f32>>()
-            .unwrap();
+        // Inp 1 should be i32 or f32 indexes and inp 2 should be a Vec<f32>
+        let indexes = get_indexes_from_tensor(&tensors[0].0);
         let weights = tensors[1]
             .0
             .borrowed()
@@ -287,6 +291,18 @@ impl Operator for Gather {
             data: Box::new(out),
         }]
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        // This op can accept integer inputs
+        if key == "int_inputs" {
+            return Some(Box::new(()));
+        }
+        None
+    }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(LuminalPrint, Default)]
@@ -331,6 +347,7 @@ impl Compiler for GatherCompiler {
             graph.graph.remove_node(sum_reduce);
             graph.safe_remove_node(mul, 0);
             graph.safe_remove_node(arange, 0);
+            graph.record_rewrite("Gather");
         }
     }
 }
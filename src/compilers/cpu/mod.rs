@@ -6,13 +6,21 @@ use std::any::Any;
 use itertools::Itertools;
 use petgraph::{stable_graph::NodeIndex, visit::EdgeRef};
 
+use rustc_hash::FxHashMap;
+
 use crate::{
-    op::{Exp2, InputTensor, Log2, Mul, Operator, Recip, Sin, SumReduce},
+    compiler_internals::*,
+    op::{
+        Add, Constant, ConstantValue, Exp2, InputTensor, Log2, Mul, Operator, Recip, Sin,
+        SumReduce, Summation,
+    },
     prelude::*,
+    shape::symbolic::Expression,
 };
 
 // Ops and compilers specific to CPU execution
 
+/// Compilers that rewrite primitive ops into faster CPU ops, like matmuls
 pub type CPUCompiler = (
     MatMulCompiler,
     binary::SubtractionCompiler,
@@ -22,7 +30,11 @@ pub type CPUCompiler = (
     UnaryFusionCompiler,
 );
 
-pub type MatMulCompiler = (MatMul2DCompiler, BatchMatMul2DCompiler);
+pub type MatMulCompiler = (
+    MatMul2DCompiler,
+    BatchMatMul2DCompiler,
+    MatMulOutputPaddingCompiler,
+);
 
 #[derive(Debug, Default)]
 pub struct MatMul2DCompiler;
@@ -44,7 +56,7 @@ impl Compiler for MatMul2DCompiler {
             .edge(
                 SelectOp::new()
                     .ty::<SumReduce>()
-                    .check(|o, _| o.is_equal(&SumReduce(0)))
+                    .check(|o, _| o.is_equal(&SumReduce(2, Summation::Naive)))
                     .ptr(&mut sum_reduce),
             );
         let mut searcher = s.search(graph);
@@ -53,14 +65,26 @@ impl Compiler for MatMul2DCompiler {
                 // The intermediate mul can't be deleted
                 continue;
             }
+            if graph.has_hint(mul, &CompilerHint::NoFuse)
+                || graph.has_hint(sum_reduce, &CompilerHint::NoFuse)
+            {
+                continue;
+            }
             // Insert MatMul2D op
             let mut srcs = graph.get_sources(mul);
             // Undo expansions and permute
             srcs[0].2.remove_dim(1);
             srcs[1].2.remove_dim(0);
             srcs[1].2.permute(&[1, 0]);
+            // sgemm reads the inputs through their strides alone
+            if srcs
+                .iter()
+                .any(|(_, _, shape)| shape.is_sliced() || shape.is_padded())
+            {
+                continue;
+            }
             let new_op = graph
-                .add_op(MatMul2D)
+                .add_op(MatMul2D::default())
                 .input(srcs[0].0, 0, srcs[0].2)
                 .input(srcs[1].0, 0, srcs[1].2)
                 .finish();
@@ -85,12 +109,52 @@ impl Compiler for MatMul2DCompiler {
             // Remove the old ops
             graph.graph.remove_node(mul);
             graph.graph.remove_node(sum_reduce);
+            graph.record_rewrite("MatMul2D");
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct MatMul2D;
+/// Columns written past the end of a fused matmul's output rows, like -inf scores rounding a context up to a tile
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputPadding {
+    /// Number of padding columns
+    pub amount: Expression,
+    /// Value the padding columns are filled with
+    pub value: f32,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+
+impl OutputPadding {
+    fn amount(&self) -> usize {
+        self.amount
+            .exec(unsafe { self.dyn_map.as_ref().unwrap() })
+            .unwrap()
+    }
+}
+
+/// Allocate `batch` MxN output matrices with the padding columns filled in, returning the padded row width
+fn padded_output(
+    batch: usize,
+    m: usize,
+    n: usize,
+    padding: &Option<OutputPadding>,
+) -> (Vec<f32>, usize) {
+    let Some(padding) = padding else {
+        return (vec![0.; batch * m * n], n);
+    };
+    let row = n + padding.amount();
+    let mut c = vec![0.; batch * m * row];
+    for r in c.chunks_exact_mut(row) {
+        r[n..].fill(padding.value);
+    }
+    (c, row)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MatMul2D {
+    /// Padding the output rows are extended with, set by the [`MatMulOutputPaddingCompiler`]
+    pub output_padding: Option<OutputPadding>,
+}
 
 impl Operator for MatMul2D {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
@@ -110,12 +174,21 @@ impl Operator for MatMul2D {
             .as_any()
             .downcast_ref::<Vec<f32>>()
             .unwrap();
-        let mut c = vec![0.; a_shape[0].to_usize().unwrap() * b_shape[1].to_usize().unwrap()];
+        let (m, k, n) = (
+            a_shape[0].to_usize().unwrap(),
+            a_shape[1].to_usize().unwrap(),
+            b_shape[1].to_usize().unwrap(),
+        );
+        let (mut c, row) = padded_output(1, m, n, &self.output_padding);
+        // An empty inner dimension sums nothing, so the output stays zeroed
+        if m * n == 0 || k == 0 {
+            return vec![Tensor::new(c)];
+        }
         unsafe {
             matrixmultiply::sgemm(
-                a_shape[0].to_usize().unwrap(),
-                a_shape[1].to_usize().unwrap(),
-                b_shape[1].to_usize().unwrap(),
+                m,
+                k,
+                n,
                 1.0,
                 a_data.as_ptr(),
                 a_strides[0].to_usize().unwrap() as isize,
@@ -125,13 +198,17 @@ impl Operator for MatMul2D {
                 b_strides[1].to_usize().unwrap() as isize,
                 0.0,
                 c.as_mut_ptr(),
-                b_shape[1].to_usize().unwrap() as isize,
+                row as isize,
                 1,
             );
         }
 
         vec![Tensor::new(c)]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Debug, Default)]
@@ -154,7 +231,7 @@ impl Compiler for BatchMatMul2DCompiler {
             .edge(
                 SelectOp::new()
                     .ty::<SumReduce>()
-                    .check(|o, _| o.is_equal(&SumReduce(3)))
+                    .check(|o, _| o.is_equal(&SumReduce(3, Summation::Naive)))
                     .ptr(&mut sum_reduce),
             );
         let mut searcher = s.search(graph);
@@ -163,6 +240,11 @@ impl Compiler for BatchMatMul2DCompiler {
                 // The intermediate mul can't be deleted
                 continue;
             }
+            if graph.has_hint(mul, &CompilerHint::NoFuse)
+                || graph.has_hint(sum_reduce, &CompilerHint::NoFuse)
+            {
+                continue;
+            }
             // Insert MatMul2D op
             let mut srcs = graph.get_sources(mul);
             // Undo expansions and permute
@@ -171,7 +253,7 @@ impl Compiler for BatchMatMul2DCompiler {
             srcs[1].2.remove_dim(0);
             srcs[1].2.permute(&[1, 0]);
             let new_op = graph
-                .add_op(BatchedMatMul2D)
+                .add_op(BatchedMatMul2D::default())
                 .input(srcs[0].0, 0, srcs[0].2)
                 .input(srcs[1].0, 0, srcs[1].2)
                 .finish();
@@ -196,12 +278,16 @@ impl Compiler for BatchMatMul2DCompiler {
             // Remove the old ops
             graph.graph.remove_node(mul);
             graph.graph.remove_node(sum_reduce);
+            graph.record_rewrite("BatchMatMul2D");
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct BatchedMatMul2D;
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatchedMatMul2D {
+    /// Padding the output rows are extended with, set by the [`MatMulOutputPaddingCompiler`]
+    pub output_padding: Option<OutputPadding>,
+}
 
 // ABCxCD -> ABD
 impl Operator for BatchedMatMul2D {
@@ -222,20 +308,25 @@ impl Operator for BatchedMatMul2D {
             .as_any()
             .downcast_ref::<Vec<f32>>()
             .unwrap();
-        let mut c = vec![
-            0.;
-            a_shape[0].to_usize().unwrap()
-                * a_shape[1].to_usize().unwrap()
-                * b_shape[1].to_usize().unwrap()
-        ];
-
-        let mat_size = a_shape[1].to_usize().unwrap() * b_shape[1].to_usize().unwrap();
-        for i in 0..a_shape[0].to_usize().unwrap() {
+        let (batch, m, k, n) = (
+            a_shape[0].to_usize().unwrap(),
+            a_shape[1].to_usize().unwrap(),
+            a_shape[2].to_usize().unwrap(),
+            b_shape[1].to_usize().unwrap(),
+        );
+        let (mut c, row) = padded_output(batch, m, n, &self.output_padding);
+        // An empty inner dimension sums nothing, so the output stays zeroed
+        if batch * m * n == 0 || k == 0 {
+            return vec![Tensor::new(c)];
+        }
+
+        let mat_size = m * row;
+        for i in 0..batch {
             unsafe {
                 matrixmultiply::sgemm(
-                    a_shape[1].to_usize().unwrap(),
-                    a_shape[2].to_usize().unwrap(),
-                    b_shape[1].to_usize().unwrap(),
+                    m,
+                    k,
+                    n,
                     1.0,
                     a_data.as_ptr().add(i * a_strides[0].to_usize().unwrap()),
                     a_strides[1].to_usize().unwrap() as isize,
@@ -245,7 +336,7 @@ impl Operator for BatchedMatMul2D {
                     b_strides[1].to_usize().unwrap() as isize,
                     0.0,
                     c.as_mut_ptr().add(i * mat_size),
-                    b_shape[1].to_usize().unwrap() as isize,
+                    row as isize,
                     1,
                 );
             }
@@ -253,6 +344,118 @@ impl Operator for BatchedMatMul2D {
 
         vec![Tensor::new(c)]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// Fold a constant concatenated onto the end of a matmul's output rows into the matmul, so it writes the padding
+/// itself instead of a separate kernel padding it.
+///
+/// This matches `matmul.concat_along::<_, Axis<LAST>, _>(cx.constant(value).expand())`, as used to round attention
+/// scores up to a multiple of a tile with -inf columns that softmax then ignores.
+#[derive(Debug, Default)]
+pub struct MatMulOutputPaddingCompiler;
+
+impl Compiler for MatMulOutputPaddingCompiler {
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut remap: T) {
+        for add in graph.graph.node_indices().collect_vec() {
+            if !graph.graph.node_weight(add).unwrap().as_any().is::<Add>() {
+                continue;
+            }
+            let srcs = graph.get_sources(add);
+            let (Some((matmul, _, matmul_shape)), Some((constant, _, constant_shape))) = (
+                srcs.iter().find(|(n, _, _)| {
+                    let op = graph.graph.node_weight(*n).unwrap().as_any();
+                    op.downcast_ref::<MatMul2D>()
+                        .map(|m| m.output_padding.is_none())
+                        .or(op
+                            .downcast_ref::<BatchedMatMul2D>()
+                            .map(|m| m.output_padding.is_none()))
+                        .unwrap_or_default()
+                }),
+                srcs.iter().find(|(n, _, _)| {
+                    graph
+                        .graph
+                        .node_weight(*n)
+                        .unwrap()
+                        .as_any()
+                        .is::<Constant>()
+                }),
+            ) else {
+                continue;
+            };
+            let (matmul, constant) = (*matmul, *constant);
+            let Some(Constant(ConstantValue::Float(value), dyn_map)) = graph
+                .graph
+                .node_weight(constant)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Constant>()
+            else {
+                continue;
+            };
+            let (value, dyn_map) = (*value, *dyn_map);
+            // The matmul output must be read contiguously with only the last dimension padded on the right, and the
+            // constant must be expanded over exactly that padding
+            let last = matmul_shape.len() - 1;
+            let amount = matmul_shape.padding[matmul_shape.indexes[last]].1;
+            if !matmul_shape.is_contiguous()
+                || matmul_shape.is_sliced()
+                || matmul_shape.padding.iter().enumerate().any(|(i, p)| {
+                    p.0 != 0.into() || (i != matmul_shape.indexes[last] && p.1 != 0.into())
+                })
+                || amount == 0.into()
+                || !constant_shape.fake.iter().all(|f| *f)
+                || constant_shape.is_sliced()
+                || constant_shape.dims[constant_shape.indexes[last]] != amount
+                || constant_shape.padding[constant_shape.indexes[last]]
+                    != (matmul_shape.dims[matmul_shape.indexes[last]], 0.into())
+                || constant_shape
+                    .padding
+                    .iter()
+                    .enumerate()
+                    .any(|(i, p)| i != constant_shape.indexes[last] && *p != (0.into(), 0.into()))
+            {
+                continue;
+            }
+            // The padded output replaces the matmul's own output, so nothing else can read it
+            if graph.no_delete.contains(&matmul)
+                || graph
+                    .graph
+                    .edges_directed(matmul, petgraph::Direction::Outgoing)
+                    .filter(|e| !e.weight().is_schedule())
+                    .count()
+                    != 1
+            {
+                continue;
+            }
+
+            let padding = Some(OutputPadding {
+                amount,
+                value,
+                dyn_map,
+            });
+            let op = graph.graph.node_weight_mut(matmul).unwrap().as_any_mut();
+            if let Some(m) = op.downcast_mut::<MatMul2D>() {
+                m.output_padding = padding;
+            } else if let Some(m) = op.downcast_mut::<BatchedMatMul2D>() {
+                m.output_padding = padding;
+            }
+            move_outgoing_edge(add, matmul, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                add,
+                matmul,
+            );
+            graph.graph.remove_node(add);
+            graph.safe_remove_node(constant, 0);
+            graph.record_rewrite("MatMulOutputPadding");
+        }
+    }
 }
 
 /// Apply multiple unary ops in sequence, without having to reindex / rewrite to memory between each
@@ -332,6 +535,7 @@ impl Compiler for UnaryFusionCompiler {
                         id,
                     );
                     graph.graph.remove_node(outgoing_target);
+                    graph.record_rewrite("FusedUnary");
                 }
             }
         }
@@ -359,13 +563,17 @@ impl Operator for FusedUnary {
 
         vec![t]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use rand::{rngs::StdRng, SeedableRng};
 
-    use crate::prelude::*;
+    use crate::prelude::{Module, *};
     crate::test_imports!();
 
     #[test]
@@ -416,4 +624,297 @@ mod tests {
         cx.execute();
         assert_close(&c.data(), &unoptimized_c);
     }
+
+    #[test]
+    fn test_matmul_sliced_input() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 2>>().set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = cx.tensor::<R2<2, 2>>().set(vec![1., 0., 1., 1.]);
+        // The rows after the first, which sgemm can't read through strides alone
+        let mut c = a
+            .slice((Expression::from(1).., ..))
+            .realize::<R2<2, 2>>()
+            .matmul(b)
+            .retrieve();
+        cx.compile(CPUCompiler::default(), &mut c);
+        cx.execute();
+        assert_exact(&c.data(), &[7., 4., 11., 6.]);
+    }
+
+    #[test]
+    fn test_matmul_degenerate_dims() {
+        // (batch, m, k, n), with an empty inner dimension giving zeros and other empty dimensions giving empty outputs
+        for (batch, m, k, n) in [
+            (1, 2, 0, 3),
+            (1, 0, 2, 3),
+            (1, 2, 3, 0),
+            (2, 2, 0, 3),
+            (0, 2, 2, 3),
+        ] {
+            for compile in [false, true] {
+                let mut cx = Graph::new();
+                let a = cx.tensor::<(Dyn<'M'>, Dyn<'K'>)>();
+                let b = cx.tensor::<(Dyn<'K'>, Dyn<'N'>)>();
+                let batched_a = cx.tensor::<(Dyn<'B'>, Dyn<'M'>, Dyn<'K'>)>();
+                let mut c = a.matmul(b).retrieve();
+                let mut batched_c = batched_a.matmul(b).retrieve();
+                if compile {
+                    cx.compile(CPUCompiler::default(), (&mut c, &mut batched_c));
+                }
+                a.set_dyn(vec![1.; m * k], &[m, k]);
+                b.set_dyn(vec![1.; k * n], &[k, n]);
+                batched_a.set_dyn(vec![1.; batch * m * k], &[batch, m, k]);
+                cx.execute();
+
+                // Each output sums k ones
+                assert_exact(&c.data(), &vec![k as f32; m * n]);
+                assert_exact(&batched_c.data(), &vec![k as f32; batch * m * n]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_matmul_no_fuse_hint() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let batched_a = cx.tensor::<R3<2, 2, 3>>().set(random_vec(12));
+        let mut c = a.matmul(b).hint(CompilerHint::NoFuse).retrieve();
+        let mut batched_c = batched_a.matmul(b).hint(CompilerHint::NoFuse).retrieve();
+        let mut fused_c = a.matmul(b).retrieve();
+        cx.execute();
+        let (unfused, batched_unfused) = (c.data(), batched_c.data());
+        c.drop();
+        batched_c.drop();
+        fused_c.drop();
+
+        cx.compile(
+            CPUCompiler::default(),
+            (&mut c, &mut batched_c, &mut fused_c),
+        );
+        // Only the unhinted matmul is rewritten
+        let n_matmuls = |cx: &Graph| {
+            cx.graph
+                .node_weights()
+                .filter(|op| op.as_any().is::<MatMul2D>() || op.as_any().is::<BatchedMatMul2D>())
+                .count()
+        };
+        assert_eq!(n_matmuls(&cx), 1);
+        cx.execute();
+        assert_close(&c.data(), &unfused);
+        assert_close(&batched_c.data(), &batched_unfused);
+    }
+
+    #[test]
+    fn test_int_token_embedding() {
+        let mut cx = Graph::new();
+        let weights = random_vec(10 * 4);
+        let embed = <crate::nn::embedding::Embedding<10, 4>>::initialize(&mut cx);
+        embed.weight.set(weights.clone());
+        let ids = cx.named_tensor_i32::<(Dyn<'s'>,)>("Token Ids");
+        let mut out = (embed.forward(ids) * 2.).retrieve();
+
+        // The ids only feed the gather once it's been fused
+        assert!(cx.check_int_inputs().is_err());
+        cx.compile(CPUCompiler::default(), &mut out);
+        assert!(cx.check_int_inputs().is_ok());
+
+        let tokens = [3u32, 0, 9, 3, 7];
+        ids.set_int_dyn(&tokens, &[tokens.len()]);
+        cx.execute();
+        let expected = tokens
+            .iter()
+            .flat_map(|t| {
+                weights[*t as usize * 4..(*t as usize + 1) * 4]
+                    .iter()
+                    .map(|w| w * 2.)
+            })
+            .collect::<Vec<_>>();
+        assert_exact(&out.data(), &expected);
+    }
+
+    /// Softmax of each row of `scores`, padded with zeros up to `padded` columns
+    fn padded_softmax(scores: &[f32], n: usize, padded: usize) -> Vec<f32> {
+        scores
+            .chunks(n)
+            .flat_map(|row| {
+                let max = row.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                let sum = row.iter().map(|s| (s - max).exp()).sum::<f32>();
+                row.iter()
+                    .map(move |s| (s - max).exp() / sum)
+                    .chain(vec![0.; padded - n])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matmul_output_padding() {
+        use crate::op::Add;
+
+        // A context one past a 32 wide tile, padded up to the next tile with -inf scores
+        const TILE: usize = 32;
+        const CTX: usize = TILE + 1;
+        const PADDED: usize = 2 * TILE;
+        let (q_data, k_data, batched_q_data) = (
+            random_vec(2 * 8),
+            random_vec(CTX * 8),
+            random_vec(3 * 2 * 8),
+        );
+        let mut cx = Graph::new();
+        let q = cx.tensor::<R2<2, 8>>().set(q_data.clone());
+        let k = cx.tensor::<R2<CTX, 8>>().set(k_data.clone());
+        let batched_q = cx.tensor::<R3<3, 2, 8>>().set(batched_q_data.clone());
+        let pad = |scores: GraphTensor<R2<2, CTX>>| {
+            scores.concat_along::<R2<2, PADDED>, LAxis<1>, _>(
+                scores
+                    .graph()
+                    .constant(f32::NEG_INFINITY)
+                    .expand::<R2<2, { PADDED - CTX }>, _>(),
+            )
+        };
+        let mut weights = pad(q.matmul(k.permute())).softmax::<1>().retrieve();
+        let batched_scores = batched_q.matmul(k.permute());
+        let mut batched_weights = batched_scores
+            .concat_along::<R3<3, 2, PADDED>, LAxis<2>, _>(
+                cx.constant(f32::NEG_INFINITY)
+                    .expand::<R3<3, 2, { PADDED - CTX }>, _>(),
+            )
+            .softmax::<2>()
+            .retrieve();
+        cx.execute();
+        let (unfused, batched_unfused) = (weights.data(), batched_weights.data());
+        weights.drop();
+        batched_weights.drop();
+
+        cx.compile(CPUCompiler::default(), (&mut weights, &mut batched_weights));
+        // Both matmuls write their padding themselves
+        assert!(!cx.graph.node_weights().any(|op| op.as_any().is::<Add>()));
+        assert!(cx.graph.node_weights().any(|op| op
+            .as_any()
+            .downcast_ref::<MatMul2D>()
+            .map(|m| m.output_padding.is_some())
+            .unwrap_or_default()));
+        assert!(cx.graph.node_weights().any(|op| op
+            .as_any()
+            .downcast_ref::<BatchedMatMul2D>()
+            .map(|m| m.output_padding.is_some())
+            .unwrap_or_default()));
+        cx.execute();
+
+        // The padded tail is masked out of the softmax
+        let scores = |q_data: &[f32]| {
+            q_data
+                .chunks(8)
+                .flat_map(|q| {
+                    k_data
+                        .chunks(8)
+                        .map(|k| q.iter().zip(k).map(|(a, b)| a * b).sum::<f32>())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = padded_softmax(&scores(&q_data), CTX, PADDED);
+        assert_close(&unfused, &expected);
+        assert_close(&weights.data(), &expected);
+        let batched_expected = padded_softmax(&scores(&batched_q_data), CTX, PADDED);
+        assert_close(&batched_unfused, &batched_expected);
+        assert_close(&batched_weights.data(), &batched_expected);
+    }
+
+    #[test]
+    fn test_golden_matmul_2d() {
+        use crate::tests::golden::*;
+
+        let mut g = GraphBuilder::new();
+        let a = g.input("A", &[2, 3]);
+        let b = g.input("B", &[3, 4]);
+        let c = g.matmul_pattern(a, b);
+        g.retrieve(c);
+        // A batched matmul isn't matched by the 2D pattern
+        let batched_a = g.input("Batched A", &[2, 2, 3]);
+        let batched_c = g.batch_matmul_pattern(batched_a, b);
+        g.retrieve(batched_c);
+        assert_compiles_to(
+            &mut g,
+            MatMul2DCompiler,
+            &[
+                "A Load",
+                "B Load",
+                "Batched A Load",
+                "Mul",
+                "SumReduce",
+                "MatMul2D",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_golden_batch_matmul_2d() {
+        use crate::tests::golden::*;
+
+        let mut g = GraphBuilder::new();
+        let a = g.input("A", &[2, 2, 3]);
+        let b = g.input("B", &[3, 4]);
+        let c = g.batch_matmul_pattern(a, b);
+        g.retrieve(c);
+        g.graph.execute();
+        let unfused = g.outputs[0].data();
+        g.outputs[0].drop();
+
+        assert_compiles_to(
+            &mut g,
+            MatMulCompiler::default(),
+            &["A Load", "B Load", "BatchedMatMul2D"],
+        );
+        g.graph.execute();
+        assert_close(&g.outputs[0].data(), &unfused);
+    }
+
+    #[test]
+    fn test_golden_retrieved_mul_not_fused() {
+        use crate::{
+            op::{Mul, SumReduce, Summation},
+            tests::golden::*,
+        };
+
+        // The mul is needed on its own, so the matmul can't replace it
+        let mut g = GraphBuilder::new();
+        let a = g.input("A", &[2, 3]);
+        let b = g.input("B", &[3, 4]);
+        let a = g.expand(a, 1, 4);
+        let b = g.permute(b, &[1, 0]);
+        let b = g.expand(b, 0, 2);
+        let mul = g.op(Mul, &[a, b]);
+        let c = g.reduce(SumReduce(2, Summation::Naive), mul, 2);
+        g.retrieve(mul);
+        g.retrieve(c);
+        assert_compiles_to(
+            &mut g,
+            MatMulCompiler::default(),
+            &["A Load", "B Load", "Mul", "SumReduce"],
+        );
+    }
+
+    #[test]
+    fn test_golden_subtraction() {
+        use crate::{
+            op::{Add, Exp2, Mul},
+            tests::golden::*,
+        };
+
+        let mut g = GraphBuilder::new();
+        let a = g.input("A", &[2, 3]);
+        let b = g.input("B", &[2, 3]);
+        let neg_one = g.constant(-1., &[2, 3]);
+        let neg_b = g.op(Mul, &[b, neg_one]);
+        let c = g.op(Add, &[a, neg_b]);
+        // A retrieved add can't be replaced, so the subtraction needs to feed another op
+        let d = g.op(Exp2, &[c]);
+        g.retrieve(d);
+        assert_compiles_to(
+            &mut g,
+            super::binary::SubtractionCompiler,
+            &["A Load", "B Load", "Sub", "Exp2"],
+        );
+    }
 }
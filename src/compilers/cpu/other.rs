@@ -1,8 +1,5 @@
-use crate::{
-    op::*,
-    prelude::{petgraph::visit::EdgeRef, *},
-    shape::symbolic::BigExpression,
-};
+use crate::{compiler_internals::*, op::*, prelude::*, shape::symbolic::BigExpression};
+use petgraph::visit::EdgeRef;
 use rustc_hash::FxHashMap;
 
 #[derive(LuminalPrint, Clone, LuminalEqFalse)]
@@ -21,6 +18,10 @@ impl Operator for ARange {
             data: Box::new((0..n_elements).map(|i| i as f32).collect::<Vec<_>>()),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(LuminalPrint, Default)]
@@ -141,6 +142,7 @@ impl Compiler for ARangeCompiler {
             graph.safe_remove_node(contig2, 0);
             graph.safe_remove_node(contig1, 0);
             graph.safe_remove_node(one_const, 0);
+            graph.record_rewrite("ARange");
             s1.clear_cached_results();
             s2.clear_cached_results();
         }
@@ -11,9 +11,10 @@ use petgraph::{
 };
 
 use crate::{
+    compiler_internals::*,
     op::{
-        Add, Constant, ConstantValue, Exp2, Function, Log2, MaxReduce, Mul, Operator, Recip,
-        SumReduce,
+        Add, Constant, ConstantValue, Contiguous, Exp2, Function, LessThan, Log2, MaxReduce, Mod,
+        Mul, Operator, Recip, Sin, Sqrt, SumReduce, Summation,
     },
     prelude::*,
 };
@@ -52,6 +53,12 @@ impl Compiler for UnarySequentialElimination {
         {
             let mut searcher = selector_graph.search(graph);
             while searcher.next_match() {
+                // The reversed search matches the pair the other way around
+                let (first, last) = if graph.graph.contains_edge(first, last) {
+                    (first, last)
+                } else {
+                    (last, first)
+                };
                 if graph.no_delete.contains(&first)
                     || graph
                         .graph
@@ -80,13 +87,14 @@ impl Compiler for UnarySequentialElimination {
                 );
                 graph.graph.remove_node(first);
                 graph.graph.remove_node(last);
+                graph.record_rewrite("InverseUnaryPair");
             }
         }
     }
 }
 
 /// [Common subexpression elimination](https://en.wikipedia.org/wiki/Common_subexpression_elimination)
-#[derive(Default)]
+#[derive(Debug, Default)]
 pub struct CSE;
 
 impl Compiler for CSE {
@@ -150,6 +158,7 @@ impl Compiler for CSE {
                         );
                         // Remove node
                         graph.graph.remove_node(node);
+                        graph.record_rewrite("CommonSubexpression");
                         eliminated = true;
                         break;
                     }
@@ -169,7 +178,7 @@ impl Compiler for CSE {
 }
 
 /// Remove maxreduces and sumreduces that don't do anything
-#[derive(Default)]
+#[derive(Debug, Default)]
 pub struct RemoveSingleReductions;
 
 impl Compiler for RemoveSingleReductions {
@@ -201,10 +210,14 @@ impl Compiler for RemoveSingleReductions {
                         e.weight()
                             .as_data()
                             .map(|w| {
-                                w.2.dims[w.2.indexes[dim]]
-                                    .to_usize()
-                                    .map(|i| i == 1)
-                                    .unwrap_or_default()
+                                // Consumers read the reduce output as contiguous, so the input has to be too
+                                w.2.is_contiguous()
+                                    && !w.2.is_sliced()
+                                    && !w.2.is_padded()
+                                    && w.2.dims[w.2.indexes[dim]]
+                                        .to_usize()
+                                        .map(|i| i == 1)
+                                        .unwrap_or_default()
                             })
                             .unwrap_or_default()
                     })
@@ -224,12 +237,45 @@ impl Compiler for RemoveSingleReductions {
                     );
                     move_outgoing_edge(node, upstream, &mut graph.graph);
                     graph.graph.remove_node(node);
+                    graph.record_rewrite("SingleReduction");
                 }
             }
         }
     }
 }
 
+/// Use [compensated summation](Summation::Compensated) in every sum reduction, for long reductions where naive
+/// summation rounds small values away. Run it before backend compilers, since sums fused into other ops (like CPU
+/// matmuls) add up naively.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let mut values = vec![1e-8; 1_000_001];
+/// values[0] = 1.;
+/// let a = cx.tensor::<R1<1_000_001>>().set(values);
+/// let mut sum = a.sum_reduce::<R0, Axis<0>>().retrieve();
+/// cx.execute();
+/// // Each 1e-8 is rounded away when added to 1
+/// assert_eq!(sum.data(), vec![1.]);
+///
+/// cx.compile(CompensatedSummation, &mut sum);
+/// sum.drop();
+/// cx.execute();
+/// assert!((sum.data()[0] - 1.01).abs() < 1e-4);
+/// ```
+#[derive(Debug, Default)]
+pub struct CompensatedSummation;
+
+impl Compiler for CompensatedSummation {
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        for op in graph.graph.node_weights_mut() {
+            if let Some(SumReduce(_, summation)) = op.as_any_mut().downcast_mut() {
+                *summation = Summation::Compensated;
+            }
+        }
+    }
+}
+
 /// Remove unused nodes
 #[derive(Default)]
 pub struct RemoveUnusedNodes;
@@ -367,7 +413,7 @@ fn is_from_set(node: NodeIndex, graph: &Graph, set: &HashSet<NodeIndex>) -> bool
 
 #[cfg(test)]
 mod tests {
-    use crate::prelude::*;
+    use crate::{compilers::LayoutPropagation, op, prelude::*};
     #[test]
     fn test_log_exp() {
         let mut cx = Graph::new();
@@ -377,6 +423,63 @@ mod tests {
         cx.compile(GenericCompiler::default(), ());
         assert_eq!(cx.graph.node_count(), 1);
     }
+
+    #[test]
+    fn test_exp_log() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let mut b = a.exp2().log2().sum_reduce::<R0, _>().retrieve();
+
+        cx.compile(GenericCompiler::default(), &mut b);
+        assert_eq!(cx.graph.node_count(), 2);
+        cx.execute();
+        assert_eq!(b.data(), vec![6.]);
+    }
+
+    #[test]
+    fn test_single_reduction_over_view() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<2, 1, 3>>().set(vec![1., 2., 3., 4., 5., 6.]);
+        let mut view = a.shape;
+        view.permute(&[2, 1, 0]);
+        let reduced = cx
+            .add_op(op::SumReduce(1, op::Summation::Naive))
+            .input(a.id, 0, view)
+            .finish();
+        // Read the output as contiguous, so the reduction has to stay to do the transpose
+        let mut b = GraphTensor::<R2<3, 2>>::from_id(
+            reduced,
+            ShapeTracker::new(&[3.into(), 2.into()]),
+            &mut cx,
+        )
+        .retrieve();
+
+        cx.compile(GenericCompiler::default(), &mut b);
+        cx.execute();
+        assert_eq!(b.data(), vec![1., 4., 2., 5., 3., 6.]);
+    }
+
+    #[test]
+    fn test_transpose_pair() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 3., 4., 5., 6.]);
+        let mut b = (a.permute::<_, Axes2<1, 0>>().contiguous().exp2() * 2.)
+            .sin()
+            .permute::<_, Axes2<1, 0>>()
+            .contiguous()
+            .retrieve();
+        let mut c = (a.exp2() * 2.).sin().retrieve();
+        cx.execute();
+        let expected = c.data();
+
+        cx.compile(LayoutPropagation, (&mut b, &mut c));
+        assert!(!cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<crate::op::Contiguous>()));
+        cx.execute();
+        assert_eq!(b.data(), expected);
+    }
 }
 
 /// **Reduces arithmetic expressions**
@@ -480,6 +583,7 @@ impl Compiler for ArithmeticElimination {
                 graph.graph.remove_node(zero);
             }
             graph.graph.remove_node(add);
+            graph.record_rewrite("AddZero");
         }
         // x * 1, 1 * x
         let (mut a, mut mul, mut one) = (
@@ -568,7 +672,180 @@ impl Compiler for ArithmeticElimination {
                 graph.graph.remove_node(one);
             }
             graph.graph.remove_node(mul);
+            graph.record_rewrite("MulOne");
         }
         // graph.display();
     }
 }
+
+/// Sink transposes through elementwise ops until they meet a transpose back, and remove both.
+///
+/// A transpose is a [`Contiguous`] op reading a view that only reorders dimensions. Layout conversions around ops
+/// that want a different layout (like `x.to_channels_last().relu().to_channels_first()` between two convolutions)
+/// leave a transpose out and a transpose back in, with elementwise ops in between. Those ops work in any layout, so
+/// they run in the original layout instead, and neither transpose is needed. This is a greedy pass: transposes are
+/// only moved when every path out of the elementwise ops ends in a transpose undoing them.
+#[derive(Debug, Default)]
+pub struct LayoutPropagation;
+
+impl Compiler for LayoutPropagation {
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut remap: T) {
+        for transpose in graph.graph.node_indices().collect::<Vec<_>>() {
+            if !graph.graph.contains_node(transpose)
+                || !graph
+                    .graph
+                    .node_weight(transpose)
+                    .unwrap()
+                    .as_any()
+                    .is::<Contiguous>()
+                || graph.no_delete.contains(&transpose)
+            {
+                continue;
+            }
+            let Some((source, output, input_shape)) = graph
+                .graph
+                .edges_directed(transpose, Direction::Incoming)
+                .find_map(|e| e.weight().as_data().map(|(_, o, sh)| (e.source(), o, sh)))
+            else {
+                continue;
+            };
+            if output != 0 || !input_shape.is_transpose() {
+                continue;
+            }
+            // Logical dim i of the transpose reads dim axes[i] of the source
+            let axes = input_shape.indexes.to_vec();
+            let mut inverse = vec![0; axes.len()];
+            for (i, a) in axes.iter().enumerate() {
+                inverse[*a] = i;
+            }
+            let transposed = input_shape.contiguous();
+            let original = ShapeTracker::new(&input_shape.dims);
+
+            let Some((region, exits)) = elementwise_region(graph, transpose, &transposed, &axes)
+            else {
+                continue;
+            };
+
+            // Run the region in the original layout
+            for node in &region {
+                for (edge, src, (input_order, output_order, mut shape)) in graph
+                    .graph
+                    .edges_directed(*node, Direction::Incoming)
+                    .filter_map(|e| e.weight().as_data().map(|w| (e.id(), e.source(), w)))
+                    .collect::<Vec<_>>()
+                {
+                    if src == transpose {
+                        graph.graph.remove_edge(edge);
+                        graph.graph.add_edge(
+                            source,
+                            *node,
+                            Dependency::Data {
+                                input_order,
+                                output_order,
+                                shape: original,
+                            },
+                        );
+                        continue;
+                    }
+                    if region.contains(&src) {
+                        shape = original;
+                    } else {
+                        shape.permute(&inverse);
+                    }
+                    *graph.graph.edge_weight_mut(edge).unwrap() = Dependency::Data {
+                        input_order,
+                        output_order,
+                        shape,
+                    };
+                }
+            }
+            // The transposes back now copy a tensor already in the original layout
+            for (producer, exit) in exits {
+                let producer = if producer == transpose {
+                    source
+                } else {
+                    producer
+                };
+                move_outgoing_edge(exit, producer, &mut graph.graph);
+                move_references(
+                    &mut remap,
+                    &mut graph.no_delete,
+                    &mut graph.to_retrieve,
+                    exit,
+                    producer,
+                );
+                graph.graph.remove_node(exit);
+            }
+            graph.graph.remove_node(transpose);
+            graph.record_rewrite("TransposePair");
+        }
+    }
+}
+
+/// Elementwise ops, and the transposes back out of them paired with the nodes they read
+type TransposedRegion = (Vec<NodeIndex>, Vec<(NodeIndex, NodeIndex)>);
+
+/// The elementwise ops downstream of a transpose, along with the transposes back (and the nodes they read) every path
+/// out of them ends in. None if some path ends elsewhere, or an op can't change layout.
+fn elementwise_region(
+    graph: &Graph,
+    transpose: NodeIndex,
+    transposed: &ShapeTracker,
+    axes: &[usize],
+) -> Option<TransposedRegion> {
+    let elementwise = [
+        TypeId::of::<Log2>(),
+        TypeId::of::<Exp2>(),
+        TypeId::of::<Sin>(),
+        TypeId::of::<Sqrt>(),
+        TypeId::of::<Recip>(),
+        TypeId::of::<Add>(),
+        TypeId::of::<Mul>(),
+        TypeId::of::<Mod>(),
+        TypeId::of::<LessThan>(),
+    ];
+    let mut region = vec![];
+    let mut exits = vec![];
+    let mut stack = vec![transpose];
+    let mut seen = HashSet::new();
+    while let Some(node) = stack.pop() {
+        if !seen.insert(node) {
+            continue;
+        }
+        for edge in graph.graph.edges_directed(node, Direction::Outgoing) {
+            let (_, _, shape) = edge.weight().as_data()?;
+            let target = edge.target();
+            let op = graph.graph.node_weight(target).unwrap().as_any();
+            if op.is::<Contiguous>()
+                && shape.is_transpose()
+                && shape.dims == transposed.dims
+                && shape.indexes.iter().enumerate().all(|(i, a)| axes[*a] == i)
+            {
+                exits.push((node, target));
+            } else if elementwise.contains(&op.type_id())
+                && shape == *transposed
+                && !graph.no_delete.contains(&target)
+            {
+                if !region.contains(&target) {
+                    region.push(target);
+                }
+                stack.push(target);
+            } else {
+                return None;
+            }
+        }
+    }
+    // Edges between the ops in the region have to be plain reads, so they can read the original layout instead
+    for node in &region {
+        for edge in graph.graph.edges_directed(*node, Direction::Incoming) {
+            if let Some((_, _, shape)) = edge.weight().as_data() {
+                if (edge.source() == transpose || region.contains(&edge.source()))
+                    && shape != *transposed
+                {
+                    return None;
+                }
+            }
+        }
+    }
+    Some((region, exits))
+}
@@ -0,0 +1,270 @@
+use std::cell::Cell;
+
+use petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction};
+use rustc_hash::FxHashSet;
+
+use crate::graph::{ExecutionError, ExecutionState, Graph};
+
+thread_local! {
+    static DEFERRED_SYNC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether device ops on this thread may return as soon as their work is enqueued, without waiting for it to finish.
+///
+/// This is on while [`Graph::execute_async`] runs the graph. Backends that defer work need to wait for it before the
+/// host reads device memory, and ops that do (like copies back to the host) answer `custom("synchronizes", ..)`.
+pub fn deferred_sync() -> bool {
+    DEFERRED_SYNC.with(|d| d.get())
+}
+
+/// The ops of an execution that wait for the device, left for [`Graph::finish_execution`]
+#[derive(Debug, Default)]
+pub(crate) struct PendingExecution {
+    /// Positions in the linearized graph, in execution order
+    nodes: Vec<usize>,
+    state: ExecutionState,
+}
+
+/// An execution started by [`Graph::execute_async`]. Its device work may still be running until [`wait`] is called,
+/// or the data of a retrieved tensor is read. The handle borrows the graph, so dropping it leaves the execution
+/// pending until [`Graph::finish_execution`] or the next execution.
+///
+/// [`wait`]: ExecutionHandle::wait
+#[must_use = "the execution only finishes once it's waited on or a retrieved tensor is read"]
+pub struct ExecutionHandle<'a> {
+    graph: &'a mut Graph,
+}
+
+impl ExecutionHandle<'_> {
+    /// Wait for the device and run the rest of the graph, so the retrieved tensors are ready
+    pub fn wait(self) {
+        self.graph.finish_execution();
+    }
+
+    /// Wait for the device and run the rest of the graph, stopping if an op fails or the tensors go over the memory
+    /// limit
+    pub fn try_wait(self) -> Result<(), ExecutionError> {
+        self.graph.try_finish_execution()
+    }
+
+    /// Whether the execution still has ops left to run
+    pub fn is_pending(&self) -> bool {
+        self.graph.pending_execution.is_some()
+    }
+}
+
+impl Graph {
+    /// Execute the graph without waiting for device work at the end, so the host can do other work (like preparing
+    /// the next inputs) while it runs.
+    ///
+    /// Ops that wait for the device and only lead to retrieved outputs, like copies of the outputs back to the host,
+    /// are deferred until [`ExecutionHandle::wait`], [`GraphTensor::data`](crate::graph_tensor::GraphTensor::data) or
+    /// the next execution. The same ops in the middle of the graph, like copies feeding a CPU op, still wait where
+    /// they are.
+    pub fn execute_async(&mut self) -> ExecutionHandle<'_> {
+        match self.try_execute_async() {
+            Ok(handle) => handle,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Execute the graph like [`Graph::execute_async`], stopping if an op fails, the tensors go over the memory
+    /// limit, or the graph was specialized and a dyn dim is set to a different size. Errors from the deferred ops
+    /// come back from [`ExecutionHandle::try_wait`] or [`Graph::try_finish_execution`].
+    pub fn try_execute_async(&mut self) -> Result<ExecutionHandle<'_>, ExecutionError> {
+        let mut state = self.start_execution(false)?;
+        let order = self.linearized_graph.clone().unwrap();
+        let deferred = self.deferrable_nodes();
+        let (eager, deferred): (Vec<_>, Vec<_>) =
+            (0..order.len()).partition(|i| !deferred.contains(&order[*i].0));
+
+        DEFERRED_SYNC.with(|d| d.set(true));
+        let result = self.run_nodes(&eager, &mut state);
+        DEFERRED_SYNC.with(|d| d.set(false));
+        if let Err(e) = result {
+            self.end_execution();
+            return Err(e);
+        }
+
+        self.pending_execution = Some(PendingExecution {
+            nodes: deferred,
+            state,
+        });
+        Ok(ExecutionHandle { graph: self })
+    }
+
+    /// Finish an execution started by [`Graph::execute_async`]. Does nothing if there isn't one.
+    pub fn finish_execution(&mut self) {
+        if let Err(e) = self.try_finish_execution() {
+            panic!("{e}");
+        }
+    }
+
+    /// Finish an execution started by [`Graph::execute_async`], stopping if an op fails or the tensors go over the
+    /// memory limit. Does nothing if there isn't one.
+    pub fn try_finish_execution(&mut self) -> Result<(), ExecutionError> {
+        let Some(PendingExecution { nodes, mut state }) = self.pending_execution.take() else {
+            return Ok(());
+        };
+        let result = self.run_nodes(&nodes, &mut state);
+        self.end_execution();
+        result
+    }
+
+    /// Ops that wait for the device, and the ops that only depend on them, which nothing else needs
+    fn deferrable_nodes(&mut self) -> FxHashSet<NodeIndex> {
+        let order = self
+            .linearized_graph
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|(node, _)| *node)
+            .collect::<Vec<_>>();
+        let mut host_side = FxHashSet::default();
+        for node in &order {
+            let sources = self
+                .graph
+                .edges_directed(*node, Direction::Incoming)
+                .filter(|e| !e.weight().is_schedule())
+                .map(|e| e.source())
+                .collect::<Vec<_>>();
+            let synchronizes = self
+                .graph
+                .node_weight_mut(*node)
+                .unwrap()
+                .custom("synchronizes", Box::new(()))
+                .is_some();
+            if synchronizes
+                || (!sources.is_empty() && sources.iter().all(|s| host_side.contains(s)))
+            {
+                host_side.insert(*node);
+            }
+        }
+        // Anything feeding ops that run now has to run now too
+        let mut deferred = FxHashSet::default();
+        for node in order.iter().rev() {
+            if host_side.contains(node)
+                && self
+                    .graph
+                    .edges_directed(*node, Direction::Outgoing)
+                    .all(|e| deferred.contains(&e.target()))
+            {
+                deferred.insert(*node);
+            }
+        }
+        deferred
+    }
+
+    /// Run these positions of the linearized graph in order
+    fn run_nodes(
+        &mut self,
+        nodes: &[usize],
+        state: &mut ExecutionState,
+    ) -> Result<(), ExecutionError> {
+        let order = self.linearized_graph.clone().unwrap();
+        for i in nodes {
+            let (node, src_ids) = &order[*i];
+            self.execute_node(*node, src_ids, state, |op, srcs| op.try_process(srcs))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+
+    use crate::{
+        op::{InputTensor, Operator},
+        prelude::*,
+        tests::assert_close,
+    };
+
+    /// Copies its input, like a copy back from a device
+    #[derive(Debug, Clone, PartialEq)]
+    struct DeviceCopy;
+
+    impl Operator for DeviceCopy {
+        fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            vec![inp.pop().unwrap().0.cloned()]
+        }
+        fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+            if key == "synchronizes" {
+                return Some(Box::new(()));
+            }
+            None
+        }
+    }
+
+    fn copy<S: Shape>(t: GraphTensor<S>) -> GraphTensor<S> {
+        let id = t
+            .graph()
+            .add_op(DeviceCopy)
+            .input(t.id, 0, t.shape)
+            .finish();
+        GraphTensor::from_id(id, t.shape.contiguous(), t.graph_ref)
+    }
+
+    #[test]
+    fn test_execute_async() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        // A copy in the middle of the graph, and ones that only lead to outputs
+        let middle = copy(a * 2.);
+        let b = copy(middle.exp() + 1.).retrieve();
+        let c = copy(a.sin() * 3.).retrieve();
+
+        let handle = cx.execute_async();
+        assert!(handle.is_pending());
+        drop(handle);
+        // The ops after the last device work are left for later
+        assert!(cx.get_tensor_ref(b.id, 0).is_none());
+        assert!(cx.get_tensor_ref(c.id, 0).is_none());
+        cx.finish_execution();
+        assert_close(
+            &b.data(),
+            &[2f32.exp() + 1., 4f32.exp() + 1., 6f32.exp() + 1.],
+        );
+
+        // Waiting on the handle finishes it
+        b.drop();
+        cx.execute_async().wait();
+        assert!(cx.pending_execution.is_none());
+        assert!(cx.get_tensor_ref(b.id, 0).is_some());
+
+        // Reading the data finishes the execution too
+        b.drop();
+        c.drop();
+        drop(cx.execute_async());
+        assert_close(
+            &c.data(),
+            &[1f32.sin() * 3., 2f32.sin() * 3., 3f32.sin() * 3.],
+        );
+        assert!(cx.pending_execution.is_none());
+    }
+
+    #[test]
+    fn test_try_execute_async() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<256>>().set(vec![1.; 256]);
+        let b = copy(a.exp()).retrieve();
+
+        // Memory limits apply
+        cx.set_memory_limit(256);
+        assert!(matches!(
+            cx.try_execute_async(),
+            Err(ExecutionError::OutOfMemory(_))
+        ));
+        assert!(cx.pending_execution.is_none());
+        assert!(cx.tensors.is_empty());
+        cx.clear_memory_limit();
+
+        // Shapes are recorded for the deferred ops too
+        cx.record_shapes();
+        cx.try_execute_async().unwrap().try_wait().unwrap();
+        assert_close(&b.data(), &[1f32.exp(); 256]);
+        let record = cx.take_shape_record().unwrap();
+        assert_eq!(record.runs, 1);
+        assert!(record.node_shapes.contains_key(&b.id));
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    fmt::Display,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use petgraph::stable_graph::NodeIndex;
+use rustc_hash::FxHashMap;
+use safetensors::Dtype;
+
+use crate::{
+    compile_stats::CompileReport,
+    compiler_utils::ToIdsMut,
+    compilers::{CPUCompiler, GenericCompiler},
+    graph::Graph,
+    op,
+};
+
+/// A compiler stack [`Graph::auto_compile`] can pick
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pipeline {
+    Cpu,
+    MetalF16,
+    MetalF32,
+    CudaF16,
+    CudaF32,
+}
+
+/// The hardware [`Graph::auto_compile`] chooses a pipeline for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Platform {
+    /// A Metal device is available
+    pub metal: bool,
+    /// The Metal device is Apple Silicon, which runs fp16 at full speed
+    pub apple_silicon: bool,
+    /// An Nvidia GPU is available
+    pub cuda: bool,
+}
+
+impl Platform {
+    /// The platform this process runs on. Metal is assumed on every Mac, and CUDA wherever the Nvidia driver is
+    /// installed.
+    pub fn detect() -> Self {
+        Self {
+            metal: cfg!(target_os = "macos"),
+            apple_silicon: cfg!(all(target_os = "macos", target_arch = "aarch64")),
+            cuda: Path::new("/proc/driver/nvidia/version").exists()
+                || (cfg!(windows) && Path::new(r"C:\Windows\System32\nvcuda.dll").exists()),
+        }
+    }
+}
+
+/// The pipeline [`Graph::auto_compile`] picked, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineChoice {
+    pub pipeline: Pipeline,
+    pub reason: String,
+}
+
+impl Display for PipelineChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Compiling for {:?}: {}", self.pipeline, self.reason)
+    }
+}
+
+/// Compiles a graph with a backend's compiler stack, remapping the given ids
+pub type PipelineCompiler = fn(&mut Graph, Vec<&mut NodeIndex>) -> CompileReport;
+
+/// Compiler stacks registered by backend crates
+static PIPELINES: OnceLock<Mutex<FxHashMap<Pipeline, PipelineCompiler>>> = OnceLock::new();
+
+/// Make a backend's compiler stack available to [`Graph::auto_compile`]. Backend crates do this in their
+/// `register_auto_compile` function, since the core crate can't depend on them.
+pub fn register_pipeline(pipeline: Pipeline, compile: PipelineCompiler) {
+    PIPELINES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .insert(pipeline, compile);
+}
+
+fn registered_pipeline(pipeline: Pipeline) -> Option<PipelineCompiler> {
+    if pipeline == Pipeline::Cpu {
+        let cpu: PipelineCompiler =
+            |graph, remap| graph.compile(<(GenericCompiler, CPUCompiler)>::default(), remap);
+        return Some(cpu);
+    }
+    PIPELINES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .get(&pipeline)
+        .copied()
+}
+
+impl Graph {
+    /// Compile the graph with the compiler stack that best fits the platform it runs on, its ops and its weights.
+    /// Pass a pipeline to use it instead. Backends need to be registered first (like with
+    /// `luminal_metal::register_auto_compile()`), otherwise the graph is compiled for the CPU.
+    pub fn auto_compile<T: ToIdsMut>(
+        &mut self,
+        mut outputs: T,
+        pipeline: Option<Pipeline>,
+    ) -> (PipelineChoice, CompileReport) {
+        let mut choice = match pipeline {
+            Some(pipeline) => PipelineChoice {
+                pipeline,
+                reason: "requested".to_string(),
+            },
+            None => self.choose_pipeline(&Platform::detect()),
+        };
+        let compile = registered_pipeline(choice.pipeline).unwrap_or_else(|| {
+            choice = PipelineChoice {
+                pipeline: Pipeline::Cpu,
+                reason: format!(
+                    "{:?} was chosen ({}), but its backend isn't registered",
+                    choice.pipeline, choice.reason
+                ),
+            };
+            registered_pipeline(Pipeline::Cpu).unwrap()
+        });
+        let report = compile(self, outputs.to_ids_mut());
+        (choice, report)
+    }
+
+    /// Pick the compiler stack for this graph on a platform. This only looks at the graph and the platform, so the
+    /// same graph always gets the same pipeline on the same platform.
+    pub fn choose_pipeline(&self, platform: &Platform) -> PipelineChoice {
+        let choice = |pipeline, reason: &str| PipelineChoice {
+            pipeline,
+            reason: reason.to_string(),
+        };
+        let (mut has_reductions, mut metal_only) = (false, false);
+        for node_op in self.graph.node_weights() {
+            let any = node_op.as_any();
+            if any.is::<op::SumReduce>() || any.is::<op::MaxReduce>() {
+                has_reductions = true;
+            } else if any.is::<op::Sort>()
+                || any.is::<op::CumSum>()
+                || any.is::<op::ArgMax>()
+                || any.is::<op::TopK>()
+                || any.is::<op::ChunkedCrossEntropy>()
+                || any.is::<op::EmbeddingBag>()
+                || any.is::<op::ScatterAdd>()
+            {
+                metal_only = true;
+            } else if !is_primitive(any) {
+                return choice(
+                    Pipeline::Cpu,
+                    "the graph has ops the GPU compilers don't lower",
+                );
+            }
+        }
+        if !has_reductions {
+            return choice(
+                Pipeline::Cpu,
+                "the graph has no reductions or matmuls worth running on a GPU",
+            );
+        }
+        // Half and quantized weights lose nothing in fp16
+        let half_weights = !self.weight_dtypes.is_empty()
+            && self
+                .weight_dtypes
+                .values()
+                .all(|d| matches!(d, Dtype::F16 | Dtype::BF16 | Dtype::I8 | Dtype::U8));
+
+        if platform.cuda && !metal_only {
+            if half_weights {
+                choice(
+                    Pipeline::CudaF16,
+                    "Nvidia GPU with half or quantized weights",
+                )
+            } else {
+                choice(Pipeline::CudaF32, "Nvidia GPU with fp32 weights")
+            }
+        } else if platform.metal {
+            if platform.apple_silicon && half_weights {
+                choice(
+                    Pipeline::MetalF16,
+                    "Apple Silicon with half or quantized weights",
+                )
+            } else if platform.apple_silicon {
+                choice(Pipeline::MetalF32, "Apple Silicon with fp32 weights")
+            } else {
+                choice(Pipeline::MetalF32, "Metal device without fast fp16")
+            }
+        } else if platform.cuda {
+            choice(
+                Pipeline::Cpu,
+                "the graph sorts or computes cross entropy, which CUDA doesn't support",
+            )
+        } else {
+            choice(Pipeline::Cpu, "no GPU available")
+        }
+    }
+}
+
+/// Whether an op is one the frontend builds graphs from, which every compiler stack knows how to lower
+fn is_primitive(op: &dyn std::any::Any) -> bool {
+    op.is::<op::Function>()
+        || op.is::<op::Constant>()
+        || op.is::<op::Print>()
+        || op.is::<op::Diff>()
+        || op.is::<op::Tap>()
+        || op.is::<op::Contiguous>()
+        || op.is::<op::Log2>()
+        || op.is::<op::Exp2>()
+        || op.is::<op::Sin>()
+        || op.is::<op::Recip>()
+        || op.is::<op::Sqrt>()
+        || op.is::<op::Add>()
+        || op.is::<op::Mul>()
+        || op.is::<op::Mod>()
+        || op.is::<op::LessThan>()
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    use safetensors::Dtype;
+
+    use super::{Pipeline, Platform};
+
+    const APPLE_SILICON: Platform = Platform {
+        metal: true,
+        apple_silicon: true,
+        cuda: false,
+    };
+    const INTEL_MAC: Platform = Platform {
+        metal: true,
+        apple_silicon: false,
+        cuda: false,
+    };
+    const NVIDIA: Platform = Platform {
+        metal: false,
+        apple_silicon: false,
+        cuda: true,
+    };
+    const CPU_ONLY: Platform = Platform {
+        metal: false,
+        apple_silicon: false,
+        cuda: false,
+    };
+
+    /// A matmul, with the weight loaded as the given dtype
+    fn matmul_graph(cx: &mut Graph, weight_dtype: Option<Dtype>) -> GraphTensor<R2<2, 4>> {
+        let a = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 3., 4., 5., 6.]);
+        let w = cx.tensor::<R2<3, 4>>().set(vec![1.; 12]);
+        if let Some(dtype) = weight_dtype {
+            cx.weight_dtypes.insert(w.id, dtype);
+        }
+        a.matmul(w).retrieve()
+    }
+
+    #[test]
+    fn test_pipeline_per_platform() {
+        let pipeline = |dtype, platform| {
+            let mut cx = Graph::new();
+            matmul_graph(&mut cx, dtype);
+            cx.choose_pipeline(&platform).pipeline
+        };
+        assert_eq!(
+            pipeline(Some(Dtype::F16), APPLE_SILICON),
+            Pipeline::MetalF16
+        );
+        assert_eq!(pipeline(Some(Dtype::I8), APPLE_SILICON), Pipeline::MetalF16);
+        assert_eq!(
+            pipeline(Some(Dtype::F32), APPLE_SILICON),
+            Pipeline::MetalF32
+        );
+        // Weights set from the host are fp32
+        assert_eq!(pipeline(None, APPLE_SILICON), Pipeline::MetalF32);
+        assert_eq!(pipeline(Some(Dtype::F16), INTEL_MAC), Pipeline::MetalF32);
+        assert_eq!(pipeline(Some(Dtype::BF16), NVIDIA), Pipeline::CudaF16);
+        assert_eq!(pipeline(Some(Dtype::F32), NVIDIA), Pipeline::CudaF32);
+        assert_eq!(pipeline(Some(Dtype::F16), CPU_ONLY), Pipeline::Cpu);
+    }
+
+    #[test]
+    fn test_pipeline_per_op_mix() {
+        // Nothing heavy enough for a GPU
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        (a * 2.).retrieve();
+        assert_eq!(cx.choose_pipeline(&APPLE_SILICON).pipeline, Pipeline::Cpu);
+
+        // Sorting is only lowered by the Metal compilers
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(vec![3., 1., 2., 6., 5., 4.]);
+        a.sum_reduce::<_, LAxis<1>>().retrieve();
+        a.argsort(true).retrieve();
+        assert_eq!(
+            cx.choose_pipeline(&APPLE_SILICON).pipeline,
+            Pipeline::MetalF32
+        );
+        assert_eq!(cx.choose_pipeline(&NVIDIA).pipeline, Pipeline::Cpu);
+    }
+
+    #[test]
+    fn test_auto_compile_falls_back_to_cpu() {
+        // No GPU backend is registered in the core crate
+        let mut cx = Graph::new();
+        let mut out = matmul_graph(&mut cx, Some(Dtype::F16));
+        let (choice, _) = cx.auto_compile(&mut out, Some(Pipeline::MetalF16));
+        assert_eq!(choice.pipeline, Pipeline::Cpu);
+        cx.execute();
+        assert_exact(&out.data(), &[6., 6., 6., 6., 15., 15., 15., 15.]);
+
+        let mut cx = Graph::new();
+        let mut out = matmul_graph(&mut cx, None);
+        let (choice, _) = cx.auto_compile(&mut out, Some(Pipeline::Cpu));
+        assert_eq!(choice.reason, "requested");
+        cx.execute();
+        assert_exact(&out.data(), &[6., 6., 6., 6., 15., 15., 15., 15.]);
+    }
+}
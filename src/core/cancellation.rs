@@ -0,0 +1,199 @@
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use rustc_hash::FxHashSet;
+
+use crate::graph::{ExecutionError, Graph};
+
+/// Stops a [`Graph::execute_with_cancel`] run from another thread, or once a deadline passes.
+///
+/// The executor checks the token between ops, so an op that already started (like a kernel in flight on a GPU) runs to
+/// completion. Cancelling takes at most as long as the slowest op.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel once `timeout` has passed from now
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(Instant::now() + timeout);
+        self
+    }
+
+    /// Cancel the executions checking this token, or any clone of it
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+}
+
+/// An execution was cancelled before it finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cancelled {
+    /// Ops that ran before the cancellation
+    pub ops_run: usize,
+    /// Ops that never ran
+    pub ops_skipped: usize,
+}
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Execution cancelled after {} ops, with {} ops left",
+            self.ops_run, self.ops_skipped
+        )
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl Graph {
+    /// Execute the graph, stopping before the next op once the token is cancelled.
+    ///
+    /// If the execution is cancelled or fails, the intermediate tensors are freed, along with any retrieved outputs
+    /// computed so far, so the graph is ready to execute again. Ops are told with `custom("cancelled", ..)`, so
+    /// backends can drop work they queued but didn't submit and wait for work that's still running.
+    pub fn execute_with_cancel(&mut self, token: &CancellationToken) -> Result<(), ExecutionError> {
+        let mut state = self.start_execution(false)?;
+        let order = self.linearized_graph.clone().unwrap();
+        let mut ran = FxHashSet::default();
+        let mut result = Ok(());
+        for (i, (node, src_ids)) in order.iter().enumerate() {
+            if token.is_cancelled() {
+                result = Err(Cancelled {
+                    ops_run: ran.len(),
+                    ops_skipped: order.len() - i,
+                }
+                .into());
+                break;
+            }
+            match self.execute_node(*node, src_ids, &mut state, |op, srcs| op.try_process(srcs)) {
+                Ok(true) => {
+                    ran.insert(*node);
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if result.is_err() {
+            // Retrieved outputs from this run would stop the next run from computing them
+            self.tensors.retain(|(n, _), _| {
+                self.no_delete.contains(n) && !(ran.contains(n) && self.to_retrieve.contains(n))
+            });
+            for node in self.graph.node_indices().collect::<Vec<_>>() {
+                self.graph
+                    .node_weight_mut(node)
+                    .unwrap()
+                    .custom("cancelled", Box::new(()));
+            }
+        }
+        self.end_execution();
+        result
+    }
+
+    /// Execute the graph, cancelling it if it runs for longer than `timeout`
+    pub fn execute_with_timeout(&mut self, timeout: Duration) -> Result<(), ExecutionError> {
+        self.execute_with_cancel(&CancellationToken::new().with_timeout(timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{CancellationToken, Cancelled};
+    use crate::graph::ExecutionError;
+    crate::test_imports!();
+
+    #[test]
+    fn test_cancel_execution() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<16>>().set(random_vec(16)).keep();
+        let token = CancellationToken::new();
+        let step = |x: GraphTensor<R1<16>>| (x.sin() * 0.9 + 0.1).exp().ln();
+        let mut x = a;
+        for i in 0..50 {
+            if i == 25 {
+                // Cancel from inside the graph, halfway through the chain
+                let canceller = token.clone();
+                x.tap("Cancel", move |_, _| canceller.cancel());
+            }
+            x = step(x);
+        }
+        let out = x.retrieve();
+
+        let Err(ExecutionError::Cancelled(Cancelled {
+            ops_run,
+            ops_skipped,
+        })) = cx.execute_with_cancel(&token)
+        else {
+            panic!("Execution wasn't cancelled");
+        };
+        assert!(ops_run > 100 && ops_skipped > 100);
+        // Only the kept input is left
+        assert_eq!(
+            cx.tensors.keys().map(|(n, _)| *n).collect::<Vec<_>>(),
+            vec![a.id]
+        );
+
+        // The graph still runs normally
+        let mut expected = a.data();
+        for _ in 0..50 {
+            expected = expected
+                .into_iter()
+                .map(|v| (v.sin() * 0.9 + 0.1).exp().ln())
+                .collect();
+        }
+        cx.execute_with_cancel(&CancellationToken::new()).unwrap();
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_execution_timeout() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let b = a.exp().retrieve();
+        assert!(matches!(
+            cx.execute_with_timeout(Duration::ZERO),
+            Err(ExecutionError::Cancelled(Cancelled { ops_run: 0, .. }))
+        ));
+        assert!(cx.tensors.is_empty());
+        cx.execute_with_timeout(Duration::from_secs(60)).unwrap();
+        assert_close(&b.data(), &[1f32.exp(), 2f32.exp(), 3f32.exp(), 4f32.exp()]);
+    }
+
+    #[test]
+    fn test_cancellable_execution_memory_limit() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<1024>>().set(random_vec(1024));
+        let b = a.exp().retrieve();
+        cx.set_memory_limit(1024);
+        assert!(matches!(
+            cx.execute_with_cancel(&CancellationToken::new()),
+            Err(ExecutionError::OutOfMemory(_))
+        ));
+        assert!(cx.tensors.is_empty());
+        cx.clear_memory_limit();
+        cx.execute_with_cancel(&CancellationToken::new()).unwrap();
+        assert_eq!(b.data().len(), 1024);
+    }
+}
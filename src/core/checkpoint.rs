@@ -0,0 +1,334 @@
+use std::fmt::Display;
+
+use petgraph::stable_graph::{NodeIndex, StableGraph};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    compiler_utils::{CompilerHint, ToIds, ToIdsMut},
+    graph::{Dependency, Graph, SourceLocation},
+    op::{Function, Operator},
+};
+
+/// An op saved in a checkpoint
+#[derive(Debug)]
+enum SavedOp {
+    /// A copy of an op that can be copied
+    Copied(Box<dyn Operator>),
+    /// An op that can't be copied, like an input's [`Function`](crate::op::Function), which has to still be in the
+    /// graph when restoring. Identified by the order its node was added in, since node indexes get reused.
+    Kept { order: Option<usize>, op: String },
+}
+
+/// The structure of a graph at some point, saved by [`Graph::checkpoint`] so it can be brought back with
+/// [`Graph::restore`] after trying out compiler passes.
+///
+/// The graph's tensors aren't copied. Restoring keeps the tensors of nodes that had them when the checkpoint was
+/// taken, and drops the ones made since.
+#[derive(Debug)]
+pub struct GraphCheckpoint {
+    graph: StableGraph<SavedOp, Dependency>,
+    tensor_keys: FxHashSet<(NodeIndex, u8)>,
+    no_delete: FxHashSet<NodeIndex>,
+    to_retrieve: FxHashSet<NodeIndex>,
+    content_keys: FxHashMap<NodeIndex, u64>,
+    compiler_hints: FxHashMap<NodeIndex, Vec<CompilerHint>>,
+    source_locations: FxHashMap<NodeIndex, SourceLocation>,
+    node_order: FxHashMap<NodeIndex, usize>,
+    nodes_added: usize,
+    int_tensors: FxHashSet<NodeIndex>,
+    loaded_weights: FxHashSet<NodeIndex>,
+    weight_dtypes: FxHashMap<NodeIndex, safetensors::Dtype>,
+    /// The ids passed to [`Graph::checkpoint`]
+    ids: Vec<NodeIndex>,
+}
+
+/// A checkpoint couldn't be restored because an op it didn't copy is no longer in the graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreError {
+    pub node: NodeIndex,
+    /// The name of the op that was at the node
+    pub op: String,
+}
+
+impl Display for RestoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Can't restore the checkpoint: {} at {:?} can't be copied, and was removed or replaced since",
+            self.op, self.node
+        )
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+/// A graph couldn't be forked because one of its ops can't be copied
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let mut a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+/// // Taps call back into user code, so they can't be copied
+/// a.tap("Print", |_, _| {});
+/// let error = cx.fork().unwrap_err();
+/// assert_eq!(error.op, "Tap-Print");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForkError {
+    pub node: NodeIndex,
+    /// The name of the op at the node
+    pub op: String,
+}
+
+impl Display for ForkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Can't fork the graph: {} at {:?} can't be copied",
+            self.op, self.node
+        )
+    }
+}
+
+impl std::error::Error for ForkError {}
+
+impl Graph {
+    /// Save the structure of the graph, so the changes of compiler passes run afterwards can be undone with
+    /// [`Graph::restore`]. The ids in `remap` are saved too, and written back when restoring.
+    ///
+    /// Ops are copied with [`Operator::boxed_clone`]. The ones that can't be copied are kept by reference, so they
+    /// have to stay in the graph for the checkpoint to be restored.
+    pub fn checkpoint<T: ToIds>(&self, remap: T) -> GraphCheckpoint {
+        GraphCheckpoint {
+            graph: self.graph.map(
+                |node, op| match op.boxed_clone() {
+                    Some(copy) => SavedOp::Copied(copy),
+                    None => SavedOp::Kept {
+                        order: self.node_order.get(&node).copied(),
+                        op: format!("{op:?}"),
+                    },
+                },
+                |_, dependency| *dependency,
+            ),
+            tensor_keys: self.tensors.keys().copied().collect(),
+            no_delete: self.no_delete.clone(),
+            to_retrieve: self.to_retrieve.clone(),
+            content_keys: self.content_keys.clone(),
+            compiler_hints: self.compiler_hints.clone(),
+            source_locations: self.source_locations.clone(),
+            node_order: self.node_order.clone(),
+            nodes_added: self.nodes_added,
+            int_tensors: self.int_tensors.clone(),
+            loaded_weights: self.loaded_weights.clone(),
+            weight_dtypes: self.weight_dtypes.clone(),
+            ids: remap.to_ids(),
+        }
+    }
+
+    /// A copy of the graph, with the same node indexes, so ids of tensors in this graph point at the same tensors in
+    /// the copy. The copy can be compiled and executed on its own, leaving this graph as it is.
+    ///
+    /// Ops are copied with [`Operator::boxed_clone`], and inputs share their [`Function`]. The tensors of kept nodes
+    /// are copied too, while the tensors of an unfinished [asynchronous execution](Graph::execute_async) aren't.
+    /// Fails if an op can't be copied.
+    pub fn fork(&self) -> Result<Graph, ForkError> {
+        let mut ops = self
+            .graph
+            .node_indices()
+            .map(|node| {
+                let op = &self.graph[node];
+                op.boxed_clone()
+                    .or_else(|| {
+                        let function = op.as_any().downcast_ref::<Function>()?;
+                        Some(Box::new(Function(function.0.clone(), function.1.clone())))
+                    })
+                    .map(|copy| (node, copy))
+                    .ok_or_else(|| ForkError {
+                        node,
+                        op: format!("{op:?}"),
+                    })
+            })
+            .collect::<Result<FxHashMap<_, _>, _>>()?;
+        let graph = self.graph.map(
+            |node, _| ops.remove(&node).unwrap(),
+            |_, dependency| *dependency,
+        );
+        Ok(Graph {
+            graph,
+            tensors: self
+                .tensors
+                .iter()
+                .filter(|((n, _), _)| self.no_delete.contains(n))
+                .map(|(key, tensor)| (*key, tensor.clone()))
+                .collect(),
+            dyn_map: self.dyn_map.clone(),
+            no_delete: self.no_delete.clone(),
+            to_retrieve: self.to_retrieve.clone(),
+            content_keys: self.content_keys.clone(),
+            compiler_hints: self.compiler_hints.clone(),
+            source_locations: self.source_locations.clone(),
+            node_order: self.node_order.clone(),
+            nodes_added: self.nodes_added,
+            int_tensors: self.int_tensors.clone(),
+            loaded_weights: self.loaded_weights.clone(),
+            weight_dtypes: self.weight_dtypes.clone(),
+            strict_dim_labels: self.strict_dim_labels,
+            memory_limit: self.memory_limit,
+            specialized_dims: self.specialized_dims.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// Bring the graph back to a checkpoint, undoing every change made to it since, and point the ids in `remap` back
+    /// at the nodes they were saved with. `remap` has to hold the same tensors as when checkpointing.
+    ///
+    /// A checkpoint can be restored any number of times. If an op that couldn't be copied was removed since, the
+    /// graph is left as it is and an error is returned.
+    pub fn restore<T: ToIdsMut>(
+        &mut self,
+        checkpoint: &GraphCheckpoint,
+        mut remap: T,
+    ) -> Result<(), RestoreError> {
+        for node in checkpoint.graph.node_indices() {
+            if let SavedOp::Kept { order, op } = &checkpoint.graph[node] {
+                if !self.graph.contains_node(node) || self.node_order.get(&node) != order.as_ref() {
+                    return Err(RestoreError {
+                        node,
+                        op: op.clone(),
+                    });
+                }
+            }
+        }
+        let mut ids = remap.to_ids_mut();
+        assert_eq!(
+            ids.len(),
+            checkpoint.ids.len(),
+            "Restoring a checkpoint with different tensors than it was taken with"
+        );
+        self.finish_execution();
+
+        let mut current = std::mem::take(&mut self.graph);
+        self.graph = checkpoint.graph.map(
+            |node, op| match op {
+                SavedOp::Copied(op) => op.boxed_clone().unwrap(),
+                SavedOp::Kept { .. } => current.remove_node(node).unwrap(),
+            },
+            |_, dependency| *dependency,
+        );
+        for (id, saved) in ids.iter_mut().zip(&checkpoint.ids) {
+            **id = *saved;
+        }
+        self.tensors
+            .retain(|key, _| checkpoint.tensor_keys.contains(key));
+        self.no_delete = checkpoint.no_delete.clone();
+        self.to_retrieve = checkpoint.to_retrieve.clone();
+        self.content_keys = checkpoint.content_keys.clone();
+        self.compiler_hints = checkpoint.compiler_hints.clone();
+        self.source_locations = checkpoint.source_locations.clone();
+        self.node_order = checkpoint.node_order.clone();
+        self.nodes_added = checkpoint.nodes_added;
+        self.int_tensors = checkpoint.int_tensors.clone();
+        self.loaded_weights = checkpoint.loaded_weights.clone();
+        self.weight_dtypes = checkpoint.weight_dtypes.clone();
+        self.toposort();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RestoreError;
+    crate::test_imports!();
+
+    #[test]
+    fn test_restore_checkpoint() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 4>>().set(random_vec(12)).keep();
+        let b = cx.tensor::<R2<4, 2>>().set(random_vec(8)).keep();
+        let mut out = (a.matmul(b).exp().ln() * 2. + 1.)
+            .sum_reduce::<_, LAxis<1>>()
+            .retrieve();
+        cx.execute();
+        let expected = out.data();
+        out.drop();
+        let (nodes, edges) = (cx.graph.node_count(), cx.graph.edge_count());
+
+        let checkpoint = cx.checkpoint(out);
+        for _ in 0..2 {
+            // Fusing ops removes and replaces nodes, and moves the output
+            cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut out);
+            assert!(cx.graph.node_count() < nodes);
+            cx.execute();
+            assert_close(&out.data(), &expected);
+            out.drop();
+
+            cx.restore(&checkpoint, &mut out).unwrap();
+            assert_eq!(
+                (cx.graph.node_count(), cx.graph.edge_count()),
+                (nodes, edges)
+            );
+            cx.execute();
+            assert_close(&out.data(), &expected);
+            out.drop();
+        }
+    }
+
+    #[test]
+    fn test_restore_removed_input() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let mut b = a.exp().retrieve();
+        let checkpoint = cx.checkpoint(b);
+
+        cx.graph.remove_node(a.id);
+        let nodes = cx.graph.node_count();
+        assert!(matches!(
+            cx.restore(&checkpoint, &mut b),
+            Err(RestoreError { node, .. }) if node == a.id
+        ));
+        // Nothing was restored
+        assert_eq!(cx.graph.node_count(), nodes);
+    }
+
+    #[test]
+    fn test_restore_replaced_input() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let mut b = a.exp().retrieve();
+        let checkpoint = cx.checkpoint(b);
+
+        // The new input takes the removed one's node index, and can take its allocation too
+        cx.graph.remove_node(a.id);
+        let c = cx.tensor::<R1<4>>();
+        assert_eq!(c.id, a.id);
+        assert!(matches!(
+            cx.restore(&checkpoint, &mut b),
+            Err(RestoreError { node, .. }) if node == a.id
+        ));
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6)).keep();
+        let out = (a.exp().ln() * 2.).sum_reduce::<_, LAxis<1>>().retrieve();
+        cx.execute();
+        let expected = out.data();
+        out.drop();
+        let nodes = cx.graph.node_count();
+
+        let mut fork = cx.fork().unwrap();
+        // Kept tensors are copied, so the input doesn't have to be set again
+        assert!(fork.get_tensor_ref(a.id, 0).is_some());
+        let mut fork_out = out;
+        fork.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut fork_out);
+        let fork_out = GraphTensor::<R1<2>>::from_id(fork_out.id, fork_out.shape, &mut fork);
+        fork.execute();
+        assert_close(&fork_out.data(), &expected);
+
+        // The original is untouched
+        assert_eq!(cx.graph.node_count(), nodes);
+        assert!(fork.graph.node_count() < nodes);
+        cx.execute();
+        assert_close(&out.data(), &expected);
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rustc_hash::FxHasher;
+
+const MAGIC: &[u8; 4] = b"LMCC";
+
+/// An on-disk cache for compilation results, like compiled pipelines or autotuning results, shared between processes.
+///
+/// Entries are keyed by a namespace and the content they were computed from, and are versioned by the crate version
+/// and a device identifier, so upgrading luminal or switching devices never reads stale results. Anything wrong with
+/// an entry (missing, truncated, corrupted or from another version) is treated as a miss and recomputed. Entries are
+/// written to a temporary file and renamed into place, so concurrent processes never see partial writes.
+#[derive(Debug, Clone)]
+pub struct CompileCache {
+    dir: Option<PathBuf>,
+    device: String,
+}
+
+impl CompileCache {
+    /// A cache storing entries in `dir` for the device identified by `device`
+    pub fn new(dir: impl Into<PathBuf>, device: impl Into<String>) -> Self {
+        Self {
+            dir: Some(dir.into()),
+            device: device.into(),
+        }
+    }
+
+    /// A cache that stores nothing, so everything is recomputed
+    pub fn disabled() -> Self {
+        Self {
+            dir: None,
+            device: String::new(),
+        }
+    }
+
+    /// A cache in the directory set by the `LUMINAL_CACHE_DIR` environment variable, or disabled if it isn't set
+    pub fn from_env(device: impl Into<String>) -> Self {
+        match std::env::var_os("LUMINAL_CACHE_DIR") {
+            Some(dir) => Self::new(dir, device),
+            None => Self::disabled(),
+        }
+    }
+
+    /// Whether this cache stores entries
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// The directory entries are stored in
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    fn content_hash(content: &[u8]) -> u64 {
+        let mut hasher = FxHasher::default();
+        content.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Path of the entry for this content
+    pub fn entry_path(&self, namespace: &str, content: &[u8]) -> Option<PathBuf> {
+        let mut hasher = FxHasher::default();
+        (env!("CARGO_PKG_VERSION"), &self.device, namespace).hash(&mut hasher);
+        Self::content_hash(content).hash(&mut hasher);
+        Some(
+            self.dir
+                .as_ref()?
+                .join(namespace)
+                .join(format!("{:016x}.bin", hasher.finish())),
+        )
+    }
+
+    /// Encode an entry's header: the version, device and content hash it was computed for
+    fn header(&self, content: &[u8]) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        for field in [env!("CARGO_PKG_VERSION").as_bytes(), self.device.as_bytes()] {
+            header.extend((field.len() as u32).to_le_bytes());
+            header.extend(field);
+        }
+        header.extend(Self::content_hash(content).to_le_bytes());
+        header
+    }
+
+    /// Get the cached value for this content, if there's a valid entry
+    pub fn get(&self, namespace: &str, content: &[u8]) -> Option<Vec<u8>> {
+        let path = self.entry_path(namespace, content)?;
+        let bytes = fs::read(&path).ok()?;
+        let value = bytes
+            .strip_prefix(self.header(content).as_slice())
+            .and_then(|rest| {
+                let (value, checksum) = rest.split_at_checked(rest.len().checked_sub(8)?)?;
+                (Self::content_hash(value).to_le_bytes() == checksum).then(|| value.to_vec())
+            });
+        if value.is_none() {
+            // Corrupted or stale, so get rid of it
+            let _ = fs::remove_file(&path);
+        }
+        value
+    }
+
+    /// Store a value for this content. Failing to write just means it'll be recomputed next time.
+    pub fn insert(&self, namespace: &str, content: &[u8], value: &[u8]) {
+        let Some(path) = self.entry_path(namespace, content) else {
+            return;
+        };
+        let mut bytes = self.header(content);
+        bytes.extend(value);
+        bytes.extend(Self::content_hash(value).to_le_bytes());
+        // Write to a unique temporary file, then atomically rename it into place
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let tmp = path.with_extension(format!("{}.{nanos}.tmp", std::process::id()));
+        let written = fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs::write(&tmp, bytes))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    /// Get the cached value for this content, or compute and cache it
+    pub fn get_or_insert_with(
+        &self,
+        namespace: &str,
+        content: &[u8],
+        compute: impl FnOnce() -> Vec<u8>,
+    ) -> Vec<u8> {
+        if let Some(value) = self.get(namespace, content) {
+            return value;
+        }
+        let value = compute();
+        self.insert(namespace, content, &value);
+        value
+    }
+
+    /// Get the cached value for this content, or compute and cache it. Entries that fail to decode are recomputed.
+    pub fn get_or_insert_with_codec<T>(
+        &self,
+        namespace: &str,
+        content: &[u8],
+        compute: impl FnOnce() -> T,
+        encode: impl FnOnce(&T) -> Vec<u8>,
+        decode: impl FnOnce(&[u8]) -> Option<T>,
+    ) -> T {
+        if let Some(value) = self.get(namespace, content).and_then(|v| decode(&v)) {
+            return value;
+        }
+        let value = compute();
+        self.insert(namespace, content, &encode(&value));
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::CompileCache;
+
+    fn cache_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "luminal_compile_cache_{name}_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_compile_cache_hit_and_rebuild() {
+        let dir = cache_dir("hit");
+        let computed = Cell::new(0);
+        let compile = || {
+            computed.set(computed.get() + 1);
+            b"compiled pipeline".to_vec()
+        };
+
+        // A first process compiles, and a second process finds its result
+        let first = CompileCache::new(&dir, "device 0");
+        assert_eq!(
+            first.get_or_insert_with("pipelines", b"kernel source", compile),
+            b"compiled pipeline"
+        );
+        let second = CompileCache::new(&dir, "device 0");
+        assert_eq!(
+            second.get_or_insert_with("pipelines", b"kernel source", compile),
+            b"compiled pipeline"
+        );
+        assert_eq!(computed.get(), 1);
+
+        // Other devices and content don't share entries
+        CompileCache::new(&dir, "device 1").get_or_insert_with(
+            "pipelines",
+            b"kernel source",
+            compile,
+        );
+        second.get_or_insert_with("pipelines", b"other source", compile);
+        assert_eq!(computed.get(), 3);
+
+        // A corrupted entry is rebuilt instead of erroring
+        let path = second.entry_path("pipelines", b"kernel source").unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+        assert_eq!(
+            second.get_or_insert_with("pipelines", b"kernel source", compile),
+            b"compiled pipeline"
+        );
+        assert_eq!(computed.get(), 4);
+        assert!(second.get("pipelines", b"kernel source").is_some());
+
+        // A disabled cache always recomputes
+        let disabled = CompileCache::disabled();
+        disabled.get_or_insert_with("pipelines", b"kernel source", compile);
+        assert_eq!(computed.get(), 5);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
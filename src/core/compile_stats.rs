@@ -0,0 +1,236 @@
+use std::{
+    any::{Any, TypeId},
+    collections::BTreeMap,
+    fmt::Display,
+};
+
+use petgraph::stable_graph::NodeIndex;
+use rustc_hash::FxHashSet;
+
+use crate::graph::Graph;
+
+/// What a compiler pass changed in the graph
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompileStats {
+    /// Number of rewrites the pass reported applying
+    pub rewrites_applied: usize,
+    /// Nodes in the graph after the pass that weren't before
+    pub nodes_added: usize,
+    /// Nodes in the graph before the pass that aren't after
+    pub nodes_removed: usize,
+    /// Number of rewrites applied, by pattern name
+    pub per_pattern_counts: BTreeMap<String, usize>,
+}
+
+impl CompileStats {
+    fn merge(&mut self, other: &CompileStats) {
+        self.rewrites_applied += other.rewrites_applied;
+        self.nodes_added += other.nodes_added;
+        self.nodes_removed += other.nodes_removed;
+        for (pattern, count) in &other.per_pattern_counts {
+            *self.per_pattern_counts.entry(pattern.clone()).or_default() += count;
+        }
+    }
+}
+
+/// Per pass stats of a [`Graph::compile`] call, in the order the passes first ran.
+///
+/// Passes are the leaf compilers of a compiler stack, named by type. A pass that runs more than once, like inside a
+/// [`Looped`](crate::compiler_utils::Looped), has its runs added together.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompileReport {
+    pub passes: Vec<(String, CompileStats)>,
+}
+
+impl CompileReport {
+    /// The stats of a pass, by type name without the module path, like `"MatMul2DCompiler"`
+    pub fn pass(&self, name: &str) -> Option<&CompileStats> {
+        self.passes.iter().find(|(n, _)| n == name).map(|(_, s)| s)
+    }
+
+    /// Stats of every pass added together
+    pub fn total(&self) -> CompileStats {
+        let mut total = CompileStats::default();
+        for (_, stats) in &self.passes {
+            total.merge(stats);
+        }
+        total
+    }
+}
+
+impl Display for CompileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.total();
+        write!(
+            f,
+            "{} rewrites, {} nodes added, {} nodes removed",
+            total.rewrites_applied, total.nodes_added, total.nodes_removed
+        )?;
+        for (name, stats) in &self.passes {
+            if stats == &CompileStats::default() {
+                continue;
+            }
+            write!(
+                f,
+                "\n  {name}: {} rewrites, +{} -{} nodes",
+                stats.rewrites_applied, stats.nodes_added, stats.nodes_removed
+            )?;
+            for (pattern, count) in &stats.per_pattern_counts {
+                write!(f, "\n    {pattern}: {count}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A pass that is currently running
+#[derive(Debug)]
+struct PassFrame {
+    /// The nodes before the pass ran, with the type of their op so a node replaced in the same slot counts as changed
+    nodes: FxHashSet<(NodeIndex, TypeId)>,
+    /// Whether a nested pass ran, making this a group of passes rather than a pass itself
+    has_children: bool,
+    stats: CompileStats,
+}
+
+/// Collects stats while the graph is compiling
+#[derive(Debug, Default)]
+pub(crate) struct CompileStatsRecorder {
+    frames: Vec<PassFrame>,
+    report: CompileReport,
+}
+
+/// A type name without module paths, like `Looped<CSE>`
+fn short_type_name<T>() -> String {
+    let name = std::any::type_name::<T>();
+    let mut short = String::new();
+    let mut segment = String::new();
+    for c in name.chars() {
+        if c.is_alphanumeric() || c == '_' || c == ':' {
+            segment.push(c);
+        } else {
+            short.push_str(segment.rsplit("::").next().unwrap());
+            segment.clear();
+            short.push(c);
+        }
+    }
+    short.push_str(segment.rsplit("::").next().unwrap());
+    short
+}
+
+impl Graph {
+    fn node_types(&self) -> FxHashSet<(NodeIndex, TypeId)> {
+        self.graph
+            .node_indices()
+            .map(|n| (n, Any::type_id(self.graph[n].as_any())))
+            .collect()
+    }
+
+    /// Mark the start of a compiler pass. Does nothing unless the graph is compiling through [`Graph::compile`].
+    pub fn begin_compile_pass(&mut self) {
+        if self.compile_stats.is_none() {
+            return;
+        }
+        let nodes = self.node_types();
+        let recorder = self.compile_stats.as_mut().unwrap();
+        if let Some(parent) = recorder.frames.last_mut() {
+            parent.has_children = true;
+        }
+        recorder.frames.push(PassFrame {
+            nodes,
+            has_children: false,
+            stats: CompileStats::default(),
+        });
+    }
+
+    /// Mark the end of the compiler pass started last, recording its stats under the name of `C`
+    pub fn end_compile_pass<C>(&mut self) {
+        let Some(frame) = self
+            .compile_stats
+            .as_mut()
+            .and_then(|recorder| recorder.frames.pop())
+        else {
+            return;
+        };
+        let mut stats = frame.stats;
+        if !frame.has_children {
+            let nodes = self.node_types();
+            stats.nodes_added = nodes.difference(&frame.nodes).count();
+            stats.nodes_removed = frame.nodes.difference(&nodes).count();
+        } else if stats.rewrites_applied == 0 {
+            // Groups of passes are only reported through their passes
+            return;
+        }
+        let name = short_type_name::<C>();
+        let report = &mut self.compile_stats.as_mut().unwrap().report;
+        match report.passes.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, existing)) => existing.merge(&stats),
+            None => report.passes.push((name, stats)),
+        }
+    }
+
+    /// Record that the running compiler pass applied a rewrite matching `pattern`.
+    ///
+    /// Compilers should call this once for each rewrite they apply, so [`Graph::compile`] can report them.
+    pub fn record_rewrite(&mut self, pattern: &str) {
+        if let Some(frame) = self
+            .compile_stats
+            .as_mut()
+            .and_then(|recorder| recorder.frames.last_mut())
+        {
+            frame.stats.rewrites_applied += 1;
+            *frame
+                .stats
+                .per_pattern_counts
+                .entry(pattern.to_string())
+                .or_default() += 1;
+        }
+    }
+
+    pub(crate) fn start_compile_stats(&mut self) {
+        self.compile_stats = Some(CompileStatsRecorder::default());
+    }
+
+    pub(crate) fn finish_compile_stats(&mut self) -> CompileReport {
+        self.compile_stats
+            .take()
+            .map(|recorder| recorder.report)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    use crate::compilers::{MatMul2DCompiler, MatMulCompiler};
+
+    #[test]
+    fn test_short_type_name() {
+        assert_eq!(
+            super::short_type_name::<Looped<MatMul2DCompiler>>(),
+            "Looped<MatMul2DCompiler>"
+        );
+    }
+
+    #[test]
+    fn test_matmul_compile_stats() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let c = cx.tensor::<R2<4, 5>>().set(random_vec(20));
+        let d = cx.tensor::<R3<2, 2, 3>>().set(random_vec(12));
+        let mut out = (a.matmul(b).matmul(c).retrieve(), d.matmul(b).retrieve());
+
+        let report = cx.compile(<(GenericCompiler, MatMulCompiler)>::default(), &mut out);
+        // Two 2D matmuls and one batched matmul, each replacing a mul and a sum reduce
+        let matmul = report.pass("MatMul2DCompiler").unwrap();
+        assert_eq!(matmul.rewrites_applied, 2);
+        assert_eq!(matmul.per_pattern_counts["MatMul2D"], 2);
+        assert_eq!((matmul.nodes_added, matmul.nodes_removed), (2, 4));
+        let batched = report.pass("BatchMatMul2DCompiler").unwrap();
+        assert_eq!(batched.per_pattern_counts["BatchMatMul2D"], 1);
+        assert_eq!(report.total().per_pattern_counts.len(), 2);
+        assert!(report.to_string().contains("MatMul2D: 2"));
+    }
+}
@@ -19,16 +19,74 @@ use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::{
     graph::Graph,
+    graph::{Dependency, MainGraph},
     op::Operator,
-    prelude::{Dependency, MainGraph, Shape, ShapeTracker},
+    shape::{Shape, ShapeTracker},
 };
 
 use super::{graph_tensor::GraphTensor, shape::symbolic::Expression};
 
+/// A hint to compilers about how to treat a node, set with `GraphTensor::hint`. Compilers ignore hints they don't understand.
+///
+/// The built-in compilers honor:
+/// - `NoFuse` on the mul or the sum reduce of a matmul: the CPU, Metal and CUDA matmul compilers leave it as a mul and
+///   sum reduce instead of rewriting it into a matmul op.
+/// - `PreferKernel("gemm")` on a matmul: the Metal matmul uses the general matrix multiply kernel even for
+///   matrix-vector products, which otherwise use the `"gemv"` kernel.
+/// - `PreferKernel("mps")` on a matmul: the Metal matmul uses MetalPerformanceShaders' matrix multiplication instead
+///   of the custom kernels.
+/// - `PreferKernel("single_pass")` on a softmax: the Metal softmax normalizes each row with a single threadgroup, even
+///   rows long enough to otherwise be split into chunks normalized by separate threadgroups.
+/// - `CausalMask` on the mask added to attention scores: the Metal flash attention kernel hides later keys by
+///   position instead of reading the mask.
+/// - `Im2Col` on the patch matrix of a convolution: the Metal compiler builds the patches in one kernel instead of
+///   running the chain of contiguous ops that expands them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompilerHint {
+    /// Don't rewrite this node into a fused op
+    NoFuse,
+    /// Use the named kernel variant where there's a choice
+    PreferKernel(String),
+    /// This node is an additive causal mask over its last two dims: 0 where the query position (second to last dim)
+    /// is at or after the key position (last dim), and negative infinity elsewhere. Set by [`Mask::to_additive`] on
+    /// masks from [`Graph::causal_mask`].
+    ///
+    /// [`Mask::to_additive`]: crate::prelude::Mask::to_additive
+    CausalMask,
+    /// This node is the (channels * kernel x * kernel y, out x * out y) patch matrix of an undilated convolution,
+    /// expanded by a chain of contiguous ops from a dense (channels, x, y) input. Set by [`Conv2D::forward`].
+    ///
+    /// [`Conv2D::forward`]: crate::nn::convolution::Conv2D::forward
+    Im2Col(Im2Col),
+}
+
+/// How a convolution's patch matrix was expanded, see [`CompilerHint::Im2Col`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Im2Col {
+    pub channels: usize,
+    /// Input (x, y), before padding
+    pub dims: [usize; 2],
+    pub kernel: [usize; 2],
+    pub stride: [usize; 2],
+    /// Zeros added to both sides of x and y
+    pub padding: [usize; 2],
+    /// Number of contiguous ops in the chain from the input to the patch matrix
+    pub ops: usize,
+}
+
+impl Im2Col {
+    /// Output (x, y) of the convolution
+    pub fn out_dims(&self) -> [usize; 2] {
+        [0, 1].map(|i| (self.dims[i] + 2 * self.padding[i] - self.kernel[i]) / self.stride[i] + 1)
+    }
+}
+
+/// Mutable ids of the tensors a compile keeps track of, so they can be pointed at the nodes that replace them
 pub trait ToIdsMut {
     fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex>;
 }
 
+/// Ids of a set of tensors, like the weights of a model
 pub trait ToIds {
     fn to_ids(&self) -> Vec<NodeIndex>;
 }
@@ -216,7 +274,11 @@ macro_rules! tuple_impls {
             Compiler, )+
         > Compiler for ($($name,)+) {
             fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut remap: T) {
-                $(self.$idx.compile(graph, &mut remap);)+
+                $(
+                    graph.begin_compile_pass();
+                    self.$idx.compile(graph, &mut remap);
+                    graph.end_compile_pass::<$name>();
+                )+
             }
         }
     };
@@ -282,7 +344,26 @@ tuple_impls!(
 // Helpers
 
 impl Graph {
-    /// Add op on the graph, and get back a NewOp
+    /// Check if a node has a compiler hint
+    pub fn has_hint(&self, node: NodeIndex, hint: &CompilerHint) -> bool {
+        self.compiler_hints
+            .get(&node)
+            .map(|h| h.contains(hint))
+            .unwrap_or_default()
+    }
+
+    /// The kernel variant preferred for a node, if any
+    pub fn preferred_kernel(&self, node: NodeIndex) -> Option<&str> {
+        self.compiler_hints
+            .get(&node)?
+            .iter()
+            .find_map(|h| match h {
+                CompilerHint::PreferKernel(k) => Some(k.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Add op on the graph, and get back a NewOp. The caller is recorded as the op's source location.
     ///
     /// ```rust
     /// use luminal::prelude::*;
@@ -294,9 +375,14 @@ impl Graph {
     ///     .finish();
     /// let b = GraphTensor::<R1<3>>::from_id(b_id, a.shape, a.graph());
     /// ```
+    #[track_caller]
     pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp {
+        let new_op_id = self.graph.add_node(Box::new(op));
+        self.source_locations
+            .insert(new_op_id, std::panic::Location::caller());
+        self.record_node_order(new_op_id);
         NewOp {
-            new_op_id: self.graph.add_node(Box::new(op)),
+            new_op_id,
             graph_ref: self,
             num_srcs: 0,
         }
@@ -339,10 +425,11 @@ impl Graph {
 
         let mut schedule_edges = vec![];
         for node in self.graph.node_indices() {
-            new_graph
-                .node_weight_mut(id_map[&node])
-                .unwrap()
-                .push_str(&node.index().to_string());
+            let label = new_graph.node_weight_mut(id_map[&node]).unwrap();
+            label.push_str(&node.index().to_string());
+            if let Some(location) = self.source_location(node) {
+                label.push_str(&format!(" @ {}:{}", location.file(), location.line()));
+            }
             for edge in self
                 .graph
                 .edges_directed(node, Direction::Outgoing)
@@ -374,13 +461,12 @@ impl Graph {
                         .map(|d| !d.2.shape().is_empty())
                         .unwrap_or_default()
                 {
-                    new_graph
-                        .node_weight_mut(id_map[&edge.target()])
-                        .unwrap()
-                        .push_str(&format!(
-                            " | {:?}",
-                            edge.weight().as_data().unwrap().2.shape()
-                        ));
+                    let shape = edge.weight().as_data().unwrap().2;
+                    let node_label = new_graph.node_weight_mut(id_map[&edge.target()]).unwrap();
+                    node_label.push_str(&format!(" | {:?}", shape.shape()));
+                    if shape.is_labeled() {
+                        node_label.push_str(&format!(" {}", shape.format_labels()));
+                    }
                 }
             }
         }
@@ -0,0 +1,225 @@
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    compiler_utils::ToIds,
+    graph::Graph,
+    graph_tensor::GraphTensor,
+    prelude::{Data, Shape},
+    tensor::Tensor,
+};
+use petgraph::stable_graph::NodeIndex;
+
+/// The per-request state of a graph's execution, so one graph and its weights can serve several requests.
+///
+/// The graph keeps its compiled structure and the tensors shared between contexts (weights, or anything else kept
+/// with `keep`), while each context has its own inputs, dyn dims, retrieved outputs and persistent state (like a KV
+/// cache, marked with [`ExecutionContext::keep_tensors`]). Contexts don't borrow the graph, so requests can be prepared
+/// independently and executed interleaved with [`Graph::execute_context`]. Executions themselves are serialized, since
+/// they share the graph's operators.
+#[derive(Debug, Default)]
+pub struct ExecutionContext {
+    /// This context's tensors. Indexed by node index and output index.
+    pub tensors: FxHashMap<(NodeIndex, u8), Tensor>,
+    /// This context's dynamic dimension sizes
+    pub dyn_map: FxHashMap<char, usize>,
+    /// Kept tensors that belong to this context rather than being shared with other contexts
+    pub state: FxHashSet<NodeIndex>,
+}
+
+impl ExecutionContext {
+    /// Create an empty context
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a tensor's data for this context
+    pub fn set_tensor(&mut self, id: NodeIndex, ind: u8, tensor: Tensor) {
+        self.tensors.insert((id, ind), tensor);
+    }
+
+    /// Set the value of an input for this context
+    pub fn set<S: Shape, T: Data + Clone>(&mut self, tensor: GraphTensor<S>, data: T) {
+        self.set_tensor(
+            tensor.id,
+            0,
+            Tensor {
+                data: Box::new(data),
+            },
+        );
+    }
+
+    /// Set the value of an input with dynamic dimensions for this context, recording the dyn dim sizes
+    pub fn set_dyn<S: Shape, T: Data + Clone>(
+        &mut self,
+        tensor: GraphTensor<S>,
+        data: T,
+        shape: &[usize],
+    ) {
+        assert_eq!(
+            S::realized_shape().len(),
+            shape.len(),
+            "Number of dimensions don't match!"
+        );
+        for (d, s) in S::realized_shape().iter().zip(shape.iter()) {
+            if let Some(c) = d.to_symbols().pop() {
+                self.dyn_map.insert(c, *s);
+            }
+        }
+        self.set(tensor, data);
+    }
+
+    /// Set a dynamic dimension for this context
+    pub fn set_dyn_dim(&mut self, dimension: char, val: usize) {
+        self.dyn_map.insert(dimension, val);
+    }
+
+    /// Try to get the tensor data in this context
+    pub fn get_tensor_ref(&self, id: NodeIndex, ind: u8) -> Option<&Tensor> {
+        self.tensors.get(&(id, ind))
+    }
+
+    /// Mark kept tensors as belonging to this context, so each context has its own copy
+    pub fn keep_tensors<T: ToIds>(&mut self, tensors: T) {
+        self.state.extend(tensors.to_ids());
+    }
+
+    /// Delete the tensor data from this context
+    pub fn drop_tensors<T: ToIds>(&mut self, tensors: T) {
+        for id in tensors.to_ids() {
+            self.tensors.remove(&(id, 0));
+        }
+    }
+
+    /// Move data from one set of nodes to another in this context, like moving an output cache to the next step's input
+    pub fn transfer_data<A: ToIds, B: ToIds>(&mut self, srcs: A, dests: B) {
+        for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
+            let mut output_num = 0;
+            while let Some(tensor) = self.tensors.remove(&(src, output_num)) {
+                self.tensors.insert((dest, output_num), tensor);
+                output_num += 1;
+            }
+        }
+    }
+
+    /// Get the contiguous data of a tensor in this context
+    pub fn data<S: Shape>(&self, tensor: GraphTensor<S>) -> Vec<f32> {
+        tensor.data_from(self.get_tensor_ref(tensor.id, 0).unwrap(), &self.dyn_map)
+    }
+}
+
+impl Graph {
+    /// Execute the graph with the inputs, dyn dims and state of a context.
+    ///
+    /// Kept tensors not in the context's state, like weights, stay in the graph to be shared with other contexts.
+    /// Retrieved outputs and the context's state are moved into the context.
+    pub fn execute_context(&mut self, context: &mut ExecutionContext) {
+        std::mem::swap(&mut self.dyn_map, &mut context.dyn_map);
+        // Set aside any shared tensors the context overrides
+        let owned = context.tensors.keys().copied().collect::<FxHashSet<_>>();
+        let shadowed = context
+            .tensors
+            .drain()
+            .filter_map(|(id, tensor)| Some((id, self.tensors.insert(id, tensor)?)))
+            .collect::<Vec<_>>();
+
+        self.execute();
+
+        std::mem::swap(&mut self.dyn_map, &mut context.dyn_map);
+        let (state, to_retrieve) = (&context.state, &self.to_retrieve);
+        let context_tensors = self
+            .tensors
+            .keys()
+            .filter(|id| owned.contains(id) || state.contains(&id.0) || to_retrieve.contains(&id.0))
+            .copied()
+            .collect::<Vec<_>>();
+        for id in context_tensors {
+            let tensor = self.tensors.remove(&id).unwrap();
+            context.tensors.insert(id, tensor);
+        }
+        self.tensors.extend(shadowed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    /// The weight, state, input and output of the model
+    type Model = (
+        GraphTensor<R2<3, 3>>,
+        GraphTensor<R1<3>>,
+        GraphTensor<(Dyn<'s'>, LConst<3>)>,
+        GraphTensor<R1<3>>,
+    );
+
+    /// A tiny stateful model: the state accumulates the column sums of each step's input times a shared weight
+    fn build(cx: &mut Graph) -> Model {
+        let weight = cx
+            .named_tensor::<R2<3, 3>>("Weight")
+            .set(vec![1., -2., 0.5, 3., 0., 1., -1., 2., 4.])
+            .keep();
+        let state = cx.named_tensor::<R1<3>>("State");
+        let input = cx.named_tensor::<(Dyn<'s'>, LConst<3>)>("Input");
+        let output = (state + input.matmul(weight).sum_reduce::<_, LAxis<0>>()).retrieve();
+        (weight, state, input, output)
+    }
+
+    fn prompts() -> [Vec<Vec<f32>>; 2] {
+        [
+            vec![random_vec(9), random_vec(3), random_vec(3)],
+            vec![random_vec(6), random_vec(3), random_vec(3), random_vec(3)],
+        ]
+    }
+
+    #[test]
+    fn test_interleaved_contexts() {
+        let prompts = prompts();
+
+        // Standalone runs
+        let expected = prompts
+            .iter()
+            .map(|steps| {
+                let mut cx = Graph::new();
+                let (_, state, input, output) = build(&mut cx);
+                state.set(vec![0.; 3]);
+                steps
+                    .iter()
+                    .map(|step| {
+                        input.set_dyn(step.clone(), &[step.len() / 3, 3]);
+                        cx.execute();
+                        let data = output.data();
+                        transfer_data_same_graph(output, state, &mut cx);
+                        data
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        // Both prompts on one graph, interleaved step by step
+        let mut cx = Graph::new();
+        let (weight, state, input, output) = build(&mut cx);
+        let mut contexts = [ExecutionContext::new(), ExecutionContext::new()];
+        for context in &mut contexts {
+            context.set(state, vec![0.; 3]);
+        }
+        for step in 0..4 {
+            for ((context, steps), expected) in contexts.iter_mut().zip(&prompts).zip(&expected) {
+                let Some((data, expected)) = steps.get(step).zip(expected.get(step)) else {
+                    continue;
+                };
+                context.set_dyn(input, data.clone(), &[data.len() / 3, 3]);
+                cx.execute_context(context);
+                assert_close(&context.data(output), expected);
+                context.transfer_data(output, state);
+            }
+        }
+
+        // The weight was loaded once into the graph, and each context only holds its own state
+        assert!(cx.get_tensor_ref(weight.id, 0).is_some());
+        for context in &contexts {
+            assert!(context.get_tensor_ref(weight.id, 0).is_none());
+            assert!(context.get_tensor_ref(state.id, 0).is_some());
+        }
+        assert!(cx.dyn_map.is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+//! Conversions between luminal and [dfdx](https://github.com/coreylowman/dfdx) CPU tensors.
+//!
+//! Luminal shapes map onto dfdx shapes at the type level: `Const<N>` becomes dfdx's `Const<N>` and every
+//! `Dyn<C>` becomes a runtime `usize`, so a `GraphTensor<(Dyn<'s'>, Const<4>)>` is set from and read into a
+//! `dfdx::Tensor<(usize, Const<4>), f32, Cpu>`.
+
+use dfdx::{
+    nn::tensor_collection::{
+        RecursiveWalker, TensorCollection, TensorOptions, TensorVisitor, ViewTensorName,
+        ViewTensorRef,
+    },
+    shapes::{Const as DConst, ConstShape as DConstShape, Dim as DDim, HasShape, Shape as DShape},
+    tensor::{Cpu, Tensor as DTensor, TensorFromVec},
+};
+use rustc_hash::FxHashMap;
+
+use crate::prelude::{Const, Dimension, Dyn, GraphTensor, Shape, Tensor};
+
+/// A luminal dimension with a dfdx equivalent
+pub trait ToDfdxDim: Dimension {
+    type Dfdx: DDim;
+}
+
+impl<const N: usize> ToDfdxDim for Const<N> {
+    type Dfdx = DConst<N>;
+}
+
+impl<const C: char> ToDfdxDim for Dyn<C> {
+    type Dfdx = usize;
+}
+
+/// A luminal shape with a dfdx equivalent
+pub trait ToDfdxShape: Shape {
+    type Dfdx: DShape;
+
+    /// Build the dfdx shape from the concrete size of each dimension. Panics if a static dimension doesn't match.
+    fn to_dfdx_shape(dims: &[usize]) -> Self::Dfdx;
+}
+
+impl ToDfdxShape for () {
+    type Dfdx = ();
+    fn to_dfdx_shape(_: &[usize]) -> Self::Dfdx {}
+}
+
+macro_rules! dfdx_shape {
+    ([$($d:ident),+], [$($i:tt),+]) => {
+        impl<$($d: ToDfdxDim),+> ToDfdxShape for ($($d,)+) {
+            type Dfdx = ($($d::Dfdx,)+);
+            fn to_dfdx_shape(dims: &[usize]) -> Self::Dfdx {
+                assert_eq!(dims.len(), Self::NUM_DIMS, "Number of dimensions don't match!");
+                ($(
+                    <$d::Dfdx as DDim>::from_size(dims[$i]).unwrap_or_else(|| {
+                        panic!("Dimension {} has size {}, which doesn't match the static shape", $i, dims[$i])
+                    }),
+                )+)
+            }
+        }
+    };
+}
+
+dfdx_shape!([A], [0]);
+dfdx_shape!([A, B], [0, 1]);
+dfdx_shape!([A, B, C], [0, 1, 2]);
+dfdx_shape!([A, B, C, D], [0, 1, 2, 3]);
+dfdx_shape!([A, B, C, D, E], [0, 1, 2, 3, 4]);
+dfdx_shape!([A, B, C, D, E, F], [0, 1, 2, 3, 4, 5]);
+
+impl<S: DShape> From<DTensor<S, f32, Cpu>> for Tensor {
+    fn from(tensor: DTensor<S, f32, Cpu>) -> Self {
+        Tensor::new(tensor.as_vec())
+    }
+}
+
+impl<S: DShape> From<&DTensor<S, f32, Cpu>> for Tensor {
+    fn from(tensor: &DTensor<S, f32, Cpu>) -> Self {
+        Tensor::new(tensor.as_vec())
+    }
+}
+
+impl<S: DConstShape> From<Tensor> for DTensor<S, f32, Cpu> {
+    fn from(tensor: Tensor) -> Self {
+        tensor.to_dfdx(S::default())
+    }
+}
+
+impl Tensor {
+    /// Copy this tensor's data into a dfdx CPU tensor of the given shape. The data is assumed to be contiguous.
+    pub fn to_dfdx<S: DShape>(&self, shape: S) -> DTensor<S, f32, Cpu> {
+        let data = self
+            .data
+            .as_any()
+            .downcast_ref::<Vec<f32>>()
+            .expect("Only CPU f32 tensors can be converted to dfdx")
+            .clone();
+        Cpu::default().tensor_from_vec(data, shape)
+    }
+}
+
+impl<S: ToDfdxShape> GraphTensor<S> {
+    /// Set the value of the tensor from a dfdx tensor, reporting any dynamic dimensions to the graph
+    pub fn set_dfdx(self, tensor: &DTensor<S::Dfdx, f32, Cpu>) -> Self {
+        let dims = tensor.shape().concrete();
+        self.set_dyn(
+            tensor.as_vec(),
+            &(0..S::NUM_DIMS).map(|i| dims[i]).collect::<Vec<_>>(),
+        )
+    }
+
+    /// Get the contiguous data of the tensor as a dfdx tensor
+    pub fn dfdx(&self) -> DTensor<S::Dfdx, f32, Cpu> {
+        let mut st = self.shape;
+        st.resolve_global_dyn_dims(&self.graph().dyn_map);
+        let dims = st
+            .shape()
+            .into_iter()
+            .map(|d| d.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        Cpu::default().tensor_from_vec(self.data(), S::to_dfdx_shape(&dims))
+    }
+}
+
+/// Convert a dfdx parameter path (`0.weight`) to the luminal state dict path (`layer0/weight`).
+///
+/// dfdx names tuple members by their index, while luminal's tuple modules name them `layer{index}`.
+pub fn dfdx_to_luminal_name(name: &str) -> String {
+    name.split('.')
+        .map(|component| {
+            if component.parse::<usize>().is_ok() {
+                format!("layer{component}")
+            } else {
+                component.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+struct ParameterCollector(FxHashMap<String, Tensor>);
+
+impl TensorVisitor<f32, Cpu> for ParameterCollector {
+    type Viewer = (ViewTensorRef, ViewTensorName);
+    type Err = ();
+    type E2 = f32;
+    type D2 = Cpu;
+
+    fn visit<S: DShape>(
+        &mut self,
+        _: TensorOptions<S, f32, Cpu>,
+        (tensor, name): (&DTensor<S, f32, Cpu>, String),
+    ) -> Result<Option<DTensor<S, f32, Cpu>>, Self::Err> {
+        self.0.insert(dfdx_to_luminal_name(&name), tensor.into());
+        Ok(None)
+    }
+}
+
+/// Copy all parameters of a dfdx model into a state dict keyed by their luminal names, to be loaded with a
+/// [`StateDictLoader`](crate::serialization::StateDictLoader).
+///
+/// Parameters are copied in dfdx's layout, so any layers where the layouts differ need fixing up before loading.
+/// For instance dfdx stores linear weights as (out, in), whereas luminal's `Linear` stores them as (in, out).
+pub fn dfdx_state_dict<M: TensorCollection<f32, Cpu>>(model: &M) -> FxHashMap<String, Tensor> {
+    let mut collector = ParameterCollector(FxHashMap::default());
+    M::iter_tensors(&mut RecursiveWalker {
+        m: (model, String::new()),
+        f: &mut collector,
+    })
+    .unwrap();
+    collector.0
+}
+
+#[cfg(test)]
+mod tests {
+    use dfdx::{
+        nn::{builders, BuildOnDevice, Module as DfdxModule},
+        shapes::{HasShape, Shape as DShape},
+        tensor::{Cpu, Tensor as DTensor, TensorFrom},
+        tensor_ops::PermuteTo,
+    };
+
+    use super::dfdx_state_dict;
+    use crate::prelude::{Module, Tensor};
+    crate::test_imports!();
+
+    #[test]
+    fn test_tensor_round_trip() {
+        let dev = Cpu::default();
+        let a = dev.tensor([1., 2., 3.]);
+        let b = dev.tensor([[1., 2., 3.], [4., 5., 6.]]);
+        let c = dev.sample_normal::<Rank3<2, 3, 4>>();
+        let d = dev.sample_normal::<Rank4<2, 1, 3, 2>>();
+
+        assert_exact(
+            &DTensor::<Rank1<3>, f32, Cpu>::from(Tensor::from(a.clone())).as_vec(),
+            &a.as_vec(),
+        );
+        assert_exact(
+            &DTensor::<Rank2<2, 3>, f32, Cpu>::from(Tensor::from(b.clone())).as_vec(),
+            &b.as_vec(),
+        );
+        assert_exact(
+            &DTensor::<Rank3<2, 3, 4>, f32, Cpu>::from(Tensor::from(c.clone())).as_vec(),
+            &c.as_vec(),
+        );
+        assert_exact(
+            &DTensor::<Rank4<2, 1, 3, 2>, f32, Cpu>::from(Tensor::from(d.clone())).as_vec(),
+            &d.as_vec(),
+        );
+    }
+
+    #[test]
+    fn test_graph_tensor_round_trip() {
+        let dev = Cpu::default();
+        let a = dev.sample_normal::<Rank1<5>>();
+        let b = dev.sample_normal::<Rank2<3, 4>>();
+        let c = dev.sample_normal::<Rank3<2, 3, 4>>();
+        let d = dev.sample_normal_like(&(2, DConst::<3>, 1, DConst::<2>));
+
+        let mut cx = Graph::new();
+        let la = cx.tensor::<R1<5>>().set_dfdx(&a).retrieve();
+        let lb = cx.tensor::<R2<3, 4>>().set_dfdx(&b).retrieve();
+        let lc = cx.tensor::<R3<2, 3, 4>>().set_dfdx(&c).retrieve();
+        let ld = cx
+            .tensor::<(Dyn<'a'>, LConst<3>, Dyn<'b'>, LConst<2>)>()
+            .set_dfdx(&d)
+            .retrieve();
+        let lt = lb.permute::<_, LAxes2<1, 0>>().retrieve();
+        cx.execute();
+
+        assert_exact(&la.dfdx().as_vec(), &a.as_vec());
+        assert_exact(&lb.dfdx().as_vec(), &b.as_vec());
+        assert_exact(&lc.dfdx().as_vec(), &c.as_vec());
+        let out = ld.dfdx();
+        assert_eq!(out.shape().concrete(), d.shape().concrete());
+        assert_exact(&out.as_vec(), &d.as_vec());
+        assert_exact(
+            &lt.dfdx().as_vec(),
+            &b.clone().permute::<_, DAxes2<1, 0>>().as_vec(),
+        );
+    }
+
+    #[test]
+    fn test_parameter_transfer() {
+        let dev = Cpu::default();
+        let d_model = <(
+            builders::UnbiasedLinear<3, 4>,
+            builders::ReLU,
+            builders::UnbiasedLinear<4, 2>,
+        )>::build_on_device(&dev);
+
+        let mut cx = Graph::new();
+        let model: (
+            crate::nn::linear::Linear<3, 4>,
+            crate::nn::activation::ReLU,
+            crate::nn::linear::Linear<4, 2>,
+        ) = InitModule::initialize(&mut cx);
+        let input = cx.tensor::<R1<3>>().set(vec![1., -2., 3.]);
+        let out = model.forward(input).retrieve();
+
+        let mut state_dict = dfdx_state_dict(&d_model);
+        assert_eq!(state_dict.len(), 2);
+        // dfdx stores linear weights transposed
+        state_dict.insert(
+            "layer0/weight".to_string(),
+            d_model.0.weight.clone().permute::<_, DAxes2<1, 0>>().into(),
+        );
+        state_dict.insert(
+            "layer2/weight".to_string(),
+            d_model.2.weight.clone().permute::<_, DAxes2<1, 0>>().into(),
+        );
+        StateDictLoader::new(state_dict).load(&model, &mut cx);
+        cx.execute();
+
+        let d_out = d_model.forward(dev.tensor([1., -2., 3.]));
+        assert_close(&out.data(), &d_out.as_vec());
+    }
+}
@@ -0,0 +1,137 @@
+use crate::graph::Graph;
+
+/// 64 bit FNV-1a, fed explicit little endian bytes so the hash is the same on every platform and Rust version
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ *b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u64(&mut self, v: u64) {
+        self.write(&v.to_le_bytes());
+    }
+}
+
+/// Snap a value to the nearest multiple of `tolerance`, keeping NaNs and infinities apart from finite values. A
+/// tolerance of 0 keeps the exact bits, with -0 and every NaN made equal.
+fn quantize(value: f32, tolerance: f32) -> (u8, i64) {
+    if value.is_nan() {
+        (1, 0)
+    } else if value.is_infinite() {
+        (2, value.signum() as i64)
+    } else if tolerance > 0. {
+        // In f64 so the division doesn't round values onto a different multiple. Huge values saturate.
+        (0, (value as f64 / tolerance as f64).round() as i64)
+    } else {
+        (0, (value + 0.).to_bits() as i64)
+    }
+}
+
+impl Graph {
+    /// Hash every retrieved output, with values snapped to a grid of `tolerance`, into a fingerprint that only changes
+    /// when the numerics do. Run it after executing, and compare it between versions to catch a change that moved an
+    /// output.
+    ///
+    /// Each value is rounded to the nearest multiple of `tolerance`, so differences of less than half the tolerance,
+    /// like last-bit changes from reordered float additions, keep the fingerprint. A value sitting right between two
+    /// multiples can still flip, so pick a tolerance well above the expected noise. Each output's element count and the
+    /// dyn dims are hashed too. Outputs are combined independently of node ids, which compilers change, so the
+    /// fingerprint of an uncompiled graph matches a compiled one.
+    ///
+    /// Retrieved tensors have to be on the host, which backends do for retrieved tensors when compiling.
+    pub fn output_fingerprint(&self, tolerance: f32) -> u64 {
+        let mut outputs = self
+            .to_retrieve
+            .iter()
+            .map(|id| {
+                let tensor = self
+                    .get_tensor_ref(*id, 0)
+                    .unwrap_or_else(|| panic!("Retrieved tensor {id:?} hasn't been computed"));
+                let data = tensor
+                    .data
+                    .as_any()
+                    .downcast_ref::<Vec<f32>>()
+                    .unwrap_or_else(|| {
+                        panic!("Retrieved tensor {id:?} isn't f32 data on the host")
+                    });
+                let mut hasher = Fnv::new();
+                hasher.write_u64(data.len() as u64);
+                for v in data {
+                    let (kind, q) = quantize(*v, tolerance);
+                    hasher.write(&[kind]);
+                    hasher.write(&q.to_le_bytes());
+                }
+                hasher.0
+            })
+            .collect::<Vec<_>>();
+        outputs.sort_unstable();
+
+        let mut hasher = Fnv::new();
+        let mut dims = self.dyn_map.iter().collect::<Vec<_>>();
+        dims.sort_unstable();
+        for (dim, size) in dims {
+            hasher.write_u64(*dim as u64);
+            hasher.write_u64(*size as u64);
+        }
+        for output in outputs {
+            hasher.write_u64(output);
+        }
+        hasher.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    fn fingerprint(data: Vec<f32>, tolerance: f32, compile: bool) -> u64 {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(data);
+        let b = cx.tensor::<R1<4>>().set(vec![0.5, -1., 2., 0.25]);
+        let mut out = (a * b).retrieve();
+        let mut sum = (a + b).sum_reduce::<_, LAxis<0>>().retrieve();
+        if compile {
+            cx.compile(
+                <(GenericCompiler, CPUCompiler)>::default(),
+                (&mut out, &mut sum),
+            );
+        }
+        cx.execute();
+        cx.output_fingerprint(tolerance)
+    }
+
+    #[test]
+    fn test_output_fingerprint() {
+        let data = vec![1., 2., -3., 4.];
+        let base = fingerprint(data.clone(), 1e-3, false);
+        assert_eq!(fingerprint(data.clone(), 1e-3, true), base);
+
+        // Well within the tolerance
+        let nudged = data.iter().map(|v| v + 1e-5).collect::<Vec<_>>();
+        assert_eq!(fingerprint(nudged.clone(), 1e-3, false), base);
+        // Above the tolerance
+        let mut moved = data.clone();
+        moved[2] += 1e-2;
+        assert_ne!(fingerprint(moved, 1e-3, false), base);
+        // A tolerance of 0 hashes exact values
+        assert_ne!(
+            fingerprint(nudged, 0., false),
+            fingerprint(data.clone(), 0., false)
+        );
+
+        // NaNs hash the same on every run
+        let mut nan = data;
+        nan[0] = f32::NAN;
+        assert_eq!(
+            fingerprint(nan.clone(), 1e-3, false),
+            fingerprint(nan, 1e-3, false)
+        );
+    }
+}
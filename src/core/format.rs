@@ -0,0 +1,227 @@
+use std::fmt::Write;
+
+use half::{bf16, f16};
+
+use crate::tensor::Tensor;
+
+/// How [`Tensor::format_with`] lays out values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrintOptions {
+    /// Digits after the decimal point for floats
+    pub precision: usize,
+    /// Items shown at the start and end of each truncated dimension
+    pub edge_items: usize,
+    /// Tensors with more elements than this are truncated
+    pub threshold: usize,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        Self {
+            precision: 4,
+            edge_items: 3,
+            threshold: 1000,
+        }
+    }
+}
+
+/// Indexes shown along a dimension, with `None` where the ellipsis goes
+fn shown(size: usize, edge_items: Option<usize>) -> Vec<Option<usize>> {
+    match edge_items {
+        Some(e) if size > 2 * e => (0..e)
+            .map(Some)
+            .chain([None])
+            .chain((size - e..size).map(Some))
+            .collect(),
+        _ => (0..size).map(Some).collect(),
+    }
+}
+
+/// Collect the flat indexes of the shown elements, in the order they're rendered
+fn collect(
+    shape: &[usize],
+    depth: usize,
+    offset: usize,
+    edge: Option<usize>,
+    out: &mut Vec<usize>,
+) {
+    if depth == shape.len() {
+        out.push(offset);
+        return;
+    }
+    let stride = shape[depth + 1..].iter().product::<usize>();
+    for i in shown(shape[depth], edge).into_iter().flatten() {
+        collect(shape, depth + 1, offset + i * stride, edge, out);
+    }
+}
+
+fn render(
+    out: &mut String,
+    shape: &[usize],
+    depth: usize,
+    edge: Option<usize>,
+    cells: &mut impl Iterator<Item = String>,
+    width: usize,
+) {
+    if depth == shape.len() {
+        write!(out, "{:>width$}", cells.next().unwrap()).unwrap();
+        return;
+    }
+    // Rows are split over lines, with a blank line between each level of nesting above them
+    let separator = if depth == shape.len() - 1 {
+        ", ".to_string()
+    } else {
+        format!(
+            ",{}{}",
+            "\n".repeat(shape.len() - depth - 1),
+            " ".repeat(depth + 1)
+        )
+    };
+    out.push('[');
+    for (n, i) in shown(shape[depth], edge).into_iter().enumerate() {
+        if n > 0 {
+            out.push_str(&separator);
+        }
+        if i.is_some() {
+            render(out, shape, depth + 1, edge, cells, width);
+        } else {
+            out.push_str("...");
+        }
+    }
+    out.push(']');
+}
+
+/// Format floats to a fixed precision, switching to scientific notation if any shown value is very large or small
+fn format_floats(values: &[f32], precision: usize) -> Vec<String> {
+    let scientific = values
+        .iter()
+        .filter(|v| v.is_finite() && **v != 0.)
+        .any(|v| v.abs() >= 1e8 || v.abs() < 1e-4);
+    values
+        .iter()
+        .map(|v| {
+            if v.is_nan() {
+                "nan".to_string()
+            } else if *v == f32::INFINITY {
+                "inf".to_string()
+            } else if *v == f32::NEG_INFINITY {
+                "-inf".to_string()
+            } else if scientific {
+                format!("{v:.precision$e}")
+            } else {
+                format!("{v:.precision$}")
+            }
+        })
+        .collect()
+}
+
+impl Tensor {
+    /// Format contiguous host data of the given shape like numpy does, with nested brackets and truncation of large
+    /// tensors. Handles f32, f16, bf16 and i32 data.
+    pub fn format(&self, shape: &[usize]) -> String {
+        self.format_with(shape, &PrintOptions::default())
+    }
+
+    /// Format contiguous host data of the given shape, with the given precision and truncation
+    pub fn format_with(&self, shape: &[usize], options: &PrintOptions) -> String {
+        let n_elements = shape.iter().product::<usize>();
+        let edge = (n_elements > options.threshold).then_some(options.edge_items);
+        let mut indexes = vec![];
+        collect(shape, 0, 0, edge, &mut indexes);
+
+        let data = self.data.as_any();
+        let len = if let Some(d) = data.downcast_ref::<Vec<f32>>() {
+            d.len()
+        } else if let Some(d) = data.downcast_ref::<Vec<f16>>() {
+            d.len()
+        } else if let Some(d) = data.downcast_ref::<Vec<bf16>>() {
+            d.len()
+        } else if let Some(d) = data.downcast_ref::<Vec<i32>>() {
+            d.len()
+        } else {
+            panic!(
+                "Can't format {:?}, only host f32, f16, bf16 and i32 data",
+                self.data
+            );
+        };
+        assert_eq!(
+            len, n_elements,
+            "Data of {len} elements doesn't match the shape {shape:?}"
+        );
+
+        let cells = if let Some(d) = data.downcast_ref::<Vec<i32>>() {
+            indexes.iter().map(|i| d[*i].to_string()).collect()
+        } else {
+            let values = if let Some(d) = data.downcast_ref::<Vec<f32>>() {
+                indexes.iter().map(|i| d[*i]).collect::<Vec<_>>()
+            } else if let Some(d) = data.downcast_ref::<Vec<f16>>() {
+                indexes.iter().map(|i| d[*i].to_f32()).collect()
+            } else {
+                let d = data.downcast_ref::<Vec<bf16>>().unwrap();
+                indexes.iter().map(|i| d[*i].to_f32()).collect()
+            };
+            format_floats(&values, options.precision)
+        };
+        let width = cells.iter().map(String::len).max().unwrap_or_default();
+
+        let mut out = String::new();
+        render(&mut out, shape, 0, edge, &mut cells.into_iter(), width);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use half::f16;
+
+    use super::PrintOptions;
+    use crate::tensor::Tensor;
+
+    #[test]
+    fn test_format_1d() {
+        let t = Tensor::new(vec![1., -2.5, std::f32::consts::PI]);
+        assert_eq!(t.format(&[3]), "[ 1.0000, -2.5000,  3.1416]");
+
+        let half = Tensor::new(vec![f16::from_f32(0.5), f16::ONE, f16::from_f32(-2.)]);
+        let options = PrintOptions {
+            precision: 2,
+            ..Default::default()
+        };
+        assert_eq!(half.format_with(&[3], &options), "[ 0.50,  1.00, -2.00]");
+
+        let tiny = Tensor::new(vec![1e-6, 2., f32::NAN, f32::NEG_INFINITY]);
+        assert_eq!(
+            tiny.format_with(&[4], &options),
+            "[1.00e-6,  2.00e0,     nan,    -inf]"
+        );
+    }
+
+    #[test]
+    fn test_format_2d() {
+        let t = Tensor::new(vec![1, 2, 3, 40, 5, -6]);
+        assert_eq!(t.format(&[2, 3]), "[[ 1,  2,  3],\n [40,  5, -6]]");
+        // Scalars have no brackets
+        assert_eq!(Tensor::new(vec![7_f32]).format(&[]), "7.0000");
+    }
+
+    #[test]
+    fn test_format_truncated_3d() {
+        let t = Tensor::new((0..60).collect::<Vec<i32>>());
+        let options = PrintOptions {
+            edge_items: 1,
+            threshold: 10,
+            ..Default::default()
+        };
+        assert_eq!(
+            t.format_with(&[3, 4, 5], &options),
+            "[[[ 0, ...,  4],\n  ...,\n  [15, ..., 19]],\n\n ...,\n\n [[40, ..., 44],\n  ...,\n  [55, ..., 59]]]"
+        );
+
+        // With the default options, a large tensor only shows its corners
+        let t = Tensor::new(vec![0.5_f32; 64 * 64 * 64]);
+        let formatted = t.format(&[64, 64, 64]);
+        // 6 blocks of 6 rows and an ellipsis, an ellipsis between them, and blank lines separating all 7
+        assert_eq!(formatted.lines().count(), 6 * 7 + 1 + 6);
+        assert!(formatted.starts_with("[[[0.5000, 0.5000, 0.5000, ..., 0.5000, 0.5000, 0.5000],\n"));
+    }
+}
@@ -1,13 +1,18 @@
 #![allow(clippy::needless_range_loop)]
 
 use crate::{
-    compiler_utils::Compiler,
+    async_execution::PendingExecution,
+    cancellation::Cancelled,
+    compile_stats::{CompileReport, CompileStatsRecorder},
+    compiler_utils::{Compiler, CompilerHint},
     graph_tensor::GraphTensor,
+    memory::{tensor_memory, AllocationRecorder, OutOfMemory},
     op::{self, InputTensor, Operator},
     shape::*,
+    specialize::{ShapeRecord, SpecializationMismatch},
     tensor::Tensor,
 };
-use std::io::Write;
+use std::{fmt::Display, io::Write, rc::Rc};
 
 use colored::Colorize;
 use itertools::Itertools;
@@ -17,8 +22,17 @@ use rustc_hash::{FxHashMap, FxHashSet};
 use super::compiler_utils::{ToIds, ToIdsMut};
 
 pub type MainGraph = StableGraph<Box<dyn Operator>, Dependency>;
+/// The id of a node in a [`Graph`], like a tensor's op
 pub use petgraph::stable_graph::NodeIndex;
+/// Where in the model code a node was created
+pub type SourceLocation = &'static std::panic::Location<'static>;
 
+/// " at file:line:column" for a node's source location, or nothing if it isn't known
+pub(crate) fn created_at(location: Option<SourceLocation>) -> String {
+    location.map(|l| format!(" at {l}")).unwrap_or_default()
+}
+
+/// A graph of ops on tensors, which models are built on, compiled and executed
 #[derive(Debug, Default)]
 pub struct Graph {
     /// The store of tensors in the graph. Indexed by node index and output index.
@@ -31,11 +45,116 @@ pub struct Graph {
     pub no_delete: rustc_hash::FxHashSet<NodeIndex>,
     /// Tensors marked in this set need to be retrieved later (mostly for optimizers to insert copy back calls, the graph itself doesn't treat these differently)
     pub to_retrieve: rustc_hash::FxHashSet<NodeIndex>,
-    /// A list of current node to run, source nodes, and view nodes to delete after execution.
+    /// Keys identifying the source data of tensors loaded from files, by loading node. Nodes loading the same data get the same key, so devices can share a single upload between them
+    pub content_keys: rustc_hash::FxHashMap<NodeIndex, u64>,
+    /// Hints to compilers about how to treat nodes
+    pub compiler_hints: rustc_hash::FxHashMap<NodeIndex, Vec<CompilerHint>>,
+    /// Where each node was created, captured by [`Graph::add_op`] and the tensor constructors. Ops built with the
+    /// frontend report the line of model code that called them.
+    pub source_locations: rustc_hash::FxHashMap<NodeIndex, SourceLocation>,
+    /// The order nodes were added in by [`Graph::add_op`] and the tensor constructors, which breaks ties in the
+    /// execution order between nodes computing the same thing
+    pub(crate) node_order: rustc_hash::FxHashMap<NodeIndex, usize>,
+    /// Nodes added so far, for numbering them in `node_order`
+    pub(crate) nodes_added: usize,
+    /// Inputs holding i32 data rather than f32, like token ids
+    pub int_tensors: rustc_hash::FxHashSet<NodeIndex>,
+    /// Weights filled in by a loader. They keep their tensors after the first execution, so later executions don't load them again
+    pub loaded_weights: rustc_hash::FxHashSet<NodeIndex>,
+    /// The dtype each weight filled in by a loader is stored as in its file, with quantized weights as I8
+    pub weight_dtypes: rustc_hash::FxHashMap<NodeIndex, safetensors::Dtype>,
+    /// Panic when a binary op combines dimensions with different labels, instead of leaving it for [`Graph::lint`] to report
+    pub strict_dim_labels: bool,
+    /// The most bytes the graph's tensors can hold while executing
+    pub memory_limit: Option<usize>,
+    /// The most bytes the graph's tensors held during the last execution, while a memory limit is set
+    pub(crate) peak_memory: usize,
+    /// Records allocations while executing for [`Graph::execute_with_report`]
+    pub(crate) allocation_recorder: Option<AllocationRecorder>,
+    /// Records the shapes seen while executing, between [`Graph::record_shapes`] and [`Graph::take_shape_record`]
+    pub(crate) shape_recorder: Option<ShapeRecord>,
+    /// Dyn dim sizes set by [`Graph::specialize`], which executions have to match
+    pub(crate) specialized_dims: FxHashMap<char, usize>,
+    /// A list of current node to run, source nodes, and view nodes to delete after execution. Shared so executors can
+    /// run ops on the graph while walking it.
     #[allow(clippy::type_complexity)]
-    pub(crate) linearized_graph: Option<Vec<(NodeIndex, Vec<((NodeIndex, u8), ShapeTracker)>)>>,
+    pub(crate) linearized_graph: Option<Rc<Vec<(NodeIndex, Vec<((NodeIndex, u8), ShapeTracker)>)>>>,
     /// Cached consumers (for execution only)
-    consumers_map: Option<FxHashMap<(NodeIndex, u8), usize>>,
+    pub(crate) consumers_map: Option<FxHashMap<(NodeIndex, u8), usize>>,
+    /// The rest of an execution started by [`Graph::execute_async`]
+    pub(crate) pending_execution: Option<PendingExecution>,
+    /// Stats of the passes run so far, while compiling
+    pub(crate) compile_stats: Option<CompileStatsRecorder>,
+}
+
+/// Why [`Graph::try_execute`] stopped before running every op
+#[derive(Debug)]
+pub enum ExecutionError {
+    /// The graph's tensors went over the memory limit
+    OutOfMemory(OutOfMemory),
+    /// An op couldn't run on its inputs
+    OpFailed {
+        node: NodeIndex,
+        /// The name of the op at that node
+        op: String,
+        /// Where the node was created
+        location: Option<SourceLocation>,
+        error: String,
+    },
+    /// A dyn dim was set to a different size than the graph was specialized for
+    SpecializationMismatch(SpecializationMismatch),
+    /// The execution was cancelled before it finished
+    Cancelled(Cancelled),
+}
+
+impl Display for ExecutionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutOfMemory(e) => e.fmt(f),
+            Self::SpecializationMismatch(e) => e.fmt(f),
+            Self::Cancelled(e) => e.fmt(f),
+            Self::OpFailed {
+                node,
+                op,
+                location,
+                error,
+            } => write!(
+                f,
+                "{op} ({node:?}{}) failed: {error}",
+                created_at(*location)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+impl From<OutOfMemory> for ExecutionError {
+    fn from(e: OutOfMemory) -> Self {
+        Self::OutOfMemory(e)
+    }
+}
+
+impl From<SpecializationMismatch> for ExecutionError {
+    fn from(e: SpecializationMismatch) -> Self {
+        Self::SpecializationMismatch(e)
+    }
+}
+
+impl From<Cancelled> for ExecutionError {
+    fn from(e: Cancelled) -> Self {
+        Self::Cancelled(e)
+    }
+}
+
+/// What an execution carries from one op to the next
+#[derive(Debug, Default)]
+pub(crate) struct ExecutionState {
+    /// Consumers of each output that haven't run yet, so outputs are freed after their last one
+    pub(crate) remaining_consumers: FxHashMap<(NodeIndex, u8), usize>,
+    dim_stack: Vec<i32>,
+    /// Keep every output instead of freeing it after its last consumer
+    keep_intermediates: bool,
 }
 
 /// A dependency between two nodes
@@ -116,40 +235,132 @@ impl Graph {
         self.tensors.insert((id, ind), tensor);
     }
 
+    /// Where a node was created, if it was created with [`Graph::add_op`] or a tensor constructor
+    pub fn source_location(&self, node: NodeIndex) -> Option<SourceLocation> {
+        self.source_locations.get(&node).copied()
+    }
+
     /// Set a dynamic dimension
     pub fn set_dyn_dim(&mut self, dimension: char, val: usize) {
         self.dyn_map.insert(dimension, val);
     }
 
     /// Create a new tensor with shape S
+    #[track_caller]
     pub fn tensor<S: Shape>(&mut self) -> GraphTensor<S> {
         self.named_tensor("Tensor")
     }
 
     /// Create a new tensor with shape S and a name. This name will show up on the graph when displayed
+    #[track_caller]
     pub fn named_tensor<S: Shape>(&mut self, name: &str) -> GraphTensor<S> {
+        let location = std::panic::Location::caller();
+        let id = self.graph.add_node(Box::new(op::Function(
+            format!("{name} Load"),
+            Rc::new(move |_| panic!("You must set a value for the tensor created at {location}!")),
+        )));
+        self.source_locations.insert(id, location);
+        self.record_node_order(id);
         GraphTensor {
-            id: self.graph.add_node(Box::new(op::Function(
-                format!("{name} Load"),
-                Box::new(|_| panic!("You must set a value for this tensor!")),
-            ))),
+            id,
             graph_ref: self,
             shape: S::to_tracker(),
             _phantom: Default::default(),
         }
     }
 
-    /// Compile the graph using the given compiler
-    pub fn compile<T: ToIdsMut, C: Compiler>(&mut self, compiler: C, remap: T) {
+    /// Create a new i32 tensor with shape S, for integer inputs like token ids. Set it with `set_int` or `set_int_dyn`
+    #[track_caller]
+    pub fn tensor_i32<S: Shape>(&mut self) -> GraphTensor<S> {
+        self.named_tensor_i32("Tensor")
+    }
+
+    /// Create a new i32 tensor with shape S and a name
+    #[track_caller]
+    pub fn named_tensor_i32<S: Shape>(&mut self, name: &str) -> GraphTensor<S> {
+        let tensor = self.named_tensor(name);
+        self.int_tensors.insert(tensor.id);
+        tensor
+    }
+
+    /// Compile the graph using the given compiler, returning what each pass changed
+    pub fn compile<T: ToIdsMut, C: Compiler>(&mut self, compiler: C, remap: T) -> CompileReport {
+        // Compiles started by a compiler are counted as part of the outer compile
+        let outermost = self.compile_stats.is_none();
+        if outermost {
+            self.start_compile_stats();
+        }
+        // Ops replaced while compiling leave their tensors behind, and new ops can reuse their node indexes, so the
+        // nodes are told apart by the order they were added in
+        let ops_with_tensors = self
+            .tensors
+            .keys()
+            .filter_map(|(n, _)| Some((*n, *self.node_order.get(n)?)))
+            .collect::<FxHashMap<_, _>>();
+        self.begin_compile_pass();
         compiler.compile(self, remap);
+        self.end_compile_pass::<C>();
+        self.tensors.retain(|(n, _), _| {
+            ops_with_tensors.get(n).is_none_or(|order| {
+                self.graph.contains_node(*n) && self.node_order.get(n) == Some(order)
+            })
+        });
         self.toposort();
+        if outermost {
+            self.finish_compile_stats()
+        } else {
+            CompileReport::default()
+        }
+    }
+
+    /// Number a new node in the order nodes were added
+    pub(crate) fn record_node_order(&mut self, node: NodeIndex) {
+        self.node_order.insert(node, self.nodes_added);
+        self.nodes_added += 1;
+    }
+
+    /// Nodes in the order they run: a depth-first topological order, with ties between independent nodes broken by
+    /// the order the nodes were added rather than by their indexes, which depend on which nodes were removed before.
+    pub(crate) fn execution_order(&self) -> Vec<NodeIndex> {
+        let rank = |n: &NodeIndex| {
+            let order = self.node_order.get(n).copied().unwrap_or(usize::MAX);
+            (order, n.index())
+        };
+
+        // Reverse postorder, visiting lower ranked nodes so they end up first
+        let mut visited = FxHashSet::default();
+        let mut finished = FxHashSet::default();
+        let mut order = vec![];
+        let mut stack = vec![];
+        for start in self.graph.node_indices().sorted_by_key(rank).rev() {
+            if visited.contains(&start) {
+                continue;
+            }
+            stack.push(start);
+            while let Some(&node) = stack.last() {
+                if visited.insert(node) {
+                    stack.extend(
+                        self.graph
+                            .neighbors_directed(node, Direction::Outgoing)
+                            .filter(|n| !visited.contains(n))
+                            .sorted_by_key(rank),
+                    );
+                } else {
+                    stack.pop();
+                    if finished.insert(node) {
+                        order.push(node);
+                    }
+                }
+            }
+        }
+        order.reverse();
+        order
     }
 
     /// Refresh the internally sorted graph
     pub(crate) fn toposort(&mut self) {
-        self.linearized_graph = Some(
-            petgraph::algo::toposort(&self.graph, None)
-                .unwrap()
+        self.linearized_graph = Some(Rc::new(
+            self.execution_order()
                 .into_iter()
                 .map(|node| {
                     (
@@ -163,7 +374,7 @@ impl Graph {
                     )
                 })
                 .collect(),
-        );
+        ));
         self.create_remaining_consumers_map();
     }
 
@@ -203,87 +414,172 @@ impl Graph {
         self.tensors.retain(|(n, _), _| self.no_delete.contains(n));
     }
 
+    /// Drop the tensors of loaded weights, so the next execution loads them again (for instance after the files changed)
+    pub fn reload_weights(&mut self) {
+        self.tensors
+            .retain(|(n, _), _| !self.loaded_weights.contains(n));
+    }
+
     /// Execute the graph.
     pub fn execute(&mut self) {
-        // Track the number of views pointing to each tensor so we know when to clear
+        if let Err(e) = self.try_execute() {
+            panic!("{e}");
+        }
+    }
+
+    /// Execute the graph, stopping if an op fails, the tensors go over the memory limit, or the graph was specialized
+    /// and a dyn dim is set to a different size
+    pub fn try_execute(&mut self) -> Result<(), ExecutionError> {
+        let mut state = self.start_execution(false)?;
+        let order = self.linearized_graph.clone().unwrap();
+        let result = order.iter().try_for_each(|(node, src_ids)| {
+            self.execute_node(*node, src_ids, &mut state, |op, srcs| op.try_process(srcs))
+                .map(|_| ())
+        });
+        self.end_execution();
+        result
+    }
+
+    /// Get ready to execute: finish a pending execution, check the specialized dims, sort the graph if it changed and
+    /// start the recorders. `keep_intermediates` keeps every output instead of freeing it after its last consumer.
+    pub(crate) fn start_execution(
+        &mut self,
+        keep_intermediates: bool,
+    ) -> Result<ExecutionState, ExecutionError> {
+        self.finish_execution();
+        self.check_specialized_dims()?;
         if self.linearized_graph.is_none() {
             self.toposort();
         }
-        let mut remaining_consumers = self.consumers_map.as_ref().unwrap().clone();
-        let mut dim_stack = Vec::new();
-
-        for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
-            if self.tensors.contains_key(&(*node, 0)) {
-                continue;
-            }
+        self.peak_memory = 0;
+        if let Some(recorder) = &mut self.allocation_recorder {
+            recorder.start(&self.tensors);
+        }
+        if let Some(recorder) = &mut self.shape_recorder {
+            recorder.start_run(&self.dyn_map);
+        }
+        Ok(ExecutionState {
+            remaining_consumers: self.consumers_map.clone().unwrap(),
+            keep_intermediates,
+            ..Default::default()
+        })
+    }
 
-            let mut srcs = Vec::new();
+    /// Run a node of the linearized graph, the step every executor takes for each op. The node's inputs are gathered
+    /// with their dyn dims resolved and recorded, `process` runs the op on them, and its outputs are stored. Inputs
+    /// no other op needs are freed, and the memory limit is checked. Returns false if the node's outputs were already
+    /// there, so it didn't run.
+    pub(crate) fn execute_node(
+        &mut self,
+        node: NodeIndex,
+        src_ids: &[((NodeIndex, u8), ShapeTracker)],
+        state: &mut ExecutionState,
+        process: impl FnOnce(
+            &mut dyn Operator,
+            Vec<(InputTensor, ShapeTracker)>,
+        ) -> Result<Vec<Tensor>, op::OpError>,
+    ) -> Result<bool, ExecutionError> {
+        if self.tensors.contains_key(&(node, 0)) {
+            return Ok(false);
+        }
+        let mut srcs = Vec::with_capacity(src_ids.len());
+        if state.keep_intermediates {
+            srcs.extend(
+                src_ids
+                    .iter()
+                    .map(|(id, st)| (InputTensor::Borrowed(self.tensors.get(id).unwrap()), *st)),
+            );
+        } else {
             get_source_tensors(
                 &self.no_delete,
                 &mut self.tensors,
                 src_ids,
-                &remaining_consumers,
+                &state.remaining_consumers,
                 &mut srcs,
             );
+        }
 
-            // Substitute in the dyn dims
-            for (_, st) in srcs.iter_mut() {
-                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
-            }
+        // Substitute in the dyn dims
+        for (_, st) in srcs.iter_mut() {
+            st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut state.dim_stack);
+        }
+        if let Some(recorder) = &mut self.shape_recorder {
+            recorder.record(node, &srcs);
+        }
 
-            // Execute
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
-            for (i, tensor) in tensors.into_iter().enumerate() {
-                self.tensors.insert((*node, i as u8), tensor);
+        let op = self.graph.node_weight_mut(node).unwrap();
+        let tensors = match process(op.as_mut(), srcs) {
+            Ok(tensors) => tensors,
+            Err(error) => {
+                let op = format!("{op:?}");
+                return Err(ExecutionError::OpFailed {
+                    node,
+                    op,
+                    location: self.source_location(node),
+                    error: error.to_string(),
+                });
             }
+        };
+        for (i, tensor) in tensors.into_iter().enumerate() {
+            self.tensors.insert((node, i as u8), tensor);
+        }
+
+        // Bookkeep remaining consumers
+        for (source, _) in src_ids {
+            *state.remaining_consumers.get_mut(source).unwrap() -= 1;
+        }
 
-            // Bookkeep remaining consumers
-            for (source, _) in src_ids {
-                *remaining_consumers.get_mut(source).unwrap() -= 1;
+        if let Some(recorder) = &mut self.allocation_recorder {
+            recorder.step(&self.tensors);
+        }
+        if let Some(limit) = self.memory_limit {
+            let used = tensor_memory(self.tensors.values());
+            self.peak_memory = self.peak_memory.max(used);
+            if used > limit {
+                return Err(OutOfMemory {
+                    node,
+                    op: format!("{:?}", self.graph.node_weight(node).unwrap()),
+                    location: self.source_location(node),
+                    used,
+                    limit,
+                }
+                .into());
             }
         }
+        Ok(true)
+    }
+
+    /// Finish an execution, freeing the tensors that aren't kept
+    pub(crate) fn end_execution(&mut self) {
         self.reset();
+        if let Some(recorder) = &mut self.allocation_recorder {
+            recorder.finish(&self.tensors);
+        }
     }
 
     /// Execute the graph without deleting intermediate tensors
     pub fn execute_no_delete(&mut self) {
-        // Track the number of views pointing to each tensor so we know when to clear;
-        if self.linearized_graph.is_none() {
-            self.toposort();
+        let result = self.start_execution(true).and_then(|mut state| {
+            let order = self.linearized_graph.clone().unwrap();
+            order.iter().try_for_each(|(node, src_ids)| {
+                self.execute_node(*node, src_ids, &mut state, |op, srcs| op.try_process(srcs))
+                    .map(|_| ())
+            })
+        });
+        if let Some(recorder) = &mut self.allocation_recorder {
+            recorder.finish(&self.tensors);
         }
-        let mut dim_stack = Vec::new();
-        for (node, src_ids) in self.linearized_graph.as_ref().unwrap().iter() {
-            if self.tensors.contains_key(&(*node, 0)) {
-                continue;
-            }
-            let mut srcs = src_ids
-                .iter()
-                .map(|(id, st)| (InputTensor::Borrowed(self.tensors.get(id).unwrap()), *st))
-                .collect_vec();
-
-            // Substitute in the dyn dims
-            for (_, st) in srcs.iter_mut() {
-                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
-            }
-
-            // All sources are ready, execute
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
-            for (i, tensor) in tensors.into_iter().enumerate() {
-                self.tensors.insert((*node, i as u8), tensor);
-            }
+        if let Err(e) = result {
+            panic!("{e}");
         }
     }
 
     /// Execute the graph with debug prints
     pub fn execute_debug(&mut self) {
-        // Track the number of views pointing to each tensor so we know when to clear
-        if self.linearized_graph.is_none() {
-            self.toposort();
-        }
-        let mut dim_stack = Vec::new();
-        let tensors_ptr = &mut self.tensors as *mut _;
-        let mut remaining_consumers = self.consumers_map.as_ref().unwrap().clone();
-        let mut op_times = FxHashMap::default();
+        let mut state = self
+            .start_execution(false)
+            .unwrap_or_else(|e| panic!("{e}"));
+        let mut op_times = FxHashMap::<_, std::time::Duration>::default();
 
         println!(
             "{:->2$} Executing {:->2$}",
@@ -292,73 +588,60 @@ impl Graph {
             (term_size::dimensions().unwrap().0 - " Executing ".len()) / 2
         );
         let start = std::time::Instant::now();
-        for (node, src_ids) in self.linearized_graph.as_ref().unwrap().iter() {
-            if self.tensors.contains_key(&(*node, 0)) {
-                continue;
-            }
-            let op_name = format!("{:?}", self.graph.node_weight(*node).unwrap());
-            print!("{}", op_name.bold().bright_green());
-
-            let mut srcs = Vec::new();
-            get_source_tensors(
-                &self.no_delete,
-                tensors_ptr,
-                src_ids,
-                &remaining_consumers,
-                &mut srcs,
-            );
-
-            // Substitute in the dyn dims
-            for (_, st) in srcs.iter_mut() {
-                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
-            }
-
-            // All sources are ready
-            let mut shapes_string = srcs
-                .iter()
-                .map(|(_, s)| {
-                    format!(
-                        "{:?}",
-                        s.shape()
-                            .into_iter()
-                            .map(|i| i.to_usize().unwrap())
-                            .collect::<Vec<_>>()
-                    )
-                })
-                .join(", ");
-            if !shapes_string.is_empty() {
-                shapes_string = format!(" ({shapes_string})");
-            }
-            print!("{shapes_string}");
-            std::io::stdout().flush().unwrap();
-            // Execute
-            let now = std::time::Instant::now();
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
-            let elapsed = now.elapsed();
-            println!(
-                "{:.>1$}",
-                if elapsed.as_secs() > 0 {
-                    format!("{:.2}s", elapsed.as_secs_f32())
-                } else if elapsed.as_millis() > 0 {
-                    format!("{}ms", elapsed.as_millis())
-                } else {
-                    format!("{}µs", elapsed.as_micros())
+        let order = self.linearized_graph.clone().unwrap();
+        for (node, src_ids) in order.iter() {
+            let location = self.source_location(*node);
+            let result = self.execute_node(*node, src_ids, &mut state, |op, srcs| {
+                let op_name = format!("{op:?}");
+                print!("{}", op_name.bold().bright_green());
+
+                let mut shapes_string = srcs
+                    .iter()
+                    .map(|(_, s)| {
+                        let shape = format!(
+                            "{:?}",
+                            s.shape()
+                                .into_iter()
+                                .map(|i| i.to_usize().unwrap())
+                                .collect::<Vec<_>>()
+                        );
+                        if s.is_labeled() {
+                            format!("{shape} {}", s.format_labels())
+                        } else {
+                            shape
+                        }
+                    })
+                    .join(", ");
+                if !shapes_string.is_empty() {
+                    shapes_string = format!(" ({shapes_string})");
                 }
-                .bold(),
-                term_size::dimensions().unwrap().0 - op_name.len() - shapes_string.len(),
-            );
-            for (i, tensor) in tensors.into_iter().enumerate() {
-                self.tensors.insert((*node, i as u8), tensor);
-            }
-            if let Some(t) = op_times.get_mut(&op_name) {
-                *t += elapsed;
-            } else {
-                op_times.insert(op_name, elapsed);
-            }
-
-            // Check if we can delete the source tensors now
-            for (source, _) in src_ids {
-                *remaining_consumers.get_mut(source).unwrap() -= 1;
+                if let Some(location) = location {
+                    shapes_string.push_str(&format!(" {}:{}", location.file(), location.line()));
+                }
+                print!("{shapes_string}");
+                std::io::stdout().flush().unwrap();
+                // Execute
+                let now = std::time::Instant::now();
+                let tensors = op.try_process(srcs);
+                let elapsed = now.elapsed();
+                println!(
+                    "{:.>1$}",
+                    if elapsed.as_secs() > 0 {
+                        format!("{:.2}s", elapsed.as_secs_f32())
+                    } else if elapsed.as_millis() > 0 {
+                        format!("{}ms", elapsed.as_millis())
+                    } else {
+                        format!("{}µs", elapsed.as_micros())
+                    }
+                    .bold(),
+                    term_size::dimensions().unwrap().0 - op_name.len() - shapes_string.len(),
+                );
+                *op_times.entry(op_name).or_default() += elapsed;
+                tensors
+            });
+            if let Err(e) = result {
+                self.end_execution();
+                panic!("{e}");
             }
         }
 
@@ -396,12 +679,12 @@ impl Graph {
             }
             .bold()
         );
-        self.reset();
+        self.end_execution();
     }
 }
 
 /// Get source tensor array for a node
-fn get_source_tensors(
+pub(crate) fn get_source_tensors(
     no_delete: &FxHashSet<NodeIndex>,
     tensors: *mut FxHashMap<(NodeIndex, u8), Tensor>,
     src_ids: &[((NodeIndex, u8), ShapeTracker)],
@@ -422,3 +705,169 @@ fn get_source_tensors(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::tensor::Tensor;
+    crate::test_imports!();
+
+    /// The ops of a graph in the order they run
+    fn op_sequence(cx: &mut Graph) -> Vec<String> {
+        cx.toposort();
+        cx.linearized_graph
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|(node, _)| format!("{:?}", cx.graph.node_weight(*node).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_execution_order_is_deterministic() {
+        // Two independent branches joined at the end. Removing nodes first makes the graph reuse their indexes, so
+        // the same graph gets different indexes.
+        let build = |removed: usize| {
+            let mut cx = Graph::new();
+            for id in (0..removed)
+                .map(|_| cx.tensor::<R0>().id)
+                .collect::<Vec<_>>()
+            {
+                cx.graph.remove_node(id);
+            }
+            let a = cx.named_tensor::<R1<4>>("A");
+            let b = cx.named_tensor::<R1<4>>("B");
+            let x = a.exp2().sin();
+            let y = (b.sqrt() * a).recip();
+            (x + y).retrieve();
+            cx
+        };
+        let mut first = build(0);
+        let sequence = op_sequence(&mut first);
+        assert_eq!(sequence.len(), 8);
+        for removed in 1..4 {
+            assert_eq!(sequence, op_sequence(&mut build(removed)));
+        }
+    }
+
+    /// Doubles f32 data, and fails on any other data
+    #[derive(Debug, Clone, PartialEq)]
+    struct DoubleF32;
+
+    impl Operator for DoubleF32 {
+        fn try_process(
+            &mut self,
+            inp: Vec<(InputTensor, ShapeTracker)>,
+        ) -> Result<Vec<Tensor>, OpError> {
+            let Some(data) = inp[0].0.borrowed().data.as_any().downcast_ref::<Vec<f32>>() else {
+                return Err("Expected f32 data".into());
+            };
+            Ok(vec![Tensor::new(
+                data.iter().map(|v| v * 2.).collect::<Vec<_>>(),
+            )])
+        }
+    }
+
+    #[test]
+    fn test_try_execute_op_error() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<2>>().set(vec![1., 2.]);
+        let ints = cx
+            .add_op(op::Function(
+                "Ints".to_string(),
+                Rc::new(|_| vec![Tensor::new(vec![1_i32, 2])]),
+            ))
+            .finish();
+        let doubled = cx.add_op(DoubleF32).input(a.id, 0, a.shape).finish();
+        let line = line!() + 1;
+        let failing = cx.add_op(DoubleF32).input(ints, 0, a.shape).finish();
+        cx.no_delete.extend([doubled, failing]);
+
+        let error = cx.try_execute().unwrap_err();
+        // The error points at the line that built the op
+        assert!(
+            error.to_string().contains(&format!("{}:{line}:", file!())),
+            "{error}"
+        );
+        let ExecutionError::OpFailed {
+            node, op, error, ..
+        } = error
+        else {
+            panic!("Expected an op to fail, got {error}");
+        };
+        assert_eq!((node, op.as_str()), (failing, "DoubleF32"));
+        assert_eq!(error, "Expected f32 data");
+
+        // Executing panics with the same message
+        let message = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cx.execute()))
+            .unwrap_err()
+            .downcast::<String>()
+            .unwrap();
+        assert!(
+            message.contains("DoubleF32 (") && message.contains("Expected f32 data"),
+            "{message}"
+        );
+    }
+
+    /// Replaces the op of the tensor with a sin of its input, which takes the removed op's node index
+    #[derive(Debug, Default)]
+    struct ReplaceWithSin;
+
+    impl Compiler for ReplaceWithSin {
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut remap: T) {
+            let node = *remap.to_ids_mut()[0];
+            let (input, shape) = graph
+                .get_sources(node)
+                .into_iter()
+                .map(|(n, _, sh)| (n, sh))
+                .next()
+                .unwrap();
+            graph.graph.remove_node(node);
+            let sin = graph.add_op(op::Sin).input(input, 0, shape).finish();
+            assert_eq!(sin, node);
+        }
+    }
+
+    #[test]
+    fn test_compile_drops_tensors_of_replaced_ops() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<2>>().set(vec![1., 2.]).keep();
+        let mut b = a.exp2().retrieve();
+        cx.execute();
+        assert_close(&b.data(), &[2., 4.]);
+
+        // Exp2 and Sin are both zero-sized, so the new op can't be told apart from the old one by its address
+        cx.compile(ReplaceWithSin, &mut b);
+        cx.execute();
+        assert_close(&b.data(), &[1_f32.sin(), 2_f32.sin()]);
+    }
+
+    /// Host overhead of executing a graph of small ops, run with `cargo test --release -- --ignored`
+    #[test]
+    #[ignore]
+    fn bench_execute_overhead() {
+        const NODES: usize = 2000;
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(random_vec(4)).keep();
+        let mut x = a;
+        // Each step adds a constant and a binary op
+        for _ in 0..NODES / 2 {
+            x = x * 0.5;
+        }
+        let mut out = x.retrieve();
+        cx.compile(CPUCompiler::default(), &mut out);
+        cx.execute();
+        let runs = 200;
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            out.drop();
+            cx.execute();
+        }
+        println!(
+            "{} nodes: {:?} per execute",
+            cx.graph.node_count(),
+            start.elapsed() / runs
+        );
+    }
+}
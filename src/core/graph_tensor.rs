@@ -1,14 +1,18 @@
 use crate::{
+    compiler_utils::CompilerHint,
+    format::PrintOptions,
     graph::Graph,
     op::{self, Function},
     prelude::Data,
-    shape::*,
+    shape::{symbolic::BigExpression, *},
     tensor::Tensor,
 };
 use std::marker::PhantomData;
+use std::rc::Rc;
 use std::{fmt::Debug, path::Path};
 
 use petgraph::graph::NodeIndex;
+use rustc_hash::FxHashMap;
 
 /// A tensor on the graph.
 ///
@@ -53,6 +57,16 @@ impl<S: Shape> GraphTensor<S> {
         self
     }
 
+    /// Give compilers a hint about how to treat this tensor's node
+    pub fn hint(self, hint: CompilerHint) -> Self {
+        self.graph()
+            .compiler_hints
+            .entry(self.id)
+            .or_default()
+            .push(hint);
+        self
+    }
+
     /// Remove this tensor's data from the graph.
     pub fn drop(&self) {
         self.graph().drop_tensors(self.id);
@@ -95,7 +109,7 @@ impl<S: Shape> GraphTensor<S> {
             .downcast_mut::<Function>()
             .unwrap();
         // We shouldn't do cloning here!
-        node.1 = Box::new(move |_| {
+        node.1 = Rc::new(move |_| {
             vec![Tensor {
                 data: Box::new(data.clone()),
             }]
@@ -103,6 +117,11 @@ impl<S: Shape> GraphTensor<S> {
         self
     }
 
+    /// Set the value of an i32 tensor with dynamic dimensions from integer data, like u32 or i64 token ids
+    pub fn set_int_dyn<T: Copy + Debug + TryInto<i32>>(self, data: &[T], shape: &[usize]) -> Self {
+        self.set_dyn(to_i32_vec(data), shape)
+    }
+
     /// Set the name of a tensor
     pub fn set_name(&self, name: &str) {
         let node = self
@@ -116,17 +135,25 @@ impl<S: Shape> GraphTensor<S> {
         node.0 = name.to_string();
     }
 
-    /// Print the value of this tensor when the graph is ran
+    /// Print the value of this tensor when the graph is ran, with large tensors truncated to their corners
+    #[track_caller]
     pub fn print<T: ToString>(&self, message: T) {
+        self.print_with(message, PrintOptions::default())
+    }
+
+    /// Print the value of this tensor when the graph is ran, with the given precision and truncation
+    #[track_caller]
+    pub fn print_with<T: ToString>(&self, message: T, options: PrintOptions) {
         let id = self
             .graph()
-            .add_op(op::Print(message.to_string()))
+            .add_op(op::Print(message.to_string(), options))
             .input(self.id, 0, self.shape)
             .finish();
         self.graph().no_delete.insert(id);
     }
 
     /// Check the tensor value against a binary file
+    #[track_caller]
     pub fn diff<T: AsRef<Path>>(&self, file: T, threshold: f32) {
         let id = self
             .graph()
@@ -136,6 +163,53 @@ impl<S: Shape> GraphTensor<S> {
         self.graph().no_delete.insert(id);
     }
 
+    /// Call `f` with the value and shape of this tensor every time the graph runs, on the host
+    #[track_caller]
+    pub fn tap<T: ToString>(&self, name: T, f: impl FnMut(&[f32], &[usize]) + 'static) {
+        let id = self
+            .graph()
+            .add_op(op::Tap(name.to_string(), Box::new(f)))
+            .input(self.id, 0, self.shape)
+            .finish();
+        self.graph().no_delete.insert(id);
+    }
+
+    /// The name of an input tensor, as given to `named_tensor` or `set_name`. Other tensors have no name.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.named_tensor::<R1<3>>("Weight");
+    /// assert_eq!(a.name().as_deref(), Some("Weight"));
+    /// assert_eq!(a.sin().name(), None);
+    /// ```
+    pub fn name(&self) -> Option<String> {
+        let name = &self
+            .graph()
+            .graph
+            .node_weight(self.id)?
+            .as_any()
+            .downcast_ref::<Function>()?
+            .0;
+        Some(name.strip_suffix(" Load").unwrap_or(name).to_string())
+    }
+
+    /// The size of each dimension, after any views. Dynamic dimensions are symbolic.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<(Dyn<'s'>, Const<4>)>();
+    /// let b = a.permute::<_, Axes2<1, 0>>();
+    /// assert_eq!(b.dims(), vec![BigExpression::from(4), BigExpression::from('s')]);
+    /// ```
+    pub fn dims(&self) -> Vec<BigExpression> {
+        self.shape.shape()
+    }
+
+    /// The number of dimensions
+    pub fn rank(&self) -> usize {
+        self.shape.len()
+    }
+
     /// Convert tensor to a shapeless tensor
     pub fn no_shape(self) -> GraphTensor<()> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
@@ -143,11 +217,35 @@ impl<S: Shape> GraphTensor<S> {
 
     /// Get the contiguous data of the tensor
     pub fn data(&self) -> Vec<f32> {
+        let graph = self.graph();
+        graph.finish_execution();
+        self.data_from(graph.get_tensor_ref(self.id, 0).unwrap(), &graph.dyn_map)
+    }
+
+    /// Get the contiguous data of an i32 tensor, like sampled token ids
+    pub fn int_data(&self) -> Vec<i32> {
+        let graph = self.graph();
+        graph.finish_execution();
+        let Some(data) = graph
+            .get_tensor_ref(self.id, 0)
+            .unwrap()
+            .data
+            .as_any()
+            .downcast_ref::<Vec<i32>>()
+        else {
+            panic!("Tensor {:?} doesn't hold i32 data", self.id);
+        };
+        let mut st = self.shape;
+        st.resolve_global_dyn_dims(&graph.dyn_map);
+        op::gather(data, &st)
+    }
+
+    /// Get the contiguous data of a tensor with this tensor's shape
+    pub(crate) fn data_from(&self, tensor: &Tensor, dyn_map: &FxHashMap<char, usize>) -> Vec<f32> {
         let mut st = self.shape;
-        st.resolve_global_dyn_dims(&self.graph().dyn_map);
-        let tensor = self.graph().get_tensor_ref(self.id, 0).unwrap();
+        st.resolve_global_dyn_dims(dyn_map);
         let orig_data = tensor.data.as_any().downcast_ref::<Vec<f32>>().unwrap();
-        let mut data = vec![0.; st.n_elements().to_usize().unwrap()];
+        let mut data = vec![0.; st.n_elements_or_zero().to_usize().unwrap()];
         let ind = st.index_expression();
         let val = st.valid_expression();
         #[allow(unused_mut)]
@@ -160,6 +258,22 @@ impl<S: Shape> GraphTensor<S> {
     }
 }
 
+impl GraphTensor<R0> {
+    /// Get the value of a scalar tensor, like a loss or a norm. The tensor needs to be marked with `.retrieve()`
+    /// before the graph executes, so backends copy it back to the host.
+    pub fn item(&self) -> f32 {
+        let graph = self.graph();
+        graph.finish_execution();
+        let Some(tensor) = graph.get_tensor_ref(self.id, 0) else {
+            panic!(
+                "Scalar tensor {:?} has no value. Call .retrieve() on it before executing the graph, and .item() after.",
+                self.id
+            );
+        };
+        self.data_from(tensor, &graph.dyn_map)[0]
+    }
+}
+
 impl<S: ConstShape> GraphTensor<S> {
     /// Set the value of the tensor matching the constant shape
     pub fn set<T: Data + Clone, D: ToData<S, T>>(self, data: D) -> Self {
@@ -173,7 +287,29 @@ impl<S: ConstShape> GraphTensor<S> {
             .unwrap();
         let data = data.to_data_vec();
         // We shouldn't do cloning here!
-        node.1 = Box::new(move |_| vec![Tensor::new(data.clone())]);
+        node.1 = Rc::new(move |_| vec![Tensor::new(data.clone())]);
+        self
+    }
+
+    /// Set the value of an i32 tensor from integer data, like u32 or i64 token ids
+    pub fn set_int<T: Copy + Debug + TryInto<i32>>(self, data: &[T]) -> Self {
+        assert_eq!(
+            data.len(),
+            <S as ConstShape>::realized_shape()
+                .iter()
+                .product::<usize>(),
+            "Number of elements doesn't match the shape!"
+        );
+        let data = to_i32_vec(data);
+        let node = self
+            .graph()
+            .graph
+            .node_weight_mut(self.id)
+            .unwrap()
+            .as_any_mut()
+            .downcast_mut::<Function>()
+            .unwrap();
+        node.1 = Rc::new(move |_| vec![Tensor::new(data.clone())]);
         self
     }
 
@@ -189,7 +325,7 @@ impl<S: ConstShape> GraphTensor<S> {
             .unwrap();
 
         // Set the closure here
-        node.1 = Box::new(move |_| {
+        node.1 = Rc::new(move |_| {
             vec![Tensor {
                 data: Box::new(loader()),
             }]
@@ -200,6 +336,15 @@ impl<S: ConstShape> GraphTensor<S> {
     }
 }
 
+fn to_i32_vec<T: Copy + Debug + TryInto<i32>>(data: &[T]) -> Vec<i32> {
+    data.iter()
+        .map(|i| {
+            (*i).try_into()
+                .unwrap_or_else(|_| panic!("Integer {i:?} doesn't fit in an i32"))
+        })
+        .collect()
+}
+
 fn pretty_print_tensor_recursive(
     f: &mut std::fmt::Formatter<'_>,
     data: &[f32],
@@ -302,6 +447,7 @@ impl<S: Shape> Debug for GraphTensor<S> {
     }
 }
 
+/// Mark a collection of tensors at once, like the weights of a model
 pub trait MarkTensors {
     /// Mark all tensors in this collection to be kept
     fn keep(&self);
@@ -417,6 +563,7 @@ tuple_impls!(
     [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
 );
 
+/// Data a tensor of shape `S` can be set to, like a `Vec<f32>` or nested arrays matching the shape
 pub trait ToData<S: Shape, T> {
     fn to_data_vec(self) -> T;
 }
@@ -0,0 +1,381 @@
+use std::fmt::Display;
+
+use itertools::Itertools;
+use petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    graph::{created_at, Graph, SourceLocation},
+    op::{Add, Constant, ConstantValue, Function, LessThan, Mod, Mul},
+};
+
+/// A likely mistake in a graph, found by [`Graph::lint`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintWarning {
+    /// An input with no path to any retrieved output
+    UnusedInput {
+        node: NodeIndex,
+        name: String,
+        location: Option<SourceLocation>,
+    },
+    /// A weight in the state dict with no path to any retrieved output
+    OrphanedWeight {
+        node: NodeIndex,
+        name: String,
+        location: Option<SourceLocation>,
+    },
+    /// A dynamic dimension with a value set that no shape in the graph uses
+    UnusedDynDim(char),
+    /// An i32 input feeding an op that only accepts f32 inputs
+    IntInputToFloatOp {
+        input: NodeIndex,
+        op: NodeIndex,
+        op_name: String,
+        /// Where the op was created
+        location: Option<SourceLocation>,
+    },
+    /// An elementwise binary op combining dimensions with different labels on the same axis
+    MismatchedDimLabels {
+        op: NodeIndex,
+        axis: usize,
+        lhs: String,
+        rhs: String,
+        location: Option<SourceLocation>,
+    },
+}
+
+impl Display for LintWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LintWarning::UnusedInput {
+                node,
+                name,
+                location,
+            } => write!(
+                f,
+                "Input \"{name}\" ({node:?}{}) isn't used by any retrieved output",
+                created_at(*location)
+            ),
+            LintWarning::OrphanedWeight {
+                node,
+                name,
+                location,
+            } => write!(
+                f,
+                "Weight \"{name}\" ({node:?}{}) isn't used by any retrieved output",
+                created_at(*location)
+            ),
+            LintWarning::UnusedDynDim(dim) => {
+                write!(f, "Dynamic dimension '{dim}' is set but never used")
+            }
+            LintWarning::IntInputToFloatOp {
+                input,
+                op,
+                op_name,
+                location,
+            } => write!(
+                f,
+                "Integer input {input:?} feeds {op_name} ({op:?}{}), which only accepts f32 inputs",
+                created_at(*location)
+            ),
+            LintWarning::MismatchedDimLabels {
+                op,
+                axis,
+                lhs,
+                rhs,
+                location,
+            } => write!(
+                f,
+                "Binary op {op:?}{} combines axis {axis} labeled \"{lhs}\" with one labeled \"{rhs}\"",
+                created_at(*location)
+            ),
+        }
+    }
+}
+
+/// Lint warnings promoted to an error by [`Graph::deny_lints`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintError(pub Vec<LintWarning>);
+
+impl Display for LintError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Graph has {} lint warnings:", self.0.len())?;
+        for warning in &self.0 {
+            write!(f, "\n  {warning}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LintError {}
+
+impl Graph {
+    /// Find inputs and weights that don't contribute to any retrieved output, dyn dims that are set but never used, and
+    /// binary ops between dimensions with different labels.
+    ///
+    /// `weights` is the state dict of the model (from `state_dict`), used to name weights in the warnings. Warnings are sorted by node.
+    pub fn lint(&self, weights: &FxHashMap<String, NodeIndex>) -> Vec<LintWarning> {
+        // Walk backwards from the retrieved outputs
+        let mut used = FxHashSet::default();
+        let mut stack = self.to_retrieve.iter().copied().collect_vec();
+        while let Some(node) = stack.pop() {
+            if used.insert(node) {
+                stack.extend(self.graph.neighbors_directed(node, Direction::Incoming));
+            }
+        }
+
+        let weight_names = weights
+            .iter()
+            .map(|(name, node)| (*node, name))
+            .collect::<FxHashMap<_, _>>();
+        let mut warnings = self
+            .graph
+            .node_indices()
+            .filter(|n| !used.contains(n))
+            .sorted()
+            .filter_map(|node| {
+                if let Some(name) = weight_names.get(&node) {
+                    return Some(LintWarning::OrphanedWeight {
+                        node,
+                        name: name.to_string(),
+                        location: self.source_location(node),
+                    });
+                }
+                let function = self
+                    .graph
+                    .node_weight(node)?
+                    .as_any()
+                    .downcast_ref::<Function>()?;
+                if self
+                    .graph
+                    .edges_directed(node, Direction::Incoming)
+                    .next()
+                    .is_some()
+                {
+                    return None;
+                }
+                Some(LintWarning::UnusedInput {
+                    node,
+                    name: function
+                        .0
+                        .strip_suffix(" Load")
+                        .unwrap_or(&function.0)
+                        .to_string(),
+                    location: self.source_location(node),
+                })
+            })
+            .collect_vec();
+
+        // Dyn dims can appear in edge shapes and in constant expressions
+        let mut used_dims = FxHashSet::default();
+        for edge in self.graph.edge_weights() {
+            if let Some((_, _, shape)) = edge.as_data() {
+                for expr in shape
+                    .dims
+                    .iter()
+                    .chain(shape.slices.iter().flat_map(|(a, b)| [a, b]))
+                    .chain(shape.padding.iter().flat_map(|(a, b)| [a, b]))
+                {
+                    used_dims.extend(expr.to_symbols());
+                }
+            }
+        }
+        for node in self.graph.node_weights() {
+            if let Some(Constant(ConstantValue::Expression(expr), _)) =
+                node.as_any().downcast_ref::<Constant>()
+            {
+                used_dims.extend(expr.to_symbols());
+            }
+        }
+        warnings.extend(
+            self.dyn_map
+                .keys()
+                .filter(|d| !used_dims.contains(*d))
+                .sorted()
+                .map(|d| LintWarning::UnusedDynDim(*d)),
+        );
+
+        // Binary ops should combine dimensions with the same meaning
+        for op in self.graph.node_indices().sorted() {
+            let operator = self.graph[op].as_any();
+            if !(operator.is::<Add>()
+                || operator.is::<Mul>()
+                || operator.is::<Mod>()
+                || operator.is::<LessThan>())
+            {
+                continue;
+            }
+            let srcs = self.get_sources(op);
+            if let [(_, _, a), (_, _, b)] = srcs.as_slice() {
+                if let Some((axis, lhs, rhs)) = a.mismatched_label(b) {
+                    warnings.push(LintWarning::MismatchedDimLabels {
+                        op,
+                        axis,
+                        lhs: lhs.to_string(),
+                        rhs: rhs.to_string(),
+                        location: self.source_location(op),
+                    });
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Check that i32 inputs only feed ops that accept integer inputs, like gathers and device copies.
+    ///
+    /// Ops declare this by answering the "int_inputs" custom key. Uncompiled graphs run their gathers as f32
+    /// primitives, so this usually only passes after compiling with a backend that fuses gathers.
+    pub fn check_int_inputs(&mut self) -> Result<(), LintError> {
+        let mut warnings = vec![];
+        for input in self.int_tensors.iter().copied().sorted() {
+            let consumers = self
+                .graph
+                .edges_directed(input, Direction::Outgoing)
+                .filter(|e| !e.weight().is_schedule())
+                .map(|e| e.target())
+                .unique()
+                .sorted()
+                .collect_vec();
+            for op in consumers {
+                let location = self.source_location(op);
+                let operator = self.graph.node_weight_mut(op).unwrap();
+                if operator.custom("int_inputs", Box::new(())).is_none() {
+                    warnings.push(LintWarning::IntInputToFloatOp {
+                        input,
+                        op,
+                        op_name: format!("{operator:?}"),
+                        location,
+                    });
+                }
+            }
+        }
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(LintError(warnings))
+        }
+    }
+
+    /// Lint the graph, treating any warnings as an error
+    pub fn deny_lints(&self, weights: &FxHashMap<String, NodeIndex>) -> Result<(), LintError> {
+        let warnings = self.lint(weights);
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(LintError(warnings))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    struct TwoWeights {
+        used: GraphTensor<R1<3>>,
+        orphaned: GraphTensor<R1<3>>,
+    }
+
+    impl SerializeModule for TwoWeights {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("used", self.used);
+            s.tensor("orphaned", self.orphaned);
+        }
+    }
+
+    #[test]
+    fn test_lint() {
+        let mut cx = Graph::new();
+        let model = TwoWeights {
+            used: cx.named_tensor("Used Weight").set([1., 2., 3.]),
+            orphaned: cx.named_tensor("Orphaned Weight").set([1., 2., 3.]),
+        };
+        let input = cx.named_tensor::<(Dyn<'s'>,)>("Input");
+        let unused = cx.named_tensor::<R1<3>>("Unused Input");
+        let _ = (input.sum_reduce::<_, LAxis<0>>().expand() * model.used).retrieve();
+        cx.set_dyn_dim('s', 2);
+        cx.set_dyn_dim('x', 4);
+
+        let weights = state_dict(&model);
+        assert_eq!(
+            cx.lint(&weights),
+            vec![
+                LintWarning::OrphanedWeight {
+                    node: model.orphaned.id,
+                    name: "orphaned".to_string(),
+                    location: cx.source_location(model.orphaned.id),
+                },
+                LintWarning::UnusedInput {
+                    node: unused.id,
+                    name: "Unused Input".to_string(),
+                    location: cx.source_location(unused.id),
+                },
+                LintWarning::UnusedDynDim('x'),
+            ]
+        );
+        let error = cx.deny_lints(&weights).unwrap_err();
+        assert!(error.to_string().contains("Weight \"orphaned\""));
+
+        // Once everything is connected there's nothing to report
+        let _ = (model.orphaned + unused).retrieve();
+        cx.dyn_map.remove(&'x');
+        assert!(cx.lint(&weights).is_empty());
+        assert!(cx.deny_lints(&weights).is_ok());
+    }
+
+    #[test]
+    fn test_lint_mismatched_labels() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R3<2, 8, 8>>()
+            .label_dims(["batch", "seq", "dhead"]);
+        let b = cx
+            .tensor::<R3<2, 8, 8>>()
+            .label_dims(["batch", "head", "dhead"]);
+        let c = (a + b).retrieve();
+        // Transposing the wrong pair of axes
+        let d = (a.permute::<_, LAxes3<0, 2, 1>>() * a).retrieve();
+        // Labels that agree are fine
+        let _ = (a * a.sin()).retrieve();
+        assert_eq!(
+            cx.lint(&Default::default()),
+            vec![
+                LintWarning::MismatchedDimLabels {
+                    op: c.id,
+                    axis: 1,
+                    lhs: "seq".to_string(),
+                    rhs: "head".to_string(),
+                    location: cx.source_location(c.id),
+                },
+                LintWarning::MismatchedDimLabels {
+                    op: d.id,
+                    axis: 1,
+                    lhs: "dhead".to_string(),
+                    rhs: "seq".to_string(),
+                    location: cx.source_location(d.id),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_source_locations() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 8>>().label_dims(["batch", "seq"]);
+        let b = cx.tensor::<R2<2, 8>>().label_dims(["batch", "head"]);
+        let line = line!() + 1;
+        let c = (a + b).retrieve();
+        let location = cx.source_location(c.id).unwrap();
+        assert_eq!((location.file(), location.line()), (file!(), line));
+
+        // Warnings and debug graphs point at the line that built the op
+        let warnings = cx.lint(&Default::default());
+        let message = warnings[0].to_string();
+        assert!(
+            message.contains(&format!("{}:{line}:", file!())),
+            "{message}"
+        );
+        let (debug_graph, _, id_map) = cx.debug_graph(false);
+        assert!(debug_graph[id_map[&c.id]].ends_with(&format!("@ {}:{line}", file!())));
+    }
+}
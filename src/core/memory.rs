@@ -0,0 +1,446 @@
+use std::{fmt::Display, ops::Range};
+
+use itertools::Itertools;
+use petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    graph::{created_at, Graph, SourceLocation},
+    shape::ShapeTracker,
+    tensor::Tensor,
+};
+
+/// Execution stopped because the graph's tensors grew past its memory limit, set with [`Graph::set_memory_limit`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutOfMemory {
+    /// The node whose outputs went over the limit
+    pub node: NodeIndex,
+    /// The name of the op at that node
+    pub op: String,
+    /// Where the node was created
+    pub location: Option<SourceLocation>,
+    /// Bytes in use after the op ran
+    pub used: usize,
+    /// The memory limit in bytes
+    pub limit: usize,
+}
+
+impl Display for OutOfMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Out of memory: {} ({:?}{}) brought memory use to {} bytes, over the limit of {} bytes",
+            self.op,
+            self.node,
+            created_at(self.location),
+            self.used,
+            self.limit
+        )
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+/// Memory backing the output of a node during an execution
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    /// The node whose output first used the memory
+    pub node: NodeIndex,
+    pub bytes: usize,
+    /// The ops during which the memory was held, counting ops run from 0. The end is exclusive: memory written by
+    /// op 2 and last read by op 4 has a lifetime of `2..5`. Memory still held when the execution finished (like
+    /// retrieved outputs) runs to the number of ops run.
+    pub lifetime: Range<usize>,
+}
+
+/// The memory a [`Graph::execute_with_report`] run used
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionReport {
+    /// The most bytes held at once, counting an op's inputs and outputs as held together while it runs
+    pub peak_bytes: usize,
+    /// Memory that was newly allocated, in the order it was allocated. Tensors already in the graph when execution
+    /// started count from op 0.
+    pub allocations: Vec<Allocation>,
+    /// Outputs backed by memory an earlier output already used and released, like buffers handed back out by a
+    /// backend's pool. These aren't new allocations, so they aren't in `allocations`.
+    pub reuses: Vec<Allocation>,
+}
+
+/// Tracks the allocations of the graph's tensors while it executes
+#[derive(Debug, Default)]
+pub(crate) struct AllocationRecorder {
+    report: ExecutionReport,
+    /// Ops run so far
+    step: usize,
+    /// Held memory by address, as (bytes, whether it's a reuse, index in the report)
+    live: FxHashMap<usize, (usize, bool, usize)>,
+    /// Addresses used during this execution
+    seen: FxHashSet<usize>,
+}
+
+impl AllocationRecorder {
+    /// Record the tensors already in the graph as allocated before the first op
+    pub(crate) fn start(&mut self, tensors: &FxHashMap<(NodeIndex, u8), Tensor>) {
+        *self = Self::default();
+        self.record_new(tensors);
+        self.report.peak_bytes = self.live_bytes();
+    }
+
+    /// Record the tensors after an op ran
+    pub(crate) fn step(&mut self, tensors: &FxHashMap<(NodeIndex, u8), Tensor>) {
+        // The op's inputs are still held while its outputs are written
+        let held = self.live_bytes();
+        let new = self.record_new(tensors);
+        self.report.peak_bytes = self.report.peak_bytes.max(held + new);
+        self.step += 1;
+        self.release_missing(tensors);
+    }
+
+    /// Record the tensors left after the execution finished
+    pub(crate) fn finish(&mut self, tensors: &FxHashMap<(NodeIndex, u8), Tensor>) {
+        self.release_missing(tensors);
+    }
+
+    /// Start the lifetimes of memory that isn't held yet, returning the number of bytes
+    fn record_new(&mut self, tensors: &FxHashMap<(NodeIndex, u8), Tensor>) -> usize {
+        let mut new = 0;
+        for ((node, _), (address, bytes)) in tensors
+            .iter()
+            .filter_map(|(id, t)| Some((*id, t.data.allocation()?)))
+            .sorted_by_key(|(id, _)| *id)
+        {
+            if self.live.contains_key(&address) {
+                continue;
+            }
+            let reuse = !self.seen.insert(address);
+            let list = if reuse {
+                &mut self.report.reuses
+            } else {
+                &mut self.report.allocations
+            };
+            list.push(Allocation {
+                node,
+                bytes,
+                lifetime: self.step..self.step,
+            });
+            self.live.insert(address, (bytes, reuse, list.len() - 1));
+            new += bytes;
+        }
+        new
+    }
+
+    /// Extend the lifetimes of held memory to the current op, and stop tracking memory no tensor holds anymore
+    fn release_missing(&mut self, tensors: &FxHashMap<(NodeIndex, u8), Tensor>) {
+        let held = tensors
+            .values()
+            .filter_map(|t| Some(t.data.allocation()?.0))
+            .collect::<FxHashSet<_>>();
+        for (_, reuse, index) in self.live.values() {
+            let list = if *reuse {
+                &mut self.report.reuses
+            } else {
+                &mut self.report.allocations
+            };
+            list[*index].lifetime.end = self.step;
+        }
+        self.live.retain(|address, _| held.contains(address));
+    }
+
+    fn live_bytes(&self) -> usize {
+        self.live.values().map(|(bytes, _, _)| bytes).sum()
+    }
+}
+
+/// Bytes held by a set of tensors, counting allocations shared between tensors once
+pub(crate) fn tensor_memory<'a>(tensors: impl Iterator<Item = &'a Tensor>) -> usize {
+    tensors
+        .filter_map(|t| t.data.allocation())
+        .collect::<FxHashMap<_, _>>()
+        .values()
+        .sum()
+}
+
+/// Number of elements physically stored for a tensor viewed through this shape
+fn physical_elements(shape: &ShapeTracker, dyn_map: &FxHashMap<char, usize>) -> usize {
+    shape
+        .dims
+        .iter()
+        .zip(shape.fake.iter())
+        .filter(|(_, fake)| !**fake)
+        .map(|(d, _)| d.exec(dyn_map).unwrap())
+        .product()
+}
+
+impl Graph {
+    /// Cap the memory the graph's tensors can use, in bytes. Executing past it stops with an [`OutOfMemory`] error
+    /// from [`Graph::try_execute`] (or a panic from [`Graph::execute`]) naming the op that went over.
+    ///
+    /// Memory is checked after each op runs. Device buffers a backend keeps outside the graph's tensors, like
+    /// shared storage buffers no op has output yet, aren't counted.
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        self.memory_limit = Some(bytes);
+    }
+
+    /// Remove the memory limit
+    pub fn clear_memory_limit(&mut self) {
+        self.memory_limit = None;
+    }
+
+    /// Bytes currently held by the graph's tensors
+    pub fn current_device_memory(&self) -> usize {
+        tensor_memory(self.tensors.values())
+    }
+
+    /// The most memory the graph's tensors held during the last execution. Only tracked while a memory limit is set.
+    pub fn peak_device_memory(&self) -> usize {
+        self.peak_memory
+    }
+
+    /// Execute the graph, recording every allocation the graph's tensors make along with the node that made it.
+    ///
+    /// Memory is identified by its address, so tensors sharing memory count once, and outputs backed by memory an
+    /// earlier output used (like pooled buffers) are reported as reuses rather than allocations. Like the memory
+    /// limit, this only sees the graph's tensors, not buffers a backend keeps for itself.
+    pub fn execute_with_report(&mut self) -> ExecutionReport {
+        self.allocation_recorder = Some(AllocationRecorder::default());
+        self.execute();
+        self.allocation_recorder.take().unwrap().report
+    }
+
+    /// Estimate the most memory the graph's tensors will hold while executing with these dyn dims, in bytes.
+    ///
+    /// Tensors already in the graph (like loaded weights) count with their real size, and every other output counts
+    /// 4 bytes per element, the size of f32 and i32 data. Output sizes come from the shapes of the edges reading
+    /// them, so outputs nothing reads (like retrieved outputs) are assumed to be as large as their largest input,
+    /// which is exact for elementwise ops.
+    pub fn estimated_device_memory(&self, dyn_map: &FxHashMap<char, usize>) -> usize {
+        let mut remaining_consumers = FxHashMap::default();
+        let mut output_sizes = FxHashMap::default();
+        for edge in self
+            .graph
+            .node_indices()
+            .flat_map(|n| self.graph.edges_directed(n, Direction::Outgoing))
+        {
+            if let Some((_, output, shape)) = edge.weight().as_data() {
+                *remaining_consumers
+                    .entry((edge.source(), output))
+                    .or_insert(0) += 1;
+                output_sizes.insert(
+                    (edge.source(), output),
+                    physical_elements(&shape, dyn_map) * std::mem::size_of::<f32>(),
+                );
+            }
+        }
+
+        let mut live = self
+            .tensors
+            .iter()
+            .filter_map(|(id, t)| Some((*id, t.data.allocation()?.1)))
+            .collect::<FxHashMap<_, _>>();
+        let mut peak = live.values().sum::<usize>();
+        for node in self.execution_order() {
+            if self.tensors.contains_key(&(node, 0)) {
+                continue;
+            }
+            let srcs = self
+                .graph
+                .edges_directed(node, Direction::Incoming)
+                .filter_map(|e| e.weight().as_data().map(|(_, o, _)| (e.source(), o)))
+                .collect_vec();
+            // Sources on their last consumer are freed as they're consumed
+            for src in &srcs {
+                let remaining = remaining_consumers.get_mut(src).unwrap();
+                *remaining -= 1;
+                if *remaining == 0 && !self.no_delete.contains(&src.0) {
+                    live.remove(src);
+                }
+            }
+            let outputs = output_sizes
+                .iter()
+                .filter(|((n, _), _)| *n == node)
+                .map(|(id, size)| (*id, *size))
+                .collect_vec();
+            if outputs.is_empty() {
+                let size = srcs
+                    .iter()
+                    .filter_map(|s| output_sizes.get(s))
+                    .copied()
+                    .max()
+                    .unwrap_or_default();
+                live.insert((node, 0), size);
+            } else {
+                live.extend(outputs);
+            }
+            peak = peak.max(live.values().sum());
+        }
+        peak
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    use std::rc::Rc;
+
+    use crate::{
+        op::Function,
+        tensor::{Data, Tensor},
+    };
+
+    #[test]
+    fn test_memory_limit() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor::<R1<64>>("A").set(random_vec(64)).keep();
+        let line = line!() + 1;
+        let out = a.exp2().sin().retrieve();
+        // Holding both A and its exp2 goes over the limit
+        cx.set_memory_limit(2 * 64 * 4 - 1);
+        let Err(ExecutionError::OutOfMemory(error)) = cx.try_execute() else {
+            panic!("Expected to run out of memory");
+        };
+        assert!(error.op.contains("Exp2"), "{error}");
+        // The error points at the line that built the op
+        assert!(
+            error.to_string().contains(&format!("{}:{line}:", file!())),
+            "{error}"
+        );
+        assert_eq!(error.used, 2 * 64 * 4);
+        assert!(cx.get_tensor_ref(out.id, 0).is_none());
+
+        cx.clear_memory_limit();
+        cx.execute();
+        assert!(cx.get_tensor_ref(out.id, 0).is_some());
+    }
+
+    #[test]
+    fn test_estimated_memory() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor::<R1<64>>("A").set(random_vec(64)).keep();
+        let b = cx.named_tensor::<R1<64>>("B").set(random_vec(64));
+        let c = (a.exp2() * b).sqrt();
+        let _ = (c + a.sin()).retrieve();
+        let estimate = cx.estimated_device_memory(&cx.dyn_map);
+        cx.set_memory_limit(usize::MAX);
+        cx.execute();
+        assert_eq!(estimate, cx.peak_device_memory());
+        assert!(cx.current_device_memory() < cx.peak_device_memory());
+    }
+
+    #[test]
+    fn test_execution_report() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let mut c = a.matmul(b).retrieve();
+        cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut c);
+
+        for _ in 0..2 {
+            let report = cx.execute_with_report();
+            let bytes = |node| {
+                report
+                    .allocations
+                    .iter()
+                    .find(|a| a.node == node)
+                    .map(|a| (a.bytes, a.lifetime.clone()))
+            };
+            assert_eq!(report.allocations.len(), 3);
+            assert_eq!(bytes(a.id).unwrap().0, 2 * 3 * 4);
+            assert_eq!(bytes(b.id).unwrap().0, 3 * 4 * 4);
+            // The matmul runs last, and its output is held until the end
+            assert_eq!(bytes(c.id), Some((2 * 4 * 4, 2..3)));
+            assert_eq!(bytes(a.id).unwrap().1.end, 3);
+            assert_eq!(report.peak_bytes, (6 + 12 + 8) * 4);
+            assert!(report.reuses.is_empty());
+            c.drop();
+        }
+    }
+
+    #[test]
+    fn test_in_place_unary_chain() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<64>>().set(random_vec(64));
+        let mut b = a;
+        for _ in 0..5 {
+            b = b.exp2().log2();
+        }
+        b.retrieve();
+
+        // Every op writes into the memory of its dead input, which is held from the load through all 10 unary ops
+        let report = cx.execute_with_report();
+        assert_eq!(report.allocations.len(), 1);
+        assert_eq!(report.allocations[0].node, a.id);
+        assert_eq!(report.allocations[0].lifetime, 0..11);
+        assert_eq!(report.peak_bytes, 64 * 4);
+    }
+
+    #[test]
+    fn test_no_in_place_on_retrieved_input() {
+        let mut cx = Graph::new();
+        let data = random_vec(64);
+        let a = cx.tensor::<R1<64>>().set(data.clone());
+        let b = a.exp2().retrieve();
+        let c = b.sin().retrieve();
+
+        let report = cx.execute_with_report();
+        // The sin can't write into the exp2 output, which is still read back
+        assert_eq!(report.allocations.len(), 2);
+        let b_data = data.iter().map(|i| i.exp2()).collect::<Vec<_>>();
+        assert_close(&b.data(), &b_data);
+        assert_close(
+            &c.data(),
+            &b_data.iter().map(|i| i.sin()).collect::<Vec<_>>(),
+        );
+    }
+
+    /// A buffer from a pool, identified by its address
+    #[derive(Debug, Clone)]
+    struct PoolBuffer(usize);
+
+    impl Data for PoolBuffer {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+        fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+        fn allocation(&self) -> Option<(usize, usize)> {
+            Some((self.0, 64))
+        }
+    }
+
+    #[test]
+    fn test_execution_report_reuse() {
+        let mut cx = Graph::new();
+        let pooled = |address| {
+            Function(
+                format!("Buffer {address}"),
+                Rc::new(move |_| vec![Tensor::new(PoolBuffer(address))]),
+            )
+        };
+        let first = cx.add_op(pooled(1)).finish();
+        let second = cx
+            .add_op(pooled(2))
+            .input(first, 0, ShapeTracker::new(&[]))
+            .finish();
+        // Gets the first buffer back after it was released
+        let third = cx
+            .add_op(pooled(1))
+            .input(second, 0, ShapeTracker::new(&[]))
+            .finish();
+        cx.no_delete.insert(third);
+
+        let report = cx.execute_with_report();
+        let allocation = |node, lifetime| Allocation {
+            node,
+            bytes: 64,
+            lifetime,
+        };
+        assert_eq!(
+            report.allocations,
+            vec![allocation(first, 0..2), allocation(second, 1..3)]
+        );
+        assert_eq!(report.reuses, vec![allocation(third, 2..3)]);
+        assert_eq!(report.peak_bytes, 128);
+    }
+}
@@ -1,8 +1,26 @@
+pub mod async_execution;
+pub mod auto_compile;
+pub mod cancellation;
+pub mod checkpoint;
+pub mod compile_cache;
+pub mod compile_stats;
 pub mod compiler_utils;
+pub mod context;
+#[cfg(feature = "dfdx")]
+pub mod dfdx_interop;
+pub mod fingerprint;
+pub mod format;
 pub mod graph;
 pub mod graph_tensor;
+pub mod lint;
+pub mod memory;
 pub mod module;
 pub mod op;
+pub mod partial_execution;
+pub mod profiling;
+pub mod region;
 pub mod serialization;
 pub mod shape;
+pub mod specialize;
+pub mod stats;
 pub mod tensor;
@@ -1,15 +1,16 @@
 #![allow(clippy::needless_range_loop)]
 
-use std::{any::Any, fmt::Debug, path::PathBuf};
+use std::{any::Any, borrow::Cow, cell::RefCell, fmt::Debug, path::PathBuf, rc::Rc};
 
 use crate::{
-    prelude::{tracker::ShapeTracker, TraitObjEq},
-    tensor::Tensor,
+    compiler_utils::TraitObjEq, format::PrintOptions, shape::ShapeTracker, tensor::Tensor,
 };
 
-use super::shape::symbolic::BigExpression;
+use super::shape::symbolic::{BigExpression, Expression};
 use colored::Colorize;
+use half::{bf16, f16};
 use itertools::Itertools;
+use rand::{rngs::StdRng, Rng};
 use rustc_hash::FxHashMap;
 
 /// Either an owned or borrowed tensor that gets consumed by ops
@@ -38,21 +39,46 @@ impl<'a> InputTensor<'a> {
     }
 }
 
+/// Why an op couldn't run
+pub type OpError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An op in the graph. Ops implement either [`Operator::process`] or [`Operator::try_process`], since each runs the
+/// other by default.
 pub trait Operator: Debug + TraitObjEq {
-    /// Process the input tensors and produce output tensors
-    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor>;
+    /// Process the input tensors and produce output tensors, panicking if the op can't run on them.
+    ///
+    /// This runs [`Operator::try_process`] by default.
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp).unwrap_or_else(|e| panic!("{e}"))
+    }
+    /// Process the input tensors, returning an error if the op can't run on them, like tensors on the wrong device.
+    /// [`Graph::try_execute`](crate::graph::Graph::try_execute) runs ops with this.
+    ///
+    /// This runs [`Operator::process`] by default. Ops that can fail override it instead of `process`.
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, OpError> {
+        Ok(self.process(inp))
+    }
     /// Implement custom functionality
     #[allow(unused)]
     fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
         None
     }
+    /// A copy of the op, for ops that can be copied. [`Graph::checkpoint`](crate::graph::Graph::checkpoint) keeps
+    /// these, so passes can freely replace the op and still be undone.
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        None
+    }
 }
 
-/// An opaque function running on CPU that takes in Vec<f32> tensors and outputs Vec<f32> tensors
+/// An opaque function running on CPU that takes in Vec<f32> tensors and outputs Vec<f32> tensors. The function is
+/// shared, so [`Graph::fork`](crate::graph::Graph::fork) can copy inputs.
 #[allow(clippy::type_complexity)]
 pub struct Function(
     pub String,
-    pub Box<dyn Fn(Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor>>,
+    pub Rc<dyn Fn(Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor>>,
 );
 
 impl PartialEq for Function {
@@ -73,9 +99,9 @@ impl Debug for Function {
     }
 }
 
-/// An op to print the value of a tensor
+/// An op to print the value of a tensor, formatted to its realized shape
 #[derive(Clone, Default, PartialEq)]
-pub struct Print(pub String);
+pub struct Print(pub String, pub PrintOptions);
 
 impl Debug for Print {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -83,21 +109,56 @@ impl Debug for Print {
     }
 }
 
+/// The logical elements of a view, with padding as zeros
+pub(crate) fn gather<T: Copy + Default>(data: &[T], shape: &ShapeTracker) -> Vec<T> {
+    let (ind, val) = (shape.index_expression(), shape.valid_expression());
+    (0..shape.n_elements_or_zero().to_usize().unwrap())
+        .map(|i| {
+            if val.exec_single_var(i) != 0 {
+                data[ind.exec_single_var(i)]
+            } else {
+                T::default()
+            }
+        })
+        .collect()
+}
+
 impl Operator for Print {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        for (i, (tensor, tracker)) in inp.iter().enumerate() {
-            println!("{}", self.0);
-            let d = tensor
-                .borrowed()
-                .data
-                .as_any()
-                .downcast_ref::<Vec<f32>>()
-                .unwrap();
-            println!("{} Data: {:?}", i + 1, &d[..d.len().min(10)]);
-            println!("{} Shape: {:?}", i + 1, tracker);
+        for (tensor, tracker) in &inp {
+            let data = tensor.borrowed().data.as_any();
+            let logical = if let Some(d) = data.downcast_ref::<Vec<f32>>() {
+                Tensor::new(gather(d, tracker))
+            } else if let Some(d) = data.downcast_ref::<Vec<i32>>() {
+                Tensor::new(gather(d, tracker))
+            } else if let Some(d) = data.downcast_ref::<Vec<f16>>() {
+                Tensor::new(gather(d, tracker))
+            } else if let Some(d) = data.downcast_ref::<Vec<bf16>>() {
+                Tensor::new(gather(d, tracker))
+            } else {
+                panic!(
+                    "{} can't print {:?}, it isn't on the host",
+                    self.0,
+                    tensor.borrowed().data
+                );
+            };
+            let dims = tracker
+                .shape()
+                .into_iter()
+                .map(|d| d.to_usize().unwrap())
+                .collect::<Vec<_>>();
+            println!(
+                "{} {dims:?}\n{}",
+                self.0,
+                logical.format_with(&dims, &self.1)
+            );
         }
         vec![]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 /// An op to diff a tensor with a binary file
@@ -259,6 +320,53 @@ impl Operator for Diff {
         }
         vec![]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// An op that hands the logical value of a tensor, along with its shape, to a host function every time the graph runs
+#[allow(clippy::type_complexity)]
+pub struct Tap(pub String, pub Box<dyn FnMut(&[f32], &[usize])>);
+
+impl PartialEq for Tap {
+    fn eq(&self, _: &Self) -> bool {
+        // Every tap is observed separately, so they never get merged
+        false
+    }
+}
+
+impl Debug for Tap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Tap-{}", self.0)
+    }
+}
+
+impl Operator for Tap {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (tensor, shape) = &inp[0];
+        let d = tensor
+            .borrowed()
+            .data
+            .as_any()
+            .downcast_ref::<Vec<f32>>()
+            .unwrap();
+        let mut data = vec![0.; shape.n_elements_or_zero().to_usize().unwrap()];
+        let (ind, val) = (shape.index_expression(), shape.valid_expression());
+        for (i, r) in data.iter_mut().enumerate() {
+            if val.exec_single_var(i) != 0 {
+                *r = d[ind.exec_single_var(i)];
+            }
+        }
+        let dims = shape
+            .shape()
+            .into_iter()
+            .map(|d| d.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        (self.1)(&data, &dims);
+        vec![]
+    }
 }
 
 /// A constant value placed on the graph at runtime. Can either be an expression evaluated at runtime, or a constant float
@@ -293,6 +401,10 @@ impl Operator for Constant {
             }]),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 /// Ensure a tensor is contiguously layed out in memory. May involve copying
@@ -302,7 +414,7 @@ impl Operator for Contiguous {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         // Copy data over to new tensor
         let src = get_vec_from_tensor(&inp[0].0);
-        let mut res = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut res = vec![0.; inp[0].1.n_elements_or_zero().to_usize().unwrap()];
         let ind = inp[0].1.index_expression();
         let val = inp[0].1.valid_expression();
         for i in 0..res.len() {
@@ -314,6 +426,10 @@ impl Operator for Contiguous {
             data: Box::new(res),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 // Below are all the primitive operators
@@ -331,6 +447,10 @@ impl Operator for Log2 {
 
         vec![tensor]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -344,6 +464,10 @@ impl Operator for Exp2 {
 
         vec![tensor]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -356,6 +480,10 @@ impl Operator for Sin {
         }
         vec![tensor]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -368,6 +496,10 @@ impl Operator for Recip {
         }
         vec![tensor]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -380,6 +512,10 @@ impl Operator for Sqrt {
         }
         vec![tensor]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 // Binary Ops (A x A -> A)
@@ -398,7 +534,7 @@ impl Operator for Add {
             inp[1].1.index_expression(),
             inp[1].1.valid_expression(),
         );
-        let mut data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut data = vec![0.; inp[0].1.n_elements_or_zero().to_usize().unwrap()];
         for i in 0..data.len() {
             let lhs = if a_val.exec_single_var(i) != 0 {
                 a_data[a_ind.exec_single_var(i)]
@@ -416,6 +552,10 @@ impl Operator for Add {
             data: Box::new(data),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -426,7 +566,7 @@ impl Operator for Mul {
             get_vec_from_tensor(&inp[0].0),
             get_vec_from_tensor(&inp[1].0),
         );
-        let mut data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut data = vec![0.; inp[0].1.n_elements_or_zero().to_usize().unwrap()];
         let (a_ind, a_val, b_ind, b_val) = (
             inp[0].1.index_expression(),
             inp[0].1.valid_expression(),
@@ -448,6 +588,10 @@ impl Operator for Mul {
             data: Box::new(data),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -458,7 +602,7 @@ impl Operator for Mod {
             get_vec_from_tensor(&inp[0].0),
             get_vec_from_tensor(&inp[1].0),
         );
-        let mut data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut data = vec![0.; inp[0].1.n_elements_or_zero().to_usize().unwrap()];
         let (a_ind, a_val, b_ind, b_val) = (
             inp[0].1.index_expression(),
             inp[0].1.valid_expression(),
@@ -480,6 +624,10 @@ impl Operator for Mod {
             data: Box::new(data),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -490,7 +638,7 @@ impl Operator for LessThan {
             get_vec_from_tensor(&inp[0].0),
             get_vec_from_tensor(&inp[1].0),
         );
-        let mut data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut data = vec![0.; inp[0].1.n_elements_or_zero().to_usize().unwrap()];
         let (a_ind, a_val, b_ind, b_val) = (
             inp[0].1.index_expression(),
             inp[0].1.valid_expression(),
@@ -514,12 +662,29 @@ impl Operator for LessThan {
             data: Box::new(data),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 // Reduce Ops (A -> B (different shape))
 
+/// How a [`SumReduce`] adds up its values on the CPU
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Summation {
+    /// Add each value to the running sum
+    #[default]
+    Naive,
+    /// Kahan summation, which tracks the rounding error of the running sum. Naive summation loses precision as the
+    /// sum grows, so small values in long reductions get rounded away. This keeps the error bounded regardless of the
+    /// length, at the cost of a few more flops per element. Set by the
+    /// [`CompensatedSummation`](crate::compilers::CompensatedSummation) compiler.
+    Compensated,
+}
+
 #[derive(Debug, Clone, Default, PartialEq)]
-pub struct SumReduce(pub usize);
+pub struct SumReduce(pub usize, pub Summation);
 impl Operator for SumReduce {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let front_size: usize = inp[0]
@@ -544,14 +709,25 @@ impl Operator for SumReduce {
         let a_data = get_vec_from_tensor(&inp[0].0);
         let ind = inp[0].1.index_expression();
         let val = inp[0].1.valid_expression();
+        let compensated = self.1 == Summation::Compensated;
 
         for i in 0..front_size {
             for j in 0..back_size {
+                let new_index = i * back_size + j;
+                // Running error of the sum, for compensated summation
+                let mut compensation = 0.0;
                 for k in 0..dim_size {
                     let original_index = i * dim_size * back_size + k * back_size + j;
-                    let new_index = i * back_size + j;
                     if val.exec_single_var(original_index) != 0 {
-                        result[new_index] += a_data[ind.exec_single_var(original_index)];
+                        let value = a_data[ind.exec_single_var(original_index)];
+                        if compensated {
+                            let y = value - compensation;
+                            let t = result[new_index] + y;
+                            compensation = (t - result[new_index]) - y;
+                            result[new_index] = t;
+                        } else {
+                            result[new_index] += value;
+                        }
                     }
                 }
             }
@@ -560,6 +736,10 @@ impl Operator for SumReduce {
             data: Box::new(result),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -605,6 +785,532 @@ impl Operator for MaxReduce {
             data: Box::new(result),
         }]
     }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// Indexes of the largest elements along a dimension, or the smallest if `min` is set, stored as floats.
+///
+/// Ties resolve to the lowest index, and NaNs win over every other value, so the first NaN is picked if there is one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArgMax {
+    pub dim: usize,
+    pub min: bool,
+}
+impl Operator for ArgMax {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shape = inp[0].1.shape();
+        let front_size: usize = shape
+            .iter()
+            .take(self.dim)
+            .filter_map(BigExpression::to_usize)
+            .product();
+        let back_size: usize = shape
+            .iter()
+            .skip(self.dim + 1)
+            .filter_map(BigExpression::to_usize)
+            .product();
+        let dim_size = match shape[self.dim].to_usize() {
+            Some(n) => n,
+            None => panic!("Can't reduce over an unknown dimension"),
+        };
+        let mut result = vec![0.0; front_size * back_size];
+        let a_data = get_vec_from_tensor(&inp[0].0);
+        let ind = inp[0].1.index_expression();
+        let val = inp[0].1.valid_expression();
+
+        for i in 0..front_size {
+            for j in 0..back_size {
+                let mut best: Option<(usize, f32)> = None;
+                for k in 0..dim_size {
+                    let original_index = i * dim_size * back_size + k * back_size + j;
+                    let value = if val.exec_single_var(original_index) != 0 {
+                        a_data[ind.exec_single_var(original_index)]
+                    } else {
+                        0.0
+                    };
+                    let better = match best {
+                        None => true,
+                        Some((_, b)) if b.is_nan() => false,
+                        Some(_) if value.is_nan() => true,
+                        Some((_, b)) => {
+                            if self.min {
+                                value < b
+                            } else {
+                                value > b
+                            }
+                        }
+                    };
+                    if better {
+                        best = Some((k, value));
+                    }
+                }
+                result[i * back_size + j] = best.map(|(k, _)| k as f32).unwrap_or_default();
+            }
+        }
+        vec![Tensor {
+            data: Box::new(result),
+        }]
+    }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// Cumulative sum along a dimension. The output is contiguous, with the same shape as the input.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CumSum(pub usize);
+impl Operator for CumSum {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shape = inp[0].1.shape();
+        let front_size: usize = shape
+            .iter()
+            .take(self.0)
+            .filter_map(BigExpression::to_usize)
+            .product();
+        let back_size: usize = shape
+            .iter()
+            .skip(self.0 + 1)
+            .filter_map(BigExpression::to_usize)
+            .product();
+        let dim_size = match shape[self.0].to_usize() {
+            Some(n) => n,
+            None => panic!("Can't sum over an unknown dimension"),
+        };
+        let mut result = vec![0.0; front_size * dim_size * back_size];
+        let a_data = get_vec_from_tensor(&inp[0].0);
+        let ind = inp[0].1.index_expression();
+        let val = inp[0].1.valid_expression();
+
+        for i in 0..front_size {
+            for j in 0..back_size {
+                let mut sum = 0.0;
+                for k in 0..dim_size {
+                    let index = i * dim_size * back_size + k * back_size + j;
+                    if val.exec_single_var(index) != 0 {
+                        sum += a_data[ind.exec_single_var(index)];
+                    }
+                    result[index] = sum;
+                }
+            }
+        }
+        vec![Tensor {
+            data: Box::new(result),
+        }]
+    }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// Sort along the last dimension. Outputs the sorted values, or the indexes that sort each row if `indices` is set.
+///
+/// The sort is stable, so equal elements keep their original order. NaNs are treated as larger than every other value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sort {
+    pub descending: bool,
+    pub indices: bool,
+}
+impl Operator for Sort {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let row_size = match inp[0].1.shape().last().and_then(BigExpression::to_usize) {
+            Some(n) => n,
+            None => panic!("Can't sort over an unknown dimension"),
+        };
+        let n_elements = inp[0].1.n_elements_or_zero().to_usize().unwrap();
+        let a_data = get_vec_from_tensor(&inp[0].0);
+        let ind = inp[0].1.index_expression();
+        let val = inp[0].1.valid_expression();
+
+        let mut result = Vec::with_capacity(n_elements);
+        let mut row = vec![0.0; row_size];
+        for row_start in (0..n_elements).step_by(row_size.max(1)) {
+            for (i, r) in row.iter_mut().enumerate() {
+                *r = if val.exec_single_var(row_start + i) != 0 {
+                    a_data[ind.exec_single_var(row_start + i)]
+                } else {
+                    0.0
+                };
+            }
+            let perm = sort_permutation(&row, self.descending);
+            if self.indices {
+                result.extend(perm.into_iter().map(|i| i as f32));
+            } else {
+                result.extend(perm.into_iter().map(|i| row[i]));
+            }
+        }
+        vec![Tensor {
+            data: Box::new(result),
+        }]
+    }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// The `k` largest elements along a dimension in descending order, or their indexes (stored as floats) if `indices`
+/// is set. The output is contiguous, with the dimension shrunk to `k`.
+///
+/// Ties keep their original order and NaNs count as the largest values, like a descending [`Sort`]. `k` can depend
+/// on dynamic dimensions, so one graph can pick a different `k` each execution.
+#[derive(Clone, PartialEq)]
+pub struct TopK {
+    pub k: Expression,
+    pub dim: usize,
+    pub indices: bool,
+    pub dyn_map: *const FxHashMap<char, usize>,
+}
+impl Debug for TopK {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "TopK {{ k: {:?}, dim: {}, indices: {} }}",
+            self.k, self.dim, self.indices
+        )
+    }
+}
+impl Operator for TopK {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shape = inp[0].1.shape();
+        let front_size: usize = shape
+            .iter()
+            .take(self.dim)
+            .filter_map(BigExpression::to_usize)
+            .product();
+        let back_size: usize = shape
+            .iter()
+            .skip(self.dim + 1)
+            .filter_map(BigExpression::to_usize)
+            .product();
+        let dim_size = match shape[self.dim].to_usize() {
+            Some(n) => n,
+            None => panic!("Can't take the top-k over an unknown dimension"),
+        };
+        let k = self
+            .k
+            .exec(unsafe { self.dyn_map.as_ref().unwrap() })
+            .unwrap();
+        assert!(
+            k <= dim_size,
+            "Top-k of {k} is larger than the dimension of {dim_size}"
+        );
+        let mut result = vec![0.0; front_size * k * back_size];
+        let a_data = get_vec_from_tensor(&inp[0].0);
+        let ind = inp[0].1.index_expression();
+        let val = inp[0].1.valid_expression();
+
+        let mut row = vec![0.0; dim_size];
+        for i in 0..front_size {
+            for j in 0..back_size {
+                for (c, r) in row.iter_mut().enumerate() {
+                    let original_index = i * dim_size * back_size + c * back_size + j;
+                    *r = if val.exec_single_var(original_index) != 0 {
+                        a_data[ind.exec_single_var(original_index)]
+                    } else {
+                        0.0
+                    };
+                }
+                let perm = sort_permutation(&row, true);
+                for (c, p) in perm.into_iter().take(k).enumerate() {
+                    result[i * k * back_size + c * back_size + j] =
+                        if self.indices { p as f32 } else { row[p] };
+                }
+            }
+        }
+        vec![Tensor {
+            data: Box::new(result),
+        }]
+    }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// Cross entropy of the logits `hidden [N, D] x weight [D, V]` against target indexes `[N]`, without materializing the
+/// logits. Outputs the loss of each row, `logsumexp(logits) - logits[target]`.
+///
+/// The vocab is walked `chunk_size` logits at a time, keeping a running max and sum of exponentials (an online
+/// log-sum-exp), so only one chunk of one row's logits exists at once. Inputs are expected to be contiguous.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkedCrossEntropy {
+    pub chunk_size: usize,
+}
+impl Operator for ChunkedCrossEntropy {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (hidden_shape, weight_shape) = (inp[0].1.shape(), inp[1].1.shape());
+        let (Some(n_rows), Some(dim), Some(vocab)) = (
+            hidden_shape[0].to_usize(),
+            hidden_shape[1].to_usize(),
+            weight_shape[1].to_usize(),
+        ) else {
+            panic!("Can't take the cross entropy over an unknown dimension");
+        };
+        let hidden = get_vec_from_tensor(&inp[0].0);
+        let weight = get_vec_from_tensor(&inp[1].0);
+        let targets = get_indexes_from_tensor(&inp[2].0);
+
+        let mut logits = vec![0.0; self.chunk_size.clamp(1, vocab.max(1))];
+        let mut result = Vec::with_capacity(n_rows);
+        for row in 0..n_rows {
+            let h = &hidden[row * dim..(row + 1) * dim];
+            let target = targets[row] as usize;
+            assert!(
+                target < vocab,
+                "Cross entropy target {target} is out of range for a vocab of {vocab}"
+            );
+            let (mut max, mut sum, mut target_logit) = (f32::NEG_INFINITY, 0.0, 0.0);
+            for start in (0..vocab).step_by(logits.len()) {
+                let len = (vocab - start).min(logits.len());
+                let chunk = &mut logits[..len];
+                chunk.fill(0.0);
+                for (d, h) in h.iter().enumerate() {
+                    let w = &weight[d * vocab + start..d * vocab + start + chunk.len()];
+                    for (l, w) in chunk.iter_mut().zip(w) {
+                        *l += h * w;
+                    }
+                }
+                if (start..start + chunk.len()).contains(&target) {
+                    target_logit = chunk[target - start];
+                }
+                // Rescale the running sum to the new max before adding this chunk
+                let chunk_max = chunk.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                if chunk_max > max {
+                    sum *= (max - chunk_max).exp();
+                    max = chunk_max;
+                }
+                sum += chunk.iter().map(|l| (l - max).exp()).sum::<f32>();
+            }
+            result.push(max + sum.ln() - target_logit);
+        }
+        vec![Tensor {
+            data: Box::new(result),
+        }]
+    }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// How [`EmbeddingBag`] combines the rows of a bag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmbeddingBagMode {
+    Sum,
+    #[default]
+    Mean,
+    Max,
+}
+
+/// Gather a variable number of rows of `table [N, D]` per bag and combine them, outputting `[B, D]`.
+///
+/// `indices [I]` holds the table rows of every bag back to back, and `offsets [B]` holds where each bag starts in it, so
+/// bag `b` is `indices[offsets[b]..offsets[b + 1]]`, with the last bag running to the end. Empty bags produce zeros in
+/// every mode, including mean and max. Inputs are expected to be contiguous.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbeddingBag {
+    pub mode: EmbeddingBagMode,
+}
+impl Operator for EmbeddingBag {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (Some(n_rows), Some(dim)) = (
+            inp[0].1.shape()[0].to_usize(),
+            inp[0].1.shape()[1].to_usize(),
+        ) else {
+            panic!("Can't gather from a table with an unknown dimension");
+        };
+        let table = get_vec_from_tensor(&inp[0].0);
+        let indices = get_indexes_from_tensor(&inp[1].0);
+        let offsets = get_indexes_from_tensor(&inp[2].0);
+
+        let mut result = vec![0.0; offsets.len() * dim];
+        for (bag, out) in result.chunks_exact_mut(dim.max(1)).enumerate() {
+            let start = offsets[bag] as usize;
+            let end = offsets
+                .get(bag + 1)
+                .map(|o| *o as usize)
+                .unwrap_or(indices.len());
+            assert!(
+                start <= end && end <= indices.len(),
+                "Embedding bag {bag} spans {start}..{end}, out of range for {} indices",
+                indices.len()
+            );
+            if start == end {
+                continue;
+            }
+            if self.mode == EmbeddingBagMode::Max {
+                out.fill(f32::NEG_INFINITY);
+            }
+            for index in &indices[start..end] {
+                let index = *index as usize;
+                assert!(
+                    index < n_rows,
+                    "Embedding bag index {index} is out of range for a table of {n_rows} rows"
+                );
+                let row = &table[index * dim..(index + 1) * dim];
+                for (o, r) in out.iter_mut().zip(row) {
+                    *o = match self.mode {
+                        EmbeddingBagMode::Max => o.max(*r),
+                        _ => *o + r,
+                    };
+                }
+            }
+            if self.mode == EmbeddingBagMode::Mean {
+                let len = (end - start) as f32;
+                out.iter_mut().for_each(|o| *o /= len);
+            }
+        }
+        vec![Tensor {
+            data: Box::new(result),
+        }]
+    }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// Sum `values [I]` into `size` bins by `indexes [I]`, so `out[indexes[i]] += values[i]`. Indexes outside of the bins
+/// are skipped. `size` can depend on dynamic dimensions. Inputs are expected to be contiguous.
+#[derive(Clone, PartialEq)]
+pub struct ScatterAdd {
+    pub size: Expression,
+    pub dyn_map: *const FxHashMap<char, usize>,
+}
+impl Debug for ScatterAdd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ScatterAdd {{ size: {:?} }}", self.size)
+    }
+}
+impl Operator for ScatterAdd {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let size = self
+            .size
+            .exec(unsafe { self.dyn_map.as_ref().unwrap() })
+            .unwrap();
+        let indexes = get_indexes_from_tensor(&inp[0].0);
+        let values = get_vec_from_tensor(&inp[1].0);
+
+        let mut result = vec![0.0; size];
+        for (index, value) in indexes.iter().zip(values) {
+            if let Some(bin) = usize::try_from(*index).ok().and_then(|i| result.get_mut(i)) {
+                *bin += value;
+            }
+        }
+        vec![Tensor {
+            data: Box::new(result),
+        }]
+    }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// Sample a token id from each row of logits along the last dimension, outputting the ids as i32.
+///
+/// The logits are divided by `temperature` and softmaxed, then the tokens are sorted by probability and cut to the
+/// `top_k` most likely, and to the smallest set whose cumulative probability reaches `top_p`. The most likely token is
+/// always kept, matching HF's `TopPLogitsWarper`. Each row takes its own uniform draw from `rng`, which carries on
+/// between executions. Inputs are expected to be contiguous.
+#[derive(Clone)]
+pub struct Sample {
+    pub temperature: f32,
+    pub top_p: f32,
+    pub top_k: Option<usize>,
+    pub rng: Rc<RefCell<StdRng>>,
+}
+impl Debug for Sample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Sample {{ temperature: {}, top_p: {}, top_k: {:?} }}",
+            self.temperature, self.top_p, self.top_k
+        )
+    }
+}
+impl PartialEq for Sample {
+    fn eq(&self, other: &Self) -> bool {
+        self.temperature == other.temperature
+            && self.top_p == other.top_p
+            && self.top_k == other.top_k
+            && Rc::ptr_eq(&self.rng, &other.rng)
+    }
+}
+impl Operator for Sample {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let Some(vocab) = inp[0].1.shape().last().and_then(BigExpression::to_usize) else {
+            panic!("Can't sample over an unknown dimension");
+        };
+        let logits = get_vec_from_tensor(&inp[0].0);
+        let mut rng = self.rng.borrow_mut();
+
+        let mut result = Vec::with_capacity(logits.len() / vocab.max(1));
+        let mut probs = vec![0.0; vocab];
+        for row in logits.chunks_exact(vocab.max(1)) {
+            // Softmax the row
+            let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            for (p, l) in probs.iter_mut().zip(row) {
+                *p = ((l - max) / self.temperature).exp();
+            }
+            let sum = probs.iter().sum::<f32>();
+            probs.iter_mut().for_each(|p| *p /= sum);
+
+            // Keep tokens while the probability mass before them is less than top_p
+            let order = sort_permutation(&probs, true);
+            let (mut kept, mut mass) = (0, 0.0);
+            for i in order.iter().take(self.top_k.unwrap_or(vocab)) {
+                if kept > 0 && mass >= self.top_p {
+                    break;
+                }
+                mass += probs[*i];
+                kept += 1;
+            }
+
+            // Invert the CDF of the kept tokens, falling back to the last one in case of rounding error
+            let target = rng.gen::<f32>() * mass;
+            let mut cumulative = 0.0;
+            let token = order[..kept]
+                .iter()
+                .find(|i| {
+                    cumulative += probs[**i];
+                    cumulative > target
+                })
+                .unwrap_or(&order[kept - 1]);
+            result.push(*token as i32);
+        }
+        vec![Tensor::new(result)]
+    }
+
+    fn boxed_clone(&self) -> Option<Box<dyn Operator>> {
+        // Copies draw the same sequence as this op from here on, without advancing it
+        Some(Box::new(Self {
+            rng: Rc::new(RefCell::new(self.rng.borrow().clone())),
+            ..self.clone()
+        }))
+    }
+}
+
+/// Get the stable permutation that sorts a row. NaNs are ordered after every other value.
+pub fn sort_permutation(row: &[f32], descending: bool) -> Vec<usize> {
+    let mut perm = (0..row.len()).collect::<Vec<_>>();
+    perm.sort_by(|a, b| {
+        let (a, b) = (row[*a], row[*b]);
+        let ord = a
+            .partial_cmp(&b)
+            .unwrap_or_else(|| a.is_nan().cmp(&b.is_nan()));
+        if descending {
+            ord.reverse()
+        } else {
+            ord
+        }
+    });
+    perm
 }
 
 pub fn get_vec_from_tensor<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
@@ -620,6 +1326,22 @@ pub fn get_vec_from_tensor_owned(tensor: &mut Tensor) -> &mut Vec<f32> {
     tensor.data.as_any_mut().downcast_mut::<Vec<f32>>().unwrap()
 }
 
+/// Get index data from either an i32 tensor, or an f32 tensor holding whole numbers
+pub fn get_indexes_from_tensor<'a>(tensor: &'a InputTensor<'a>) -> Cow<'a, [i32]> {
+    let data = tensor.borrowed().data.as_any();
+    if let Some(indexes) = data.downcast_ref::<Vec<i32>>() {
+        Cow::Borrowed(indexes)
+    } else {
+        Cow::Owned(
+            data.downcast_ref::<Vec<f32>>()
+                .unwrap()
+                .iter()
+                .map(|i| *i as i32)
+                .collect(),
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
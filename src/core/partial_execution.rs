@@ -0,0 +1,176 @@
+use std::{
+    fmt::Display,
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+use itertools::Itertools;
+use petgraph::stable_graph::NodeIndex;
+use rustc_hash::FxHashMap;
+
+use crate::{
+    graph::{ExecutionError, Graph},
+    shape::ShapeTracker,
+    tensor::Tensor,
+};
+
+/// An op that failed during [`Graph::execute_partial`]
+#[derive(Debug, Clone)]
+pub struct FailedOp {
+    /// The node that failed
+    pub node: NodeIndex,
+    /// The name of the op at that node
+    pub op: String,
+    /// The error or panic message
+    pub error: String,
+    /// The shapes of the inputs the op got, with dyn dims resolved
+    pub input_shapes: Vec<ShapeTracker>,
+}
+
+/// What happened during a [`Graph::execute_partial`] run
+#[derive(Debug, Clone, Default)]
+pub struct PartialExecutionReport {
+    /// Ops that failed
+    pub failed: Vec<FailedOp>,
+    /// Ops that didn't run because an op they depend on failed, in execution order
+    pub skipped: Vec<NodeIndex>,
+    /// The outputs computed during the run that the graph doesn't keep, taken out of the graph to inspect
+    pub tensors: FxHashMap<(NodeIndex, u8), Tensor>,
+}
+
+impl PartialExecutionReport {
+    /// Whether every op ran successfully
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl Display for PartialExecutionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_complete() {
+            return write!(f, "All ops executed");
+        }
+        for failed in &self.failed {
+            let shapes = failed
+                .input_shapes
+                .iter()
+                .map(|s| format!("{:?}", s.shape()))
+                .join(", ");
+            writeln!(
+                f,
+                "{} ({:?}) failed with inputs ({shapes}): {}",
+                failed.op, failed.node, failed.error
+            )?;
+        }
+        write!(f, "Skipped {} dependent ops", self.skipped.len())
+    }
+}
+
+impl Graph {
+    /// Execute the graph for debugging, carrying on past ops that fail.
+    ///
+    /// Errors and panics in ops are caught and recorded in the report along with the failing op's input shapes, and ops
+    /// that depend on a failed op are skipped. Going over the memory limit is recorded as a failure of the op that did
+    /// it, and stops the run. Every computed tensor is kept until the end, then the ones the graph doesn't keep are
+    /// moved into [`PartialExecutionReport::tensors`], so they stay available to inspect and the graph is ready to
+    /// execute again. Panics if the graph was specialized and a dyn dim is set to a different size.
+    pub fn execute_partial(&mut self) -> PartialExecutionReport {
+        let mut state = self.start_execution(true).unwrap_or_else(|e| panic!("{e}"));
+        let order = self.linearized_graph.clone().unwrap();
+        let mut report = PartialExecutionReport::default();
+        for (node, src_ids) in order.iter() {
+            if !src_ids.iter().all(|(id, _)| self.tensors.contains_key(id)) {
+                // An input failed or was skipped
+                report.skipped.push(*node);
+                continue;
+            }
+
+            let mut input_shapes = vec![];
+            let result = self.execute_node(*node, src_ids, &mut state, |op, srcs| {
+                input_shapes = srcs.iter().map(|(_, st)| *st).collect_vec();
+                match catch_unwind(AssertUnwindSafe(|| op.try_process(srcs))) {
+                    Ok(result) => result,
+                    Err(payload) => Err(payload
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "Unknown error".to_string())
+                        .into()),
+                }
+            });
+            match result {
+                Ok(_) => {}
+                Err(ExecutionError::OpFailed {
+                    node, op, error, ..
+                }) => report.failed.push(FailedOp {
+                    node,
+                    op,
+                    error,
+                    input_shapes,
+                }),
+                Err(error) => {
+                    report.failed.push(FailedOp {
+                        node: *node,
+                        op: format!("{:?}", self.graph.node_weight(*node).unwrap()),
+                        error: error.to_string(),
+                        input_shapes,
+                    });
+                    break;
+                }
+            }
+        }
+        (self.tensors, report.tensors) = std::mem::take(&mut self.tensors)
+            .into_iter()
+            .partition(|((n, _), _)| self.no_delete.contains(n));
+        self.end_execution();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    use std::rc::Rc;
+
+    use crate::op::Function;
+
+    #[test]
+    fn test_execute_partial() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let upstream = a.exp2();
+        let broken = cx
+            .add_op(Function(
+                "Broken".to_string(),
+                Rc::new(|_| panic!("Rigged to fail")),
+            ))
+            .input(upstream.id, 0, upstream.shape)
+            .finish();
+        let broken = GraphTensor::<R1<4>>::from_id(broken, upstream.shape, upstream.graph_ref);
+        let downstream = broken.sin().retrieve();
+        let independent = a.sqrt().retrieve();
+
+        let report = cx.execute_partial();
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].node, broken.id);
+        assert_eq!(report.failed[0].error, "Rigged to fail");
+        assert_eq!(report.failed[0].input_shapes, vec![upstream.shape]);
+        assert_eq!(report.skipped, vec![downstream.id]);
+
+        // Everything that ran is still around, and the graph only holds the retrieved outputs
+        let upstream_data = report.tensors[&(upstream.id, 0)]
+            .data
+            .as_any()
+            .downcast_ref::<Vec<f32>>()
+            .unwrap();
+        assert_close(upstream_data, &[2., 4., 8., 16.]);
+        assert_close(&independent.data(), &[1., 2_f32.sqrt(), 3_f32.sqrt(), 2.]);
+        assert!(cx.get_tensor_ref(downstream.id, 0).is_none());
+        assert!(cx.get_tensor_ref(upstream.id, 0).is_none());
+
+        // It runs the same way again
+        let report = cx.execute_partial();
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.skipped, vec![downstream.id]);
+    }
+}
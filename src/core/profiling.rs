@@ -0,0 +1,287 @@
+use std::{
+    cell::Cell,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use petgraph::stable_graph::NodeIndex;
+use rustc_hash::FxHashMap;
+
+use crate::graph::{ExecutionError, Graph};
+
+thread_local! {
+    static PROFILING: Cell<bool> = const { Cell::new(false) };
+    static DEVICE_TIME: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+/// Whether ops on this thread are running under [`Graph::execute_profiled`].
+///
+/// Device ops should then submit and wait for their own work rather than batching it with other ops, and report the
+/// time the device spent on it with [`record_device_time`].
+pub fn profiling() -> bool {
+    PROFILING.with(|p| p.get())
+}
+
+/// Add to the device time of the op that's running. Does nothing unless the graph is being profiled.
+pub fn record_device_time(time: Duration) {
+    if profiling() {
+        DEVICE_TIME.with(|d| d.set(Some(d.get().unwrap_or_default() + time)));
+    }
+}
+
+/// The time and memory traffic of an op, over every profiled execution it ran in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpProfile {
+    pub node: NodeIndex,
+    /// The name of the op at the node
+    pub op: String,
+    /// Number of times the op ran
+    pub executions: usize,
+    /// Time spent in the op's `process`, including waiting for the device
+    pub host_time: Duration,
+    /// Time the device reported spending on the op's work, for ops that run on one
+    pub device_time: Option<Duration>,
+    /// Bytes of the op's inputs and outputs
+    pub bytes: usize,
+}
+
+impl OpProfile {
+    /// The device time for device ops, and the host time for the others
+    pub fn time(&self) -> Duration {
+        self.device_time.unwrap_or(self.host_time)
+    }
+}
+
+/// The ops run by [`Graph::execute_profiled`], in execution order. Reports of several executions are combined with
+/// [`merge`](ProfileReport::merge), and displaying one lists the ops from the most time taken to the least.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileReport {
+    pub ops: Vec<OpProfile>,
+}
+
+impl ProfileReport {
+    /// Add the ops of another report, summing the ones that ran at the same node
+    pub fn merge(&mut self, other: ProfileReport) {
+        let mut positions = self
+            .ops
+            .iter()
+            .enumerate()
+            .map(|(i, op)| (op.node, i))
+            .collect::<FxHashMap<_, _>>();
+        for op in other.ops {
+            let Some(i) = positions.get(&op.node) else {
+                positions.insert(op.node, self.ops.len());
+                self.ops.push(op);
+                continue;
+            };
+            let existing = &mut self.ops[*i];
+            existing.executions += op.executions;
+            existing.host_time += op.host_time;
+            existing.device_time = match (existing.device_time, op.device_time) {
+                (None, None) => None,
+                (a, b) => Some(a.unwrap_or_default() + b.unwrap_or_default()),
+            };
+            existing.bytes += op.bytes;
+        }
+    }
+
+    /// Time taken by all ops
+    pub fn total_time(&self) -> Duration {
+        self.ops.iter().map(|op| op.time()).sum()
+    }
+
+    /// The ops sorted from the most time taken to the least
+    pub fn slowest(&self) -> Vec<&OpProfile> {
+        let mut ops = self.ops.iter().collect::<Vec<_>>();
+        ops.sort_by_key(|op| std::cmp::Reverse(op.time()));
+        ops
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration.as_secs() > 0 {
+        format!("{:.2}s", duration.as_secs_f32())
+    } else if duration.as_millis() > 0 {
+        format!("{:.2}ms", duration.as_secs_f32() * 1e3)
+    } else {
+        format!("{}µs", duration.as_micros())
+    }
+}
+
+impl Display for ProfileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.total_time();
+        writeln!(
+            f,
+            "{:>10} {:>6} {:>10} {:>10} {:>5} {:>12}  Op",
+            "Time", "Share", "Device", "Host", "Runs", "Bytes"
+        )?;
+        for op in self.slowest() {
+            writeln!(
+                f,
+                "{:>10} {:>5.1}% {:>10} {:>10} {:>5} {:>12}  {} ({:?})",
+                format_duration(op.time()),
+                op.time().as_secs_f64() / total.as_secs_f64().max(f64::MIN_POSITIVE) * 100.,
+                op.device_time
+                    .map(format_duration)
+                    .unwrap_or_else(|| "-".to_string()),
+                format_duration(op.host_time),
+                op.executions,
+                op.bytes,
+                op.op,
+                op.node
+            )?;
+        }
+        write!(f, "{:>10} total", format_duration(total))
+    }
+}
+
+impl Graph {
+    /// Execute the graph, timing each op and counting the bytes it reads and writes.
+    ///
+    /// Backends report the time their ops spend on the device, which on the Metal backend means running each kernel
+    /// in its own command buffer rather than batching them. That makes profiled executions slower than normal ones,
+    /// but the time of each op is its own.
+    pub fn execute_profiled(&mut self) -> ProfileReport {
+        self.try_execute_profiled()
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Profile an execution like [`Graph::execute_profiled`], stopping if an op fails, the tensors go over the memory
+    /// limit, or the graph was specialized and a dyn dim is set to a different size
+    pub fn try_execute_profiled(&mut self) -> Result<ProfileReport, ExecutionError> {
+        let mut state = self.start_execution(false)?;
+        let order = self.linearized_graph.clone().unwrap();
+        let mut report = ProfileReport::default();
+
+        PROFILING.with(|p| p.set(true));
+        let result = order.iter().try_for_each(|(node, src_ids)| {
+            self.execute_node(*node, src_ids, &mut state, |op, srcs| {
+                let input_bytes = srcs
+                    .iter()
+                    .filter_map(|(t, _)| t.borrowed().data.allocation())
+                    .map(|(_, bytes)| bytes)
+                    .sum::<usize>();
+
+                DEVICE_TIME.with(|d| d.set(None));
+                let start = Instant::now();
+                let tensors = op.try_process(srcs)?;
+                let host_time = start.elapsed();
+                report.ops.push(OpProfile {
+                    node: *node,
+                    op: format!("{op:?}"),
+                    executions: 1,
+                    host_time,
+                    device_time: DEVICE_TIME.with(|d| d.take()),
+                    bytes: input_bytes
+                        + tensors
+                            .iter()
+                            .filter_map(|t| t.data.allocation())
+                            .map(|(_, bytes)| bytes)
+                            .sum::<usize>(),
+                });
+                Ok(tensors)
+            })
+            .map(|_| ())
+        });
+        PROFILING.with(|p| p.set(false));
+        self.end_execution();
+        result.map(|_| report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{record_device_time, ProfileReport};
+    use crate::{
+        op::{InputTensor, Operator},
+        prelude::*,
+        tests::assert_close,
+    };
+
+    /// Doubles its input, reporting a fixed device time
+    #[derive(Debug, Clone, PartialEq)]
+    struct DeviceDouble;
+
+    impl Operator for DeviceDouble {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            record_device_time(Duration::from_millis(3));
+            let data = inp[0]
+                .0
+                .borrowed()
+                .data
+                .as_any()
+                .downcast_ref::<Vec<f32>>()
+                .unwrap()
+                .iter()
+                .map(|v| v * 2.)
+                .collect::<Vec<_>>();
+            vec![Tensor::new(data)]
+        }
+    }
+
+    #[test]
+    fn test_execute_profiled() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let doubled = cx.add_op(DeviceDouble).input(a.id, 0, a.shape).finish();
+        let doubled = GraphTensor::<R1<4>>::from_id(doubled, a.shape, a.graph_ref);
+        let out = (doubled.exp() + a).retrieve();
+
+        let mut report = ProfileReport::default();
+        for _ in 0..3 {
+            report.merge(cx.execute_profiled());
+            assert_close(
+                &out.data(),
+                &[1., 2., 3., 4.].map(|v: f32| (v * 2.).exp() + v),
+            );
+            out.drop();
+        }
+
+        // Every op is reported once, with its three runs added up
+        assert_eq!(report.ops.len(), cx.graph.node_count());
+        assert!(report.ops.iter().all(|op| op.executions == 3));
+        let device_op = report.ops.iter().find(|op| op.node == doubled.id).unwrap();
+        assert_eq!(device_op.device_time, Some(Duration::from_millis(9)));
+        assert_eq!(device_op.bytes, 3 * 2 * 4 * 4);
+        assert!(report
+            .ops
+            .iter()
+            .filter(|op| op.node != doubled.id)
+            .all(|op| op.device_time.is_none()));
+        // The slowest op is listed first
+        assert_eq!(report.slowest()[0].node, doubled.id);
+        assert!(report
+            .to_string()
+            .lines()
+            .nth(1)
+            .unwrap()
+            .contains("DeviceDouble"));
+    }
+
+    #[test]
+    fn test_try_execute_profiled() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<256>>().set(vec![1.; 256]);
+        let b = a.exp().retrieve();
+        cx.set_memory_limit(256);
+        assert!(matches!(
+            cx.try_execute_profiled(),
+            Err(ExecutionError::OutOfMemory(_))
+        ));
+        assert!(cx.tensors.is_empty());
+
+        cx.clear_memory_limit();
+        cx.record_shapes();
+        let report = cx.try_execute_profiled().unwrap();
+        assert_eq!(report.ops.len(), cx.graph.node_count());
+        assert_close(&b.data(), &[1f32.exp(); 256]);
+        assert!(cx
+            .take_shape_record()
+            .unwrap()
+            .node_shapes
+            .contains_key(&b.id));
+    }
+}
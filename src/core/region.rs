@@ -0,0 +1,277 @@
+use std::fmt::Display;
+
+use petgraph::{stable_graph::NodeIndex, Direction};
+
+use crate::{graph::Graph, tensor::Tensor};
+
+/// Why [`Graph::set_tensor_region`] couldn't write a region
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionError {
+    /// The node has no tensor in the graph. Tensors only stay in the graph between executions if they're kept.
+    MissingTensor(NodeIndex),
+    /// The tensor's shape isn't known, since no op consumes it
+    UnknownShape(NodeIndex),
+    /// The offsets and region shape need one entry per dimension of the tensor
+    RankMismatch {
+        rank: usize,
+        offsets: usize,
+        region: usize,
+    },
+    /// The region runs past the end of a dimension
+    OutOfBounds { dim: usize, end: usize, size: usize },
+    /// The data needs one value per element of the region
+    WrongLength { expected: usize, got: usize },
+    /// The tensor's data can't be written from the host, like quantized weights
+    Unsupported(NodeIndex),
+}
+
+impl Display for RegionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegionError::MissingTensor(node) => write!(f, "No tensor is held for {node:?}"),
+            RegionError::UnknownShape(node) => write!(f, "The shape of {node:?} isn't known"),
+            RegionError::RankMismatch {
+                rank,
+                offsets,
+                region,
+            } => write!(
+                f,
+                "Got {offsets} offsets and a region of rank {region} for a tensor of rank {rank}"
+            ),
+            RegionError::OutOfBounds { dim, end, size } => write!(
+                f,
+                "The region ends at {end} in dimension {dim}, which has size {size}"
+            ),
+            RegionError::WrongLength { expected, got } => {
+                write!(f, "Expected {expected} values for the region, got {got}")
+            }
+            RegionError::Unsupported(node) => {
+                write!(f, "The tensor at {node:?} can't be written from the host")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegionError {}
+
+/// Host data to write into a tensor that isn't a host `Vec<f32>`, handed to the op that produced it with
+/// `custom("write_region", ..)`. Ops that can write their outputs convert the data to the tensor's type and return the
+/// written tensor, which replaces the old one in the graph.
+#[derive(Debug, Clone)]
+pub struct RegionWrite {
+    pub tensor: Tensor,
+    /// Element offset of each contiguous run the region covers, in order
+    pub runs: Vec<usize>,
+    /// Elements in each run
+    pub run_len: usize,
+    /// The values of the region, run after run
+    pub data: Vec<f32>,
+}
+
+impl Graph {
+    /// Overwrite a rectangular region of a tensor held in the graph, like a few rows of a kept weight, without setting
+    /// the whole tensor again. The region starts at `offsets` and has the shape `region_shape`, with `data` holding its
+    /// values in row major order.
+    ///
+    /// Host tensors are written in place. Backends write device tensors from the op that produced them, converting
+    /// the data to the tensor's type, so after compiling, pass the node holding the device copy (found with
+    /// [`downstream`](crate::module::downstream)).
+    pub fn set_tensor_region(
+        &mut self,
+        node: NodeIndex,
+        offsets: &[usize],
+        data: &[f32],
+        region_shape: &[usize],
+    ) -> Result<(), RegionError> {
+        if !self.tensors.contains_key(&(node, 0)) {
+            return Err(RegionError::MissingTensor(node));
+        }
+        let shape = self
+            .held_shape(node)
+            .ok_or(RegionError::UnknownShape(node))?;
+        if offsets.len() != shape.len() || region_shape.len() != shape.len() {
+            return Err(RegionError::RankMismatch {
+                rank: shape.len(),
+                offsets: offsets.len(),
+                region: region_shape.len(),
+            });
+        }
+        for (dim, ((offset, len), size)) in offsets.iter().zip(region_shape).zip(&shape).enumerate()
+        {
+            if offset + len > *size {
+                return Err(RegionError::OutOfBounds {
+                    dim,
+                    end: offset + len,
+                    size: *size,
+                });
+            }
+        }
+        let expected = region_shape.iter().product::<usize>();
+        if data.len() != expected {
+            return Err(RegionError::WrongLength {
+                expected,
+                got: data.len(),
+            });
+        }
+        if expected == 0 {
+            return Ok(());
+        }
+
+        let (runs, run_len) = region_runs(&shape, offsets, region_shape);
+        let tensor = self.tensors.get_mut(&(node, 0)).unwrap();
+        if let Some(values) = tensor.data.as_any_mut().downcast_mut::<Vec<f32>>() {
+            if values.len() != shape.iter().product::<usize>() {
+                return Err(RegionError::UnknownShape(node));
+            }
+            for (offset, run) in runs.into_iter().zip(data.chunks_exact(run_len)) {
+                values[offset..offset + run_len].copy_from_slice(run);
+            }
+            return Ok(());
+        }
+        let write = RegionWrite {
+            tensor: tensor.clone(),
+            runs,
+            run_len,
+            data: data.to_vec(),
+        };
+        let written = self
+            .graph
+            .node_weight_mut(node)
+            .and_then(|op| op.custom("write_region", Box::new(write)))
+            .and_then(|t| t.downcast::<Tensor>().ok())
+            .ok_or(RegionError::Unsupported(node))?;
+        self.tensors.insert((node, 0), *written);
+        Ok(())
+    }
+
+    /// The shape of the tensor a node outputs, as its consumers see it before expanding it
+    fn held_shape(&self, node: NodeIndex) -> Option<Vec<usize>> {
+        let (_, _, shape) = self
+            .graph
+            .edges_directed(node, Direction::Outgoing)
+            .filter_map(|e| e.weight().as_data())
+            .find(|(_, output, _)| *output == 0)?;
+        (0..shape.dims.len())
+            .filter(|i| !shape.fake[*i])
+            .map(|i| shape.dims[i].exec(&self.dyn_map))
+            .collect()
+    }
+}
+
+/// Split a region of a row major tensor into equally long runs of contiguous elements, as (run offsets, run length).
+/// Trailing dimensions the region fully covers merge into longer runs.
+fn region_runs(shape: &[usize], offsets: &[usize], region_shape: &[usize]) -> (Vec<usize>, usize) {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    // The first dimension of each run
+    let mut split = shape.len().saturating_sub(1);
+    while split > 0 && region_shape[split] == shape[split] {
+        split -= 1;
+    }
+    let run_len = region_shape[split..].iter().product::<usize>();
+    let start = offsets
+        .iter()
+        .zip(&strides)
+        .map(|(o, s)| o * s)
+        .sum::<usize>();
+    let n_runs = region_shape[..split].iter().product::<usize>();
+    let runs = (0..n_runs)
+        .map(|mut run| {
+            let mut offset = start;
+            for dim in (0..split).rev() {
+                offset += run % region_shape[dim] * strides[dim];
+                run /= region_shape[dim];
+            }
+            offset
+        })
+        .collect();
+    (runs, run_len)
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    use super::{region_runs, RegionError};
+
+    #[test]
+    fn test_region_runs() {
+        // Rows merge into a single run
+        assert_eq!(region_runs(&[5, 3], &[1, 0], &[2, 3]), (vec![3], 6));
+        // Part of each row
+        assert_eq!(region_runs(&[5, 3], &[1, 1], &[2, 2]), (vec![4, 7], 2));
+        // A column
+        assert_eq!(
+            region_runs(&[3, 2, 2], &[0, 1, 0], &[3, 1, 2]),
+            (vec![2, 6, 10], 2)
+        );
+        // Scalars
+        assert_eq!(region_runs(&[], &[], &[]), (vec![0], 1));
+    }
+
+    #[test]
+    fn test_set_embedding_rows() {
+        let mut cx = Graph::new();
+        let table = cx
+            .tensor::<R2<5, 3>>()
+            .set((0..15).map(|i| i as f32).collect::<Vec<_>>())
+            .keep();
+        let ids = cx.tensor::<R1<3>>().set(vec![1., 2., 4.]);
+        let mut out = table.gather(ids).retrieve();
+        cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut out);
+        cx.execute();
+        assert_exact(&out.data(), &[3., 4., 5., 6., 7., 8., 12., 13., 14.]);
+
+        // Overwrite rows 1 and 2, and the last value of row 4
+        cx.set_tensor_region(table.id, &[1, 0], &[-1., -2., -3., -4., -5., -6.], &[2, 3])
+            .unwrap();
+        cx.set_tensor_region(table.id, &[4, 2], &[-7.], &[1, 1])
+            .unwrap();
+        out.drop();
+        cx.execute();
+        assert_exact(&out.data(), &[-1., -2., -3., -4., -5., -6., 12., 13., -7.]);
+    }
+
+    #[test]
+    fn test_region_errors() {
+        let mut cx = Graph::new();
+        let table = cx.tensor::<R2<5, 3>>().set(vec![0.; 15]);
+        let out = (table * 2.).retrieve();
+        assert_eq!(
+            cx.set_tensor_region(table.id, &[0, 0], &[1.], &[1, 1]),
+            Err(RegionError::MissingTensor(table.id))
+        );
+
+        cx.keep_tensors(table);
+        cx.execute();
+        assert_eq!(
+            cx.set_tensor_region(table.id, &[4, 0], &[1.; 6], &[2, 3]),
+            Err(RegionError::OutOfBounds {
+                dim: 0,
+                end: 6,
+                size: 5
+            })
+        );
+        assert_eq!(
+            cx.set_tensor_region(table.id, &[0], &[1.; 3], &[3]),
+            Err(RegionError::RankMismatch {
+                rank: 2,
+                offsets: 1,
+                region: 1
+            })
+        );
+        assert_eq!(
+            cx.set_tensor_region(table.id, &[0, 0], &[1.; 2], &[1, 3]),
+            Err(RegionError::WrongLength {
+                expected: 3,
+                got: 2
+            })
+        );
+        assert_eq!(
+            cx.set_tensor_region(out.id, &[0, 0], &[1.], &[1, 1]),
+            Err(RegionError::UnknownShape(out.id))
+        );
+    }
+}
@@ -0,0 +1,576 @@
+use std::{borrow::Cow, fmt::Display, fs::File, ops::Range, path::Path};
+
+use half::{bf16, f16};
+use memmap2::MmapOptions;
+use safetensors::{
+    tensor::{Dtype, View},
+    SafeTensorError, SafeTensors,
+};
+
+use super::{gguf, npz};
+
+/// Options for [`convert_checkpoint`]
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// Floating point type (F32, F16 or BF16) to store float tensors as. By default float tensors keep their type,
+    /// except fp64 and GGML quantized tensors, which become fp32.
+    pub dtype: Option<Dtype>,
+    /// Quantize float tensors with at least 2 dimensions to int8, with a symmetric fp32 scale per output channel
+    /// (the first dimension) stored alongside as `{name}.scales`, ready to be loaded by the
+    /// [`SafeTensorLoader`](super::SafeTensorLoader)
+    pub quantize_int8: bool,
+}
+
+/// Why [`convert_checkpoint`] or loading a GGUF file failed
+#[derive(Debug)]
+pub enum ConvertError {
+    Io(std::io::Error),
+    SafeTensor(SafeTensorError),
+    /// The input file is malformed or uses an unsupported feature
+    Format(String),
+}
+
+impl Display for ConvertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConvertError::Io(e) => write!(f, "{e}"),
+            ConvertError::SafeTensor(e) => write!(f, "{e:?}"),
+            ConvertError::Format(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+impl From<std::io::Error> for ConvertError {
+    fn from(e: std::io::Error) -> Self {
+        ConvertError::Io(e)
+    }
+}
+
+impl From<SafeTensorError> for ConvertError {
+    fn from(e: SafeTensorError) -> Self {
+        ConvertError::SafeTensor(e)
+    }
+}
+
+/// The storage type of a tensor in an input checkpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum SourceDtype {
+    F64,
+    F32,
+    F16,
+    BF16,
+    I8,
+    U8,
+    /// GGML blocks of 32 int8 values sharing an fp16 scale
+    Q8_0,
+    /// GGML blocks of 32 4-bit values sharing an fp16 scale
+    Q4_0,
+}
+
+impl SourceDtype {
+    /// Size in bytes of n elements of this type
+    pub(super) fn size_of(self, n: usize) -> usize {
+        match self {
+            SourceDtype::F64 => n * 8,
+            SourceDtype::F32 => n * 4,
+            SourceDtype::F16 | SourceDtype::BF16 => n * 2,
+            SourceDtype::I8 | SourceDtype::U8 => n,
+            SourceDtype::Q8_0 => n / 32 * 34,
+            SourceDtype::Q4_0 => n / 32 * 18,
+        }
+    }
+
+    /// The safetensors dtype closest to how this type is stored, with quantized blocks as I8
+    pub(super) fn stored_as(self) -> Dtype {
+        match self {
+            SourceDtype::F64 => Dtype::F64,
+            SourceDtype::F32 => Dtype::F32,
+            SourceDtype::F16 => Dtype::F16,
+            SourceDtype::BF16 => Dtype::BF16,
+            SourceDtype::I8 | SourceDtype::Q8_0 | SourceDtype::Q4_0 => Dtype::I8,
+            SourceDtype::U8 => Dtype::U8,
+        }
+    }
+
+    fn is_float(self) -> bool {
+        !matches!(self, SourceDtype::I8 | SourceDtype::U8)
+    }
+}
+
+/// A tensor stored in an input checkpoint, located by its byte range in the file
+#[derive(Debug, Clone)]
+pub(super) struct SourceTensor {
+    pub(super) name: String,
+    pub(super) shape: Vec<usize>,
+    pub(super) dtype: SourceDtype,
+    pub(super) data: Range<usize>,
+}
+
+/// Convert a safetensors, GGUF or npz checkpoint (detected by the file extension) to safetensors, keeping tensor
+/// names and shapes.
+///
+/// Tensors are streamed from the memory mapped input, so at most one converted tensor is held in memory at a time.
+/// Integer tensors are copied as-is. GGUF files can contain F32, F16, Q8_0 and Q4_0 tensors, and npz files must be
+/// uncompressed (`np.savez`, not `np.savez_compressed`).
+pub fn convert_checkpoint(
+    input: &Path,
+    output: &Path,
+    options: &ConvertOptions,
+) -> Result<(), ConvertError> {
+    if let Some(dtype) = options.dtype {
+        if !matches!(dtype, Dtype::F32 | Dtype::F16 | Dtype::BF16) {
+            return Err(ConvertError::Format(format!(
+                "Can't convert float tensors to {dtype:?}"
+            )));
+        }
+    }
+    let file = File::open(input)?;
+    let buffer = unsafe { MmapOptions::new().map(&file)? };
+    let sources = match input.extension().and_then(|e| e.to_str()) {
+        Some("safetensors") => read_safetensors(&buffer)?,
+        Some("gguf") => gguf::read_tensors(&buffer)?,
+        Some("npz") => npz::read_tensors(&buffer)?,
+        _ => {
+            return Err(ConvertError::Format(format!(
+                "Unknown checkpoint format for {}",
+                input.display()
+            )))
+        }
+    };
+
+    let mut outputs = vec![];
+    for source in sources {
+        if source.data.end > buffer.len()
+            || source.dtype.size_of(n_elements(&source.shape)) != source.data.len()
+        {
+            return Err(ConvertError::Format(format!(
+                "Tensor \"{}\" doesn't fit in the file",
+                source.name
+            )));
+        }
+        let data = &buffer[source.data.clone()];
+        if !source.dtype.is_float() {
+            outputs.push((
+                source.name.clone(),
+                ConvertedTensor::new(data, &source, Output::Raw),
+            ));
+        } else if options.quantize_int8 && source.shape.len() >= 2 {
+            outputs.push((
+                format!("{}.scales", source.name),
+                ConvertedTensor::new(data, &source, Output::Scales),
+            ));
+            outputs.push((
+                source.name.clone(),
+                ConvertedTensor::new(data, &source, Output::Int8),
+            ));
+        } else {
+            let dtype = options.dtype.unwrap_or(match source.dtype {
+                SourceDtype::F16 => Dtype::F16,
+                SourceDtype::BF16 => Dtype::BF16,
+                _ => Dtype::F32,
+            });
+            outputs.push((
+                source.name.clone(),
+                ConvertedTensor::new(data, &source, Output::Float(dtype)),
+            ));
+        }
+    }
+    safetensors::serialize_to_file(outputs, &None, output)?;
+    Ok(())
+}
+
+fn n_elements(shape: &[usize]) -> usize {
+    shape.iter().product()
+}
+
+fn read_safetensors(buffer: &[u8]) -> Result<Vec<SourceTensor>, ConvertError> {
+    let (header_size, metadata) = SafeTensors::read_metadata(buffer)?;
+    let data_start = 8 + header_size;
+    metadata
+        .tensors()
+        .into_iter()
+        .map(|(name, info)| {
+            let dtype = match info.dtype {
+                Dtype::F64 => SourceDtype::F64,
+                Dtype::F32 => SourceDtype::F32,
+                Dtype::F16 => SourceDtype::F16,
+                Dtype::BF16 => SourceDtype::BF16,
+                Dtype::I8 => SourceDtype::I8,
+                Dtype::U8 => SourceDtype::U8,
+                d => {
+                    return Err(ConvertError::Format(format!(
+                        "Tensor \"{name}\" has unsupported dtype {d:?}"
+                    )))
+                }
+            };
+            Ok(SourceTensor {
+                name,
+                shape: info.shape.clone(),
+                dtype,
+                data: data_start + info.data_offsets.0..data_start + info.data_offsets.1,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Output {
+    /// Copy the bytes as they are
+    Raw,
+    /// Float tensor of the given type
+    Float(Dtype),
+    /// Int8 values of a quantized tensor
+    Int8,
+    /// Per channel scales of a quantized tensor
+    Scales,
+}
+
+/// A tensor to be written out, converted from the source bytes only once it's being written
+struct ConvertedTensor<'a> {
+    source: &'a [u8],
+    source_dtype: SourceDtype,
+    shape: Vec<usize>,
+    output: Output,
+}
+
+impl<'a> ConvertedTensor<'a> {
+    fn new(source: &'a [u8], tensor: &SourceTensor, output: Output) -> Self {
+        Self {
+            source,
+            source_dtype: tensor.dtype,
+            shape: match output {
+                Output::Scales => vec![tensor.shape[0]],
+                _ => tensor.shape.clone(),
+            },
+            output,
+        }
+    }
+
+    /// Symmetric scale for each channel, mapping the channel's absolute max to 127
+    fn channel_scales(values: &[f32], channels: usize) -> Vec<f32> {
+        values
+            .chunks(values.len() / channels.max(1))
+            .map(|c| c.iter().fold(0_f32, |m, v| m.max(v.abs())) / 127.)
+            .collect()
+    }
+}
+
+impl View for ConvertedTensor<'_> {
+    fn dtype(&self) -> Dtype {
+        match self.output {
+            Output::Raw => match self.source_dtype {
+                SourceDtype::I8 => Dtype::I8,
+                _ => Dtype::U8,
+            },
+            Output::Float(dtype) => dtype,
+            Output::Int8 => Dtype::I8,
+            Output::Scales => Dtype::F32,
+        }
+    }
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+    fn data(&self) -> Cow<'_, [u8]> {
+        let float_source = || decode(self.source, self.source_dtype);
+        match self.output {
+            Output::Raw => self.source.into(),
+            Output::Float(Dtype::F32) if self.source_dtype == SourceDtype::F32 => {
+                self.source.into()
+            }
+            Output::Float(Dtype::F16) if self.source_dtype == SourceDtype::F16 => {
+                self.source.into()
+            }
+            Output::Float(Dtype::BF16) if self.source_dtype == SourceDtype::BF16 => {
+                self.source.into()
+            }
+            Output::Float(Dtype::F16) => float_source()
+                .into_iter()
+                .flat_map(|v| f16::from_f32(v).to_le_bytes())
+                .collect::<Vec<_>>()
+                .into(),
+            Output::Float(Dtype::BF16) => float_source()
+                .into_iter()
+                .flat_map(|v| bf16::from_f32(v).to_le_bytes())
+                .collect::<Vec<_>>()
+                .into(),
+            Output::Float(_) => float_source()
+                .into_iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>()
+                .into(),
+            Output::Int8 => {
+                let values = float_source();
+                let scales = Self::channel_scales(&values, self.shape[0]);
+                let channel_size = values.len() / self.shape[0].max(1);
+                values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| {
+                        let scale = scales[i / channel_size];
+                        if scale == 0. {
+                            0
+                        } else {
+                            (v / scale).round().clamp(-127., 127.) as i8 as u8
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .into()
+            }
+            Output::Scales => Self::channel_scales(&float_source(), self.shape[0])
+                .into_iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>()
+                .into(),
+        }
+    }
+    fn data_len(&self) -> usize {
+        let n = n_elements(&self.shape);
+        match self.output {
+            Output::Raw => self.source.len(),
+            Output::Float(dtype) => n * dtype.size(),
+            Output::Int8 => n,
+            Output::Scales => n * 4,
+        }
+    }
+}
+
+/// Decode little endian float or GGML quantized data to fp32
+pub(super) fn decode(bytes: &[u8], dtype: SourceDtype) -> Vec<f32> {
+    match dtype {
+        SourceDtype::F64 => bytes
+            .chunks_exact(8)
+            .map(|c| f64::from_le_bytes(c.try_into().unwrap()) as f32)
+            .collect(),
+        SourceDtype::F32 => bytes
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+            .collect(),
+        SourceDtype::F16 => bytes
+            .chunks_exact(2)
+            .map(|c| f16::from_le_bytes([c[0], c[1]]).to_f32())
+            .collect(),
+        SourceDtype::BF16 => bytes
+            .chunks_exact(2)
+            .map(|c| bf16::from_le_bytes([c[0], c[1]]).to_f32())
+            .collect(),
+        SourceDtype::I8 => bytes.iter().map(|b| *b as i8 as f32).collect(),
+        SourceDtype::U8 => bytes.iter().map(|b| *b as f32).collect(),
+        SourceDtype::Q8_0 => bytes
+            .chunks_exact(34)
+            .flat_map(|block| {
+                let scale = f16::from_le_bytes([block[0], block[1]]).to_f32();
+                block[2..].iter().map(move |q| *q as i8 as f32 * scale)
+            })
+            .collect(),
+        SourceDtype::Q4_0 => bytes
+            .chunks_exact(18)
+            .flat_map(|block| {
+                let scale = f16::from_le_bytes([block[0], block[1]]).to_f32();
+                // Low nibbles hold the first half of the block, high nibbles the second half
+                let low = block[2..].iter().map(|q| (q & 0xF) as i32);
+                let high = block[2..].iter().map(|q| (q >> 4) as i32);
+                low.chain(high).map(move |q| (q - 8) as f32 * scale)
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use safetensors::{tensor::Dtype, SafeTensors};
+
+    use super::{convert_checkpoint, decode, ConvertOptions, SourceDtype};
+    use crate::{
+        prelude::*,
+        tests::{assert_close_precision, assert_exact},
+    };
+
+    /// The fixture checkpoint, as written by resources/fixtures/multi_dtype.py
+    struct Checkpoint {
+        weight: GraphTensor<R2<3, 4>>,
+        bias: GraphTensor<R1<4>>,
+        embed: GraphTensor<R2<2, 32>>,
+        norm: GraphTensor<R1<4>>,
+    }
+
+    impl SerializeModule for Checkpoint {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("weight", self.weight);
+            s.tensor("bias", self.bias);
+            s.tensor("embed", self.embed);
+            s.tensor("layer0/norm", self.norm);
+        }
+    }
+
+    fn fixture(extension: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources/fixtures")
+            .join(format!("multi_dtype.{extension}"))
+    }
+
+    /// Reference values from resources/fixtures/multi_dtype.py
+    fn expected() -> [Vec<f32>; 4] {
+        [
+            (0..12).map(|i| (i - 5) as f32 * 0.25).collect(),
+            vec![0.5, -1.0, 2.0, 0.125],
+            (0..64)
+                .map(|i| {
+                    if i % 32 == 0 {
+                        31.75
+                    } else {
+                        ((i * 37) % 255 - 127) as f32 * 0.25
+                    }
+                })
+                .collect(),
+            vec![1.0, 1.5, 2.0, 2.5],
+        ]
+    }
+
+    /// Convert the fixture, returning the output file and the tensors loaded from it with the SafeTensorLoader
+    fn convert_and_load(extension: &str, options: ConvertOptions) -> (Vec<u8>, [Vec<f32>; 4]) {
+        let output = std::env::temp_dir().join(format!(
+            "luminal_convert_{extension}_{:?}_{}.safetensors",
+            options.dtype, options.quantize_int8
+        ));
+        convert_checkpoint(&fixture(extension), &output, &options).unwrap();
+
+        let mut cx = Graph::new();
+        let checkpoint = Checkpoint {
+            weight: cx.tensor().retrieve(),
+            bias: cx.tensor().retrieve(),
+            embed: cx.tensor().retrieve(),
+            norm: cx.tensor().retrieve(),
+        };
+        SafeTensorLoader::new(&[output.to_str().unwrap()]).load(&checkpoint, &mut cx);
+        cx.execute();
+        (
+            std::fs::read(output).unwrap(),
+            [
+                checkpoint.weight.data(),
+                checkpoint.bias.data(),
+                checkpoint.embed.data(),
+                checkpoint.norm.data(),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_convert_formats() {
+        for (extension, embed_dtype) in [
+            ("safetensors", Dtype::BF16),
+            ("gguf", Dtype::F32),
+            ("npz", Dtype::F32),
+        ] {
+            let (file, loaded) = convert_and_load(extension, ConvertOptions::default());
+            for (loaded, expected) in loaded.iter().zip(expected()) {
+                assert_exact(loaded, &expected);
+            }
+
+            // Names, shapes and (widened) dtypes are kept
+            let file = SafeTensors::deserialize(&file).unwrap();
+            for (name, dtype, shape) in [
+                ("weight", Dtype::F32, vec![3, 4]),
+                ("bias", Dtype::F16, vec![4]),
+                ("embed", embed_dtype, vec![2, 32]),
+                ("layer0.norm", Dtype::F32, vec![4]),
+            ] {
+                let tensor = file.tensor(name).unwrap();
+                assert_eq!(tensor.dtype(), dtype, "{name} from {extension}");
+                assert_eq!(tensor.shape(), shape, "{name} from {extension}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_convert_dtypes() {
+        for extension in ["safetensors", "gguf", "npz"] {
+            for dtype in [Dtype::F32, Dtype::F16, Dtype::BF16] {
+                let (file, loaded) = convert_and_load(
+                    extension,
+                    ConvertOptions {
+                        dtype: Some(dtype),
+                        ..Default::default()
+                    },
+                );
+                // All fixture values are exactly representable in every dtype
+                for (loaded, expected) in loaded.iter().zip(expected()) {
+                    assert_exact(loaded, &expected);
+                }
+                let file = SafeTensors::deserialize(&file).unwrap();
+                for name in ["weight", "bias", "embed", "layer0.norm"] {
+                    assert_eq!(file.tensor(name).unwrap().dtype(), dtype);
+                }
+            }
+        }
+        // Integer tensors are left alone
+        let (file, _) = convert_and_load(
+            "safetensors",
+            ConvertOptions {
+                dtype: Some(Dtype::F16),
+                ..Default::default()
+            },
+        );
+        let file = SafeTensors::deserialize(&file).unwrap();
+        assert_eq!(file.tensor("qweight").unwrap().dtype(), Dtype::I8);
+        assert_eq!(file.tensor("qweight.scales").unwrap().dtype(), Dtype::F16);
+    }
+
+    #[test]
+    fn test_convert_quantized() {
+        for extension in ["safetensors", "gguf", "npz"] {
+            let (file, loaded) = convert_and_load(
+                extension,
+                ConvertOptions {
+                    quantize_int8: true,
+                    ..Default::default()
+                },
+            );
+            for (loaded, expected) in loaded.iter().zip(expected()) {
+                assert_close_precision(loaded, &expected, 1);
+            }
+            // Only matrices are quantized
+            let file = SafeTensors::deserialize(&file).unwrap();
+            for (name, dtype) in [
+                ("weight", Dtype::I8),
+                ("weight.scales", Dtype::F32),
+                ("embed", Dtype::I8),
+                ("embed.scales", Dtype::F32),
+                ("bias", Dtype::F16),
+                ("layer0.norm", Dtype::F32),
+            ] {
+                assert_eq!(file.tensor(name).unwrap().dtype(), dtype);
+            }
+            assert_eq!(file.tensor("embed.scales").unwrap().shape(), [2]);
+        }
+    }
+
+    #[test]
+    fn test_q4_0_blocks() {
+        // Rows of 40 values span a padded second block
+        let data = (0..3 * 40)
+            .map(|i| ((i * 37) % 101) as f32 * 0.1 - 5.)
+            .collect::<Vec<_>>();
+        let blocks = Q4_0Blocks::quantize(&data, 40);
+        assert_eq!(blocks.0.len(), 3 * 2 * Q4_0Blocks::BLOCK_BYTES);
+
+        // Laid out the way GGUF files store Q4_0, padding included
+        let padded = decode(&blocks.0, SourceDtype::Q4_0);
+        let dequantized = blocks.dequantize(40);
+        for row in 0..3 {
+            assert_exact(
+                &padded[row * 64..row * 64 + 40],
+                &dequantized[row * 40..(row + 1) * 40],
+            );
+            assert_exact(&padded[row * 64 + 40..(row + 1) * 64], &[0.; 24]);
+        }
+        // Values are at most 5 from 0, so steps are at most 5 / 8. Only the end of the range opposite the block's max,
+        // which is clamped to 7 steps, can be off by a whole step
+        for (a, b) in data.iter().zip(&dequantized) {
+            assert!((a - b).abs() <= 5. / 8. + 1e-3, "{a} quantized to {b}");
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Reading tensor locations from GGUF files.
+//!
+//! Spec: https://github.com/ggerganov/ggml/blob/master/docs/gguf.md
+
+use super::convert::{ConvertError, SourceDtype, SourceTensor};
+
+const DEFAULT_ALIGNMENT: usize = 32;
+/// The fewest bytes a tensor's entry in the header takes: an empty name's length, the dimension count, the type and
+/// the offset
+const MIN_TENSOR_INFO_BYTES: usize = 20;
+
+fn overflow(what: &str) -> ConvertError {
+    ConvertError::Format(format!("GGUF {what} overflows"))
+}
+
+/// Cursor over the header of a GGUF file
+struct Reader<'a> {
+    buffer: &'a [u8],
+    position: usize,
+    /// Version 1 stores lengths and counts as u32, later versions use u64
+    version: u32,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], ConvertError> {
+        let bytes = self
+            .buffer
+            .get(
+                self.position
+                    ..self
+                        .position
+                        .checked_add(n)
+                        .ok_or_else(|| overflow("header length"))?,
+            )
+            .ok_or_else(|| ConvertError::Format("GGUF header is truncated".to_string()))?;
+        self.position += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, ConvertError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ConvertError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A length or count, which depends on the version
+    fn size(&mut self) -> Result<usize, ConvertError> {
+        Ok(if self.version == 1 {
+            self.u32()? as usize
+        } else {
+            self.u64()? as usize
+        })
+    }
+
+    fn string(&mut self) -> Result<String, ConvertError> {
+        let len = self.size()?;
+        Ok(String::from_utf8_lossy(self.bytes(len)?)
+            .trim_end_matches('\0')
+            .to_string())
+    }
+
+    /// Read a metadata value, returning it if it's an unsigned integer
+    fn value(&mut self, value_type: u32) -> Result<Option<u64>, ConvertError> {
+        Ok(match value_type {
+            0 | 1 | 7 => Some(self.bytes(1)?[0] as u64),
+            2 | 3 => Some(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()) as u64),
+            4 | 5 => Some(self.u32()? as u64),
+            6 => {
+                self.bytes(4)?;
+                None
+            }
+            8 => {
+                self.string()?;
+                None
+            }
+            9 => {
+                let (element_type, len) = (self.u32()?, self.size()?);
+                for _ in 0..len {
+                    self.value(element_type)?;
+                }
+                None
+            }
+            10 | 11 => Some(self.u64()?),
+            12 => {
+                self.bytes(8)?;
+                None
+            }
+            t => {
+                return Err(ConvertError::Format(format!(
+                    "Unknown GGUF metadata type {t}"
+                )))
+            }
+        })
+    }
+}
+
+/// Find the tensors in a GGUF file. Shapes are outermost dimension first, the reverse of how GGUF stores them.
+pub(super) fn read_tensors(buffer: &[u8]) -> Result<Vec<SourceTensor>, ConvertError> {
+    let mut reader = Reader {
+        buffer,
+        position: 0,
+        version: 0,
+    };
+    if reader.bytes(4)? != b"GGUF" {
+        return Err(ConvertError::Format("Not a GGUF file".to_string()));
+    }
+    reader.version = reader.u32()?;
+    if !(1..=3).contains(&reader.version) {
+        return Err(ConvertError::Format(format!(
+            "Unsupported GGUF version {}",
+            reader.version
+        )));
+    }
+    let (n_tensors, n_metadata) = (reader.size()?, reader.size()?);
+
+    let mut alignment = DEFAULT_ALIGNMENT;
+    for _ in 0..n_metadata {
+        let key = reader.string()?;
+        let value_type = reader.u32()?;
+        let value = reader.value(value_type)?;
+        if key == "general.alignment" {
+            alignment = value.unwrap_or(DEFAULT_ALIGNMENT as u64) as usize;
+            if alignment == 0 || !alignment.is_multiple_of(8) {
+                return Err(ConvertError::Format(format!(
+                    "GGUF alignment {alignment} isn't a multiple of 8"
+                )));
+            }
+        }
+    }
+
+    // The count comes from the file, so don't trust it further than the entries that could fit in it
+    let mut tensors = Vec::with_capacity(n_tensors.min(buffer.len() / MIN_TENSOR_INFO_BYTES));
+    for _ in 0..n_tensors {
+        let name = reader.string()?;
+        let n_dims = reader.u32()?;
+        let mut shape = (0..n_dims)
+            .map(|_| reader.size())
+            .collect::<Result<Vec<_>, _>>()?;
+        shape.reverse();
+        let dtype = match reader.u32()? {
+            0 => SourceDtype::F32,
+            1 => SourceDtype::F16,
+            2 => SourceDtype::Q4_0,
+            8 => SourceDtype::Q8_0,
+            t => {
+                return Err(ConvertError::Format(format!(
+                    "Tensor \"{name}\" has unsupported GGML type {t}"
+                )))
+            }
+        };
+        // No type takes more than 8 bytes an element, so the size in bytes can't overflow either
+        let n_elements = shape
+            .iter()
+            .try_fold(1_usize, |n, d| n.checked_mul(*d))
+            .filter(|n| n.checked_mul(8).is_some())
+            .ok_or_else(|| overflow(&format!("tensor \"{name}\" size")))?;
+        if matches!(dtype, SourceDtype::Q8_0 | SourceDtype::Q4_0) && n_elements % 32 != 0 {
+            return Err(ConvertError::Format(format!(
+                "Tensor \"{name}\" doesn't fill whole quantization blocks"
+            )));
+        }
+        let offset = reader.u64()? as usize;
+        let n_bytes = dtype.size_of(n_elements);
+        let end = offset
+            .checked_add(n_bytes)
+            .ok_or_else(|| overflow(&format!("tensor \"{name}\" offset")))?;
+        tensors.push(SourceTensor {
+            name,
+            shape,
+            dtype,
+            data: offset..end,
+        });
+    }
+
+    // Tensor data starts at the next aligned position after the header
+    let data_start = reader
+        .position
+        .checked_next_multiple_of(alignment)
+        .ok_or_else(|| overflow("data offset"))?;
+    for tensor in &mut tensors {
+        let (Some(start), Some(end)) = (
+            data_start.checked_add(tensor.data.start),
+            data_start.checked_add(tensor.data.end),
+        ) else {
+            return Err(overflow(&format!("tensor \"{}\" offset", tensor.name)));
+        };
+        if end > buffer.len() {
+            return Err(ConvertError::Format(format!(
+                "Tensor \"{}\" doesn't fit in the file",
+                tensor.name
+            )));
+        }
+        tensor.data = start..end;
+    }
+    Ok(tensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_tensors;
+    use crate::serialization::ConvertError;
+
+    /// A version 3 GGUF header with these metadata entries, followed by one f32 tensor "t" of this shape and offset
+    fn header(
+        metadata: &[(&str, u32, &[u8])],
+        n_tensors: u64,
+        shape: &[u64],
+        offset: u64,
+    ) -> Vec<u8> {
+        let mut bytes = b"GGUF".to_vec();
+        bytes.extend(3_u32.to_le_bytes());
+        bytes.extend(n_tensors.to_le_bytes());
+        bytes.extend((metadata.len() as u64).to_le_bytes());
+        for (key, value_type, value) in metadata {
+            bytes.extend((key.len() as u64).to_le_bytes());
+            bytes.extend(key.as_bytes());
+            bytes.extend(value_type.to_le_bytes());
+            bytes.extend(*value);
+        }
+        bytes.extend(1_u64.to_le_bytes());
+        bytes.push(b't');
+        bytes.extend((shape.len() as u32).to_le_bytes());
+        for d in shape {
+            bytes.extend(d.to_le_bytes());
+        }
+        bytes.extend(0_u32.to_le_bytes());
+        bytes.extend(offset.to_le_bytes());
+        bytes
+    }
+
+    fn format_error(buffer: &[u8]) -> String {
+        match read_tensors(buffer) {
+            Err(ConvertError::Format(e)) => e,
+            other => panic!("Expected a format error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_read_tensors() {
+        let mut file = header(&[], 1, &[4, 2], 0);
+        assert!(format_error(&file).contains("doesn't fit"));
+        file.resize(file.len().next_multiple_of(32) + 32, 0);
+        let tensors = read_tensors(&file).unwrap();
+        assert_eq!(tensors[0].shape, vec![2, 4]);
+        assert_eq!(tensors[0].data.len(), 32);
+        assert_eq!(tensors[0].data.start % 32, 0);
+    }
+
+    #[test]
+    fn test_malformed_headers() {
+        let alignment = |a: u32| {
+            let mut file = header(&[("general.alignment", 4, &a.to_le_bytes())], 1, &[4], 0);
+            file.resize(file.len() + 128, 0);
+            file
+        };
+        assert_eq!(read_tensors(&alignment(64)).unwrap()[0].data.start % 64, 0);
+        assert!(format_error(&alignment(0)).contains("alignment"));
+        assert!(format_error(&alignment(12)).contains("alignment"));
+
+        // A huge tensor count runs out of header instead of allocating for it
+        assert!(format_error(&header(&[], u64::MAX, &[4], 0)).contains("truncated"));
+        assert!(format_error(&header(&[], 1, &[u64::MAX, 2], 0)).contains("overflows"));
+        assert!(format_error(&header(&[], 1, &[4], u64::MAX - 8)).contains("overflows"));
+        assert!(format_error(&header(&[], 1, &[4], u64::MAX - 40)).contains("overflows"));
+    }
+}
@@ -0,0 +1,1236 @@
+use crate::op::Function;
+use crate::prelude::{Graph, GraphTensor, Q8_0Blocks, Shape, Tensor};
+use half::{bf16, f16};
+use itertools::Itertools;
+use memmap2::MmapOptions;
+use petgraph::stable_graph::NodeIndex;
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+/// The types tensors are stored as in safetensors files
+pub use safetensors::tensor::Dtype;
+use safetensors::tensor::{TensorView, View};
+use safetensors::{SafeTensorError, SafeTensors};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use super::module::state_dict;
+
+mod convert;
+mod gguf;
+mod npz;
+mod quantized;
+use convert::SourceDtype;
+pub use convert::{convert_checkpoint, ConvertError, ConvertOptions};
+pub use quantized::QuantizationScheme;
+
+/// Tell luminal how to represent the module as a dict of (String, NodeIndex)'s
+pub trait SerializeModule {
+    fn serialize(&self, s: &mut Serializer);
+}
+
+/// Something that can load the state of a module into the graph
+pub trait Loader {
+    type Output;
+    fn load<M: SerializeModule>(self, model: &M, graph: &mut Graph) -> Self::Output;
+}
+
+/// Something that can save the state of a module from the graph
+pub trait Saver {
+    type Saved;
+    fn save<M: SerializeModule>(self, model: &M, graph: &mut Graph) -> Self::Saved;
+}
+
+/// Extract the state dict from a model
+pub struct StateDictSaver;
+
+impl Saver for StateDictSaver {
+    type Saved = FxHashMap<String, Tensor>;
+    fn save<M: SerializeModule>(self, model: &M, graph: &mut Graph) -> Self::Saved {
+        // Attempt to get all tensor data from the graph
+        state_dict(model)
+            .into_iter()
+            .map(|(k, v)| (k, graph.get_tensor(v, 0).unwrap()))
+            .collect()
+    }
+}
+
+/// Save a model to a safetensor file
+///
+/// Weights holding [`Q8_0Blocks`] or [`Q4_0Blocks`](crate::prelude::Q4_0Blocks) are saved as they are, in the
+/// layout described by [`QuantizationScheme`], so the [`SafeTensorLoader`] can load them without quantizing again.
+pub struct SafeTensorSaver {
+    path: String,
+}
+
+impl SafeTensorSaver {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+        }
+    }
+}
+
+impl Saver for SafeTensorSaver {
+    type Saved = Result<(), SafeTensorError>;
+    fn save<M: SerializeModule>(self, model: &M, graph: &mut Graph) -> Self::Saved {
+        let mut tensors = vec![];
+        let mut schemes = vec![];
+        // Attempt to get all tensor data from the graph
+        for (name, node) in state_dict(model) {
+            let tensor = graph.get_tensor_ref(node, 0).unwrap();
+            let Some((scheme, blocks)) = QuantizationScheme::of(tensor) else {
+                let data = tensor.data.as_any().downcast_ref::<Vec<f32>>().unwrap();
+                tensors.push((
+                    name,
+                    SavedTensor {
+                        dtype: Dtype::F32,
+                        shape: vec![data.len()],
+                        data: data.iter().flat_map(|f| f.to_le_bytes()).collect(),
+                    },
+                ));
+                continue;
+            };
+            let (values, scales) = quantized::split_blocks(scheme, blocks);
+            let n_blocks = scales.len() / 2;
+            tensors.push((
+                format!("{name}{}", quantized::BLOCK_SCALES_SUFFIX),
+                SavedTensor {
+                    dtype: Dtype::F16,
+                    shape: vec![n_blocks],
+                    data: scales,
+                },
+            ));
+            tensors.push((
+                name.clone(),
+                SavedTensor {
+                    dtype: scheme.packed_dtype(),
+                    shape: vec![n_blocks, scheme.value_bytes()],
+                    data: values,
+                },
+            ));
+            schemes.push((name, scheme));
+        }
+        let metadata = quantized::write_metadata(
+            schemes
+                .iter()
+                .map(|(name, scheme)| (name.as_str(), *scheme)),
+        );
+        safetensors::serialize_to_file(
+            tensors.iter().map(|(name, tensor)| (name.as_str(), tensor)),
+            &metadata,
+            self.path.as_ref(),
+        )
+    }
+}
+
+/// A tensor as it's written to a safetensors file
+struct SavedTensor {
+    dtype: Dtype,
+    shape: Vec<usize>,
+    data: Vec<u8>,
+}
+
+impl View for &SavedTensor {
+    fn dtype(&self) -> Dtype {
+        self.dtype
+    }
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.data)
+    }
+    fn data_len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Load the model from a state dict
+pub struct StateDictLoader {
+    state_dict: FxHashMap<String, Tensor>,
+}
+
+impl StateDictLoader {
+    pub fn new(state_dict: FxHashMap<String, Tensor>) -> Self {
+        Self { state_dict }
+    }
+}
+
+impl Loader for StateDictLoader {
+    type Output = ();
+    fn load<M: SerializeModule>(mut self, model: &M, graph: &mut Graph) {
+        for (s, n) in state_dict(model) {
+            let t = self.state_dict.remove(&s).unwrap();
+            graph.no_delete.insert(n);
+            graph.tensors.insert((n, 0), t);
+        }
+    }
+}
+
+/// Weights a model expects that aren't in a checkpoint, from [`SafeTensorLoader::missing_weights`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MissingWeights {
+    /// Weights the model has but no file holds. Loading them fails when the graph runs.
+    pub missing: Vec<String>,
+    /// Optional weights the model was built without, like the biases of layers without one. Loaders don't look for
+    /// these, so they aren't a problem.
+    pub absent: Vec<String>,
+}
+
+/// How the weights of a model were matched to the tensors in a checkpoint, returned by loading with a
+/// [`SafeTensorLoader`]. Weight names have their path components joined by '.'.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// Weights matched to a tensor, as (weight, tensor name in the file)
+    pub loaded: Vec<(String, String)>,
+    /// Weights matched to a tensor that a weight in `loaded` was matched to first, as (weight, tensor name in the
+    /// file). The tensor is read once and shared between them.
+    pub aliased: Vec<(String, String)>,
+    /// Weights no tensor matched. They aren't loaded, so they have to be set some other way before executing.
+    pub missing: Vec<String>,
+    /// Tensors in the files no weight matched, besides the companions of loaded quantized weights. Entries without
+    /// elements only hold metadata, so they're never matched or reported.
+    pub unexpected: Vec<String>,
+    /// Weights loaded as quantized blocks, saved by the [`SafeTensorSaver`]. These are meant for backends that
+    /// multiply quantized weights directly, so their nodes are to be passed to the backend's quantized compiler.
+    pub quantized: Vec<(NodeIndex, QuantizationScheme)>,
+}
+
+impl LoadReport {
+    /// The nodes of the weights loaded as blocks of `scheme`
+    pub fn quantized_nodes(&self, scheme: QuantizationScheme) -> Vec<NodeIndex> {
+        self.quantized
+            .iter()
+            .filter(|(_, s)| *s == scheme)
+            .map(|(node, _)| *node)
+            .collect()
+    }
+}
+
+/// Matches the weights of a model to the names of tensors in a checkpoint
+pub trait NameResolver {
+    /// Names to look for a weight under, most preferred first, given its path in the model (components joined by '/')
+    fn candidates(&self, path: &str) -> Vec<String>;
+
+    /// The canonical form of a name. When no tensor has exactly one of a weight's candidate names, a tensor with the
+    /// same canonical form as a candidate matches instead. Defaults to the name itself.
+    fn normalize(&self, name: &str) -> String {
+        name.to_string()
+    }
+}
+
+/// Tries the path components joined by '.', '/' and "::", then falls back to matching names regardless of case and
+/// of which of those separators they mix
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultNameResolver;
+
+impl NameResolver for DefaultNameResolver {
+    fn candidates(&self, path: &str) -> Vec<String> {
+        [".", "/", "::"]
+            .into_iter()
+            .map(|separator| path.replace('/', separator))
+            .collect()
+    }
+
+    fn normalize(&self, name: &str) -> String {
+        name.replace("::", ".").replace('/', ".").to_lowercase()
+    }
+}
+
+/// Where a tensor's data lives (see [`SafeTensorLoader::tensor_sources`]), its stored dtype, and how it's quantized
+/// if it holds blocks
+type TensorSource = (u64, Dtype, Option<QuantizationScheme>);
+
+/// Tensors read by one of the weights aliasing them, with the number of aliases still to take them
+type SharedReads = Rc<RefCell<FxHashMap<String, (Vec<f32>, usize)>>>;
+
+/// Take a tensor another alias already read, dropping it once the last alias has it
+fn take_shared_read(shared: &SharedReads, name: &str) -> Option<Vec<f32>> {
+    let mut shared = shared.borrow_mut();
+    let remaining = {
+        let (_, remaining) = shared.get_mut(name)?;
+        *remaining -= 1;
+        *remaining
+    };
+    if remaining == 0 {
+        shared.remove(name).map(|(data, _)| data)
+    } else {
+        Some(shared[name].0.clone())
+    }
+}
+
+/// Load the model from a safetensor file
+///
+/// Int8 and uint8 weights are dequantized to fp32 on load, using the companion scale tensor (and optional zero point
+/// tensor) stored alongside them in the same file. The scales and zero points can hold either a single value for the
+/// whole tensor, or one value per output channel (the first dimension). Block quantized weights written by the
+/// [`SafeTensorSaver`] are instead loaded as their blocks, and listed in the report's
+/// [`quantized`](LoadReport::quantized).
+///
+/// Weights are matched to tensors by a [`NameResolver`], [`DefaultNameResolver`] unless another is set, and loading
+/// returns a [`LoadReport`] of the matches. Weights matched to the same tensor share a single read of it. Weights are
+/// read on the first execution (or while loading, with [`eager`](SafeTensorLoader::eager)) and kept in the graph
+/// after that. Use [`Graph::reload_weights`] to read them again.
+pub struct SafeTensorLoader {
+    /// The paths to the safetensors file
+    paths: Vec<String>,
+    /// Suffix appended to a quantized weight's name to find its scales
+    scales_suffix: String,
+    /// Suffix appended to a quantized weight's name to find its zero points
+    zeros_suffix: String,
+    /// Matches weights to the tensors in the files
+    resolver: Box<dyn NameResolver>,
+    /// Read the weights while loading, rather than on the first execution
+    eager: bool,
+}
+
+impl SafeTensorLoader {
+    pub fn new<S: ToString>(paths: &[S]) -> Self {
+        Self {
+            paths: paths.iter().map(|s| s.to_string()).collect(),
+            scales_suffix: ".scales".to_string(),
+            zeros_suffix: ".qzeros".to_string(),
+            resolver: Box::new(DefaultNameResolver),
+            eager: false,
+        }
+    }
+
+    /// Match weights to tensors with a custom resolver
+    pub fn resolver<R: NameResolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Box::new(resolver);
+        self
+    }
+
+    /// Read the weights while loading rather than on the first execution, so files that can't be read fail right
+    /// away
+    pub fn eager(mut self) -> Self {
+        self.eager = true;
+        self
+    }
+
+    /// Set the suffixes of the companion tensors holding quantized weights' scales and zero points. Defaults to `.scales` and `.qzeros`
+    pub fn quantization_companions(mut self, scales_suffix: &str, zeros_suffix: &str) -> Self {
+        self.scales_suffix = scales_suffix.to_string();
+        self.zeros_suffix = zeros_suffix.to_string();
+        self
+    }
+
+    /// Compare the weights `model` expects with the tensors in the files, without loading anything. Only the headers
+    /// are read.
+    pub fn missing_weights<M: SerializeModule>(&self, model: &M) -> MissingWeights {
+        let sources = self.tensor_sources();
+        let mut s = Serializer::default();
+        model.serialize(&mut s);
+        let (_, report) = self.resolve(s.state, &sources);
+        MissingWeights {
+            missing: report.missing,
+            absent: s
+                .absent
+                .into_iter()
+                .map(|name| name.replace('/', "."))
+                .sorted()
+                .collect(),
+        }
+    }
+
+    /// Key each tensor in the files by where its data lives (file path and byte range), so tensors loaded from the
+    /// same region get the same key, along with its stored dtype and quantization scheme. Only the headers are read.
+    /// Files that can't be read are skipped, they'll error when the tensors are actually loaded.
+    fn tensor_sources(&self) -> FxHashMap<String, TensorSource> {
+        let mut sources = FxHashMap::default();
+        // Earlier files take precedence, same as when loading
+        for file_path in self.paths.iter().rev() {
+            let Ok(path) = std::fs::canonicalize(file_path) else {
+                continue;
+            };
+            let Ok(file) = File::open(&path) else {
+                continue;
+            };
+            let Ok(buffer) = (unsafe { MmapOptions::new().map(&file) }) else {
+                continue;
+            };
+            let Ok((_, metadata)) = SafeTensors::read_metadata(&buffer) else {
+                continue;
+            };
+            let mut schemes = quantized::read_metadata(metadata.metadata(), file_path);
+            for (name, info) in metadata.tensors() {
+                // Entries without elements only hold metadata
+                if info.shape.iter().product::<usize>() == 0 {
+                    continue;
+                }
+                let mut hasher = FxHasher::default();
+                (&path, info.data_offsets).hash(&mut hasher);
+                // Quantized tensors also depend on which companions they're dequantized with
+                if matches!(info.dtype, Dtype::I8 | Dtype::U8) {
+                    (&self.scales_suffix, &self.zeros_suffix).hash(&mut hasher);
+                }
+                let scheme = schemes.remove(&name);
+                sources.insert(name, (hasher.finish(), info.dtype, scheme));
+            }
+        }
+        sources
+    }
+
+    /// Match each weight to a tensor in the files, returning the matched tensor's name by node along with the report
+    fn resolve(
+        &self,
+        weights: impl IntoIterator<Item = (String, NodeIndex)>,
+        sources: &FxHashMap<String, TensorSource>,
+    ) -> (Vec<(NodeIndex, Option<String>)>, LoadReport) {
+        let mut normalized = FxHashMap::default();
+        for name in sources.keys().sorted() {
+            normalized
+                .entry(self.resolver.normalize(name))
+                .or_insert(name);
+        }
+
+        let mut report = LoadReport::default();
+        let mut matched = FxHashSet::default();
+        let mut resolved = vec![];
+        for (path, node) in weights.into_iter().sorted() {
+            let candidates = self.resolver.candidates(&path);
+            // Exact names take precedence over normalized ones
+            let tensor = candidates
+                .iter()
+                .find(|c| sources.contains_key(*c))
+                .or_else(|| {
+                    candidates
+                        .iter()
+                        .find_map(|c| normalized.get(&self.resolver.normalize(c)).copied())
+                })
+                .cloned();
+            let weight = path.replace('/', ".");
+            match &tensor {
+                Some(tensor) if matched.insert(tensor.clone()) => {
+                    report.loaded.push((weight, tensor.clone()))
+                }
+                Some(tensor) => report.aliased.push((weight, tensor.clone())),
+                None => report.missing.push(weight),
+            }
+            resolved.push((node, tensor));
+        }
+
+        // The companions of quantized weights are read along with them
+        let companions = matched
+            .iter()
+            .filter(|name| matches!(sources[*name].1, Dtype::I8 | Dtype::U8))
+            .flat_map(|name| {
+                [
+                    format!("{name}{}", self.scales_suffix),
+                    format!("{name}{}", self.zeros_suffix),
+                    format!("{name}{}", quantized::BLOCK_SCALES_SUFFIX),
+                ]
+            })
+            .collect::<FxHashSet<_>>();
+        report.unexpected = sources
+            .keys()
+            .filter(|name| !matched.contains(*name) && !companions.contains(*name))
+            .sorted()
+            .cloned()
+            .collect();
+        (resolved, report)
+    }
+}
+
+impl Loader for SafeTensorLoader {
+    type Output = LoadReport;
+    fn load<M: SerializeModule>(self, model: &M, graph: &mut Graph) -> LoadReport {
+        let sources = self.tensor_sources();
+        let (resolved, mut report) = self.resolve(state_dict(model), &sources);
+        let mut readers = FxHashMap::<String, usize>::default();
+        for tensor in resolved.iter().filter_map(|(_, t)| t.clone()) {
+            *readers.entry(tensor).or_default() += 1;
+        }
+        let shared_reads = SharedReads::default();
+        for (node_index, tensor) in resolved {
+            // Missing weights are left for the caller to set
+            let Some(tensor) = tensor else {
+                continue;
+            };
+            let (key, dtype, scheme) = sources[&tensor];
+            graph.content_keys.insert(node_index, key);
+            graph.weight_dtypes.insert(node_index, dtype);
+            if let Some(scheme) = scheme {
+                report.quantized.push((node_index, scheme));
+            }
+            // Keep the loaded weight around, so the file is only read on the first execution
+            graph.no_delete.insert(node_index);
+            graph.loaded_weights.insert(node_index);
+            if let Some(loading_node) = graph
+                .graph
+                .node_weight_mut(node_index)
+                .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
+            {
+                let file_paths = self.paths.clone();
+                let (scales_suffix, zeros_suffix) =
+                    (self.scales_suffix.clone(), self.zeros_suffix.clone());
+                let readers = readers[&tensor];
+                let shared_reads = shared_reads.clone();
+                loading_node.1 = Rc::new(move |_| {
+                    if let Some(data) = take_shared_read(&shared_reads, &tensor) {
+                        return vec![Tensor::new(data)];
+                    }
+                    for file_path in file_paths.iter() {
+                        let file = File::open(file_path).unwrap();
+                        let buffer = unsafe { MmapOptions::new().map(&file).unwrap() };
+                        let safetensors = SafeTensors::deserialize(&buffer).unwrap();
+
+                        if let Ok(tensor_view) = safetensors.tensor(&tensor) {
+                            if let Some(scheme) = scheme {
+                                return vec![quantized::read_blocks(
+                                    scheme,
+                                    &tensor,
+                                    &tensor_view,
+                                    &safetensors,
+                                )];
+                            }
+                            let data = match tensor_view.dtype() {
+                                Dtype::I8 | Dtype::U8 => dequantize(
+                                    &tensor,
+                                    &tensor_view,
+                                    &safetensors,
+                                    &scales_suffix,
+                                    &zeros_suffix,
+                                ),
+                                _ => to_f32(&tensor_view),
+                            };
+                            // Leave the data for the other weights aliasing the tensor
+                            if readers > 1 {
+                                shared_reads
+                                    .borrow_mut()
+                                    .insert(tensor.clone(), (data.clone(), readers - 1));
+                            }
+                            return vec![Tensor {
+                                data: Box::new(data),
+                            }];
+                        }
+                    }
+
+                    panic!("Tensor \"{tensor}\" not found in files");
+                });
+                if self.eager {
+                    let mut loaded = (loading_node.1)(vec![]);
+                    graph.tensors.insert((node_index, 0), loaded.remove(0));
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Load the model from a GGUF file
+///
+/// F32 and F16 tensors are loaded as fp32, and quantized tensors are dequantized to fp32. With
+/// [`keep_quantized`](GgufLoader::keep_quantized), Q8_0 tensors are instead loaded as their raw [`Q8_0Blocks`], for
+/// backends that multiply quantized weights directly. Loading returns the nodes of the weights kept quantized, to be
+/// passed to the backend's quantized compiler.
+///
+/// Weights are read on the first execution and kept in the graph after that, like with the [`SafeTensorLoader`].
+pub struct GgufLoader {
+    path: String,
+    keep_quantized: bool,
+}
+
+impl GgufLoader {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            keep_quantized: false,
+        }
+    }
+
+    /// Load Q8_0 weights as raw blocks rather than dequantizing them
+    pub fn keep_quantized(mut self) -> Self {
+        self.keep_quantized = true;
+        self
+    }
+}
+
+impl Loader for GgufLoader {
+    type Output = Result<Vec<NodeIndex>, ConvertError>;
+    fn load<M: SerializeModule>(self, model: &M, graph: &mut Graph) -> Self::Output {
+        let path = std::fs::canonicalize(&self.path)?;
+        let file = File::open(&path)?;
+        let buffer = unsafe { MmapOptions::new().map(&file)? };
+        let sources = gguf::read_tensors(&buffer)?
+            .into_iter()
+            .map(|t| (t.name.clone(), t))
+            .collect::<FxHashMap<_, _>>();
+        let mut quantized = vec![];
+        for (weight_name, node_index) in state_dict(model) {
+            let source = sources.get(&weight_name.replace('/', ".")).cloned();
+            let keep_quantized = self.keep_quantized
+                && source
+                    .as_ref()
+                    .map(|s| s.dtype == SourceDtype::Q8_0)
+                    .unwrap_or_default();
+            if let Some(source) = &source {
+                let mut hasher = FxHasher::default();
+                (&path, &source.data, keep_quantized).hash(&mut hasher);
+                graph.content_keys.insert(node_index, hasher.finish());
+                graph
+                    .weight_dtypes
+                    .insert(node_index, source.dtype.stored_as());
+            }
+            if keep_quantized {
+                quantized.push(node_index);
+            }
+            graph.no_delete.insert(node_index);
+            graph.loaded_weights.insert(node_index);
+            if let Some(loading_node) = graph
+                .graph
+                .node_weight_mut(node_index)
+                .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
+            {
+                let path = path.clone();
+                loading_node.1 = Rc::new(move |_| {
+                    let Some(source) = &source else {
+                        panic!("Tensor \"{weight_name}\" not found in file");
+                    };
+                    let file = File::open(&path).unwrap();
+                    let buffer = unsafe { MmapOptions::new().map(&file).unwrap() };
+                    let bytes = &buffer[source.data.clone()];
+                    if keep_quantized {
+                        vec![Tensor::new(Q8_0Blocks(bytes.to_vec()))]
+                    } else {
+                        vec![Tensor::new(convert::decode(bytes, source.dtype))]
+                    }
+                });
+            }
+        }
+        Ok(quantized)
+    }
+}
+
+/// Convert a floating point or 8-bit integer tensor to fp32
+fn to_f32(tensor_view: &TensorView) -> Vec<f32> {
+    let bytes = tensor_view.data();
+    match tensor_view.dtype() {
+        Dtype::F32 => bytes
+            .chunks_exact(4)
+            .map(|c| f32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+            .collect(),
+        Dtype::F16 => bytes
+            .chunks_exact(2)
+            .map(|c| f16::from_ne_bytes([c[0], c[1]]).to_f32())
+            .collect(),
+        Dtype::BF16 => bytes
+            .chunks_exact(2)
+            .map(|c| bf16::from_ne_bytes([c[0], c[1]]).to_f32())
+            .collect(),
+        Dtype::I8 => bytes.iter().map(|b| *b as i8 as f32).collect(),
+        Dtype::U8 => bytes.iter().map(|b| *b as f32).collect(),
+        _ => panic!("{:?} is not a supported dtype", tensor_view.dtype()),
+    }
+}
+
+/// Dequantize an 8-bit weight as (weight - zero point) * scale
+fn dequantize(
+    name: &str,
+    weight: &TensorView,
+    safetensors: &SafeTensors,
+    scales_suffix: &str,
+    zeros_suffix: &str,
+) -> Vec<f32> {
+    let channels = weight.shape().first().copied().unwrap_or(1);
+    // Get a companion tensor, checking it holds either one value or one value per channel
+    let companion = |suffix: &str| {
+        let companion_name = format!("{name}{suffix}");
+        safetensors.tensor(&companion_name).ok().map(|view| {
+            let data = to_f32(&view);
+            assert!(
+                data.len() == 1 || data.len() == channels,
+                "\"{companion_name}\" has {} elements, expected 1 (per-tensor) or {channels} (per-channel)",
+                data.len()
+            );
+            data
+        })
+    };
+    let scales = companion(scales_suffix).unwrap_or_else(|| {
+        panic!("Quantized tensor \"{name}\" has no \"{name}{scales_suffix}\" scale tensor")
+    });
+    let zeros = companion(zeros_suffix).unwrap_or_else(|| vec![0.]);
+
+    let weight = to_f32(weight);
+    let channel_size = weight.len() / channels.max(1);
+    weight
+        .iter()
+        .enumerate()
+        .map(|(i, w)| {
+            let channel = i / channel_size.max(1);
+            (w - zeros[channel % zeros.len()]) * scales[channel % scales.len()]
+        })
+        .collect()
+}
+
+/// Serializer keeps track of the tensors and modules that make up a model
+#[derive(Debug, Default)]
+pub struct Serializer {
+    current_path: Vec<String>,
+    pub state: FxHashMap<String, NodeIndex>,
+    /// Optional tensors the model was built without, like the bias of a linear layer without one
+    pub absent: FxHashSet<String>,
+}
+
+impl Serializer {
+    pub fn tensor<S: Shape>(&mut self, name: &str, tensor: GraphTensor<S>) {
+        if !name.is_empty() {
+            // Add new path component
+            self.current_path.push(name.to_string());
+        }
+        // Insert tensor id
+        self.state.insert(self.current_path.join("/"), tensor.id);
+        if !name.is_empty() {
+            // Remove new path component
+            self.current_path.pop();
+        }
+    }
+    /// Record that an optional tensor, like a bias, was left out of the model on purpose
+    pub fn absent(&mut self, name: &str) {
+        self.current_path.push(name.to_string());
+        self.absent.insert(self.current_path.join("/"));
+        self.current_path.pop();
+    }
+    /// The path of the module being serialized, like `layer0/self_attn`
+    pub fn path(&self) -> String {
+        self.current_path.join("/")
+    }
+    pub fn module<T: SerializeModule>(&mut self, name: &str, module: &T) {
+        if !name.is_empty() {
+            // Add new path component
+            self.current_path.push(name.to_string());
+        }
+        // Serialize
+        module.serialize(self);
+        if !name.is_empty() {
+            // Remove new path component
+            self.current_path.pop();
+        }
+    }
+}
+
+impl<'data> View for &'data Tensor {
+    fn dtype(&self) -> Dtype {
+        Dtype::F32 // For now just assume float, this should change in the future
+    }
+    fn shape(&self) -> &[usize] {
+        &[]
+    }
+    fn data(&self) -> Cow<'_, [u8]> {
+        self.data
+            .as_any()
+            .downcast_ref::<Vec<f32>>()
+            .unwrap()
+            .iter()
+            .flat_map(|f| f.to_le_bytes().into_iter())
+            .collect::<Vec<_>>()
+            .into()
+    }
+    fn data_len(&self) -> usize {
+        self.data.as_any().downcast_ref::<Vec<f32>>().unwrap().len()
+    }
+}
+
+impl<'a> std::convert::From<safetensors::tensor::TensorView<'a>> for Tensor {
+    fn from(value: safetensors::tensor::TensorView<'a>) -> Self {
+        Tensor {
+            data: Box::new(unsafe { std::mem::transmute::<_, &'a [f32]>(value.data()) }.to_vec()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+
+    use crate::{
+        nn::transformer::Transformer,
+        prelude::*,
+        tests::{assert_close, assert_exact},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_serialization() {
+        let mut rng = thread_rng();
+        let enc_data = (0..(24 * 32)).map(|_| rng.gen()).collect::<Vec<f32>>();
+        let trg_data = (0..(20 * 32)).map(|_| rng.gen()).collect::<Vec<f32>>();
+
+        let mut cx = Graph::new();
+        let model: Transformer<32, 5, 4, 4, 3, 2> = InitModule::initialize(&mut cx);
+        let enc = cx.tensor::<R2<24, 32>>().set(enc_data.clone()).keep();
+        let trg = cx.tensor::<R2<20, 32>>().set(trg_data.clone()).keep();
+        let mut out1 = model.forward((trg, enc)).retrieve();
+        cx.compile(CPUCompiler::default(), &mut out1);
+
+        cx.execute_no_delete();
+
+        let state_dict = StateDictSaver.save(&model, &mut cx);
+        let out1 = out1.data();
+
+        let mut cx = Graph::new();
+        let model: Transformer<32, 5, 4, 4, 3, 2> = InitModule::initialize(&mut cx);
+        StateDictLoader::new(state_dict).load(&model, &mut cx);
+        let enc = cx.tensor::<R2<24, 32>>().set(enc_data);
+        let trg = cx.tensor::<R2<20, 32>>().set(trg_data);
+        let mut out2 = model.forward((trg, enc)).retrieve();
+
+        cx.compile(CPUCompiler::default(), &mut out2);
+        cx.execute();
+
+        assert_close(&out1, &out2.data());
+    }
+
+    const QUANTIZED_FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/resources/fixtures/quantized_int8.safetensors"
+    );
+
+    /// A single named weight
+    struct Weight<S: Shape>(&'static str, GraphTensor<S>);
+
+    impl<S: Shape> SerializeModule for Weight<S> {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor(self.0, self.1);
+        }
+    }
+
+    #[test]
+    fn test_load_quantized_safetensors() {
+        let mut cx = Graph::new();
+        let per_channel = cx.named_tensor::<R2<3, 4>>("Per Channel").retrieve();
+        let per_tensor = cx.named_tensor::<R2<2, 3>>("Per Tensor").retrieve();
+        SafeTensorLoader::new(&[QUANTIZED_FIXTURE])
+            .load(&Weight("per_channel", per_channel), &mut cx);
+        SafeTensorLoader::new(&[QUANTIZED_FIXTURE])
+            .load(&Weight("per_tensor", per_tensor), &mut cx);
+        cx.execute();
+
+        // Reference values from resources/fixtures/quantized_int8.py
+        assert_close(
+            &per_channel.data(),
+            &[
+                -64.0, -1.5, 0.0, 3.5, 0.01, 0.02, 0.03, 1.27, -100.0, 50.0, -20.0, 10.0,
+            ],
+        );
+        assert_close(&per_tensor.data(), &[-32.0, 0.0, 31.75, -7.0, 0.5, -30.25]);
+    }
+
+    #[test]
+    #[should_panic(expected = "has no \"missing.scales\" scale tensor")]
+    fn test_load_quantized_missing_scales() {
+        let mut cx = Graph::new();
+        let missing = cx.named_tensor::<R1<4>>("Missing").retrieve();
+        SafeTensorLoader::new(&[QUANTIZED_FIXTURE]).load(&Weight("missing", missing), &mut cx);
+        cx.execute();
+    }
+
+    const QUIRKY_FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/resources/fixtures/quirky_names.safetensors"
+    );
+
+    /// Weights under their paths in the model
+    struct Weights(Vec<(&'static str, GraphTensor<R1<6>>)>);
+
+    impl SerializeModule for Weights {
+        fn serialize(&self, s: &mut Serializer) {
+            for (path, weight) in &self.0 {
+                s.tensor(path, *weight);
+            }
+        }
+    }
+
+    const EMBED: [f32; 6] = [0.5, -1.0, 2.0, 3.25, -4.5, 6.0];
+
+    #[test]
+    fn test_load_quirky_names() {
+        let mut cx = Graph::new();
+        let [embed, w_q, lm_head, bias] =
+            ["Embed", "W_Q", "LM Head", "Bias"].map(|name| cx.named_tensor::<R1<6>>(name));
+        let model = Weights(vec![
+            ("embed/weight", embed),
+            ("layers/0/attn/w_q", w_q),
+            ("lm_head/weight", lm_head),
+            ("ln/bias", bias),
+        ]);
+        let loader = || SafeTensorLoader::new(&[QUIRKY_FIXTURE]);
+        let report = loader().eager().load(&model, &mut cx);
+
+        // See resources/fixtures/quirky_names.py for the quirks of each tensor
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(a, b)| (a.to_string(), b.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            report,
+            LoadReport {
+                loaded: pairs(&[
+                    ("embed.weight", "Embed::Weight"),
+                    ("layers.0.attn.w_q", "layers.0/attn::w_q"),
+                    ("lm_head.weight", "LM_HEAD.weight"),
+                ]),
+                aliased: vec![],
+                missing: vec!["ln.bias".to_string()],
+                unexpected: vec!["extra::unused".to_string()],
+                quantized: vec![],
+            }
+        );
+        assert_eq!(loader().missing_weights(&model).missing, ["ln.bias"]);
+        // Loaded eagerly, so the data is there before executing
+        assert_exact(&embed.data(), &EMBED);
+        assert_exact(&lm_head.data(), &EMBED);
+        assert_exact(&w_q.data(), &[1., -1., 2., -2., 3., -3.]);
+        // The missing weight is left alone
+        assert!(cx.get_tensor_ref(bias.id, 0).is_none());
+        assert!(!cx.loaded_weights.contains(&bias.id));
+    }
+
+    /// Ties the output projection to the embedding, like checkpoints that only store the embedding
+    struct TiedResolver;
+
+    impl NameResolver for TiedResolver {
+        fn candidates(&self, path: &str) -> Vec<String> {
+            let path = if path == "lm_head/weight" {
+                "embed/weight"
+            } else {
+                path
+            };
+            DefaultNameResolver.candidates(path)
+        }
+
+        fn normalize(&self, name: &str) -> String {
+            DefaultNameResolver.normalize(name)
+        }
+    }
+
+    #[test]
+    fn test_load_aliased_weights() {
+        let mut cx = Graph::new();
+        let [embed, lm_head] = ["Embed", "LM Head"].map(|name| cx.named_tensor::<R1<6>>(name));
+        let out = (embed + lm_head).retrieve();
+        let report = SafeTensorLoader::new(&[QUIRKY_FIXTURE])
+            .resolver(TiedResolver)
+            .load(
+                &Weights(vec![("embed/weight", embed), ("lm_head/weight", lm_head)]),
+                &mut cx,
+            );
+        let tensor = "Embed::Weight".to_string();
+        assert_eq!(
+            report.loaded,
+            [("embed.weight".to_string(), tensor.clone())]
+        );
+        assert_eq!(report.aliased, [("lm_head.weight".to_string(), tensor)]);
+        assert!(report.unexpected.contains(&"LM_HEAD.weight".to_string()));
+        assert_eq!(cx.content_keys[&embed.id], cx.content_keys[&lm_head.id]);
+
+        // Both weights get the data, including after reloading
+        for _ in 0..2 {
+            cx.execute();
+            assert_exact(&out.data(), &EMBED.map(|v| v * 2.));
+            assert_exact(&lm_head.data(), &EMBED);
+            cx.reload_weights();
+            out.drop();
+        }
+    }
+
+    #[test]
+    fn test_load_gguf() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/fixtures/multi_dtype.gguf"
+        );
+        // The fixture's embed is two Q8_0 blocks, see resources/fixtures/multi_dtype.py
+        let embed_data = (0..64)
+            .map(|i| {
+                if i % 32 == 0 {
+                    31.75
+                } else {
+                    ((i * 37) % 255 - 127) as f32 * 0.25
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut cx = Graph::new();
+        let weight = cx.named_tensor::<R2<3, 4>>("Weight").retrieve();
+        let embed = cx.named_tensor::<R2<2, 32>>("Embed").retrieve();
+        let loader = || GgufLoader::new(path).keep_quantized();
+        let quantized = [
+            loader().load(&Weight("weight", weight), &mut cx).unwrap(),
+            loader().load(&Weight("embed", embed), &mut cx).unwrap(),
+        ];
+        cx.execute();
+
+        // Only the Q8_0 tensor is kept quantized
+        assert_close(
+            &weight.data(),
+            &(0..12).map(|i| (i - 5) as f32 * 0.25).collect::<Vec<_>>(),
+        );
+        assert_eq!(quantized, [vec![], vec![embed.id]]);
+        let blocks = cx
+            .get_tensor_ref(embed.id, 0)
+            .unwrap()
+            .data
+            .as_any()
+            .downcast_ref::<Q8_0Blocks>()
+            .unwrap();
+        assert_eq!(blocks.0.len(), 2 * Q8_0Blocks::BLOCK_BYTES);
+        assert_close(&blocks.dequantize(), &embed_data);
+        // Quantizing the same values gives the same blocks
+        assert_eq!(&Q8_0Blocks::quantize(&embed_data), blocks);
+
+        // Without keeping them quantized, the blocks are dequantized on load
+        let mut cx = Graph::new();
+        let embed = cx.named_tensor::<R2<2, 32>>("Embed").retrieve();
+        let quantized = GgufLoader::new(path)
+            .load(&Weight("embed", embed), &mut cx)
+            .unwrap();
+        cx.execute();
+        assert!(quantized.is_empty());
+        assert_close(&embed.data(), &embed_data);
+
+        assert!(GgufLoader::new("missing.gguf")
+            .load(&Weight("embed", embed), &mut Graph::new())
+            .is_err());
+    }
+
+    #[test]
+    fn test_content_keys() {
+        let load = |name| {
+            let mut cx = Graph::new();
+            let a = cx.named_tensor::<R2<3, 4>>("A");
+            let b = cx.named_tensor::<R2<3, 4>>("B");
+            SafeTensorLoader::new(&[QUANTIZED_FIXTURE]).load(&Weight(name, a), &mut cx);
+            SafeTensorLoader::new(&[QUANTIZED_FIXTURE]).load(&Weight(name, b), &mut cx);
+            (cx.content_keys[&a.id], cx.content_keys[&b.id])
+        };
+        // The same region gets the same key, both within and across graphs
+        let (a, b) = load("per_channel");
+        assert_eq!(a, b);
+        assert_eq!(load("per_channel").0, a);
+        assert_ne!(load("per_tensor").0, a);
+    }
+
+    #[test]
+    fn test_weights_load_once() {
+        let mut cx = Graph::new();
+        let weight = cx.named_tensor::<R2<3, 4>>("Weight");
+        let out = (weight * 2.).retrieve();
+        SafeTensorLoader::new(&[QUANTIZED_FIXTURE]).load(&Weight("per_channel", weight), &mut cx);
+        // Count how many times the file is read
+        let loads = std::rc::Rc::new(std::cell::Cell::new(0));
+        let function = cx.graph[weight.id]
+            .as_any_mut()
+            .downcast_mut::<Function>()
+            .unwrap();
+        let load = std::mem::replace(&mut function.1, Rc::new(|_| vec![]));
+        let counter = loads.clone();
+        function.1 = Rc::new(move |inp| {
+            counter.set(counter.get() + 1);
+            load(inp)
+        });
+
+        for _ in 0..3 {
+            cx.execute();
+        }
+        assert_eq!(loads.get(), 1);
+        let first = out.data();
+
+        cx.reload_weights();
+        cx.execute();
+        assert_eq!(loads.get(), 2);
+        assert_close(&out.data(), &first);
+    }
+
+    #[test]
+    fn test_load_bias_free_checkpoint() {
+        use crate::nn::{linear::Linear, transformer::attention::MultiHeadSelfAttention};
+
+        // A checkpoint of an attention layer without biases
+        let weights = ["w_q", "w_k", "w_v", "w_o"]
+            .map(|name| (format!("{name}.weight"), crate::tests::random_vec(16)));
+        let bytes = weights
+            .iter()
+            .map(|(_, w)| w.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let path = std::env::temp_dir().join(format!(
+            "luminal_bias_free_{}.safetensors",
+            std::process::id()
+        ));
+        safetensors::serialize_to_file(
+            weights.iter().zip(&bytes).map(|((name, _), bytes)| {
+                (
+                    name.as_str(),
+                    TensorView::new(Dtype::F32, vec![4, 4], bytes).unwrap(),
+                )
+            }),
+            &None,
+            &path,
+        )
+        .unwrap();
+        let loader = || SafeTensorLoader::new(&[path.to_str().unwrap()]);
+
+        let mut cx = Graph::new();
+        let model = MultiHeadSelfAttention::<4, 4, 4, 1>::new(&mut cx, false);
+        let report = loader().missing_weights(&model);
+        assert!(report.missing.is_empty(), "{report:?}");
+        assert_eq!(
+            report.absent,
+            ["w_k.bias", "w_o.bias", "w_q.bias", "w_v.bias"]
+        );
+        loader().load(&model, &mut cx);
+        let input = crate::tests::random_vec(3 * 4);
+        let x = cx.tensor::<R2<3, 4>>().set(input.clone());
+        let out = model.forward(x).retrieve();
+        cx.execute();
+
+        // Same as setting the weights directly
+        let mut reference_cx = Graph::new();
+        let reference = MultiHeadSelfAttention::<4, 4, 4, 1>::new(&mut reference_cx, false);
+        for (linear, (_, weight)) in [
+            &reference.w_q,
+            &reference.w_k,
+            &reference.w_v,
+            &reference.w_o,
+        ]
+        .into_iter()
+        .zip(&weights)
+        {
+            linear.weight.set(weight.clone());
+        }
+        let x = reference_cx.tensor::<R2<3, 4>>().set(input);
+        let reference_out = reference.forward(x).retrieve();
+        reference_cx.execute();
+        assert_close(&out.data(), &reference_out.data());
+
+        // A layer with a bias the checkpoint doesn't have reports it as missing
+        let mut cx = Graph::new();
+        let biased = Linear::<4, 4>::new(&mut cx, true);
+        struct Named<'a>(&'a Linear<4, 4>);
+        impl SerializeModule for Named<'_> {
+            fn serialize(&self, s: &mut Serializer) {
+                s.module("w_q", self.0);
+            }
+        }
+        let report = loader().missing_weights(&Named(&biased));
+        assert_eq!(report.missing, ["w_q.bias"]);
+        assert!(report.absent.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// A dense weight and weights quantized with each scheme
+    struct QuantizedWeights {
+        dense: GraphTensor<R1<6>>,
+        q8_0: GraphTensor<R2<2, 64>>,
+        q4_0: GraphTensor<R2<3, 40>>,
+    }
+
+    impl QuantizedWeights {
+        fn new(cx: &mut Graph) -> Self {
+            Self {
+                dense: cx.named_tensor("Dense"),
+                q8_0: cx.named_tensor("Q8_0"),
+                q4_0: cx.named_tensor("Q4_0"),
+            }
+        }
+    }
+
+    impl SerializeModule for QuantizedWeights {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("dense", self.dense);
+            s.tensor("layer/q8_0", self.q8_0);
+            s.tensor("layer/q4_0", self.q4_0);
+        }
+    }
+
+    fn quantized_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("luminal_{name}_{}.safetensors", std::process::id()))
+    }
+
+    #[test]
+    fn test_save_quantized() {
+        let q8_0 = Q8_0Blocks::quantize(&crate::tests::random_vec(2 * 64));
+        // Rows of 40 are padded to two blocks
+        let q4_0 = Q4_0Blocks::quantize(&crate::tests::random_vec(3 * 40), 40);
+        let mut cx = Graph::new();
+        let model = QuantizedWeights::new(&mut cx);
+        model.dense.set(EMBED.to_vec());
+        cx.tensors
+            .insert((model.q8_0.id, 0), Tensor::new(q8_0.clone()));
+        cx.tensors
+            .insert((model.q4_0.id, 0), Tensor::new(q4_0.clone()));
+        cx.execute_no_delete();
+        let path = quantized_path("save_quantized");
+        SafeTensorSaver::new(path.to_str().unwrap())
+            .save(&model, &mut cx)
+            .unwrap();
+
+        let mut cx = Graph::new();
+        let model = QuantizedWeights::new(&mut cx);
+        let report = SafeTensorLoader::new(&[path.to_str().unwrap()]).load(&model, &mut cx);
+        assert!(report.missing.is_empty() && report.unexpected.is_empty());
+        assert_eq!(
+            report.quantized_nodes(QuantizationScheme::Q8_0),
+            [model.q8_0.id]
+        );
+        assert_eq!(
+            report.quantized_nodes(QuantizationScheme::Q4_0),
+            [model.q4_0.id]
+        );
+        model.dense.retrieve();
+        model.q8_0.retrieve();
+        model.q4_0.retrieve();
+        cx.execute();
+
+        // The blocks come back exactly as they were saved
+        assert_exact(&model.dense.data(), &EMBED);
+        let loaded = |id| cx.get_tensor_ref(id, 0).unwrap().data.as_any();
+        assert_eq!(loaded(model.q8_0.id).downcast_ref(), Some(&q8_0));
+        assert_eq!(loaded(model.q4_0.id).downcast_ref(), Some(&q4_0));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "saved in format version 2, but only version 1 can be read")]
+    fn test_load_quantized_future_version() {
+        let path = quantized_path("future_quantized");
+        let (values, scales) = (vec![0_u8; 32], vec![0_u8; 2]);
+        let metadata = [
+            ("luminal.quantization.version", "2"),
+            (
+                "luminal.quantization.tensor.weight",
+                "scheme=q8_0,group_size=32",
+            ),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .into_iter()
+        .collect();
+        safetensors::serialize_to_file(
+            [
+                (
+                    "weight",
+                    TensorView::new(Dtype::I8, vec![1, 32], &values).unwrap(),
+                ),
+                (
+                    "weight.block_scales",
+                    TensorView::new(Dtype::F16, vec![1], &scales).unwrap(),
+                ),
+            ],
+            &Some(metadata),
+            &path,
+        )
+        .unwrap();
+        let mut cx = Graph::new();
+        let weight = cx.named_tensor::<R1<32>>("Weight");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            SafeTensorLoader::new(&[path.to_str().unwrap()])
+                .missing_weights(&Weight("weight", weight))
+        }));
+        std::fs::remove_file(path).unwrap();
+        std::panic::resume_unwind(result.unwrap_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Reading tensor locations from uncompressed npz files (zip archives of .npy arrays, as written by `np.savez`).
+
+use super::convert::{ConvertError, SourceDtype, SourceTensor};
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+fn format_error<T>(message: &str) -> Result<T, ConvertError> {
+    Err(ConvertError::Format(message.to_string()))
+}
+
+fn u16_at(buffer: &[u8], position: usize) -> Result<u16, ConvertError> {
+    match buffer.get(position..position + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => format_error("Zip archive is truncated"),
+    }
+}
+
+fn u32_at(buffer: &[u8], position: usize) -> Result<u32, ConvertError> {
+    match buffer.get(position..position + 4) {
+        Some(b) => Ok(u32::from_le_bytes(b.try_into().unwrap())),
+        None => format_error("Zip archive is truncated"),
+    }
+}
+
+fn u64_at(buffer: &[u8], position: usize) -> Result<u64, ConvertError> {
+    match buffer.get(position..position + 8) {
+        Some(b) => Ok(u64::from_le_bytes(b.try_into().unwrap())),
+        None => format_error("Zip archive is truncated"),
+    }
+}
+
+/// Find the arrays in an npz file, named by their file names without the `.npy` extension
+pub(super) fn read_tensors(buffer: &[u8]) -> Result<Vec<SourceTensor>, ConvertError> {
+    // The end of central directory record sits at the end of the file, followed by a comment of up to 64KiB
+    let Some(end) = (0..buffer.len().saturating_sub(21))
+        .rev()
+        .take(u16::MAX as usize + 1)
+        .find(|i| u32_at(buffer, *i).ok() == Some(END_OF_CENTRAL_DIRECTORY))
+    else {
+        return format_error("Not a zip archive");
+    };
+    let n_entries = u16_at(buffer, end + 10)? as usize;
+    let mut position = u32_at(buffer, end + 16)? as usize;
+
+    let mut tensors = Vec::with_capacity(n_entries);
+    for _ in 0..n_entries {
+        if u32_at(buffer, position)? != CENTRAL_DIRECTORY_HEADER {
+            return format_error("Malformed zip central directory");
+        }
+        let compression = u16_at(buffer, position + 10)?;
+        let mut size = u32_at(buffer, position + 24)? as u64;
+        let name_len = u16_at(buffer, position + 28)? as usize;
+        let extra_len = u16_at(buffer, position + 30)? as usize;
+        let comment_len = u16_at(buffer, position + 32)? as usize;
+        let mut local_header = u32_at(buffer, position + 42)? as u64;
+        let name_start = position + 46;
+        let Some(name) = buffer.get(name_start..name_start + name_len) else {
+            return format_error("Zip archive is truncated");
+        };
+        let name = String::from_utf8_lossy(name).to_string();
+
+        // Sizes and offsets too big for 32 bits are stored in the zip64 extra field, in this order
+        let mut extra = name_start + name_len;
+        while extra + 4 <= name_start + name_len + extra_len {
+            let (tag, len) = (u16_at(buffer, extra)?, u16_at(buffer, extra + 2)? as usize);
+            if tag == ZIP64_EXTRA_FIELD {
+                let mut field = extra + 4;
+                if size == u32::MAX as u64 {
+                    size = u64_at(buffer, field)?;
+                    field += 8;
+                }
+                if u32_at(buffer, position + 20)? == u32::MAX {
+                    // Compressed size, same as the size for stored files
+                    field += 8;
+                }
+                if local_header == u32::MAX as u64 {
+                    local_header = u64_at(buffer, field)?;
+                }
+            }
+            extra += 4 + len;
+        }
+        position = name_start + name_len + extra_len + comment_len;
+
+        if compression != 0 {
+            return Err(ConvertError::Format(format!(
+                "\"{name}\" is compressed, only uncompressed npz files (np.savez) are supported"
+            )));
+        }
+        let local_header = local_header as usize;
+        if u32_at(buffer, local_header)? != LOCAL_FILE_HEADER {
+            return format_error("Malformed zip local file header");
+        }
+        let data_start = local_header
+            + 30
+            + u16_at(buffer, local_header + 26)? as usize
+            + u16_at(buffer, local_header + 28)? as usize;
+        let Some(npy) = buffer.get(data_start..data_start + size as usize) else {
+            return format_error("Zip archive is truncated");
+        };
+        let (shape, dtype, header_len) = read_npy_header(&name, npy)?;
+        let data_start = data_start + header_len;
+        tensors.push(SourceTensor {
+            name: name.strip_suffix(".npy").unwrap_or(&name).to_string(),
+            data: data_start..data_start + dtype.size_of(shape.iter().product()),
+            shape,
+            dtype,
+        });
+    }
+    Ok(tensors)
+}
+
+/// Parse the header of an .npy file, returning the shape, dtype and header length
+fn read_npy_header(
+    name: &str,
+    npy: &[u8],
+) -> Result<(Vec<usize>, SourceDtype, usize), ConvertError> {
+    let error = |message: &str| ConvertError::Format(format!("\"{name}\": {message}"));
+    if npy.get(..6) != Some(b"\x93NUMPY") || npy.len() < 10 {
+        return Err(error("not an npy file"));
+    }
+    // Version 1 has a u16 header length, versions 2 and 3 have a u32
+    let (header_start, header_len) = if npy[6] == 1 {
+        (10, u16::from_le_bytes([npy[8], npy[9]]) as usize)
+    } else {
+        (12, u32_at(npy, 8)? as usize)
+    };
+    let header = npy
+        .get(header_start..header_start + header_len)
+        .map(String::from_utf8_lossy)
+        .ok_or_else(|| error("header is truncated"))?;
+
+    // The header is a python dict literal, like {'descr': '<f4', 'fortran_order': False, 'shape': (3, 4), }
+    let value = |key: &str| {
+        header
+            .split_once(&format!("'{key}':"))
+            .map(|(_, rest)| rest.trim_start())
+            .ok_or_else(|| error(&format!("header has no {key}")))
+    };
+    if value("fortran_order")?.starts_with("True") {
+        return Err(error("fortran ordered arrays aren't supported"));
+    }
+    let descr = value("descr")?;
+    let dtype = match descr.get(1..4) {
+        Some("<f8") => SourceDtype::F64,
+        Some("<f4") => SourceDtype::F32,
+        Some("<f2") => SourceDtype::F16,
+        Some("|i1") => SourceDtype::I8,
+        Some("|u1") => SourceDtype::U8,
+        _ => return Err(error(&format!("unsupported dtype {descr}"))),
+    };
+    let shape = value("shape")?;
+    let shape = shape[1..shape.find(')').ok_or_else(|| error("malformed shape"))?]
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>().map_err(|_| error("malformed shape")))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((shape, dtype, header_start + header_len))
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use rustc_hash::FxHashMap;
+use safetensors::{
+    tensor::{Dtype, TensorView},
+    SafeTensors,
+};
+
+use crate::prelude::{Q4_0Blocks, Q8_0Blocks, Tensor};
+
+/// Version of the layout quantized tensors are saved in. Files record it in their metadata, so a loader reading an
+/// older or newer layout fails instead of misreading the blocks.
+pub(super) const FORMAT_VERSION: u32 = 1;
+const VERSION_KEY: &str = "luminal.quantization.version";
+/// Prefix of the metadata entries recording each quantized tensor's scheme
+const SCHEME_PREFIX: &str = "luminal.quantization.tensor.";
+/// Suffix of the tensor holding the fp16 scales of a quantized tensor's blocks
+pub(super) const BLOCK_SCALES_SUFFIX: &str = ".block_scales";
+
+/// Block quantization schemes weights can be saved and loaded in
+///
+/// A quantized tensor is saved as its packed values, with a row per block, and a companion tensor holding each
+/// block's fp16 scale named with a `.block_scales` suffix. The file's metadata records the scheme and group size of
+/// each quantized tensor, and the version of the layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuantizationScheme {
+    /// See [`Q8_0Blocks`]. Values are saved as I8.
+    Q8_0,
+    /// See [`Q4_0Blocks`]. Pairs of values are saved packed into U8, blocks padding included.
+    Q4_0,
+}
+
+impl QuantizationScheme {
+    /// The scheme and blocks of a tensor holding quantized data
+    pub(super) fn of(tensor: &Tensor) -> Option<(Self, &[u8])> {
+        let data = tensor.data.as_any();
+        data.downcast_ref::<Q8_0Blocks>()
+            .map(|b| (Self::Q8_0, &b.0[..]))
+            .or_else(|| {
+                data.downcast_ref::<Q4_0Blocks>()
+                    .map(|b| (Self::Q4_0, &b.0[..]))
+            })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            QuantizationScheme::Q8_0 => "q8_0",
+            QuantizationScheme::Q4_0 => "q4_0",
+        }
+    }
+
+    /// Number of values sharing a scale
+    pub fn group_size(self) -> usize {
+        match self {
+            QuantizationScheme::Q8_0 => Q8_0Blocks::BLOCK_SIZE,
+            QuantizationScheme::Q4_0 => Q4_0Blocks::BLOCK_SIZE,
+        }
+    }
+
+    fn block_bytes(self) -> usize {
+        match self {
+            QuantizationScheme::Q8_0 => Q8_0Blocks::BLOCK_BYTES,
+            QuantizationScheme::Q4_0 => Q4_0Blocks::BLOCK_BYTES,
+        }
+    }
+
+    /// Bytes of packed values in each block, after its fp16 scale
+    pub(super) fn value_bytes(self) -> usize {
+        self.block_bytes() - 2
+    }
+
+    pub(super) fn packed_dtype(self) -> Dtype {
+        match self {
+            QuantizationScheme::Q8_0 => Dtype::I8,
+            QuantizationScheme::Q4_0 => Dtype::U8,
+        }
+    }
+
+    fn metadata_value(self) -> String {
+        format!("scheme={},group_size={}", self.name(), self.group_size())
+    }
+
+    fn from_metadata_value(value: &str) -> Option<Self> {
+        let fields = value
+            .split(',')
+            .filter_map(|field| field.split_once('='))
+            .collect::<FxHashMap<_, _>>();
+        let scheme = [QuantizationScheme::Q8_0, QuantizationScheme::Q4_0]
+            .into_iter()
+            .find(|s| fields.get("scheme") == Some(&s.name()))?;
+        (fields.get("group_size")?.parse::<usize>().ok()? == scheme.group_size()).then_some(scheme)
+    }
+}
+
+/// Record the schemes of a file's quantized tensors, along with the layout version
+pub(super) fn write_metadata<'a>(
+    schemes: impl IntoIterator<Item = (&'a str, QuantizationScheme)>,
+) -> Option<HashMap<String, String>> {
+    let mut metadata = schemes
+        .into_iter()
+        .map(|(name, scheme)| (format!("{SCHEME_PREFIX}{name}"), scheme.metadata_value()))
+        .collect::<HashMap<_, _>>();
+    if metadata.is_empty() {
+        return None;
+    }
+    metadata.insert(VERSION_KEY.to_string(), FORMAT_VERSION.to_string());
+    Some(metadata)
+}
+
+/// The schemes of the quantized tensors in a file, from its metadata. Panics if they were saved in another layout
+/// version, or with a scheme this version doesn't know.
+pub(super) fn read_metadata(
+    metadata: &Option<HashMap<String, String>>,
+    path: &str,
+) -> FxHashMap<String, QuantizationScheme> {
+    let Some(metadata) = metadata else {
+        return FxHashMap::default();
+    };
+    let schemes = metadata
+        .iter()
+        .filter_map(|(key, value)| Some((key.strip_prefix(SCHEME_PREFIX)?, value)))
+        .map(|(name, value)| {
+            let scheme = QuantizationScheme::from_metadata_value(value).unwrap_or_else(|| {
+                panic!(
+                    "Quantized tensor \"{name}\" in \"{path}\" has an unsupported scheme: {value}"
+                )
+            });
+            (name.to_string(), scheme)
+        })
+        .collect::<FxHashMap<_, _>>();
+    if schemes.is_empty() {
+        return schemes;
+    }
+    let version = metadata.get(VERSION_KEY).map(|v| v.as_str());
+    if version != Some(FORMAT_VERSION.to_string().as_str()) {
+        panic!(
+            "\"{path}\" holds quantized tensors saved in format version {}, but only version {FORMAT_VERSION} can be read",
+            version.unwrap_or("(none)")
+        );
+    }
+    schemes
+}
+
+/// Split blocks into their packed values and their scales
+pub(super) fn split_blocks(scheme: QuantizationScheme, blocks: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let n_blocks = blocks.len() / scheme.block_bytes();
+    let mut values = Vec::with_capacity(n_blocks * scheme.value_bytes());
+    let mut scales = Vec::with_capacity(n_blocks * 2);
+    for block in blocks.chunks_exact(scheme.block_bytes()) {
+        scales.extend_from_slice(&block[..2]);
+        values.extend_from_slice(&block[2..]);
+    }
+    (values, scales)
+}
+
+/// Read a quantized tensor and its scales back into blocks, exactly as they were saved
+pub(super) fn read_blocks(
+    scheme: QuantizationScheme,
+    name: &str,
+    values: &TensorView,
+    safetensors: &SafeTensors,
+) -> Tensor {
+    let scales_name = format!("{name}{BLOCK_SCALES_SUFFIX}");
+    let scales = safetensors.tensor(&scales_name).unwrap_or_else(|_| {
+        panic!("Quantized tensor \"{name}\" has no \"{scales_name}\" scale tensor")
+    });
+    let (values, scales) = (values.data(), scales.data());
+    assert_eq!(
+        values.len() / scheme.value_bytes(),
+        scales.len() / 2,
+        "\"{name}\" and \"{scales_name}\" hold different numbers of blocks"
+    );
+    let bytes = scales
+        .chunks_exact(2)
+        .zip(values.chunks_exact(scheme.value_bytes()))
+        .flat_map(|(scale, values)| scale.iter().chain(values))
+        .copied()
+        .collect();
+    match scheme {
+        QuantizationScheme::Q8_0 => Tensor::new(Q8_0Blocks(bytes)),
+        QuantizationScheme::Q4_0 => Tensor::new(Q4_0Blocks(bytes)),
+    }
+}
@@ -0,0 +1,160 @@
+use itertools::Itertools;
+
+use crate::prelude::*;
+
+impl ShapeTracker {
+    /// Name every dimension, in the current axis order
+    pub fn label_dims(&mut self, labels: &[&'static str]) {
+        assert_eq!(
+            labels.len(),
+            self.len(),
+            "Got {} labels for {} dimensions",
+            labels.len(),
+            self.len()
+        );
+        self.set_labels(&labels.iter().map(|l| Some(*l)).collect_vec());
+    }
+
+    /// Set the labels of the dimensions, in the current axis order
+    pub fn set_labels(&mut self, labels: &[Option<&'static str>]) {
+        for (axis, label) in labels.iter().enumerate() {
+            self.labels[self.indexes[axis]] = *label;
+        }
+    }
+
+    /// The labels of the dimensions, in the current axis order
+    pub fn labels(&self) -> Vec<Option<&'static str>> {
+        self.indexes.iter().map(|i| self.labels[*i]).collect()
+    }
+
+    /// Whether any dimension has a label
+    pub fn is_labeled(&self) -> bool {
+        self.labels.iter().any(|l| l.is_some())
+    }
+
+    /// The first axis where both shapes have a label and the labels differ, along with the two labels
+    pub fn mismatched_label(
+        &self,
+        other: &ShapeTracker,
+    ) -> Option<(usize, &'static str, &'static str)> {
+        self.labels()
+            .into_iter()
+            .zip(other.labels())
+            .enumerate()
+            .find_map(|(axis, labels)| match labels {
+                (Some(a), Some(b)) if a != b => Some((axis, a, b)),
+                _ => None,
+            })
+    }
+
+    /// The labels as a list like `[batch, seq, _]`, with unlabeled dimensions shown as `_`
+    pub fn format_labels(&self) -> String {
+        format!(
+            "[{}]",
+            self.labels()
+                .into_iter()
+                .map(|l| l.unwrap_or("_"))
+                .join(", ")
+        )
+    }
+}
+
+/// The labels of the output of an elementwise binary op, taking each label from whichever input has one.
+///
+/// Panics if the inputs have different labels on the same axis and the graph has strict dimension labels.
+#[track_caller]
+pub(crate) fn binary_op_labels(
+    graph: &Graph,
+    lhs: &ShapeTracker,
+    rhs: &ShapeTracker,
+) -> Vec<Option<&'static str>> {
+    if graph.strict_dim_labels {
+        if let Some((axis, a, b)) = lhs.mismatched_label(rhs) {
+            panic!(
+                "Axis {axis} is labeled \"{a}\" on the left and \"{b}\" on the right of the binary op at {}: {} and {}",
+                std::panic::Location::caller(),
+                lhs.format_labels(),
+                rhs.format_labels()
+            );
+        }
+    }
+    lhs.labels()
+        .into_iter()
+        .zip(rhs.labels())
+        .map(|(a, b)| a.or(b))
+        .collect()
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// Name the dimensions of this tensor, like `["batch", "head", "seq", "dhead"]`.
+    ///
+    /// Labels follow the dimensions through permutes, expands, slices, pads and reductions, and show up in shape
+    /// errors, debug graphs and `execute_debug`. Binary ops between dimensions with different labels are reported by
+    /// [`Graph::lint`], or panic if [`Graph::strict_dim_labels`] is set. Reshapes drop the labels.
+    pub fn label_dims<const N: usize>(mut self, labels: [&'static str; N]) -> GraphTensor<S> {
+        self.shape.label_dims(&labels);
+        self
+    }
+
+    /// The labels of the dimensions, in order
+    pub fn dim_labels(&self) -> Vec<Option<&'static str>> {
+        self.shape.labels()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_labels_follow_permute() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R4<2, 3, 4, 5>>()
+            .label_dims(["batch", "head", "seq", "dhead"]);
+        let b = a.permute::<_, LAxes4<0, 2, 1, 3>>();
+        assert_eq!(
+            b.dim_labels(),
+            vec![Some("batch"), Some("seq"), Some("head"), Some("dhead")]
+        );
+        // Labels survive making the permuted tensor contiguous, and reductions remove the reduced label
+        let c = b.contiguous().sum_reduce::<_, LAxis<1>>();
+        assert_eq!(
+            c.dim_labels(),
+            vec![Some("batch"), Some("head"), Some("dhead")]
+        );
+        // Expanded dimensions are unlabeled
+        let d = c.expand::<R4<2, 3, 6, 5>, LAxis<2>>();
+        assert_eq!(d.shape.format_labels(), "[batch, head, _, dhead]");
+        // Elementwise ops keep labels from either side
+        let e = d.sin()
+            + cx.tensor::<R4<2, 3, 6, 5>>()
+                .label_dims(["batch", "head", "kv", "dhead"]);
+        assert_eq!(e.shape.format_labels(), "[batch, head, kv, dhead]");
+    }
+
+    #[test]
+    #[should_panic(expected = "Axis 1 is labeled \"seq\" on the left and \"head\" on the right")]
+    fn test_strict_labels() {
+        let mut cx = Graph::new();
+        cx.strict_dim_labels = true;
+        let a = cx.tensor::<R2<4, 4>>().label_dims(["batch", "seq"]);
+        let b = cx.tensor::<R2<4, 4>>().label_dims(["batch", "head"]);
+        let _ = a + b;
+    }
+
+    #[test]
+    fn test_strict_labels_location() {
+        let mut cx = Graph::new();
+        cx.strict_dim_labels = true;
+        let a = cx.tensor::<R2<4, 4>>().label_dims(["batch", "seq"]);
+        let b = cx.tensor::<R2<4, 4>>().label_dims(["batch", "head"]);
+        let line = line!() + 1;
+        let error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| a + b)).unwrap_err();
+        let message = error.downcast_ref::<String>().unwrap();
+        assert!(
+            message.contains(&format!("binary op at {}:{line}:", file!())),
+            "{message}"
+        );
+    }
+}
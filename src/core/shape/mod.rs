@@ -1,5 +1,6 @@
 mod axes;
 mod broadcast;
+mod labels;
 mod permute;
 mod realize;
 mod slice;
@@ -11,6 +12,7 @@ pub use slice::*;
 
 pub use axes::*;
 pub use broadcast::*;
+pub(crate) use labels::binary_op_labels;
 pub use permute::*;
 pub use tracker::*;
 
@@ -35,6 +37,7 @@ pub trait ConstDim: Default + Dimension {
     const SIZE: usize;
 }
 
+/// A dimension whose size is only known at runtime, named by the character `C`
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct Dyn<const C: char>;
 
@@ -214,8 +217,8 @@ pub type R4<const M: usize, const N: usize, const O: usize, const P: usize> =
 /// Compile time known shape with 5 dimensions
 pub type R5<const M: usize, const N: usize, const O: usize, const P: usize, const Q: usize> =
     (Const<M>, Const<N>, Const<O>, Const<P>, Const<Q>);
-#[rustfmt::skip]
 /// Compile time known shape with 6 dimensions
+#[rustfmt::skip]
 pub type R6<const M: usize, const N: usize, const O: usize, const P: usize, const Q: usize, const R: usize> =
     (Const<M>, Const<N>, Const<O>, Const<P>, Const<Q>, Const<R>);
 
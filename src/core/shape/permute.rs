@@ -1,5 +1,6 @@
 use crate::shape::*;
 
+/// Marker for shapes that can be permuted to [Shape] `Dst` by the [Axes] `Ax`
 pub trait PermuteShapeTo<Dst, Ax> {}
 
 #[rustfmt::skip]
@@ -1,5 +1,6 @@
 use crate::prelude::*;
 
+/// Marker for shapes with the same rank as `Dst`, which they can be realized to
 pub trait RealizeShapeTo<Dst: Shape>: Shape {}
 
 impl<Src: Shape<Concrete = Dst::Concrete>, Dst: Shape> RealizeShapeTo<Dst> for Src {}
@@ -24,6 +24,7 @@ fn dim_to_size(r: Expression) -> usize {
     r.to_usize().unwrap_or(i32::MAX as usize)
 }
 
+/// The dimension a range slices a dimension `D` to. Full ranges keep the dimension, and the others make it dynamic.
 pub trait RangeToDim<D: Dimension> {
     type Dimension: Dimension;
 }
@@ -56,6 +57,7 @@ impl<D: Dimension> RangeToDim<D> for RangeFull {
     type Dimension = D;
 }
 
+/// A tuple of ranges, one per dimension, that slices a tensor of [Shape] `S`
 pub trait SliceOfShape<S: Shape> {
     type OutputShape: Shape;
     fn to_range_vec(&self) -> Vec<(Expression, Expression)>;
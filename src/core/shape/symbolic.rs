@@ -4,7 +4,7 @@
 use std::{
     cmp::Ordering,
     fmt::Debug,
-    ops::{Add, BitAnd, BitOr, Div, IndexMut, Mul, Rem, Sub},
+    ops::{Add, BitAnd, BitOr, Div, IndexMut, Mul, Range, Rem, Sub},
 };
 
 use itertools::Itertools;
@@ -104,6 +104,11 @@ impl<S: ExpressionStorage + Clone> Debug for GenericExpression<S> {
                     symbols.pop().unwrap(),
                     symbols.pop().unwrap()
                 ),
+                Term::CeilDiv => format!(
+                    "ceil_div({}, {})",
+                    symbols.pop().unwrap(),
+                    symbols.pop().unwrap()
+                ),
                 _ => format!(
                     "({}{term:?}{})",
                     symbols.pop().unwrap(),
@@ -130,6 +135,7 @@ impl<S: ExpressionStorage> GenericExpression<S> {
         {
             self = reduce_add_sub(self);
         }
+        self = reduce_ceil_div(self);
         reduce_triples(self)
     }
 
@@ -160,6 +166,20 @@ impl<S: ExpressionStorage> GenericExpression<S> {
         rhs.terms.push(Term::Lt);
         rhs.minimize()
     }
+
+    /// Divide, rounding up. Like the number of tiles of size `rhs` needed to cover `self`
+    pub fn ceil_div<E: Into<Self>>(self, rhs: E) -> Self {
+        let mut rhs = rhs.into();
+        rhs.terms.extend(self.terms);
+        rhs.terms.push(Term::CeilDiv);
+        rhs.minimize()
+    }
+
+    /// Round up to the next multiple of `alignment`
+    pub fn align_up<E: Into<Self>>(self, alignment: E) -> Self {
+        let alignment = alignment.into();
+        self.ceil_div(alignment.clone()) * alignment
+    }
 }
 
 fn reduce_triples<S: ExpressionStorage>(mut expr: GenericExpression<S>) -> GenericExpression<S> {
@@ -271,8 +291,12 @@ fn reduce_triples<S: ExpressionStorage>(mut expr: GenericExpression<S>) -> Gener
                 (Some(Term::Num(0)), Term::Div, _) => {
                     remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(b_ind)]);
                 }
-                // Remove i / 1
-                (_, Term::Div, Some(Term::Num(1))) => {
+                // Remove i / 1 and ceil_div(i, 1)
+                (_, Term::Div | Term::CeilDiv, Some(Term::Num(1))) => {
+                    remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(b_ind)]);
+                }
+                // Simplify ceil_div(0, i) to 0
+                (Some(Term::Num(0)), Term::CeilDiv, _) => {
                     remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(b_ind)]);
                 }
                 // Remove i * 1 and 1 * i
@@ -342,6 +366,120 @@ fn reduce_triples<S: ExpressionStorage>(mut expr: GenericExpression<S>) -> Gener
     expr
 }
 
+/// Where each subexpression starts, indexed by the term it ends at
+fn subexpression_starts(terms: &[Term]) -> Vec<usize> {
+    let mut starts: Vec<usize> = Vec::with_capacity(terms.len());
+    for (i, term) in terms.iter().enumerate() {
+        let start = match term {
+            Term::Num(_) | Term::Var(_) => i,
+            // The left operand ends right before the op, and the right operand right before that
+            _ => starts[starts[i - 1] - 1],
+        };
+        starts.push(start);
+    }
+    starts
+}
+
+/// The left and right operands of the op at `op`
+fn operands(starts: &[usize], op: usize) -> (Range<usize>, Range<usize>) {
+    let left = starts[op - 1]..op;
+    let right = starts[left.start - 1]..left.start;
+    (left, right)
+}
+
+/// If `operand` is one of `a` and `b`, the other one
+fn other_operand<'a>(a: &'a [Term], b: &'a [Term], operand: &[Term]) -> Option<&'a [Term]> {
+    if a == operand {
+        Some(b)
+    } else if b == operand {
+        Some(a)
+    } else {
+        None
+    }
+}
+
+/// Split an expression like `x + 2 - 1` into the range of terms of `x` and the constant added to it
+fn split_constant(terms: &[Term], starts: &[usize], expr: Range<usize>) -> (Range<usize>, i32) {
+    let op = expr.end - 1;
+    if matches!(terms[op], Term::Add | Term::Sub) {
+        let (a, b) = operands(starts, op);
+        let sign = if terms[op] == Term::Sub { -1 } else { 1 };
+        if let [Term::Num(n)] = &terms[b.clone()] {
+            let (x, c) = split_constant(terms, starts, a);
+            return (x, c + sign * n);
+        }
+        if let (Term::Add, [Term::Num(n)]) = (terms[op], &terms[a]) {
+            let (x, c) = split_constant(terms, starts, b);
+            return (x, c + n);
+        }
+    }
+    (expr, 0)
+}
+
+/// Rewrite ceiling divisions into one form, so equivalent expressions compare equal. These hold for the non-negative
+/// values dimensions take:
+/// - `(x + t - 1) / t` to `ceil_div(x, t)`
+/// - `ceil_div(x * t, t)` to `x`, which also makes aligning an aligned value a no-op
+/// - `ceil_div(ceil_div(x, a), b)` to `ceil_div(x, a * b)`
+fn reduce_ceil_div<S: ExpressionStorage>(expr: GenericExpression<S>) -> GenericExpression<S> {
+    if !(0..expr.terms.len()).any(|i| matches!(expr.terms[i], Term::Div | Term::CeilDiv)) {
+        return expr;
+    }
+    let mut terms = expr.terms.into_iter().collect::<Vec<_>>();
+    while let Some((range, replacement)) = find_ceil_div_rewrite(&terms) {
+        terms.splice(range, replacement);
+    }
+    let mut s = S::default();
+    s.extend(terms);
+    GenericExpression { terms: s }
+}
+
+/// The range of terms to replace, and what to replace them with
+fn find_ceil_div_rewrite(terms: &[Term]) -> Option<(Range<usize>, Vec<Term>)> {
+    let starts = subexpression_starts(terms);
+    for (op, term) in terms.iter().enumerate() {
+        if !matches!(term, Term::Div | Term::CeilDiv) {
+            continue;
+        }
+        let (left, right) = operands(&starts, op);
+        let divisor = &terms[right.clone()];
+        let inner_op = terms[left.end - 1];
+        if matches!(inner_op, Term::Num(_) | Term::Var(_)) {
+            continue;
+        }
+        let (a, b) = operands(&starts, left.end - 1);
+        let (a, b) = (&terms[a], &terms[b]);
+        let replacement = match (term, inner_op, divisor) {
+            (Term::Div, Term::Add | Term::Sub, [Term::Num(t)]) if *t > 1 => {
+                let (x, c) = split_constant(terms, &starts, left.clone());
+                (c == t - 1).then(|| {
+                    [Term::Num(*t)]
+                        .into_iter()
+                        .chain(terms[x].iter().copied())
+                        .chain([Term::CeilDiv])
+                        .collect()
+                })
+            }
+            (Term::CeilDiv, Term::Mul, _) => other_operand(a, b, divisor).map(|x| x.to_vec()),
+            (Term::CeilDiv, Term::CeilDiv, [Term::Num(outer)]) => match b {
+                [Term::Num(inner)] => Some(
+                    [Term::Num(inner * outer)]
+                        .into_iter()
+                        .chain(a.iter().copied())
+                        .chain([Term::CeilDiv])
+                        .collect(),
+                ),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(replacement) = replacement {
+            return Some((right.start..op + 1, replacement));
+        }
+    }
+    None
+}
+
 fn reduce_add_sub<S: ExpressionStorage>(expr: GenericExpression<S>) -> GenericExpression<S> {
     let mut stack: Vec<FxHashMap<Term, i32>> = Vec::new();
 
@@ -519,6 +657,8 @@ pub enum Term {
     Sub,
     Mul,
     Div,
+    /// Division rounding up
+    CeilDiv,
     Mod,
     Min,
     Max,
@@ -537,6 +677,7 @@ impl Debug for Term {
             Term::Sub => write!(f, "-"),
             Term::Mul => write!(f, "*"),
             Term::Div => write!(f, "/"),
+            Term::CeilDiv => write!(f, "ceil_div"),
             Term::Mod => write!(f, "%"),
             Term::Min => write!(f, "min"),
             Term::Max => write!(f, "max"),
@@ -561,6 +702,7 @@ impl Term {
             Term::Sub => Some(std::ops::Sub::sub),
             Term::Mul => Some(std::ops::Mul::mul),
             Term::Div => Some(std::ops::Div::div),
+            Term::CeilDiv => Some(|a, b| (a + b - 1) / b),
             Term::Mod => Some(std::ops::Rem::rem),
             Term::Max => Some(core::cmp::Ord::max),
             Term::Min => Some(core::cmp::Ord::min),
@@ -752,4 +894,79 @@ mod tests {
         let reduced_expr = expr.minimize();
         assert_eq!(reduced_expr, 'a'.into());
     }
+
+    #[test]
+    fn test_ceil_div() {
+        let x = BigExpression::from('x');
+        for t in 1..=17 {
+            for v in 0..=100 {
+                let vars = [('x', v)].into_iter().collect();
+                assert_eq!(x.clone().ceil_div(t).exec(&vars), Some(v.div_ceil(t)));
+                assert_eq!(
+                    x.clone().align_up(t).exec(&vars),
+                    Some(v.next_multiple_of(t))
+                );
+            }
+        }
+        assert_eq!(BigExpression::from(100).ceil_div(32), 4.into());
+        assert_eq!(format!("{:?}", x.ceil_div(4)), "ceil_div(x, 4)");
+    }
+
+    #[test]
+    fn test_ceil_div_minimizations() {
+        let x = || BigExpression::from('x');
+        let (n, var) = (Term::Num, Term::Var('x'));
+        for t in 1..=17 {
+            // Unminimized expressions, and what they should minimize to
+            let cases = [
+                // (x + (t - 1)) / t
+                (
+                    vec![n(t), n(t - 1), var, Term::Add, Term::Div],
+                    x().ceil_div(t),
+                ),
+                // ((x + t) - 1) / t
+                (
+                    vec![n(t), n(1), n(t), var, Term::Add, Term::Sub, Term::Div],
+                    x().ceil_div(t),
+                ),
+                // ceil_div(x * t, t)
+                (vec![n(t), n(t), var, Term::Mul, Term::CeilDiv], x()),
+                // align_up(align_up(x, t), t)
+                (
+                    vec![
+                        n(t),
+                        n(t),
+                        n(t),
+                        n(t),
+                        var,
+                        Term::CeilDiv,
+                        Term::Mul,
+                        Term::CeilDiv,
+                        Term::Mul,
+                    ],
+                    x().align_up(t),
+                ),
+                // ceil_div(ceil_div(x, t), 3)
+                (
+                    vec![n(3), n(t), var, Term::CeilDiv, Term::CeilDiv],
+                    x().ceil_div(3 * t),
+                ),
+            ];
+            for (terms, expected) in cases {
+                let expr = BigExpression { terms };
+                let minimized = expr.clone().minimize();
+                assert_eq!(minimized, expected, "{expr:?}");
+                // Brute force check the rewrite doesn't change the value
+                for v in 0..=200 {
+                    let vars = [('x', v)].into_iter().collect();
+                    assert_eq!(minimized.exec(&vars), expr.exec(&vars), "{expr:?} at {v}");
+                }
+            }
+        }
+
+        // Composing ceiling divisions from the arithmetic operators gives the same expressions
+        assert_eq!((x() + 15) / 16, x().ceil_div(16));
+        assert_eq!((x() + 16 - 1) / 16, x().ceil_div(16));
+        assert_eq!((x() * 4 + 3) / 4, x());
+    }
 }
@@ -3,13 +3,26 @@ use tinyvec::ArrayVec;
 
 use super::symbolic::{BigExpression, Expression};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Tracks how the logical shape of a tensor maps onto its data, through permutes, expands, slices and pads
+#[derive(Debug, Clone, Copy)]
 pub struct ShapeTracker {
     pub dims: ArrayVec<[Expression; 6]>,
     pub indexes: ArrayVec<[usize; 6]>,
     pub fake: ArrayVec<[bool; 6]>,
     pub slices: ArrayVec<[(Expression, Expression); 6]>,
     pub padding: ArrayVec<[(Expression, Expression); 6]>,
+    /// Optional names of the dimensions, like "batch" or "seq". These are only for documentation and error messages, so they don't affect equality
+    pub labels: ArrayVec<[Option<&'static str>; 6]>,
+}
+
+impl PartialEq for ShapeTracker {
+    fn eq(&self, other: &Self) -> bool {
+        self.dims == other.dims
+            && self.indexes == other.indexes
+            && self.fake == other.fake
+            && self.slices == other.slices
+            && self.padding == other.padding
+    }
 }
 
 impl ShapeTracker {
@@ -20,6 +33,7 @@ impl ShapeTracker {
             fake: Default::default(),
             slices: Default::default(),
             padding: Default::default(),
+            labels: Default::default(),
         };
         for (i, d) in dims.iter().enumerate() {
             s.dims.push(*d);
@@ -27,6 +41,7 @@ impl ShapeTracker {
             s.fake.push(false);
             s.slices.push((0.into(), i32::MAX.into())); // Unset upper bound slices are i32::MAX
             s.padding.push((0.into(), 0.into()));
+            s.labels.push(None);
         }
         s
     }
@@ -47,6 +62,7 @@ impl ShapeTracker {
         self.fake.push(false);
         self.slices.push((0.into(), i32::MAX.into()));
         self.padding.push((0.into(), 0.into()));
+        self.labels.push(None);
     }
 
     /// Add fake dim along a certian axis
@@ -66,15 +82,68 @@ impl ShapeTracker {
         }
         self.slices.remove(index);
         self.padding.remove(index);
+        self.labels.remove(index);
         self.dims.remove(index)
     }
 
-    /// Permute the dimensions
+    /// Permute the dimensions. Axis `i` of the result is axis `axes[i]` of the current shape.
+    ///
+    /// Panics if `axes` isn't a permutation of `0..len`.
     pub fn permute(&mut self, axes: &[usize]) {
+        self.assert_permutation(axes);
         let new_indexes = axes.iter().map(|i| self.indexes[*i]).collect::<Vec<_>>();
         self.indexes.copy_from_slice(&new_indexes);
     }
 
+    /// Undo a permute with the same axes
+    pub fn inverse_permute(&mut self, axes: &[usize]) {
+        self.assert_permutation(axes);
+        let mut inverse = vec![0; axes.len()];
+        for (i, a) in axes.iter().enumerate() {
+            inverse[*a] = i;
+        }
+        self.permute(&inverse);
+    }
+
+    fn assert_permutation(&self, axes: &[usize]) {
+        let labels = if self.is_labeled() {
+            format!("{} ", self.format_labels())
+        } else {
+            String::new()
+        };
+        let mut seen = [false; 6];
+        assert!(
+            axes.len() == self.len()
+                && axes
+                    .iter()
+                    .all(|a| *a < self.len() && !std::mem::replace(&mut seen[*a], true)),
+            "Permute axes {axes:?} aren't a permutation of the {} dimensions {}of {self:?}",
+            self.len(),
+            labels
+        );
+    }
+
+    /// Permute the dimensions into the same order as another tracker over the same underlying dimensions, returning the axes used
+    pub fn permute_to_match(&mut self, other: &ShapeTracker) -> Vec<usize> {
+        let axes = other
+            .indexes
+            .iter()
+            .map(|i| {
+                self.indexes
+                    .iter()
+                    .position(|j| i == j)
+                    .unwrap_or_else(|| panic!("{other:?} has dimensions {self:?} doesn't"))
+            })
+            .collect::<Vec<_>>();
+        self.permute(&axes);
+        axes
+    }
+
+    /// The current axis order: axis `i` is dimension `permutation()[i]` of the unpermuted shape
+    pub fn permutation(&self) -> Vec<usize> {
+        self.indexes.to_vec()
+    }
+
     /// Strides without permute applied
     fn unordered_strides(&self) -> Vec<Expression> {
         let mut strides = self
@@ -149,21 +218,21 @@ impl ShapeTracker {
 
     /// If this BigExpression evaluates to 0, the logical index is invalid. Otherwise it is valid
     pub fn valid_expression(&self) -> BigExpression {
-        if self.is_contiguous() && !self.is_sliced() && !self.is_padded() {
+        if self.is_always_valid() {
             return 1.into();
         }
         let mut ret = BigExpression::from(1);
         let mut acc = BigExpression::from(1);
         let logical = BigExpression::from('z');
-        for (sh, padding, slice, fake) in self
+        for (i, sh, padding, slice) in self
             .indexes
             .into_iter()
             .rev()
-            .map(|i| (self.dims[i], self.padding[i], self.slices[i], self.fake[i]))
+            .map(|i| (i, self.dims[i], self.padding[i], self.slices[i]))
         {
             let logical_sh =
                 (BigExpression::from(sh) + padding.0 + padding.1).min(slice.1) - slice.0;
-            if !fake {
+            if self.affects_validity(i) {
                 let dim_ind = (logical.clone() / acc.clone()) % logical_sh.clone();
                 ret = ret
                     & dim_ind.clone().gte(
@@ -177,22 +246,52 @@ impl ShapeTracker {
         ret.minimize()
     }
 
-    /// The number of elements in this tensor, including pads and slices
+    /// Whether a dimension (by physical index) can make a logical index invalid. Only real dimensions that are padded or sliced can
+    fn affects_validity(&self, index: usize) -> bool {
+        let ((pad_start, pad_end), (slice_start, slice_end)) =
+            (self.padding[index], self.slices[index]);
+        !self.fake[index]
+            && (pad_start.to_usize().map(|n| n != 0).unwrap_or(true)
+                || pad_end.to_usize().map(|n| n != 0).unwrap_or(true)
+                || slice_start.to_usize().map(|n| n != 0).unwrap_or(true)
+                || slice_end
+                    .to_usize()
+                    .map(|n| n as i32 != i32::MAX)
+                    .unwrap_or(true))
+    }
+
+    /// Check if every logical index is valid, meaning the valid expression is always 1.
+    ///
+    /// Unlike checking for contiguity, this still holds after permutes, expands, and pads or slices on fake dimensions.
+    pub fn is_always_valid(&self) -> bool {
+        !(0..self.len()).any(|i| self.affects_validity(i))
+    }
+
+    /// The number of elements in this tensor, including pads and slices. Tensors with a zero-sized dimension count
+    /// as one element, so buffers sized by this are never empty.
     pub fn n_elements(&self) -> BigExpression {
-        let r = self
-            .indexes
+        let r = self.n_elements_or_zero();
+        if r == 0.into() {
+            1.into()
+        } else {
+            r
+        }
+    }
+
+    /// The number of elements in this tensor, including pads and slices, which is zero if any dimension is zero.
+    /// Scalars have one element.
+    pub fn n_elements_or_zero(&self) -> BigExpression {
+        if self.is_empty() {
+            return 1.into();
+        }
+        self.indexes
             .into_iter()
             .map(|i| (i, BigExpression::from(self.dims[i])))
             // Add pads
             .map(|(i, dim)| (i, dim + self.padding[i].0 + self.padding[i].1))
             // Slice
             .map(|(i, dim)| dim.min(self.slices[i].1) - self.slices[i].0)
-            .product();
-        if r == 0.into() {
-            1.into()
-        } else {
-            r
-        }
+            .product()
     }
 
     /// The number of elements in this tensor, not including pads and slices
@@ -234,12 +333,13 @@ impl ShapeTracker {
             .indexes
             .into_iter()
             .map(|i| {
-                self.dims[i].min(self.slices[i].1 - self.slices[i].0)
-                    + self.padding[i].0
-                    + self.padding[i].1
+                (self.dims[i] + self.padding[i].0 + self.padding[i].1).min(self.slices[i].1)
+                    - self.slices[i].0
             })
             .collect::<Vec<_>>();
-        Self::new(&new_dims)
+        let mut contiguous = Self::new(&new_dims);
+        contiguous.set_labels(&self.labels());
+        contiguous
     }
 
     /// Check if contiguous
@@ -252,9 +352,9 @@ impl ShapeTracker {
         self.indexes
             .into_iter()
             .map(|i| {
-                (BigExpression::from(self.dims[i]) + self.padding[i].0 - self.slices[i].0
-                    + self.padding[i].1)
+                (BigExpression::from(self.dims[i]) + self.padding[i].0 + self.padding[i].1)
                     .min(self.slices[i].1)
+                    - self.slices[i].0
             })
             .collect()
     }
@@ -267,17 +367,34 @@ impl ShapeTracker {
         }
     }
 
-    /// Add padding
+    /// Add padding. Negative padding known at build time crops the dimension instead, by slicing it
     pub fn pad(&mut self, padding: &[(Expression, Expression)]) {
         for (i, (s, e)) in padding.iter().enumerate() {
+            let ind = self.indexes[i];
+            let (crop_start, crop_end) = (crop_amount(*s), crop_amount(*e));
+            if crop_start > 0 || crop_end > 0 {
+                if self.padding[ind] != (0.into(), 0.into()) {
+                    panic!("Cropping a padded dimension isn't supported")
+                }
+                let end = if self.slices[ind].1 == i32::MAX.into() {
+                    self.dims[ind]
+                } else {
+                    self.slices[ind].1.min(self.dims[ind])
+                };
+                self.slices[ind] = (self.slices[ind].0 + crop_start, end - crop_end);
+            }
+            let (s, e) = (
+                if crop_start > 0 { 0.into() } else { *s },
+                if crop_end > 0 { 0.into() } else { *e },
+            );
             if (e.to_usize().map(|n| n != 0).unwrap_or(true)
-                && self.slices[self.indexes[i]]
+                && self.slices[ind]
                     .1
                     .to_usize()
                     .map(|n| n as i32 != i32::MAX)
                     .unwrap_or(true))
                 || (s.to_usize().map(|n| n != 0).unwrap_or(true)
-                    && self.slices[self.indexes[i]]
+                    && self.slices[ind]
                         .0
                         .to_usize()
                         .map(|n| n as i32 != 0)
@@ -285,8 +402,8 @@ impl ShapeTracker {
             {
                 panic!("Adding padding to a slice isn't supported")
             }
-            self.padding[self.indexes[i]].0 = self.padding[self.indexes[i]].0 + s.max(0);
-            self.padding[self.indexes[i]].1 = self.padding[self.indexes[i]].1 + e.max(0);
+            self.padding[ind].0 = self.padding[ind].0 + s;
+            self.padding[ind].1 = self.padding[ind].1 + e;
         }
     }
 
@@ -304,9 +421,17 @@ impl ShapeTracker {
         for d in self.dims.iter_mut() {
             *d = d.exec_stack(dyn_dim_map, stack).unwrap().into();
         }
-        for (a, b) in self.padding.iter_mut() {
+        for (i, (a, b)) in self.padding.iter_mut().enumerate() {
             *a = a.exec_stack(dyn_dim_map, stack).unwrap().into();
             *b = b.exec_stack(dyn_dim_map, stack).unwrap().into();
+            if crop_amount(*a) > 0 || crop_amount(*b) > 0 {
+                panic!(
+                    "Padding on axis {} resolved to ({}, {}), but negative padding must be known when the graph is built",
+                    self.indexes.iter().position(|ind| *ind == i).unwrap(),
+                    a.to_usize().unwrap() as i32,
+                    b.to_usize().unwrap() as i32,
+                );
+            }
         }
         for (a, b) in self.slices.iter_mut() {
             *a = a.exec_stack(dyn_dim_map, stack).unwrap().into();
@@ -314,6 +439,20 @@ impl ShapeTracker {
         }
     }
 
+    /// Substitute sizes for the dyn dims in the map. Expressions that use dyn dims missing from the map stay as they are.
+    pub fn specialize(&mut self, dyn_dim_map: &FxHashMap<char, usize>) {
+        let substitute = |e: &mut Expression| {
+            if let Some(n) = e.exec(dyn_dim_map) {
+                *e = n.into();
+            }
+        };
+        self.dims.iter_mut().for_each(substitute);
+        for (a, b) in self.padding.iter_mut().chain(self.slices.iter_mut()) {
+            substitute(a);
+            substitute(b);
+        }
+    }
+
     pub fn is_sliced(&self) -> bool {
         self.slices.iter().any(|(b, e)| {
             b.to_usize().map(|i| i != 0).unwrap_or(true)
@@ -321,6 +460,14 @@ impl ShapeTracker {
         })
     }
 
+    /// Whether this view only reorders the dimensions of a contiguous tensor, so making it contiguous is a transpose
+    pub fn is_transpose(&self) -> bool {
+        !self.is_contiguous()
+            && self.fake.iter().all(|f| !*f)
+            && !self.is_sliced()
+            && !self.is_padded()
+    }
+
     pub fn is_padded(&self) -> bool {
         self.padding.iter().any(|(b, e)| {
             b.to_usize().map(|i| i != 0).unwrap_or(true)
@@ -329,6 +476,14 @@ impl ShapeTracker {
     }
 }
 
+/// How much a padding amount crops, if it's a negative constant
+pub(crate) fn crop_amount(padding: Expression) -> usize {
+    padding
+        .to_usize()
+        .map(|n| (n as i32).min(0).unsigned_abs() as usize)
+        .unwrap_or_default()
+}
+
 /// Resolve shapes between the two trackers to the best of our ability
 pub fn resolve_local_dyn_dims(a: &mut ShapeTracker, b: &mut ShapeTracker, default_to_one: bool) {
     // B to A
@@ -351,3 +506,155 @@ pub fn resolve_local_dyn_dims(a: &mut ShapeTracker, b: &mut ShapeTracker, defaul
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    /// The valid expression built from every real dimension, without skipping any
+    fn full_valid_expression(sh: &ShapeTracker) -> BigExpression {
+        let mut ret = BigExpression::from(1);
+        let mut acc = BigExpression::from(1);
+        let logical = BigExpression::from('z');
+        for i in sh.indexes.into_iter().rev() {
+            let (dim, padding, slice) = (sh.dims[i], sh.padding[i], sh.slices[i]);
+            let logical_sh =
+                (BigExpression::from(dim) + padding.0 + padding.1).min(slice.1) - slice.0;
+            if !sh.fake[i] {
+                let dim_ind = (logical.clone() / acc.clone()) % logical_sh.clone();
+                ret = ret
+                    & dim_ind.clone().gte(
+                        BigExpression::from(padding.0)
+                            - BigExpression::from(slice.0).min(padding.0),
+                    );
+                ret = ret & dim_ind.lt((BigExpression::from(dim) + padding.0).min(slice.1));
+            }
+            acc = acc * logical_sh;
+        }
+        ret
+    }
+
+    #[test]
+    fn test_valid_expression_fast_path() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..500 {
+            let n_dims = rng.gen_range(1..5);
+            let dims = (0..n_dims)
+                .map(|_| Expression::from(rng.gen_range(1..5)))
+                .collect::<Vec<_>>();
+            let mut sh = ShapeTracker::new(&dims);
+            if rng.gen_bool(0.5) {
+                sh.expand(rng.gen_range(0..=n_dims), 3.into());
+            }
+            let mut axes = (0..sh.len()).collect::<Vec<_>>();
+            for i in (1..axes.len()).rev() {
+                axes.swap(i, rng.gen_range(0..=i));
+            }
+            sh.permute(&axes);
+            match rng.gen_range(0..3) {
+                0 => {
+                    let mut padding = vec![(Expression::from(0), Expression::from(0)); sh.len()];
+                    padding[rng.gen_range(0..sh.len())] =
+                        (rng.gen_range(0..2).into(), rng.gen_range(0..3).into());
+                    sh.pad(&padding);
+                }
+                1 => {
+                    let mut slices =
+                        vec![(Expression::from(0), Expression::from(i32::MAX)); sh.len()];
+                    let axis = rng.gen_range(0..sh.len());
+                    let size = sh.shape()[axis].to_usize().unwrap();
+                    let start = rng.gen_range(0..size);
+                    slices[axis] = (start.into(), rng.gen_range(start + 1..=size + 1).into());
+                    sh.slice(&slices);
+                }
+                _ => {}
+            }
+
+            let (fast, full) = (sh.valid_expression(), full_valid_expression(&sh));
+            if sh.is_always_valid() {
+                assert_eq!(fast, 1.into());
+            }
+            for z in 0..sh.n_elements().to_usize().unwrap() {
+                assert_eq!(
+                    fast.exec_single_var(z) != 0,
+                    full.exec_single_var(z) != 0,
+                    "Valid expressions differ at {z} for {sh:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Permute axes [0, 0, 2] aren't a permutation")]
+    fn test_permute_duplicate_axes() {
+        ShapeTracker::new(&[2.into(), 3.into(), 4.into()]).permute(&[0, 0, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Permute axes [1, 0] aren't a permutation of the 3 dimensions")]
+    fn test_permute_wrong_length() {
+        ShapeTracker::new(&[2.into(), 3.into(), 4.into()]).permute(&[1, 0]);
+    }
+
+    #[test]
+    fn test_permute_round_trip() {
+        let mut sh = ShapeTracker::new(&[2.into(), 3.into(), 4.into()]);
+        sh.expand(1, 5.into());
+        sh.pad(&[
+            (0.into(), 0.into()),
+            (0.into(), 0.into()),
+            (1.into(), 2.into()),
+            (0.into(), 0.into()),
+        ]);
+        let original = sh;
+        let axes = [3, 0, 2, 1];
+        sh.permute(&axes);
+        assert_eq!(sh.permutation(), vec![2, 0, 1, 3]);
+        assert_eq!(sh.shape(), [4, 2, 6, 5].map(BigExpression::from).to_vec());
+
+        sh.inverse_permute(&axes);
+        assert_eq!(sh, original);
+        assert_eq!(sh.index_expression(), original.index_expression());
+
+        let mut other = original;
+        other.permute(&[1, 3, 0, 2]);
+        assert_eq!(sh.permute_to_match(&other), vec![1, 3, 0, 2]);
+        assert_eq!(sh, other);
+    }
+
+    #[test]
+    fn test_slice_shape() {
+        let mut sh = ShapeTracker::new(&[4.into(), 5.into()]);
+        sh.slice(&[(1.into(), 3.into()), (2.into(), i32::MAX.into())]);
+        assert_eq!(sh.shape(), [2, 3].map(BigExpression::from).to_vec());
+        assert_eq!(sh.n_elements(), 6.into());
+        assert_eq!(
+            sh.contiguous().shape(),
+            [2, 3].map(BigExpression::from).to_vec()
+        );
+    }
+
+    #[test]
+    fn test_zero_sized_n_elements() {
+        let scalar = ShapeTracker::new(&[]);
+        assert_eq!(scalar.n_elements(), 1.into());
+        assert_eq!(scalar.n_elements_or_zero(), 1.into());
+
+        let mut sh = ShapeTracker::new(&[3.into(), 0.into()]);
+        assert_eq!(sh.n_elements(), 1.into());
+        assert_eq!(sh.n_elements_or_zero(), 0.into());
+        assert_eq!(sh.n_physical_elements(), 1.into());
+
+        // Slicing a dimension down to nothing empties the view too
+        let mut sliced = ShapeTracker::new(&[3.into(), 2.into()]);
+        sliced.slice(&[(3.into(), i32::MAX.into()), (0.into(), i32::MAX.into())]);
+        assert_eq!(sliced.n_elements_or_zero(), 0.into());
+        assert_eq!(sliced.n_elements(), 1.into());
+
+        // Padding doesn't make an empty dimension non-empty unless it adds elements
+        sh.pad(&[(0.into(), 0.into()), (1.into(), 0.into())]);
+        assert_eq!(sh.n_elements_or_zero(), 3.into());
+    }
+}
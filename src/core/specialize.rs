@@ -0,0 +1,299 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use petgraph::stable_graph::NodeIndex;
+use rustc_hash::FxHashMap;
+
+use crate::{
+    checkpoint::ForkError,
+    compiler_utils::{Compiler, ToIdsMut},
+    graph::{Dependency, Graph},
+    op::InputTensor,
+    shape::ShapeTracker,
+};
+
+/// The sizes dyn dims and node inputs resolved to while recording with [`Graph::record_shapes`]
+#[derive(Debug, Clone, Default)]
+pub struct ShapeRecord {
+    /// Number of executions recorded
+    pub runs: usize,
+    /// Every size each dyn dim had during an execution
+    pub dims: BTreeMap<char, BTreeSet<usize>>,
+    /// Every set of input shapes each node ran with
+    pub node_shapes: FxHashMap<NodeIndex, BTreeSet<Vec<Vec<usize>>>>,
+}
+
+impl ShapeRecord {
+    /// The dyn dims that had the same size in every recorded execution, ready to pass to [`Graph::specialize`]
+    pub fn constant_dims(&self) -> FxHashMap<char, usize> {
+        self.dims
+            .iter()
+            .filter(|(_, sizes)| sizes.len() == 1)
+            .map(|(dim, sizes)| (*dim, *sizes.first().unwrap()))
+            .collect()
+    }
+
+    /// Whether a node ran with the same input shapes in every recorded execution
+    pub fn is_constant(&self, node: NodeIndex) -> bool {
+        self.node_shapes
+            .get(&node)
+            .map(|shapes| shapes.len() == 1)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn start_run(&mut self, dyn_map: &FxHashMap<char, usize>) {
+        self.runs += 1;
+        for (dim, size) in dyn_map {
+            self.dims.entry(*dim).or_default().insert(*size);
+        }
+    }
+
+    pub(crate) fn record(&mut self, node: NodeIndex, srcs: &[(InputTensor, ShapeTracker)]) {
+        let shapes = srcs
+            .iter()
+            .map(|(_, st)| {
+                st.shape()
+                    .into_iter()
+                    .map(|d| d.to_usize().unwrap())
+                    .collect()
+            })
+            .collect();
+        self.node_shapes.entry(node).or_default().insert(shapes);
+    }
+}
+
+/// A dyn dim was set to a different size than the graph was specialized for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecializationMismatch {
+    pub dim: char,
+    /// The size the graph was specialized for
+    pub specialized: usize,
+    /// The size the dim was set to
+    pub got: usize,
+}
+
+impl Display for SpecializationMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Graph was specialized for {} = {}, but it was set to {}",
+            self.dim, self.specialized, self.got
+        )
+    }
+}
+
+impl std::error::Error for SpecializationMismatch {}
+
+impl Graph {
+    /// Start recording the sizes of dyn dims and the input shapes of every node on each execution, until
+    /// [`Graph::take_shape_record`] is called
+    pub fn record_shapes(&mut self) {
+        self.shape_recorder = Some(ShapeRecord::default());
+    }
+
+    /// Stop recording shapes, returning what was recorded
+    pub fn take_shape_record(&mut self) -> Option<ShapeRecord> {
+        self.shape_recorder.take()
+    }
+
+    /// A copy of the graph with dyn dims fixed to these sizes, compiled with `compiler`. Every shape in the copy has
+    /// the sizes substituted in, so compilers see static shapes, and executing it with a dim set to a different size
+    /// fails with [`ExecutionError::SpecializationMismatch`](crate::graph::ExecutionError::SpecializationMismatch).
+    ///
+    /// This graph is left as it is, so it can still run other sizes. The ids in `remap` are moved to the compiled
+    /// copy's tensors, and tensors are used on the copy by building them again with
+    /// [`GraphTensor::from_id`](crate::graph_tensor::GraphTensor::from_id). Run the model on representative inputs
+    /// while [recording shapes](Graph::record_shapes) to find the [constant dims](ShapeRecord::constant_dims) to
+    /// specialize for. Fails if the graph can't be [forked](Graph::fork).
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<(Dyn<'s'>,)>().set_dyn(vec![1., 2., 3.], &[3]);
+    /// let b = (a * 2.).retrieve();
+    /// let mut spec_b = b;
+    /// let mut spec = cx
+    ///     .specialize(&[('s', 3)].into_iter().collect(), GenericCompiler::default(), &mut spec_b)
+    ///     .unwrap();
+    /// let spec_b = GraphTensor::<(Dyn<'s'>,)>::from_id(spec_b.id, spec_b.shape, &mut spec);
+    /// spec.execute();
+    /// assert_eq!(spec_b.data(), vec![2., 4., 6.]);
+    /// ```
+    pub fn specialize<T: ToIdsMut, C: Compiler>(
+        &self,
+        dims: &FxHashMap<char, usize>,
+        compiler: C,
+        remap: T,
+    ) -> Result<Graph, ForkError> {
+        let mut graph = self.fork()?;
+        for edge in graph.graph.edge_weights_mut() {
+            if let Dependency::Data { shape, .. } = edge {
+                shape.specialize(dims);
+            }
+        }
+        for (dim, size) in dims {
+            graph.dyn_map.insert(*dim, *size);
+            graph.specialized_dims.insert(*dim, *size);
+        }
+        graph.compile(compiler, remap);
+        Ok(graph)
+    }
+
+    /// The dyn dim sizes the graph was specialized for
+    pub fn specialized_dims(&self) -> &FxHashMap<char, usize> {
+        &self.specialized_dims
+    }
+
+    /// Check the dyn dims are set to the sizes the graph was specialized for
+    pub fn check_specialized_dims(&self) -> Result<(), SpecializationMismatch> {
+        for (dim, specialized) in &self.specialized_dims {
+            if let Some(got) = self.dyn_map.get(dim).filter(|got| *got != specialized) {
+                return Err(SpecializationMismatch {
+                    dim: *dim,
+                    specialized: *specialized,
+                    got: *got,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    use rustc_hash::FxHashMap;
+
+    use super::SpecializationMismatch;
+    use crate::graph::ExecutionError;
+
+    #[test]
+    fn test_record_shapes() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<(Dyn<'b'>, Dyn<'s'>)>();
+        let b = cx
+            .tensor::<(Dyn<'s'>, LConst<2>)>()
+            .set_dyn(random_vec(4), &[2, 2]);
+        let mut c = a.matmul(b).retrieve();
+        cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut c);
+
+        cx.record_shapes();
+        for s in [2, 3] {
+            a.set_dyn(random_vec(3 * s), &[3, s]);
+            b.set_dyn(random_vec(2 * s), &[s, 2]);
+            cx.execute();
+            // The output is kept, so it has to be dropped for the matmul to run again
+            c.drop();
+        }
+        // Runs without deleting intermediates are recorded too
+        a.set_dyn(random_vec(3 * 4), &[3, 4]);
+        b.set_dyn(random_vec(2 * 4), &[4, 2]);
+        cx.execute_no_delete();
+        let record = cx.take_shape_record().unwrap();
+        assert_eq!(record.runs, 3);
+        assert_eq!(record.dims[&'s'].len(), 3);
+        assert_eq!(
+            record.constant_dims(),
+            [('b', 3)].into_iter().collect::<FxHashMap<_, _>>()
+        );
+        // Every node ran on each execution, with the sizes s was set to
+        assert!(!record.node_shapes.is_empty());
+        for shapes in record.node_shapes.values() {
+            assert_eq!(
+                shapes.len(),
+                if shapes.iter().all(Vec::is_empty) {
+                    1
+                } else {
+                    3
+                }
+            );
+        }
+        assert!(record
+            .node_shapes
+            .values()
+            .any(|s| s.contains(&vec![vec![3, 4], vec![4, 2]])));
+        assert!(record.node_shapes.keys().any(|n| !record.is_constant(*n)));
+
+        // Nothing is recorded once the record is taken
+        cx.execute();
+        assert!(cx.take_shape_record().is_none());
+    }
+
+    #[test]
+    fn test_specialize() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<(Dyn<'b'>, LConst<3>)>()
+            .set_dyn(random_vec(6), &[2, 3]);
+        let w = cx.tensor::<R2<3, 2>>().set(random_vec(6));
+        let mut out = a.matmul(w).relu().retrieve();
+
+        let mut spec_out = out;
+        let mut spec = cx
+            .specialize(
+                &[('b', 2)].into_iter().collect(),
+                <(GenericCompiler, CPUCompiler)>::default(),
+                &mut spec_out,
+            )
+            .unwrap();
+        let is_static = |cx: &Graph| {
+            cx.graph
+                .edge_weights()
+                .filter_map(|e| e.as_data())
+                .all(|(_, _, shape)| shape.shape().iter().all(|d| d.to_usize().is_some()))
+        };
+        assert!(is_static(&spec));
+        let spec_out =
+            GraphTensor::<(Dyn<'b'>, LConst<2>)>::from_id(spec_out.id, spec_out.shape, &mut spec);
+        spec.execute();
+
+        // The original graph is still dynamic
+        assert!(!is_static(&cx));
+        cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut out);
+        cx.execute();
+        assert_exact(&spec_out.data(), &out.data());
+        out.drop();
+        a.set_dyn(random_vec(9), &[3, 3]);
+        cx.execute();
+        assert_eq!(out.data().len(), 6);
+    }
+
+    #[test]
+    fn test_specialized_mismatch() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<(Dyn<'b'>, LConst<3>)>()
+            .set_dyn(random_vec(6), &[2, 3]);
+        let mut out = (a * 2.).retrieve();
+        let mut spec = cx
+            .specialize(
+                &[('b', 2)].into_iter().collect(),
+                <(GenericCompiler, CPUCompiler)>::default(),
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(spec.check_specialized_dims(), Ok(()));
+        spec.execute();
+
+        let a = GraphTensor::<(Dyn<'b'>, LConst<3>)>::from_id(a.id, a.shape, &mut spec);
+        a.set_dyn(random_vec(9), &[3, 3]);
+        let error = spec.check_specialized_dims().unwrap_err();
+        assert_eq!(
+            error,
+            SpecializationMismatch {
+                dim: 'b',
+                specialized: 2,
+                got: 3
+            }
+        );
+        assert!(matches!(
+            spec.try_execute(),
+            Err(ExecutionError::SpecializationMismatch(e)) if e == error
+        ));
+        let panic =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| spec.execute())).unwrap_err();
+        assert_eq!(panic.downcast_ref::<String>(), Some(&error.to_string()));
+    }
+}
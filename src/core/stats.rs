@@ -0,0 +1,238 @@
+use std::{cell::RefCell, collections::BTreeMap, fmt::Display, rc::Rc};
+
+use crate::prelude::*;
+
+/// Running min, max, mean and variance of every value a tensor has taken, across executions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunningStats {
+    /// Number of values seen
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f64,
+    /// Sum of squared differences from the mean
+    pub m2: f64,
+}
+
+impl Default for RunningStats {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            mean: 0.,
+            m2: 0.,
+        }
+    }
+}
+
+impl RunningStats {
+    /// Add a batch of values
+    pub fn update(&mut self, data: &[f32]) {
+        if data.is_empty() {
+            return;
+        }
+        let mut batch = RunningStats {
+            count: data.len(),
+            mean: data.iter().map(|v| *v as f64).sum::<f64>() / data.len() as f64,
+            ..Default::default()
+        };
+        for v in data {
+            batch.min = batch.min.min(*v);
+            batch.max = batch.max.max(*v);
+            batch.m2 += (*v as f64 - batch.mean).powi(2);
+        }
+        self.merge(&batch);
+    }
+
+    /// Combine with stats collected over other values, as if both had seen every value
+    pub fn merge(&mut self, other: &RunningStats) {
+        if other.count == 0 {
+            return;
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.m2 += other.m2 + delta * delta * (self.count * other.count) as f64 / count as f64;
+        self.count = count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Population variance of the values seen
+    pub fn variance(&self) -> f64 {
+        if self.count == 0 {
+            return 0.;
+        }
+        self.m2 / self.count as f64
+    }
+
+    /// Unbiased sample variance of the values seen
+    pub fn sample_variance(&self) -> f64 {
+        if self.count < 2 {
+            return 0.;
+        }
+        self.m2 / (self.count - 1) as f64
+    }
+
+    pub fn std(&self) -> f64 {
+        self.variance().sqrt()
+    }
+}
+
+impl Display for RunningStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "n: {} min: {} max: {} mean: {:.6} std: {:.6}",
+            self.count,
+            self.min,
+            self.max,
+            self.mean,
+            self.std()
+        )
+    }
+}
+
+/// Collects [`RunningStats`] of named tensors over many executions, like activation ranges for quantization
+/// calibration or batch norm statistics.
+///
+/// Registered tensors are tapped, so their stats update after every execute. On GPU backends the values are copied
+/// back to the host to be added. Clones share the same stats.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let a = cx.tensor::<R1<3>>();
+/// let stats = StatsCollector::new();
+/// stats.register("a", a);
+/// for batch in [[1., 2., 3.], [4., 5., 6.]] {
+///     a.set(batch.to_vec());
+///     cx.execute();
+/// }
+/// let a_stats = stats.get("a").unwrap();
+/// assert_eq!((a_stats.count, a_stats.min, a_stats.max), (6, 1., 6.));
+/// assert_eq!(a_stats.mean, 3.5);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct StatsCollector {
+    stats: Rc<RefCell<BTreeMap<String, RunningStats>>>,
+}
+
+impl StatsCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Collect stats of every value `tensor` takes, under `name`
+    pub fn register<S: Shape>(&self, name: &str, tensor: GraphTensor<S>) {
+        self.stats
+            .borrow_mut()
+            .insert(name.to_string(), RunningStats::default());
+        let (stats, key) = (self.stats.clone(), name.to_string());
+        tensor.tap(format!("Stats-{name}"), move |data, _| {
+            stats
+                .borrow_mut()
+                .entry(key.clone())
+                .or_default()
+                .update(data)
+        });
+    }
+
+    /// The stats of a registered tensor
+    pub fn get(&self, name: &str) -> Option<RunningStats> {
+        self.stats.borrow().get(name).copied()
+    }
+
+    /// The stats of every registered tensor, by name
+    pub fn report(&self) -> BTreeMap<String, RunningStats> {
+        self.stats.borrow().clone()
+    }
+
+    /// Forget every value seen so far, keeping the registered tensors
+    pub fn reset(&self) {
+        for stats in self.stats.borrow_mut().values_mut() {
+            *stats = RunningStats::default();
+        }
+    }
+
+    /// Add in the stats from another collector, like one running on a different shard of the data
+    pub fn merge(&self, other: &StatsCollector) {
+        if Rc::ptr_eq(&self.stats, &other.stats) {
+            return;
+        }
+        let mut stats = self.stats.borrow_mut();
+        for (name, other) in other.stats.borrow().iter() {
+            stats.entry(name.clone()).or_default().merge(other);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_stats_collector() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<4, 8>>();
+        let b = (a * 2.).exp2().sum_reduce::<_, LAxis<1>>();
+        let (stats, shard) = (StatsCollector::new(), StatsCollector::new());
+        stats.register("a", a);
+        stats.register("b", b);
+        cx.compile(<(GenericCompiler, CPUCompiler)>::default(), ());
+
+        let (mut all_a, mut all_b) = (vec![], vec![]);
+        for _ in 0..100 {
+            let data = random_vec(32);
+            a.set(data.clone());
+            cx.execute();
+            all_b.extend(
+                data.chunks(8)
+                    .map(|r| r.iter().map(|v| (v * 2.).exp2()).sum::<f32>()),
+            );
+            all_a.extend(data);
+        }
+        for (name, all) in [("a", &all_a), ("b", &all_b)] {
+            let s = stats.get(name).unwrap();
+            let n = all.len() as f64;
+            let mean = all.iter().map(|v| *v as f64).sum::<f64>() / n;
+            let var = all.iter().map(|v| (*v as f64 - mean).powi(2)).sum::<f64>() / n;
+            assert_eq!(s.count, all.len());
+            assert!(
+                (s.mean - mean).abs() < 1e-4,
+                "{name} mean {} vs {mean}",
+                s.mean
+            );
+            assert!(
+                (s.variance() - var).abs() < 1e-3,
+                "{name} var {} vs {var}",
+                s.variance()
+            );
+            assert_eq!(s.min, all.iter().copied().fold(f32::INFINITY, f32::min));
+            assert_eq!(s.max, all.iter().copied().fold(f32::NEG_INFINITY, f32::max));
+        }
+
+        // Merging two shards matches collecting everything in one place
+        let before = stats.get("a").unwrap();
+        let (first, second) = all_a.split_at(1000);
+        let mut shard_stats = RunningStats::default();
+        shard_stats.update(first);
+        shard
+            .stats
+            .borrow_mut()
+            .insert("a".to_string(), shard_stats);
+        stats.reset();
+        assert_eq!(stats.get("a").unwrap().count, 0);
+        stats
+            .stats
+            .borrow_mut()
+            .get_mut("a")
+            .unwrap()
+            .update(second);
+        stats.merge(&shard);
+        let merged = stats.get("a").unwrap();
+        assert_eq!(merged.count, before.count);
+        assert!((merged.mean - before.mean).abs() < 1e-9);
+        assert!((merged.variance() - before.variance()).abs() < 1e-9);
+    }
+}
@@ -20,6 +20,14 @@ impl Tensor {
 pub trait Data: Any + Debug + DynClone {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// The name of the concrete data type, for errors about data of the wrong type
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+    /// The memory backing this data as (address, size in bytes), so shared allocations are only counted once
+    fn allocation(&self) -> Option<(usize, usize)> {
+        None
+    }
 }
 
 clone_trait_object!(Data);
@@ -31,4 +39,181 @@ impl Data for Vec<f32> {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn allocation(&self) -> Option<(usize, usize)> {
+        Some((
+            self.as_ptr() as usize,
+            self.capacity() * std::mem::size_of::<f32>(),
+        ))
+    }
+}
+
+impl Data for Vec<i32> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn allocation(&self) -> Option<(usize, usize)> {
+        Some((
+            self.as_ptr() as usize,
+            self.capacity() * std::mem::size_of::<i32>(),
+        ))
+    }
+}
+
+impl Data for Vec<half::f16> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn allocation(&self) -> Option<(usize, usize)> {
+        Some((
+            self.as_ptr() as usize,
+            self.capacity() * std::mem::size_of::<half::f16>(),
+        ))
+    }
+}
+
+impl Data for Vec<half::bf16> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn allocation(&self) -> Option<(usize, usize)> {
+        Some((
+            self.as_ptr() as usize,
+            self.capacity() * std::mem::size_of::<half::bf16>(),
+        ))
+    }
+}
+
+/// Weights in GGML's Q8_0 format: blocks of 32 int8 values sharing an fp16 scale, each stored as the little endian
+/// scale followed by the 32 values (34 bytes). Blocks run along the last dimension.
+///
+/// Loaders hand these to backends with quantized matmuls as-is, rather than dequantizing to fp32.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Q8_0Blocks(pub Vec<u8>);
+
+impl Q8_0Blocks {
+    pub const BLOCK_SIZE: usize = 32;
+    pub const BLOCK_BYTES: usize = 34;
+
+    /// Quantize values, with each block scaled by its absolute max. The length must be a multiple of 32.
+    pub fn quantize(data: &[f32]) -> Self {
+        assert!(
+            data.len().is_multiple_of(Self::BLOCK_SIZE),
+            "Q8_0 needs a multiple of {} values, got {}",
+            Self::BLOCK_SIZE,
+            data.len()
+        );
+        let mut bytes = Vec::with_capacity(data.len() / Self::BLOCK_SIZE * Self::BLOCK_BYTES);
+        for block in data.chunks_exact(Self::BLOCK_SIZE) {
+            let scale = block.iter().fold(0_f32, |m, v| m.max(v.abs())) / 127.;
+            let inv_scale = if scale == 0. { 0. } else { 1. / scale };
+            bytes.extend(half::f16::from_f32(scale).to_le_bytes());
+            bytes.extend(block.iter().map(|v| (v * inv_scale).round() as i8 as u8));
+        }
+        Self(bytes)
+    }
+
+    /// The values the blocks hold, as fp32
+    pub fn dequantize(&self) -> Vec<f32> {
+        self.0
+            .chunks_exact(Self::BLOCK_BYTES)
+            .flat_map(|block| {
+                let scale = half::f16::from_le_bytes([block[0], block[1]]).to_f32();
+                block[2..].iter().map(move |q| *q as i8 as f32 * scale)
+            })
+            .collect()
+    }
+}
+
+impl Data for Q8_0Blocks {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn allocation(&self) -> Option<(usize, usize)> {
+        Some((self.0.as_ptr() as usize, self.0.capacity()))
+    }
+}
+
+/// Weights in GGML's Q4_0 format: blocks of 32 4-bit values sharing an fp16 scale, each stored as the little endian
+/// scale followed by 16 bytes of packed values (18 bytes). Byte `i` holds value `i` in its low nibble and value `i + 16`
+/// in its high nibble, offset by 8. Blocks run along the last dimension, with each row padded to a whole block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Q4_0Blocks(pub Vec<u8>);
+
+impl Q4_0Blocks {
+    pub const BLOCK_SIZE: usize = 32;
+    pub const BLOCK_BYTES: usize = 18;
+
+    /// Quantize rows of `row_len` values. Rows that aren't a multiple of 32 long are padded with zeros.
+    pub fn quantize(data: &[f32], row_len: usize) -> Self {
+        assert!(
+            row_len > 0 && data.len().is_multiple_of(row_len),
+            "{} values can't be split into rows of {row_len}",
+            data.len()
+        );
+        let blocks_per_row = row_len.div_ceil(Self::BLOCK_SIZE);
+        let mut bytes =
+            Vec::with_capacity(data.len() / row_len * blocks_per_row * Self::BLOCK_BYTES);
+        let mut block = [0_f32; Self::BLOCK_SIZE];
+        for row in data.chunks_exact(row_len) {
+            for values in row.chunks(Self::BLOCK_SIZE) {
+                block.fill(0.);
+                block[..values.len()].copy_from_slice(values);
+                // Like GGML, the value with the largest magnitude maps to -8, so it's represented exactly
+                let max = block
+                    .iter()
+                    .fold(0_f32, |m, v| if v.abs() > m.abs() { *v } else { m });
+                let scale = max / -8.;
+                let inv_scale = if scale == 0. { 0. } else { 1. / scale };
+                let quant = |v: f32| ((v * inv_scale + 8.5) as u8).min(15);
+                bytes.extend(half::f16::from_f32(scale).to_le_bytes());
+                bytes.extend(
+                    (0..Self::BLOCK_SIZE / 2)
+                        .map(|i| quant(block[i]) | (quant(block[i + Self::BLOCK_SIZE / 2]) << 4)),
+                );
+            }
+        }
+        Self(bytes)
+    }
+
+    /// The values the blocks hold, as fp32, without the padding of rows of `row_len` values
+    pub fn dequantize(&self, row_len: usize) -> Vec<f32> {
+        let blocks_per_row = row_len.div_ceil(Self::BLOCK_SIZE);
+        self.0
+            .chunks_exact(blocks_per_row * Self::BLOCK_BYTES)
+            .flat_map(|row| {
+                row.chunks_exact(Self::BLOCK_BYTES)
+                    .flat_map(|block| {
+                        let scale = half::f16::from_le_bytes([block[0], block[1]]).to_f32();
+                        let low = block[2..].iter().map(|q| q & 0xF);
+                        let high = block[2..].iter().map(|q| q >> 4);
+                        low.chain(high).map(move |q| (q as i32 - 8) as f32 * scale)
+                    })
+                    .take(row_len)
+            })
+            .collect()
+    }
+}
+
+impl Data for Q4_0Blocks {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn allocation(&self) -> Option<(usize, usize)> {
+        Some((self.0.as_ptr() as usize, self.0.capacity()))
+    }
 }
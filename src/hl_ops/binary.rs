@@ -1,5 +1,7 @@
 use crate::op;
+use crate::compiler_internals::*;
 use crate::prelude::*;
+use crate::shape::binary_op_labels;
 use std::ops::AddAssign;
 use std::ops::DivAssign;
 use std::ops::MulAssign;
@@ -14,10 +16,12 @@ use self::symbolic::Term;
 impl<S: Shape> Add for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn add(mut self, mut rhs: GraphTensor<S>) -> Self::Output {
         resolve_local_dyn_dims(&mut self.shape, &mut rhs.shape, false);
         let mut new_shape = ShapeTracker::new(&S::realized_shape());
         resolve_local_dyn_dims(&mut new_shape, &mut rhs.shape, false);
+        new_shape.set_labels(&binary_op_labels(self.graph(), &self.shape, &rhs.shape));
         let new_id = self
             .graph()
             .add_op(op::Add)
@@ -29,6 +33,7 @@ impl<S: Shape> Add for GraphTensor<S> {
 }
 
 impl<S: Shape> AddAssign for GraphTensor<S> {
+    #[track_caller]
     fn add_assign(&mut self, rhs: Self) {
         *self = *self + rhs;
     }
@@ -37,12 +42,14 @@ impl<S: Shape> AddAssign for GraphTensor<S> {
 impl<S: Shape> Sub for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn sub(self, rhs: GraphTensor<S>) -> Self::Output {
         self + -rhs
     }
 }
 
 impl<S: Shape> SubAssign for GraphTensor<S> {
+    #[track_caller]
     fn sub_assign(&mut self, rhs: Self) {
         *self = *self - rhs;
     }
@@ -51,19 +58,23 @@ impl<S: Shape> SubAssign for GraphTensor<S> {
 impl<S: Shape> Mul for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn mul(mut self, mut rhs: GraphTensor<S>) -> Self::Output {
         resolve_local_dyn_dims(&mut self.shape, &mut rhs.shape, false);
+        let mut new_shape = self.shape.contiguous();
+        new_shape.set_labels(&binary_op_labels(self.graph(), &self.shape, &rhs.shape));
         let new_id = self
             .graph()
             .add_op(op::Mul)
             .input(self.id, 0, self.shape)
             .input(rhs.id, 0, rhs.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, new_shape, self.graph_ref)
     }
 }
 
 impl<S: Shape> MulAssign for GraphTensor<S> {
+    #[track_caller]
     fn mul_assign(&mut self, rhs: Self) {
         *self = *self * rhs;
     }
@@ -73,12 +84,14 @@ impl<S: Shape> MulAssign for GraphTensor<S> {
 impl<S: Shape> Div<GraphTensor<S>> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn div(self, rhs: GraphTensor<S>) -> Self::Output {
         self * rhs.recip()
     }
 }
 
 impl<S: Shape> DivAssign for GraphTensor<S> {
+    #[track_caller]
     fn div_assign(&mut self, rhs: Self) {
         *self = *self / rhs;
     }
@@ -87,10 +100,12 @@ impl<S: Shape> DivAssign for GraphTensor<S> {
 impl<S: Shape> Rem<GraphTensor<S>> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn rem(mut self, mut rhs: GraphTensor<S>) -> Self::Output {
         resolve_local_dyn_dims(&mut self.shape, &mut rhs.shape, false);
         let mut new_shape = ShapeTracker::new(&S::realized_shape());
         resolve_local_dyn_dims(&mut new_shape, &mut rhs.shape, false);
+        new_shape.set_labels(&binary_op_labels(self.graph(), &self.shape, &rhs.shape));
         let new_id = self
             .graph()
             .add_op(op::Mod)
@@ -102,6 +117,7 @@ impl<S: Shape> Rem<GraphTensor<S>> for GraphTensor<S> {
 }
 
 impl<S: Shape> RemAssign for GraphTensor<S> {
+    #[track_caller]
     fn rem_assign(&mut self, rhs: Self) {
         *self = *self % rhs;
     }
@@ -110,6 +126,7 @@ impl<S: Shape> RemAssign for GraphTensor<S> {
 impl<S: Shape> Add<f32> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn add(self, rhs: f32) -> Self::Output {
         self + self.graph().constant(rhs).expand()
     }
@@ -121,6 +138,7 @@ where
 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn add(self, rhs: GenericExpression<St>) -> Self::Output {
         self + self.graph().constant_expr(rhs).expand()
     }
@@ -129,6 +147,7 @@ where
 impl<S: Shape> Sub<f32> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn sub(self, rhs: f32) -> Self::Output {
         self - self.graph().constant(rhs).expand()
     }
@@ -140,6 +159,7 @@ where
 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn sub(self, rhs: GenericExpression<St>) -> Self::Output {
         self - self.graph().constant_expr(rhs).expand()
     }
@@ -148,6 +168,7 @@ where
 impl<S: Shape> Mul<f32> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn mul(self, rhs: f32) -> Self::Output {
         self * self.graph().constant(rhs).expand()
     }
@@ -159,6 +180,7 @@ where
 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn mul(self, rhs: GenericExpression<St>) -> Self::Output {
         self * self.graph().constant_expr(rhs).expand()
     }
@@ -168,6 +190,7 @@ where
 impl<S: Shape> Div<f32> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn div(self, rhs: f32) -> Self::Output {
         self * self.graph().constant(rhs.recip()).expand()
     }
@@ -179,6 +202,7 @@ where
 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn div(self, rhs: GenericExpression<St>) -> Self::Output {
         self / self.graph().constant_expr(rhs).expand()
     }
@@ -187,6 +211,7 @@ where
 impl<S: Shape> Rem<f32> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn rem(self, rhs: f32) -> Self::Output {
         self % self.graph().constant(rhs).expand()
     }
@@ -198,6 +223,7 @@ where
 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn rem(self, rhs: GenericExpression<St>) -> Self::Output {
         self % self.graph().constant_expr(rhs).expand()
     }
@@ -205,10 +231,12 @@ where
 
 // Comparisons (based on https://github.com/tinygrad/tinygrad/blob/3e0c2d256fe9f4f5f85cd3e4d8733a51d7b4a984/tinygrad/tensor.py#L653)
 impl<S: Shape> GraphTensor<S> {
+    #[track_caller]
     pub fn less_than(mut self, mut rhs: GraphTensor<S>) -> GraphTensor<S> {
         resolve_local_dyn_dims(&mut self.shape, &mut rhs.shape, false);
         let mut new_shape = ShapeTracker::new(&S::realized_shape());
         resolve_local_dyn_dims(&mut new_shape, &mut rhs.shape, false);
+        new_shape.set_labels(&binary_op_labels(self.graph(), &self.shape, &rhs.shape));
         let new_id = self
             .graph()
             .add_op(op::LessThan)
@@ -218,22 +246,27 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(new_id, new_shape, self.graph_ref)
     }
 
+    #[track_caller]
     pub fn greater_than(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         rhs.less_than(self)
     }
 
+    #[track_caller]
     pub fn less_than_equal(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         -self.greater_than(rhs) + 1.0
     }
 
+    #[track_caller]
     pub fn greater_than_equal(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         -self.less_than(rhs) + 1.0
     }
 
+    #[track_caller]
     pub fn not_equals(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         self.less_than(rhs) + self.greater_than(rhs)
     }
 
+    #[track_caller]
     pub fn equals(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         -self.not_equals(rhs) + 1.0
     }
@@ -242,26 +275,31 @@ impl<S: Shape> GraphTensor<S> {
 // Clipping ops (min, max, clip)
 impl<S: Shape> GraphTensor<S> {
     /// Take the elementwise maximum of two tensors
+    #[track_caller]
     pub fn max(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         (self.less_than(rhs) * rhs) + (rhs.less_than_equal(self) * self)
     }
 
     /// Take the elementwise maximum of a tensor and a float
+    #[track_caller]
     pub fn max_f32(self, rhs: f32) -> GraphTensor<S> {
         self.max(self.graph().constant(rhs).expand())
     }
 
     /// Take the elementwise minimum of two tensors
+    #[track_caller]
     pub fn min(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         -(-self).max(-rhs)
     }
 
     /// Take the elementwise minimum of a tensor and a float
+    #[track_caller]
     pub fn min_f32(self, rhs: f32) -> GraphTensor<S> {
         -(-self).max_f32(-rhs)
     }
 
     /// Clip a tensor in a range
+    #[track_caller]
     pub fn clip(self, min: f32, max: f32) -> GraphTensor<S> {
         self.min_f32(min).max_f32(max)
     }
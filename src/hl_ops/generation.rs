@@ -0,0 +1,270 @@
+use std::fmt::Display;
+
+use crate::prelude::*;
+
+/// What [`GenerationLimits::prompt`] does with a prompt longer than the context
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Truncation {
+    /// Reject the prompt
+    #[default]
+    Error,
+    /// Drop tokens from the start, keeping the end of the prompt
+    Left,
+    /// Drop tokens from the end, keeping the start of the prompt
+    Right,
+}
+
+/// Why a generation was refused before running the model
+#[derive(Debug, Clone, PartialEq)]
+pub enum GenerationError {
+    /// The prompt doesn't fit in the context, and the truncation policy is [`Truncation::Error`]
+    PromptTooLong { len: usize, max_context: usize },
+    /// A token id has no row in the embedding table
+    TokenOutOfRange {
+        position: usize,
+        token: usize,
+        vocab: usize,
+    },
+    /// Top-p has to be in (0, 1]
+    InvalidTopP(f32),
+    /// Temperature has to be positive
+    InvalidTemperature(f32),
+    /// Top-k has to keep at least one token
+    InvalidTopK(usize),
+}
+
+impl Display for GenerationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GenerationError::PromptTooLong { len, max_context } => {
+                write!(
+                    f,
+                    "Prompt of {len} tokens is longer than the context of {max_context}"
+                )
+            }
+            GenerationError::TokenOutOfRange {
+                position,
+                token,
+                vocab,
+            } => write!(
+                f,
+                "Token {token} at position {position} is out of range for a vocab of {vocab}"
+            ),
+            GenerationError::InvalidTopP(p) => write!(f, "Top-p of {p} isn't in (0, 1]"),
+            GenerationError::InvalidTemperature(t) => {
+                write!(f, "Temperature of {t} isn't positive")
+            }
+            GenerationError::InvalidTopK(k) => write!(f, "Top-k of {k} keeps no tokens"),
+        }
+    }
+}
+
+impl std::error::Error for GenerationError {}
+
+/// Sampling settings for a generation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingParams {
+    pub temperature: f32,
+    /// Nucleus sampling mass, where 1 keeps every token
+    pub top_p: f32,
+    /// Only sample from the `k` most likely tokens
+    pub top_k: Option<usize>,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            temperature: 1.,
+            top_p: 1.,
+            top_k: None,
+        }
+    }
+}
+
+impl SamplingParams {
+    /// Check the settings are in range
+    pub fn validate(&self) -> Result<(), GenerationError> {
+        if !(self.top_p > 0. && self.top_p <= 1.) {
+            return Err(GenerationError::InvalidTopP(self.top_p));
+        }
+        if !(self.temperature > 0. && self.temperature.is_finite()) {
+            return Err(GenerationError::InvalidTemperature(self.temperature));
+        }
+        if self.top_k == Some(0) {
+            return Err(GenerationError::InvalidTopK(0));
+        }
+        Ok(())
+    }
+}
+
+/// The bounds a model puts on its inputs, for checking a generation on the host before anything runs on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenerationLimits {
+    /// Rows in the embedding table
+    pub vocab: usize,
+    /// The most tokens the model can take at once
+    pub max_context: usize,
+    pub truncation: Truncation,
+}
+
+impl GenerationLimits {
+    /// Limits for a model embedding tokens with `embedding`, whose vocab size is read from its shape.
+    ///
+    /// `max_context` is the longest sequence the model was built for, or the window W of a
+    /// [`RollingCache`](crate::nn::transformer::rolling_cache::RollingCache), which can't take longer chunks.
+    pub fn new<V: Dimension, const DIM: usize>(
+        embedding: GraphTensor<(V, Const<DIM>)>,
+        max_context: usize,
+    ) -> Self {
+        let Some(vocab) = embedding.shape.shape()[0].to_usize() else {
+            panic!("Embedding tables need a static number of rows to check tokens against");
+        };
+        Self {
+            vocab,
+            max_context,
+            truncation: Truncation::default(),
+        }
+    }
+
+    /// Handle prompts longer than the context with `truncation`, instead of rejecting them
+    pub fn with_truncation(mut self, truncation: Truncation) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    /// Check every token of a prompt is in the vocab, and fit it to the context with the truncation policy.
+    /// Returns the tokens to run the model on.
+    pub fn prompt<'a>(&self, tokens: &'a [usize]) -> Result<&'a [usize], GenerationError> {
+        if let Some((position, token)) = tokens.iter().enumerate().find(|(_, t)| **t >= self.vocab)
+        {
+            return Err(GenerationError::TokenOutOfRange {
+                position,
+                token: *token,
+                vocab: self.vocab,
+            });
+        }
+        if tokens.len() <= self.max_context {
+            return Ok(tokens);
+        }
+        match self.truncation {
+            Truncation::Error => Err(GenerationError::PromptTooLong {
+                len: tokens.len(),
+                max_context: self.max_context,
+            }),
+            Truncation::Left => Ok(&tokens[tokens.len() - self.max_context..]),
+            Truncation::Right => Ok(&tokens[..self.max_context]),
+        }
+    }
+
+    /// Check a prompt and the sampling settings together, returning the tokens to run the model on
+    pub fn validate<'a>(
+        &self,
+        tokens: &'a [usize],
+        params: &SamplingParams,
+    ) -> Result<&'a [usize], GenerationError> {
+        params.validate()?;
+        self.prompt(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_prompt_validation() {
+        let mut cx = Graph::new();
+        let embedding = cx.tensor::<R2<10, 4>>();
+        let limits = GenerationLimits::new(embedding, 4);
+        assert_eq!(limits.vocab, 10);
+
+        assert_eq!(limits.prompt(&[1, 2, 9]), Ok(&[1, 2, 9][..]));
+        assert_eq!(
+            limits.prompt(&[1, 10, 2]),
+            Err(GenerationError::TokenOutOfRange {
+                position: 1,
+                token: 10,
+                vocab: 10
+            })
+        );
+        let long = [1, 2, 3, 4, 5, 6];
+        assert_eq!(
+            limits.prompt(&long),
+            Err(GenerationError::PromptTooLong {
+                len: 6,
+                max_context: 4
+            })
+        );
+        assert_eq!(
+            limits.with_truncation(Truncation::Left).prompt(&long),
+            Ok(&[3, 4, 5, 6][..])
+        );
+        assert_eq!(
+            limits.with_truncation(Truncation::Right).prompt(&long),
+            Ok(&[1, 2, 3, 4][..])
+        );
+        // Tokens are checked even if truncation would drop them
+        assert!(matches!(
+            limits
+                .with_truncation(Truncation::Left)
+                .prompt(&[11, 1, 2, 3, 4]),
+            Err(GenerationError::TokenOutOfRange { position: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_sampling_validation() {
+        let valid = SamplingParams {
+            temperature: 0.7,
+            top_p: 1.,
+            top_k: Some(1),
+        };
+        assert_eq!(valid.validate(), Ok(()));
+        for (params, error) in [
+            (
+                SamplingParams { top_p: 0., ..valid },
+                GenerationError::InvalidTopP(0.),
+            ),
+            (
+                SamplingParams {
+                    top_p: 1.5,
+                    ..valid
+                },
+                GenerationError::InvalidTopP(1.5),
+            ),
+            (
+                SamplingParams {
+                    temperature: 0.,
+                    ..valid
+                },
+                GenerationError::InvalidTemperature(0.),
+            ),
+            (
+                SamplingParams {
+                    temperature: -1.,
+                    ..valid
+                },
+                GenerationError::InvalidTemperature(-1.),
+            ),
+            (
+                SamplingParams {
+                    top_k: Some(0),
+                    ..valid
+                },
+                GenerationError::InvalidTopK(0),
+            ),
+        ] {
+            assert_eq!(params.validate(), Err(error.clone()));
+            let mut cx = Graph::new();
+            let limits = GenerationLimits::new(cx.tensor::<R2<10, 4>>(), 4);
+            assert_eq!(limits.validate(&[1], &params), Err(error));
+        }
+        // NaN settings are rejected too
+        assert!(SamplingParams {
+            top_p: f32::NAN,
+            ..valid
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::ops::{BitAnd, BitOr, BitXor, Not};
+
+use crate::prelude::*;
+
+/// A boolean tensor, like an attention or padding mask.
+///
+/// Masks compose with `&`, `|`, `^` and `!`, and are converted to floats once where they're used, with
+/// [`Mask::to_float`], [`Mask::to_additive`] or [`Mask::select`]. Edges don't carry dtypes in this tree, so each
+/// element is stored as a 0 or 1 float.
+#[derive(Clone, Copy)]
+pub struct Mask<S: Shape> {
+    tensor: GraphTensor<S>,
+    /// Whether this is a causal mask from [`Graph::causal_mask`], so [`Mask::to_additive`] can hint it
+    causal: bool,
+}
+
+impl<S: Shape> Mask<S> {
+    /// Treat a tensor of 0s and 1s as a mask, like a padding mask that is 1 for real tokens
+    pub fn from_float(tensor: GraphTensor<S>) -> Self {
+        Self {
+            tensor,
+            causal: false,
+        }
+    }
+
+    /// 1 where the mask is true and 0 where it's false
+    pub fn to_float(self) -> GraphTensor<S> {
+        self.tensor
+    }
+
+    /// 0 where the mask is true and negative infinity where it's false, to be added to attention scores. Causal masks
+    /// come out hinted with [`CompilerHint::CausalMask`], so attention kernels can apply them by position.
+    #[track_caller]
+    pub fn to_additive(self) -> GraphTensor<S> {
+        // ln(0) is -inf, and taking it avoids the NaNs multiplying infinity by a mask would give
+        let additive = self.tensor.ln();
+        if self.causal {
+            additive.hint(CompilerHint::CausalMask)
+        } else {
+            additive
+        }
+    }
+
+    /// Take `on_true` where the mask is true and `on_false` where it's false. Both sides need to be finite.
+    #[track_caller]
+    pub fn select(self, on_true: GraphTensor<S>, on_false: GraphTensor<S>) -> GraphTensor<S> {
+        on_false + self.tensor * (on_true - on_false)
+    }
+
+    pub fn expand<Dst: Shape, Ax: Axes>(self) -> Mask<Dst>
+    where
+        S: BroadcastShapeTo<Dst, Ax>,
+    {
+        Mask {
+            tensor: self.tensor.expand(),
+            causal: self.causal,
+        }
+    }
+
+    pub fn permute<Dst: Shape, Ax: Axes>(self) -> Mask<Dst>
+    where
+        S: PermuteShapeTo<Dst, Ax>,
+    {
+        Mask {
+            tensor: self.tensor.permute(),
+            causal: self.causal,
+        }
+    }
+
+    pub fn retrieve(self) -> Self {
+        Mask {
+            tensor: self.tensor.retrieve(),
+            causal: self.causal,
+        }
+    }
+
+    /// The values of a retrieved mask
+    pub fn data(&self) -> Vec<bool> {
+        self.tensor.data().into_iter().map(|v| v != 0.).collect()
+    }
+}
+
+impl<S: Shape> ToIdsMut for Mask<S> {
+    fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex> {
+        self.tensor.to_ids_mut()
+    }
+}
+
+impl<S: Shape> ToIds for Mask<S> {
+    fn to_ids(&self) -> Vec<NodeIndex> {
+        self.tensor.to_ids()
+    }
+}
+
+#[allow(clippy::suspicious_arithmetic_impl)]
+impl<S: Shape> BitAnd for Mask<S> {
+    type Output = Mask<S>;
+
+    #[track_caller]
+    fn bitand(self, rhs: Mask<S>) -> Self::Output {
+        Mask::from_float(self.tensor * rhs.tensor)
+    }
+}
+
+impl<S: Shape> BitOr for Mask<S> {
+    type Output = Mask<S>;
+
+    #[track_caller]
+    fn bitor(self, rhs: Mask<S>) -> Self::Output {
+        Mask::from_float(self.tensor.max(rhs.tensor))
+    }
+}
+
+impl<S: Shape> BitXor for Mask<S> {
+    type Output = Mask<S>;
+
+    #[track_caller]
+    fn bitxor(self, rhs: Mask<S>) -> Self::Output {
+        Mask::from_float(self.tensor.not_equals(rhs.tensor))
+    }
+}
+
+impl<S: Shape> Not for Mask<S> {
+    type Output = Mask<S>;
+
+    #[track_caller]
+    fn not(self) -> Self::Output {
+        Mask::from_float(-self.tensor + 1.)
+    }
+}
+
+// Comparisons producing masks
+impl<S: Shape> GraphTensor<S> {
+    #[track_caller]
+    pub fn is_lt(self, rhs: GraphTensor<S>) -> Mask<S> {
+        Mask::from_float(self.less_than(rhs))
+    }
+
+    #[track_caller]
+    pub fn is_le(self, rhs: GraphTensor<S>) -> Mask<S> {
+        Mask::from_float(self.less_than_equal(rhs))
+    }
+
+    #[track_caller]
+    pub fn is_gt(self, rhs: GraphTensor<S>) -> Mask<S> {
+        Mask::from_float(self.greater_than(rhs))
+    }
+
+    #[track_caller]
+    pub fn is_ge(self, rhs: GraphTensor<S>) -> Mask<S> {
+        Mask::from_float(self.greater_than_equal(rhs))
+    }
+
+    #[track_caller]
+    pub fn is_eq(self, rhs: GraphTensor<S>) -> Mask<S> {
+        Mask::from_float(self.equals(rhs))
+    }
+
+    #[track_caller]
+    pub fn is_ne(self, rhs: GraphTensor<S>) -> Mask<S> {
+        Mask::from_float(self.not_equals(rhs))
+    }
+}
+
+impl Graph {
+    /// A mask letting each query position see its own and earlier key positions
+    #[track_caller]
+    pub fn causal_mask<S: Dimension>(&mut self) -> Mask<(S, S)> {
+        Mask {
+            tensor: self.tril::<S>(0),
+            causal: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_mask_logic() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 1., 0., 0.]);
+        let b = cx.tensor::<R1<4>>().set(vec![1., 0., 1., 0.]);
+        let zero = cx.constant(0.).expand::<R1<4>, _>();
+        let (a, b) = (a.is_gt(zero), b.is_ne(zero));
+        let mut and = (a & b).retrieve();
+        let mut or = (a | b).retrieve();
+        let mut xor = (a ^ b).retrieve();
+        let mut nand = (!(a & b)).retrieve();
+        let x = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let y = cx.tensor::<R1<4>>().set(vec![-1., -2., -3., -4.]);
+        let mut selected = (a ^ b).select(x, y).retrieve();
+        let mut additive = or.to_additive().retrieve();
+        cx.compile(
+            <(GenericCompiler, CPUCompiler)>::default(),
+            (
+                &mut and,
+                &mut or,
+                &mut xor,
+                &mut nand,
+                &mut selected,
+                &mut additive,
+            ),
+        );
+        cx.execute();
+
+        assert_eq!(and.data(), [true, false, false, false]);
+        assert_eq!(or.data(), [true, true, true, false]);
+        assert_eq!(xor.data(), [false, true, true, false]);
+        assert_eq!(nand.data(), [false, true, true, true]);
+        assert_exact(&selected.data(), &[-1., 2., 3., -4.]);
+        assert_exact(&additive.data(), &[0., 0., 0., f32::NEG_INFINITY]);
+    }
+
+    #[test]
+    fn test_causal_hint() {
+        let mut cx = Graph::new();
+        let causal = cx.causal_mask::<LConst<3>>();
+        let padding = cx.tensor::<R1<3>>().set(vec![1., 1., 0.]);
+        let padded = causal & Mask::from_float(padding.expand::<_, LAxis<0>>());
+        // Only the unmodified causal mask can be applied by position
+        let (causal, padded) = (causal.to_additive(), padded.to_additive());
+        assert!(cx.has_hint(causal.id, &CompilerHint::CausalMask));
+        assert!(!cx.has_hint(padded.id, &CompilerHint::CausalMask));
+    }
+}
@@ -1,8 +1,16 @@
 // The high level interface implemented on GraphTensor. All of these ops get translated to primitive ops.
 pub mod binary;
+pub mod generation;
+pub use generation::*;
+pub mod mask;
+pub use mask::*;
 pub mod matmul;
 pub use matmul::*;
 pub mod movement;
 pub mod other;
 pub mod reduction;
+pub mod rows;
+pub use rows::*;
+pub mod sampling;
+pub use sampling::*;
 pub mod unary;
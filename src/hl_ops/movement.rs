@@ -4,9 +4,11 @@ use crate::{
         symbolic::{BigExpression, Expression},
         *,
     },
+    shape::tracker::crop_amount,
 };
 
 impl<S: Shape> GraphTensor<S> {
+    #[track_caller]
     pub fn permute<Dst: Shape, Ax: Axes>(mut self) -> GraphTensor<Dst>
     where
         S: PermuteShapeTo<Dst, Ax>,
@@ -16,6 +18,7 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    #[track_caller]
     pub fn expand<Dst: Shape, Ax: Axes>(mut self) -> GraphTensor<Dst>
     where
         S: BroadcastShapeTo<Dst, Ax>,
@@ -30,6 +33,7 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    #[track_caller]
     pub fn reshape<N: Shape>(mut self) -> GraphTensor<N> {
         if !self.shape.is_contiguous() {
             // Insert contiguous call
@@ -44,6 +48,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Dynamically reshape with annotations for the shape tracker
+    #[track_caller]
     pub fn dyn_reshape<N: Shape>(mut self, shape: Vec<Expression>) -> GraphTensor<N> {
         if !self.shape.indexes.iter().enumerate().all(|(a, b)| a == *b) {
             // Insert contiguous call
@@ -53,6 +58,7 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, ShapeTracker::new(&shape), self.graph_ref)
     }
 
+    #[track_caller]
     pub fn realize<Dst: Shape<Concrete = <<S as HasShape>::Shape as Shape>::Concrete>>(
         self,
     ) -> GraphTensor<Dst>
@@ -62,6 +68,7 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    #[track_caller]
     pub fn contiguous(self) -> GraphTensor<S> {
         if self.shape.is_contiguous() && !self.shape.is_sliced() && !self.shape.is_padded() {
             return self;
@@ -75,6 +82,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Take a slice of the original tensor. Any dimension with bounds becomes a dynamic dimension
+    #[track_caller]
     pub fn slice<Slice: SliceOfShape<S>>(
         mut self,
         slice: Slice,
@@ -93,6 +101,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Cut out 'size' elements every 'spacing' elements in the last dimension. 'size' must be smaller than the last dimension
+    #[track_caller]
     pub fn excise<Dst: Shape>(mut self, spacing: usize, size: usize) -> GraphTensor<Dst> {
         let n_dims = self.shape.len();
         // Pad out to a multiple of spacing + size
@@ -120,6 +129,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Pool elements along the last dimension, pools are exposed as a new dimension
+    #[track_caller]
     pub fn pool_last_dim<Dst: Shape>(
         mut self,
         kernel: Expression,
@@ -165,14 +175,44 @@ impl<S: Shape> GraphTensor<S> {
         }
     }
 
+    /// Pad the start and end of each dimension with zeros. Negative padding crops the dimension instead, as long as
+    /// it's known when building the graph. Padding that depends on dynamic dimensions panics if it resolves to a
+    /// negative value, so clamp it with `.max(0)` if it can go negative.
+    #[track_caller]
     pub fn pad<Dst: Shape, Start: Into<Expression> + Copy, End: Into<Expression> + Copy>(
         mut self,
         ranges: &[(Start, End)],
     ) -> GraphTensor<Dst> {
-        let ranges = ranges
+        let mut ranges = ranges
             .iter()
             .map(|i| (i.0.into(), i.1.into()))
             .collect::<Vec<_>>();
+        // Negative padding crops, which is done as a slice before any padding is added
+        let crops = ranges
+            .iter()
+            .map(|(s, e)| {
+                (
+                    Expression::from(-(crop_amount(*s) as i32)),
+                    Expression::from(-(crop_amount(*e) as i32)),
+                )
+            })
+            .collect::<Vec<_>>();
+        if crops.iter().any(|c| *c != (0.into(), 0.into())) {
+            if crops.iter().zip(self.shape.indexes).any(|(crop, ind)| {
+                *crop != (0.into(), 0.into()) && self.shape.padding[ind] != (0.into(), 0.into())
+            }) {
+                self = self.contiguous();
+            }
+            self.shape.pad(&crops);
+            for ((s, e), (crop_s, crop_e)) in ranges.iter_mut().zip(&crops) {
+                if *crop_s != 0.into() {
+                    *s = 0.into();
+                }
+                if *crop_e != 0.into() {
+                    *e = 0.into();
+                }
+            }
+        }
         // This exists because currently padding and slicing on the same dimension (even on opposite sides) is unsupported
         if ranges.iter().zip(self.shape.indexes).any(|(range, ind)| {
             (range.0 != 0.into() || range.1 != 0.into())
@@ -185,6 +225,7 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    #[track_caller]
     pub fn concat_along<Dst: Shape, Ax: Axes<Array = [usize; 1]>, Rhs: Shape>(
         self,
         rhs: GraphTensor<Rhs>,
@@ -315,6 +356,43 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_negative_pad() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<3, 4>>()
+            .set(vec![0., 1., 2., 3., 4., 5., 6., 7., 8., 9., 10., 11.]);
+        // Crop the first row and the first and last columns, then pad a row on the end
+        let b = a
+            .pad::<R2<3, 2>, i32, i32>(&[(-1, 1), (-1, -1)])
+            .contiguous()
+            .retrieve();
+        // Cropping a padded dimension
+        let c = a
+            .pad::<R2<3, 6>, i32, i32>(&[(0, 0), (2, 0)])
+            .pad::<R2<3, 3>, i32, i32>(&[(0, 0), (-1, -2)])
+            .retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[5., 6., 9., 10., 0., 0.]);
+        assert_exact(&c.data(), &[0., 0., 1., 0., 4., 5., 0., 8., 9.]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Padding on axis 1 resolved to (0, -2)")]
+    fn test_negative_dyn_pad() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<(LConst<2>, Dyn<'a'>)>()
+            .set_dyn(vec![1., 2., 3., 4., 5., 6.], &[2, 3]);
+        let b: GraphTensor<(LConst<2>, LConst<1>)> = a
+            .pad(&[(0, 0.into()), (0, Expression::from(1) - 'a')])
+            .contiguous()
+            .retrieve();
+        cx.execute();
+        b.data();
+    }
+
     #[test]
     fn test_slice_2d() {
         let mut cx = Graph::new();
@@ -5,6 +5,7 @@ use crate::{
 
 impl<S: Shape> GraphTensor<S> {
     /// Cumulative sum last dimension
+    #[track_caller]
     pub fn cumsum_last_dim(mut self) -> Self {
         let axis = self.shape.len() - 1;
         if !self.shape.is_contiguous() {
@@ -20,21 +21,106 @@ impl<S: Shape> GraphTensor<S> {
         // Sum Reduce along new dimension
         let final_id = self
             .graph()
-            .add_op(op::SumReduce(axis))
+            .add_op(op::SumReduce(axis, op::Summation::Naive))
             .input(pooled.id, 0, pooled.shape)
             .finish();
         pooled.shape.remove_dim(axis + 1);
         GraphTensor::from_id(final_id, pooled.shape, self.graph_ref)
     }
 
+    /// Cumulative sum along an axis, in a single scan instead of the pooled reduction `cumsum_last_dim` builds
+    #[track_caller]
+    pub fn cumsum(self, axis: usize) -> Self {
+        assert!(
+            axis < self.shape.len(),
+            "Axis {axis} is out of range for a tensor of rank {}",
+            self.shape.len()
+        );
+        let new_id = self
+            .graph()
+            .add_op(op::CumSum(axis))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
+
     /// Cumulative product last dimension
+    #[track_caller]
     pub fn cumprod_last_dim(self) -> Self {
         self.ln().cumsum_last_dim().exp()
     }
+
+    /// Sort the last dimension. Equal elements keep their original order.
+    #[track_caller]
+    pub fn sort(self, descending: bool) -> Self {
+        let new_id = self
+            .graph()
+            .add_op(op::Sort {
+                descending,
+                indices: false,
+            })
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
+
+    /// Get the indexes that would sort the last dimension. Equal elements keep their original order.
+    #[track_caller]
+    pub fn argsort(self, descending: bool) -> Self {
+        let new_id = self
+            .graph()
+            .add_op(op::Sort {
+                descending,
+                indices: true,
+            })
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
+
+    /// Get the `k` largest elements along an axis in descending order, and their indexes. Equal elements keep their
+    /// original order. `k` can be a dynamic dimension, like `'k'`, to pick it when executing.
+    #[track_caller]
+    pub fn topk<Dst: Shape>(
+        self,
+        k: impl Into<Expression>,
+        axis: usize,
+    ) -> (GraphTensor<Dst>, GraphTensor<Dst>) {
+        assert!(
+            axis < self.shape.len(),
+            "Axis {axis} is out of range for a tensor of rank {}",
+            self.shape.len()
+        );
+        let k = k.into();
+        if let (Some(k), Some(n)) = (k.to_usize(), self.shape.shape()[axis].to_usize()) {
+            assert!(k <= n, "Top-k of {k} is larger than the dimension of {n}");
+        }
+        let mut shape = self.shape.contiguous();
+        shape.remove_dim(axis);
+        shape.add_dim(axis, k);
+        // The new dim goes at the end of the dims, so lay them out in order to match the op's contiguous output
+        let shape = shape.contiguous();
+        let dyn_map = &self.graph().dyn_map as *const _;
+        let [values, indices] = [false, true].map(|indices| {
+            let new_id = self
+                .graph()
+                .add_op(op::TopK {
+                    k,
+                    dim: axis,
+                    indices,
+                    dyn_map,
+                })
+                .input(self.id, 0, self.shape)
+                .finish();
+            GraphTensor::from_id(new_id, shape, self.graph_ref)
+        });
+        (values, indices)
+    }
 }
 
 impl Graph {
     /// A scalar constant
+    #[track_caller]
     pub fn constant(&mut self, i: f32) -> GraphTensor<R0> {
         GraphTensor::from_id(
             self.add_op(Constant(ConstantValue::Float(i), &self.dyn_map))
@@ -45,6 +131,7 @@ impl Graph {
     }
 
     /// A scalar constant evaluated from an expression at runtime
+    #[track_caller]
     pub fn constant_expr<E: Into<BigExpression>>(&mut self, expr: E) -> GraphTensor<R0> {
         GraphTensor::from_id(
             self.add_op(Constant(
@@ -58,6 +145,7 @@ impl Graph {
     }
 
     /// ARange from 0 to N
+    #[track_caller]
     pub fn arange<N: Dimension>(&mut self) -> GraphTensor<(N,)> {
         if N::const_size()
             .to_usize()
@@ -74,6 +162,7 @@ impl Graph {
     /// Lower left-hand triangle of 1s. Currently required to be square
     ///
     /// Same API as https://pytorch.org/docs/stable/generated/torch.tril
+    #[track_caller]
     pub fn tril<S: Dimension>(&mut self, diagonal: i32) -> GraphTensor<(S, S)> {
         let horizontal = self.arange::<S>().expand::<(S, S), Axis<0>>();
         let vertical = self.arange::<S>().expand::<(S, S), Axis<1>>();
@@ -84,6 +173,7 @@ impl Graph {
     /// Upper right-hand triangle of 1s
     ///
     /// Same API as https://pytorch.org/docs/stable/generated/torch.triu
+    #[track_caller]
     pub fn triu<S: Dimension>(&mut self, diagonal: i32) -> GraphTensor<(S, S)> {
         let horizontal = self.arange::<S>().expand::<(S, S), Axis<0>>();
         let vertical = self.arange::<S>().expand::<(S, S), Axis<1>>();
@@ -94,6 +184,7 @@ impl Graph {
 
 impl<S: Dimension, const DIM: usize> GraphTensor<(S, Const<DIM>)> {
     /// Gather a batch of vectors from a matrix
+    #[track_caller]
     pub fn gather<B: Dimension>(self, indexes: GraphTensor<(B,)>) -> GraphTensor<(B, Const<DIM>)> {
         let one_hot = indexes
             .graph()
@@ -104,6 +195,27 @@ impl<S: Dimension, const DIM: usize> GraphTensor<(S, Const<DIM>)> {
     }
 }
 
+impl<I: Dimension> GraphTensor<(I,)> {
+    /// Sum these values into N bins by their indexes. Indexes outside of the bins are skipped.
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.Tensor.scatter_add_ on a tensor of zeros
+    #[track_caller]
+    pub fn scatter_add<N: Dimension>(self, indexes: GraphTensor<(I,)>) -> GraphTensor<(N,)> {
+        let (values, indexes) = (self.contiguous(), indexes.contiguous());
+        let dyn_map = &self.graph().dyn_map as *const _;
+        let new_id = self
+            .graph()
+            .add_op(op::ScatterAdd {
+                size: N::const_size(),
+                dyn_map,
+            })
+            .input(indexes.id, 0, indexes.shape)
+            .input(values.id, 0, values.shape)
+            .finish();
+        GraphTensor::from_id(new_id, <(N,)>::to_tracker(), self.graph_ref)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     crate::test_imports!();
@@ -128,6 +240,170 @@ mod tests {
         assert_close(&b.data(), &[3., 6., 30.]);
     }
 
+    #[test]
+    fn test_sort() {
+        let mut cx = Graph::new();
+
+        let a = cx
+            .tensor::<R2<2, 5>>()
+            .set([[3., 1., 2., 1., 5.], [0., -1., 0., 4., -1.]]);
+        let asc = a.sort(false).retrieve();
+        let desc = a.sort(true).retrieve();
+        let asc_idx = a.argsort(false).retrieve();
+        let desc_idx = a.argsort(true).retrieve();
+        cx.execute();
+
+        assert_exact(&asc.data(), &[1., 1., 2., 3., 5., -1., -1., 0., 0., 4.]);
+        assert_exact(&desc.data(), &[5., 3., 2., 1., 1., 4., 0., 0., -1., -1.]);
+        // Ties keep their original order in both directions
+        assert_exact(&asc_idx.data(), &[1., 3., 2., 0., 4., 1., 4., 0., 2., 3.]);
+        assert_exact(&desc_idx.data(), &[4., 0., 2., 1., 3., 3., 0., 2., 1., 4.]);
+    }
+
+    #[test]
+    fn test_sort_random() {
+        let mut cx = Graph::new();
+        // Quantize to get lots of ties
+        let data = random_vec(3 * 37)
+            .into_iter()
+            .map(|i| (i * 8.).round())
+            .collect::<Vec<_>>();
+        let a = cx.tensor::<R2<3, 37>>().set(data.clone());
+        let b = a.permute::<R2<37, 3>, _>().sort(true).retrieve();
+        let c = a.argsort(false).retrieve();
+        cx.execute();
+
+        let (mut sorted, mut indexes) = (vec![], vec![]);
+        for row in data.chunks(37) {
+            let mut perm = (0..37).collect::<Vec<_>>();
+            perm.sort_by(|a, b| row[*a].partial_cmp(&row[*b]).unwrap());
+            indexes.extend(perm.into_iter().map(|i| i as f32));
+        }
+        for col in 0..37 {
+            let mut column = (0..3).map(|r| data[r * 37 + col]).collect::<Vec<_>>();
+            column.sort_by(|a, b| b.partial_cmp(a).unwrap());
+            sorted.extend(column);
+        }
+        assert_exact(&b.data(), &sorted);
+        assert_exact(&c.data(), &indexes);
+    }
+
+    #[test]
+    fn test_topk() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<2, 5>>()
+            .set([[3., 1., 3., 5., 1.], [0., 1., 0., 4., -1.]]);
+        let (values, indices) = a.topk::<R2<2, 3>>(3, 1);
+        let (values, indices) = (values.retrieve(), indices.retrieve());
+        // A k of the whole row sorts it
+        let (all_values, all_indices) = a.topk::<R2<2, 5>>(5, 1);
+        let (all_values, all_indices) = (all_values.retrieve(), all_indices.retrieve());
+        // Down the columns, with k set when executing
+        let (col_values, col_indices) = a.topk::<(Dyn<'k'>, LConst<5>)>('k', 0);
+        let (col_values, col_indices) = (col_values.retrieve(), col_indices.retrieve());
+        cx.set_dyn_dim('k', 1);
+        cx.execute();
+
+        // Duplicates keep their original order
+        assert_exact(&values.data(), &[5., 3., 3., 4., 1., 0.]);
+        assert_exact(&indices.data(), &[3., 0., 2., 3., 1., 0.]);
+        assert_exact(
+            &all_values.data(),
+            &[5., 3., 3., 1., 1., 4., 1., 0., 0., -1.],
+        );
+        assert_exact(
+            &all_indices.data(),
+            &[3., 0., 2., 1., 4., 3., 1., 0., 2., 4.],
+        );
+        assert_exact(&col_values.data(), &[3., 1., 3., 5., 1.]);
+        assert_exact(&col_indices.data(), &[0.; 5]);
+
+        // The same graph with a different k
+        col_values.drop();
+        col_indices.drop();
+        cx.set_dyn_dim('k', 2);
+        cx.execute();
+        assert_exact(
+            &col_values.data(),
+            &[3., 1., 3., 5., 1., 0., 1., 0., 4., -1.],
+        );
+        assert_exact(
+            &col_indices.data(),
+            &[0., 0., 0., 0., 0., 1., 1., 1., 1., 1.],
+        );
+    }
+
+    #[test]
+    fn test_scatter_add() {
+        let mut cx = Graph::new();
+        let values = cx.tensor::<R1<6>>().set(vec![1., 2., 3., 4., 5., 6.]);
+        // Out of range indexes are skipped
+        let indexes = cx.tensor::<R1<6>>().set(vec![2., 0., 2., -1., 4., 3.]);
+        let a = values.scatter_add::<LConst<4>>(indexes).retrieve();
+        // Into a dynamic number of bins, from sliced views
+        let b = values
+            .slice((..Expression::from(3),))
+            .scatter_add::<Dyn<'n'>>(indexes.slice((Expression::from(3)..,)))
+            .retrieve();
+        cx.set_dyn_dim('n', 5);
+        cx.execute();
+
+        assert_exact(&a.data(), &[2., 0., 4., 6.]);
+        assert_exact(&b.data(), &[0., 0., 0., 3., 2.]);
+    }
+
+    #[test]
+    fn test_cumsum() {
+        let mut cx = Graph::new();
+        let data = random_vec(3 * 4 * 5);
+        let a = cx.tensor::<R3<3, 4, 5>>().set(data.clone());
+        let sums = (0..3)
+            .map(|axis| a.cumsum(axis).retrieve())
+            .collect::<Vec<_>>();
+        // Permuted, so the scan follows the index expression
+        let permuted = a.permute::<_, LAxes3<2, 0, 1>>().cumsum(0).retrieve();
+        let reduced = a.sum_reduce::<_, LAxis<2>>().cumsum(1).retrieve();
+        cx.execute();
+
+        for (axis, sum) in sums.iter().enumerate() {
+            let shape = [3, 4, 5];
+            let back = shape[axis + 1..].iter().product::<usize>();
+            let mut expected = data.clone();
+            for i in 0..expected.len() {
+                if (i / back) % shape[axis] != 0 {
+                    expected[i] += expected[i - back];
+                }
+            }
+            assert_close(&sum.data(), &expected);
+        }
+        let mut expected = vec![0.; 60];
+        for k in 0..5 {
+            for i in 0..3 {
+                for j in 0..4 {
+                    let value = data[i * 20 + j * 5 + k];
+                    expected[k * 12 + i * 4 + j] = value
+                        + if k > 0 {
+                            expected[(k - 1) * 12 + i * 4 + j]
+                        } else {
+                            0.
+                        };
+                }
+            }
+        }
+        assert_close(&permuted.data(), &expected);
+        let mut expected = data
+            .chunks(5)
+            .map(|c| c.iter().sum::<f32>())
+            .collect::<Vec<_>>();
+        for i in 1..expected.len() {
+            if i % 4 != 0 {
+                expected[i] += expected[i - 1];
+            }
+        }
+        assert_close(&reduced.data(), &expected);
+    }
+
     #[test]
     fn test_dyn_arange() {
         let mut cx = Graph::new();
@@ -1,11 +1,16 @@
 use itertools::Itertools;
+use petgraph::stable_graph::NodeIndex;
 
 use crate::{
     op::{self},
-    prelude::{symbolic::Expression, *},
+    prelude::{
+        symbolic::{BigExpression, Expression},
+        *,
+    },
 };
 
 impl<S: Shape> GraphTensor<S> {
+    #[track_caller]
     pub fn sum_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
     where
         S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
@@ -16,7 +21,7 @@ impl<S: Shape> GraphTensor<S> {
         for dim in Ax::as_array().into_iter().collect_vec().into_iter().rev() {
             new_id = self
                 .graph()
-                .add_op(op::SumReduce(dim))
+                .add_op(op::SumReduce(dim, op::Summation::Naive))
                 .input(new_id, 0, shape)
                 .finish();
             // Reduce shape
@@ -25,6 +30,7 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(new_id, shape, self.graph_ref)
     }
 
+    #[track_caller]
     pub fn max_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
     where
         S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
@@ -44,46 +50,92 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(new_id, shape, self.graph_ref)
     }
 
+    #[track_caller]
     pub fn mean_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
     where
         S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
     {
         let mut shape = self.shape;
         let mut node_id = self.id;
+        let mut reduced_size = BigExpression::from(1);
         for dim in Ax::as_array().into_iter().collect_vec().into_iter().rev() {
             // Sum reduce
             node_id = self
                 .graph()
-                .add_op(op::SumReduce(dim))
+                .add_op(op::SumReduce(dim, op::Summation::Naive))
                 .input(node_id, 0, shape)
                 .finish();
-
-            // Divide by size of dimension
-            let div_tensor = self.graph().constant_expr(shape.remove_dim(dim)).id;
-            let mul_tensor = self
-                .graph()
-                .add_op(op::Recip)
-                .input(div_tensor, 0, ShapeTracker::new(&[]))
-                .finish();
-            node_id = self
-                .graph()
-                .add_op(op::Mul)
-                .input(node_id, 0, shape)
-                .input(
-                    mul_tensor,
-                    0,
-                    ShapeTracker::fake(
-                        &shape
-                            .shape()
-                            .into_iter()
-                            .map(Expression::from)
-                            .collect::<Vec<_>>(),
-                    ),
-                )
-                .finish();
+            reduced_size = reduced_size * shape.remove_dim(dim);
         }
+
+        // Divide by the number of reduced elements once, at the end
+        let div_tensor = self.graph().constant_expr(reduced_size).id;
+        let mul_tensor = self
+            .graph()
+            .add_op(op::Recip)
+            .input(div_tensor, 0, ShapeTracker::new(&[]))
+            .finish();
+        node_id = self
+            .graph()
+            .add_op(op::Mul)
+            .input(node_id, 0, shape)
+            .input(
+                mul_tensor,
+                0,
+                ShapeTracker::fake(
+                    &shape
+                        .shape()
+                        .into_iter()
+                        .map(Expression::from)
+                        .collect::<Vec<_>>(),
+                ),
+            )
+            .finish();
         GraphTensor::from_id(node_id, shape, self.graph_ref)
     }
+
+    /// Flatten into a single contiguous dimension, so reducing everything is one reduce op instead of one per axis
+    #[track_caller]
+    fn flat_shape(self) -> (NodeIndex, ShapeTracker) {
+        let flat = self.contiguous();
+        let n_elements = flat
+            .shape
+            .dims
+            .into_iter()
+            .fold(Expression::from(1), |acc, d| acc * d);
+        (flat.id, ShapeTracker::new(&[n_elements]))
+    }
+
+    /// Sum every element into a scalar
+    #[track_caller]
+    pub fn sum_all(self) -> GraphTensor<R0> {
+        let (id, shape) = self.flat_shape();
+        let new_id = self
+            .graph()
+            .add_op(op::SumReduce(0, op::Summation::Naive))
+            .input(id, 0, shape)
+            .finish();
+        GraphTensor::from_id(new_id, ShapeTracker::new(&[]), self.graph_ref)
+    }
+
+    /// Max of every element as a scalar
+    #[track_caller]
+    pub fn max_all(self) -> GraphTensor<R0> {
+        let (id, shape) = self.flat_shape();
+        let new_id = self
+            .graph()
+            .add_op(op::MaxReduce(0))
+            .input(id, 0, shape)
+            .finish();
+        GraphTensor::from_id(new_id, ShapeTracker::new(&[]), self.graph_ref)
+    }
+
+    /// Mean of every element as a scalar
+    #[track_caller]
+    pub fn mean_all(self) -> GraphTensor<R0> {
+        let n_elements = self.graph().constant_expr(self.shape.n_elements());
+        self.sum_all() * n_elements.recip()
+    }
 }
 
 #[cfg(test)]
@@ -143,4 +195,65 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_full_reductions() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(24);
+        let a = cx.tensor::<R3<2, 3, 4>>().set(a_data.clone());
+        let sum = a.sum_all().retrieve();
+        let max = a.permute::<_, LAxes3<2, 0, 1>>().max_all().retrieve();
+        let mean = a.mean_all().retrieve();
+        // Each full reduction is a single reduce op
+        assert_eq!(
+            cx.graph
+                .node_indices()
+                .filter(|n| cx.graph[*n].as_any().is::<crate::op::SumReduce>())
+                .count(),
+            2
+        );
+
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>, DConst::<4>));
+        assert_close(&[sum.item()], &[d_a.clone().sum::<Rank0, _>().array()]);
+        assert_close(&[max.item()], &[d_a.clone().max::<Rank0, _>().array()]);
+        assert_close(&[mean.item()], &[d_a.mean::<Rank0, _>().array()]);
+    }
+
+    #[test]
+    fn test_compensated_sum() {
+        // Small values get rounded away once the running sum is large
+        let a_data = (0..1_000_000)
+            .map(|i| if i % 2 == 0 { 1.0 } else { 1e-4 })
+            .collect::<Vec<f32>>();
+        let reference = a_data.iter().map(|v| *v as f64).sum::<f64>();
+        let run = |compensated| {
+            let mut cx = Graph::new();
+            let a = cx.tensor::<R1<1_000_000>>().set(a_data.clone());
+            let mut sum = a.sum_reduce::<R0, _>().retrieve();
+            let mut mean = a.mean_reduce::<R0, _>().retrieve();
+            if compensated {
+                cx.compile(CompensatedSummation, (&mut sum, &mut mean));
+            }
+            cx.execute();
+            (sum.item() as f64, mean.item() as f64)
+        };
+
+        let (naive_sum, _) = run(false);
+        let (sum, mean) = run(true);
+        assert!((naive_sum - reference).abs() > 10.0);
+        assert!((sum - reference).abs() < 0.1);
+        assert!((mean - reference / 1_000_000.).abs() < 1e-6);
+    }
+
+    #[test]
+    #[should_panic(expected = "Call .retrieve() on it before executing the graph")]
+    fn test_item_before_execute() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let sum = a.sum_all().retrieve();
+        sum.item();
+    }
 }
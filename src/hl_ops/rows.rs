@@ -0,0 +1,228 @@
+use std::fmt::Display;
+
+use crate::prelude::*;
+
+/// Why [`Graph::tensor_from_padded_rows`] couldn't build a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowsError {
+    /// The batch dimension is static, and holds a different number of rows
+    WrongRowCount { expected: usize, got: usize },
+    /// A row's length differs from the others, and no padding value was given
+    Ragged {
+        row: usize,
+        len: usize,
+        expected: usize,
+    },
+    /// A row is longer than the static row dimension
+    TooLong { row: usize, len: usize, size: usize },
+}
+
+impl Display for RowsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RowsError::WrongRowCount { expected, got } => {
+                write!(f, "Expected {expected} rows, got {got}")
+            }
+            RowsError::Ragged { row, len, expected } => write!(
+                f,
+                "Row {row} has {len} values while others have {expected}, and no padding value was given"
+            ),
+            RowsError::TooLong { row, len, size } => {
+                write!(f, "Row {row} has {len} values, more than the {size} a row holds")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RowsError {}
+
+/// A batch of padded rows along with the length of each row
+type PaddedRows<B, L> = (GraphTensor<(B, L)>, GraphTensor<(B,)>);
+
+impl Graph {
+    /// A matrix with one row per item, like a batch of feature vectors. A dynamic leading dimension is set to the
+    /// number of rows, and a static one has to match it.
+    #[track_caller]
+    pub fn tensor_from_rows<N: Dimension, const D: usize>(
+        &mut self,
+        rows: impl IntoIterator<Item = [f32; D]>,
+    ) -> GraphTensor<(N, Const<D>)> {
+        let rows = rows.into_iter().collect::<Vec<_>>();
+        if let Some(n) = N::const_size().to_usize() {
+            assert_eq!(rows.len(), n, "Number of rows doesn't match the shape!");
+        }
+        let data = rows.iter().flatten().copied().collect::<Vec<_>>();
+        self.tensor().set_dyn(data, &[rows.len(), D])
+    }
+
+    /// A batch of variable length rows, like tokenized sequences, along with the length of each row. Rows are padded
+    /// with `padding` to the longest one, or to the row dimension if it's static. Without a padding value, every row
+    /// needs the same length. Dynamic dimensions are set to the number of rows and the padded row length.
+    ///
+    /// The lengths turn into a padding mask with [`GraphTensor::length_mask`].
+    #[track_caller]
+    pub fn tensor_from_padded_rows<B: Dimension, L: Dimension>(
+        &mut self,
+        rows: Vec<Vec<f32>>,
+        padding: Option<f32>,
+    ) -> Result<PaddedRows<B, L>, RowsError> {
+        if let Some(n) = B::const_size().to_usize() {
+            if rows.len() != n {
+                return Err(RowsError::WrongRowCount {
+                    expected: n,
+                    got: rows.len(),
+                });
+            }
+        }
+        let longest = rows.iter().map(Vec::len).max().unwrap_or_default();
+        let row_len = L::const_size().to_usize().unwrap_or(longest);
+        for (row, values) in rows.iter().enumerate() {
+            if values.len() > row_len {
+                return Err(RowsError::TooLong {
+                    row,
+                    len: values.len(),
+                    size: row_len,
+                });
+            }
+            if padding.is_none() && values.len() != row_len {
+                return Err(RowsError::Ragged {
+                    row,
+                    len: values.len(),
+                    expected: row_len,
+                });
+            }
+        }
+
+        let mut data = Vec::with_capacity(rows.len() * row_len);
+        for values in &rows {
+            data.extend_from_slice(values);
+            data.resize(
+                data.len() + row_len - values.len(),
+                padding.unwrap_or_default(),
+            );
+        }
+        let lengths = rows.iter().map(|r| r.len() as f32).collect::<Vec<_>>();
+        Ok((
+            self.tensor().set_dyn(data, &[rows.len(), row_len]),
+            self.tensor().set_dyn(lengths, &[rows.len()]),
+        ))
+    }
+}
+
+impl<B: Dimension> GraphTensor<(B,)> {
+    /// A mask that's true for the first `length` positions of each row, from a tensor of row lengths
+    #[track_caller]
+    pub fn length_mask<L: Dimension>(self) -> Mask<(B, L)> {
+        self.graph()
+            .arange::<L>()
+            .expand::<(B, L), Axis<0>>()
+            .is_lt(self.expand::<(B, L), Axis<1>>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    use super::RowsError;
+
+    #[test]
+    fn test_rows_exact_length() {
+        let mut cx = Graph::new();
+        let x = cx.tensor_from_rows::<Dyn<'B'>, 3>(vec![[1., 2., 3.], [4., 5., 6.]]);
+        let (batch, lengths) = cx
+            .tensor_from_padded_rows::<LConst<2>, Dyn<'L'>>(vec![vec![1., 2.], vec![3., 4.]], None)
+            .unwrap();
+        let mut out = (x * 2.).retrieve();
+        let mut batch = batch.retrieve();
+        let mut lengths = lengths.retrieve();
+        cx.compile(
+            <(GenericCompiler, CPUCompiler)>::default(),
+            (&mut out, &mut batch, &mut lengths),
+        );
+        cx.execute();
+
+        assert_eq!(cx.dyn_map[&'B'], 2);
+        assert_eq!(cx.dyn_map[&'L'], 2);
+        assert_exact(&out.data(), &[2., 4., 6., 8., 10., 12.]);
+        assert_exact(&batch.data(), &[1., 2., 3., 4.]);
+        assert_exact(&lengths.data(), &[2., 2.]);
+    }
+
+    #[test]
+    fn test_ragged_rows() {
+        let rows = vec![vec![1., 2., 3.], vec![4.], vec![5., 6.]];
+        let mut cx = Graph::new();
+        let (batch, lengths) = cx
+            .tensor_from_padded_rows::<Dyn<'B'>, Dyn<'L'>>(rows.clone(), Some(-1.))
+            .unwrap();
+        let mut batch = batch.retrieve();
+        let mut lengths = lengths.retrieve();
+        let mut mask = lengths.length_mask::<Dyn<'L'>>().retrieve();
+        cx.compile(
+            <(GenericCompiler, CPUCompiler)>::default(),
+            (&mut batch, &mut lengths, &mut mask),
+        );
+        cx.execute();
+
+        assert_eq!(cx.dyn_map[&'L'], 3);
+        assert_exact(&batch.data(), &[1., 2., 3., 4., -1., -1., 5., 6., -1.]);
+        assert_exact(&lengths.data(), &[3., 1., 2.]);
+        assert_eq!(
+            mask.data(),
+            [true, true, true, true, false, false, true, true, false]
+        );
+
+        assert_eq!(
+            cx.tensor_from_padded_rows::<Dyn<'B'>, Dyn<'L'>>(rows.clone(), None)
+                .err(),
+            Some(RowsError::Ragged {
+                row: 1,
+                len: 1,
+                expected: 3
+            })
+        );
+        assert_eq!(
+            cx.tensor_from_padded_rows::<Dyn<'B'>, LConst<2>>(rows.clone(), Some(0.))
+                .err(),
+            Some(RowsError::TooLong {
+                row: 0,
+                len: 3,
+                size: 2
+            })
+        );
+        assert_eq!(
+            cx.tensor_from_padded_rows::<LConst<2>, Dyn<'L'>>(rows, Some(0.))
+                .err(),
+            Some(RowsError::WrongRowCount {
+                expected: 2,
+                got: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_empty_rows() {
+        let mut cx = Graph::new();
+        let mut x = cx
+            .tensor_from_rows::<Dyn<'B'>, 4>(std::iter::empty())
+            .retrieve();
+        let (batch, lengths) = cx
+            .tensor_from_padded_rows::<Dyn<'N'>, Dyn<'L'>>(vec![], Some(0.))
+            .unwrap();
+        let mut batch = batch.retrieve();
+        let mut lengths = lengths.retrieve();
+        cx.compile(
+            <(GenericCompiler, CPUCompiler)>::default(),
+            (&mut x, &mut batch, &mut lengths),
+        );
+        cx.execute();
+
+        assert_eq!(cx.dyn_map[&'B'], 0);
+        assert_eq!(cx.dyn_map[&'N'], 0);
+        assert_eq!(cx.dyn_map[&'L'], 0);
+        assert!(x.data().is_empty());
+        assert!(batch.data().is_empty());
+        assert!(lengths.data().is_empty());
+    }
+}
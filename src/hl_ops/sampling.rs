@@ -0,0 +1,554 @@
+use std::{cell::RefCell, rc::Rc};
+
+use rand::rngs::StdRng;
+
+use crate::{op, prelude::*};
+
+impl<S: Shape> GraphTensor<S> {
+    /// Sample a token id from each row of logits along the last axis, with the temperature, top-k and nucleus (top-p)
+    /// settings of `params`.
+    ///
+    /// Each row takes its own uniform draw from `rng` on every execution. Token ids are output as i32, so read them
+    /// with [`GraphTensor::int_data`]. Panics if the settings are out of range.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// use rand::{rngs::StdRng, SeedableRng};
+    /// let mut cx = Graph::new();
+    /// let logits = cx.tensor::<R2<2, 3>>().set(vec![0., 9., 0., 9., 0., 0.]);
+    /// let params = SamplingParams {
+    ///     top_k: Some(1),
+    ///     ..Default::default()
+    /// };
+    /// let ids = logits.sample(params, StdRng::seed_from_u64(0)).retrieve();
+    /// cx.execute();
+    /// assert_eq!(ids.int_data(), vec![1, 0]);
+    /// ```
+    #[track_caller]
+    pub fn sample(
+        self,
+        params: SamplingParams,
+        rng: StdRng,
+    ) -> GraphTensor<<S as ReduceShape<<S as Shape>::LastAxis>>::Reduced> {
+        if let Err(e) = params.validate() {
+            panic!("{e}");
+        }
+        let logits = self.contiguous();
+        let new_id = self
+            .graph()
+            .add_op(op::Sample {
+                temperature: params.temperature,
+                top_p: params.top_p,
+                top_k: params.top_k,
+                rng: Rc::new(RefCell::new(rng)),
+            })
+            .input(logits.id, 0, logits.shape)
+            .finish();
+        self.graph().int_tensors.insert(new_id);
+        let mut shape = logits.shape;
+        shape.remove_dim(shape.len() - 1);
+        GraphTensor::from_id(new_id, shape.contiguous(), self.graph_ref)
+    }
+
+    /// Nucleus (top-p) sampling of token ids from logits along the last axis.
+    ///
+    /// Tokens are sorted by probability and the smallest set whose cumulative probability reaches `p` is kept.
+    /// The most likely token is always kept, even if its probability alone exceeds `p`, matching HF's `TopPLogitsWarper`.
+    /// See [`GraphTensor::sample`].
+    #[track_caller]
+    pub fn top_p_sample(
+        self,
+        p: f32,
+        temperature: f32,
+        rng: StdRng,
+    ) -> GraphTensor<<S as ReduceShape<<S as Shape>::LastAxis>>::Reduced> {
+        let params = SamplingParams {
+            temperature,
+            top_p: p,
+            top_k: None,
+        };
+        self.sample(params, rng)
+    }
+}
+
+impl<H: Dimension> GraphTensor<(H,)> {
+    /// Count the occurrences of each token id, giving a histogram over a vocabulary of size V. Ids outside of the
+    /// vocabulary aren't counted.
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.bincount, with token ids given as floats.
+    #[track_caller]
+    pub fn bincount<V: Dimension>(self) -> GraphTensor<(V,)> {
+        // Built from the ids so the ones take their real length, which H doesn't give for runtime lengths
+        let ones = self * 0. + 1.;
+        ones.scatter_add(self)
+    }
+}
+
+impl<B: Dimension, V: Dimension> GraphTensor<(B, V)> {
+    /// Greedily pick the next token of each row of logits, and gather its row of `embedding` so the next step can
+    /// take it as input without the token id going through the host.
+    ///
+    /// Token ids are returned as floats.
+    #[track_caller]
+    pub fn greedy_embed<const DIM: usize>(
+        self,
+        embedding: GraphTensor<(V, Const<DIM>)>,
+    ) -> (GraphTensor<(B,)>, GraphTensor<(B, Const<DIM>)>) {
+        let ids = self.argmax::<1>();
+        (ids, embedding.gather(ids))
+    }
+}
+
+/// A greedy decode loop that keeps its state on device.
+///
+/// Each step feeds the embedding of the last generated token back in as the model input, and shifts the token id
+/// into a history of the last `K` ids, both by moving the outputs onto the inputs between executions. The host
+/// never uploads a token, and only reads the history once every `K` steps.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let embedding = cx.tensor::<R2<3, 2>>().set(vec![1., 0., 0., 1., -1., -1.]);
+/// let swap = cx.tensor::<R2<2, 2>>().set(vec![0., 1., 1., 0.]);
+/// let mut decoder = GreedyDecoder::<2, 2>::new(&mut cx);
+/// decoder.input.set(vec![1., 0.]);
+/// // The model swaps the values of its input, so the tokens alternate
+/// let logits = decoder.input.matmul(swap).matmul(embedding.permute());
+/// decoder.finish(logits, embedding);
+/// cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut decoder);
+/// assert_eq!(decoder.step(&mut cx), None);
+/// assert_eq!(decoder.step(&mut cx), Some(vec![1, 0]));
+/// ```
+pub struct GreedyDecoder<const DIM: usize, const K: usize> {
+    /// The embedding of the current token, which the model should be built on
+    pub input: GraphTensor<(Const<1>, Const<DIM>)>,
+    /// The last `K` generated token ids, oldest first
+    pub history: GraphTensor<(Const<K>,)>,
+    next_input: Option<GraphTensor<(Const<1>, Const<DIM>)>>,
+    next_history: Option<GraphTensor<(Const<K>,)>>,
+    steps: usize,
+}
+
+impl<const DIM: usize, const K: usize> GreedyDecoder<DIM, K> {
+    /// Create the state tensors. Set the embedding of the first token on `input`, build the model on it, then pass
+    /// its logits to [`GreedyDecoder::finish`].
+    #[track_caller]
+    pub fn new(cx: &mut Graph) -> Self {
+        Self {
+            input: cx.named_tensor("Greedy Input"),
+            history: cx.named_tensor("Greedy History").set(vec![0.; K]),
+            next_input: None,
+            next_history: None,
+            steps: 0,
+        }
+    }
+
+    /// Add the greedy step on the logits of the model, looking up the next input in `embedding`
+    #[track_caller]
+    pub fn finish<V: Dimension>(
+        &mut self,
+        logits: GraphTensor<(Const<1>, V)>,
+        embedding: GraphTensor<(V, Const<DIM>)>,
+    ) {
+        let (id, next_input) = logits.greedy_embed(embedding);
+        let next_history = self
+            .history
+            .slice((Expression::from(1)..,))
+            .concat_along::<(Const<K>,), Axis<0>, _>(id);
+        self.next_input = Some(next_input.keep());
+        self.next_history = Some(next_history.retrieve());
+    }
+
+    /// Run one step. Returns the ids of the last `K` tokens once every `K` steps.
+    pub fn step(&mut self, cx: &mut Graph) -> Option<Vec<usize>> {
+        let (Some(next_input), Some(next_history)) = (self.next_input, self.next_history) else {
+            panic!("Call GreedyDecoder::finish before stepping");
+        };
+        cx.execute();
+        self.steps += 1;
+        let ids = self.steps.is_multiple_of(K).then(|| {
+            next_history
+                .data()
+                .into_iter()
+                .map(|i| i as usize)
+                .collect()
+        });
+        transfer_data_same_graph((next_input, next_history), (self.input, self.history), cx);
+        ids
+    }
+}
+
+impl<const DIM: usize, const K: usize> ToIdsMut for GreedyDecoder<DIM, K> {
+    fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex> {
+        let mut ids = vec![&mut self.input.id, &mut self.history.id];
+        ids.extend(self.next_input.iter_mut().map(|t| &mut t.id));
+        ids.extend(self.next_history.iter_mut().map(|t| &mut t.id));
+        ids
+    }
+}
+
+/// A transformation of next-token logits based on the tokens generated so far
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogitsProcessor {
+    /// Penalize tokens already in the history, matching HF's `RepetitionPenaltyLogitsProcessor`
+    RepetitionPenalty(f32),
+    /// Subtract the penalty once for each time a token appears in the history
+    FrequencyPenalty(f32),
+    /// Subtract the penalty from every token that appears in the history
+    PresencePenalty(f32),
+    /// Never generate these token ids
+    BanTokens(Vec<usize>),
+}
+
+impl<V: Dimension> GraphTensor<(V,)> {
+    /// Divide the logits of previously generated tokens by `penalty` if positive, multiply them by it if negative.
+    ///
+    /// `counts` is the histogram of the token history, from [`GraphTensor::bincount`].
+    #[track_caller]
+    pub fn repetition_penalty(self, counts: GraphTensor<(V,)>, penalty: f32) -> Self {
+        let positive = self.greater_than(self.graph().constant(0.).expand());
+        let penalized = positive * (self / penalty) + (-positive + 1.) * (self * penalty);
+        let seen = counts.min_f32(1.);
+        self + seen * (penalized - self)
+    }
+
+    /// Subtract `frequency` for each occurrence of a token in the history, and `presence` for any occurrence.
+    ///
+    /// `counts` is the histogram of the token history, from [`GraphTensor::bincount`].
+    #[track_caller]
+    pub fn frequency_presence_penalty(
+        self,
+        counts: GraphTensor<(V,)>,
+        frequency: f32,
+        presence: f32,
+    ) -> Self {
+        self - counts * frequency - counts.min_f32(1.) * presence
+    }
+
+    /// Set the logits of the given token ids to negative infinity
+    #[track_caller]
+    pub fn ban_tokens(self, tokens: &[usize]) -> Self {
+        if tokens.is_empty() {
+            return self;
+        }
+        let ids = tokens.iter().map(|t| *t as f32).collect::<Vec<_>>();
+        let banned = self
+            .graph()
+            .add_op(op::Function(
+                "Banned Tokens Load".to_string(),
+                Rc::new(move |_| vec![Tensor::new(ids.clone())]),
+            ))
+            .finish();
+        let banned = GraphTensor::<(Dyn<'-'>,)>::from_id(
+            banned,
+            ShapeTracker::new(&[tokens.len().into()]),
+            self.graph_ref,
+        );
+        // ln(0) is -inf, and adding it avoids the NaNs multiplying by a mask would give
+        self + (-banned.bincount::<V>().min_f32(1.) + 1.).ln()
+    }
+
+    /// Apply a chain of logits processors in order, given the token ids generated so far (as floats).
+    ///
+    /// Token bans produce infinite logits, so they should come after the penalties in the chain.
+    #[track_caller]
+    pub fn process_logits<H: Dimension>(
+        self,
+        history: GraphTensor<(H,)>,
+        processors: &[LogitsProcessor],
+    ) -> Self {
+        let mut counts = None;
+        let mut counts = || *counts.get_or_insert_with(|| history.bincount::<V>());
+        processors
+            .iter()
+            .fold(self, |logits, processor| match processor {
+                LogitsProcessor::RepetitionPenalty(p) => logits.repetition_penalty(counts(), *p),
+                LogitsProcessor::FrequencyPenalty(f) => {
+                    logits.frequency_presence_penalty(counts(), *f, 0.)
+                }
+                LogitsProcessor::PresencePenalty(p) => {
+                    logits.frequency_presence_penalty(counts(), 0., *p)
+                }
+                LogitsProcessor::BanTokens(tokens) => logits.ban_tokens(tokens),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use rand::{rngs::StdRng, SeedableRng};
+    crate::test_imports!();
+
+    /// Reference nucleus filtering, returning the sampling probability of each token
+    fn reference_top_p(logits: &[f32], p: f32, temperature: f32) -> Vec<f32> {
+        let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let exp = logits
+            .iter()
+            .map(|l| ((l - max) / temperature).exp())
+            .collect::<Vec<_>>();
+        let sum = exp.iter().sum::<f32>();
+        let probs = exp.iter().map(|e| e / sum).collect::<Vec<_>>();
+        let mut order = (0..probs.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| probs[*b].partial_cmp(&probs[*a]).unwrap());
+        let mut filtered = vec![0.; probs.len()];
+        let mut cumulative = 0.;
+        for i in order {
+            if cumulative >= p {
+                break;
+            }
+            filtered[i] = probs[i];
+            cumulative += probs[i];
+        }
+        let sum = filtered.iter().sum::<f32>();
+        filtered.into_iter().map(|f| f / sum).collect()
+    }
+
+    #[test]
+    fn test_top_p_first_token_kept() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![10., 1., 0., -1.]);
+        let b = a
+            .top_p_sample(0.5, 1.0, StdRng::seed_from_u64(0))
+            .retrieve();
+
+        for _ in 0..10 {
+            cx.execute();
+            assert_eq!(b.int_data(), vec![0]);
+            b.drop();
+        }
+    }
+
+    #[test]
+    fn test_top_p_distribution() {
+        let logits = vec![1.0, 2.5, 0.3, 2.4, -1.0, 0.9, 2.0];
+        let (p, temperature, n_draws) = (0.8, 0.7, 4000);
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<7>>().set(logits.clone());
+        let b = a
+            .top_p_sample(p, temperature, StdRng::seed_from_u64(42))
+            .retrieve();
+
+        let mut counts = vec![0; logits.len()];
+        for _ in 0..n_draws {
+            cx.execute();
+            counts[b.int_data()[0] as usize] += 1;
+            b.drop();
+        }
+
+        let reference = reference_top_p(&logits, p, temperature);
+        for (count, prob) in counts.into_iter().zip(reference) {
+            if prob == 0. {
+                assert_eq!(count, 0);
+            } else {
+                assert!((count as f32 / n_draws as f32 - prob).abs() < 0.03);
+            }
+        }
+    }
+
+    #[test]
+    fn test_top_p_rows() {
+        // Identical rows of uniform logits take their own draws
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 100>>().set(vec![0.; 200]);
+        let b = a
+            .top_p_sample(1.0, 1.0, StdRng::seed_from_u64(0))
+            .retrieve();
+
+        let mut matches = 0;
+        for _ in 0..20 {
+            cx.execute();
+            let ids = b.int_data();
+            assert!(ids.iter().all(|i| (0..100).contains(i)));
+            matches += (ids[0] == ids[1]) as usize;
+            b.drop();
+        }
+        assert!(matches < 5);
+    }
+
+    #[test]
+    fn test_top_p_seeded() {
+        let draws = |seed| {
+            let mut cx = Graph::new();
+            let a = cx
+                .tensor::<R2<2, 5>>()
+                .set(vec![0.5, 0.1, 0.3, 0.2, 0.4, 1.0, 0.0, 1.0, 0.5, 0.9]);
+            let b = a
+                .top_p_sample(0.9, 1.0, StdRng::seed_from_u64(seed))
+                .retrieve();
+            (0..20)
+                .flat_map(|_| {
+                    cx.execute();
+                    let d = b.int_data();
+                    b.drop();
+                    d
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws(3), draws(3));
+        assert_ne!(draws(3), draws(4));
+    }
+
+    #[test]
+    fn test_sample_top_k() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<5>>().set(vec![1., 3., 2., 3., 0.]);
+        let params = SamplingParams {
+            top_k: Some(2),
+            ..Default::default()
+        };
+        let b = a.sample(params, StdRng::seed_from_u64(0)).retrieve();
+
+        // Ties keep their original order, so only the two 3s can be drawn
+        let mut seen = vec![];
+        for _ in 0..50 {
+            cx.execute();
+            seen.extend(b.int_data());
+            b.drop();
+        }
+        assert_eq!(
+            seen.into_iter().unique().sorted().collect::<Vec<_>>(),
+            [1, 3]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Top-p of 0 isn't in (0, 1]")]
+    fn test_sample_invalid() {
+        let mut cx = Graph::new();
+        cx.tensor::<R1<5>>()
+            .top_p_sample(0., 1.0, StdRng::seed_from_u64(0));
+    }
+
+    #[test]
+    fn test_bincount() {
+        let mut cx = Graph::new();
+        let ids = cx
+            .tensor::<R1<5>>()
+            .set(vec![3., 0., 3., 150_000., 200_000.]);
+        // Large vocabularies only cost their histogram
+        let counts = ids.bincount::<LConst<150_001>>().retrieve();
+        cx.execute();
+
+        let counts = counts.data();
+        assert_exact(&counts[..4], &[1., 0., 0., 2.]);
+        assert_eq!(counts[150_000], 1.);
+        assert_eq!(counts.iter().sum::<f32>(), 4.);
+    }
+
+    /// Reference logits processing, following HF's repetition penalty and OpenAI's frequency / presence penalties
+    fn reference_process(
+        logits: &[f32],
+        history: &[usize],
+        processors: &[LogitsProcessor],
+    ) -> Vec<f32> {
+        let mut logits = logits.to_vec();
+        let mut counts = vec![0.; logits.len()];
+        for t in history {
+            counts[*t] += 1.;
+        }
+        for processor in processors {
+            for (i, l) in logits.iter_mut().enumerate() {
+                match processor {
+                    LogitsProcessor::RepetitionPenalty(p) if counts[i] > 0. => {
+                        *l = if *l < 0. { *l * p } else { *l / p }
+                    }
+                    LogitsProcessor::FrequencyPenalty(f) => *l -= counts[i] * f,
+                    LogitsProcessor::PresencePenalty(p) if counts[i] > 0. => *l -= p,
+                    LogitsProcessor::BanTokens(t) if t.contains(&i) => *l = f32::NEG_INFINITY,
+                    _ => {}
+                }
+            }
+        }
+        logits
+    }
+
+    #[test]
+    fn test_logits_processors_generation() {
+        let processors = [
+            LogitsProcessor::RepetitionPenalty(1.3),
+            LogitsProcessor::FrequencyPenalty(0.2),
+            LogitsProcessor::PresencePenalty(0.1),
+            LogitsProcessor::BanTokens(vec![0, 7]),
+        ];
+        let mut cx = Graph::new();
+        let logits = cx.tensor::<R1<10>>();
+        let history = cx.named_tensor::<(Dyn<'h'>,)>("History");
+        let processed = logits.process_logits(history, &processors).retrieve();
+
+        // Greedily generate from scripted logits, starting from a prompt
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut tokens = vec![1, 3, 3];
+        for _ in 0..8 {
+            let step_logits = random_vec_rng(10, &mut rng);
+            logits.set(step_logits.clone());
+            history.set_dyn(
+                tokens.iter().map(|t| *t as f32).collect::<Vec<_>>(),
+                &[tokens.len()],
+            );
+            cx.execute();
+
+            let reference = reference_process(&step_logits, &tokens, &processors);
+            let out = processed.data();
+            for (a, b) in out.iter().zip(&reference) {
+                if b.is_infinite() {
+                    assert_eq!(a, b);
+                } else {
+                    assert!((a - b).abs() < 1e-5, "{out:?} != {reference:?}");
+                }
+            }
+            processed.drop();
+            let next = (0..10)
+                .max_by(|a, b| reference[*a].partial_cmp(&reference[*b]).unwrap())
+                .unwrap();
+            assert!(next != 0 && next != 7);
+            tokens.push(next);
+        }
+    }
+
+    #[test]
+    fn test_greedy_decoder() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let (embed_data, proj_data) = (
+            random_vec_rng(16 * 8, &mut rng),
+            random_vec_rng(8 * 16, &mut rng),
+        );
+        let model =
+            |x: GraphTensor<R2<1, 8>>, proj: GraphTensor<R2<8, 16>>| (x.matmul(proj) * 40.).sin();
+
+        // Unfused: the host picks each token and uploads it
+        let mut cx = Graph::new();
+        let embedding = cx.tensor::<R2<16, 8>>().set(embed_data.clone());
+        let proj = cx.tensor::<R2<8, 16>>().set(proj_data.clone());
+        let token = cx.tensor::<R1<1>>();
+        let mut logits = model(embedding.gather(token), proj).retrieve();
+        cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut logits);
+        let mut expected = vec![3];
+        for _ in 0..12 {
+            token.set(vec![*expected.last().unwrap() as f32]);
+            cx.execute();
+            let data = logits.data();
+            logits.drop();
+            expected.push(
+                (0..16)
+                    .max_by(|a, b| data[*a].partial_cmp(&data[*b]).unwrap())
+                    .unwrap(),
+            );
+        }
+
+        // Fused: the next input is gathered on device, and ids are read back every 4 tokens
+        let mut cx = Graph::new();
+        let embedding = cx.tensor::<R2<16, 8>>().set(embed_data.clone());
+        let proj = cx.tensor::<R2<8, 16>>().set(proj_data);
+        let mut decoder = GreedyDecoder::<8, 4>::new(&mut cx);
+        decoder.input.set(embed_data[3 * 8..4 * 8].to_vec());
+        let logits = model(decoder.input, proj);
+        decoder.finish(logits, embedding);
+        cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut decoder);
+        let mut generated = vec![3];
+        for step in 1..=12 {
+            let ids = decoder.step(&mut cx);
+            assert_eq!(ids.is_some(), step % 4 == 0);
+            generated.extend(ids.unwrap_or_default());
+        }
+        assert_eq!(generated, expected);
+    }
+}
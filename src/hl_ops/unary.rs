@@ -4,6 +4,7 @@ use std::ops::{Add, Mul, Neg};
 impl<S: Shape> Neg for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn neg(self) -> Self::Output {
         self * -1.0
     }
@@ -11,6 +12,7 @@ impl<S: Shape> Neg for GraphTensor<S> {
 
 impl<S: Shape> GraphTensor<S> {
     /// Base 2 log
+    #[track_caller]
     pub fn log2(self) -> GraphTensor<S> {
         let new_id = self
             .graph()
@@ -21,6 +23,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Base 2 exp
+    #[track_caller]
     pub fn exp2(self) -> GraphTensor<S> {
         let new_id = self
             .graph()
@@ -31,16 +34,19 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Natural exp
+    #[track_caller]
     pub fn exp(self) -> GraphTensor<S> {
         (self * (1.0 / f32::ln(2.))).exp2()
     }
 
     /// Natural log
+    #[track_caller]
     pub fn ln(self) -> GraphTensor<S> {
         self.log2() * f32::ln(2.)
     }
 
     /// Take the reciprocal of each element
+    #[track_caller]
     pub fn recip(self) -> GraphTensor<S> {
         let new_id = self
             .graph()
@@ -51,6 +57,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// The sin(x) function
+    #[track_caller]
     pub fn sin(self) -> GraphTensor<S> {
         let new_id = self
             .graph()
@@ -61,11 +68,13 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// The cos(x) function
+    #[track_caller]
     pub fn cos(self) -> GraphTensor<S> {
         (-self + (std::f32::consts::PI / 2.)).sin()
     }
 
     /// The square root function
+    #[track_caller]
     pub fn sqrt(self) -> GraphTensor<S> {
         let new_id = self
             .graph()
@@ -76,6 +85,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Scale so std is 1.0
+    #[track_caller]
     pub fn std_norm<const DIM: usize, T>(self, epsilon: T) -> GraphTensor<S>
     where
         <S as ReduceShape<Axis<DIM>>>::Reduced: Shape,
@@ -93,6 +103,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Center so mean is 0.0
+    #[track_caller]
     pub fn mean_norm<const DIM: usize>(self) -> GraphTensor<S>
     where
         <S as ReduceShape<Axis<DIM>>>::Reduced: Shape,
@@ -104,6 +115,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Applies a layer norm along an axis
+    #[track_caller]
     pub fn layer_norm<const DIM: usize, T>(self, epsilon: T) -> GraphTensor<S>
     where
         <S as ReduceShape<Axis<DIM>>>::Reduced: Shape,
@@ -115,6 +127,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Applies a softmax function along an axis
+    #[track_caller]
     pub fn softmax<const DIM: usize>(self) -> GraphTensor<S>
     where
         <S as ReduceShape<Axis<DIM>>>::Reduced: Shape,
@@ -129,26 +142,53 @@ impl<S: Shape> GraphTensor<S> {
         exp / exp_sum.expand()
     }
 
-    /// Get the indicies of the max elements along the last axis
-    pub fn argmax(self) -> GraphTensor<<S as ReduceShape<<S as Shape>::LastAxis>>::Reduced> {
-        let x_equal = self.equals(self.max_reduce::<_, S::LastAxis>().expand());
-        // ARange to shape
-        let r = self.graph().constant(1.).expand().cumsum_last_dim() - 1.;
-        (x_equal * r).max_reduce::<_, S::LastAxis>()
+    /// Get the indexes of the max elements along an axis, as floats. Ties resolve to the lowest index.
+    #[track_caller]
+    pub fn argmax<const DIM: usize>(self) -> GraphTensor<<S as ReduceShape<Axis<DIM>>>::Reduced>
+    where
+        <S as ReduceShape<Axis<DIM>>>::Reduced: Shape,
+        S: ReduceShape<Axis<DIM>>,
+    {
+        self.arg_reduce(DIM, false)
+    }
+
+    /// Get the indexes of the min elements along an axis, as floats. Ties resolve to the lowest index.
+    #[track_caller]
+    pub fn argmin<const DIM: usize>(self) -> GraphTensor<<S as ReduceShape<Axis<DIM>>>::Reduced>
+    where
+        <S as ReduceShape<Axis<DIM>>>::Reduced: Shape,
+        S: ReduceShape<Axis<DIM>>,
+    {
+        self.arg_reduce(DIM, true)
+    }
+
+    #[track_caller]
+    fn arg_reduce<Dst: Shape>(self, dim: usize, min: bool) -> GraphTensor<Dst> {
+        let new_id = self
+            .graph()
+            .add_op(op::ArgMax { dim, min })
+            .input(self.id, 0, self.shape)
+            .finish();
+        let mut shape = self.shape;
+        shape.remove_dim(dim);
+        GraphTensor::from_id(new_id, shape.contiguous(), self.graph_ref)
     }
 
     /// Take the absolute value
+    #[track_caller]
     pub fn abs(self) -> GraphTensor<S> {
         self.relu() + (-self).relu()
     }
 
     /// Get the sign of each element, '1' for positive and '-1' for negative
+    #[track_caller]
     pub fn sign(self) -> GraphTensor<S> {
         self / (self.abs() + 1e-10)
     }
 
     /// Raise the tensor to a power
     /// Approximate, see full impl here: https://github.com/tinygrad/tinygrad/blob/a32c67760140dd26b60d7932268f2e62e96a66e0/tinygrad/tensor.py#L568
+    #[track_caller]
     pub fn pow<T>(self, e: T) -> GraphTensor<S>
     where
         Self: Mul<T, Output = Self>,
@@ -157,16 +197,19 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// 1 / (base ^ x)
+    #[track_caller]
     pub fn inv_pow(self, base: f32) -> GraphTensor<S> {
         self.mul(base.abs().ln()).exp()
     }
 
     /// The Rectified Linear Unit activation function
+    #[track_caller]
     pub fn relu(self) -> GraphTensor<S> {
         self.max_f32(0.)
     }
 
     /// The sigmoid activation function
+    #[track_caller]
     pub fn sigmoid(self) -> GraphTensor<S> {
         // Based on https://github.com/tinygrad/tinygrad/blob/9d142430cbe61121c864c0015f1de83c94a7d2c0/tinygrad/mlops.py#L70
         let one = self.graph().constant(1.0);
@@ -174,16 +217,19 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// The swish activation function
+    #[track_caller]
     pub fn swish(self) -> GraphTensor<S> {
         self * self.sigmoid()
     }
 
     /// The tanh activation function
+    #[track_caller]
     pub fn tanh(self) -> GraphTensor<S> {
         (self * 2.0).sigmoid() * 2.0 - 1.0
     }
 
     /// The leaky relu activation function
+    #[track_caller]
     pub fn leaky_relu(self, neg_slope: f32) -> GraphTensor<S> {
         self.relu() - (self * -neg_slope).relu()
     }
@@ -208,6 +254,27 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_argmax() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 4>>().set([
+            [1., 5., 5., -2.],
+            [0., 0., 0., 0.],
+            [3., f32::NAN, 7., f32::NAN],
+        ]);
+        let max_rows = a.argmax::<1>().retrieve();
+        let min_rows = a.argmin::<1>().retrieve();
+        let max_cols = a.argmax::<0>().retrieve();
+        let min_cols = a.argmin::<0>().retrieve();
+        cx.execute();
+
+        // Ties go to the lowest index, and the first NaN wins
+        assert_exact(&max_rows.data(), &[1., 0., 1.]);
+        assert_exact(&min_rows.data(), &[3., 0., 1.]);
+        assert_exact(&max_cols.data(), &[2., 2., 2., 2.]);
+        assert_exact(&min_cols.data(), &[1., 2., 1., 2.]);
+    }
+
     #[test]
     fn test_layer_norm() {
         let mut cx = Graph::new();
@@ -7,17 +7,85 @@ mod hl_ops;
 
 pub mod tests;
 
+/// Everything needed to build, compile, run and save models.
+///
+/// These are the supported user-facing items. Building blocks for writing compilers and backends live in
+/// [`compiler_internals`] instead, and can change between releases.
+///
+/// ```rust
+/// use luminal::prelude::*;
+///
+/// let mut cx = Graph::new();
+/// let x = cx.named_tensor::<(Dyn<'b'>, Const<3>)>("Input");
+/// let w = cx.named_tensor::<R2<3, 2>>("Weight").set(vec![1., 0., 0., 1., 1., 1.]);
+/// let mut out = x.matmul(w).relu().sum_reduce::<(Dyn<'b'>,), Axis<1>>().retrieve();
+/// assert_eq!(out.rank(), 1);
+///
+/// x.set_dyn(vec![1., 2., 3., -1., -2., -3.], &[2, 3]);
+/// cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut out);
+/// cx.execute();
+/// assert_eq!(out.data(), vec![9., 0.]);
+/// ```
 pub mod prelude {
+    pub use crate::async_execution::ExecutionHandle;
+    pub use crate::auto_compile::{Pipeline, PipelineChoice, Platform};
+    pub use crate::cancellation::{CancellationToken, Cancelled};
+    pub use crate::checkpoint::{ForkError, GraphCheckpoint, RestoreError};
+    pub use crate::compile_cache::CompileCache;
+    pub use crate::compile_stats::{CompileReport, CompileStats};
+    pub use crate::compiler_utils::{CompilerHint, Im2Col, Looped, Timed, ToIds, ToIdsMut};
+    pub use crate::compilers::{CPUCompiler, CompensatedSummation, GenericCompiler};
+    pub use crate::context::ExecutionContext;
+    #[cfg(feature = "dfdx")]
+    pub use crate::dfdx_interop::*;
+    pub use crate::format::PrintOptions;
+    pub use crate::graph::{ExecutionError, Graph, NodeIndex};
+    pub use crate::graph_tensor::{GraphTensor, MarkTensors, ToData};
+    pub use crate::hl_ops::{
+        GenerationError, GenerationLimits, GreedyDecoder, LogitsProcessor, Mask, Matmul, RowsError,
+        SamplingParams, Truncation,
+    };
+    pub use crate::lint::{LintError, LintWarning};
+    pub use crate::memory::{Allocation, ExecutionReport, OutOfMemory};
+    pub use crate::module::{
+        delete_inputs, downstream, state_dict, state_set, transfer_data, transfer_data_same_graph,
+        InitModule, Module,
+    };
+    pub use crate::partial_execution::{FailedOp, PartialExecutionReport};
+    pub use crate::profiling::{OpProfile, ProfileReport};
+    pub use crate::region::RegionError;
+    pub use crate::serialization::{
+        convert_checkpoint, ConvertError, ConvertOptions, DefaultNameResolver, Dtype, GgufLoader,
+        LoadReport, Loader, MissingWeights, NameResolver, QuantizationScheme, SafeTensorLoader,
+        SafeTensorSaver, Saver, SerializeModule, Serializer, StateDictLoader, StateDictSaver,
+    };
+    pub use crate::shape::{
+        symbolic::{self, BigExpression, Expression},
+        AssertSameNumel, Axes, Axes2, Axes3, Axes4, Axes5, Axes6, Axis, BroadcastShapeTo, Const,
+        ConstDim, ConstShape, Dimension, Dyn, HasAxes, HasShape, PermuteShapeTo, RangeToDim,
+        RealizeShapeTo, ReduceShape, ReduceShapeTo, Shape, ShapeTracker, SliceOfShape, R0, R1, R2,
+        R3, R4, R5, R6,
+    };
+    pub use crate::specialize::{ShapeRecord, SpecializationMismatch};
+    pub use crate::stats::{RunningStats, StatsCollector};
+    pub use crate::tensor::{Data, Q4_0Blocks, Q8_0Blocks, Tensor};
+    pub use half::{bf16, f16};
+    pub use luminal_macro::*;
+}
+
+/// Building blocks for writing compilers and backends: graph search and rewriting, the primitive ops, and shape
+/// tracker manipulation.
+///
+/// Unlike the [`prelude`], these follow the internals of the graph and can change between releases.
+pub mod compiler_internals {
+    pub use crate::async_execution::deferred_sync;
+    pub use crate::auto_compile::{register_pipeline, PipelineCompiler};
     pub use crate::compiler_utils::*;
     pub use crate::compilers::*;
     pub use crate::graph::*;
-    pub use crate::graph_tensor::*;
-    pub use crate::hl_ops::*;
-    pub use crate::module::*;
-    pub use crate::serialization::*;
+    pub use crate::op::{self, InputTensor, OpError, Operator};
+    pub use crate::profiling::{profiling, record_device_time};
+    pub use crate::region::RegionWrite;
     pub use crate::shape::*;
-    pub use crate::tensor::*;
-    pub use half::{bf16, f16};
-    pub use luminal_macro::*;
     pub use petgraph;
 }
@@ -1,6 +1,84 @@
 use crate::prelude::*;
 use rand::{thread_rng, Rng};
 
+/// How a convolution's activations and weights are laid out in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConvLayout {
+    /// Channels first: activations are (channels, x, y) and weights are (out, in, kernel x, kernel y), like PyTorch
+    #[default]
+    Nchw,
+    /// Channels last: activations are (x, y, channels) and weights are (out, kernel x, kernel y, in), like TensorFlow
+    Nhwc,
+}
+
+/// Reorder the dimensions of a row-major array of shape `dims`, so dimension `i` of the result is `axes[i]` of the
+/// input
+fn permute_data(data: &[f32], dims: [usize; 4], axes: [usize; 4]) -> Vec<f32> {
+    assert_eq!(
+        data.len(),
+        dims.iter().product::<usize>(),
+        "Data doesn't match the shape!"
+    );
+    let mut strides = [1; 4];
+    for i in (0..3).rev() {
+        strides[i] = strides[i + 1] * dims[i + 1];
+    }
+    let out_dims = axes.map(|a| dims[a]);
+    let mut out = Vec::with_capacity(data.len());
+    for i in 0..data.len() {
+        let mut rem = i;
+        let mut src = 0;
+        for d in (0..4).rev() {
+            src += (rem % out_dims[d]) * strides[axes[d]];
+            rem /= out_dims[d];
+        }
+        out.push(data[src]);
+    }
+    out
+}
+
+/// Convert convolution weights from (out, in, kernel x, kernel y) to (out, kernel x, kernel y, in)
+pub fn oihw_to_ohwi(weight: &[f32], [o, i, h, w]: [usize; 4]) -> Vec<f32> {
+    permute_data(weight, [o, i, h, w], [0, 2, 3, 1])
+}
+
+/// Convert convolution weights from (out, kernel x, kernel y, in) to (out, in, kernel x, kernel y)
+pub fn ohwi_to_oihw(weight: &[f32], [o, h, w, i]: [usize; 4]) -> Vec<f32> {
+    permute_data(weight, [o, h, w, i], [0, 3, 1, 2])
+}
+
+impl<C: Dimension, X: Dimension, Y: Dimension> GraphTensor<(C, X, Y)> {
+    /// Transpose a (channels, x, y) activation to (x, y, channels)
+    #[track_caller]
+    pub fn to_channels_last(self) -> GraphTensor<(X, Y, C)> {
+        self.permute::<_, Axes3<1, 2, 0>>().contiguous()
+    }
+}
+
+impl<X: Dimension, Y: Dimension, C: Dimension> GraphTensor<(X, Y, C)> {
+    /// Transpose an (x, y, channels) activation to (channels, x, y)
+    #[track_caller]
+    pub fn to_channels_first(self) -> GraphTensor<(C, X, Y)> {
+        self.permute::<_, Axes3<2, 0, 1>>().contiguous()
+    }
+}
+
+impl<N: Dimension, C: Dimension, X: Dimension, Y: Dimension> GraphTensor<(N, C, X, Y)> {
+    /// Transpose a batch of (channels, x, y) activations to (x, y, channels)
+    #[track_caller]
+    pub fn to_channels_last(self) -> GraphTensor<(N, X, Y, C)> {
+        self.permute::<_, Axes4<0, 2, 3, 1>>().contiguous()
+    }
+}
+
+impl<N: Dimension, X: Dimension, Y: Dimension, C: Dimension> GraphTensor<(N, X, Y, C)> {
+    /// Transpose a batch of (x, y, channels) activations to (channels, x, y)
+    #[track_caller]
+    pub fn to_channels_first(self) -> GraphTensor<(N, C, X, Y)> {
+        self.permute::<_, Axes4<0, 3, 1, 2>>().contiguous()
+    }
+}
+
 pub struct Conv1D<
     const CHANNELS_IN: usize,
     const CHANNELS_OUT: usize,
@@ -91,7 +169,13 @@ pub struct Conv2D<
     const DILATIONY: usize,
     const CHANNELS_IN_TIMES_KERNELX_KERNELY: usize,
 > {
+    /// Flattened weights, (out, in, kernel x, kernel y) or (out, kernel x, kernel y, in) depending on the layout
     pub weight: GraphTensor<R2<CHANNELS_OUT, CHANNELS_IN_TIMES_KERNELX_KERNELY>>,
+    pub bias: Option<GraphTensor<R1<CHANNELS_OUT>>>,
+    /// Layout of the weights
+    pub layout: ConvLayout,
+    /// Zeros added to both sides of the input's x and y
+    pub padding: (usize, usize),
 }
 
 impl<
@@ -118,18 +202,7 @@ impl<
     >
 {
     fn initialize(cx: &mut Graph) -> Self {
-        let conv = Self {
-            weight: cx.named_tensor("Weight"),
-        };
-
-        // Init weight as uniform(-1, 1)
-        let mut rng = thread_rng();
-        conv.weight.set(
-            (0..(CHANNELS_IN * CHANNELS_OUT * KERNELX * KERNELY))
-                .map(|_| rng.gen_range(-1_f32..1_f32))
-                .collect::<Vec<_>>(),
-        );
-        conv
+        Self::new(cx, false)
     }
 }
 
@@ -158,6 +231,10 @@ impl<
 {
     fn serialize(&self, s: &mut crate::serialization::Serializer) {
         s.tensor("weight", self.weight);
+        match self.bias {
+            Some(bias) => s.tensor("bias", bias),
+            None => s.absent("bias"),
+        }
     }
 }
 
@@ -185,6 +262,57 @@ impl<
         CHANNELS_IN_TIMES_KERNELX_KERNELY,
     >
 {
+    /// Create a convolution with weights (and a bias if `bias` is set) initialized as uniform(-1, 1), and no padding
+    pub fn new(cx: &mut Graph, bias: bool) -> Self {
+        let mut rng = thread_rng();
+        let mut uniform = |n| {
+            (0..n)
+                .map(|_| rng.gen_range(-1_f32..1_f32))
+                .collect::<Vec<_>>()
+        };
+        Self {
+            weight: cx
+                .named_tensor("Weight")
+                .set(uniform(CHANNELS_IN * CHANNELS_OUT * KERNELX * KERNELY)),
+            bias: bias.then(|| cx.named_tensor("Bias").set(uniform(CHANNELS_OUT))),
+            layout: ConvLayout::default(),
+            padding: (0, 0),
+        }
+    }
+
+    /// Pad the input's x and y with zeros on both sides. Odd kernels with a stride of 1 keep the input size with a
+    /// padding of half the kernel, rounded down.
+    pub fn with_padding(mut self, x: usize, y: usize) -> Self {
+        self.padding = (x, y);
+        self
+    }
+
+    /// Read the weights as laid out in `layout`, like weights from a channels last checkpoint
+    pub fn with_layout(mut self, layout: ConvLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// The weights as (out, in, kernel x, kernel y)
+    fn channels_first_weight(
+        &self,
+    ) -> GraphTensor<R2<CHANNELS_OUT, CHANNELS_IN_TIMES_KERNELX_KERNELY>> {
+        match self.layout {
+            ConvLayout::Nchw => self.weight,
+            ConvLayout::Nhwc => self
+                .weight
+                .reshape::<R4<CHANNELS_OUT, KERNELX, KERNELY, CHANNELS_IN>>()
+                .permute::<_, Axes4<0, 3, 1, 2>>()
+                .reshape(),
+        }
+    }
+
+    /// Convolve a (channels, x, y) input. The output dims are `(dim + 2 * padding - span) / stride + 1`, where `span` is
+    /// the kernel's size with its dilation.
+    ///
+    /// The input's patches are expanded into a (channels * kernel x * kernel y, out x * out y) matrix, which the weights
+    /// are multiplied with. Without dilation the expansion is hinted with [`CompilerHint::Im2Col`], so backends can
+    /// build it in one kernel.
     pub fn forward<
         const DIMX_IN: usize,
         const DIMY_IN: usize,
@@ -195,7 +323,14 @@ impl<
         &self,
         input: GraphTensor<R3<CHANNELS_IN, DIMX_IN, DIMY_IN>>,
     ) -> GraphTensor<R3<CHANNELS_OUT, DIMX_OUT, DIMY_OUT>> {
-        let input_pooled = input
+        // The expansion starts from dense data, so it can be rebuilt from the input alone
+        let input = input.contiguous();
+        let ops_before = input.graph().nodes_added;
+        let (px, py) = self.padding;
+        // The x dims in these types are before padding, the shape trackers hold the padded sizes
+        let mut input_pooled = input
+            .pad::<(), usize, usize>(&[(0, 0), (px, px), (py, py)])
+            .contiguous()
             .pool_last_dim::<R4<CHANNELS_IN, DIMX_IN, DIMY_OUT, KERNELY>>(
                 KERNELY.into(),
                 STRIDEY.into(),
@@ -209,17 +344,58 @@ impl<
             )
             .permute::<_, Axes5<0, 4, 2, 3, 1>>()
             .reshape::<R2<CHANNELS_IN_TIMES_KERNELX_KERNELY, DIMX_TIMES_DIMY_OUT>>();
-
-        self.weight
+        if DILATIONX == 0 && DILATIONY == 0 {
+            let ops = input.graph().nodes_added - ops_before;
+            input_pooled = input_pooled.hint(CompilerHint::Im2Col(Im2Col {
+                channels: CHANNELS_IN,
+                dims: [DIMX_IN, DIMY_IN],
+                kernel: [KERNELX, KERNELY],
+                stride: [STRIDEX, STRIDEY],
+                padding: [px, py],
+                ops,
+            }));
+        }
+
+        let output = self
+            .channels_first_weight()
             .matmul(input_pooled)
-            .reshape::<R3<CHANNELS_OUT, DIMX_OUT, DIMY_OUT>>()
+            .reshape::<R3<CHANNELS_OUT, DIMX_OUT, DIMY_OUT>>();
+        match self.bias {
+            Some(bias) => output + bias.expand::<_, Axes2<1, 2>>(),
+            None => output,
+        }
+    }
+
+    /// Convolve an (x, y, channels) input. The convolution runs channels first, so the input and output are
+    /// transposed, and [`LayoutPropagation`](crate::compilers::LayoutPropagation) removes the transposes between
+    /// convolutions that cancel out.
+    pub fn forward_nhwc<
+        const DIMX_IN: usize,
+        const DIMY_IN: usize,
+        const DIMX_OUT: usize,
+        const DIMY_OUT: usize,
+        const DIMX_TIMES_DIMY_OUT: usize,
+    >(
+        &self,
+        input: GraphTensor<R3<DIMX_IN, DIMY_IN, CHANNELS_IN>>,
+    ) -> GraphTensor<R3<DIMX_OUT, DIMY_OUT, CHANNELS_OUT>> {
+        self.forward::<DIMX_IN, DIMY_IN, DIMX_OUT, DIMY_OUT, DIMX_TIMES_DIMY_OUT>(
+            input.to_channels_first(),
+        )
+        .to_channels_last()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Conv1D;
-    use crate::{nn::convolution::Conv2D, prelude::*, tests::assert_close};
+    use super::{ohwi_to_oihw, oihw_to_ohwi, Conv1D, ConvLayout};
+    use crate::{
+        compilers::LayoutPropagation,
+        nn::convolution::Conv2D,
+        op::Contiguous,
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
 
     #[test]
     fn test_conv1d_simple() {
@@ -402,4 +578,120 @@ mod tests {
 
         assert_close(&out1.data(), &exp_out1.data())
     }
+
+    #[test]
+    fn test_conv2d_same_padding() {
+        let mut cx = Graph::new();
+        let model: Conv2D<1, 1, 3, 3, 1, 1, 0, 0, 9> =
+            Conv2D::new(&mut cx, true).with_padding(1, 1);
+        // out[x, y] = in[x, y] - in[x - 1, y] + 2 * in[x + 1, y + 1] + 0.5
+        model.weight.set(vec![0., -1., 0., 0., 1., 0., 0., 0., 2.]);
+        model.bias.unwrap().set(vec![0.5]);
+        let input = cx
+            .tensor::<R3<1, 3, 4>>()
+            .set((1..=12).map(|i| i as f32).collect::<Vec<_>>());
+        let out = model.forward::<3, 4, 3, 4, 12>(input).retrieve();
+        cx.execute();
+
+        assert_close(
+            &out.data(),
+            &[
+                13.5, 16.5, 19.5, 4.5, 24.5, 26.5, 28.5, 4.5, 4.5, 4.5, 4.5, 4.5,
+            ],
+        );
+        assert!(cx.compiler_hints.values().flatten().any(|h| matches!(
+            h,
+            CompilerHint::Im2Col(Im2Col {
+                kernel: [3, 3],
+                padding: [1, 1],
+                ..
+            })
+        )));
+    }
+
+    #[test]
+    fn test_conv2d_strided() {
+        let mut cx = Graph::new();
+        let model: Conv2D<2, 1, 3, 3, 2, 2, 0, 0, 18> =
+            Conv2D::initialize(&mut cx).with_padding(1, 1);
+        // Sum the 3x3 window of the first channel, and add 10 times the center of the second
+        let mut weight = vec![1.; 9];
+        weight.extend([0., 0., 0., 0., 10., 0., 0., 0., 0.]);
+        model.weight.set(weight);
+        let mut data = (0..25).map(|i| i as f32).collect::<Vec<_>>();
+        data.extend([1.; 25]);
+        let input = cx.tensor::<R3<2, 5, 5>>().set(data);
+        let out = model.forward::<5, 5, 3, 3, 9>(input).retrieve();
+        cx.execute();
+
+        // Windows centered on every other element of the ramp in[x, y] = 5x + y
+        assert_close(
+            &out.data(),
+            &[22., 37., 34., 73., 118., 91., 82., 127., 94.],
+        );
+    }
+
+    #[test]
+    fn test_conv2d_layouts() {
+        let mut cx = Graph::new();
+        let weight = random_vec(3 * 2 * 2 * 2);
+        let ohwi = oihw_to_ohwi(&weight, [3, 2, 2, 2]);
+        assert_eq!(ohwi_to_oihw(&ohwi, [3, 2, 2, 2]), weight);
+
+        let nchw: Conv2D<2, 3, 2, 2, 1, 1, 0, 0, 8> = Conv2D::initialize(&mut cx);
+        nchw.weight.set(weight);
+        let nhwc: Conv2D<2, 3, 2, 2, 1, 1, 0, 0, 8> =
+            Conv2D::initialize(&mut cx).with_layout(ConvLayout::Nhwc);
+        nhwc.weight.set(ohwi);
+
+        let input = cx.tensor::<R3<2, 5, 4>>().set(random_vec(2 * 5 * 4));
+        let out_nchw = nchw
+            .forward::<5, 4, 4, 3, 12>(input)
+            .to_channels_last()
+            .retrieve();
+        let out_nhwc = nhwc
+            .forward_nhwc::<5, 4, 4, 3, 12>(input.to_channels_last())
+            .retrieve();
+        cx.execute();
+
+        assert_close(&out_nhwc.data(), &out_nchw.data());
+    }
+
+    #[test]
+    fn test_conv_relu_conv_transposes() {
+        let mut cx = Graph::new();
+        let conv1: Conv2D<2, 3, 2, 2, 1, 1, 0, 0, 8> = Conv2D::initialize(&mut cx);
+        let conv2: Conv2D<3, 2, 2, 2, 1, 1, 0, 0, 12> = Conv2D::initialize(&mut cx);
+        let input = cx.tensor::<R3<5, 4, 2>>().set(random_vec(5 * 4 * 2));
+        let mut out = conv2
+            .forward_nhwc::<4, 3, 3, 2, 6>(conv1.forward_nhwc::<5, 4, 4, 3, 12>(input).relu())
+            .retrieve();
+        cx.execute();
+        let expected = out.data();
+
+        let transposes = |cx: &Graph| {
+            cx.graph
+                .node_indices()
+                .filter(|n| {
+                    cx.graph
+                        .node_weight(*n)
+                        .unwrap()
+                        .as_any()
+                        .is::<Contiguous>()
+                        && cx
+                            .graph
+                            .edges_directed(*n, petgraph::Direction::Incoming)
+                            .any(|e| e.weight().as_data().unwrap().2.is_transpose())
+                })
+                .count()
+        };
+        // Into and out of channels first around each convolution, and one inside each convolution that lays out its
+        // unfolded patches for the matmul
+        assert_eq!(transposes(&cx), 6);
+        cx.compile(LayoutPropagation, &mut out);
+        // The pair around the relu is gone, leaving the transposes at the boundaries and in the convolutions
+        assert_eq!(transposes(&cx), 4);
+        cx.execute();
+        assert_close(&out.data(), &expected);
+    }
 }
@@ -1,4 +1,6 @@
-use crate::prelude::*;
+use crate::{op, prelude::*};
+
+pub use crate::op::EmbeddingBagMode;
 
 pub struct Embedding<const N: usize, const DIM: usize> {
     pub weight: GraphTensor<R2<N, DIM>>,
@@ -42,6 +44,68 @@ impl<B: Dimension, S: Dimension, const N: usize, const DIM: usize> Module<GraphT
     }
 }
 
+/// Gather the rows of `table` in each bag of `indices` and combine them with `mode`, like PyTorch's
+/// `embedding_bag`.
+///
+/// `offsets` holds where each bag starts in `indices`, so bag `b` is `indices[offsets[b]..offsets[b + 1]]` and the last
+/// bag runs to the end of `indices`. Empty bags produce zeros, in every mode.
+pub fn embedding_bag<const N: usize, const DIM: usize, I: Dimension, B: Dimension>(
+    table: GraphTensor<R2<N, DIM>>,
+    indices: GraphTensor<(I,)>,
+    offsets: GraphTensor<(B,)>,
+    mode: EmbeddingBagMode,
+) -> GraphTensor<(B, Const<DIM>)> {
+    let (table, indices, offsets) = (
+        table.contiguous(),
+        indices.contiguous(),
+        offsets.contiguous(),
+    );
+    let id = table
+        .graph()
+        .add_op(op::EmbeddingBag { mode })
+        .input(table.id, 0, table.shape)
+        .input(indices.id, 0, indices.shape)
+        .input(offsets.id, 0, offsets.shape)
+        .finish();
+    GraphTensor::from_id(
+        id,
+        ShapeTracker::new(&[offsets.shape.shape()[0].clone().into(), DIM.into()]),
+        table.graph_ref,
+    )
+}
+
+/// Sums, means or maxes a variable number of embeddings per row. Takes the flat `(indices, offsets)` of the bags, see
+/// [`embedding_bag`].
+pub struct EmbeddingBag<const N: usize, const DIM: usize> {
+    pub weight: GraphTensor<R2<N, DIM>>,
+    pub mode: EmbeddingBagMode,
+}
+
+impl<const A: usize, const B: usize> InitModule for EmbeddingBag<A, B> {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            weight: cx.named_tensor("EmbeddingBag Weight"),
+            mode: EmbeddingBagMode::default(),
+        }
+    }
+}
+
+impl<const A: usize, const B: usize> SerializeModule for EmbeddingBag<A, B> {
+    fn serialize(&self, s: &mut crate::serialization::Serializer) {
+        s.tensor("weight", self.weight);
+    }
+}
+
+impl<I: Dimension, B: Dimension, const N: usize, const DIM: usize>
+    Module<(GraphTensor<(I,)>, GraphTensor<(B,)>)> for EmbeddingBag<N, DIM>
+{
+    type Output = GraphTensor<(B, Const<DIM>)>;
+
+    fn forward(&self, (indices, offsets): (GraphTensor<(I,)>, GraphTensor<(B,)>)) -> Self::Output {
+        embedding_bag(self.weight, indices, offsets, self.mode)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use dfdx::{
@@ -51,7 +115,7 @@ mod tests {
 
     use crate::prelude::Module;
 
-    use super::Embedding;
+    use super::{Embedding, EmbeddingBag, EmbeddingBagMode};
     use dfdx::nn::BuildOnDevice;
     crate::test_imports!();
 
@@ -89,4 +153,70 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
         assert_close(&batch_out.data(), &d_batch_out.as_vec());
     }
+
+    #[test]
+    fn test_embedding_bag() {
+        const N: usize = 5;
+        const DIM: usize = 3;
+        let table = random_vec(N * DIM);
+        // Bags of 2, 0, 4 and 1 indices, with a repeated index
+        let indices: [usize; 7] = [1, 4, 0, 2, 2, 3, 4];
+        let offsets: [usize; 4] = [0, 2, 2, 6];
+
+        let mut cx = Graph::new();
+        let mut model: EmbeddingBag<N, DIM> = InitModule::initialize(&mut cx);
+        model.weight.set(table.clone());
+        let idx = cx
+            .tensor::<(Dyn<'i'>,)>()
+            .set_dyn(indices.map(|i| i as f32).to_vec(), &[indices.len()]);
+        let off = cx
+            .tensor::<(Dyn<'b'>,)>()
+            .set_dyn(offsets.map(|o| o as f32).to_vec(), &[offsets.len()]);
+        let mut outs = [
+            EmbeddingBagMode::Sum,
+            EmbeddingBagMode::Mean,
+            EmbeddingBagMode::Max,
+        ]
+        .into_iter()
+        .map(|mode| {
+            model.mode = mode;
+            model.forward((idx, off)).retrieve()
+        })
+        .collect::<Vec<_>>();
+        cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut outs);
+        cx.execute();
+
+        // Reference: gather every row and combine them one bag at a time
+        let bag = |b: usize| {
+            let end = offsets.get(b + 1).copied().unwrap_or(indices.len());
+            indices[offsets[b]..end]
+                .iter()
+                .map(|i| &table[i * DIM..(i + 1) * DIM])
+                .collect::<Vec<_>>()
+        };
+        let reference = |combine: fn(&[&[f32]], usize) -> f32| {
+            (0..offsets.len())
+                .flat_map(|b| {
+                    let rows = bag(b);
+                    (0..DIM).map(move |d| {
+                        if rows.is_empty() {
+                            0.
+                        } else {
+                            combine(&rows, d)
+                        }
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        let sum = reference(|rows, d| rows.iter().map(|r| r[d]).sum());
+        let mean = reference(|rows, d| rows.iter().map(|r| r[d]).sum::<f32>() / rows.len() as f32);
+        let max = reference(|rows, d| rows.iter().map(|r| r[d]).fold(f32::NEG_INFINITY, f32::max));
+        assert_close(&outs[0].data(), &sum);
+        assert_close(&outs[1].data(), &mean);
+        assert_exact(&outs[2].data(), &max);
+        // The empty bag is zeros in every mode
+        for out in &outs {
+            assert_exact(&out.data()[DIM..2 * DIM], &[0.; DIM]);
+        }
+    }
 }
@@ -0,0 +1,285 @@
+use std::{any::TypeId, cell::RefCell, rc::Rc};
+
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+thread_local! {
+    /// The hooks being attached to a model, and the type of module they're limited to
+    static ATTACHING: RefCell<Option<(ForwardHooks, Option<TypeId>)>> = const { RefCell::new(None) };
+}
+
+/// Wraps a module so the tensors going in and out of it can be inspected after executing, once [`ForwardHooks`] are
+/// attached to the model.
+///
+/// Hooked modules serialize exactly like the module they wrap, so weights load the same. The tensors are named after
+/// the module's path in the model, like `layer0/self_attn/input` and `layer0/self_attn/output`. Modules taking or
+/// returning several tensors number them, like `layer0/self_attn/input/1`.
+pub struct Hooked<M> {
+    pub module: M,
+    /// The path of the module and the hooks attached to it
+    hook: RefCell<Option<(String, ForwardHooks)>>,
+}
+
+impl<M> Hooked<M> {
+    pub fn new(module: M) -> Self {
+        Self {
+            module,
+            hook: RefCell::new(None),
+        }
+    }
+}
+
+impl<M: InitModule> InitModule for Hooked<M> {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self::new(M::initialize(cx))
+    }
+}
+
+impl<M: SerializeModule + 'static> SerializeModule for Hooked<M> {
+    fn serialize(&self, s: &mut Serializer) {
+        let hooks = ATTACHING.with(|a| {
+            a.borrow()
+                .as_ref()
+                .filter(|(_, ty)| ty.is_none_or(|t| t == TypeId::of::<M>()))
+                .map(|(hooks, _)| hooks.clone())
+        });
+        if let Some(hooks) = hooks {
+            *self.hook.borrow_mut() = Some((s.path(), hooks));
+        }
+        self.module.serialize(s);
+    }
+}
+
+impl<I: HookTensors, M: Module<I>> Module<I> for Hooked<M>
+where
+    M::Output: HookTensors,
+{
+    type Output = M::Output;
+
+    fn forward(&self, input: I) -> Self::Output {
+        let hook = self.hook.borrow().clone();
+        if let Some((path, hooks)) = &hook {
+            input.hook(hooks, &join_path(path, "input"));
+        }
+        let output = self.module.forward(input);
+        if let Some((path, hooks)) = &hook {
+            output.hook(hooks, &join_path(path, "output"));
+        }
+        output
+    }
+}
+
+fn join_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{path}/{name}")
+    }
+}
+
+/// Tensors a [`Hooked`] module can take in or return
+pub trait HookTensors {
+    /// Capture the tensors under `name`, numbering them if there are several
+    fn hook(&self, hooks: &ForwardHooks, name: &str);
+}
+
+impl<S: Shape> HookTensors for GraphTensor<S> {
+    fn hook(&self, hooks: &ForwardHooks, name: &str) {
+        hooks.capture(name, *self);
+    }
+}
+
+macro_rules! hook_tuple {
+    ($($name:ident $idx:tt),+) => {
+        impl<$($name: HookTensors),+> HookTensors for ($($name,)+) {
+            fn hook(&self, hooks: &ForwardHooks, name: &str) {
+                $(self.$idx.hook(hooks, &format!("{name}/{}", $idx));)+
+            }
+        }
+    };
+}
+
+hook_tuple!(A 0, B 1);
+hook_tuple!(A 0, B 1, C 2);
+hook_tuple!(A 0, B 1, C 2, D 3);
+
+/// The data and shape of a hooked tensor
+type HookedValue = (Vec<f32>, Vec<usize>);
+
+/// The values of the tensors going in and out of [`Hooked`] modules, captured every execution.
+///
+/// Hooks are attached to a model before running its forward pass. Clones share the same values.
+/// ```rust
+/// use luminal::{nn::{hooks::{ForwardHooks, Hooked}, linear::Linear}, prelude::*};
+/// let mut cx = Graph::new();
+/// let model = (Hooked::new(Linear::<3, 4>::new(&mut cx, false)), Linear::<4, 2>::new(&mut cx, false));
+/// let hooks = ForwardHooks::new();
+/// hooks.attach_all(&model);
+/// let out = model.forward(cx.tensor::<R1<3>>().set(vec![1., 2., 3.])).retrieve();
+/// cx.execute();
+/// assert_eq!(hooks.names(), vec!["layer0/input", "layer0/output"]);
+/// assert_eq!(hooks.get("layer0/output").unwrap().1, vec![4]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ForwardHooks {
+    /// Names of the hooked tensors, in the order the forward pass reached them
+    names: Rc<RefCell<Vec<String>>>,
+    values: Rc<RefCell<FxHashMap<String, HookedValue>>>,
+    stats: Option<StatsCollector>,
+}
+
+impl ForwardHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also collect stats of every hooked tensor, under the same names
+    pub fn with_stats(mut self, stats: StatsCollector) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Hook every [`Hooked`] module in the model
+    pub fn attach_all<M: SerializeModule>(&self, model: &M) {
+        self.attach_filtered(model, None);
+    }
+
+    /// Hook the [`Hooked`] modules in the model that wrap a `T`, like every attention layer
+    pub fn attach<T: 'static, M: SerializeModule>(&self, model: &M) {
+        self.attach_filtered(model, Some(TypeId::of::<T>()));
+    }
+
+    fn attach_filtered<M: SerializeModule>(&self, model: &M, ty: Option<TypeId>) {
+        ATTACHING.with(|a| *a.borrow_mut() = Some((self.clone(), ty)));
+        model.serialize(&mut Serializer::default());
+        ATTACHING.with(|a| *a.borrow_mut() = None);
+    }
+
+    fn capture<S: Shape>(&self, name: &str, tensor: GraphTensor<S>) {
+        self.names.borrow_mut().push(name.to_string());
+        if let Some(stats) = &self.stats {
+            stats.register(name, tensor);
+        }
+        let (values, key) = (self.values.clone(), name.to_string());
+        tensor.tap(format!("Hook-{name}"), move |data, shape| {
+            values
+                .borrow_mut()
+                .insert(key.clone(), (data.to_vec(), shape.to_vec()));
+        });
+    }
+
+    /// The data and shape a hooked tensor had in the last execution
+    pub fn get(&self, name: &str) -> Option<(Vec<f32>, Vec<usize>)> {
+        self.values.borrow().get(name).cloned()
+    }
+
+    /// Names of the hooked tensors, in forward order
+    pub fn names(&self) -> Vec<String> {
+        self.names.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{ForwardHooks, Hooked};
+    use crate::{
+        nn::{linear::Linear, transformer::attention::MultiHeadSelfAttention, Repeated},
+        prelude::Module,
+        tests::compare::compare_hooks,
+    };
+    crate::test_imports!();
+
+    type Attention = MultiHeadSelfAttention<8, 8, 8, 2>;
+    type Model = Repeated<(Hooked<Attention>, Hooked<Linear<8, 8>>), 2>;
+
+    /// Build the model with the same weights and input every time
+    fn build(
+        cx: &mut Graph,
+    ) -> (
+        Model,
+        ForwardHooks,
+        GraphTensor<R2<3, 8>>,
+        GraphTensor<R2<3, 8>>,
+    ) {
+        let mut rng = StdRng::seed_from_u64(0);
+        let model = Model::initialize(cx);
+        for (attention, linear) in &model.modules {
+            let a = &attention.module;
+            for weight in [
+                a.w_q.weight,
+                a.w_k.weight,
+                a.w_v.weight,
+                a.w_o.weight,
+                linear.module.weight,
+            ] {
+                weight.set(random_vec_rng(64, &mut rng));
+            }
+        }
+        let hooks = ForwardHooks::new().with_stats(StatsCollector::new());
+        hooks.attach::<Attention, _>(&model);
+        let input = cx.tensor::<R2<3, 8>>().set(random_vec_rng(24, &mut rng));
+        let out = model.forward(input).retrieve();
+        (model, hooks, input, out)
+    }
+
+    #[test]
+    fn test_hook_attention_outputs() {
+        let mut cx = Graph::new();
+        let (model, hooks, input, _) = build(&mut cx);
+        // Rerun the layers without hooks to get the expected values
+        let attn0 = model.modules[0].0.module.forward(input).retrieve();
+        let attn1 = model.modules[1]
+            .0
+            .module
+            .forward(model.modules[0].1.module.forward(attn0))
+            .retrieve();
+        cx.execute();
+
+        // Only the attention layers are hooked
+        assert_eq!(
+            hooks.names(),
+            vec![
+                "layer0/layer0/input",
+                "layer0/layer0/output",
+                "layer1/layer0/input",
+                "layer1/layer0/output"
+            ]
+        );
+        let (out0, shape) = hooks.get("layer0/layer0/output").unwrap();
+        assert_eq!(shape, vec![3, 8]);
+        assert_close(&out0, &attn0.data());
+        assert_close(&hooks.get("layer1/layer0/output").unwrap().0, &attn1.data());
+        assert!(hooks.get("layer0/layer1/output").is_none());
+        let stats = hooks.stats.as_ref().unwrap();
+        assert_eq!(stats.get("layer1/layer0/output").unwrap().count, 24);
+
+        // Hooked modules serialize like the modules they wrap
+        assert!(state_dict(&model).contains_key("layer1/layer0/w_q/weight"));
+    }
+
+    #[test]
+    fn test_compare_hooks() {
+        let mut cx = Graph::new();
+        let (_, reference, _, _) = build(&mut cx);
+        cx.execute();
+
+        let mut cx = Graph::new();
+        let (_, compiled, _, mut out) = build(&mut cx);
+        cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut out);
+        cx.execute();
+        assert!(compare_hooks(&reference, &compiled, 1e-4).is_none());
+
+        compiled
+            .values
+            .borrow_mut()
+            .get_mut("layer1/layer0/output")
+            .unwrap()
+            .0[5] += 1.;
+        let divergence = compare_hooks(&reference, &compiled, 1e-4).unwrap();
+        assert_eq!(divergence.name, "layer1/layer0/output");
+        assert_eq!(divergence.index, 5);
+    }
+}
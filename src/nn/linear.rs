@@ -1,31 +1,44 @@
 use rand::{thread_rng, Rng};
 
-use crate::prelude::*;
+use crate::prelude::{symbolic::Expression, *};
 
-/// A simple linear layer
+/// A simple linear layer, with an optional bias
 pub struct Linear<const A: usize, const B: usize> {
     pub weight: GraphTensor<R2<A, B>>,
+    pub bias: Option<GraphTensor<R1<B>>>,
 }
 
-impl<const A: usize, const B: usize> InitModule for Linear<A, B> {
-    fn initialize(cx: &mut Graph) -> Self {
-        let s = Self {
-            weight: cx.named_tensor("Weight"),
-        };
-        // Init weight as uniform(-1, 1)
+impl<const A: usize, const B: usize> Linear<A, B> {
+    /// Create a layer with weights (and a bias if `bias` is set) initialized as uniform(-1, 1).
+    ///
+    /// Without a bias no add is built, and the bias isn't serialized, so checkpoints don't need to hold one.
+    pub fn new(cx: &mut Graph, bias: bool) -> Self {
         let mut rng = thread_rng();
-        s.weight.set(
-            (0..(A * B))
+        let mut uniform = |n| {
+            (0..n)
                 .map(|_| rng.gen_range(-1_f32..1_f32))
-                .collect::<Vec<_>>(),
-        );
-        s
+                .collect::<Vec<_>>()
+        };
+        Self {
+            weight: cx.named_tensor("Weight").set(uniform(A * B)),
+            bias: bias.then(|| cx.named_tensor("Bias").set(uniform(B))),
+        }
+    }
+}
+
+impl<const A: usize, const B: usize> InitModule for Linear<A, B> {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self::new(cx, false)
     }
 }
 
 impl<const A: usize, const B: usize> SerializeModule for Linear<A, B> {
     fn serialize(&self, s: &mut crate::serialization::Serializer) {
         s.tensor("weight", self.weight);
+        match self.bias {
+            Some(bias) => s.tensor("bias", bias),
+            None => s.absent("bias"),
+        }
     }
 }
 
@@ -34,7 +47,11 @@ impl<const A: usize, const B: usize> Module<GraphTensor<R1<A>>> for Linear<A, B>
     type Output = GraphTensor<R1<B>>;
 
     fn forward(&self, input: GraphTensor<R1<A>>) -> Self::Output {
-        input.matmul(self.weight)
+        let output = input.matmul(self.weight);
+        match self.bias {
+            Some(bias) => output + bias,
+            None => output,
+        }
     }
 }
 
@@ -45,7 +62,41 @@ impl<const A: usize, const B: usize, C: Dimension> Module<GraphTensor<(C, Const<
     type Output = GraphTensor<(C, Const<B>)>;
 
     fn forward(&self, input: GraphTensor<(C, Const<A>)>) -> Self::Output {
-        input.matmul(self.weight)
+        self.add_bias(input.matmul(self.weight))
+    }
+}
+
+impl<const A: usize, const B: usize> Linear<A, B> {
+    /// Add the bias to an output, if the layer has one
+    fn add_bias<S: Dimension>(
+        &self,
+        output: GraphTensor<(S, Const<B>)>,
+    ) -> GraphTensor<(S, Const<B>)> {
+        match self.bias {
+            Some(bias) => output + bias.expand(),
+            None => output,
+        }
+    }
+
+    /// Apply the layer to the last dimension of an input of any rank, by folding the leading dimensions into the rows
+    /// of a single 2D matmul and unfolding them from the output. The fold is a view of contiguous inputs, other inputs
+    /// are made contiguous first.
+    fn forward_folded<S: Shape, O: Shape>(&self, input: GraphTensor<S>) -> GraphTensor<O> {
+        let input = input.contiguous();
+        let mut leading = input
+            .shape
+            .shape()
+            .into_iter()
+            .map(Expression::from)
+            .collect::<Vec<_>>();
+        leading.pop();
+        let rows = leading.iter().fold(Expression::from(1), |acc, d| acc * *d);
+        self.add_bias(
+            input
+                .dyn_reshape::<(Dyn<'-'>, Const<A>)>(vec![rows, A.into()])
+                .matmul(self.weight),
+        )
+        .dyn_reshape(leading.into_iter().chain([B.into()]).collect())
     }
 }
 
@@ -56,7 +107,7 @@ impl<const A: usize, const B: usize, C: Dimension, D: Dimension>
     type Output = GraphTensor<(C, D, Const<B>)>;
 
     fn forward(&self, input: GraphTensor<(C, D, Const<A>)>) -> Self::Output {
-        input.matmul(self.weight)
+        self.forward_folded(input)
     }
 }
 
@@ -67,14 +118,30 @@ impl<const A: usize, const B: usize, C: Dimension, D: Dimension, E: Dimension>
     type Output = GraphTensor<(C, D, E, Const<B>)>;
 
     fn forward(&self, input: GraphTensor<(C, D, E, Const<A>)>) -> Self::Output {
-        input.matmul(self.weight.expand())
+        self.forward_folded(input)
+    }
+}
+
+// 4x Batched
+impl<const A: usize, const B: usize, C: Dimension, D: Dimension, E: Dimension, F: Dimension>
+    Module<GraphTensor<(C, D, E, F, Const<A>)>> for Linear<A, B>
+{
+    type Output = GraphTensor<(C, D, E, F, Const<B>)>;
+
+    fn forward(&self, input: GraphTensor<(C, D, E, F, Const<A>)>) -> Self::Output {
+        self.forward_folded(input)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::Linear;
-    use crate::{prelude::*, tests::assert_close};
+    use crate::{
+        compilers::MatMul2D,
+        op::Contiguous,
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
     #[test]
     fn test_linear() {
         let mut cx = Graph::new();
@@ -98,4 +165,117 @@ mod tests {
         assert_close(&unoptimized_b, &b.data());
         assert_close(&unoptimized_batch_out, &batch_out.data());
     }
+
+    /// Reference linear layer over rows of the input
+    fn linear_rows(input: &[f32], weight: &[f32], a: usize, b: usize) -> Vec<f32> {
+        input
+            .chunks(a)
+            .flat_map(|row| {
+                (0..b).map(move |j| (0..a).map(|k| row[k] * weight[k * b + j]).sum::<f32>())
+            })
+            .collect()
+    }
+
+    fn count_ops<T: 'static>(cx: &Graph) -> usize {
+        cx.graph
+            .node_indices()
+            .filter(|n| cx.graph[*n].as_any().is::<T>())
+            .count()
+    }
+
+    #[test]
+    fn test_linear_leading_dims() {
+        let mut cx = Graph::new();
+        let model: Linear<3, 4> = Linear::initialize(&mut cx);
+        let weight = random_vec(12);
+        model.weight.set(weight.clone());
+        let data = random_vec(2 * 5 * 2 * 3);
+        let rank2 = cx.tensor::<R2<20, 3>>().set(data.clone());
+        let rank3 = cx.tensor::<R3<4, 5, 3>>().set(data.clone());
+        let rank4 = cx.tensor::<R4<2, 5, 2, 3>>().set(data.clone());
+        let mut out2 = model.forward(rank2).retrieve();
+        let mut out3 = model.forward(rank3).retrieve();
+        let mut out4 = model.forward(rank4).retrieve();
+        // Contiguous inputs fold without copying
+        assert_eq!(count_ops::<Contiguous>(&cx), 0);
+
+        // A permuted input needs one copy to fold
+        let permuted = cx
+            .tensor::<R3<5, 4, 3>>()
+            .set(data.clone())
+            .permute::<R3<4, 5, 3>, _>();
+        let mut out_permuted = model.forward(permuted).retrieve();
+        assert_eq!(count_ops::<Contiguous>(&cx), 1);
+
+        cx.compile(
+            GenericCompiler::default(),
+            (&mut out2, &mut out3, &mut out4, &mut out_permuted),
+        );
+        cx.execute();
+
+        let expected = linear_rows(&data, &weight, 3, 4);
+        assert_close(&out2.data(), &expected);
+        assert_close(&out3.data(), &expected);
+        assert_close(&out4.data(), &expected);
+        let permuted_data = (0..4)
+            .flat_map(|i| (0..5).flat_map(move |j| (0..3).map(move |k| (j * 4 + i) * 3 + k)))
+            .map(|i| data[i])
+            .collect::<Vec<_>>();
+        assert_close(
+            &out_permuted.data(),
+            &linear_rows(&permuted_data, &weight, 3, 4),
+        );
+    }
+
+    #[test]
+    fn test_linear_folds_into_matmul() {
+        let mut cx = Graph::new();
+        let model: Linear<3, 4> = Linear::initialize(&mut cx);
+        let input = cx.tensor::<(Dyn<'b'>, Dyn<'s'>, Const<3>)>();
+        let mut out = model.forward(input).retrieve();
+        cx.compile(CPUCompiler::default(), &mut out);
+        // Leading dims are folded into a single 2D matmul
+        assert_eq!(count_ops::<MatMul2D>(&cx), 1);
+
+        let data = random_vec(2 * 3 * 3);
+        let weight = random_vec(12);
+        model.weight.set(weight.clone());
+        input.set_dyn(data.clone(), &[2, 3, 3]);
+        cx.execute();
+        assert_eq!(out.shape.shape()[..2], input.shape.shape()[..2]);
+        assert_close(&out.data(), &linear_rows(&data, &weight, 3, 4));
+    }
+
+    #[test]
+    fn test_linear_optional_bias() {
+        let mut cx = Graph::new();
+        let unbiased: Linear<3, 4> = Linear::new(&mut cx, false);
+        let input = random_vec(2 * 3);
+        let a = cx.tensor::<R2<2, 3>>().set(input.clone());
+        let _ = unbiased.forward(a).retrieve();
+        // No add node is built for a missing bias
+        assert_eq!(count_ops::<crate::op::Add>(&cx), 0);
+
+        let biased: Linear<3, 4> = Linear::new(&mut cx, true);
+        let weight = random_vec(12);
+        let bias = random_vec(4);
+        biased.weight.set(weight.clone());
+        biased.bias.unwrap().set(bias.clone());
+        let b = cx.tensor::<R3<2, 1, 3>>().set(input.clone());
+        let c = cx.tensor::<R1<3>>().set(input[..3].to_vec());
+        let out = biased.forward(a).retrieve();
+        let out_folded = biased.forward(b).retrieve();
+        let out_single = biased.forward(c).retrieve();
+        assert_eq!(count_ops::<crate::op::Add>(&cx), 3);
+        cx.execute();
+
+        let expected = linear_rows(&input, &weight, 3, 4)
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| v + bias[i % 4])
+            .collect::<Vec<_>>();
+        assert_close(&out.data(), &expected);
+        assert_close(&out_folded.data(), &expected);
+        assert_close(&out_single.data(), &expected[..4]);
+    }
 }
@@ -0,0 +1,107 @@
+use crate::{op, prelude::*};
+
+/// How many logits of a row [`cross_entropy_fused`] computes at once
+pub const CROSS_ENTROPY_CHUNK_SIZE: usize = 4096;
+
+/// Mean cross entropy of the logits `hidden x lm_head_weight` against `targets`, without materializing the `[N, V]`
+/// logits.
+///
+/// Equivalent to `-log_softmax(hidden.matmul(lm_head_weight))[target]` averaged over rows, but the vocab is walked in
+/// chunks of [`CROSS_ENTROPY_CHUNK_SIZE`] with an online log-sum-exp, so only one chunk of logits is held at a time.
+/// `lm_head_weight` is laid out like a [`Linear`](crate::nn::linear::Linear) weight, and `targets` holds token indexes.
+#[track_caller]
+pub fn cross_entropy_fused<N: Dimension, V: Dimension, const D: usize>(
+    hidden: GraphTensor<(N, Const<D>)>,
+    lm_head_weight: GraphTensor<(Const<D>, V)>,
+    targets: GraphTensor<(N,)>,
+) -> GraphTensor<R0> {
+    let (hidden, lm_head_weight, targets) = (
+        hidden.contiguous(),
+        lm_head_weight.contiguous(),
+        targets.contiguous(),
+    );
+    let id = hidden
+        .graph()
+        .add_op(op::ChunkedCrossEntropy {
+            chunk_size: CROSS_ENTROPY_CHUNK_SIZE,
+        })
+        .input(hidden.id, 0, hidden.shape)
+        .input(lm_head_weight.id, 0, lm_head_weight.shape)
+        .input(targets.id, 0, targets.shape)
+        .finish();
+    GraphTensor::<(N,)>::from_id(id, targets.shape, hidden.graph_ref).mean_reduce::<_, Axis<0>>()
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+    use super::cross_entropy_fused;
+
+    #[test]
+    fn test_cross_entropy_fused() {
+        let mut cx = Graph::new();
+        // Spans a partial chunk so the online log-sum-exp merges chunks
+        const V: usize = 2 * super::CROSS_ENTROPY_CHUNK_SIZE + 100;
+        let hidden = cx.tensor::<R2<6, 8>>().set(random_vec(6 * 8));
+        // Scaled up so the logits are large enough to overflow a naive sum of exponentials
+        let weight = cx.tensor::<R2<8, V>>().set(
+            random_vec(8 * V)
+                .into_iter()
+                .map(|v| v * 30.)
+                .collect::<Vec<_>>(),
+        );
+        let target_data = [0, 5, 4096, 4095, 8291, 100];
+        let targets = cx
+            .tensor::<R1<6>>()
+            .set(target_data.map(|t| t as f32).to_vec());
+        let mut fused = cross_entropy_fused(hidden, weight, targets).retrieve();
+        // Naive log-sum-exp through the full logits
+        let mut logits = hidden.matmul(weight).retrieve();
+        let max = logits.max_reduce::<_, LAxis<1>>();
+        let mut lse = ((logits - max.expand())
+            .exp()
+            .sum_reduce::<_, LAxis<1>>()
+            .ln()
+            + max)
+            .retrieve();
+        cx.compile(
+            <(GenericCompiler, CPUCompiler)>::default(),
+            (&mut fused, &mut logits, &mut lse),
+        );
+        cx.execute();
+
+        let (logits, lse) = (logits.data(), lse.data());
+        let naive = target_data
+            .iter()
+            .enumerate()
+            .map(|(row, t)| lse[row] - logits[row * V + t])
+            .sum::<f32>()
+            / 6.;
+        assert!(naive.is_finite());
+        assert_close_precision(&fused.data(), &[naive], 2);
+    }
+
+    #[test]
+    fn test_cross_entropy_fused_memory() {
+        const N: usize = 64;
+        const V: usize = 32768;
+        let logits_bytes = N * V * std::mem::size_of::<f32>();
+        let mut cx = Graph::new();
+        let hidden = cx.tensor::<R2<N, 8>>().set(random_vec(N * 8));
+        let weight = cx.tensor::<R2<8, V>>().set(random_vec(8 * V));
+        let targets = cx
+            .tensor::<R1<N>>()
+            .set((0..N).map(|i| (i * 511) as f32).collect::<Vec<_>>());
+        let mut loss = cross_entropy_fused(hidden, weight, targets).retrieve();
+        cx.compile(<(GenericCompiler, CPUCompiler)>::default(), &mut loss);
+        let report = cx.execute_with_report();
+
+        assert!(loss.data()[0].is_finite());
+        // Only the inputs and per-row losses are ever allocated, never the logits
+        assert!(
+            report.peak_bytes < logits_bytes / 2,
+            "Peak of {} bytes, logits are {logits_bytes} bytes",
+            report.peak_bytes
+        );
+    }
+}
@@ -3,7 +3,9 @@ use crate::prelude::*;
 pub mod activation;
 pub mod convolution;
 pub mod embedding;
+pub mod hooks;
 pub mod linear;
+pub mod loss;
 pub mod norm;
 pub mod transformer;
 
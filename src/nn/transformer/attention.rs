@@ -2,6 +2,8 @@ use std::ops::Mul;
 
 use crate::{nn::linear::Linear, prelude::*};
 
+use super::rotary::{RopeConfig, RotaryEmbedding};
+
 // This is still single head attention because I need a runtime reshape, like the try_reshape in dfdx
 pub struct MultiHeadSelfAttention<
     const DIM: usize,
@@ -13,19 +15,41 @@ pub struct MultiHeadSelfAttention<
     pub w_k: Linear<DIM, K_DIM>,
     pub w_v: Linear<DIM, V_DIM>,
     pub w_o: Linear<V_DIM, DIM>,
+    /// Rotary embeddings applied to the queries and keys, counting positions from the start of each sequence
+    pub rope: Option<RotaryEmbedding>,
 }
 
 impl<const DIM: usize, const K_DIM: usize, const V_DIM: usize, const HEADS: usize> InitModule
     for MultiHeadSelfAttention<DIM, K_DIM, V_DIM, HEADS>
 {
     fn initialize(cx: &mut Graph) -> Self {
+        Self::new(cx, false)
+    }
+}
+
+impl<const DIM: usize, const K_DIM: usize, const V_DIM: usize, const HEADS: usize>
+    MultiHeadSelfAttention<DIM, K_DIM, V_DIM, HEADS>
+{
+    /// Create the attention, with biases on the q, k, v and o projections if `bias` is set
+    pub fn new(cx: &mut Graph, bias: bool) -> Self {
         Self {
-            w_q: InitModule::initialize(cx),
-            w_k: InitModule::initialize(cx),
-            w_v: InitModule::initialize(cx),
-            w_o: InitModule::initialize(cx),
+            w_q: Linear::new(cx, bias),
+            w_k: Linear::new(cx, bias),
+            w_v: Linear::new(cx, bias),
+            w_o: Linear::new(cx, bias),
+            rope: None,
         }
     }
+
+    /// Rotate the queries and keys of each head with rotary embeddings
+    pub fn with_rope(mut self, config: RopeConfig) -> Self {
+        self.rope = Some(RotaryEmbedding::new(
+            self.w_q.weight.graph(),
+            K_DIM / HEADS,
+            config,
+        ));
+        self
+    }
 }
 
 impl<const DIM: usize, const K_DIM: usize, const V_DIM: usize, const HEADS: usize> SerializeModule
@@ -138,6 +162,56 @@ impl<
             GraphTensor<(B, S1, Const<DIM>)>,
         ),
     ) -> Self::Output {
+        self.forward_masked(keys, queries, values, None)
+    }
+}
+
+// Batched self attention with a padding mask
+impl<
+        const DIM: usize,
+        const K_DIM: usize,
+        const V_DIM: usize,
+        const HEADS: usize,
+        S: Dimension,
+        B: Dimension,
+    > Module<(GraphTensor<(B, S, Const<DIM>)>, GraphTensor<(B, S)>)>
+    for MultiHeadSelfAttention<DIM, K_DIM, V_DIM, HEADS>
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (input, padding_mask): (GraphTensor<(B, S, Const<DIM>)>, GraphTensor<(B, S)>),
+    ) -> Self::Output {
+        self.forward_masked(input, input, input, Some(padding_mask))
+    }
+}
+
+impl<const DIM: usize, const K_DIM: usize, const V_DIM: usize, const HEADS: usize>
+    MultiHeadSelfAttention<DIM, K_DIM, V_DIM, HEADS>
+{
+    /// Attend the queries over the keys and values. The padding mask is 1 for real key tokens and 0 for padding,
+    /// which no query attends to. Without a mask every query attends to every key, and no mask is added at all.
+    pub fn forward_masked<B: Dimension, S1: Dimension, S2: Dimension>(
+        &self,
+        keys: GraphTensor<(B, S1, Const<DIM>)>,
+        queries: GraphTensor<(B, S2, Const<DIM>)>,
+        values: GraphTensor<(B, S1, Const<DIM>)>,
+        padding_mask: Option<GraphTensor<(B, S1)>>,
+    ) -> GraphTensor<(B, S2, Const<DIM>)> {
+        let mask = padding_mask.map(|m| Mask::from_float(m).expand::<_, Axis<1>>());
+        self.forward_with_mask(keys, queries, values, mask)
+    }
+
+    /// Attend the queries over the keys and values, where each query only attends to the keys its row of the mask
+    /// is true for, like a causal mask composed with a padding mask. Every query needs to see at least one key.
+    pub fn forward_with_mask<B: Dimension, S1: Dimension, S2: Dimension>(
+        &self,
+        keys: GraphTensor<(B, S1, Const<DIM>)>,
+        queries: GraphTensor<(B, S2, Const<DIM>)>,
+        values: GraphTensor<(B, S1, Const<DIM>)>,
+        mask: Option<Mask<(B, S2, S1)>>,
+    ) -> GraphTensor<(B, S2, Const<DIM>)> {
         let values = self
             .w_v
             .forward(values)
@@ -157,7 +231,7 @@ impl<
                 HEADS.into(),
                 (K_DIM / HEADS).into(),
             ])
-            .permute::<_, Axes4<0, 2, 3, 1>>();
+            .permute::<_, Axes4<0, 2, 1, 3>>();
         let queries = self
             .w_q
             .forward(queries)
@@ -168,11 +242,19 @@ impl<
                 (K_DIM / HEADS).into(),
             ])
             .permute::<_, Axes4<0, 2, 1, 3>>();
+        let (queries, keys) = match &self.rope {
+            Some(rope) => (rope.forward(queries, 0), rope.forward(keys, 0)),
+            None => (queries, keys),
+        };
 
-        let weights = queries
-            .matmul(keys)
-            .mul((1.0 / ((K_DIM / HEADS) as f64).sqrt()) as f32)
-            .softmax::<3>();
+        let mut weights = queries
+            .matmul(keys.permute::<_, Axes4<0, 1, 3, 2>>())
+            .mul((1.0 / ((K_DIM / HEADS) as f64).sqrt()) as f32);
+        if let Some(mask) = mask {
+            // Masked out keys get no weight after the softmax
+            weights += mask.to_additive().expand();
+        }
+        let weights = weights.softmax::<3>();
 
         let tokens: GraphTensor<(B, S2, Const<V_DIM>)> = weights
             .matmul(values)
@@ -186,7 +268,7 @@ impl<
 mod tests {
     use crate::{
         prelude::{Module, *},
-        tests::assert_close,
+        tests::{assert_close, random_vec},
     };
     use dfdx::prelude::{Module as DfdxModule, *};
 
@@ -275,4 +357,73 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_composed_mask_attention() {
+        use crate::shape::{Axes3 as LAxes3, Axis as LAxis, Const as LConst};
+        let mut cx = Graph::new();
+        let model: MultiHeadSelfAttention<4, 4, 4, 1> = InitModule::initialize(&mut cx);
+        let x = cx.tensor::<R3<2, 4, 4>>().set(random_vec(2 * 4 * 4));
+        let padding = cx
+            .tensor::<R2<2, 4>>()
+            .set(vec![1., 1., 1., 0., 1., 1., 0., 0.]);
+        // Compose the causal and padding masks before converting them once
+        let mask = cx.causal_mask::<LConst<4>>().expand::<_, LAxis<0>>()
+            & Mask::from_float(padding).expand::<_, LAxis<1>>();
+        let out = model.forward_with_mask(x, x, x, Some(mask)).retrieve();
+
+        // The same attention with a large negative float added for each mask
+        let q = model.w_q.forward(x);
+        let k = model.w_k.forward(x);
+        let v = model.w_v.forward(x);
+        let causal = cx.triu::<LConst<4>>(1) * -1e9;
+        let padding = (padding - 1.) * 1e9;
+        let weights = q.matmul(k.permute::<_, LAxes3<0, 2, 1>>()) * 0.5
+            + causal.expand::<(LConst<2>, LConst<4>, LConst<4>), _>()
+            + padding.expand::<(LConst<2>, LConst<4>, LConst<4>), LAxis<1>>();
+        let reference = model
+            .w_o
+            .forward(weights.softmax::<2>().matmul(v))
+            .retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &reference.data());
+    }
+
+    #[test]
+    fn test_rope_attention() {
+        use crate::{
+            nn::transformer::rotary::{RopeConfig, RopeScaling},
+            shape::{Axes3 as LAxes3, Axis as LAxis, Const as LConst},
+        };
+        let mut cx = Graph::new();
+        let config = RopeConfig {
+            theta: 10_000.,
+            scaling: RopeScaling::Linear { factor: 2. },
+        };
+        let model = MultiHeadSelfAttention::<4, 4, 4, 1>::new(&mut cx, false).with_rope(config);
+        let x = cx.tensor::<R3<2, 3, 4>>().set(random_vec(2 * 3 * 4));
+        let out = model.forward(x).retrieve();
+
+        // The same attention with the queries and keys rotated as a single head
+        let rope = model.rope.as_ref().unwrap();
+        let rotate = |t: GraphTensor<R3<2, 3, 4>>| {
+            rope.forward(
+                t.expand::<(LConst<2>, LConst<1>, LConst<3>, LConst<4>), LAxis<1>>(),
+                0,
+            )
+            .reshape::<R3<2, 3, 4>>()
+        };
+        let q = rotate(model.w_q.forward(x));
+        let k = rotate(model.w_k.forward(x));
+        let v = model.w_v.forward(x);
+        let weights = q.matmul(k.permute::<_, LAxes3<0, 2, 1>>()) * 0.5;
+        let reference = model
+            .w_o
+            .forward(weights.softmax::<2>().matmul(v))
+            .retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &reference.data());
+    }
 }
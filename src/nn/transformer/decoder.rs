@@ -3,7 +3,7 @@ use crate::{
     prelude::*,
 };
 
-use super::attention::MultiHeadSelfAttention;
+use super::{attention::MultiHeadSelfAttention, rotary::RopeConfig};
 
 /// A transformer decoder as layed out in *Attention Is All You Need*.
 pub struct TransformerDecoder<
@@ -102,12 +102,28 @@ impl<const DIM: usize, const FF: usize, const HEADS: usize> InitModule
     for TransformerDecoderBlock<DIM, FF, HEADS>
 {
     fn initialize(cx: &mut Graph) -> Self {
+        Self::new(cx, false, false)
+    }
+}
+
+impl<const DIM: usize, const FF: usize, const HEADS: usize>
+    TransformerDecoderBlock<DIM, FF, HEADS>
+{
+    /// Create the block, with biases on the attention projections and the feed forward layers if they're set
+    pub fn new(cx: &mut Graph, attention_bias: bool, ff_bias: bool) -> Self {
         Self {
-            cross_attention: InitModule::initialize(cx),
-            self_attention: InitModule::initialize(cx),
-            ff: InitModule::initialize(cx),
+            cross_attention: MultiHeadSelfAttention::new(cx, attention_bias),
+            self_attention: MultiHeadSelfAttention::new(cx, attention_bias),
+            ff: (Linear::new(cx, ff_bias), ReLU, Linear::new(cx, ff_bias)),
         }
     }
+
+    /// Use rotary embeddings in the self attention. The cross attention keys come from the encoder, so they keep
+    /// whatever positions it gave them.
+    pub fn with_rope(mut self, config: RopeConfig) -> Self {
+        self.self_attention = self.self_attention.with_rope(config);
+        self
+    }
 }
 
 impl<const DIM: usize, const FF: usize, const HEADS: usize> SerializeModule
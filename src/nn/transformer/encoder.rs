@@ -3,7 +3,7 @@ use crate::{
     prelude::*,
 };
 
-use super::attention::MultiHeadSelfAttention;
+use super::{attention::MultiHeadSelfAttention, rotary::RopeConfig};
 
 /// A transformer encoder as layed out in *Attention Is All You Need*.
 pub type TransformerEncoder<
@@ -23,11 +23,26 @@ impl<const DIM: usize, const FF: usize, const HEADS: usize> InitModule
     for TransformerEncoderBlock<DIM, FF, HEADS>
 {
     fn initialize(cx: &mut Graph) -> Self {
+        Self::new(cx, false, false)
+    }
+}
+
+impl<const DIM: usize, const FF: usize, const HEADS: usize>
+    TransformerEncoderBlock<DIM, FF, HEADS>
+{
+    /// Create the block, with biases on the attention projections and the feed forward layers if they're set
+    pub fn new(cx: &mut Graph, attention_bias: bool, ff_bias: bool) -> Self {
         Self {
-            attention: InitModule::initialize(cx),
-            ff: InitModule::initialize(cx),
+            attention: MultiHeadSelfAttention::new(cx, attention_bias),
+            ff: (Linear::new(cx, ff_bias), ReLU, Linear::new(cx, ff_bias)),
         }
     }
+
+    /// Use rotary embeddings in the attention
+    pub fn with_rope(mut self, config: RopeConfig) -> Self {
+        self.attention = self.attention.with_rope(config);
+        self
+    }
 }
 
 impl<const DIM: usize, const FF: usize, const HEADS: usize> SerializeModule
@@ -66,6 +81,63 @@ impl<const DIM: usize, const FF: usize, const HEADS: usize, S: Dimension, B: Dim
     }
 }
 
+// Batched with a padding mask, which is passed through so blocks can be repeated
+impl<const DIM: usize, const FF: usize, const HEADS: usize, S: Dimension, B: Dimension>
+    Module<(GraphTensor<(B, S, Const<DIM>)>, GraphTensor<(B, S)>)>
+    for TransformerEncoderBlock<DIM, FF, HEADS>
+{
+    type Output = (GraphTensor<(B, S, Const<DIM>)>, GraphTensor<(B, S)>);
+
+    fn forward(
+        &self,
+        (x, padding_mask): (GraphTensor<(B, S, Const<DIM>)>, GraphTensor<(B, S)>),
+    ) -> Self::Output {
+        let y = self.attention.forward((x, padding_mask));
+        let x = (x + y).layer_norm::<2, _>(1e-5);
+        let y = self.ff.forward(x);
+        ((x + y).layer_norm::<2, _>(1e-5), padding_mask)
+    }
+}
+
+/// Average the encoded tokens of each sequence into one embedding, to put a classification head on top of an encoder.
+///
+/// With a padding mask (1 for real tokens, 0 for padding) only the real tokens are averaged.
+#[derive(Default)]
+pub struct MeanPool;
+
+impl InitModule for MeanPool {
+    fn initialize(_: &mut Graph) -> Self {
+        Self
+    }
+}
+
+impl SerializeModule for MeanPool {
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl<const DIM: usize, S: Dimension, B: Dimension> Module<GraphTensor<(B, S, Const<DIM>)>>
+    for MeanPool
+{
+    type Output = GraphTensor<(B, Const<DIM>)>;
+
+    fn forward(&self, x: GraphTensor<(B, S, Const<DIM>)>) -> Self::Output {
+        x.mean_reduce()
+    }
+}
+
+impl<const DIM: usize, S: Dimension, B: Dimension>
+    Module<(GraphTensor<(B, S, Const<DIM>)>, GraphTensor<(B, S)>)> for MeanPool
+{
+    type Output = GraphTensor<(B, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (x, padding_mask): (GraphTensor<(B, S, Const<DIM>)>, GraphTensor<(B, S)>),
+    ) -> Self::Output {
+        (x * padding_mask.expand()).sum_reduce() / padding_mask.sum_reduce::<_, Axis<1>>().expand()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use dfdx::{
@@ -76,10 +148,11 @@ mod tests {
 
     use crate::{
         prelude::{Module, *},
-        tests::assert_close,
+        tests::{assert_close, random_vec},
     };
 
-    use super::TransformerEncoderBlock;
+    use super::{MeanPool, TransformerEncoderBlock};
+
     #[test]
     fn test_transformer_encoder_block() {
         let mut cx = Graph::new();
@@ -184,4 +257,49 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_encoder_padding_mask() {
+        let mut cx = Graph::new();
+        let encoder: super::TransformerEncoder<4, 8, 2, 2> = InitModule::initialize(&mut cx);
+        let tokens = random_vec(3 * 4);
+        let padding = random_vec(2 * 4);
+        let x = cx.tensor::<R3<1, 3, 4>>().set(tokens.clone());
+        let padded_x = cx.tensor::<R3<1, 5, 4>>().set([tokens, padding].concat());
+        let mask = cx.tensor::<R2<1, 5>>().set(vec![1., 1., 1., 0., 0.]);
+        let out = encoder.forward(x).retrieve();
+        let pooled = MeanPool.forward(out).retrieve();
+        let (padded_out, _) = encoder.forward((padded_x, mask));
+        let padded_out = padded_out.retrieve();
+        let padded_pooled = MeanPool.forward((padded_out, mask)).retrieve();
+        cx.execute();
+
+        // The padding tokens don't change the real tokens or the pooled embedding
+        assert_close(&padded_out.data()[..3 * 4], &out.data());
+        assert_close(&padded_pooled.data(), &pooled.data());
+    }
+
+    #[test]
+    fn test_encoder_no_mask_ops() {
+        // The padding mask is the only thing taking a log, so without one there's no mask to add
+        let has_mask = |masked: bool| {
+            let mut cx = Graph::new();
+            let encoder: super::TransformerEncoder<4, 8, 2, 2> = InitModule::initialize(&mut cx);
+            let x = cx.tensor::<R3<1, 5, 4>>();
+            let mut out = if masked {
+                let mask = cx.tensor::<R2<1, 5>>();
+                encoder.forward((x, mask)).0.retrieve()
+            } else {
+                encoder.forward(x).retrieve()
+            };
+            cx.compile(GenericCompiler::default(), &mut out);
+            let has_log = cx
+                .graph
+                .node_weights()
+                .any(|op| op.as_any().is::<crate::op::Log2>());
+            has_log
+        };
+        assert!(!has_mask(false));
+        assert!(has_mask(true));
+    }
 }
@@ -3,6 +3,8 @@ use crate::prelude::*;
 pub mod attention;
 pub mod decoder;
 pub mod encoder;
+pub mod rolling_cache;
+pub mod rotary;
 
 pub struct Transformer<
     const DIM: usize,
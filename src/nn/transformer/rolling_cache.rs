@@ -0,0 +1,199 @@
+use crate::{prelude::*, shape::symbolic::BigExpression};
+
+/// A bounded-memory KV cache holding the keys and values of the last W positions in a ring buffer.
+///
+/// The entry for absolute position `p` lives in slot `p % W`, so once the cache is full new entries overwrite the oldest.
+/// Keys should have rotary embeddings applied with their absolute position before being cached, not their slot.
+///
+/// Each step the cache inputs are set to the cache outputs of the previous step (zeros before the first step), and
+/// the new tokens attend to the window of positions `(p - W, p]` before them, giving sliding window attention of
+/// unbounded length. Positions are computed in f32, so they're exact up to 2^24.
+pub struct RollingCache<B: Dimension, H: Dimension, const W: usize, const D: usize> {
+    pub keys: GraphTensor<(B, H, Const<W>, Const<D>)>,
+    pub values: GraphTensor<(B, H, Const<W>, Const<D>)>,
+}
+
+impl<B: Dimension, H: Dimension, const W: usize, const D: usize> Clone
+    for RollingCache<B, H, W, D>
+{
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B: Dimension, H: Dimension, const W: usize, const D: usize> Copy for RollingCache<B, H, W, D> {}
+
+impl<B: Dimension, H: Dimension, const W: usize, const D: usize> RollingCache<B, H, W, D> {
+    /// Create cache inputs on the graph
+    pub fn new(cx: &mut Graph) -> Self {
+        Self {
+            keys: cx.named_tensor("Rolling Key Cache"),
+            values: cx.named_tensor("Rolling Value Cache"),
+        }
+    }
+
+    /// Attend a chunk of S new tokens, starting at absolute position `pos`, over themselves and the cached window.
+    ///
+    /// T is the length of the cache plus the chunk, W + S. Chunks can be at most W long. Returns the attention
+    /// output and the cache with the chunk written into it.
+    pub fn attend<S: Dimension, T: Dimension>(
+        self,
+        queries: GraphTensor<(B, H, S, Const<D>)>,
+        keys: GraphTensor<(B, H, S, Const<D>)>,
+        values: GraphTensor<(B, H, S, Const<D>)>,
+        pos: BigExpression,
+    ) -> (GraphTensor<(B, H, S, Const<D>)>, Self) {
+        // Attend over the cache before the chunk is written, so nothing the chunk needs has been overwritten yet
+        let all_keys = self
+            .keys
+            .concat_along::<(B, H, T, Const<D>), Axis<2>, _>(keys);
+        let all_values = self
+            .values
+            .concat_along::<(B, H, T, Const<D>), Axis<2>, _>(values);
+        let weights = queries.matmul(all_keys.permute::<_, Axes4<0, 1, 3, 2>>())
+            / (D as f32).sqrt()
+            + Self::attention_mask::<S, T>(queries.graph(), pos.clone())
+                .to_additive()
+                .expand();
+        let output = weights.softmax::<3>().matmul(all_values);
+
+        (output, self.update(keys, values, pos))
+    }
+
+    /// A mask letting each token in a chunk starting at `pos` see itself, earlier tokens in the chunk,
+    /// and cache slots written within the last W positions before it
+    pub fn attention_mask<S: Dimension, T: Dimension>(
+        cx: &mut Graph,
+        pos: BigExpression,
+    ) -> Mask<(S, T)> {
+        // Age of the entry in each slot, 1 for the latest position before the chunk and W for the oldest
+        let slots = cx.arange::<Const<W>>();
+        let age = ((-slots + pos.clone() + (W - 1) as f32) % W as f32) + 1.;
+        // Slots are only filled once a position has been written to them
+        let filled = slots.is_lt(cx.constant_expr(pos).expand());
+        // Token i of the chunk can see entries less than W - i positions old
+        let chunk = cx.arange::<S>();
+        let cache_visible = (age.expand::<(S, Const<W>), _>() + chunk.expand())
+            .is_lt(cx.constant(W as f32).expand())
+            & filled.expand();
+        let chunk_visible = cx.causal_mask::<S>();
+        Mask::from_float(
+            cache_visible
+                .to_float()
+                .concat_along::<(S, T), Axis<1>, _>(chunk_visible.to_float()),
+        )
+    }
+
+    /// Write a chunk of S keys and values starting at absolute position `pos` into the cache, wrapping around
+    pub fn update<S: Dimension>(
+        self,
+        keys: GraphTensor<(B, H, S, Const<D>)>,
+        values: GraphTensor<(B, H, S, Const<D>)>,
+        pos: BigExpression,
+    ) -> Self {
+        // Which slot each token of the chunk is written to
+        let cx = keys.graph();
+        let write_slots = (cx.arange::<S>() + pos) % W as f32;
+        let writes = cx
+            .arange::<Const<W>>()
+            .expand::<(S, Const<W>), _>()
+            .equals(write_slots.expand());
+        let kept = -writes.sum_reduce::<_, Axis<0>>() + 1.;
+        let scatter = writes
+            .permute::<(Const<W>, S), _>()
+            .expand::<(B, H, _, _), _>();
+        Self {
+            keys: (self.keys * kept.expand() + scatter.matmul(keys)).contiguous(),
+            values: (self.values * kept.expand() + scatter.matmul(values)).contiguous(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    crate::test_imports!();
+
+    use super::RollingCache;
+
+    const W: usize = 64;
+    const D: usize = 4;
+
+    /// Sliding window attention for a token at `pos`, rebuilding the window from the full history
+    fn reference_attention(
+        q: &[f32],
+        keys: &[Vec<f32>],
+        values: &[Vec<f32>],
+        pos: usize,
+    ) -> Vec<f32> {
+        let window = (pos + 1).saturating_sub(W)..=pos;
+        let scores = window
+            .clone()
+            .map(|j| q.iter().zip(&keys[j]).map(|(a, b)| a * b).sum::<f32>() / (D as f32).sqrt())
+            .collect::<Vec<_>>();
+        let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let exp = scores.iter().map(|s| (s - max).exp()).collect::<Vec<_>>();
+        let sum = exp.iter().sum::<f32>();
+        let mut out = vec![0.; D];
+        for (e, j) in exp.iter().zip(window) {
+            for (o, v) in out.iter_mut().zip(&values[j]) {
+                *o += e / sum * v;
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_rolling_cache_generation() {
+        let mut cx = Graph::new();
+        let cache = RollingCache::<LConst<1>, LConst<1>, W, D>::new(&mut cx);
+        cache.keys.set(vec![0.; W * D]);
+        cache.values.set(vec![0.; W * D]);
+        let q = cx.named_tensor::<(LConst<1>, LConst<1>, Dyn<'s'>, LConst<D>)>("Queries");
+        let k = cx.named_tensor::<(LConst<1>, LConst<1>, Dyn<'s'>, LConst<D>)>("Keys");
+        let v = cx.named_tensor::<(LConst<1>, LConst<1>, Dyn<'s'>, LConst<D>)>("Values");
+        let (out, new_cache) = cache.attend::<_, Dyn<'t'>>(q, k, v, 'p'.into());
+        let out = out.retrieve();
+        new_cache.keys.keep();
+        new_cache.values.keep();
+
+        // Prefill a chunk, then decode token by token well past the window, with a chunk across the wrap point
+        let mut rng = StdRng::seed_from_u64(0);
+        let (mut all_keys, mut all_values) = (vec![], vec![]);
+        let mut pos = 0;
+        let chunks = [5]
+            .into_iter()
+            .chain([1; 2 * W - 10])
+            .chain([7])
+            .chain([1; 20]);
+        for chunk in chunks {
+            let q_data = random_vec_rng(chunk * D, &mut rng);
+            let k_data = random_vec_rng(chunk * D, &mut rng);
+            let v_data = random_vec_rng(chunk * D, &mut rng);
+            all_keys.extend(k_data.chunks(D).map(|c| c.to_vec()));
+            all_values.extend(v_data.chunks(D).map(|c| c.to_vec()));
+            q.set_dyn(q_data.clone(), &[1, 1, chunk, D]);
+            k.set_dyn(k_data, &[1, 1, chunk, D]);
+            v.set_dyn(v_data, &[1, 1, chunk, D]);
+            cx.set_dyn_dim('p', pos);
+            cx.set_dyn_dim('t', W + chunk);
+            cx.execute();
+
+            let expected = q_data
+                .chunks(D)
+                .enumerate()
+                .flat_map(|(i, q)| reference_attention(q, &all_keys, &all_values, pos + i))
+                .collect::<Vec<_>>();
+            assert_close(&out.data(), &expected);
+            out.drop();
+
+            // The new cache becomes the input to the next step
+            transfer_data_same_graph(
+                vec![new_cache.keys.id, new_cache.values.id],
+                vec![cache.keys.id, cache.values.id],
+                &mut cx,
+            );
+            pos += chunk;
+        }
+    }
+}
@@ -0,0 +1,441 @@
+use std::{fmt::Display, rc::Rc};
+
+use regex::Regex;
+
+use crate::{
+    op::Function,
+    prelude::{
+        symbolic::{BigExpression, Expression},
+        *,
+    },
+};
+
+/// How rotary embeddings are stretched to run past the context a model was trained on
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RopeScaling {
+    #[default]
+    None,
+    /// Position interpolation: positions are divided by the factor
+    Linear { factor: f32 },
+    /// NTK-aware scaling: the base becomes `theta * factor^(dim / (dim - 2))`, so the lowest frequencies are
+    /// interpolated the most and the highest barely change
+    Ntk { factor: f32 },
+    /// YaRN: frequencies that rotate more than `beta_fast` times over the original context are kept, ones that rotate
+    /// less than `beta_slow` times are interpolated, and ones between are blended. Attention is sharpened by
+    /// `0.1 * ln(factor) + 1`.
+    Yarn {
+        factor: f32,
+        original_max_position: usize,
+        beta_fast: f32,
+        beta_slow: f32,
+    },
+}
+
+impl RopeScaling {
+    /// How much longer the context is than the original one, 1 without scaling
+    pub fn factor(&self) -> f32 {
+        match self {
+            RopeScaling::None => 1.,
+            RopeScaling::Linear { factor }
+            | RopeScaling::Ntk { factor }
+            | RopeScaling::Yarn { factor, .. } => *factor,
+        }
+    }
+}
+
+/// Rotary embedding settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RopeConfig {
+    /// Base of the frequencies
+    pub theta: f32,
+    pub scaling: RopeScaling,
+}
+
+impl Default for RopeConfig {
+    fn default() -> Self {
+        Self {
+            theta: 10_000.,
+            scaling: RopeScaling::None,
+        }
+    }
+}
+
+/// Why a config's rotary embedding settings couldn't be read
+#[derive(Debug, Clone, PartialEq)]
+pub enum RopeConfigError {
+    /// A `rope_scaling` type other than linear, ntk or yarn
+    UnsupportedType(String),
+    /// A field the scaling type needs is missing
+    MissingField(&'static str),
+    /// A field isn't a number
+    InvalidValue { field: String, value: String },
+}
+
+impl Display for RopeConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RopeConfigError::UnsupportedType(t) => write!(f, "Unsupported rope scaling type {t}"),
+            RopeConfigError::MissingField(field) => write!(f, "Rope scaling is missing {field}"),
+            RopeConfigError::InvalidValue { field, value } => {
+                write!(f, "Rope scaling field {field} has invalid value {value}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RopeConfigError {}
+
+impl RopeConfig {
+    /// Read `rope_theta` and `rope_scaling` from a Hugging Face `config.json`. YaRN's original context defaults to
+    /// `max_position_embeddings`, like in `transformers`.
+    pub fn from_hf_config(config: &str) -> Result<Self, RopeConfigError> {
+        let field = Regex::new(r#""(\w+)"\s*:\s*("[^"]*"|[-+0-9.eE]+|null|true|false)"#).unwrap();
+        let fields = |s: &str| {
+            field
+                .captures_iter(s)
+                .map(|c| (c[1].to_string(), c[2].trim_matches('"').to_string()))
+                .collect::<Vec<_>>()
+        };
+        let number = |fields: &[(String, String)], name: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(k, v)| {
+                    v.parse::<f32>().map_err(|_| RopeConfigError::InvalidValue {
+                        field: k.clone(),
+                        value: v.clone(),
+                    })
+                })
+                .transpose()
+        };
+        // The scaling is a flat object, so it ends at the first closing brace
+        let scaling = Regex::new(r#""rope_scaling"\s*:\s*\{([^}]*)\}"#)
+            .unwrap()
+            .captures(config)
+            .map(|c| fields(&c[1]));
+        let outer = fields(
+            &Regex::new(r#""rope_scaling"\s*:\s*\{[^}]*\}"#)
+                .unwrap()
+                .replace(config, ""),
+        );
+
+        let theta = number(&outer, "rope_theta")?.unwrap_or(10_000.);
+        let Some(scaling) = scaling else {
+            return Ok(Self {
+                theta,
+                scaling: RopeScaling::None,
+            });
+        };
+        let ty = scaling
+            .iter()
+            .find(|(k, _)| k == "rope_type" || k == "type")
+            .map(|(_, v)| v.as_str())
+            .ok_or(RopeConfigError::MissingField("type"))?;
+        if ty == "default" {
+            return Ok(Self {
+                theta,
+                scaling: RopeScaling::None,
+            });
+        }
+        let factor = number(&scaling, "factor")?.ok_or(RopeConfigError::MissingField("factor"))?;
+        let scaling = match ty {
+            "linear" => RopeScaling::Linear { factor },
+            "ntk" => RopeScaling::Ntk { factor },
+            "yarn" => RopeScaling::Yarn {
+                factor,
+                original_max_position: number(&scaling, "original_max_position_embeddings")?
+                    .or(number(&outer, "max_position_embeddings")?)
+                    .ok_or(RopeConfigError::MissingField(
+                        "original_max_position_embeddings",
+                    ))? as usize,
+                beta_fast: number(&scaling, "beta_fast")?.unwrap_or(32.),
+                beta_slow: number(&scaling, "beta_slow")?.unwrap_or(1.),
+            },
+            _ => return Err(RopeConfigError::UnsupportedType(ty.to_string())),
+        };
+        Ok(Self { theta, scaling })
+    }
+}
+
+/// Rotary position embeddings, rotating the first half of each head against the second half like Hugging Face's
+/// `rotate_half`.
+///
+/// The frequencies are computed in the graph from the scaling factor, which is an input tensor, so a model can switch
+/// factors without being rebuilt. The frequencies that don't depend on the factor are computed on the host when the
+/// embedding is created.
+pub struct RotaryEmbedding {
+    pub head_dim: usize,
+    pub config: RopeConfig,
+    /// The scaling factor, starting at the config's. Set it with [`RotaryEmbedding::set_factor`].
+    pub factor: GraphTensor<R0>,
+}
+
+impl RotaryEmbedding {
+    pub fn new(cx: &mut Graph, head_dim: usize, config: RopeConfig) -> Self {
+        assert!(
+            head_dim.is_multiple_of(2) && head_dim > 2,
+            "Rotary embeddings need an even head dim larger than 2, not {head_dim}"
+        );
+        Self {
+            head_dim,
+            config,
+            factor: cx
+                .named_tensor("RoPE Factor")
+                .set(vec![config.scaling.factor()]),
+        }
+    }
+
+    /// Change the scaling factor, which takes effect on the next execution. Has no effect without scaling.
+    pub fn set_factor(&self, factor: f32) {
+        self.factor.set(vec![factor]);
+    }
+
+    /// Rotate a (batch, heads, seq, head dim) tensor, where the first position of the sequence is `prev_seq`. The
+    /// heads and head dim can be placeholders, like after a dynamic reshape.
+    pub fn forward<B: Dimension, H: Dimension, S: Dimension, D: Dimension>(
+        &self,
+        x: GraphTensor<(B, H, S, D)>,
+        prev_seq: impl Into<BigExpression>,
+    ) -> GraphTensor<(B, H, S, D)> {
+        let shape = x.shape.shape();
+        assert_eq!(
+            shape[3].to_usize(),
+            Some(self.head_dim),
+            "Expected a head dim of {}",
+            self.head_dim
+        );
+        let (sin, cos) = self.sin_cos::<S, D>(shape[2].clone().into(), prev_seq.into());
+        let broadcast = |mut t: GraphTensor<(S, D)>| {
+            t.shape.expand(0, shape[0].clone().into());
+            t.shape.expand(1, shape[1].clone().into());
+            GraphTensor::<(B, H, S, D)>::from_id(t.id, t.shape, t.graph_ref)
+        };
+        let half = Expression::from(self.head_dim / 2);
+        let x1 = x.slice((.., .., .., ..half)).contiguous();
+        let x2 = x.slice((.., .., .., half..)).contiguous();
+        let mut rotated = (-x2).concat_along::<(B, H, S, D), Axis<3>, _>(x1);
+        // Placeholder dims are resolved from the unpadded halves when they're added, so take the shape from x
+        rotated.shape = x.shape.contiguous();
+        rotated * broadcast(sin) + x * broadcast(cos)
+    }
+
+    /// The sin and cos of each position's angles, scaled by the attention factor
+    fn sin_cos<S: Dimension, D: Dimension>(
+        &self,
+        seq: Expression,
+        prev_seq: BigExpression,
+    ) -> SinCos<S, D> {
+        let cx = self.factor.graph();
+        let (dim, half) = (self.head_dim, self.head_dim / 2);
+        // Each pair of the head's halves shares a frequency
+        let per_dim = |f: &dyn Fn(usize) -> f32| (0..dim).map(|i| f(i % half)).collect::<Vec<_>>();
+        let inv_freq = per_dim(&|i| self.config.theta.powf(-2. * i as f32 / dim as f32));
+        let expand = |mut t: GraphTensor<R0>| {
+            t.shape.expand(0, dim.into());
+            GraphTensor::<(D,)>::from_id(t.id, t.shape, t.graph_ref)
+        };
+
+        let inv_freq = host_vector::<D>(cx, "RoPE Inverse Frequencies", inv_freq);
+        let (inv_freq, attention_factor) = match self.config.scaling {
+            RopeScaling::None => (inv_freq, None),
+            RopeScaling::Linear { .. } => (inv_freq * expand(self.factor.recip()), None),
+            RopeScaling::Ntk { .. } => {
+                // theta^(-2i / dim) becomes theta^(-2i / dim) * factor^(-2i / (dim - 2))
+                let exponents = per_dim(&|i| -2. * i as f32 / (dim - 2) as f32);
+                let exponents = host_vector::<D>(cx, "RoPE NTK Exponents", exponents);
+                let scale = (expand(self.factor.log2()) * exponents).exp2();
+                (inv_freq * scale, None)
+            }
+            RopeScaling::Yarn {
+                original_max_position,
+                beta_fast,
+                beta_slow,
+                ..
+            } => {
+                // How much of each frequency is kept as is, instead of interpolated
+                let correction_dim = |rotations: f32| {
+                    dim as f32
+                        * (original_max_position as f32 / (rotations * 2. * std::f32::consts::PI))
+                            .ln()
+                        / (2. * self.config.theta.ln())
+                };
+                let low = correction_dim(beta_fast).floor().max(0.);
+                let mut high = correction_dim(beta_slow).ceil().min(dim as f32 - 1.);
+                if low == high {
+                    high += 0.001;
+                }
+                let ramp = per_dim(&|i| ((i as f32 - low) / (high - low)).clamp(0., 1.));
+                let extrapolation = ramp.iter().map(|r| 1. - r).collect();
+                let extrapolation = host_vector::<D>(cx, "RoPE YaRN Extrapolation", extrapolation);
+                let interpolation = host_vector::<D>(cx, "RoPE YaRN Interpolation", ramp)
+                    * expand(self.factor.recip());
+                let attention_factor = (self.factor.ln() * 0.1 + 1.).max_f32(1.);
+                (
+                    inv_freq * (extrapolation + interpolation),
+                    Some(attention_factor),
+                )
+            }
+        };
+
+        let mut positions = cx.arange::<S>() + prev_seq;
+        positions.shape.expand(1, dim.into());
+        let mut inv_freq = inv_freq;
+        inv_freq.shape.expand(0, seq);
+        let angles = GraphTensor::<(S, D)>::from_id(positions.id, positions.shape, cx)
+            * GraphTensor::<(S, D)>::from_id(inv_freq.id, inv_freq.shape, cx);
+        let (mut sin, mut cos) = (angles.sin(), angles.cos());
+        if let Some(mut factor) = attention_factor {
+            factor.shape.expand(0, seq);
+            factor.shape.expand(1, dim.into());
+            let factor = GraphTensor::<(S, D)>::from_id(factor.id, factor.shape, cx);
+            sin *= factor;
+            cos *= factor;
+        }
+        (sin, cos)
+    }
+}
+
+/// The sin and cos of each position's angles
+type SinCos<S, D> = (GraphTensor<(S, D)>, GraphTensor<(S, D)>);
+
+/// A vector computed on the host, along a dimension that may be a placeholder
+fn host_vector<D: Dimension>(cx: &mut Graph, name: &str, data: Vec<f32>) -> GraphTensor<(D,)> {
+    let n = data.len();
+    let id = cx
+        .add_op(Function(
+            format!("{name} Load"),
+            Rc::new(move |_| vec![Tensor::new(data.clone())]),
+        ))
+        .finish();
+    GraphTensor::from_id(id, ShapeTracker::new(&[n.into()]), cx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RopeConfig, RopeConfigError, RopeScaling, RotaryEmbedding};
+    crate::test_imports!();
+
+    const X: [f32; 8] = [0.5, -1.0, 0.25, 2.0, -0.75, 1.5, 1.0, -0.5];
+
+    /// Rotate the same head at positions 2, 3, 4095, 4096, 16382 and 16383, past a 4096 token training context
+    fn rotate(config: RopeConfig, new_factor: Option<f32>) -> Vec<f32> {
+        let mut cx = Graph::new();
+        let rope = RotaryEmbedding::new(&mut cx, 8, config);
+        let x = cx.tensor::<R4<1, 1, 2, 8>>().set([X, X].concat());
+        let outs = [2usize, 4095, 16382].map(|p| rope.forward(x, p).retrieve());
+        cx.execute();
+        if let Some(factor) = new_factor {
+            // The same graph, executed with another factor
+            rope.set_factor(factor);
+            cx.drop_tensors(outs.to_vec());
+            cx.execute();
+        }
+        outs.iter().flat_map(|o| o.data()).collect()
+    }
+
+    // References from Hugging Face's rope initialization functions, with a head dim of 8, theta 10000 and a factor of
+    // 4. NTK-aware scaling follows the original formula for the base.
+    const REFERENCE_NONE: [f32; 48] = [
+        0.4739, -1.2781, 0.2300, 2.0010, 0.7668, 1.2714, 1.0048, -0.4960, -0.3892, -1.3986, 0.2199,
+        2.0015, 0.8131, 1.1375, 1.0070, -0.4940, -0.7814, -1.7918, -0.1394, -1.5655, -0.4494,
+        -0.1982, -1.0213, -1.3413, -0.0440, -1.7631, -0.1292, -1.5642, -0.9003, -0.3761, -1.0226,
+        -1.3429, 0.6576, 1.6253, -0.2171, -1.8747, 0.6165, 0.7800, 1.0077, -0.8576, -0.1634,
+        1.5393, -0.2272, -1.8738, 0.8864, 0.9383, 1.0054, -0.8595,
+    ];
+    const REFERENCE_LINEAR: [f32; 48] = [
+        0.7984, -1.0737, 0.2450, 2.0002, -0.4185, 1.4481, 1.0012, -0.4990, 0.8771, -1.1096, 0.2425,
+        2.0004, -0.2079, 1.4209, 1.0018, -0.4985, 0.1603, -1.1745, 0.5543, 1.4674, -0.8870,
+        -1.3677, -0.8691, 1.4480, 0.3748, -1.1399, 0.5565, 1.4670, -0.8198, -1.3966, -0.8677,
+        1.4484, -0.4702, -1.7797, -0.1343, -1.5648, -0.7690, -0.2875, -1.0220, -1.3421, -0.2654,
+        -1.7720, -0.1318, -1.5645, -0.8614, -0.3319, -1.0223, -1.3425,
+    ];
+    const REFERENCE_NTK: [f32; 48] = [
+        0.4739, -1.1806, 0.2421, 2.0002, 0.7668, 1.3625, 1.0020, -0.4990, -0.3892, -1.2640, 0.2381,
+        2.0004, 0.8131, 1.2854, 1.0029, -0.4985, -0.7814, -1.4625, 0.3027, 1.4674, -0.4494, 1.0542,
+        -0.9853, 1.4480, -0.0440, -1.5259, 0.3066, 1.4670, -0.9003, 0.9600, -0.9841, 1.4484,
+        0.6576, -1.5117, -0.9631, -1.5648, 0.6165, -0.9821, -0.3673, -1.3421, -0.1634, -1.4469,
+        -0.9616, -1.5645, 0.8864, -1.0754, -0.3712, -1.3425,
+    ];
+    const REFERENCE_YARN: [f32; 48] = [
+        0.5396, -1.4552, 0.2704, 2.2775, 0.8731, 1.4477, 1.1421, -0.5682, -0.4431, -1.5925, 0.2633,
+        2.2777, 0.9258, 1.2952, 1.1438, -0.5676, -0.8897, -2.0403, -0.2516, 1.6708, -0.5117,
+        -0.2257, 1.1464, 1.6488, -0.0501, -2.0075, -0.2587, 1.6704, -1.0251, -0.4282, 1.1448,
+        1.6492, 0.7488, 1.8506, -1.1727, -1.7818, 0.7019, 0.8881, -0.0478, -1.5282, -0.1861,
+        1.7527, -1.1724, -1.7814, 1.0093, 1.0684, -0.0552, -1.5286,
+    ];
+
+    #[test]
+    fn test_rope_scaling_references() {
+        let config = |scaling| RopeConfig {
+            theta: 10_000.,
+            scaling,
+        };
+        let yarn = RopeScaling::Yarn {
+            factor: 4.,
+            original_max_position: 4096,
+            beta_fast: 32.,
+            beta_slow: 1.,
+        };
+        assert_close(&rotate(config(RopeScaling::None), None), &REFERENCE_NONE);
+        assert_close(
+            &rotate(config(RopeScaling::Linear { factor: 4. }), None),
+            &REFERENCE_LINEAR,
+        );
+        assert_close(
+            &rotate(config(RopeScaling::Ntk { factor: 4. }), None),
+            &REFERENCE_NTK,
+        );
+        assert_close(&rotate(config(yarn), None), &REFERENCE_YARN);
+
+        // A factor of 1 turns every variant off, and the factor changes without rebuilding the graph
+        assert_close(
+            &rotate(config(RopeScaling::Linear { factor: 1. }), None),
+            &REFERENCE_NONE,
+        );
+        assert_close(
+            &rotate(config(RopeScaling::Linear { factor: 1. }), Some(4.)),
+            &REFERENCE_LINEAR,
+        );
+        assert_close(
+            &rotate(config(RopeScaling::Ntk { factor: 4. }), Some(1.)),
+            &REFERENCE_NONE,
+        );
+    }
+
+    #[test]
+    fn test_rope_config_from_hf() {
+        let config = RopeConfig::from_hf_config(
+            r#"{"max_position_embeddings": 32768, "rope_scaling": {"factor": 4.0, "original_max_position_embeddings": 4096, "type": "yarn"}, "rope_theta": 1000000.0}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            RopeConfig {
+                theta: 1_000_000.,
+                scaling: RopeScaling::Yarn {
+                    factor: 4.,
+                    original_max_position: 4096,
+                    beta_fast: 32.,
+                    beta_slow: 1.,
+                },
+            }
+        );
+        let config = RopeConfig::from_hf_config(
+            r#"{"rope_scaling": {"rope_type": "linear", "factor": 2}, "vocab_size": 32000}"#,
+        )
+        .unwrap();
+        assert_eq!(config.scaling, RopeScaling::Linear { factor: 2. });
+        assert_eq!(config.theta, 10_000.);
+        assert_eq!(
+            RopeConfig::from_hf_config(r#"{"rope_scaling": null, "rope_theta": 500000.0}"#),
+            Ok(RopeConfig {
+                theta: 500_000.,
+                scaling: RopeScaling::None
+            })
+        );
+        assert_eq!(
+            RopeConfig::from_hf_config(r#"{"rope_scaling": {"type": "dynamic", "factor": 2.0}}"#),
+            Err(RopeConfigError::UnsupportedType("dynamic".to_string()))
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    cell::RefCell,
+    io::Write,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use itertools::Itertools;
+use petgraph::{visit::EdgeRef, Direction};
+use rustc_hash::FxHashMap;
+
+use crate::{compiler_utils::Compiler, nn::hooks::ForwardHooks, prelude::*};
+
+use super::golden::op_name;
+
+/// The earliest node, in construction order, whose value differs between two backends
+#[derive(Debug, Clone)]
+pub struct NodeDivergence {
+    /// Position of the node in construction order, which is the same in both builds
+    pub position: usize,
+    /// Id of the node before compiling
+    pub node: NodeIndex,
+    /// Name of the node's op before compiling, like `"Exp2"`
+    pub op: String,
+    /// Ids the node was remapped to by each backend's compile
+    pub compiled: (NodeIndex, NodeIndex),
+    pub shape: Vec<usize>,
+    /// First differing element
+    pub index: usize,
+    pub a: f32,
+    pub b: f32,
+    /// Largest absolute difference across the node's values
+    pub max_diff: f32,
+    /// The .npy files the node's values from each backend were written to, if a dump directory was given
+    pub dumps: Option<(PathBuf, PathBuf)>,
+}
+
+/// The value of every tapped node, by position in construction order
+type Captured = Rc<RefCell<FxHashMap<usize, (Vec<f32>, Vec<usize>)>>>;
+
+/// Build the model twice with `build`, compile one copy with each backend, and find the earliest intermediate whose
+/// values differ by more than `tol` (relative to the magnitude of the value from `backend_a`).
+///
+/// Nodes are matched by construction order, so `build` must build the same graph every time, and set the same input
+/// data. Every node is tapped before compiling, which keeps all intermediates alive but also stops compilers from
+/// fusing across them. A divergence that only shows up in a fused kernel will still be caught at the fused outputs.
+///
+/// If `dump_dir` is given, both values of the diverging node are written there as .npy files.
+pub fn compare_backends<A: Compiler, B: Compiler>(
+    build: impl Fn(&mut Graph) -> Vec<GraphTensor<()>>,
+    backend_a: A,
+    backend_b: B,
+    tol: f32,
+    dump_dir: Option<&Path>,
+) -> Option<NodeDivergence> {
+    let (nodes, ops, compiled_a, values_a) = run_tapped(&build, backend_a);
+    let (_, _, compiled_b, values_b) = run_tapped(&build, backend_b);
+    let (values_a, values_b) = (values_a.borrow(), values_b.borrow());
+    for position in 0..nodes.len() {
+        let (Some((a, shape)), Some((b, _))) = (values_a.get(&position), values_b.get(&position))
+        else {
+            continue;
+        };
+        let Some(index) = first_difference(a, b, tol) else {
+            continue;
+        };
+        let max_diff = max_difference(a, b);
+        let dumps = dump_dir.map(|dir| {
+            let name = format!("{position}_{}", ops[position]);
+            let paths = (
+                dir.join(format!("{name}_a.npy")),
+                dir.join(format!("{name}_b.npy")),
+            );
+            write_npy(&paths.0, a, shape).unwrap();
+            write_npy(&paths.1, b, shape).unwrap();
+            paths
+        });
+        return Some(NodeDivergence {
+            position,
+            node: nodes[position],
+            op: ops[position].clone(),
+            compiled: (compiled_a[position], compiled_b[position]),
+            shape: shape.clone(),
+            index,
+            a: a.get(index).copied().unwrap_or(f32::NAN),
+            b: b.get(index).copied().unwrap_or(f32::NAN),
+            max_diff,
+            dumps,
+        });
+    }
+    None
+}
+
+/// The earliest hooked tensor whose values differ between two runs of a model
+#[derive(Debug, Clone)]
+pub struct HookDivergence {
+    /// Name of the hooked tensor, like `"layer0/self_attn/output"`
+    pub name: String,
+    pub shape: Vec<usize>,
+    /// First differing element
+    pub index: usize,
+    pub a: f32,
+    pub b: f32,
+    /// Largest absolute difference across the tensor's values
+    pub max_diff: f32,
+}
+
+/// Find the first hooked tensor, in forward order, whose values differ by more than `tol` (relative to the magnitude
+/// of the value from `a`) between two runs of the same model, like on two backends. Tensors only hooked in one run
+/// are skipped.
+pub fn compare_hooks(a: &ForwardHooks, b: &ForwardHooks, tol: f32) -> Option<HookDivergence> {
+    a.names().into_iter().find_map(|name| {
+        let ((a, shape), (b, _)) = (a.get(&name)?, b.get(&name)?);
+        let index = first_difference(&a, &b, tol)?;
+        Some(HookDivergence {
+            shape,
+            index,
+            a: a.get(index).copied().unwrap_or(f32::NAN),
+            b: b.get(index).copied().unwrap_or(f32::NAN),
+            max_diff: max_difference(&a, &b),
+            name,
+        })
+    })
+}
+
+/// The first element that isn't within `tol` (relative to the magnitude of `a`), or the end of the shorter slice if
+/// the lengths differ. NaNs only match NaNs.
+fn first_difference(a: &[f32], b: &[f32], tol: f32) -> Option<usize> {
+    let close = |a: f32, b: f32| {
+        if a.is_nan() || b.is_nan() {
+            return a.is_nan() && b.is_nan();
+        }
+        a == b || (a - b).abs() <= tol * (1. + a.abs())
+    };
+    a.iter()
+        .zip(b)
+        .position(|(a, b)| !close(*a, *b))
+        .or((a.len() != b.len()).then_some(a.len().min(b.len())))
+}
+
+fn max_difference(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0., f32::max)
+}
+
+/// Build the graph, tap every node, compile it with `backend` and execute it, returning the nodes and their ops in
+/// construction order, the ids they were remapped to, and their values
+fn run_tapped<C: Compiler>(
+    build: &impl Fn(&mut Graph) -> Vec<GraphTensor<()>>,
+    backend: C,
+) -> (Vec<NodeIndex>, Vec<String>, Vec<NodeIndex>, Captured) {
+    let mut cx = Graph::new();
+    let mut outputs = build(&mut cx);
+    let nodes = cx.graph.node_indices().sorted().collect_vec();
+    let ops = nodes
+        .iter()
+        .map(|n| op_name(cx.graph[*n].as_ref()))
+        .collect_vec();
+    let captured = Captured::default();
+    for (position, node) in nodes.iter().enumerate() {
+        let view = cx
+            .graph
+            .edges_directed(*node, Direction::Outgoing)
+            .find_map(|e| e.weight().as_data().map(|(_, _, shape)| shape))
+            .or_else(|| outputs.iter().find(|o| o.id == *node).map(|o| o.shape));
+        let Some(view) = view else {
+            continue;
+        };
+        let captured = captured.clone();
+        GraphTensor::<()>::from_id(*node, buffer_shape(view), &mut cx).tap(
+            format!("Compare-{position}"),
+            move |data, shape| {
+                captured
+                    .borrow_mut()
+                    .insert(position, (data.to_vec(), shape.to_vec()));
+            },
+        );
+    }
+    let mut compiled = nodes.clone();
+    cx.compile(
+        backend,
+        (&mut outputs, &mut compiled.iter_mut().collect_vec()),
+    );
+    cx.execute();
+    (nodes, ops, compiled, captured)
+}
+
+/// The shape of the buffer a view reads from, dropping expanded dimensions and undoing any permute
+fn buffer_shape(view: ShapeTracker) -> ShapeTracker {
+    ShapeTracker::new(
+        &view
+            .dims
+            .into_iter()
+            .zip(view.fake)
+            .filter(|(_, fake)| !fake)
+            .map(|(d, _)| d)
+            .collect_vec(),
+    )
+}
+
+/// Write little-endian f32s as a version 1 .npy file
+fn write_npy(path: &Path, data: &[f32], shape: &[usize]) -> std::io::Result<()> {
+    let shape = match shape {
+        [d] => format!("({d},)"),
+        _ => format!("({})", shape.iter().join(", ")),
+    };
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {shape}, }}");
+    // The magic, version, header length and header are padded to a multiple of 64 bytes, ending in a newline
+    header.push_str(&" ".repeat(63 - (10 + header.len()) % 64));
+    header.push('\n');
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(b"\x93NUMPY\x01\x00")?;
+    file.write_all(&(header.len() as u16).to_le_bytes())?;
+    file.write_all(header.as_bytes())?;
+    file.write_all(&data.iter().flat_map(|v| v.to_le_bytes()).collect_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler_utils::Compiler,
+        op::{Exp2, InputTensor, Operator},
+        prelude::*,
+        tests::{golden::op_name, random_vec_rng},
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::compare_backends;
+
+    /// An Exp2 that adds a small error to its output
+    #[derive(Debug, Clone, PartialEq)]
+    struct Perturbed(Exp2);
+
+    impl Operator for Perturbed {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            let mut out = self.0.process(inp);
+            for v in out[0].data.as_any_mut().downcast_mut::<Vec<f32>>().unwrap() {
+                *v += 0.01;
+            }
+            out
+        }
+    }
+
+    /// A deliberately broken pass that perturbs every Exp2
+    #[derive(Debug, Default)]
+    struct BreakExp2;
+
+    impl Compiler for BreakExp2 {
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+            for node in graph.graph.node_indices().collect::<Vec<_>>() {
+                if graph.graph[node].as_any().is::<Exp2>() {
+                    graph.graph[node] = Box::new(Perturbed(Exp2));
+                }
+            }
+        }
+    }
+
+    fn build(cx: &mut Graph) -> Vec<GraphTensor<()>> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let a = cx.tensor::<R2<4, 8>>().set(random_vec_rng(32, &mut rng));
+        let b = (a * 2.).exp2().sum_reduce::<_, Axis<1>>().sqrt();
+        vec![b.retrieve().no_shape()]
+    }
+
+    #[test]
+    fn test_compare_backends() {
+        assert!(compare_backends(
+            build,
+            CPUCompiler::default(),
+            CPUCompiler::default(),
+            1e-5,
+            None
+        )
+        .is_none());
+
+        let dir = std::env::temp_dir().join(format!("luminal_compare_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let divergence = compare_backends(
+            build,
+            CPUCompiler::default(),
+            <(CPUCompiler, BreakExp2)>::default(),
+            1e-5,
+            Some(&dir),
+        )
+        .unwrap();
+        // The Exp2 is caught, not the sum reduce and sqrt after it that also differ
+        assert_eq!(divergence.op, "Exp2");
+        assert_eq!(divergence.shape, vec![4, 8]);
+        assert_eq!(divergence.index, 0);
+        assert!((divergence.b - divergence.a - 0.01).abs() < 1e-5);
+        let mut cx = Graph::new();
+        build(&mut cx);
+        assert_eq!(op_name(cx.graph[divergence.node].as_ref()), "Exp2");
+
+        let (a, b) = divergence.dumps.unwrap();
+        for path in [a, b] {
+            let bytes = std::fs::read(path).unwrap();
+            assert_eq!(&bytes[..6], b"\x93NUMPY");
+            let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+            assert_eq!((10 + header_len) % 64, 0);
+            assert!(std::str::from_utf8(&bytes[10..10 + header_len])
+                .unwrap()
+                .contains("'shape': (4, 8)"));
+            assert_eq!(bytes.len(), 10 + header_len + 32 * 4);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+use std::{collections::BTreeMap, fmt::Display, path::Path};
+
+/// Named [output fingerprints](crate::graph::Graph::output_fingerprint) stored in a text file, for test suites to check
+/// a model's numerics against.
+///
+/// Each line is a name and a hex fingerprint, like `llama_prefill 0f3a9c1e2b4d5a67`. Lines starting with `#` are
+/// comments, and are dropped when the file is saved.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FingerprintFile {
+    pub fingerprints: BTreeMap<String, u64>,
+}
+
+/// A fingerprint didn't match the one stored for its name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FingerprintMismatch {
+    pub name: String,
+    pub stored: u64,
+    pub got: u64,
+}
+
+impl Display for FingerprintMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Fingerprint of {} changed from {:016x} to {:016x}",
+            self.name, self.stored, self.got
+        )
+    }
+}
+
+impl std::error::Error for FingerprintMismatch {}
+
+impl FingerprintFile {
+    /// Read the fingerprints in a file. A missing file has no fingerprints.
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let mut fingerprints = BTreeMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fingerprint = line
+                .rsplit_once(char::is_whitespace)
+                .and_then(|(name, hash)| Some((name.trim(), u64::from_str_radix(hash, 16).ok()?)));
+            let Some((name, hash)) = fingerprint else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Line {} isn't a name and a hex fingerprint: {line}", i + 1),
+                ));
+            };
+            fingerprints.insert(name.to_string(), hash);
+        }
+        Ok(Self { fingerprints })
+    }
+
+    /// Write the fingerprints to a file, one per line in name order
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let contents = self
+            .fingerprints
+            .iter()
+            .map(|(name, hash)| format!("{name} {hash:016x}\n"))
+            .collect::<String>();
+        std::fs::write(path, contents)
+    }
+
+    /// Compare a fingerprint with the one stored under `name`. A name with nothing stored yet records the fingerprint,
+    /// and matches.
+    pub fn check(&mut self, name: &str, fingerprint: u64) -> Result<(), FingerprintMismatch> {
+        match self.fingerprints.get(name) {
+            Some(stored) if *stored != fingerprint => Err(FingerprintMismatch {
+                name: name.to_string(),
+                stored: *stored,
+                got: fingerprint,
+            }),
+            Some(_) => Ok(()),
+            None => {
+                self.fingerprints.insert(name.to_string(), fingerprint);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FingerprintFile, FingerprintMismatch};
+
+    #[test]
+    fn test_fingerprint_file() {
+        let path =
+            std::env::temp_dir().join(format!("luminal_fingerprints_{}.txt", std::process::id()));
+        let mut file = FingerprintFile::load(&path).unwrap();
+        assert!(file.fingerprints.is_empty());
+        assert_eq!(file.check("mlp", 0xdeadbeef), Ok(()));
+        assert_eq!(file.check("mlp", 0xdeadbeef), Ok(()));
+        file.fingerprints.insert("conv net".to_string(), u64::MAX);
+        file.save(&path).unwrap();
+
+        let mut loaded = FingerprintFile::load(&path).unwrap();
+        assert_eq!(loaded, file);
+        assert_eq!(
+            loaded.check("mlp", 1),
+            Err(FingerprintMismatch {
+                name: "mlp".to_string(),
+                stored: 0xdeadbeef,
+                got: 1
+            })
+        );
+
+        std::fs::write(&path, "# comment\nmlp\n").unwrap();
+        assert!(FingerprintFile::load(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,780 @@
+use std::{
+    fmt::Display,
+    panic::{catch_unwind, AssertUnwindSafe},
+    rc::Rc,
+};
+
+use itertools::Itertools;
+use petgraph::stable_graph::NodeIndex;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    compiler_utils::{Compiler, NewOp},
+    op::{self, Function},
+    prelude::{symbolic::Expression, *},
+};
+
+// Cross-check backends by running random small graphs on the CPU and on the backend under test
+
+/// An op the fuzzer can put in a graph
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FuzzOp {
+    Exp2,
+    Log2,
+    Sin,
+    Sqrt,
+    Recip,
+    Add,
+    Mul,
+    Mod,
+    LessThan,
+    SumReduce,
+    MaxReduce,
+    Contiguous,
+}
+
+impl FuzzOp {
+    fn n_inputs(&self) -> usize {
+        match self {
+            FuzzOp::Add | FuzzOp::Mul | FuzzOp::Mod | FuzzOp::LessThan => 2,
+            _ => 1,
+        }
+    }
+
+    fn is_reduce(&self) -> bool {
+        matches!(self, FuzzOp::SumReduce | FuzzOp::MaxReduce)
+    }
+
+    /// Unary ops run on the input's data as is, so their output has the input's view
+    fn keeps_view(&self) -> bool {
+        matches!(
+            self,
+            FuzzOp::Exp2 | FuzzOp::Log2 | FuzzOp::Sin | FuzzOp::Sqrt | FuzzOp::Recip
+        )
+    }
+
+    fn add_to(self, cx: &mut Graph, axis: usize) -> NewOp<'_> {
+        match self {
+            FuzzOp::Exp2 => cx.add_op(op::Exp2),
+            FuzzOp::Log2 => cx.add_op(op::Log2),
+            FuzzOp::Sin => cx.add_op(op::Sin),
+            FuzzOp::Sqrt => cx.add_op(op::Sqrt),
+            FuzzOp::Recip => cx.add_op(op::Recip),
+            FuzzOp::Add => cx.add_op(op::Add),
+            FuzzOp::Mul => cx.add_op(op::Mul),
+            FuzzOp::Mod => cx.add_op(op::Mod),
+            FuzzOp::LessThan => cx.add_op(op::LessThan),
+            FuzzOp::SumReduce => cx.add_op(op::SumReduce(axis, op::Summation::Naive)),
+            FuzzOp::MaxReduce => cx.add_op(op::MaxReduce(axis)),
+            FuzzOp::Contiguous => cx.add_op(op::Contiguous),
+        }
+    }
+}
+
+/// A view of a value taken before feeding it to an op
+#[derive(Debug, Clone, PartialEq)]
+pub enum FuzzView {
+    Permute(Vec<usize>),
+    Pad {
+        axis: usize,
+        start: usize,
+        end: usize,
+    },
+    Slice {
+        axis: usize,
+        start: usize,
+        end: usize,
+    },
+    /// Insert a broadcasted dimension
+    Expand {
+        axis: usize,
+        size: usize,
+    },
+}
+
+/// An input of an op: a value and the views applied to it, in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzEdge {
+    pub src: usize,
+    pub views: Vec<FuzzView>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FuzzNode {
+    Input {
+        dims: Vec<Expression>,
+        data: Vec<f32>,
+    },
+    Op {
+        op: FuzzOp,
+        /// The reduced axis, for reductions
+        axis: usize,
+        inputs: Vec<FuzzEdge>,
+    },
+}
+
+/// A small random graph. Nodes only take values from nodes before them, and ops nobody consumes are the outputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzGraph {
+    pub nodes: Vec<FuzzNode>,
+    pub dyn_dims: FxHashMap<char, usize>,
+}
+
+/// How to generate and compare fuzzed graphs
+#[derive(Debug, Clone)]
+pub struct FuzzConfig {
+    /// Seed of the first graph. Graph `i` uses `seed + i`, so any failing graph can be regenerated on its own
+    pub seed: u64,
+    pub n_graphs: usize,
+    /// The ops graphs are built from
+    pub ops: Vec<FuzzOp>,
+    pub max_ops: usize,
+    pub max_rank: usize,
+    pub max_dim: usize,
+    /// Chance of each input dimension being dynamic
+    pub dyn_dim_chance: f64,
+    /// Chance of each kind of view being applied to an op input
+    pub view_chance: f64,
+    /// Absolute tolerance of backend outputs
+    pub atol: f32,
+    /// Tolerance of backend outputs relative to the CPU output
+    pub rtol: f32,
+}
+
+impl Default for FuzzConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            n_graphs: 200,
+            // Mod and LessThan are discontinuous, so tiny differences upstream can flip their outputs
+            ops: vec![
+                FuzzOp::Exp2,
+                FuzzOp::Log2,
+                FuzzOp::Sin,
+                FuzzOp::Sqrt,
+                FuzzOp::Recip,
+                FuzzOp::Add,
+                FuzzOp::Mul,
+                FuzzOp::SumReduce,
+                FuzzOp::MaxReduce,
+                FuzzOp::Contiguous,
+            ],
+            max_ops: 8,
+            max_rank: 3,
+            max_dim: 5,
+            dyn_dim_chance: 0.2,
+            view_chance: 0.4,
+            atol: 1e-3,
+            rtol: 1e-3,
+        }
+    }
+}
+
+impl FuzzConfig {
+    /// A fixed small corpus, quick enough to run on every test run
+    pub fn ci() -> Self {
+        Self {
+            n_graphs: 25,
+            ..Default::default()
+        }
+    }
+}
+
+/// Where a backend's output differs from the CPU's
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// The output node
+    pub output: usize,
+    /// The first differing element
+    pub index: usize,
+    pub expected: f32,
+    pub got: f32,
+    /// The panic message, if the backend panicked instead of producing outputs
+    pub panic: Option<String>,
+}
+
+/// A fuzzed graph a backend got wrong, shrunk to a minimal reproduction
+#[derive(Debug, Clone)]
+pub struct FuzzFailure {
+    /// The seed the graph was generated from
+    pub seed: u64,
+    /// Number of ops in the graph before shrinking
+    pub original_ops: usize,
+    pub graph: FuzzGraph,
+    pub divergence: Divergence,
+}
+
+impl Display for FuzzFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let d = &self.divergence;
+        write!(
+            f,
+            "Graph from seed {} (shrunk from {} to {} ops) ",
+            self.seed,
+            self.original_ops,
+            self.graph.n_ops()
+        )?;
+        match &d.panic {
+            Some(message) => writeln!(f, "panicked on the backend: {message}")?,
+            None => writeln!(
+                f,
+                "differs at v{} index {}: expected {}, got {}",
+                d.output, d.index, d.expected, d.got
+            )?,
+        }
+        write!(f, "{}", self.graph)
+    }
+}
+
+impl Display for FuzzGraph {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.dyn_dims.is_empty() {
+            writeln!(
+                f,
+                "dyn {}",
+                self.dyn_dims
+                    .iter()
+                    .sorted()
+                    .map(|(c, v)| format!("{c}={v}"))
+                    .join(", ")
+            )?;
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            match node {
+                FuzzNode::Input { dims, .. } => writeln!(f, "v{i} = input {dims:?}")?,
+                FuzzNode::Op { op, axis, inputs } => {
+                    let inputs = inputs
+                        .iter()
+                        .map(|e| {
+                            let mut s = format!("v{}", e.src);
+                            for view in &e.views {
+                                s.push_str(&match view {
+                                    FuzzView::Permute(axes) => format!(".permute({axes:?})"),
+                                    FuzzView::Pad { axis, start, end } => {
+                                        format!(".pad({axis}, {start}, {end})")
+                                    }
+                                    FuzzView::Slice { axis, start, end } => {
+                                        format!(".slice({axis}, {start}..{end})")
+                                    }
+                                    FuzzView::Expand { axis, size } => {
+                                        format!(".expand({axis}, {size})")
+                                    }
+                                });
+                            }
+                            s
+                        })
+                        .join(", ");
+                    if op.is_reduce() {
+                        writeln!(f, "v{i} = {op:?}({axis})({inputs})")?;
+                    } else {
+                        writeln!(f, "v{i} = {op:?}({inputs})")?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The shape of a value after applying `views`
+fn edge_tracker(mut st: ShapeTracker, views: &[FuzzView]) -> ShapeTracker {
+    for view in views {
+        match view {
+            FuzzView::Permute(axes) => st.permute(axes),
+            FuzzView::Pad { axis, start, end } => {
+                let mut padding = vec![(Expression::from(0), Expression::from(0)); st.len()];
+                padding[*axis] = ((*start).into(), (*end).into());
+                st.pad(&padding);
+            }
+            FuzzView::Slice { axis, start, end } => {
+                let mut slices = vec![(Expression::from(0), Expression::from(i32::MAX)); st.len()];
+                slices[*axis] = ((*start).into(), (*end).into());
+                st.slice(&slices);
+            }
+            FuzzView::Expand { axis, size } => st.expand(*axis, (*size).into()),
+        }
+    }
+    st
+}
+
+fn logical_dims(st: &ShapeTracker) -> Vec<Expression> {
+    st.shape().into_iter().map(Expression::from).collect()
+}
+
+impl FuzzGraph {
+    /// Generate a random graph
+    pub fn generate(config: &FuzzConfig, seed: u64) -> FuzzGraph {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut graph = FuzzGraph {
+            nodes: vec![],
+            dyn_dims: FxHashMap::default(),
+        };
+        for _ in 0..rng.gen_range(1..=2) {
+            let rank = rng.gen_range(1..=config.max_rank);
+            let sizes = (0..rank)
+                .map(|_| rng.gen_range(1..=config.max_dim))
+                .collect_vec();
+            graph.add_input(&sizes, config, &mut rng);
+        }
+        for _ in 0..rng.gen_range(1..=config.max_ops) {
+            let op = *config.ops.choose(&mut rng).unwrap();
+            let trackers = graph.trackers();
+            let sizes = trackers
+                .iter()
+                .map(|st| graph.concrete(&logical_dims(st)))
+                .collect_vec();
+            let src = rng.gen_range(0..graph.nodes.len());
+            let lhs = random_edge(src, &trackers[src], &sizes[src], config, &mut rng);
+            let lhs_sizes = graph.concrete(&logical_dims(&edge_tracker(trackers[src], &lhs.views)));
+            if op.is_reduce() && lhs_sizes.is_empty() {
+                continue;
+            }
+            let mut inputs = vec![lhs];
+            if op.n_inputs() == 2 {
+                // Pair with a value of the same shape, or a new input
+                let candidates = (0..graph.nodes.len())
+                    .filter(|i| sizes[*i] == lhs_sizes)
+                    .collect_vec();
+                let rhs = match candidates.choose(&mut rng) {
+                    Some(i) => *i,
+                    None => graph.add_input(&lhs_sizes, config, &mut rng),
+                };
+                inputs.push(FuzzEdge {
+                    src: rhs,
+                    views: vec![],
+                });
+            }
+            let axis = if op.is_reduce() {
+                rng.gen_range(0..lhs_sizes.len())
+            } else {
+                0
+            };
+            graph.nodes.push(FuzzNode::Op { op, axis, inputs });
+        }
+        graph
+    }
+
+    fn add_input(&mut self, sizes: &[usize], config: &FuzzConfig, rng: &mut StdRng) -> usize {
+        let dims = sizes
+            .iter()
+            .map(|size| {
+                if !rng.gen_bool(config.dyn_dim_chance) {
+                    return Expression::from(*size);
+                }
+                // Dimensions of the same size share a dyn dim
+                let dim = match self.dyn_dims.iter().find(|(_, v)| **v == *size) {
+                    Some((c, _)) => *c,
+                    None => (b'a' + self.dyn_dims.len() as u8) as char,
+                };
+                self.dyn_dims.insert(dim, *size);
+                Expression::from(dim)
+            })
+            .collect_vec();
+        let data = (0..sizes.iter().product::<usize>())
+            .map(|_| rng.gen_range(0.25..2.0))
+            .collect();
+        self.nodes.push(FuzzNode::Input { dims, data });
+        self.nodes.len() - 1
+    }
+
+    /// The number of ops, not counting inputs
+    pub fn n_ops(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|n| matches!(n, FuzzNode::Op { .. }))
+            .count()
+    }
+
+    /// The ops nothing consumes
+    pub fn outputs(&self) -> Vec<usize> {
+        let consumed = self.consumed();
+        (0..self.nodes.len())
+            .filter(|i| matches!(self.nodes[*i], FuzzNode::Op { .. }) && !consumed.contains(i))
+            .collect()
+    }
+
+    fn consumed(&self) -> FxHashSet<usize> {
+        self.nodes
+            .iter()
+            .filter_map(|n| match n {
+                FuzzNode::Op { inputs, .. } => Some(inputs.iter().map(|e| e.src)),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// The output shape of every node
+    fn trackers(&self) -> Vec<ShapeTracker> {
+        let mut trackers: Vec<ShapeTracker> = vec![];
+        for node in &self.nodes {
+            trackers.push(match node {
+                FuzzNode::Input { dims, .. } => ShapeTracker::new(dims),
+                FuzzNode::Op { op, axis, inputs } => {
+                    let st = edge_tracker(trackers[inputs[0].src], &inputs[0].views);
+                    if op.keeps_view() {
+                        st
+                    } else {
+                        let mut dims = logical_dims(&st);
+                        if op.is_reduce() {
+                            dims.remove(*axis);
+                        }
+                        ShapeTracker::new(&dims)
+                    }
+                }
+            });
+        }
+        trackers
+    }
+
+    fn concrete(&self, dims: &[Expression]) -> Vec<usize> {
+        dims.iter()
+            .map(|d| d.exec(&self.dyn_dims).unwrap())
+            .collect()
+    }
+
+    /// Build the graph, returning the node of each value
+    fn build(&self, cx: &mut Graph) -> Vec<NodeIndex> {
+        for (dim, size) in &self.dyn_dims {
+            cx.set_dyn_dim(*dim, *size);
+        }
+        let trackers = self.trackers();
+        let mut ids: Vec<NodeIndex> = vec![];
+        for (i, node) in self.nodes.iter().enumerate() {
+            ids.push(match node {
+                FuzzNode::Input { data, .. } => {
+                    let data = data.clone();
+                    cx.add_op(Function(
+                        format!("Input {i} Load"),
+                        Rc::new(move |_| vec![Tensor::new(data.clone())]),
+                    ))
+                    .finish()
+                }
+                FuzzNode::Op { op, axis, inputs } => {
+                    let mut new_op = op.add_to(cx, *axis);
+                    for edge in inputs {
+                        new_op = new_op.input(
+                            ids[edge.src],
+                            0,
+                            edge_tracker(trackers[edge.src], &edge.views),
+                        );
+                    }
+                    new_op.finish()
+                }
+            });
+        }
+        ids
+    }
+
+    /// Execute the graph after compiling it with `compile`, getting the data of `values`
+    fn run<B: Fn(&mut Graph, &mut Vec<GraphTensor<()>>)>(
+        &self,
+        values: &[usize],
+        compile: &B,
+    ) -> Vec<Vec<f32>> {
+        let mut cx = Graph::new();
+        let ids = self.build(&mut cx);
+        let trackers = self.trackers();
+        let mut tensors = values
+            .iter()
+            .map(|v| GraphTensor::<()>::from_id(ids[*v], trackers[*v], &mut cx).retrieve())
+            .collect_vec();
+        compile(&mut cx, &mut tensors);
+        cx.execute();
+        tensors.iter().map(|t| t.data()).collect()
+    }
+
+    /// Compare the outputs of the uncompiled graph on the CPU with the outputs after compiling with `backend`.
+    ///
+    /// Graphs that compute a NaN anywhere on the CPU are skipped. NaNs come from values outside an op's domain, like
+    /// the log of a negative number, and compilers are free to rewrite those, like simplifying exp2(log2(x)) to x.
+    pub fn divergence<B: Fn(&mut Graph, &mut Vec<GraphTensor<()>>)>(
+        &self,
+        config: &FuzzConfig,
+        backend: &B,
+    ) -> Option<Divergence> {
+        let ops = (0..self.nodes.len())
+            .filter(|i| matches!(self.nodes[*i], FuzzNode::Op { .. }))
+            .collect_vec();
+        let values = self.run(&ops, &|_, _| {});
+        if values.iter().flatten().any(|v| v.is_nan()) {
+            return None;
+        }
+        let outputs = self.outputs();
+        let expected = outputs
+            .iter()
+            .map(|o| values[ops.iter().position(|op| op == o).unwrap()].clone())
+            .collect_vec();
+        let got = match catch_unwind(AssertUnwindSafe(|| self.run(&outputs, backend))) {
+            Ok(got) => got,
+            Err(payload) => {
+                return Some(Divergence {
+                    output: outputs[0],
+                    index: 0,
+                    expected: f32::NAN,
+                    got: f32::NAN,
+                    panic: Some(
+                        payload
+                            .downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| payload.downcast_ref::<String>().cloned())
+                            .unwrap_or_else(|| "Unknown error".to_string()),
+                    ),
+                })
+            }
+        };
+        for ((output, expected), got) in outputs.iter().zip(expected).zip(got) {
+            let index = expected
+                .iter()
+                .zip(&got)
+                .position(|(e, g)| !is_close(*e, *g, config));
+            if let Some(index) =
+                index.or((expected.len() != got.len()).then_some(expected.len().min(got.len())))
+            {
+                return Some(Divergence {
+                    output: *output,
+                    index,
+                    expected: expected.get(index).copied().unwrap_or(f32::NAN),
+                    got: got.get(index).copied().unwrap_or(f32::NAN),
+                    panic: None,
+                });
+            }
+        }
+        None
+    }
+
+    /// Keep only `outputs` and the nodes they depend on
+    fn prune(&self, outputs: &[usize]) -> FuzzGraph {
+        let mut keep = vec![false; self.nodes.len()];
+        let mut stack = outputs.to_vec();
+        while let Some(node) = stack.pop() {
+            if !std::mem::replace(&mut keep[node], true) {
+                if let FuzzNode::Op { inputs, .. } = &self.nodes[node] {
+                    stack.extend(inputs.iter().map(|e| e.src));
+                }
+            }
+        }
+        let mut remap = FxHashMap::default();
+        let mut nodes = vec![];
+        for (i, node) in self.nodes.iter().enumerate().filter(|(i, _)| keep[*i]) {
+            remap.insert(i, nodes.len());
+            let mut node = node.clone();
+            if let FuzzNode::Op { inputs, .. } = &mut node {
+                for edge in inputs {
+                    edge.src = remap[&edge.src];
+                }
+            }
+            nodes.push(node);
+        }
+        let mut graph = FuzzGraph {
+            nodes,
+            dyn_dims: self.dyn_dims.clone(),
+        };
+        let used = graph
+            .trackers()
+            .into_iter()
+            .flat_map(|st| st.dims)
+            .flat_map(|d| d.to_symbols())
+            .collect::<FxHashSet<_>>();
+        graph.dyn_dims.retain(|d, _| used.contains(d));
+        graph
+    }
+
+    /// Shrink the graph while `still_fails` holds, by dropping outputs and by replacing ops with inputs holding the
+    /// values the ops computed on the CPU
+    pub fn shrink(&self, still_fails: impl Fn(&FuzzGraph) -> bool) -> FuzzGraph {
+        let mut graph = self.clone();
+        'shrink: loop {
+            let outputs = graph.outputs();
+            for output in &outputs {
+                let remaining = outputs
+                    .iter()
+                    .copied()
+                    .filter(|o| o != output)
+                    .collect_vec();
+                if remaining.is_empty() {
+                    break;
+                }
+                let candidate = graph.prune(&remaining);
+                if still_fails(&candidate) {
+                    graph = candidate;
+                    continue 'shrink;
+                }
+            }
+            // Cutting the earliest ops first removes the most
+            let ops = (0..graph.nodes.len())
+                .filter(|i| matches!(graph.nodes[*i], FuzzNode::Op { .. }) && !outputs.contains(i))
+                .collect_vec();
+            let values = graph.run(&ops, &|_, _| {});
+            let trackers = graph.trackers();
+            for (op, data) in ops.into_iter().zip(values) {
+                // Non-finite inputs tend to turn the failure into a different one
+                if data.iter().any(|v| !v.is_finite()) {
+                    continue;
+                }
+                let mut candidate = graph.clone();
+                candidate.nodes[op] = FuzzNode::Input {
+                    dims: logical_dims(&trackers[op]),
+                    data,
+                };
+                let candidate = candidate.prune(&outputs);
+                if still_fails(&candidate) {
+                    graph = candidate;
+                    continue 'shrink;
+                }
+            }
+            return graph;
+        }
+    }
+}
+
+/// Random views of a value with shape `st` and concrete logical sizes `sizes`
+fn random_edge(
+    src: usize,
+    st: &ShapeTracker,
+    sizes: &[usize],
+    config: &FuzzConfig,
+    rng: &mut StdRng,
+) -> FuzzEdge {
+    let mut st = *st;
+    let mut sizes = sizes.to_vec();
+    let mut views = vec![];
+    if sizes.len() >= 2 && rng.gen_bool(config.view_chance) {
+        let mut axes = (0..sizes.len()).collect_vec();
+        axes.shuffle(rng);
+        sizes = axes.iter().map(|a| sizes[*a]).collect();
+        st.permute(&axes);
+        views.push(FuzzView::Permute(axes));
+    }
+    // Padding and slicing the same dimension isn't supported, so only view dimensions that are still untouched
+    let untouched = (0..sizes.len())
+        .filter(|a| {
+            let i = st.indexes[*a];
+            !st.fake[i]
+                && st.padding[i] == (0.into(), 0.into())
+                && st.slices[i] == (0.into(), i32::MAX.into())
+        })
+        .collect_vec();
+    if !untouched.is_empty() && rng.gen_bool(config.view_chance) {
+        let axis = *untouched.choose(rng).unwrap();
+        if rng.gen_bool(0.5) {
+            views.push(FuzzView::Pad {
+                axis,
+                start: rng.gen_range(0..=2),
+                end: rng.gen_range(0..=2),
+            });
+        } else {
+            let start = rng.gen_range(0..sizes[axis]);
+            views.push(FuzzView::Slice {
+                axis,
+                start,
+                end: rng.gen_range(start + 1..=sizes[axis]),
+            });
+        }
+    }
+    if sizes.len() < 4 && rng.gen_bool(config.view_chance / 2.) {
+        views.push(FuzzView::Expand {
+            axis: rng.gen_range(0..=sizes.len()),
+            size: rng.gen_range(1..=config.max_dim),
+        });
+    }
+    FuzzEdge { src, views }
+}
+
+fn is_close(expected: f32, got: f32, config: &FuzzConfig) -> bool {
+    if expected.is_nan() || got.is_nan() {
+        return expected.is_nan() && got.is_nan();
+    }
+    if expected.is_infinite() || got.is_infinite() {
+        return expected == got;
+    }
+    (expected - got).abs() <= config.atol + config.rtol * expected.abs()
+}
+
+/// Run `config.n_graphs` random graphs on the CPU and through `backend`, which compiles each graph for the backend
+/// under test (remapping the output tensors it's given).
+///
+/// Returns the first graph whose outputs differ beyond tolerance, shrunk to a minimal reproduction.
+pub fn fuzz_backend<B: Fn(&mut Graph, &mut Vec<GraphTensor<()>>)>(
+    config: &FuzzConfig,
+    backend: B,
+) -> Result<(), FuzzFailure> {
+    for i in 0..config.n_graphs {
+        let seed = config.seed.wrapping_add(i as u64);
+        let graph = FuzzGraph::generate(config, seed);
+        if graph.divergence(config, &backend).is_none() {
+            continue;
+        }
+        let shrunk = graph.shrink(|g| g.divergence(config, &backend).is_some());
+        return Err(FuzzFailure {
+            seed,
+            original_ops: graph.n_ops(),
+            divergence: shrunk.divergence(config, &backend).unwrap(),
+            graph: shrunk,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A broken backend that runs sines as exp2s
+    #[derive(Debug, Default)]
+    struct SinAsExp2;
+
+    impl Compiler for SinAsExp2 {
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+            for node in graph.graph.node_indices().collect_vec() {
+                if graph.graph[node].as_any().is::<op::Sin>() {
+                    graph.graph[node] = Box::new(op::Exp2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_cpu_compilers() {
+        if let Err(failure) = fuzz_backend(&FuzzConfig::ci(), |cx, outputs| {
+            cx.compile(<(GenericCompiler, CPUCompiler)>::default(), outputs);
+        }) {
+            panic!("{failure}");
+        }
+    }
+
+    #[test]
+    fn test_fuzz_shrinks_failures() {
+        let config = FuzzConfig {
+            ops: vec![FuzzOp::Sin, FuzzOp::Add, FuzzOp::Mul, FuzzOp::SumReduce],
+            max_ops: 10,
+            ..FuzzConfig::ci()
+        };
+        let failure = fuzz_backend(&config, |cx, outputs| {
+            cx.compile(SinAsExp2, outputs);
+        })
+        .unwrap_err();
+        // Only the wrong sine is left, reading an input
+        assert_eq!(failure.graph.n_ops(), 1);
+        assert!(failure.graph.nodes.iter().any(|n| matches!(
+            n,
+            FuzzNode::Op {
+                op: FuzzOp::Sin,
+                ..
+            }
+        )));
+        assert!(failure.to_string().contains("= Sin(v0"));
+        // The seed reproduces the failure
+        let graph = FuzzGraph::generate(&config, failure.seed);
+        assert!(graph.n_ops() >= failure.graph.n_ops());
+        assert!(graph
+            .divergence(
+                &config,
+                &|cx: &mut Graph, outputs: &mut Vec<GraphTensor<()>>| {
+                    cx.compile(SinAsExp2, outputs);
+                }
+            )
+            .is_some());
+    }
+}
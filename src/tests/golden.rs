@@ -0,0 +1,193 @@
+use std::collections::BTreeSet;
+
+use petgraph::Direction;
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{compiler_utils::Compiler, op::*, prelude::*};
+
+use super::random_vec_rng;
+
+/// Builds small graphs of primitive ops directly, without going through the frontend, for testing compiler patterns.
+///
+/// Tensors are untyped, carrying their shape in their shape tracker, so any layout a pattern expects can be built.
+/// Inputs are filled with seeded random data, so the graph can also be executed.
+/// ```rust
+/// use luminal::{compilers::MatMul2DCompiler, prelude::*, tests::golden::*};
+/// let mut g = GraphBuilder::new();
+/// let a = g.input("A", &[2, 3]);
+/// let b = g.input("B", &[3, 4]);
+/// let c = g.matmul_pattern(a, b);
+/// g.retrieve(c);
+/// assert_compiles_to(&mut g, MatMul2DCompiler, &["A Load", "B Load", "MatMul2D"]);
+/// ```
+pub struct GraphBuilder {
+    pub graph: Box<Graph>,
+    /// Retrieved tensors, remapped through every compile
+    pub outputs: Vec<GraphTensor<()>>,
+    rng: StdRng,
+}
+
+impl Default for GraphBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GraphBuilder {
+    pub fn new() -> Self {
+        Self {
+            graph: Box::new(Graph::new()),
+            outputs: vec![],
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+
+    /// An input tensor named `name`, shown as `"{name} Load"`
+    pub fn input(&mut self, name: &str, shape: &[usize]) -> GraphTensor<()> {
+        let n = shape.iter().product();
+        let mut tensor = self
+            .graph
+            .named_tensor::<()>(name)
+            .set(random_vec_rng(n, &mut self.rng));
+        tensor.shape = ShapeTracker::new(&shape.iter().map(|s| (*s).into()).collect::<Vec<_>>());
+        tensor
+    }
+
+    /// A scalar constant, expanded to `shape`
+    pub fn constant(&mut self, value: f32, shape: &[usize]) -> GraphTensor<()> {
+        let mut tensor = self.graph.constant(value).no_shape();
+        for (i, s) in shape.iter().enumerate() {
+            tensor.shape.expand(i, (*s).into());
+        }
+        tensor
+    }
+
+    /// Apply an op to the inputs, giving an output shaped like the first input
+    pub fn op<O: Operator + 'static>(
+        &mut self,
+        op: O,
+        inputs: &[GraphTensor<()>],
+    ) -> GraphTensor<()> {
+        self.op_with_shape(op, inputs, inputs[0].shape.contiguous())
+    }
+
+    /// Apply a reduction to `input`, removing `dim` from its shape
+    pub fn reduce<O: Operator + 'static>(
+        &mut self,
+        op: O,
+        input: GraphTensor<()>,
+        dim: usize,
+    ) -> GraphTensor<()> {
+        let mut shape = input.shape.contiguous();
+        shape.remove_dim(dim);
+        self.op_with_shape(op, &[input], shape)
+    }
+
+    /// Apply an op to the inputs, giving an output of the given shape
+    pub fn op_with_shape<O: Operator + 'static>(
+        &mut self,
+        op: O,
+        inputs: &[GraphTensor<()>],
+        shape: ShapeTracker,
+    ) -> GraphTensor<()> {
+        let mut new_op = self.graph.add_op(op);
+        for input in inputs {
+            new_op = new_op.input(input.id, 0, input.shape);
+        }
+        GraphTensor::from_id(new_op.finish(), shape, self.graph.as_mut())
+    }
+
+    /// Insert a new dimension of size `size` at `dim`, without copying
+    pub fn expand(&self, mut tensor: GraphTensor<()>, dim: usize, size: usize) -> GraphTensor<()> {
+        tensor.shape.expand(dim, size.into());
+        tensor
+    }
+
+    /// Reorder the dimensions, without copying
+    pub fn permute(&self, mut tensor: GraphTensor<()>, axes: &[usize]) -> GraphTensor<()> {
+        tensor.shape.permute(axes);
+        tensor
+    }
+
+    /// The mul and sum reduce the frontend builds for a `[M, K] x [K, N]` matmul
+    pub fn matmul_pattern(&mut self, a: GraphTensor<()>, b: GraphTensor<()>) -> GraphTensor<()> {
+        let n = b.shape.shape()[1].to_usize().unwrap();
+        let m = a.shape.shape()[0].to_usize().unwrap();
+        let a = self.expand(a, 1, n);
+        let b = self.permute(b, &[1, 0]);
+        let b = self.expand(b, 0, m);
+        let mul = self.op(Mul, &[a, b]);
+        self.reduce(SumReduce(2, Summation::Naive), mul, 2)
+    }
+
+    /// The mul and sum reduce the frontend builds for a `[B, M, K] x [K, N]` matmul
+    pub fn batch_matmul_pattern(
+        &mut self,
+        a: GraphTensor<()>,
+        b: GraphTensor<()>,
+    ) -> GraphTensor<()> {
+        let a_shape = a.shape.shape();
+        let n = b.shape.shape()[1].to_usize().unwrap();
+        let a = self.expand(a, 2, n);
+        let b = self.permute(b, &[1, 0]);
+        let b = self.expand(b, 0, a_shape[1].to_usize().unwrap());
+        let b = self.expand(b, 0, a_shape[0].to_usize().unwrap());
+        let mul = self.op(Mul, &[a, b]);
+        self.reduce(SumReduce(3, Summation::Naive), mul, 3)
+    }
+
+    /// Mark a tensor to be retrieved, keeping it through compiles
+    pub fn retrieve(&mut self, tensor: GraphTensor<()>) {
+        self.outputs.push(tensor.retrieve());
+    }
+}
+
+/// The name of an op, its debug output up to any fields, like `"MatMul2D"` or `"SumReduce"`
+pub fn op_name(op: &dyn Operator) -> String {
+    let debug = format!("{op:?}");
+    debug.split(['(', '{']).next().unwrap().trim().to_string()
+}
+
+/// The names of every op in the graph in topological order, taking the lowest node index first when there's a choice
+pub fn op_names(graph: &Graph) -> Vec<String> {
+    let mut waiting = graph
+        .graph
+        .node_indices()
+        .map(|n| {
+            (
+                n,
+                graph
+                    .graph
+                    .neighbors_directed(n, Direction::Incoming)
+                    .count(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut ready = waiting
+        .iter()
+        .filter(|(_, deps)| *deps == 0)
+        .map(|(n, _)| *n)
+        .collect::<BTreeSet<_>>();
+    let mut names = vec![];
+    while let Some(node) = ready.pop_first() {
+        names.push(op_name(graph.graph[node].as_ref()));
+        for dest in graph.graph.neighbors_directed(node, Direction::Outgoing) {
+            let (_, deps) = waiting.iter_mut().find(|(n, _)| *n == dest).unwrap();
+            *deps -= 1;
+            if *deps == 0 {
+                ready.insert(dest);
+            }
+        }
+    }
+    names
+}
+
+/// Compile the built graph and assert its ops are exactly `expected`, by name in topological order
+pub fn assert_compiles_to<C: Compiler>(builder: &mut GraphBuilder, compiler: C, expected: &[&str]) {
+    builder.graph.compile(compiler, &mut builder.outputs);
+    let names = op_names(&builder.graph);
+    assert_eq!(
+        names, expected,
+        "Compiled graph doesn't match the golden graph"
+    );
+}
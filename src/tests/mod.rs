@@ -2,6 +2,14 @@
 
 #[cfg(test)]
 mod dynamic;
+#[cfg(any(test, feature = "testing"))]
+pub mod compare;
+#[cfg(any(test, feature = "testing"))]
+pub mod fingerprint;
+#[cfg(any(test, feature = "testing"))]
+pub mod fuzz;
+#[cfg(any(test, feature = "testing"))]
+pub mod golden;
 #[cfg(test)]
 pub mod harness;
 pub mod test_graphs;
@@ -135,6 +143,7 @@ macro_rules! test_imports {
         };
         #[allow(unused_imports)]
         use $crate::{
+            compiler_internals::*,
             prelude::{
                 Axes as LAxes, Axes2 as LAxes2, Axes3 as LAxes3, Axes4 as LAxes4, Axes5 as LAxes5,
                 Axis as LAxis, Const as LConst, *,